@@ -0,0 +1,171 @@
+//! MMIO の debug-exit ポート
+//!
+//! QEMU の `isa-debug-exit` と同じく、ゲストがポートに値を書くとその値から決めた
+//! 終了コードで VM を終わらせる。ゲストの中で動くテストの結果を、シリアルの出力を
+//! 解析せずに CI のプロセスの終了コードとして返すのに使う。
+//!
+//! `hypervisor-device` だけに依存する、外部の crate で作るデバイスの見本でもある。
+//!
+//! ```ignore
+//! hv.add_device(DEBUG_EXIT_BASE, None, DebugExit::new())?;
+//! let result = hv.run(None, None, None)?;
+//! if let StopReason::DeviceExit(exit) = result.stop_reason {
+//!     std::process::exit(exit.code as i32);
+//! }
+//! ```
+//!
+//! ゲスト側 (AArch64):
+//!
+//! ```text
+//! mov  x0, #0x0a1f0000   // DEBUG_EXIT_BASE
+//! mov  w1, #0            // 成功 → 終了コード 1 (QEMU 互換)
+//! str  w1, [x0]
+//! ```
+
+use hypervisor_device::{Device, DeviceContext, DtDescribe, DtNode, VmControl};
+use std::error::Error;
+use std::sync::Arc;
+
+/// 既定のベースアドレス (QEMU virt の空き領域)
+pub const DEBUG_EXIT_BASE: u64 = 0x0a1f_0000;
+/// レジスタ領域のサイズ
+pub const DEBUG_EXIT_SIZE: u64 = 0x1000;
+/// 終了コードを書くレジスタのオフセット
+pub const EXIT_PORT: u64 = 0x0;
+
+/// 書かれた値から終了コードを決める方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExitCodeMapping {
+    /// QEMU の `isa-debug-exit` と同じ `(value << 1) | 1` (0 は成功と区別できない)
+    #[default]
+    Qemu,
+    /// 書かれた値をそのまま使う
+    Raw,
+}
+
+impl ExitCodeMapping {
+    /// 書かれた値に対応する終了コード
+    pub fn code(self, value: u64) -> u32 {
+        match self {
+            Self::Qemu => ((value as u32) << 1) | 1,
+            Self::Raw => value as u32,
+        }
+    }
+}
+
+/// debug-exit ポート
+#[derive(Default)]
+pub struct DebugExit {
+    mapping: ExitCodeMapping,
+    control: Option<Arc<dyn VmControl>>,
+}
+
+impl DebugExit {
+    /// QEMU 互換の終了コードを返すポート
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 終了コードの決め方を変える
+    pub fn mapping(mut self, mapping: ExitCodeMapping) -> Self {
+        self.mapping = mapping;
+        self
+    }
+}
+
+impl DtDescribe for DebugExit {
+    fn dt_node(&self, base: u64, _irq: Option<u32>) -> Option<DtNode> {
+        Some(DtNode::new("debug-exit", base, DEBUG_EXIT_SIZE).compatible("hypervisor,debug-exit"))
+    }
+}
+
+impl Device for DebugExit {
+    fn name(&self) -> &str {
+        "debug-exit"
+    }
+
+    fn size(&self) -> u64 {
+        DEBUG_EXIT_SIZE
+    }
+
+    fn read(&mut self, _offset: u64, _size: usize) -> Result<u64, Box<dyn Error>> {
+        Ok(0)
+    }
+
+    fn write(&mut self, offset: u64, value: u64, _size: usize) -> Result<(), Box<dyn Error>> {
+        if offset != EXIT_PORT {
+            return Ok(());
+        }
+        let control = self.control.as_ref().ok_or("debug-exit is not attached")?;
+        control.request_exit(self.mapping.code(value));
+        Ok(())
+    }
+
+    fn attach(&mut self, ctx: DeviceContext) {
+        self.control = Some(ctx.control);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hypervisor_device::GuestMemory;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<u32>>);
+
+    impl VmControl for Recorder {
+        fn request_exit(&self, code: u32) {
+            self.0.lock().unwrap().push(code);
+        }
+    }
+
+    struct NoMemory;
+
+    impl GuestMemory for NoMemory {
+        fn read(&self, _addr: u64, _buf: &mut [u8]) -> Result<(), Box<dyn Error>> {
+            Err("no memory".into())
+        }
+
+        fn write(&self, _addr: u64, _buf: &[u8]) -> Result<(), Box<dyn Error>> {
+            Err("no memory".into())
+        }
+    }
+
+    fn attached(port: DebugExit) -> (DebugExit, Arc<Recorder>) {
+        let recorder = Arc::new(Recorder::default());
+        let mut port = port;
+        port.attach(DeviceContext::new(
+            DEBUG_EXIT_BASE,
+            None,
+            Arc::new(NoMemory),
+            recorder.clone(),
+        ));
+        (port, recorder)
+    }
+
+    #[test]
+    fn 書いた値から_qemu_と同じ終了コードを作る() {
+        let (mut port, recorder) = attached(DebugExit::new());
+        port.write(EXIT_PORT, 0, 4).unwrap();
+        port.write(EXIT_PORT, 0x10, 1).unwrap();
+        // ほかのオフセットへの書き込みは無視する
+        port.write(0x8, 3, 4).unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), [1, 0x21]);
+        assert_eq!(port.read(EXIT_PORT, 4).unwrap(), 0);
+
+        let (mut raw, recorder) = attached(DebugExit::new().mapping(ExitCodeMapping::Raw));
+        raw.write(EXIT_PORT, 0, 4).unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), [0]);
+
+        assert!(DebugExit::new().write(EXIT_PORT, 0, 4).is_err());
+    }
+
+    #[test]
+    fn device_tree_のノードを返す() {
+        let node = DebugExit::new().dt_node(DEBUG_EXIT_BASE, None).unwrap();
+        assert_eq!(node.reg, (DEBUG_EXIT_BASE, DEBUG_EXIT_SIZE));
+        assert_eq!(node.compatible, ["hypervisor,debug-exit"]);
+    }
+}
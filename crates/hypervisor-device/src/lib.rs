@@ -0,0 +1,346 @@
+//! ハイパーバイザーの外で作る MMIO デバイスのための API
+//!
+//! `hypervisor` crate の `MmioHandler` は統計・マイグレーション・障害注入などの
+//! フックを持ち、VMM の変更に合わせて頻繁に変わる。この crate は
+//! デバイスを作るのに必要な最小限の型だけを切り出したもので、別の crate として
+//! 配布するデバイスはこの crate だけに依存すればよい。
+//!
+//! - [`Device`] - レジスタの読み書きとリセット
+//! - [`IrqLine`] - レベルトリガの割り込み線
+//! - [`GuestMemory`] - DMA のためのゲスト物理メモリの読み書き
+//! - [`DtDescribe`] / [`DtNode`] - Device Tree に追加するノード
+//! - [`VmControl`] - VM の終了の要求
+//!
+//! VMM 側は `Hypervisor::add_device` でデバイスを配置し、[`Device::attach`] で
+//! 割り込み線・ゲストメモリ・VM の操作を渡す。
+//!
+//! # 互換性
+//!
+//! semver に従う。0.x の間はマイナーバージョンを破壊的変更として扱い、trait に
+//! メソッドを追加するときは必ず既定の実装を付ける (パッチ・マイナーの更新で既存の
+//! デバイスがコンパイルできなくなることはない)。VMM が作る構造体と enum は
+//! `#[non_exhaustive]` にしてあり、フィールドやバリアントの追加も破壊的変更にならない。
+//!
+//! ```ignore
+//! struct Scratch { reg: u64 }
+//!
+//! impl DtDescribe for Scratch {
+//!     fn dt_node(&self, base: u64, irq: Option<u32>) -> Option<DtNode> {
+//!         Some(DtNode::new("scratch", base, 0x1000).compatible("acme,scratch").interrupt(irq))
+//!     }
+//! }
+//!
+//! impl Device for Scratch {
+//!     fn name(&self) -> &str { "scratch" }
+//!     fn size(&self) -> u64 { 0x1000 }
+//!     fn read(&mut self, _offset: u64, _size: usize) -> Result<u64, Box<dyn Error>> { Ok(self.reg) }
+//!     fn write(&mut self, _offset: u64, value: u64, _size: usize) -> Result<(), Box<dyn Error>> {
+//!         self.reg = value;
+//!         Ok(())
+//!     }
+//! }
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// この API のバージョン (`Cargo.toml` の `version`)
+pub const API_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// ゲスト物理メモリ
+///
+/// デバイスが DMA でゲスト RAM を読み書きするのに使う。アドレスはゲスト物理アドレス
+/// そのもの (IOMMU はない)。範囲外のアクセスはエラーになる。
+pub trait GuestMemory: Send + Sync {
+    /// `addr` から `buf` を埋める
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), Box<dyn Error>>;
+
+    /// `buf` を `addr` に書き込む
+    fn write(&self, addr: u64, buf: &[u8]) -> Result<(), Box<dyn Error>>;
+}
+
+/// レベルトリガの割り込み線
+///
+/// VMM がデバイスに 1 本ずつ渡す。デバイスがアサートしている間、VMM は VM Exit ごとに
+/// GIC にペンディングとして設定する。複製したハンドルは同じ線を指す
+/// (別のスレッドから割り込みを上げられる)。
+#[derive(Clone)]
+pub struct IrqLine {
+    intid: u32,
+    level: Arc<AtomicBool>,
+    /// アサートしたときに vCPU を起こす (VMM が設定する)
+    notify: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+impl fmt::Debug for IrqLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IrqLine")
+            .field("intid", &self.intid)
+            .field("level", &self.is_asserted())
+            .finish_non_exhaustive()
+    }
+}
+
+impl IrqLine {
+    /// GIC の INTID `intid` につながる線 (VMM が作る)
+    pub fn new(intid: u32) -> Self {
+        Self {
+            intid,
+            level: Arc::new(AtomicBool::new(false)),
+            notify: None,
+        }
+    }
+
+    /// アサートされるたびに `notify` を呼ぶ線 (VMM が作る)
+    ///
+    /// VMM は `notify` で vCPU を起こし、別のスレッドから上げた割り込みも
+    /// ゲストがアイドル中や実行中のままにならずに届くようにする。
+    pub fn with_notify(intid: u32, notify: impl Fn() + Send + Sync + 'static) -> Self {
+        Self {
+            notify: Some(Arc::new(notify)),
+            ..Self::new(intid)
+        }
+    }
+
+    /// GIC の INTID
+    pub fn intid(&self) -> u32 {
+        self.intid
+    }
+
+    /// 線のレベルを設定する
+    pub fn set_level(&self, asserted: bool) {
+        let was_asserted = self.level.swap(asserted, Ordering::AcqRel);
+        if asserted && !was_asserted {
+            if let Some(notify) = &self.notify {
+                notify();
+            }
+        }
+    }
+
+    /// アサートする
+    pub fn raise(&self) {
+        self.set_level(true);
+    }
+
+    /// アサートをやめる
+    pub fn lower(&self) {
+        self.set_level(false);
+    }
+
+    /// アサートしているか
+    pub fn is_asserted(&self) -> bool {
+        self.level.load(Ordering::Acquire)
+    }
+}
+
+/// Device Tree のプロパティの値
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DtValue {
+    /// 値のないプロパティ (`dma-coherent;` など)
+    Empty,
+    /// 32 ビットのセルの並び
+    U32(Vec<u32>),
+    /// 64 ビットの値の並び
+    U64(Vec<u64>),
+    /// 文字列
+    String(String),
+    /// 文字列のリスト
+    StringList(Vec<String>),
+}
+
+/// Device Tree に追加するノード
+///
+/// ルート直下に `<name>@<base>` として置かれる。`interrupts` と
+/// `interrupt-parent` は VMM が GIC の形式で書き込む。[`DtNode::new`] で作る。
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DtNode {
+    /// ノード名 (ユニットアドレスを除く)
+    pub name: String,
+    /// `reg` に書く (ベースアドレス, サイズ)
+    pub reg: (u64, u64),
+    /// `compatible` (先頭ほど具体的)
+    pub compatible: Vec<String>,
+    /// レベルトリガの SPI として書く割り込み (GIC の INTID)
+    pub interrupt: Option<u32>,
+    /// その他のプロパティ
+    pub properties: Vec<(String, DtValue)>,
+}
+
+impl DtNode {
+    /// `reg = <base size>` のノード
+    pub fn new(name: &str, base: u64, size: u64) -> Self {
+        Self {
+            name: name.to_string(),
+            reg: (base, size),
+            compatible: Vec::new(),
+            interrupt: None,
+            properties: Vec::new(),
+        }
+    }
+
+    /// `compatible` に追加する
+    pub fn compatible(mut self, compatible: &str) -> Self {
+        self.compatible.push(compatible.to_string());
+        self
+    }
+
+    /// 割り込みを設定する
+    pub fn interrupt(mut self, intid: Option<u32>) -> Self {
+        self.interrupt = intid;
+        self
+    }
+
+    /// プロパティを追加する
+    pub fn property(mut self, name: &str, value: DtValue) -> Self {
+        self.properties.push((name.to_string(), value));
+        self
+    }
+}
+
+/// Device Tree に自身を記述する
+pub trait DtDescribe {
+    /// `base` に配置され割り込み `irq` を割り当てられたときのノード
+    ///
+    /// Device Tree に載せない (ゲストのドライバが固定アドレスで探す) 場合は None。
+    fn dt_node(&self, base: u64, irq: Option<u32>) -> Option<DtNode> {
+        let _ = (base, irq);
+        None
+    }
+}
+
+/// デバイスから VM を操作する
+pub trait VmControl: Send + Sync {
+    /// VM を終了させる
+    ///
+    /// 処理中の VM Exit を終えたところで run ループが `code` を付けて戻る。
+    /// 別のスレッドから呼んだ場合も、VMM が vCPU を起こしてすぐに戻らせる。
+    fn request_exit(&self, code: u32);
+}
+
+/// VMM がデバイスに渡す資源
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct DeviceContext {
+    /// 配置したベースアドレス
+    pub base: u64,
+    /// 割り当てた割り込み線 (割り込みを使わないデバイスは None)
+    pub irq: Option<IrqLine>,
+    /// ゲスト物理メモリ
+    pub memory: Arc<dyn GuestMemory>,
+    /// VM の操作
+    pub control: Arc<dyn VmControl>,
+}
+
+impl DeviceContext {
+    /// VMM (やデバイスのテスト) が作る
+    pub fn new(
+        base: u64,
+        irq: Option<IrqLine>,
+        memory: Arc<dyn GuestMemory>,
+        control: Arc<dyn VmControl>,
+    ) -> Self {
+        Self {
+            base,
+            irq,
+            memory,
+            control,
+        }
+    }
+}
+
+impl fmt::Debug for DeviceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceContext")
+            .field("base", &self.base)
+            .field("irq", &self.irq)
+            .finish_non_exhaustive()
+    }
+}
+
+/// MMIO デバイス
+///
+/// オフセットはデバイスのベースアドレスからの値。`read` / `write` は vCPU の
+/// スレッドから VM Exit のたびに呼ばれる。
+pub trait Device: DtDescribe + Send + Sync {
+    /// 統計やログに表示する名前
+    fn name(&self) -> &str;
+
+    /// レジスタ領域のサイズ (bytes)
+    fn size(&self) -> u64;
+
+    /// レジスタを読む
+    ///
+    /// # Arguments
+    /// * `offset` - ベースアドレスからのオフセット
+    /// * `size` - 読み取るサイズ (1, 2, 4, 8 bytes)
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, Box<dyn Error>>;
+
+    /// レジスタに書き込む
+    ///
+    /// # Arguments
+    /// * `offset` - ベースアドレスからのオフセット
+    /// * `value` - 書き込む値
+    /// * `size` - 書き込むサイズ (1, 2, 4, 8 bytes)
+    fn write(&mut self, offset: u64, value: u64, size: usize) -> Result<(), Box<dyn Error>>;
+
+    /// 作成直後の状態に戻す (ゲストの再起動)
+    ///
+    /// ゲストから見えるレジスタだけを戻し、ホスト側の接続はそのまま残す。
+    fn reset(&mut self) {}
+
+    /// 配置されたときに 1 回呼ばれる
+    fn attach(&mut self, ctx: DeviceContext) {
+        let _ = ctx;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn 複製した割り込み線は同じ線を指す() {
+        let line = IrqLine::new(42);
+        let other = line.clone();
+        assert!(!line.is_asserted());
+        other.raise();
+        assert!(line.is_asserted());
+        assert_eq!(line.intid(), 42);
+        line.lower();
+        assert!(!other.is_asserted());
+    }
+
+    #[test]
+    fn アサートしたときだけ通知する() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let line = IrqLine::with_notify(42, move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let other = line.clone();
+        std::thread::spawn(move || other.raise()).join().unwrap();
+        line.raise();
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        line.lower();
+        line.lower();
+        line.set_level(true);
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn ノードを組み立てる() {
+        let node = DtNode::new("scratch", 0x0a10_0000, 0x1000)
+            .compatible("acme,scratch")
+            .interrupt(Some(80))
+            .property("dma-coherent", DtValue::Empty);
+        assert_eq!(node.reg, (0x0a10_0000, 0x1000));
+        assert_eq!(node.compatible, ["acme,scratch"]);
+        assert_eq!(node.interrupt, Some(80));
+        assert_eq!(node.properties[0].1, DtValue::Empty);
+    }
+}
@@ -63,6 +63,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         cmdline: "console=ttyAMA0 earlycon debug".to_string(),
         initrd_start: None,
         initrd_end: None,
+        ..Default::default()
     };
     println!("    設定:");
     println!(
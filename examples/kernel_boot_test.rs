@@ -17,7 +17,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // 2. UART デバイスを登録
     println!("\n[2] UART デバイスを登録中...");
     let uart = Box::new(Pl011Uart::new(0x0900_0000));
-    hv.register_mmio_handler(uart);
+    hv.register_fast_mmio_handler(uart, Pl011Uart::FAST_WRITE_OFFSETS)?;
     println!("    ✓ UART デバイス登録完了");
 
     // 3. 簡単なブートコードを作成
@@ -58,7 +58,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // 4. boot_linux() でカーネルをブート
     println!("\n[4] カーネルをブート中...");
     println!("    設定:");
-    println!("      - エントリーポイント: 0x{:x}", kernel.entry_point());
+    println!("      - エントリーポイント: 0x{:x}", kernel.load_address());
     println!("      - Device Tree アドレス: 0x44000000");
     println!("      - コマンドライン: console=ttyAMA0");
     println!("\n    === カーネル出力 ===");
@@ -82,6 +82,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             println!("    ✗ 予期しない例外");
         }
     }
+    if let Some(el1) = result.el1 {
+        println!("    - EL1: {}", el1);
+    }
 
     println!("\n✅ テスト完了");
     println!("\n=== カーネルブート機能のテスト完了 ===");
@@ -11,7 +11,7 @@
 use hypervisor::boot::device_tree::{generate_device_tree, DeviceTreeConfig};
 use hypervisor::boot::kernel::KernelImage;
 use hypervisor::devices::gic::Gic;
-use hypervisor::devices::interrupt::InterruptController;
+use hypervisor::devices::irqchip::IrqChip;
 use hypervisor::devices::timer::TimerReg;
 use hypervisor::devices::uart::Pl011Uart;
 use hypervisor::devices::virtio::block::VirtioBlockDevice;
@@ -97,6 +97,7 @@ fn test_device_tree_generation() -> Result<Vec<u8>, Box<dyn std::error::Error>>
         cmdline: "console=ttyAMA0 earlycon root=/dev/vda rw".to_string(),
         initrd_start: None,
         initrd_end: None,
+        ..Default::default()
     };
 
     println!("    設定:");
@@ -145,24 +146,26 @@ fn test_gic_configuration() {
 }
 
 fn test_timer_configuration() {
-    let mut ic = InterruptController::new();
+    let mut ic = IrqChip::new();
 
     // 周波数を取得
-    let freq = ic.timer.get_frequency();
+    let freq = ic.timer().get_frequency();
     println!("    Timer 周波数: {} Hz ({} MHz)", freq, freq / 1_000_000);
 
     // 現在のカウンター値
-    let phys_cnt = ic.timer.get_phys_counter();
-    let virt_cnt = ic.timer.get_virt_counter();
+    let phys_cnt = ic.timer().get_phys_counter();
+    let virt_cnt = ic.timer().get_virt_counter();
     println!("    物理カウンタ: {}", phys_cnt);
     println!("    仮想カウンタ: {}", virt_cnt);
 
     // 物理タイマーを設定（1秒後）
     let cval = phys_cnt + freq;
-    ic.timer
+    ic.timer_mut()
         .write_sysreg(TimerReg::CNTP_CVAL_EL0, cval)
         .unwrap();
-    ic.timer.write_sysreg(TimerReg::CNTP_CTL_EL0, 1).unwrap();
+    ic.timer_mut()
+        .write_sysreg(TimerReg::CNTP_CTL_EL0, 1)
+        .unwrap();
     println!("    物理タイマー: CVAL={} (1秒後に発火)", cval);
 
     // タイマー状態確認
@@ -323,7 +326,7 @@ fn print_device_tree_summary(dtb: &[u8]) {
     println!("  ├── pl011@9000000 (UART)");
     println!("  │   └── IRQ: SPI 1 (IRQ 33)");
     println!("  │");
-    println!("  ├── virtio_block@a000000");
+    println!("  ├── virtio_mmio@a000000");
     println!("  │   └── IRQ: SPI 2 (IRQ 34)");
     println!("  │");
     println!("  └── chosen/");
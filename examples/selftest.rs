@@ -0,0 +1,33 @@
+//! ホストの Hypervisor.framework の動作を表示する
+//!
+//! システムレジスタのトラップ・CNTVCT_EL0・WFI/WFE・MMIO アクセスの syndrome を
+//! 調べ、このハイパーバイザーの前提と異なる点があれば警告する。macOS や
+//! CPU を変えたときに最初に実行する。
+//!
+//! # 実行方法
+//! ```sh
+//! cargo run --example selftest
+//! ```
+
+use hypervisor::Hypervisor;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut hv = Hypervisor::new(0x4000_0000, 0x10_0000)?;
+    let report = hv.selftest()?;
+    print!("{}", report);
+
+    let violations = report.violated_assumptions();
+    if violations.is_empty() {
+        println!("\nAll assumptions hold on this host.");
+        return Ok(());
+    }
+    println!();
+    for violation in &violations {
+        println!("warning: {}", violation);
+    }
+    Err(format!(
+        "{} assumption(s) do not hold on this host",
+        violations.len()
+    )
+    .into())
+}
@@ -9,14 +9,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // ハイパーバイザーを初期化
     println!("[1] ハイパーバイザーを初期化中...");
     let guest_addr = 0x10000;
-    let mut hv = Hypervisor::new(guest_addr, 0x1000)?;
+    let mut hv = Hypervisor::new(guest_addr, 0x4000)?;
     println!("    ✓ ゲストアドレス: 0x{:x}", guest_addr);
 
     // UART デバイスを登録
     println!("\n[2] UART デバイスを登録中...");
     const UART_BASE: u64 = 0x09000000;
     let uart = Pl011Uart::new(UART_BASE);
-    hv.register_mmio_handler(Box::new(uart));
+    hv.register_mmio_handler(Box::new(uart))?;
     println!("    ✓ UART ベースアドレス: 0x{:x}", UART_BASE);
 
     // ゲストコードを書き込む
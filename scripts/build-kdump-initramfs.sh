@@ -0,0 +1,77 @@
+#!/bin/bash
+# kdump テスト用 initramfs ビルドスクリプト
+# build-initramfs.sh の rootfs に kexec-tools と crash カーネルを追加する
+#
+# 1 回目の起動: crash カーネルを `kexec -p` でロードしてから sysrq でパニックさせる
+# 2 回目の起動 (crash カーネル): /proc/vmcore を確認して電源を切る
+
+set -e
+
+KEXEC_TOOLS_VERSION="2.0.28"
+BUILD_DIR="/build/initramfs"
+OUTPUT_DIR="/output"
+INITRAMFS_ROOT="$BUILD_DIR/rootfs"
+
+if [ ! -d "$INITRAMFS_ROOT" ] || [ ! -f "$OUTPUT_DIR/Image" ]; then
+    echo "Run build-linux-kernel.sh and build-initramfs.sh first"
+    exit 1
+fi
+
+echo "=== Building kdump initramfs ==="
+
+cd "$BUILD_DIR"
+
+# kexec-tools を静的リンクでビルド
+if [ ! -f "kexec-tools-${KEXEC_TOOLS_VERSION}/build/sbin/kexec" ]; then
+    echo "Building kexec-tools ${KEXEC_TOOLS_VERSION}..."
+    wget -q "https://kernel.org/pub/linux/utils/kernel/kexec/kexec-tools-${KEXEC_TOOLS_VERSION}.tar.xz"
+    tar xJf "kexec-tools-${KEXEC_TOOLS_VERSION}.tar.xz"
+    cd "kexec-tools-${KEXEC_TOOLS_VERSION}"
+    ./configure --host=aarch64-linux-gnu LDFLAGS=-static
+    make -j$(nproc)
+    cd "$BUILD_DIR"
+fi
+
+KDUMP_ROOT="$BUILD_DIR/kdump-rootfs"
+rm -rf "$KDUMP_ROOT"
+cp -a "$INITRAMFS_ROOT" "$KDUMP_ROOT"
+cp "kexec-tools-${KEXEC_TOOLS_VERSION}/build/sbin/kexec" "$KDUMP_ROOT/sbin/kexec"
+cp "$OUTPUT_DIR/Image" "$KDUMP_ROOT/Image"
+
+cat > "$KDUMP_ROOT/init" << 'INIT_EOF'
+#!/bin/sh
+
+mount -t proc none /proc
+mount -t sysfs none /sys
+mount -t devtmpfs none /dev 2>/dev/null || true
+
+if [ -e /proc/vmcore ]; then
+    echo "KDUMP: vmcore captured ($(wc -c < /proc/vmcore) bytes)"
+    poweroff -f
+fi
+
+echo "KDUMP: loading crash kernel"
+kexec -p /Image --initrd=/crash-initramfs.cpio.gz --append="console=ttyAMA0" || {
+    echo "KDUMP: kexec -p failed"
+    poweroff -f
+}
+echo "KDUMP: triggering panic"
+echo c > /proc/sysrq-trigger
+INIT_EOF
+chmod +x "$KDUMP_ROOT/init"
+
+# crash カーネル用の initramfs (kexec-tools とカーネルを含まない、init は共通)
+CRASH_ROOT="$BUILD_DIR/crash-rootfs"
+rm -rf "$CRASH_ROOT"
+cp -a "$INITRAMFS_ROOT" "$CRASH_ROOT"
+cp "$KDUMP_ROOT/init" "$CRASH_ROOT/init"
+(cd "$CRASH_ROOT" && find . -print0 | cpio --null -o --format=newc 2>/dev/null | gzip -9) \
+    > "$KDUMP_ROOT/crash-initramfs.cpio.gz"
+
+echo "Creating kdump-initramfs.cpio.gz..."
+(cd "$KDUMP_ROOT" && find . -print0 | cpio --null -o --format=newc 2>/dev/null | gzip -9) \
+    > "$OUTPUT_DIR/kdump-initramfs.cpio.gz"
+
+echo ""
+echo "=== kdump initramfs build complete ==="
+ls -lh "$OUTPUT_DIR/kdump-initramfs.cpio.gz"
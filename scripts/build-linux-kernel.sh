@@ -53,13 +53,19 @@ CONFIG_DRM=n
 CONFIG_FB=n
 CONFIG_VGA_CONSOLE=n
 
+# kexec / kdump (scripts/build-kdump-initramfs.sh)
+CONFIG_KEXEC=y
+CONFIG_CRASH_DUMP=y
+CONFIG_PROC_VMCORE=y
+CONFIG_MAGIC_SYSRQ=y
+
 # Enable debug
 CONFIG_DEBUG_INFO=y
 CONFIG_PRINTK=y
 CONFIG_PRINTK_TIME=y
 
 # Command line
-CONFIG_CMDLINE="console=ttyAMA0 earlycon=pl011,0x09000000 loglevel=8"
+CONFIG_CMDLINE="console=ttyAMA0 earlycon=pl011,0x09000000 loglevel=8 crashkernel=64M"
 CONFIG_CMDLINE_FORCE=y
 EOF
 
@@ -0,0 +1,25 @@
+#!/bin/bash
+# テスト用ペイロードのアセンブル
+#
+# src/payloads/*.S をアセンブルし、同じ名前の .bin (生のバイナリ) を作る。
+# .bin はリポジトリに含めており、ビルドにアセンブラは不要。.S を変更したら
+# このスクリプトを実行して .bin も更新すること。
+#
+# 使い方: ./scripts/build-payloads.sh
+# 必要なもの: llvm-mc, llvm-objcopy (LLVM)
+
+set -e
+
+cd "$(dirname "$0")/../src/payloads"
+
+LLVM_MC=${LLVM_MC:-llvm-mc}
+LLVM_OBJCOPY=${LLVM_OBJCOPY:-llvm-objcopy}
+TMP=$(mktemp -d)
+trap 'rm -rf "${TMP}"' EXIT
+
+for src in *.S; do
+    name="${src%.S}"
+    "${LLVM_MC}" -triple=aarch64 -filetype=obj -o "${TMP}/${name}.o" "${src}"
+    "${LLVM_OBJCOPY}" -O binary -j .text "${TMP}/${name}.o" "${name}.bin"
+    echo "${name}.bin: $(wc -c < "${name}.bin") bytes"
+done
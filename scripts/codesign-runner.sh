@@ -0,0 +1,18 @@
+#!/bin/bash
+# cargo の runner: 実行前にバイナリへ Hypervisor.framework の entitlement を付けて署名する
+#
+# .cargo/config.toml から cargo run / cargo test / cargo bench のすべてのバイナリに使う。
+# 署名に失敗しても実行は続け、Hypervisor::check_availability のエラーで原因を示す。
+#
+# 使い方: scripts/codesign-runner.sh <binary> [args...]
+
+binary="$1"
+shift
+
+entitlements="$(cd "$(dirname "$0")/.." && pwd)/entitlements.plist"
+
+if ! output=$(codesign --sign - --entitlements "$entitlements" --force "$binary" 2>&1); then
+    printf 'codesign-runner: failed to sign %s\n%s\n' "$binary" "$output" >&2
+fi
+
+exec "$binary" "$@"
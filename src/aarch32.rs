@@ -0,0 +1,174 @@
+//! AArch32 ゲストのサポート
+//!
+//! 32-bit ARM カーネル / ペイロードを EL1 (AArch32) で起動するための定数と、
+//! AArch32 から発生する例外 (MCR/MRC、MCRR/MRRC) の ISS デコードを提供する。
+//!
+//! # 制限
+//!
+//! Apple Silicon (M1-M4) は AArch64 専用で、どの EL でも AArch32 を実装していない
+//! (ID_AA64PFR0_EL1.EL1 = 0b0001)。[`el1_supports_aarch32`] で事前に確認し、
+//! 未サポートのホストでは `Hypervisor::boot_aarch32` がエラーを返す。
+//!
+//! AArch32 から例外が発生した場合、ESR の Rt/SRT は AArch64 側のレジスタ番号
+//! (バンクレジスタのマッピング済み) で報告されるため、X0-X30 としてそのまま扱える。
+
+/// User モード
+pub const PSR_MODE_USR: u64 = 0x10;
+/// Supervisor モード (Linux の起動モード)
+pub const PSR_MODE_SVC: u64 = 0x13;
+/// Thumb 実行状態ビット
+pub const PSR_T_BIT: u64 = 1 << 5;
+/// FIQ マスク
+pub const PSR_F_BIT: u64 = 1 << 6;
+/// IRQ マスク
+pub const PSR_I_BIT: u64 = 1 << 7;
+/// 非同期アボートマスク
+pub const PSR_A_BIT: u64 = 1 << 8;
+
+/// SVC モード、A/I/F マスク済みの初期 CPSR (0x1d3)
+pub const INITIAL_CPSR_SVC: u64 = PSR_MODE_SVC | PSR_F_BIT | PSR_I_BIT | PSR_A_BIT;
+
+/// ID_AA64PFR0_EL1 の値から EL1 が AArch32 をサポートするか判定する
+///
+/// EL1 フィールド (bits [7:4]) が 0b0010 なら AArch64 と AArch32 の両方をサポート。
+pub fn el1_supports_aarch32(id_aa64pfr0: u64) -> bool {
+    (id_aa64pfr0 >> 4) & 0xf == 0b0010
+}
+
+/// エントリーポイントから初期 PC と CPSR を決定する
+///
+/// ARM の interworking 規約に従い、bit 0 が立っていれば Thumb で開始する。
+///
+/// # Returns
+/// (PC, CPSR)
+pub fn entry_state(entry: u64) -> (u64, u64) {
+    if entry & 1 != 0 {
+        (entry & !1, INITIAL_CPSR_SVC | PSR_T_BIT)
+    } else {
+        (entry, INITIAL_CPSR_SVC)
+    }
+}
+
+/// MCR/MRC (EC=0x03 / 0x05) のデコード結果
+///
+/// ISS の構造:
+/// - [19:17] Opc2
+/// - [16:14] Opc1
+/// - [13:10] CRn
+/// - [9:5] Rt
+/// - [4:1] CRm
+/// - [0] Direction (0 = MCR 書き込み, 1 = MRC 読み取り)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoprocAccess {
+    pub opc1: u8,
+    pub crn: u8,
+    pub crm: u8,
+    pub opc2: u8,
+    pub rt: u8,
+    pub is_read: bool,
+}
+
+impl CoprocAccess {
+    /// ESR の値からデコードする
+    pub fn decode(syndrome: u64) -> Self {
+        let iss = syndrome & 0x1FF_FFFF;
+        Self {
+            opc2: ((iss >> 17) & 0x7) as u8,
+            opc1: ((iss >> 14) & 0x7) as u8,
+            crn: ((iss >> 10) & 0xf) as u8,
+            rt: ((iss >> 5) & 0x1f) as u8,
+            crm: ((iss >> 1) & 0xf) as u8,
+            is_read: iss & 0x1 != 0,
+        }
+    }
+}
+
+/// MCRR/MRRC (EC=0x04 / 0x0C) のデコード結果
+///
+/// ISS の構造:
+/// - [19:16] Opc1
+/// - [14:10] Rt2 (上位 32 bit)
+/// - [9:5] Rt (下位 32 bit)
+/// - [4:1] CRm
+/// - [0] Direction (0 = MCRR 書き込み, 1 = MRRC 読み取り)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoprocAccess64 {
+    pub opc1: u8,
+    pub crm: u8,
+    pub rt: u8,
+    pub rt2: u8,
+    pub is_read: bool,
+}
+
+impl CoprocAccess64 {
+    /// ESR の値からデコードする
+    pub fn decode(syndrome: u64) -> Self {
+        let iss = syndrome & 0x1FF_FFFF;
+        Self {
+            opc1: ((iss >> 16) & 0xf) as u8,
+            rt2: ((iss >> 10) & 0x1f) as u8,
+            rt: ((iss >> 5) & 0x1f) as u8,
+            crm: ((iss >> 1) & 0xf) as u8,
+            is_read: iss & 0x1 != 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arm_エントリーは_svc_モードで開始する() {
+        let (pc, cpsr) = entry_state(0x4000_8000);
+        assert_eq!(pc, 0x4000_8000);
+        assert_eq!(cpsr, 0x1d3);
+        assert_eq!(cpsr & PSR_T_BIT, 0);
+    }
+
+    #[test]
+    fn bit0_が立ったエントリーは_thumb_で開始する() {
+        let (pc, cpsr) = entry_state(0x4000_8001);
+        assert_eq!(pc, 0x4000_8000);
+        assert_ne!(cpsr & PSR_T_BIT, 0);
+        assert_eq!(cpsr & 0x1f, PSR_MODE_SVC);
+    }
+
+    #[test]
+    fn id_aa64pfr0_の_el1_フィールドを判定する() {
+        // Apple Silicon: EL0-EL3 すべて AArch64 のみ
+        assert!(!el1_supports_aarch32(0x1111));
+        assert!(el1_supports_aarch32(0x1121));
+    }
+
+    #[test]
+    fn mrc_の_iss_をデコードする() {
+        // MRC p15, 0, R2, c14, c3, 1 (CNTV_CTL 読み取り)
+        let iss = (1 << 17) | (14 << 10) | (2 << 5) | (3 << 1) | 1;
+        let syndrome = (0x03 << 26) | (1 << 25) | iss;
+        let access = CoprocAccess::decode(syndrome);
+        assert_eq!(
+            access,
+            CoprocAccess {
+                opc1: 0,
+                crn: 14,
+                crm: 3,
+                opc2: 1,
+                rt: 2,
+                is_read: true,
+            }
+        );
+    }
+
+    #[test]
+    fn mcrr_の_iss_をデコードする() {
+        // MCRR p15, 3, R0, R1, c14 (CNTV_CVAL 書き込み)
+        let iss = (3 << 16) | (1 << 10) | (14 << 1);
+        let access = CoprocAccess64::decode((0x04 << 26) | iss);
+        assert_eq!(access.opc1, 3);
+        assert_eq!(access.crm, 14);
+        assert_eq!(access.rt, 0);
+        assert_eq!(access.rt2, 1);
+        assert!(!access.is_read);
+    }
+}
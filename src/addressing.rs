@@ -0,0 +1,264 @@
+//! ゲストのページ粒度と物理アドレス幅
+//!
+//! ゲストカーネルは 4KB / 16KB / 64KB のページ粒度と、48-bit または
+//! 52-bit (FEAT_LPA / FEAT_LPA2) の物理アドレスでビルドできる。
+//! カーネルは起動直後に ID_AA64MMFR0_EL1 の TGran* / PARange フィールドを確認し、
+//! 粒度がサポートされていなければコンソール出力前に停止する。
+//!
+//! # Hypervisor.framework での扱い
+//!
+//! - stage-2 変換はフレームワークが管理し、ホストのページ (16KB) 単位で
+//!   `hv_vm_map` する。ゲストの stage-1 粒度とは独立しているため、
+//!   RAM の配置とサイズがゲストの粒度に揃っていればどの粒度でも動作する。
+//! - ID_AA64MMFR0_EL1 の読み取りはトラップされないが、vCPU ごとの値を
+//!   `hv_vcpu_set_sys_reg` で書き換えられる。[`synthesize_mmfr0`] で作った値を
+//!   [`Hypervisor::set_address_config`](crate::Hypervisor::set_address_config) が
+//!   設定し、ゲストには PARange を切り詰めた値が見える。
+//! - Apple Silicon (M1-M4) は 64KB 粒度をサポートしない (TGran64 = 0xF)。
+
+use std::error::Error;
+use std::fmt;
+
+/// ID_AA64MMFR0_EL1.PARange (bits [3:0])
+const MMFR0_PARANGE_MASK: u64 = 0xf;
+/// ID_AA64MMFR0_EL1.TGran16 (bits [23:20])
+const MMFR0_TGRAN16_SHIFT: u32 = 20;
+/// ID_AA64MMFR0_EL1.TGran64 (bits [27:24])
+const MMFR0_TGRAN64_SHIFT: u32 = 24;
+/// ID_AA64MMFR0_EL1.TGran4 (bits [31:28])
+const MMFR0_TGRAN4_SHIFT: u32 = 28;
+
+/// PARange のエンコーディングと物理アドレス幅 (bits) の対応
+const PARANGE_BITS: [u8; 7] = [32, 36, 40, 42, 44, 48, 52];
+
+/// ゲストの stage-1 ページ粒度
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PageGranule {
+    /// 4KB ページ
+    #[default]
+    Size4K,
+    /// 16KB ページ
+    Size16K,
+    /// 64KB ページ
+    Size64K,
+}
+
+impl PageGranule {
+    /// ページサイズ (bytes)
+    pub fn size(self) -> u64 {
+        match self {
+            PageGranule::Size4K => 0x1000,
+            PageGranule::Size16K => 0x4000,
+            PageGranule::Size64K => 0x1_0000,
+        }
+    }
+}
+
+impl fmt::Display for PageGranule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}KB", self.size() / 1024)
+    }
+}
+
+/// ゲストのアドレス空間設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressConfig {
+    /// stage-1 ページ粒度
+    pub granule: PageGranule,
+    /// 物理アドレス幅 (32, 36, 40, 42, 44, 48, 52)
+    pub pa_bits: u8,
+}
+
+impl Default for AddressConfig {
+    fn default() -> Self {
+        Self {
+            granule: PageGranule::Size4K,
+            pa_bits: 48,
+        }
+    }
+}
+
+/// PARange のエンコーディングを物理アドレス幅に変換する
+///
+/// 未定義のエンコーディングは最大の 52 bit として扱う。
+pub fn parange_to_bits(parange: u64) -> u8 {
+    PARANGE_BITS
+        .get(parange as usize)
+        .copied()
+        .unwrap_or(PARANGE_BITS[PARANGE_BITS.len() - 1])
+}
+
+/// 物理アドレス幅を PARange のエンコーディングに変換する
+pub fn bits_to_parange(pa_bits: u8) -> Option<u64> {
+    PARANGE_BITS
+        .iter()
+        .position(|&b| b == pa_bits)
+        .map(|p| p as u64)
+}
+
+/// ID_AA64MMFR0_EL1 が指定した粒度をサポートするか
+///
+/// `lpa2` が true の場合は 52-bit 出力アドレス (FEAT_LPA2) のサポートも要求する。
+/// 64KB 粒度の 52-bit は FEAT_LPA (PARange = 52 bit) で判定するためここでは見ない。
+pub fn granule_supported(mmfr0: u64, granule: PageGranule, lpa2: bool) -> bool {
+    let field = |shift: u32| (mmfr0 >> shift) & 0xf;
+    match granule {
+        // 0b0000 = サポート, 0b0001 = 52-bit もサポート, 0b1111 = 非サポート
+        PageGranule::Size4K => match field(MMFR0_TGRAN4_SHIFT) {
+            0b0000 => !lpa2,
+            0b0001 => true,
+            _ => false,
+        },
+        // 0b0000 = 非サポート, 0b0001 = サポート, 0b0010 = 52-bit もサポート
+        PageGranule::Size16K => match field(MMFR0_TGRAN16_SHIFT) {
+            0b0001 => !lpa2,
+            0b0010 => true,
+            _ => false,
+        },
+        // 0b0000 = サポート, 0b1111 = 非サポート
+        PageGranule::Size64K => field(MMFR0_TGRAN64_SHIFT) == 0b0000,
+    }
+}
+
+/// ホストの ID_AA64MMFR0_EL1 から、設定に合わせたゲスト向けの値を作る
+///
+/// PARange を `pa_bits` に切り詰める。ホストが要求した粒度や
+/// 物理アドレス幅をサポートしない場合はエラーを返す。
+pub fn synthesize_mmfr0(host_mmfr0: u64, config: &AddressConfig) -> Result<u64, Box<dyn Error>> {
+    let parange = bits_to_parange(config.pa_bits).ok_or_else(|| {
+        format!(
+            "Unsupported physical address width {} (expected one of {:?})",
+            config.pa_bits, PARANGE_BITS
+        )
+    })?;
+    let host_bits = parange_to_bits(host_mmfr0 & MMFR0_PARANGE_MASK);
+    if config.pa_bits > host_bits {
+        return Err(format!(
+            "Guest requests {}-bit physical addresses but the host supports only {} bits",
+            config.pa_bits, host_bits
+        )
+        .into());
+    }
+    // 4KB / 16KB 粒度の 52-bit は FEAT_LPA2 が必要
+    let lpa2 = config.pa_bits == 52 && config.granule != PageGranule::Size64K;
+    if !granule_supported(host_mmfr0, config.granule, lpa2) {
+        return Err(format!(
+            "Host does not support {} pages{} for the guest",
+            config.granule,
+            if lpa2 { " with 52-bit addresses" } else { "" }
+        )
+        .into());
+    }
+    Ok((host_mmfr0 & !MMFR0_PARANGE_MASK) | parange)
+}
+
+/// ゲスト RAM が設定したアドレス空間に収まるか確認する
+///
+/// RAM の先頭とサイズがゲストのページ粒度に揃い、
+/// 末尾が物理アドレス幅に収まっていることを確認する。
+pub fn validate_ram(config: &AddressConfig, base: u64, size: u64) -> Result<(), Box<dyn Error>> {
+    let page = config.granule.size();
+    if !base.is_multiple_of(page) || !size.is_multiple_of(page) {
+        return Err(format!(
+            "Guest RAM 0x{:x} (+0x{:x}) is not aligned to the guest's {} pages",
+            base, size, config.granule
+        )
+        .into());
+    }
+    let limit = 1u128 << config.pa_bits;
+    if base as u128 + size as u128 > limit {
+        return Err(format!(
+            "Guest RAM 0x{:x} (+0x{:x}) exceeds the {}-bit physical address space",
+            base, size, config.pa_bits
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Apple M2 相当: PARange = 40 bit, TGran4 / TGran16 サポート, TGran64 非サポート
+    const APPLE_MMFR0: u64 = (0xf << 24) | (0x1 << 20) | 0x2;
+    /// 52-bit 対応ホスト: PARange = 52 bit, LPA2 付きの 4KB / 16KB, 64KB サポート
+    const LPA_MMFR0: u64 = (0x1 << 28) | (0x2 << 20) | 0x6;
+
+    #[test]
+    fn parange_と物理アドレス幅を相互に変換できる() {
+        assert_eq!(parange_to_bits(0x5), 48);
+        assert_eq!(parange_to_bits(0x6), 52);
+        assert_eq!(bits_to_parange(40), Some(2));
+        assert_eq!(bits_to_parange(47), None);
+    }
+
+    #[test]
+    fn apple_silicon_は_64kb_粒度を拒否する() {
+        let config = AddressConfig {
+            granule: PageGranule::Size64K,
+            pa_bits: 40,
+        };
+        let err = synthesize_mmfr0(APPLE_MMFR0, &config).unwrap_err();
+        assert!(err.to_string().contains("64KB"));
+
+        let config = AddressConfig {
+            granule: PageGranule::Size16K,
+            pa_bits: 36,
+        };
+        // PARange だけが切り詰められる
+        assert_eq!(
+            synthesize_mmfr0(APPLE_MMFR0, &config).unwrap(),
+            (APPLE_MMFR0 & !0xf) | 0x1
+        );
+    }
+
+    #[test]
+    fn ホストより広い物理アドレスは拒否する() {
+        let config = AddressConfig {
+            granule: PageGranule::Size4K,
+            pa_bits: 48,
+        };
+        assert!(synthesize_mmfr0(APPLE_MMFR0, &config).is_err());
+    }
+
+    #[test]
+    fn _52bit_は粒度ごとに_lpa_または_lpa2_を要求する() {
+        for granule in [
+            PageGranule::Size4K,
+            PageGranule::Size16K,
+            PageGranule::Size64K,
+        ] {
+            let config = AddressConfig {
+                granule,
+                pa_bits: 52,
+            };
+            assert_eq!(synthesize_mmfr0(LPA_MMFR0, &config).unwrap(), LPA_MMFR0);
+        }
+
+        // LPA2 のない 4KB 粒度では 52-bit を使えない
+        let no_lpa2 = LPA_MMFR0 & !(0xf << 28);
+        let config = AddressConfig {
+            granule: PageGranule::Size4K,
+            pa_bits: 52,
+        };
+        assert!(synthesize_mmfr0(no_lpa2, &config).is_err());
+    }
+
+    #[test]
+    fn ram_は粒度と物理アドレス幅に収まる必要がある() {
+        let config = AddressConfig {
+            granule: PageGranule::Size64K,
+            pa_bits: 40,
+        };
+        assert!(validate_ram(&config, 0x4000_0000, 0x800_0000).is_ok());
+        assert!(validate_ram(&config, 0x4000_0000, 0x800_4000).is_err());
+        assert!(validate_ram(&config, 0xff_c000_0000, 0x8000_0000).is_err());
+
+        let config = AddressConfig {
+            pa_bits: 52,
+            ..config
+        };
+        assert!(validate_ram(&config, 0x1_0000_0000_0000, 0x4000_0000).is_ok());
+    }
+}
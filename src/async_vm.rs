@@ -0,0 +1,343 @@
+//! async ランタイムから VM を操作するためのレイヤー (`async` feature)
+//!
+//! Hypervisor.framework の vCPU は作成したスレッドでしか実行できないため、
+//! [`AsyncVm`] は専用のスレッドで [`Hypervisor`] を作成し、`run` などの呼び出しを
+//! そのスレッドに送る。呼び出し側には [`Future`] として結果を返すので、
+//! async サーバーのタスクをブロックせずにゲストの終了を待てる。
+//!
+//! 特定のランタイムには依存しない (返す Future は waker で起こすだけなので、
+//! tokio でも他のランタイムでも `.await` できる)。
+//!
+//! ```ignore
+//! let (sink, mut stream) = console_stream(FlushPolicy::Line);
+//! let vm = AsyncVm::spawn(move || {
+//!     let mut hv = Hypervisor::new(0x4000_0000, 128 * 1024 * 1024)?;
+//!     hv.register_mmio_handler(Box::new(Pl011Uart::with_console(UART_BASE, sink)))?;
+//!     Ok(hv)
+//! })
+//! .await?;
+//! let run = vm.run(None, None, Some(entry));
+//! let banner = stream.next_chunk().await; // ゲストの出力を待つ
+//! let result = run.await?;
+//! ```
+//!
+//! 制限:
+//! - Hypervisor.framework はプロセスごとに VM を 1 つしか作れない。複数の VM を
+//!   扱うサーバーは VM ごとにプロセスを分ける必要がある
+//! - デバイス (ディスク・ネットワーク) の処理は vCPU のスレッドで同期的に行われる。
+//!   async のディスク・ネットワークバックエンドはない
+//! - `Hypervisor` は作成したスレッドから動かせないため `Hypervisor::run_async` はなく、
+//!   [`AsyncVm::run`] がその代わりになる
+
+use crate::devices::console::{ConsoleSink, FlushPolicy};
+use crate::vcpu_handle::VcpuHandle;
+use crate::{Hypervisor, HypervisorResult};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, JoinHandle};
+
+/// vCPU スレッドで実行する処理
+type Job = Box<dyn FnOnce(&mut Hypervisor) + Send>;
+
+/// スレッドをまたぐエラー (`Box<dyn Error>` は `Send` ではない)
+fn to_error(message: String) -> Box<dyn Error> {
+    message.into()
+}
+
+struct OneshotState<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+/// 1 回だけ値を受け取る Future
+struct Oneshot<T> {
+    state: Arc<Mutex<OneshotState<T>>>,
+}
+
+/// [`Oneshot`] に値を送る側
+struct OneshotSender<T> {
+    state: Arc<Mutex<OneshotState<T>>>,
+}
+
+fn oneshot<T>() -> (OneshotSender<T>, Oneshot<T>) {
+    let state = Arc::new(Mutex::new(OneshotState {
+        value: None,
+        waker: None,
+        closed: false,
+    }));
+    (
+        OneshotSender {
+            state: state.clone(),
+        },
+        Oneshot { state },
+    )
+}
+
+impl<T> OneshotSender<T> {
+    fn send(self, value: T) {
+        self.state.lock().unwrap().value = Some(value);
+    }
+}
+
+impl<T> Drop for OneshotSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Future for Oneshot<T> {
+    /// 送る側が値を送らずに破棄された場合 (vCPU スレッドの終了) は None
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(value) = state.value.take() {
+            return Poll::Ready(Some(value));
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// 専用スレッドで動く VM
+pub struct AsyncVm {
+    jobs: Option<Sender<Job>>,
+    vcpu: VcpuHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AsyncVm {
+    /// 専用スレッドで `build` を呼んで VM を作成する
+    ///
+    /// デバイスの登録など `Hypervisor` の初期設定は `build` の中で行う。
+    pub fn spawn<F>(build: F) -> impl Future<Output = Result<AsyncVm, Box<dyn Error>>>
+    where
+        F: FnOnce() -> Result<Hypervisor, Box<dyn Error>> + Send + 'static,
+    {
+        let (ready_tx, ready) = oneshot();
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let thread = thread::Builder::new()
+            .name("vcpu0".to_string())
+            .spawn(move || {
+                let mut hv = match build() {
+                    Ok(hv) => hv,
+                    Err(e) => return ready_tx.send(Err(e.to_string())),
+                };
+                ready_tx.send(Ok(hv.vcpu_handle()));
+                for job in receiver {
+                    job(&mut hv);
+                }
+            });
+        async move {
+            let thread = thread?;
+            let vcpu = ready
+                .await
+                .ok_or("vCPU thread exited before the VM was created")?
+                .map_err(to_error)?;
+            Ok(AsyncVm {
+                jobs: Some(jobs),
+                vcpu,
+                thread: Some(thread),
+            })
+        }
+    }
+
+    /// vCPU スレッドで `f` を実行し、結果を返す
+    ///
+    /// `run` 中に呼ぶと、ゲストが VM Exit で `run` から戻るまで待たされる。
+    pub fn with<F, R>(&self, f: F) -> impl Future<Output = Result<R, Box<dyn Error>>>
+    where
+        F: FnOnce(&mut Hypervisor) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot();
+        let sent = self
+            .jobs
+            .as_ref()
+            .map(|jobs| jobs.send(Box::new(move |hv: &mut Hypervisor| tx.send(f(hv)))));
+        async move {
+            if !matches!(sent, Some(Ok(()))) {
+                return Err("vCPU thread has exited".into());
+            }
+            rx.await
+                .ok_or_else(|| "vCPU thread exited while handling a request".into())
+        }
+    }
+
+    /// [`Hypervisor::run`] を vCPU スレッドで実行する
+    pub fn run(
+        &self,
+        cpsr: Option<u64>,
+        trap_debug: Option<bool>,
+        pc: Option<u64>,
+    ) -> impl Future<Output = Result<HypervisorResult, Box<dyn Error>>> {
+        let result = self.with(move |hv| hv.run(cpsr, trap_debug, pc).map_err(|e| e.to_string()));
+        async move { result.await?.map_err(to_error) }
+    }
+
+    /// 実行中のゲストを止める (`run` は `ExitReason::CANCELED` で完了する)
+    pub fn request_stop(&self) -> Result<(), Box<dyn Error>> {
+        self.vcpu.request_stop()
+    }
+
+    /// vCPU のハンドル (コンソール入力の kick などに使う)
+    pub fn vcpu_handle(&self) -> VcpuHandle {
+        self.vcpu.clone()
+    }
+}
+
+impl Drop for AsyncVm {
+    /// VM を破棄する (実行中なら停止を要求し、vCPU スレッドの終了を待つ)
+    fn drop(&mut self) {
+        self.jobs = None;
+        let _ = self.vcpu.request_stop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Default)]
+struct StreamState {
+    chunks: VecDeque<Vec<u8>>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+/// UART の出力を受け取る async ストリーム
+///
+/// [`console_stream`] で作成した [`ConsoleSink`] に書かれた出力を、
+/// 書き出された単位 (チャンク) ごとに返す。
+pub struct ConsoleStream {
+    state: Arc<Mutex<StreamState>>,
+}
+
+/// [`ConsoleStream`] に書き込む出力先
+struct StreamWriter {
+    state: Arc<Mutex<StreamState>>,
+}
+
+impl Write for StreamWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        state.chunks.push_back(buf.to_vec());
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for StreamWriter {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// UART に渡す出力先と、その出力を読む async ストリームを作成する
+///
+/// 1 文字ごとにタスクが起こされないよう、`policy` には [`FlushPolicy::Line`] などを指定する。
+pub fn console_stream(policy: FlushPolicy) -> (ConsoleSink, ConsoleStream) {
+    let state = Arc::new(Mutex::new(StreamState::default()));
+    let writer = StreamWriter {
+        state: state.clone(),
+    };
+    (
+        ConsoleSink::new(Box::new(writer), policy),
+        ConsoleStream { state },
+    )
+}
+
+impl ConsoleStream {
+    /// 次の出力を待つ (出力先が破棄され、残りもなければ None)
+    pub fn next_chunk(&mut self) -> impl Future<Output = Option<Vec<u8>>> + '_ {
+        std::future::poll_fn(move |cx| {
+            let mut state = self.state.lock().unwrap();
+            if let Some(chunk) = state.chunks.pop_front() {
+                return Poll::Ready(Some(chunk));
+            }
+            if state.closed {
+                return Poll::Ready(None);
+            }
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+    }
+}
+
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// ランタイムを使わずに Future を完了まで進める
+///
+/// async ランタイムを持たないプログラムやテストから [`AsyncVm`] を使うための最小限の executor。
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+            return value;
+        }
+        thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oneshot_は別スレッドからの値で完了する() {
+        let (tx, rx) = oneshot();
+        let sender = thread::spawn(move || tx.send(42));
+        assert_eq!(block_on(rx), Some(42));
+        sender.join().unwrap();
+
+        // 値を送らずに破棄された
+        let (tx, rx) = oneshot::<u32>();
+        drop(tx);
+        assert_eq!(block_on(rx), None);
+    }
+
+    #[test]
+    fn コンソールの出力を行ごとに受け取る() {
+        let (mut sink, mut stream) = console_stream(FlushPolicy::Line);
+        let writer = thread::spawn(move || {
+            for &b in b"login: \nroot\n" {
+                sink.write_byte(b).unwrap();
+            }
+        });
+        assert_eq!(block_on(stream.next_chunk()).unwrap(), b"login: \n");
+        assert_eq!(block_on(stream.next_chunk()).unwrap(), b"root\n");
+        writer.join().unwrap();
+        assert_eq!(block_on(stream.next_chunk()), None);
+    }
+}
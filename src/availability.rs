@@ -0,0 +1,122 @@
+//! Hypervisor.framework を使えるかどうかの確認
+//!
+//! 初回実行時の失敗は applevisor のエラー (`operation not allowed by the system` など)
+//! だけでは原因が分かりにくい。VM の作成に失敗した場合は [`AvailabilityError`] で
+//! 原因と対処 (署名のコマンドなど) を返す。
+//! [`Hypervisor::check_availability`](crate::Hypervisor::check_availability) は
+//! VM を作成・破棄して、ゲストを用意する前に確認する。
+//!
+//! `cargo run` / `cargo test` は `.cargo/config.toml` の runner
+//! (`scripts/codesign-runner.sh`) が実行前にバイナリへ署名する。
+
+use applevisor::HypervisorError;
+use std::fmt;
+use std::path::PathBuf;
+
+/// リポジトリの entitlements (com.apple.security.hypervisor)
+const ENTITLEMENTS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/entitlements.plist");
+
+/// Hypervisor.framework を使えない理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unavailable {
+    /// 実行ファイルに com.apple.security.hypervisor の entitlement がない
+    MissingEntitlement,
+    /// ホストが Hypervisor.framework に対応していない
+    /// (Intel Mac、macOS 11 より前、入れ子の仮想化のない VM の中など)
+    Unsupported,
+    /// このプロセスに `Hypervisor` 以外で作成した VM がある
+    Busy,
+    /// その他の `hv_vm_create` のエラー
+    Other(HypervisorError),
+}
+
+/// VM を作成できなかった理由と対処
+#[derive(Debug, Clone)]
+pub struct AvailabilityError {
+    /// 理由
+    pub reason: Unavailable,
+    /// 実行中のバイナリ (署名のコマンドに使う)
+    executable: Option<PathBuf>,
+}
+
+impl AvailabilityError {
+    /// `hv_vm_create` のエラーから作成する
+    pub fn from_hv_error(error: HypervisorError) -> Self {
+        let reason = match error {
+            HypervisorError::Denied => Unavailable::MissingEntitlement,
+            HypervisorError::Unsupported => Unavailable::Unsupported,
+            HypervisorError::Busy => Unavailable::Busy,
+            other => Unavailable::Other(other),
+        };
+        Self {
+            reason,
+            executable: std::env::current_exe().ok(),
+        }
+    }
+}
+
+impl fmt::Display for AvailabilityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            Unavailable::MissingEntitlement => {
+                let executable = self
+                    .executable
+                    .as_ref()
+                    .map_or("<binary>".into(), |path| path.display().to_string());
+                write!(
+                    f,
+                    "Hypervisor.framework denied VM creation: the executable is not signed with \
+                     the com.apple.security.hypervisor entitlement\n  \
+                     sign it with: codesign --sign - --entitlements {} --force {}\n  \
+                     cargo run / cargo test sign automatically through scripts/codesign-runner.sh",
+                    ENTITLEMENTS, executable
+                )
+            }
+            Unavailable::Unsupported => f.write_str(
+                "Hypervisor.framework is not supported on this host: it requires Apple Silicon \
+                 with macOS 11 or later, and nested virtualization when running inside a VM",
+            ),
+            Unavailable::Busy => f.write_str(
+                "a VM created outside Hypervisor already exists in this process; \
+                 destroy it before creating a Hypervisor",
+            ),
+            Unavailable::Other(error) => write!(f, "hv_vm_create failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for AvailabilityError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entitlement_がない場合は署名のコマンドを示す() {
+        let err = AvailabilityError::from_hv_error(HypervisorError::Denied);
+        assert_eq!(err.reason, Unavailable::MissingEntitlement);
+        let message = err.to_string();
+        assert!(message.contains("com.apple.security.hypervisor"));
+        assert!(message.contains("codesign --sign - --entitlements"));
+        assert!(message.contains("entitlements.plist"));
+    }
+
+    #[test]
+    fn hv_のエラーを理由に分類する() {
+        let reason = |e| AvailabilityError::from_hv_error(e).reason;
+        assert_eq!(
+            reason(HypervisorError::Unsupported),
+            Unavailable::Unsupported
+        );
+        assert_eq!(reason(HypervisorError::Busy), Unavailable::Busy);
+        assert_eq!(
+            reason(HypervisorError::NoResources),
+            Unavailable::Other(HypervisorError::NoResources)
+        );
+        assert!(
+            AvailabilityError::from_hv_error(HypervisorError::Unsupported)
+                .to_string()
+                .contains("Apple Silicon")
+        );
+    }
+}
@@ -0,0 +1,654 @@
+//! vCPU と VM の操作の抽象化
+//!
+//! [`Hypervisor`](crate::Hypervisor) は Hypervisor.framework を直接呼ばず、
+//! [`VcpuBackend`] (レジスタ・実行・割り込み線) と [`VmBackend`]
+//! (VM の作成とゲストの物理アドレス空間へのマッピング) を通して操作する。
+//! 通常は applevisor の [`Vcpu`] と [`HvfVm`] を使う。
+//!
+//! [`MockVcpu`] と [`MockVm`] は `com.apple.security.hypervisor` エンタイトルメントの
+//! ない macOS (署名していないテストバイナリや CI のランナー) でも run ループを動かす
+//! ためのモックで、台本に書いた VM Exit を順に返す。例外の振り分け・MMIO・割り込みの
+//! 処理は実機と同じコードを通る。applevisor が Hypervisor.framework をリンクするため、
+//! macOS 以外ではモックでもリンクできない。
+//!
+//! ```ignore
+//! let vcpu = MockVcpu::new();
+//! vcpu.push_exit(MockExit::mmio_write(UART_BASE, 4, b'A' as u64));
+//! vcpu.push_exit(MockExit::brk());
+//! let mut hv = Hypervisor::with_backend(0x4000_0000, ram, &MockVm, Box::new(vcpu.clone()))?;
+//! let result = hv.run(None, None, None)?;
+//! ```
+
+use crate::availability::AvailabilityError;
+use crate::host_capabilities::HostCapabilities;
+use applevisor::{
+    ExitReason, HypervisorError, InterruptType, Reg, SimdFpReg, SysReg, Vcpu, VcpuExit,
+    VcpuExitException, VcpuInstance,
+};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// run ループが使う vCPU の操作
+///
+/// メソッドは applevisor の [`Vcpu`] と同じ名前とシグネチャを持つ。
+pub trait VcpuBackend {
+    /// VM Exit までゲストを実行する
+    fn run(&self) -> applevisor::Result<()>;
+    /// 直前の VM Exit の情報
+    fn get_exit_info(&self) -> VcpuExit;
+    fn get_reg(&self, reg: Reg) -> applevisor::Result<u64>;
+    fn set_reg(&self, reg: Reg, value: u64) -> applevisor::Result<()>;
+    /// 汎用レジスタ X0-X30 を番号順に取得する
+    ///
+    /// VM Exit ごとに呼ばれる。Hypervisor.framework には一括で読む API がないため、
+    /// 既定の実装は [`get_reg`](Self::get_reg) を 31 回呼ぶ (呼び出しの回数は減らない)。
+    fn read_gprs(&self) -> applevisor::Result<[u64; 31]> {
+        let mut registers = [0u64; 31];
+        for (index, value) in registers.iter_mut().enumerate() {
+            *value = self.get_reg(crate::REGISTER_TABLE[index])?;
+        }
+        Ok(registers)
+    }
+    fn get_sys_reg(&self, reg: SysReg) -> applevisor::Result<u64>;
+    fn set_sys_reg(&self, reg: SysReg, value: u64) -> applevisor::Result<()>;
+    fn get_simd_fp_reg(&self, reg: SimdFpReg) -> applevisor::Result<u128>;
+    fn set_simd_fp_reg(&self, reg: SimdFpReg, value: u128) -> applevisor::Result<()>;
+    /// IRQ / FIQ の割り込み線を設定する
+    fn set_pending_interrupt(&self, intr: InterruptType, pending: bool) -> applevisor::Result<()>;
+    fn set_trap_debug_exceptions(&self, value: bool) -> applevisor::Result<()>;
+    fn get_vtimer_offset(&self) -> applevisor::Result<u64>;
+    fn set_vtimer_offset(&self, offset: u64) -> applevisor::Result<()>;
+    /// ホストのハードウェアカウンタ (CNTVCT_EL0) の値
+    fn hardware_counter(&self) -> u64;
+    /// ゲストが読む CNTFRQ_EL0 (Hz)
+    ///
+    /// Hypervisor.framework は CNTFRQ_EL0 をトラップせず、ゲストにはホストの値が見える。
+    fn counter_frequency(&self) -> u64;
+    /// [`VcpuHandle`](crate::vcpu_handle::VcpuHandle) で外から止めるための instance
+    ///
+    /// 止める必要のない実装は `None` を返す。
+    fn instance(&self) -> Option<VcpuInstance>;
+}
+
+impl VcpuBackend for Vcpu {
+    fn run(&self) -> applevisor::Result<()> {
+        Vcpu::run(self)
+    }
+
+    fn get_exit_info(&self) -> VcpuExit {
+        Vcpu::get_exit_info(self)
+    }
+
+    fn get_reg(&self, reg: Reg) -> applevisor::Result<u64> {
+        Vcpu::get_reg(self, reg)
+    }
+
+    fn set_reg(&self, reg: Reg, value: u64) -> applevisor::Result<()> {
+        Vcpu::set_reg(self, reg, value)
+    }
+
+    fn get_sys_reg(&self, reg: SysReg) -> applevisor::Result<u64> {
+        Vcpu::get_sys_reg(self, reg)
+    }
+
+    fn set_sys_reg(&self, reg: SysReg, value: u64) -> applevisor::Result<()> {
+        Vcpu::set_sys_reg(self, reg, value)
+    }
+
+    fn get_simd_fp_reg(&self, reg: SimdFpReg) -> applevisor::Result<u128> {
+        Vcpu::get_simd_fp_reg(self, reg)
+    }
+
+    fn set_simd_fp_reg(&self, reg: SimdFpReg, value: u128) -> applevisor::Result<()> {
+        Vcpu::set_simd_fp_reg(self, reg, value)
+    }
+
+    fn set_pending_interrupt(&self, intr: InterruptType, pending: bool) -> applevisor::Result<()> {
+        Vcpu::set_pending_interrupt(self, intr, pending)
+    }
+
+    fn set_trap_debug_exceptions(&self, value: bool) -> applevisor::Result<()> {
+        Vcpu::set_trap_debug_exceptions(self, value)
+    }
+
+    fn get_vtimer_offset(&self) -> applevisor::Result<u64> {
+        Vcpu::get_vtimer_offset(self)
+    }
+
+    fn set_vtimer_offset(&self, offset: u64) -> applevisor::Result<()> {
+        Vcpu::set_vtimer_offset(self, offset)
+    }
+
+    fn hardware_counter(&self) -> u64 {
+        read_hardware_counter()
+    }
+
+    fn counter_frequency(&self) -> u64 {
+        read_counter_frequency()
+    }
+
+    fn instance(&self) -> Option<VcpuInstance> {
+        Some(self.get_instance())
+    }
+}
+
+/// ハードウェアカウンタを読み取る
+#[cfg(target_arch = "aarch64")]
+fn read_hardware_counter() -> u64 {
+    let counter: u64;
+    // SAFETY: CNTVCT_EL0 は EL0 から読める
+    unsafe {
+        std::arch::asm!("mrs {}, cntvct_el0", out(reg) counter);
+    }
+    counter
+}
+
+/// Apple Silicon 以外には CNTVCT_EL0 がない (実機の vCPU も作れないため使われない)
+#[cfg(not(target_arch = "aarch64"))]
+fn read_hardware_counter() -> u64 {
+    0
+}
+
+/// ホストのカウンタ周波数を読み取る
+#[cfg(target_arch = "aarch64")]
+fn read_counter_frequency() -> u64 {
+    let frequency: u64;
+    // SAFETY: CNTFRQ_EL0 は EL0 から読める
+    unsafe {
+        std::arch::asm!("mrs {}, cntfrq_el0", out(reg) frequency);
+    }
+    frequency
+}
+
+/// Apple Silicon 以外には CNTFRQ_EL0 がない (実機の vCPU も作れないため使われない)
+#[cfg(not(target_arch = "aarch64"))]
+fn read_counter_frequency() -> u64 {
+    crate::devices::timer::TIMER_FREQ
+}
+
+/// VM の作成・破棄とゲストメモリのマッピング
+///
+/// Hypervisor.framework の VM はプロセスに 1 つなので、実装は状態を持たず
+/// `&'static` で共有する。[`GuestRam`](crate::memory::GuestRam) はマッピングした
+/// バックエンドを覚えておき、破棄時の解除にも使う。
+pub trait VmBackend: Sync {
+    /// VM を作成する
+    fn create(&self) -> Result<(), Box<dyn Error>>;
+    /// VM を破棄する
+    fn destroy(&self) -> Result<(), Box<dyn Error>>;
+    /// ホストのメモリをゲストの物理アドレス空間にマッピングする (RWX)
+    fn map(&self, host_addr: *const u8, guest_addr: u64, size: usize)
+        -> Result<(), Box<dyn Error>>;
+    /// マッピングを解除する
+    fn unmap(&self, guest_addr: u64, size: usize) -> Result<(), Box<dyn Error>>;
+    /// ゲストからの書き込みを許可・禁止する (読み取りと実行は常に許可)
+    fn protect(&self, guest_addr: u64, size: usize, writable: bool) -> Result<(), Box<dyn Error>>;
+    /// ホストが扱える VM の範囲 (IPA 幅、vCPU の最大数など)
+    fn capabilities(&self) -> Result<HostCapabilities, Box<dyn Error>>;
+}
+
+/// Hypervisor.framework の戻り値 (成功)
+const HV_SUCCESS: i32 = 0;
+
+/// プロセス内に VM が存在するかどうか
+///
+/// Hypervisor.framework は 1 プロセスにつき 1 つの VM しか作成できないため、
+/// 二重作成をフレームワークのエラーより先に検出する。
+static VM_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Hypervisor.framework の VM
+#[derive(Debug)]
+pub struct HvfVm;
+
+impl HvfVm {
+    /// VM を作成してすぐ破棄し、このプロセスで VM を作成できるか確認する
+    ///
+    /// すでに `Hypervisor` が存在する場合は作成できているので確認しない。
+    pub fn probe(&self) -> Result<(), AvailabilityError> {
+        if VM_ACTIVE
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Ok(());
+        }
+        let ret = unsafe { applevisor_sys::hv_vm_create(std::ptr::null_mut()) };
+        if ret == HV_SUCCESS {
+            unsafe { applevisor_sys::hv_vm_destroy() };
+        }
+        VM_ACTIVE.store(false, Ordering::SeqCst);
+        match ret {
+            HV_SUCCESS => Ok(()),
+            code => Err(AvailabilityError::from_hv_error(HypervisorError::from(
+                code,
+            ))),
+        }
+    }
+}
+
+impl VmBackend for HvfVm {
+    fn create(&self) -> Result<(), Box<dyn Error>> {
+        if VM_ACTIVE
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err("A Hypervisor already exists in this process \
+                 (Hypervisor.framework allows one VM per process); \
+                 call shutdown() or drop it before creating another"
+                .into());
+        }
+        let ret = unsafe { applevisor_sys::hv_vm_create(std::ptr::null_mut()) };
+        if ret != HV_SUCCESS {
+            VM_ACTIVE.store(false, Ordering::SeqCst);
+            return Err(AvailabilityError::from_hv_error(HypervisorError::from(ret)).into());
+        }
+        Ok(())
+    }
+
+    fn destroy(&self) -> Result<(), Box<dyn Error>> {
+        let ret = unsafe { applevisor_sys::hv_vm_destroy() };
+        VM_ACTIVE.store(false, Ordering::SeqCst);
+        if ret != HV_SUCCESS {
+            return Err("Failed to destroy VM".into());
+        }
+        Ok(())
+    }
+
+    fn map(
+        &self,
+        host_addr: *const u8,
+        guest_addr: u64,
+        size: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let ret = unsafe {
+            applevisor_sys::hv_vm_map(
+                host_addr as *const c_void,
+                guest_addr,
+                size,
+                applevisor_sys::HV_MEMORY_READ
+                    | applevisor_sys::HV_MEMORY_WRITE
+                    | applevisor_sys::HV_MEMORY_EXEC,
+            )
+        };
+        if ret != HV_SUCCESS {
+            return Err(format!(
+                "hv_vm_map failed for guest RAM at 0x{:x} (error 0x{:x})",
+                guest_addr, ret
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn unmap(&self, guest_addr: u64, size: usize) -> Result<(), Box<dyn Error>> {
+        let ret = unsafe { applevisor_sys::hv_vm_unmap(guest_addr, size) };
+        if ret != HV_SUCCESS {
+            return Err(format!("hv_vm_unmap failed (error 0x{:x})", ret).into());
+        }
+        Ok(())
+    }
+
+    fn protect(&self, guest_addr: u64, size: usize, writable: bool) -> Result<(), Box<dyn Error>> {
+        let mut flags = applevisor_sys::HV_MEMORY_READ | applevisor_sys::HV_MEMORY_EXEC;
+        if writable {
+            flags |= applevisor_sys::HV_MEMORY_WRITE;
+        }
+        let ret = unsafe { applevisor_sys::hv_vm_protect(guest_addr, size, flags) };
+        if ret != HV_SUCCESS {
+            return Err(format!(
+                "hv_vm_protect failed for 0x{:x}+0x{:x} (error 0x{:x})",
+                guest_addr, size, ret
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Result<HostCapabilities, Box<dyn Error>> {
+        HostCapabilities::query()
+    }
+}
+
+/// 何もしない VM (ゲストメモリはホスト側からだけ読み書きする)
+///
+/// 複数作成でき、同じプロセスで [`HvfVm`] と併用できる。
+#[derive(Debug)]
+pub struct MockVm;
+
+impl VmBackend for MockVm {
+    fn create(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn destroy(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn map(&self, _: *const u8, _: u64, _: usize) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn unmap(&self, _: u64, _: usize) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn protect(&self, _: u64, _: usize, _: bool) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// 40-bit IPA、16KB の stage-2 ページ、vCPU は GICv2 の上限まで
+    fn capabilities(&self) -> Result<HostCapabilities, Box<dyn Error>> {
+        Ok(HostCapabilities {
+            ipa_bits: 40,
+            max_ipa_bits: 40,
+            stage2_page_size: applevisor::PAGE_SIZE as u64,
+            max_vcpus: crate::vm_config::MAX_VCPUS,
+            mmfr0: None,
+        })
+    }
+}
+
+/// [`MockVcpu`] が返す VM Exit 1 回分
+///
+/// `run()` は VM Exit を返す前に、ゲストが実行したことにするレジスタの
+/// 書き込みを反映する。
+#[derive(Debug, Clone)]
+pub struct MockExit {
+    exit: VcpuExit,
+    regs: Vec<(Reg, u64)>,
+    sys_regs: Vec<(SysReg, u64)>,
+}
+
+impl MockExit {
+    /// 例外による VM Exit
+    ///
+    /// # Arguments
+    /// * `syndrome` - ESR_EL2 の値
+    /// * `physical_address` - フォルトした IPA (Data Abort 以外は 0)
+    pub fn exception(syndrome: u64, physical_address: u64) -> Self {
+        Self::new(ExitReason::EXCEPTION, syndrome, physical_address)
+    }
+
+    /// MMIO への書き込み (`STR Xn/Wn, [addr]`, 値は X1 から)
+    pub fn mmio_write(addr: u64, size: usize, value: u64) -> Self {
+        Self::exception(data_abort_syndrome(size, 1, true), addr).reg(Reg::X1, value)
+    }
+
+    /// MMIO からの読み取り (`LDR Xn/Wn, [addr]`, 値は X0 へ)
+    pub fn mmio_read(addr: u64, size: usize) -> Self {
+        Self::exception(data_abort_syndrome(size, 0, false), addr)
+    }
+
+    /// `HVC #0` (X0 に関数 ID)
+    pub fn hvc(function_id: u64) -> Self {
+        Self::exception(0x16 << 26 | 1 << 25, 0).reg(Reg::X0, function_id)
+    }
+
+    /// `WFI`
+    pub fn wfi() -> Self {
+        Self::exception(0x01 << 26 | 1 << 25, 0)
+    }
+
+    /// `BRK #0` (run ループはこの VM Exit で戻る)
+    pub fn brk() -> Self {
+        Self::exception(0x3c << 26 | 1 << 25, 0)
+    }
+
+    /// 仮想タイマーの発火 (`VTIMER_ACTIVATED`)
+    pub fn vtimer() -> Self {
+        Self::new(ExitReason::VTIMER_ACTIVATED, 0, 0)
+    }
+
+    /// VM Exit の前にゲストが汎用レジスタに書き込んだことにする
+    pub fn reg(mut self, reg: Reg, value: u64) -> Self {
+        self.regs.push((reg, value));
+        self
+    }
+
+    /// VM Exit の前にゲストがシステムレジスタに書き込んだことにする
+    pub fn sys_reg(mut self, reg: SysReg, value: u64) -> Self {
+        self.sys_regs.push((reg, value));
+        self
+    }
+
+    fn new(reason: ExitReason, syndrome: u64, physical_address: u64) -> Self {
+        Self {
+            exit: VcpuExit {
+                reason,
+                exception: VcpuExitException {
+                    syndrome,
+                    virtual_address: physical_address,
+                    physical_address,
+                },
+            },
+            regs: Vec::new(),
+            sys_regs: Vec::new(),
+        }
+    }
+}
+
+/// Data Abort (ISV=1, 64-bit レジスタ) の ESR_EL2
+fn data_abort_syndrome(size: usize, srt: u64, write: bool) -> u64 {
+    let sas = size.trailing_zeros() as u64;
+    0x24 << 26 | 1 << 25 | 1 << 24 | sas << 22 | srt << 16 | 1 << 15 | (write as u64) << 6
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    script: VecDeque<MockExit>,
+    exit: Option<VcpuExit>,
+    regs: HashMap<Reg, u64>,
+    sys_regs: HashMap<SysReg, u64>,
+    simd_fp_regs: HashMap<SimdFpReg, u128>,
+    irq: bool,
+    fiq: bool,
+    vtimer_offset: u64,
+    counter: u64,
+    counter_frequency: Option<u64>,
+    runs: u64,
+}
+
+/// 台本の VM Exit を順に返す vCPU
+///
+/// clone したハンドルは同じ状態を共有するため、`Hypervisor` に渡した後も
+/// 台本を足したりレジスタや割り込み線を確認したりできる。台本を使い切った後の
+/// `run()` はエラーを返す。
+#[derive(Debug, Clone, Default)]
+pub struct MockVcpu {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockVcpu {
+    /// 台本が空の vCPU を作成
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 台本の末尾に VM Exit を追加する
+    pub fn push_exit(&self, exit: MockExit) {
+        self.lock().script.push_back(exit);
+    }
+
+    /// 汎用レジスタの現在値 (書き込まれていなければ 0)
+    pub fn reg(&self, reg: Reg) -> u64 {
+        self.lock().regs.get(&reg).copied().unwrap_or(0)
+    }
+
+    /// システムレジスタの現在値 (書き込まれていなければ 0)
+    pub fn sys_reg(&self, reg: SysReg) -> u64 {
+        self.lock().sys_regs.get(&reg).copied().unwrap_or(0)
+    }
+
+    /// IRQ 線がアサートされているか
+    pub fn irq_pending(&self) -> bool {
+        self.lock().irq
+    }
+
+    /// `hardware_counter()` が返す値を設定する
+    pub fn set_counter(&self, counter: u64) {
+        self.lock().counter = counter;
+    }
+
+    /// `counter_frequency()` が返す値を設定する (既定は [`TIMER_FREQ`](crate::devices::timer::TIMER_FREQ))
+    pub fn set_counter_frequency(&self, frequency: u64) {
+        self.lock().counter_frequency = Some(frequency);
+    }
+
+    /// これまでに `run()` した回数
+    pub fn runs(&self) -> u64 {
+        self.lock().runs
+    }
+
+    /// 台本に残っている VM Exit の数
+    pub fn remaining(&self) -> usize {
+        self.lock().script.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl VcpuBackend for MockVcpu {
+    fn run(&self) -> applevisor::Result<()> {
+        let mut state = self.lock();
+        let next = state
+            .script
+            .pop_front()
+            .ok_or(HypervisorError::IllegalState)?;
+        state.regs.extend(next.regs);
+        state.sys_regs.extend(next.sys_regs);
+        state.exit = Some(next.exit);
+        state.runs += 1;
+        Ok(())
+    }
+
+    fn get_exit_info(&self) -> VcpuExit {
+        self.lock()
+            .exit
+            .clone()
+            .unwrap_or_else(|| MockExit::new(ExitReason::UNKNOWN, 0, 0).exit)
+    }
+
+    fn get_reg(&self, reg: Reg) -> applevisor::Result<u64> {
+        Ok(self.reg(reg))
+    }
+
+    fn set_reg(&self, reg: Reg, value: u64) -> applevisor::Result<()> {
+        self.lock().regs.insert(reg, value);
+        Ok(())
+    }
+
+    fn get_sys_reg(&self, reg: SysReg) -> applevisor::Result<u64> {
+        Ok(self.sys_reg(reg))
+    }
+
+    fn set_sys_reg(&self, reg: SysReg, value: u64) -> applevisor::Result<()> {
+        self.lock().sys_regs.insert(reg, value);
+        Ok(())
+    }
+
+    fn get_simd_fp_reg(&self, reg: SimdFpReg) -> applevisor::Result<u128> {
+        Ok(self.lock().simd_fp_regs.get(&reg).copied().unwrap_or(0))
+    }
+
+    fn set_simd_fp_reg(&self, reg: SimdFpReg, value: u128) -> applevisor::Result<()> {
+        self.lock().simd_fp_regs.insert(reg, value);
+        Ok(())
+    }
+
+    fn set_pending_interrupt(&self, intr: InterruptType, pending: bool) -> applevisor::Result<()> {
+        let mut state = self.lock();
+        match intr {
+            InterruptType::IRQ => state.irq = pending,
+            InterruptType::FIQ => state.fiq = pending,
+        }
+        Ok(())
+    }
+
+    fn set_trap_debug_exceptions(&self, _: bool) -> applevisor::Result<()> {
+        Ok(())
+    }
+
+    fn get_vtimer_offset(&self) -> applevisor::Result<u64> {
+        Ok(self.lock().vtimer_offset)
+    }
+
+    fn set_vtimer_offset(&self, offset: u64) -> applevisor::Result<()> {
+        self.lock().vtimer_offset = offset;
+        Ok(())
+    }
+
+    fn hardware_counter(&self) -> u64 {
+        self.lock().counter
+    }
+
+    fn counter_frequency(&self) -> u64 {
+        self.lock()
+            .counter_frequency
+            .unwrap_or(crate::devices::timer::TIMER_FREQ)
+    }
+
+    fn instance(&self) -> Option<VcpuInstance> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn 台本の順に_vm_exit_を返す() {
+        let vcpu = MockVcpu::new();
+        vcpu.push_exit(MockExit::hvc(0x8400_0000));
+        vcpu.push_exit(MockExit::brk());
+        let backend: Box<dyn VcpuBackend> = Box::new(vcpu.clone());
+
+        backend.run().unwrap();
+        let exit = backend.get_exit_info();
+        assert_eq!(exit.reason, ExitReason::EXCEPTION);
+        assert_eq!((exit.exception.syndrome >> 26) & 0x3f, 0x16);
+        assert_eq!(backend.get_reg(Reg::X0).unwrap(), 0x8400_0000);
+
+        backend.run().unwrap();
+        assert_eq!(
+            (backend.get_exit_info().exception.syndrome >> 26) & 0x3f,
+            0x3c
+        );
+        assert_eq!(vcpu.runs(), 2);
+        // 台本を使い切ったら止める
+        assert!(backend.run().is_err());
+    }
+
+    #[test]
+    fn 汎用レジスタを番号順に読む() {
+        let vcpu = MockVcpu::new();
+        vcpu.set_reg(Reg::X0, 1).unwrap();
+        vcpu.set_reg(Reg::X17, 0x17).unwrap();
+        vcpu.set_reg(Reg::X30, 0x30).unwrap();
+        let registers = vcpu.read_gprs().unwrap();
+        assert_eq!(registers[0], 1);
+        assert_eq!(registers[17], 0x17);
+        assert_eq!(registers[30], 0x30);
+        assert_eq!(crate::gpr(30), Some(Reg::X30));
+        assert_eq!(crate::gpr(31), None);
+    }
+
+    #[test]
+    fn mmio_の_syndrome_はサイズと転送レジスタを表す() {
+        let write = MockExit::mmio_write(0x0900_0000, 4, 0x41);
+        let iss = write.exit.exception.syndrome & 0x1FF_FFFF;
+        assert_eq!((iss >> 22) & 0x3, 2);
+        assert_eq!((iss >> 16) & 0x1f, 1);
+        assert_ne!(iss & (1 << 6), 0);
+        assert_eq!(write.exit.exception.physical_address, 0x0900_0000);
+
+        let read = MockExit::mmio_read(0x0900_0018, 8);
+        let iss = read.exit.exception.syndrome & 0x1FF_FFFF;
+        assert_eq!((iss >> 22) & 0x3, 3);
+        assert_eq!(iss & (1 << 6), 0);
+    }
+}
@@ -0,0 +1,9 @@
+//! 1 つの VM を動かすワーカープロセス
+//!
+//! 標準入力から制御プロトコルの要求を読み、応答とゲストのコンソール出力を
+//! 標準出力に書く。`hypervisor::orchestration::VmPool` から起動される。
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let stdin = std::io::stdin();
+    hypervisor::orchestration::worker::serve(stdin.lock(), std::io::stdout())
+}
@@ -0,0 +1,183 @@
+//! カーネルコマンドラインのコンソール指定の補完
+//!
+//! `console=` を付け忘れたカーネルは、Device Tree の `stdout-path` を使わない構成
+//! (`CONFIG_SERIAL_AMBA_PL011_CONSOLE` なしなど) では何も出力しないまま起動し、
+//! `earlycon` がないと起動初期に止まった場合の手がかりも残らない。
+//! [`complete_cmdline`] は、コマンドラインにない方だけを UART の種類と
+//! ベースアドレスから作って補う。
+//!
+//! ```text
+//! "root=/dev/vda"  →  "root=/dev/vda console=ttyAMA0 earlycon=pl011,mmio32,0x9000000"
+//! ```
+//!
+//! `--` 以降は init への引数なので、見るのも補うのもその前だけ。
+//! カーネルと同じく `"` で囲んだ部分は空白を含んでも 1 つのパラメータとして扱い、
+//! 元のコマンドラインは書き換えずに末尾へ足すだけにする。
+
+/// ゲストのコンソールにする UART の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartType {
+    /// ARM PL011 ([`crate::devices::uart`])
+    Pl011,
+    /// 16550 互換 (`add_device` などで追加したもの)
+    Ns16550,
+}
+
+impl UartType {
+    /// `console=` に指定するデバイス名
+    pub fn console(self) -> &'static str {
+        match self {
+            UartType::Pl011 => "ttyAMA0",
+            UartType::Ns16550 => "ttyS0",
+        }
+    }
+
+    /// `earlycon=` に指定する値
+    pub fn earlycon(self, base: u64) -> String {
+        match self {
+            UartType::Pl011 => format!("pl011,mmio32,0x{:x}", base),
+            UartType::Ns16550 => format!("uart8250,mmio32,0x{:x}", base),
+        }
+    }
+}
+
+/// `boot_linux` でコマンドラインを補完するか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GuestConsole {
+    /// マシンレイアウトの位置に PL011 を登録していれば使う (なければ補完しない)
+    #[default]
+    Auto,
+    /// 指定した UART を使う
+    Uart {
+        /// 種類
+        kind: UartType,
+        /// ベースアドレス
+        base: u64,
+    },
+    /// 補完しない (コマンドラインをそのまま渡す)
+    Off,
+}
+
+/// `console=` と `earlycon` のうちコマンドラインにない方を補う
+pub fn complete_cmdline(cmdline: &str, kind: UartType, base: u64) -> String {
+    let params = split_params(cmdline);
+    let (kernel, init) = match params.iter().find(|&&(_, p)| p == "--") {
+        Some(&(start, _)) => (&cmdline[..start], Some(&cmdline[start..])),
+        None => (cmdline, None),
+    };
+    let params: Vec<&str> = params
+        .iter()
+        .take_while(|&&(_, p)| p != "--")
+        .map(|&(_, p)| p)
+        .collect();
+
+    let mut completed = kernel.trim_end().to_string();
+    let mut push = |param: String| {
+        if !completed.is_empty() {
+            completed.push(' ');
+        }
+        completed.push_str(&param);
+    };
+    if !params.iter().any(|p| p.starts_with("console=")) {
+        push(format!("console={}", kind.console()));
+    }
+    if !params
+        .iter()
+        .any(|&p| p == "earlycon" || p.starts_with("earlycon="))
+    {
+        push(format!("earlycon={}", kind.earlycon(base)));
+    }
+    if let Some(init) = init {
+        push(init.to_string());
+    }
+    completed
+}
+
+/// コマンドラインをパラメータ (開始位置と内容) に分ける
+///
+/// カーネルの `next_arg` と同じく、`"` の内側の空白では区切らない。
+fn split_params(cmdline: &str) -> Vec<(usize, &str)> {
+    let mut params = Vec::new();
+    let mut start = None;
+    let mut in_quote = false;
+    for (i, c) in cmdline.char_indices() {
+        if c == '"' {
+            in_quote = !in_quote;
+        }
+        if c.is_whitespace() && !in_quote {
+            if let Some(s) = start.take() {
+                params.push((s, &cmdline[s..i]));
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        params.push((s, &cmdline[s..]));
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ないものだけを補う() {
+        assert_eq!(
+            complete_cmdline("root=/dev/vda rw", UartType::Pl011, 0x0900_0000),
+            "root=/dev/vda rw console=ttyAMA0 earlycon=pl011,mmio32,0x9000000"
+        );
+        assert_eq!(
+            complete_cmdline("", UartType::Ns16550, 0x0a20_0000),
+            "console=ttyS0 earlycon=uart8250,mmio32,0xa200000"
+        );
+        // 指定済みなら変えない
+        assert_eq!(
+            complete_cmdline("console=hvc0 earlycon", UartType::Pl011, 0x0900_0000),
+            "console=hvc0 earlycon"
+        );
+        assert_eq!(
+            complete_cmdline("console=ttyAMA0", UartType::Pl011, 0x0900_0000),
+            "console=ttyAMA0 earlycon=pl011,mmio32,0x9000000"
+        );
+        // 「netconsole=」は console= ではない
+        assert!(
+            complete_cmdline("netconsole=@/,@1.2.3.4/ earlycon", UartType::Pl011, 0)
+                .contains(" console=ttyAMA0")
+        );
+    }
+
+    #[test]
+    fn init_への引数には触れない() {
+        assert_eq!(
+            complete_cmdline("rdinit=/init -- console=x", UartType::Pl011, 0x0900_0000),
+            "rdinit=/init console=ttyAMA0 earlycon=pl011,mmio32,0x9000000 -- console=x"
+        );
+        assert_eq!(
+            complete_cmdline("-- single", UartType::Pl011, 0x0900_0000),
+            "console=ttyAMA0 earlycon=pl011,mmio32,0x9000000 -- single"
+        );
+    }
+
+    #[test]
+    fn 引用符の内側は書き換えない() {
+        assert_eq!(
+            complete_cmdline(
+                "dyndbg=\"file  drivers/* +p\" rw",
+                UartType::Pl011,
+                0x0900_0000
+            ),
+            "dyndbg=\"file  drivers/* +p\" rw console=ttyAMA0 earlycon=pl011,mmio32,0x9000000"
+        );
+        // 引用符の中の「 -- 」や「console=」は区切りでもコンソール指定でもない
+        assert_eq!(
+            complete_cmdline(
+                "init.args=\"a -- console=x\" -- b  c",
+                UartType::Pl011,
+                0x0900_0000
+            ),
+            "init.args=\"a -- console=x\" console=ttyAMA0 earlycon=pl011,mmio32,0x9000000 -- b  c"
+        );
+    }
+}
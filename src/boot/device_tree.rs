@@ -1,7 +1,29 @@
 //! Device Tree (FDT) generation for ARM64 Linux boot
 
+use super::layout::{dt_interrupt, IrqMap, MachineLayout, VIRTIO_SLOT_SIZE};
+use crate::devices::clock::{WALL_CLOCK_COMPATIBLE, WALL_CLOCK_SIZE};
+use crate::devices::gic::{GIC_HYP_SIZE, GIC_VCPU_SIZE};
+use crate::devices::pl330::PL330_SIZE;
+use crate::devices::scmi::{protocol, SCMI_SHMEM_SIZE, SCMI_SMC_ID};
+use crate::devices::smmu::SMMU_SIZE;
+use crate::vm_config::{check_vcpu_count, mpidr_affinity};
 use std::error::Error;
-use vm_fdt::FdtWriter;
+use vm_fdt::{FdtReserveEntry, FdtWriter};
+
+/// phandle of the GIC node
+const GIC_PHANDLE: u32 = 1;
+/// phandle of the fixed APB clock feeding the PL011
+const APB_PCLK_PHANDLE: u32 = 2;
+/// phandle of the SCMI shared memory node
+const SCMI_SHMEM_PHANDLE: u32 = 3;
+/// phandle of the SCMI power domain provider (`power-domains = <&phandle id>`)
+pub const SCMI_POWER_PHANDLE: u32 = 4;
+/// phandle of the SCMI clock provider (`clocks = <&phandle id>`)
+pub const SCMI_CLOCK_PHANDLE: u32 = 5;
+/// Console options appended to `stdout-path` (baud, parity, bits)
+const STDOUT_OPTIONS: &str = "115200n8";
+/// Size of `/chosen/rng-seed` in bytes (same as QEMU)
+const RNG_SEED_SIZE: usize = 32;
 
 /// Device Tree configuration
 #[derive(Debug, Clone)]
@@ -10,20 +32,79 @@ pub struct DeviceTreeConfig {
     pub memory_base: u64,
     /// Memory size in bytes (e.g., 0x8000000 = 128MB)
     pub memory_size: u64,
+    /// Number of `cpu@N` nodes (see [`crate::vm_config::VmConfig::vcpus`])
+    ///
+    /// Each node's `reg` is the CPU's MPIDR affinity. More than one CPU
+    /// requires the `smp` feature.
+    pub cpus: u32,
     /// UART base address (typically 0x09000000)
     pub uart_base: u64,
-    /// VirtIO Block device base address (typically 0x0a000000)
+    /// Base address of the first VirtIO MMIO transport (typically 0x0a000000)
     pub virtio_base: u64,
+    /// Number of VirtIO MMIO transports to declare
+    ///
+    /// Transports are laid out every 0x200 bytes from `virtio_base` and use
+    /// consecutive interrupts from `irqs.virtio`, like QEMU's `virt` machine.
+    /// Slots without a device read as device ID 0 and are skipped by the driver.
+    pub virtio_slots: u32,
     /// GIC Distributor base address (typically 0x08000000)
     pub gic_dist_base: u64,
     /// GIC CPU Interface base address (typically 0x08010000)
     pub gic_cpu_base: u64,
+    /// GIC virtual interface control (GICH) base address (typically 0x08030000)
+    pub gic_hyp_base: u64,
+    /// GIC virtual CPU interface (GICV) base address (typically 0x08040000)
+    pub gic_vcpu_base: u64,
     /// Kernel command line
     pub cmdline: String,
     /// initramfs start address (optional)
     pub initrd_start: Option<u64>,
     /// initramfs end address (optional)
     pub initrd_end: Option<u64>,
+    /// Guest address the DTB will be placed at (optional)
+    ///
+    /// When set, the DTB itself is covered by a `/memreserve/` entry.
+    pub dtb_addr: Option<u64>,
+    /// PL011 reference clock frequency in Hz (exposed as a fixed-clock node)
+    pub uart_clock_hz: u32,
+    /// Root node `compatible` string (board identification)
+    pub compatible: String,
+    /// Root node `model` string
+    pub model: String,
+    /// Populate `/chosen/rng-seed` and `kaslr-seed` with host entropy
+    ///
+    /// Disable to get a byte-for-byte reproducible DTB.
+    pub seed_entropy: bool,
+    /// Host wall-clock device base address (optional)
+    ///
+    /// See [`crate::devices::clock`] for the guest-side protocol.
+    pub wall_clock_base: Option<u64>,
+    /// SCMI shared memory base address (optional)
+    ///
+    /// Adds the `arm,scmi-smc` firmware node with clock and power domain
+    /// providers, plus a `psci` node so the guest uses HVC as the SMCCC
+    /// conduit. See [`crate::devices::scmi`].
+    pub scmi_shmem_base: Option<u64>,
+    /// PL330 DMA controller stub base address (optional)
+    ///
+    /// For kernels built with the PL330 DMA engine driver; the stub never
+    /// performs transfers. See [`crate::devices::pl330`].
+    pub pl330_base: Option<u64>,
+    /// Persistent memory regions as `(base, size, volatile)` (see [`crate::Hypervisor::add_pmem`])
+    ///
+    /// Each becomes a `pmem-region` node; Linux (`CONFIG_OF_PMEM`) exposes it
+    /// as a DAX-capable `/dev/pmemN`. `volatile` marks regions whose writes
+    /// are not persisted.
+    pub pmem_regions: Vec<(u64, u64, bool)>,
+    /// Nodes of out-of-tree devices (see [`crate::Hypervisor::add_device`])
+    pub device_nodes: Vec<hypervisor_device::DtNode>,
+    /// SMMUv3 stub base address (optional)
+    ///
+    /// For kernels built with `CONFIG_ARM_SMMU_V3`; the stub never translates
+    /// and no device node references it. See [`crate::devices::smmu`].
+    pub smmu_base: Option<u64>,
+    /// Interrupt assignment for the timer, UART and VirtIO nodes
+    pub irqs: IrqMap,
 }
 
 impl Default for DeviceTreeConfig {
@@ -31,27 +112,99 @@ impl Default for DeviceTreeConfig {
         Self {
             memory_base: 0x4000_0000,
             memory_size: 0x800_0000, // 128MB
+            cpus: 1,
             uart_base: 0x0900_0000,
             virtio_base: 0x0a00_0000,
+            virtio_slots: 1,
             gic_dist_base: 0x0800_0000,
             gic_cpu_base: 0x0801_0000,
+            gic_hyp_base: 0x0803_0000,
+            gic_vcpu_base: 0x0804_0000,
             cmdline: "console=ttyAMA0 root=/dev/vda rw".to_string(),
             initrd_start: None,
             initrd_end: None,
+            dtb_addr: None,
+            uart_clock_hz: 24_000_000,
+            compatible: "linux,dummy-virt".to_string(),
+            model: "hypervisor-virt".to_string(),
+            seed_entropy: true,
+            wall_clock_base: None,
+            scmi_shmem_base: None,
+            pl330_base: None,
+            pmem_regions: Vec::new(),
+            device_nodes: Vec::new(),
+            smmu_base: None,
+            irqs: IrqMap::QEMU_VIRT,
+        }
+    }
+}
+
+impl DeviceTreeConfig {
+    /// Build a configuration from a [`MachineLayout`]
+    ///
+    /// # Arguments
+    /// * `layout` - Guest physical memory map
+    /// * `memory_size` - RAM size in bytes
+    /// * `cmdline` - Kernel command line
+    pub fn from_layout(layout: &MachineLayout, memory_size: u64, cmdline: &str) -> Self {
+        Self {
+            memory_base: layout.ram_base,
+            memory_size,
+            uart_base: layout.uart_base,
+            virtio_base: layout.virtio_base,
+            virtio_slots: layout.virtio_slots,
+            gic_dist_base: layout.gic_dist_base,
+            gic_cpu_base: layout.gic_cpu_base,
+            gic_hyp_base: layout.gic_hyp_base(),
+            gic_vcpu_base: layout.gic_vcpu_base(),
+            cmdline: cmdline.to_string(),
+            initrd_start: None,
+            initrd_end: None,
+            uart_clock_hz: layout.uart_clock_hz,
+            irqs: layout.irqs,
+            ..Default::default()
+        }
+    }
+
+    /// Board variant matching Zephyr's `qemu_cortex_a53` board
+    ///
+    /// Zephyr embeds its own devicetree at build time, but bootloaders and
+    /// tooling that inspect the DTB expect the board identification to match.
+    /// The memory map is identical to [`MachineLayout::QEMU_VIRT`]. Load the
+    /// generated DTB with `Hypervisor::load_blob` and pass its address in X0 to
+    /// `Hypervisor::boot_bare_metal`.
+    pub fn zephyr_qemu_cortex_a53() -> Self {
+        Self {
+            compatible: "qemu,arm-cortex-a53".to_string(),
+            model: "QEMU Cortex-A53".to_string(),
+            cmdline: String::new(),
+            ..Default::default()
         }
     }
 }
 
 /// Generate a Device Tree binary for ARM64 Linux boot
 ///
+/// The DTB (when `dtb_addr` is set) and the initramfs are listed in the
+/// memory reservation block, so the kernel never allocates over them before
+/// it has parsed `linux,initrd-end`.
+///
 /// Creates a minimal Device Tree with:
 /// - CPU node (single ARM64 CPU)
 /// - Memory node
 /// - GICv2 interrupt controller node
 /// - Timer node (ARM Generic Timer)
+/// - Fixed APB clock node (PL011 reference clock)
 /// - UART (PL011) node
-/// - VirtIO Block device node
-/// - chosen node with bootargs
+/// - VirtIO MMIO transport nodes (one per slot, `dma-coherent`)
+/// - Host wall-clock device node (when `wall_clock_base` is set)
+/// - PSCI and SCMI firmware nodes (when `scmi_shmem_base` is set)
+/// - PL330 DMA controller stub node (when `pl330_base` is set)
+/// - SMMUv3 stub node (when `smmu_base` is set)
+/// - `pmem-region` nodes (one per `pmem_regions` entry)
+/// - out-of-tree device nodes (`device_nodes`)
+/// - aliases node (serial0)
+/// - chosen node with bootargs (and entropy seeds)
 ///
 /// # Arguments
 /// * `config` - Device Tree configuration
@@ -59,29 +212,62 @@ impl Default for DeviceTreeConfig {
 /// # Returns
 /// Device Tree binary (FDT blob)
 pub fn generate_device_tree(config: &DeviceTreeConfig) -> Result<Vec<u8>, Box<dyn Error>> {
-    let mut fdt = FdtWriter::new()?;
+    check_vcpu_count(config.cpus)?;
+    config.irqs.validate()?;
+    config.irqs.validate_virtio_slots(config.virtio_slots)?;
+    // Reservation entries have a fixed size, so a first pass with a placeholder
+    // DTB entry tells us how large the final blob will be.
+    // (vm-fdt rejects zero-sized entries)
+    let dtb = build_device_tree(config, 1)?;
+    match config.dtb_addr {
+        Some(_) => build_device_tree(config, dtb.len() as u64),
+        None => Ok(dtb),
+    }
+}
+
+/// Memory reservation entries for the DTB and initramfs
+fn memory_reservations(
+    config: &DeviceTreeConfig,
+    dtb_size: u64,
+) -> Result<Vec<FdtReserveEntry>, Box<dyn Error>> {
+    let mut entries = Vec::new();
+    if let Some(addr) = config.dtb_addr {
+        entries.push(FdtReserveEntry::new(addr, dtb_size)?);
+    }
+    if let (Some(start), Some(end)) = (config.initrd_start, config.initrd_end) {
+        if end > start {
+            entries.push(FdtReserveEntry::new(start, end - start)?);
+        }
+    }
+    Ok(entries)
+}
+
+fn build_device_tree(config: &DeviceTreeConfig, dtb_size: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut fdt = FdtWriter::new_with_mem_reserv(&memory_reservations(config, dtb_size)?)?;
 
     // Root node
     let root_node = fdt.begin_node("")?;
-    fdt.property_string("compatible", "linux,dummy-virt")?;
+    fdt.property_string("compatible", &config.compatible)?;
     fdt.property_u32("#address-cells", 2)?;
     fdt.property_u32("#size-cells", 2)?;
-    fdt.property_string("model", "hypervisor-virt")?;
+    fdt.property_string("model", &config.model)?;
     // Interrupt cells for GICv2
-    fdt.property_u32("interrupt-parent", 1)?; // phandle of GIC
+    fdt.property_u32("interrupt-parent", GIC_PHANDLE)?;
 
     // CPUs node
     let cpus_node = fdt.begin_node("cpus")?;
     fdt.property_u32("#address-cells", 1)?;
     fdt.property_u32("#size-cells", 0)?;
 
-    // CPU0
-    let cpu0_node = fdt.begin_node("cpu@0")?;
-    fdt.property_string("device_type", "cpu")?;
-    fdt.property_string("compatible", "arm,armv8")?;
-    fdt.property_string("enable-method", "psci")?;
-    fdt.property_u32("reg", 0)?;
-    fdt.end_node(cpu0_node)?; // cpu@0
+    for cpu in 0..config.cpus {
+        let reg = mpidr_affinity(cpu);
+        let cpu_node = fdt.begin_node(&format!("cpu@{:x}", reg))?;
+        fdt.property_string("device_type", "cpu")?;
+        fdt.property_string("compatible", "arm,armv8")?;
+        fdt.property_string("enable-method", "psci")?;
+        fdt.property_u32("reg", reg)?;
+        fdt.end_node(cpu_node)?; // cpu@N
+    }
 
     fdt.end_node(cpus_node)?; // cpus
 
@@ -90,7 +276,7 @@ pub fn generate_device_tree(config: &DeviceTreeConfig) -> Result<Vec<u8>, Box<dy
     let memory_node = fdt.begin_node(&memory_node_name)?;
     fdt.property_string("device_type", "memory")?;
     // reg = <address-high address-low size-high size-low>
-    // For simplicity, we assume 64-bit addresses fit in the low 32-bits
+    // (#address-cells = #size-cells = 2, so RAM above 4GB is described as-is)
     fdt.property_array_u64("reg", &[config.memory_base, config.memory_size])?;
     fdt.end_node(memory_node)?; // memory
 
@@ -100,7 +286,9 @@ pub fn generate_device_tree(config: &DeviceTreeConfig) -> Result<Vec<u8>, Box<dy
     fdt.property_string("compatible", "arm,cortex-a15-gic")?;
     fdt.property_null("interrupt-controller")?;
     fdt.property_u32("#interrupt-cells", 3)?; // GIC requires 3 cells
-                                              // reg = <GICD_base GICD_size GICC_base GICC_size>
+                                              // reg = <GICD GICC GICH GICV> (base, size pairs)
+                                              // GICH / GICV let guest kernels built with KVM probe the virtualization
+                                              // extensions; see crate::devices::gic for what they actually implement.
     fdt.property_array_u64(
         "reg",
         &[
@@ -108,60 +296,198 @@ pub fn generate_device_tree(config: &DeviceTreeConfig) -> Result<Vec<u8>, Box<dy
             0x1_0000, // GICD size
             config.gic_cpu_base,
             0x1_0000, // GICC size
+            config.gic_hyp_base,
+            GIC_HYP_SIZE,
+            config.gic_vcpu_base,
+            GIC_VCPU_SIZE,
         ],
     )?;
-    fdt.property_u32("phandle", 1)?; // phandle for interrupt-parent reference
+    // GICH maintenance interrupt (level-high, never asserted)
+    fdt.property_array_u32(
+        "interrupts",
+        &dt_interrupt(config.irqs.gic_maintenance, 0xf04),
+    )?;
+    fdt.property_u32("phandle", GIC_PHANDLE)?; // phandle for interrupt-parent reference
     fdt.end_node(gic_node)?; // intc
 
     // Timer node (ARM Generic Timer)
-    // Virtual Timer のみを使用（Physical Timer はハイパーバイザーが使用）
-    // PPI IRQs: Secure Phys=13, Non-secure Phys=14, Virt=11, Hyp=10
+    // Only the virtual timer is used (the physical timer belongs to the hypervisor)
+    // PPI IRQs (QEMU virt): Secure Phys=13, Non-secure Phys=14, Virt=11, Hyp=10
     let timer_node = fdt.begin_node("timer")?;
     fdt.property_string("compatible", "arm,armv8-timer")?;
     // interrupts: <type irq flags> for each timer
     // type: 1=PPI, irq: actual IRQ number (PPI base is 16, so subtract 16)
     // flags: 0xf08 = level-high, CPU0 only
-    // Virtual Timer のみ有効（他は無効な割り込みとして 0xfff でマーク）
-    fdt.property_array_u32(
-        "interrupts",
-        &[
-            1, 13, 0xf08, // Secure Physical Timer (IRQ 29) - masked
-            1, 14, 0xf08, // Non-secure Physical Timer (IRQ 30) - masked
-            1, 11, 0xf08, // Virtual Timer (IRQ 27) - level-high
-            1, 10, 0xf08, // Hypervisor Timer (IRQ 26) - masked
-        ],
-    )?;
+    // Only the virtual timer is enabled (the others are marked invalid with 0xfff)
+    let irqs = &config.irqs;
+    let timer_irqs: Vec<u32> = [
+        irqs.sec_phys_timer, // Secure Physical Timer - masked
+        irqs.phys_timer,     // Non-secure Physical Timer - masked
+        irqs.virt_timer,     // Virtual Timer - level-high
+        irqs.hyp_timer,      // Hypervisor Timer - masked
+    ]
+    .iter()
+    .flat_map(|&irq| dt_interrupt(irq, 0xf08))
+    .collect();
+    fdt.property_array_u32("interrupts", &timer_irqs)?;
     fdt.property_null("always-on")?;
     fdt.end_node(timer_node)?; // timer
 
+    // Fixed clock feeding the PL011 (both uartclk and apb_pclk)
+    // The Linux and U-Boot PL011 drivers derive the baud rate divisor from it
+    let clock_node = fdt.begin_node("apb-pclk")?;
+    fdt.property_string("compatible", "fixed-clock")?;
+    fdt.property_u32("#clock-cells", 0)?;
+    fdt.property_u32("clock-frequency", config.uart_clock_hz)?;
+    fdt.property_string(
+        "clock-output-names",
+        &clock_output_name(config.uart_clock_hz),
+    )?;
+    fdt.property_u32("phandle", APB_PCLK_PHANDLE)?;
+    fdt.end_node(clock_node)?; // apb-pclk
+
     // UART node (PL011)
     let uart_node_name = format!("pl011@{:x}", config.uart_base);
     let uart_node = fdt.begin_node(&uart_node_name)?;
     fdt.property_string("compatible", "arm,pl011")?;
     fdt.property_array_u64("reg", &[config.uart_base, 0x1000])?;
-    // UART uses SPI IRQ 1 (IRQ 33)
-    fdt.property_array_u32("interrupts", &[0, 1, 0x4])?; // SPI, IRQ 1, level-high
-    fdt.property_null("clock-names")?;
+    // QEMU virt: SPI 1 (IRQ 33), level-high
+    fdt.property_array_u32("interrupts", &dt_interrupt(irqs.uart, 0x4))?;
+    fdt.property_array_u32("clocks", &[APB_PCLK_PHANDLE, APB_PCLK_PHANDLE])?;
+    fdt.property_string_list(
+        "clock-names",
+        vec!["uartclk".to_string(), "apb_pclk".to_string()],
+    )?;
     fdt.end_node(uart_node)?; // pl011
 
-    // VirtIO Block device node
-    let virtio_node_name = format!("virtio_block@{:x}", config.virtio_base);
-    let virtio_node = fdt.begin_node(&virtio_node_name)?;
-    fdt.property_string("compatible", "virtio,mmio")?;
-    fdt.property_array_u64("reg", &[config.virtio_base, 0x200])?;
-    // VirtIO uses SPI IRQ 2 (IRQ 34)
-    fdt.property_array_u32("interrupts", &[0, 2, 0x1])?; // SPI, IRQ 2, edge-rising
-    fdt.end_node(virtio_node)?; // virtio_block
+    // VirtIO MMIO transport nodes (devices are bound to slots at runtime)
+    for slot in 0..config.virtio_slots {
+        let base = config.virtio_base + slot as u64 * VIRTIO_SLOT_SIZE;
+        let virtio_node = fdt.begin_node(&format!("virtio_mmio@{:x}", base))?;
+        fdt.property_string("compatible", "virtio,mmio")?;
+        fdt.property_array_u64("reg", &[base, VIRTIO_SLOT_SIZE])?;
+        // QEMU virt: SPI 2 (IRQ 34) onwards, edge-rising
+        fdt.property_array_u32("interrupts", &dt_interrupt(irqs.virtio_slot(slot), 0x1))?;
+        // Guest RAM is ordinary host memory, so DMA needs no cache maintenance
+        fdt.property_null("dma-coherent")?;
+        fdt.end_node(virtio_node)?; // virtio_mmio
+    }
+
+    // Host wall-clock device node
+    if let Some(base) = config.wall_clock_base {
+        let clock_node = fdt.begin_node(&format!("wall-clock@{:x}", base))?;
+        fdt.property_string("compatible", WALL_CLOCK_COMPATIBLE)?;
+        fdt.property_array_u64("reg", &[base, WALL_CLOCK_SIZE])?;
+        fdt.end_node(clock_node)?; // wall-clock
+    }
+
+    // PL330 DMA controller stub (an AMBA PrimeCell, so it needs apb_pclk)
+    if let Some(base) = config.pl330_base {
+        let dma_node = fdt.begin_node(&format!("dma-controller@{:x}", base))?;
+        fdt.property_string_list(
+            "compatible",
+            vec!["arm,pl330".to_string(), "arm,primecell".to_string()],
+        )?;
+        fdt.property_array_u64("reg", &[base, PL330_SIZE])?;
+        fdt.property_u32("#dma-cells", 1)?;
+        fdt.property_u32("clocks", APB_PCLK_PHANDLE)?;
+        fdt.property_string("clock-names", "apb_pclk")?;
+        fdt.property_null("dma-coherent")?;
+        fdt.end_node(dma_node)?; // dma-controller
+    }
+
+    // Persistent memory mapped straight from host files
+    for &(base, size, volatile) in &config.pmem_regions {
+        let pmem_node = fdt.begin_node(&format!("pmem@{:x}", base))?;
+        fdt.property_string("compatible", "pmem-region")?;
+        fdt.property_array_u64("reg", &[base, size])?;
+        if volatile {
+            fdt.property_null("volatile")?;
+        }
+        fdt.end_node(pmem_node)?; // pmem
+    }
+
+    for node in &config.device_nodes {
+        write_device_node(&mut fdt, node)?;
+    }
+
+    // SMMUv3 stub. Its queues never report events, so no interrupts are wired;
+    // Linux only warns that events and global errors will not be reported.
+    // Devices carry no `iommus`, so their DMA stays untranslated.
+    if let Some(base) = config.smmu_base {
+        let smmu_node = fdt.begin_node(&format!("iommu@{:x}", base))?;
+        fdt.property_string("compatible", "arm,smmu-v3")?;
+        fdt.property_array_u64("reg", &[base, SMMU_SIZE])?;
+        fdt.property_u32("#iommu-cells", 1)?;
+        // Must agree with IDR0.COHACC
+        fdt.property_null("dma-coherent")?;
+        fdt.end_node(smmu_node)?; // iommu
+    }
+
+    // SCMI firmware (clock and power domain providers)
+    if let Some(base) = config.scmi_shmem_base {
+        // The SCMI SMC transport follows the SMCCC conduit discovered via PSCI
+        let psci_node = fdt.begin_node("psci")?;
+        fdt.property_string_list(
+            "compatible",
+            vec!["arm,psci-1.0".to_string(), "arm,psci-0.2".to_string()],
+        )?;
+        fdt.property_string("method", "hvc")?;
+        fdt.end_node(psci_node)?; // psci
+
+        let shmem_node = fdt.begin_node(&format!("scmi-shmem@{:x}", base))?;
+        fdt.property_string("compatible", "arm,scmi-shmem")?;
+        fdt.property_array_u64("reg", &[base, SCMI_SHMEM_SIZE])?;
+        fdt.property_u32("phandle", SCMI_SHMEM_PHANDLE)?;
+        fdt.end_node(shmem_node)?; // scmi-shmem
+
+        let firmware_node = fdt.begin_node("firmware")?;
+        let scmi_node = fdt.begin_node("scmi")?;
+        fdt.property_string("compatible", "arm,scmi-smc")?;
+        fdt.property_u32("arm,smc-id", SCMI_SMC_ID)?;
+        fdt.property_u32("shmem", SCMI_SHMEM_PHANDLE)?;
+        fdt.property_u32("#address-cells", 1)?;
+        fdt.property_u32("#size-cells", 0)?;
+
+        let power_node = fdt.begin_node(&format!("protocol@{:x}", protocol::POWER))?;
+        fdt.property_u32("reg", protocol::POWER as u32)?;
+        fdt.property_u32("#power-domain-cells", 1)?;
+        fdt.property_u32("phandle", SCMI_POWER_PHANDLE)?;
+        fdt.end_node(power_node)?; // protocol@11
+
+        let clock_node = fdt.begin_node(&format!("protocol@{:x}", protocol::CLOCK))?;
+        fdt.property_u32("reg", protocol::CLOCK as u32)?;
+        fdt.property_u32("#clock-cells", 1)?;
+        fdt.property_u32("phandle", SCMI_CLOCK_PHANDLE)?;
+        fdt.end_node(clock_node)?; // protocol@14
+
+        fdt.end_node(scmi_node)?; // scmi
+        fdt.end_node(firmware_node)?; // firmware
+    }
+
+    // aliases node (serial0 lets the console bind without `console=`)
+    let uart_path = format!("/{}", uart_node_name);
+    let aliases_node = fdt.begin_node("aliases")?;
+    fdt.property_string("serial0", &uart_path)?;
+    fdt.end_node(aliases_node)?; // aliases
 
     // chosen node (boot parameters)
     let chosen_node = fdt.begin_node("chosen")?;
     fdt.property_string("bootargs", &config.cmdline)?;
-    fdt.property_string("stdout-path", &uart_node_name)?;
+    // Full path plus console options, e.g. "/pl011@9000000:115200n8"
+    fdt.property_string("stdout-path", &format!("{}:{}", uart_path, STDOUT_OPTIONS))?;
     // initramfs (initrd) addresses
     if let (Some(start), Some(end)) = (config.initrd_start, config.initrd_end) {
         fdt.property_u64("linux,initrd-start", start)?;
         fdt.property_u64("linux,initrd-end", end)?;
     }
+    // Early entropy: KASLR offset and the kernel's RNG pool before any driver is up
+    if config.seed_entropy {
+        let seed = host_entropy(RNG_SEED_SIZE + 8)?;
+        fdt.property("rng-seed", &seed[..RNG_SEED_SIZE])?;
+        let kaslr_seed = u64::from_le_bytes(seed[RNG_SEED_SIZE..].try_into().unwrap());
+        fdt.property_u64("kaslr-seed", kaslr_seed)?;
+    }
     fdt.end_node(chosen_node)?; // chosen
 
     fdt.end_node(root_node)?; // root
@@ -171,6 +497,61 @@ pub fn generate_device_tree(config: &DeviceTreeConfig) -> Result<Vec<u8>, Box<dy
     Ok(dtb.to_vec())
 }
 
+/// `clock-output-names` for a fixed clock, in QEMU's style (`clk24mhz`)
+fn clock_output_name(hz: u32) -> String {
+    if hz.is_multiple_of(1_000_000) {
+        format!("clk{}mhz", hz / 1_000_000)
+    } else {
+        format!("clk{}hz", hz)
+    }
+}
+
+/// Read `len` bytes of entropy from the host
+fn host_entropy(len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    use std::io::Read;
+
+    let mut buf = vec![0u8; len];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut buf))
+        .map_err(|e| format!("Failed to read host entropy: {}", e))?;
+    Ok(buf)
+}
+
+/// Write a node described through the `hypervisor-device` API
+fn write_device_node(
+    fdt: &mut FdtWriter,
+    node: &hypervisor_device::DtNode,
+) -> Result<(), Box<dyn Error>> {
+    use hypervisor_device::DtValue;
+
+    let (base, size) = node.reg;
+    let dev_node = fdt.begin_node(&format!("{}@{:x}", node.name, base))?;
+    if !node.compatible.is_empty() {
+        fdt.property_string_list("compatible", node.compatible.clone())?;
+    }
+    fdt.property_array_u64("reg", &[base, size])?;
+    if let Some(intid) = node.interrupt {
+        fdt.property_u32("interrupt-parent", GIC_PHANDLE)?;
+        fdt.property_array_u32("interrupts", &dt_interrupt(intid, 0x4))?;
+    }
+    for (name, value) in &node.properties {
+        match value {
+            DtValue::Empty => fdt.property_null(name)?,
+            DtValue::U32(cells) => fdt.property_array_u32(name, cells)?,
+            DtValue::U64(values) => fdt.property_array_u64(name, values)?,
+            DtValue::String(s) => fdt.property_string(name, s)?,
+            DtValue::StringList(list) => fdt.property_string_list(name, list.clone())?,
+            other => {
+                return Err(
+                    format!("Unsupported value for property '{}': {:?}", name, other).into(),
+                )
+            }
+        }
+    }
+    fdt.end_node(dev_node)?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -198,6 +579,7 @@ mod tests {
             cmdline: "console=ttyAMA0 earlycon root=/dev/vda rw".to_string(),
             initrd_start: None,
             initrd_end: None,
+            ..Default::default()
         };
 
         let dtb = generate_device_tree(&config).unwrap();
@@ -219,6 +601,7 @@ mod tests {
             cmdline: "console=ttyAMA0 rdinit=/init".to_string(),
             initrd_start: Some(0x4500_0000),
             initrd_end: Some(0x4600_0000),
+            ..Default::default()
         };
 
         let dtb = generate_device_tree(&config).unwrap();
@@ -239,4 +622,335 @@ mod tests {
         assert_eq!(config.gic_cpu_base, 0x0801_0000);
         assert_eq!(config.cmdline, "console=ttyAMA0 root=/dev/vda rw");
     }
+
+    #[test]
+    fn test_uart_clock_node_is_emitted() {
+        let config = DeviceTreeConfig::default();
+        let dtb = generate_device_tree(&config).unwrap();
+
+        let contains = |needle: &[u8]| dtb.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"fixed-clock"));
+        assert!(contains(b"apb_pclk"));
+        // clock-frequency = 24MHz (big-endian)
+        assert!(contains(&24_000_000u32.to_be_bytes()));
+        assert!(contains(b"clk24mhz"));
+
+        let config = DeviceTreeConfig {
+            uart_clock_hz: 1_843_200,
+            ..Default::default()
+        };
+        let dtb = generate_device_tree(&config).unwrap();
+        let contains = |needle: &[u8]| dtb.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(&1_843_200u32.to_be_bytes()));
+        assert!(contains(b"clk1843200hz"));
+        assert!(!contains(b"clk24mhz"));
+    }
+
+    #[test]
+    fn test_device_tree_config_from_layout() {
+        let layout = MachineLayout::default();
+        let config = DeviceTreeConfig::from_layout(&layout, 0x1000_0000, "console=ttyAMA0");
+        assert_eq!(config.memory_base, layout.ram_base);
+        assert_eq!(config.memory_size, 0x1000_0000);
+        assert_eq!(config.uart_base, layout.uart_base);
+        assert_eq!(config.uart_clock_hz, layout.uart_clock_hz);
+        assert_eq!(config.cmdline, "console=ttyAMA0");
+    }
+
+    #[test]
+    fn test_zephyr_board_variant() {
+        let config = DeviceTreeConfig::zephyr_qemu_cortex_a53();
+        assert_eq!(config.compatible, "qemu,arm-cortex-a53");
+        assert_eq!(config.memory_base, 0x4000_0000);
+        assert_eq!(config.uart_base, 0x0900_0000);
+
+        let dtb = generate_device_tree(&config).unwrap();
+        assert!(dtb
+            .windows(b"qemu,arm-cortex-a53".len())
+            .any(|w| w == b"qemu,arm-cortex-a53"));
+    }
+
+    #[test]
+    fn test_chosen_entropy_seeds() {
+        let config = DeviceTreeConfig::default();
+        let dts = crate::boot::fdt::to_dts(&generate_device_tree(&config).unwrap()).unwrap();
+        assert!(dts.contains("rng-seed = <"));
+        assert!(dts.contains("kaslr-seed = <"));
+
+        // Each boot gets fresh entropy
+        let a = generate_device_tree(&config).unwrap();
+        let b = generate_device_tree(&config).unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_entropy_seeds_can_be_disabled() {
+        let config = DeviceTreeConfig {
+            seed_entropy: false,
+            ..Default::default()
+        };
+        let a = generate_device_tree(&config).unwrap();
+        let b = generate_device_tree(&config).unwrap();
+        assert_eq!(a, b);
+        let dts = crate::boot::fdt::to_dts(&a).unwrap();
+        assert!(!dts.contains("rng-seed"));
+        assert!(!dts.contains("kaslr-seed"));
+    }
+
+    #[test]
+    fn test_stdout_path_and_serial_alias() {
+        let config = DeviceTreeConfig {
+            seed_entropy: false,
+            ..Default::default()
+        };
+        let dts = crate::boot::fdt::to_dts(&generate_device_tree(&config).unwrap()).unwrap();
+        assert!(dts.contains("stdout-path = \"/pl011@9000000:115200n8\";"));
+        assert!(dts.contains("aliases {"));
+        assert!(dts.contains("serial0 = \"/pl011@9000000\";"));
+    }
+
+    #[test]
+    fn test_dtb_and_initrd_are_reserved() {
+        let config = DeviceTreeConfig {
+            initrd_start: Some(0x4500_0000),
+            initrd_end: Some(0x4600_0000),
+            dtb_addr: Some(0x4400_0000),
+            seed_entropy: false,
+            ..Default::default()
+        };
+        let dtb = generate_device_tree(&config).unwrap();
+        let dts = crate::boot::fdt::to_dts(&dtb).unwrap();
+        assert!(dts.contains(&format!("/memreserve/ 0x44000000 0x{:x};", dtb.len())));
+        assert!(dts.contains("/memreserve/ 0x45000000 0x1000000;"));
+    }
+
+    #[test]
+    fn test_no_reservations_by_default() {
+        let dtb = generate_device_tree(&DeviceTreeConfig::default()).unwrap();
+        let dts = crate::boot::fdt::to_dts(&dtb).unwrap();
+        assert!(!dts.contains("/memreserve/"));
+    }
+
+    #[test]
+    fn test_wall_clock_node() {
+        let dtb = generate_device_tree(&DeviceTreeConfig::default()).unwrap();
+        let dts = crate::boot::fdt::to_dts(&dtb).unwrap();
+        assert!(!dts.contains("wall-clock"));
+
+        let config = DeviceTreeConfig {
+            wall_clock_base: Some(crate::devices::clock::WALL_CLOCK_BASE),
+            ..Default::default()
+        };
+        let dts = crate::boot::fdt::to_dts(&generate_device_tree(&config).unwrap()).unwrap();
+        assert!(dts.contains("wall-clock@90c0000 {"));
+        assert!(dts.contains("compatible = \"hypervisor,wall-clock\";"));
+    }
+
+    #[test]
+    fn test_pl330_node() {
+        let dtb = generate_device_tree(&DeviceTreeConfig::default()).unwrap();
+        let dts = crate::boot::fdt::to_dts(&dtb).unwrap();
+        assert!(!dts.contains("arm,pl330"));
+
+        let config = DeviceTreeConfig {
+            pl330_base: Some(crate::devices::pl330::PL330_BASE),
+            ..Default::default()
+        };
+        let dts = crate::boot::fdt::to_dts(&generate_device_tree(&config).unwrap()).unwrap();
+        assert!(dts.contains("dma-controller@90e0000 {"));
+        assert!(dts.contains("compatible = \"arm,pl330\", \"arm,primecell\";"));
+        assert!(dts.contains("#dma-cells = <0x1>;"));
+        assert!(dts.contains("clock-names = \"apb_pclk\";"));
+    }
+
+    #[test]
+    fn test_memory_above_4gb() {
+        let config = DeviceTreeConfig {
+            memory_size: 0x2_0000_0000, // 8GB
+            ..Default::default()
+        };
+        let dts = crate::boot::fdt::to_dts(&generate_device_tree(&config).unwrap()).unwrap();
+        assert!(dts.contains("memory@40000000 {"));
+        assert!(dts.contains("reg = <0x0 0x40000000 0x2 0x0>;"));
+    }
+
+    #[test]
+    fn test_pmem_nodes() {
+        let config = DeviceTreeConfig {
+            pmem_regions: vec![
+                (0x1_0000_0000, 0x10_0000, false),
+                (0x1_0010_0000, 0x4000, true),
+            ],
+            ..Default::default()
+        };
+        let dts = crate::boot::fdt::to_dts(&generate_device_tree(&config).unwrap()).unwrap();
+        assert_eq!(dts.matches("compatible = \"pmem-region\";").count(), 2);
+        assert!(dts.contains("pmem@100000000 {"));
+        assert!(dts.contains("reg = <0x1 0x0 0x0 0x100000>;"));
+        assert_eq!(dts.matches("volatile;").count(), 1);
+    }
+
+    #[test]
+    fn test_device_nodes() {
+        use hypervisor_device::{DtNode, DtValue};
+
+        let config = DeviceTreeConfig {
+            device_nodes: vec![DtNode::new("scratch", 0x0a10_0000, 0x1000)
+                .compatible("acme,scratch")
+                .interrupt(Some(80))
+                .property("acme,lanes", DtValue::U32(vec![4]))
+                .property("dma-coherent", DtValue::Empty)],
+            ..Default::default()
+        };
+        let dts = crate::boot::fdt::to_dts(&generate_device_tree(&config).unwrap()).unwrap();
+        assert!(dts.contains("scratch@a100000 {"));
+        assert!(dts.contains("compatible = \"acme,scratch\";"));
+        assert!(dts.contains("reg = <0x0 0xa100000 0x0 0x1000>;"));
+        assert!(dts.contains("interrupts = <0x0 0x30 0x4>;"));
+        assert!(dts.contains("acme,lanes = <0x4>;"));
+    }
+
+    #[test]
+    fn test_smmu_node() {
+        let dtb = generate_device_tree(&DeviceTreeConfig::default()).unwrap();
+        let dts = crate::boot::fdt::to_dts(&dtb).unwrap();
+        assert!(!dts.contains("arm,smmu-v3"));
+
+        let config = DeviceTreeConfig {
+            smmu_base: Some(crate::devices::smmu::SMMU_BASE),
+            ..Default::default()
+        };
+        let dts = crate::boot::fdt::to_dts(&generate_device_tree(&config).unwrap()).unwrap();
+        assert!(dts.contains("iommu@9050000 {"));
+        assert!(dts.contains("compatible = \"arm,smmu-v3\";"));
+        assert!(dts.contains("reg = <0x0 0x9050000 0x0 0x20000>;"));
+        assert!(dts.contains("#iommu-cells = <0x1>;"));
+        assert!(!dts.contains("iommus"));
+        // virtio slot + SMMU
+        assert_eq!(dts.matches("dma-coherent;").count(), 2);
+    }
+
+    #[test]
+    fn test_scmi_nodes() {
+        let dts =
+            crate::boot::fdt::to_dts(&generate_device_tree(&DeviceTreeConfig::default()).unwrap())
+                .unwrap();
+        assert!(!dts.contains("scmi"));
+        assert!(!dts.contains("psci {"));
+
+        let config = DeviceTreeConfig {
+            scmi_shmem_base: Some(crate::devices::scmi::SCMI_SHMEM_BASE),
+            ..Default::default()
+        };
+        let dts = crate::boot::fdt::to_dts(&generate_device_tree(&config).unwrap()).unwrap();
+        assert!(dts.contains("method = \"hvc\";"));
+        assert!(dts.contains("scmi-shmem@90d0000 {"));
+        assert!(dts.contains("compatible = \"arm,scmi-smc\";"));
+        assert!(dts.contains("arm,smc-id = <0x82000010>;"));
+        assert!(dts.contains("protocol@14 {"));
+        assert!(dts.contains("#power-domain-cells = <0x1>;"));
+    }
+
+    #[test]
+    fn test_virtio_slots() {
+        let config = DeviceTreeConfig {
+            virtio_slots: 3,
+            ..Default::default()
+        };
+        let dts = crate::boot::fdt::to_dts(&generate_device_tree(&config).unwrap()).unwrap();
+        assert_eq!(dts.matches("compatible = \"virtio,mmio\";").count(), 3);
+        assert!(dts.contains("virtio_mmio@a000400 {"));
+        assert!(dts.contains("reg = <0x0 0xa000400 0x0 0x200>;"));
+        assert!(dts.contains("interrupts = <0x0 0x4 0x1>;"));
+        assert_eq!(dts.matches("dma-coherent;").count(), 3);
+
+        let config = DeviceTreeConfig {
+            virtio_slots: 0,
+            ..Default::default()
+        };
+        let dts = crate::boot::fdt::to_dts(&generate_device_tree(&config).unwrap()).unwrap();
+        assert!(!dts.contains("virtio,mmio"));
+
+        // Slot interrupts must not run into the UART
+        let config = DeviceTreeConfig {
+            virtio_slots: 2,
+            irqs: IrqMap {
+                uart: 35,
+                ..IrqMap::QEMU_VIRT
+            },
+            ..Default::default()
+        };
+        assert!(generate_device_tree(&config).is_err());
+    }
+
+    #[test]
+    fn test_gic_virtualization_regions() {
+        let dts =
+            crate::boot::fdt::to_dts(&generate_device_tree(&DeviceTreeConfig::default()).unwrap())
+                .unwrap();
+        assert!(dts.contains(
+            "reg = <0x0 0x8000000 0x0 0x10000 0x0 0x8010000 0x0 0x10000 \
+             0x0 0x8030000 0x0 0x10000 0x0 0x8040000 0x0 0x20000>;"
+        ));
+        assert!(dts.contains("interrupts = <0x1 0x9 0xf04>;"));
+    }
+
+    #[test]
+    fn test_cpu_nodes() {
+        let dts =
+            crate::boot::fdt::to_dts(&generate_device_tree(&DeviceTreeConfig::default()).unwrap())
+                .unwrap();
+        assert_eq!(dts.matches("device_type = \"cpu\";").count(), 1);
+        assert!(dts.contains("cpu@0 {"));
+
+        let config = DeviceTreeConfig {
+            cpus: 0,
+            ..Default::default()
+        };
+        assert!(generate_device_tree(&config).is_err());
+
+        let config = DeviceTreeConfig {
+            cpus: 2,
+            ..Default::default()
+        };
+        let result = generate_device_tree(&config);
+        if cfg!(feature = "smp") {
+            let dts = crate::boot::fdt::to_dts(&result.unwrap()).unwrap();
+            assert!(dts.contains("cpu@1 {"));
+            assert!(dts.contains("reg = <0x1>;"));
+        } else {
+            assert!(result.unwrap_err().to_string().contains("--features smp"));
+        }
+    }
+
+    #[test]
+    fn test_interrupts_follow_irq_map() {
+        let config = DeviceTreeConfig {
+            seed_entropy: false,
+            ..Default::default()
+        };
+        let dts = crate::boot::fdt::to_dts(&generate_device_tree(&config).unwrap()).unwrap();
+        assert!(dts.contains("interrupts = <0x0 0x1 0x4>;"));
+        assert!(dts.contains("interrupts = <0x0 0x2 0x1>;"));
+
+        let config = DeviceTreeConfig {
+            seed_entropy: false,
+            irqs: IrqMap {
+                uart: 40,
+                ..IrqMap::QEMU_VIRT
+            },
+            ..Default::default()
+        };
+        let dts = crate::boot::fdt::to_dts(&generate_device_tree(&config).unwrap()).unwrap();
+        assert!(dts.contains("interrupts = <0x0 0x8 0x4>;"));
+
+        let config = DeviceTreeConfig {
+            irqs: IrqMap {
+                uart: 34,
+                ..IrqMap::QEMU_VIRT
+            },
+            ..Default::default()
+        };
+        assert!(generate_device_tree(&config).is_err());
+    }
 }
@@ -0,0 +1,274 @@
+//! Flattened Device Tree (DTB) の読み取り
+//!
+//! ゲストに渡した DTB を dtc と同じ形式の DTS テキストに変換する。
+//! `Hypervisor::dump_device_tree` と組み合わせて、カーネルが実際に受け取った
+//! Device Tree を確認するために使用する。
+
+use std::error::Error;
+use std::fmt::Write;
+
+/// FDT ヘッダーのマジックナンバー
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// FDT ヘッダーのサイズ (version 17)
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// DTB 内のオフセットから big-endian の u32 を読む
+fn be32(dtb: &[u8], offset: usize) -> Result<u32, Box<dyn Error>> {
+    let bytes = dtb
+        .get(offset..offset + 4)
+        .ok_or_else(|| format!("DTB is truncated at offset 0x{:x}", offset))?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// DTB 内のオフセットから big-endian の u64 を読む
+fn be64(dtb: &[u8], offset: usize) -> Result<u64, Box<dyn Error>> {
+    Ok(((be32(dtb, offset)? as u64) << 32) | be32(dtb, offset + 4)? as u64)
+}
+
+/// offset から NUL 終端の文字列を読む
+fn c_str(dtb: &[u8], offset: usize) -> Result<&str, Box<dyn Error>> {
+    let rest = dtb
+        .get(offset..)
+        .ok_or_else(|| format!("DTB string offset 0x{:x} is out of range", offset))?;
+    let len = rest
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or("DTB string is not NUL-terminated")?;
+    Ok(std::str::from_utf8(&rest[..len])?)
+}
+
+/// プロパティ値を DTS の表記に変換する
+///
+/// dtc と同じく、表示可能な文字列のリストなら文字列、
+/// 4 バイトの倍数ならセル (`<...>`)、それ以外はバイト列 (`[...]`) として表示する。
+fn format_value(value: &[u8]) -> String {
+    let is_string_list = value.last() == Some(&0)
+        && value[..value.len() - 1]
+            .split(|&b| b == 0)
+            .all(|s| !s.is_empty() && s.iter().all(|&b| (0x20..0x7f).contains(&b)));
+    if is_string_list {
+        let strings: Vec<String> = value[..value.len() - 1]
+            .split(|&b| b == 0)
+            .map(|s| format!("\"{}\"", String::from_utf8_lossy(s).escape_default()))
+            .collect();
+        strings.join(", ")
+    } else if value.len().is_multiple_of(4) {
+        let cells: Vec<String> = value
+            .chunks(4)
+            .map(|c| format!("0x{:x}", u32::from_be_bytes(c.try_into().unwrap())))
+            .collect();
+        format!("<{}>", cells.join(" "))
+    } else {
+        let bytes: Vec<String> = value.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("[{}]", bytes.join(" "))
+    }
+}
+
+/// DTB を DTS テキストに変換する
+///
+/// # Arguments
+/// * `dtb` - Flattened Device Tree のバイト列
+///
+/// # Returns
+/// `/dts-v1/;` から始まる DTS テキスト
+pub fn to_dts(dtb: &[u8]) -> Result<String, Box<dyn Error>> {
+    if dtb.len() < FDT_HEADER_SIZE {
+        return Err(format!("DTB is too small ({} bytes)", dtb.len()).into());
+    }
+    let magic = be32(dtb, 0)?;
+    if magic != FDT_MAGIC {
+        return Err(format!("Invalid DTB magic 0x{:08x}", magic).into());
+    }
+    let total_size = be32(dtb, 4)? as usize;
+    if total_size > dtb.len() {
+        return Err(format!(
+            "DTB totalsize 0x{:x} exceeds buffer size 0x{:x}",
+            total_size,
+            dtb.len()
+        )
+        .into());
+    }
+    let dtb = &dtb[..total_size];
+    let off_struct = be32(dtb, 8)? as usize;
+    let off_strings = be32(dtb, 12)? as usize;
+    let off_rsvmap = be32(dtb, 16)? as usize;
+
+    let mut out = String::from("/dts-v1/;\n\n");
+
+    // メモリ予約マップ (address = size = 0 で終端)
+    let mut offset = off_rsvmap;
+    loop {
+        let address = be64(dtb, offset)?;
+        let size = be64(dtb, offset + 8)?;
+        if address == 0 && size == 0 {
+            break;
+        }
+        writeln!(out, "/memreserve/ 0x{:x} 0x{:x};", address, size)?;
+        offset += 16;
+    }
+
+    // 構造ブロック
+    let mut offset = off_struct;
+    let mut depth = 0usize;
+    loop {
+        let token = be32(dtb, offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = c_str(dtb, offset)?;
+                offset = (offset + name.len() + 1).next_multiple_of(4);
+                let name = if depth == 0 && name.is_empty() {
+                    "/"
+                } else {
+                    name
+                };
+                writeln!(out, "{}{} {{", "\t".repeat(depth), name)?;
+                depth += 1;
+            }
+            FDT_END_NODE => {
+                if depth == 0 {
+                    return Err("Unbalanced FDT_END_NODE in DTB".into());
+                }
+                depth -= 1;
+                writeln!(out, "{}}};", "\t".repeat(depth))?;
+            }
+            FDT_PROP => {
+                let len = be32(dtb, offset)? as usize;
+                let name_off = be32(dtb, offset + 4)? as usize;
+                offset += 8;
+                let value = dtb
+                    .get(offset..offset + len)
+                    .ok_or("DTB property value is out of range")?;
+                offset = (offset + len).next_multiple_of(4);
+                let name = c_str(dtb, off_strings + name_off)?;
+                let indent = "\t".repeat(depth);
+                if value.is_empty() {
+                    writeln!(out, "{}{};", indent, name)?;
+                } else {
+                    writeln!(out, "{}{} = {};", indent, name, format_value(value))?;
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => break,
+            _ => {
+                return Err(format!(
+                    "Unknown DTB token 0x{:x} at offset 0x{:x}",
+                    token,
+                    offset - 4
+                )
+                .into())
+            }
+        }
+    }
+    if depth != 0 {
+        return Err("DTB ended inside a node".into());
+    }
+    Ok(out)
+}
+
+/// 2 つの DTS テキストを行単位で比較する
+///
+/// 空白のみの差は無視する。テストで参照用の DTS と比較するために使用する。
+///
+/// # Returns
+/// 差分がなければ None。あれば `expected` にしかない行を `-`、
+/// `actual` にしかない行を `+` で始めた差分テキスト
+pub fn diff_dts(expected: &str, actual: &str) -> Option<String> {
+    let normalize = |dts: &str| -> Vec<String> {
+        dts.lines()
+            .map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|l| !l.is_empty())
+            .collect()
+    };
+    let expected = normalize(expected);
+    let actual = normalize(actual);
+    if expected == actual {
+        return None;
+    }
+
+    let mut diff = String::new();
+    for line in expected.iter().filter(|l| !actual.contains(l)) {
+        let _ = writeln!(diff, "- {}", line);
+    }
+    for line in actual.iter().filter(|l| !expected.contains(l)) {
+        let _ = writeln!(diff, "+ {}", line);
+    }
+    if diff.is_empty() {
+        // 同じ行の並び替えのみ
+        diff.push_str("(lines are reordered)\n");
+    }
+    Some(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot::device_tree::{generate_device_tree, DeviceTreeConfig};
+    use vm_fdt::FdtWriter;
+
+    #[test]
+    fn 小さな_dtb_を_dts_に変換できる() {
+        let mut fdt = FdtWriter::new().unwrap();
+        let root = fdt.begin_node("").unwrap();
+        fdt.property_string("compatible", "linux,dummy-virt")
+            .unwrap();
+        fdt.property_u32("#address-cells", 2).unwrap();
+        let chosen = fdt.begin_node("chosen").unwrap();
+        fdt.property_null("ranges").unwrap();
+        fdt.property("mac", &[1, 2, 3]).unwrap();
+        fdt.end_node(chosen).unwrap();
+        fdt.end_node(root).unwrap();
+        let dtb = fdt.finish().unwrap();
+
+        let dts = to_dts(&dtb).unwrap();
+        let expected = "/dts-v1/;\n\n\
+            / {\n\
+            \tcompatible = \"linux,dummy-virt\";\n\
+            \t#address-cells = <0x2>;\n\
+            \tchosen {\n\
+            \t\tranges;\n\
+            \t\tmac = [01 02 03];\n\
+            \t};\n\
+            };\n";
+        assert_eq!(dts, expected);
+    }
+
+    #[test]
+    fn 生成した_device_tree_の内容を確認できる() {
+        let config = DeviceTreeConfig {
+            cmdline: "console=ttyAMA0 earlycon".to_string(),
+            ..Default::default()
+        };
+        let dts = to_dts(&generate_device_tree(&config).unwrap()).unwrap();
+
+        assert!(dts.contains("bootargs = \"console=ttyAMA0 earlycon\";"));
+        assert!(dts.contains("intc@8000000 {"));
+        assert!(dts.contains("memory@40000000 {"));
+        // 64-bit の reg はセルとして表示される
+        assert!(dts.contains("reg = <0x0 0x40000000 0x0 0x8000000>;"));
+    }
+
+    #[test]
+    fn マジックが不正な_dtb_はエラーになる() {
+        assert!(to_dts(&[0u8; 64]).is_err());
+        assert!(to_dts(&[0xd0, 0x0d, 0xfe, 0xed]).is_err());
+    }
+
+    #[test]
+    fn diff_dts_は空白の違いを無視する() {
+        let a = "/ {\n\tmodel = \"virt\";\n};\n";
+        let b = "/ {\n    model = \"virt\";\n\n};";
+        assert_eq!(diff_dts(a, b), None);
+
+        let c = "/ {\n\tmodel = \"other\";\n};\n";
+        let diff = diff_dts(a, c).unwrap();
+        assert!(diff.contains("- model = \"virt\";"));
+        assert!(diff.contains("+ model = \"other\";"));
+    }
+}
@@ -0,0 +1,393 @@
+//! initramfs の生成
+//!
+//! テストで使う最小限のルートファイルシステムを、ビルド済みの
+//! `output/initramfs.cpio.gz` に頼らずプログラムから組み立てる。
+//!
+//! 出力は newc 形式の cpio を gzip で包んだもの。圧縮ライブラリに依存しないよう
+//! deflate の無圧縮ブロック (stored block) を使うため、サイズは小さくならないが
+//! カーネルの initramfs 展開処理はそのまま受け付ける。
+//!
+//! ```ignore
+//! let initramfs = Builder::new()
+//!     .add_host_file("/bin/busybox", "output/busybox")?
+//!     .symlink("/bin/sh", "busybox")
+//!     .init_script("echo hello from init\npoweroff -f\n")
+//!     .build();
+//! ```
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// cpio のファイル種別
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+
+/// deflate の無圧縮ブロック 1 つあたりの最大サイズ
+const STORED_BLOCK_MAX: usize = 0xffff;
+
+/// アーカイブ内の 1 エントリ
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    /// 先頭の `/` を除いたパス
+    path: String,
+    /// ファイル種別とパーミッション
+    mode: u32,
+    data: Vec<u8>,
+    /// デバイス番号 (キャラクタデバイスのみ)
+    rdev: (u32, u32),
+}
+
+/// initramfs (cpio.gz) の組み立て
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
+    entries: Vec<Entry>,
+}
+
+impl Builder {
+    /// 空のアーカイブを作成
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ディレクトリを追加する (親ディレクトリも作成する)
+    pub fn dir(mut self, path: &str) -> Self {
+        self.add_dir(&normalize(path));
+        self
+    }
+
+    /// ファイルを追加する
+    ///
+    /// # Arguments
+    /// * `path` - アーカイブ内のパス (例: `/etc/hostname`)
+    /// * `data` - ファイルの内容
+    /// * `mode` - パーミッション (例: `0o644`)
+    pub fn file(mut self, path: &str, data: impl Into<Vec<u8>>, mode: u32) -> Self {
+        self.add(
+            &normalize(path),
+            S_IFREG | (mode & 0o7777),
+            data.into(),
+            (0, 0),
+        );
+        self
+    }
+
+    /// シンボリックリンクを追加する
+    pub fn symlink(mut self, path: &str, target: &str) -> Self {
+        self.add(
+            &normalize(path),
+            S_IFLNK | 0o777,
+            target.as_bytes().to_vec(),
+            (0, 0),
+        );
+        self
+    }
+
+    /// キャラクタデバイスを追加する (例: `/dev/console` = 5:1)
+    pub fn char_device(mut self, path: &str, major: u32, minor: u32) -> Self {
+        self.add(
+            &normalize(path),
+            S_IFCHR | 0o600,
+            Vec::new(),
+            (major, minor),
+        );
+        self
+    }
+
+    /// `/init` としてシェルスクリプトを追加する
+    ///
+    /// 先頭に shebang がなければ `#!/bin/sh` を付ける。カーネルが init の
+    /// 標準出力を開けるよう `/dev/console` も追加する。
+    pub fn init_script(self, script: &str) -> Self {
+        let script = if script.starts_with("#!") {
+            script.to_string()
+        } else {
+            format!("#!/bin/sh\n{}", script)
+        };
+        self.char_device("/dev/console", 5, 1)
+            .file("/init", script, 0o755)
+    }
+
+    /// ホストのファイルを追加する (パーミッションはホストのものを使う)
+    pub fn add_host_file(
+        self,
+        path: &str,
+        host_path: impl AsRef<Path>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let host_path = host_path.as_ref();
+        let data = fs::read(host_path)
+            .map_err(|e| format!("Failed to read {}: {}", host_path.display(), e))?;
+        let mode = host_mode(host_path)?;
+        Ok(self.file(path, data, mode))
+    }
+
+    /// ホストのディレクトリを再帰的に追加する
+    ///
+    /// シンボリックリンクはリンクとして追加し、リンク先はたどらない。
+    pub fn add_host_dir(
+        mut self,
+        path: &str,
+        host_dir: impl AsRef<Path>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let host_dir = host_dir.as_ref();
+        self = self.dir(path);
+        let mut children = fs::read_dir(host_dir)
+            .map_err(|e| format!("Failed to read {}: {}", host_dir.display(), e))?
+            .collect::<Result<Vec<_>, _>>()?;
+        // 生成結果を実行ごとに同じにする
+        children.sort_by_key(|e| e.file_name());
+
+        for child in children {
+            let name = child.file_name();
+            let guest = format!("{}/{}", path.trim_end_matches('/'), name.to_string_lossy());
+            let file_type = child.file_type()?;
+            self = if file_type.is_symlink() {
+                let target = fs::read_link(child.path())?;
+                self.symlink(&guest, &target.to_string_lossy())
+            } else if file_type.is_dir() {
+                self.add_host_dir(&guest, child.path())?
+            } else {
+                self.add_host_file(&guest, child.path())?
+            };
+        }
+        Ok(self)
+    }
+
+    /// 非圧縮の cpio (newc 形式) を生成する
+    pub fn build_cpio(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (i, entry) in self.entries.iter().enumerate() {
+            write_entry(&mut out, i as u32 + 1, entry);
+        }
+        let trailer = Entry {
+            path: "TRAILER!!!".to_string(),
+            mode: 0,
+            data: Vec::new(),
+            rdev: (0, 0),
+        };
+        write_entry(&mut out, 0, &trailer);
+        out
+    }
+
+    /// cpio.gz を生成する
+    pub fn build(&self) -> Vec<u8> {
+        gzip_stored(&self.build_cpio())
+    }
+
+    /// 同じパスのエントリは置き換える
+    fn add(&mut self, path: &str, mode: u32, data: Vec<u8>, rdev: (u32, u32)) {
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            self.add_dir(parent);
+        }
+        let entry = Entry {
+            path: path.to_string(),
+            mode,
+            data,
+            rdev,
+        };
+        match self.entries.iter_mut().find(|e| e.path == path) {
+            Some(existing) => *existing = entry,
+            None => self.entries.push(entry),
+        }
+    }
+
+    fn add_dir(&mut self, path: &str) {
+        if path.is_empty() || self.entries.iter().any(|e| e.path == path) {
+            return;
+        }
+        self.add(path, S_IFDIR | 0o755, Vec::new(), (0, 0));
+    }
+}
+
+/// 先頭と末尾の `/` を取り除く
+fn normalize(path: &str) -> String {
+    path.trim_matches('/').to_string()
+}
+
+#[cfg(unix)]
+fn host_mode(path: &Path) -> Result<u32, Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(fs::metadata(path)?.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn host_mode(_path: &Path) -> Result<u32, Box<dyn Error>> {
+    Ok(0o644)
+}
+
+/// newc 形式のヘッダーとデータを書き込む
+fn write_entry(out: &mut Vec<u8>, ino: u32, entry: &Entry) {
+    let nlink = if entry.mode & S_IFDIR != 0 { 2 } else { 1 };
+    let fields = [
+        ino,
+        entry.mode,
+        0, // uid
+        0, // gid
+        nlink,
+        0, // mtime
+        entry.data.len() as u32,
+        0, // devmajor
+        0, // devminor
+        entry.rdev.0,
+        entry.rdev.1,
+        entry.path.len() as u32 + 1,
+        0, // check
+    ];
+    out.extend_from_slice(b"070701");
+    for field in fields {
+        out.extend_from_slice(format!("{:08x}", field).as_bytes());
+    }
+    out.extend_from_slice(entry.path.as_bytes());
+    out.push(0);
+    pad4(out);
+    out.extend_from_slice(&entry.data);
+    pad4(out);
+}
+
+fn pad4(out: &mut Vec<u8>) {
+    while !out.len().is_multiple_of(4) {
+        out.push(0);
+    }
+}
+
+/// 無圧縮ブロックだけで gzip (RFC 1952) を作る
+fn gzip_stored(data: &[u8]) -> Vec<u8> {
+    // ID1 ID2 CM=deflate FLG=0 MTIME=0 XFL=0 OS=unix
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3];
+    let mut chunks = data.chunks(STORED_BLOCK_MAX).peekable();
+    if chunks.peek().is_none() {
+        // 空データでも最終ブロックが 1 つ必要
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let len = chunk.len() as u16;
+        out.push(last as u8); // BFINAL, BTYPE=00 (stored)
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// CRC-32 (IEEE 802.3)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// newc アーカイブから (パス, モード, データ) を取り出す
+    fn parse_cpio(mut cpio: &[u8]) -> Vec<(String, u32, Vec<u8>)> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            assert_eq!(&cpio[..6], b"070701");
+            let field = |i: usize| {
+                let s = std::str::from_utf8(&cpio[6 + i * 8..14 + i * 8]).unwrap();
+                u32::from_str_radix(s, 16).unwrap() as usize
+            };
+            let (mode, size, namesize) = (field(1) as u32, field(6), field(11));
+            let name = String::from_utf8(cpio[110..110 + namesize - 1].to_vec()).unwrap();
+            let data_start = (offset + 110 + namesize).next_multiple_of(4) - offset;
+            let data = cpio[data_start..data_start + size].to_vec();
+            let next = (offset + data_start + size).next_multiple_of(4) - offset;
+            if name == "TRAILER!!!" {
+                return entries;
+            }
+            entries.push((name, mode, data));
+            cpio = &cpio[next..];
+            offset += next;
+        }
+    }
+
+    #[test]
+    fn init_スクリプトと親ディレクトリを含む() {
+        let cpio = Builder::new()
+            .file("/etc/hostname", "guest\n", 0o644)
+            .init_script("echo hi\n")
+            .build_cpio();
+        let entries = parse_cpio(&cpio);
+        let names: Vec<_> = entries.iter().map(|e| e.0.as_str()).collect();
+        assert_eq!(names, ["etc", "etc/hostname", "dev", "dev/console", "init"]);
+
+        let init = &entries[4];
+        assert_eq!(init.1, S_IFREG | 0o755);
+        assert_eq!(init.2, b"#!/bin/sh\necho hi\n");
+        assert_eq!(entries[3].1, S_IFCHR | 0o600);
+        assert_eq!(cpio.len() % 4, 0);
+    }
+
+    #[test]
+    fn 同じパスは後から追加したもので置き換える() {
+        let cpio = Builder::new()
+            .file("/a", "old", 0o644)
+            .file("a", "new", 0o600)
+            .symlink("/bin/sh", "busybox")
+            .build_cpio();
+        let entries = parse_cpio(&cpio);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries[0],
+            ("a".to_string(), S_IFREG | 0o600, b"new".to_vec())
+        );
+        assert_eq!(entries[2].1, S_IFLNK | 0o777);
+        assert_eq!(entries[2].2, b"busybox");
+    }
+
+    #[test]
+    fn gzip_は無圧縮ブロックで包む() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let data = vec![0xa5u8; STORED_BLOCK_MAX + 10];
+        let gz = gzip_stored(&data);
+        assert_eq!(&gz[..3], &[0x1f, 0x8b, 8]);
+        // ヘッダー 10 + ブロックヘッダー 5 x 2 + データ + トレーラー 8
+        assert_eq!(gz.len(), 10 + 10 + data.len() + 8);
+        assert_eq!(gz[10], 0); // 最初のブロックは BFINAL=0
+        assert_eq!(gz[10 + 5 + STORED_BLOCK_MAX], 1);
+        let trailer = &gz[gz.len() - 8..];
+        assert_eq!(trailer[..4], crc32(&data).to_le_bytes());
+        assert_eq!(trailer[4..], (data.len() as u32).to_le_bytes());
+
+        assert_eq!(gzip_stored(&[]).len(), 10 + 5 + 8);
+    }
+
+    #[test]
+    fn ホストのディレクトリを再帰的に追加する() {
+        let root = std::env::temp_dir().join(format!("initramfs-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/file.txt"), "data").unwrap();
+
+        let cpio = Builder::new()
+            .add_host_dir("/opt/test", &root)
+            .unwrap()
+            .build_cpio();
+        fs::remove_dir_all(&root).unwrap();
+
+        let entries = parse_cpio(&cpio);
+        let names: Vec<_> = entries.iter().map(|e| e.0.as_str()).collect();
+        assert_eq!(
+            names,
+            ["opt", "opt/test", "opt/test/sub", "opt/test/sub/file.txt"]
+        );
+        assert_eq!(entries[3].2, b"data");
+    }
+}
@@ -1,9 +1,17 @@
 //! Linux カーネルローダー
 
+use crate::boot::verify::{Image, ImageKind, ImageVerifier};
 use std::error::Error;
 use std::fs;
 use std::path::Path;
 
+/// ARM64 Image ヘッダーのマジック ("ARM\x64", オフセット 0x38)
+const ARM64_IMAGE_MAGIC: u32 = 0x644d_5241;
+/// Image ヘッダーがない場合の text_offset (v3.17 より前のカーネルの既定値)
+const DEFAULT_TEXT_OFFSET: u64 = 0x8_0000;
+/// Image の配置の基準となる境界 (booting.rst: 2MB 境界 + text_offset)
+const KERNEL_ALIGN: u64 = 0x20_0000;
+
 /// Linux カーネルイメージ
 #[derive(Debug)]
 pub struct KernelImage {
@@ -38,6 +46,23 @@ impl KernelImage {
         Ok(Self { data, entry_point })
     }
 
+    /// カーネルイメージをファイルから読み込み、ゲストに渡す前に検証する
+    ///
+    /// `verifier` が拒否した場合はエラーを返す ([`crate::boot::verify`])。
+    ///
+    /// # Arguments
+    /// * `path` - カーネルイメージファイルのパス
+    /// * `verifier` - 内容の検証 (SHA-256 の許可リストや署名の確認)
+    pub fn load_verified<P: AsRef<Path>>(
+        path: P,
+        verifier: &dyn ImageVerifier,
+    ) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let kernel = Self::load(path)?;
+        verifier.verify(&Image::from_bytes(ImageKind::Kernel, path, &kernel.data))?;
+        Ok(kernel)
+    }
+
     /// カーネルイメージをバイトデータから作成する
     ///
     /// # Arguments
@@ -72,6 +97,35 @@ impl KernelImage {
     pub fn data(&self) -> &[u8] {
         &self.data
     }
+
+    /// 2MB 境界からの配置オフセット (text_offset) を取得する
+    ///
+    /// ARM64 Image ヘッダーがあればその値、なければ 0x80000 を返す。
+    pub fn text_offset(&self) -> u64 {
+        let magic = self
+            .data
+            .get(0x38..0x3c)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+        match (magic, self.data.get(0x08..0x10)) {
+            (Some(ARM64_IMAGE_MAGIC), Some(offset)) => {
+                u64::from_le_bytes(offset.try_into().unwrap())
+            }
+            _ => DEFAULT_TEXT_OFFSET,
+        }
+    }
+
+    /// カーネルを配置するアドレス (2MB 境界 + text_offset)
+    ///
+    /// `entry_point` 以上で、ブートプロトコルの配置要件を満たす最小のアドレスを返す。
+    /// text_offset が 0 の Image (v3.17 以降の既定) を既定の 0x40080000 で作成した場合は
+    /// 0x40200000 になる。
+    pub fn load_address(&self) -> u64 {
+        let text_offset = self.text_offset();
+        self.entry_point
+            .saturating_sub(text_offset)
+            .next_multiple_of(KERNEL_ALIGN)
+            + text_offset
+    }
 }
 
 #[cfg(test)]
@@ -112,4 +166,39 @@ mod tests {
         assert_eq!(kernel.size(), 1024 * 1024);
         assert_eq!(kernel.data(), &data);
     }
+
+    #[test]
+    fn test_load_verified_checks_digest() {
+        use crate::boot::verify::{sha256, to_hex, Sha256AllowList};
+
+        let path = std::env::temp_dir().join("hv-kernel-verified-test");
+        fs::write(&path, [0x00, 0x00, 0x00, 0x14]).unwrap();
+        let allow = Sha256AllowList::new()
+            .allow(&to_hex(&sha256(&[0x00, 0x00, 0x00, 0x14])))
+            .unwrap();
+
+        let kernel = KernelImage::load_verified(&path, &allow).unwrap();
+        assert_eq!(kernel.size(), 4);
+        fs::write(&path, [0x1f, 0x20, 0x03, 0xd5]).unwrap();
+        assert!(KernelImage::load_verified(&path, &allow).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_text_offset_from_image_header() {
+        let mut data = vec![0u8; 0x40];
+        data[0x08..0x10].copy_from_slice(&0u64.to_le_bytes());
+        data[0x38..0x3c].copy_from_slice(b"ARM\x64");
+        let kernel = KernelImage::from_bytes(data, Some(0x4020_0000));
+        assert_eq!(kernel.text_offset(), 0);
+        assert_eq!(kernel.load_address(), 0x4020_0000);
+        // 既定のエントリーポイントは 2MB 境界に揃えて配置する
+        let kernel = KernelImage::from_bytes(kernel.data().to_vec(), None);
+        assert_eq!(kernel.load_address(), 0x4020_0000);
+
+        // ヘッダーのない生のコードは既定値
+        let raw = KernelImage::from_bytes(vec![0x00, 0x00, 0x00, 0x14], None);
+        assert_eq!(raw.text_offset(), 0x8_0000);
+        assert_eq!(raw.load_address(), 0x4008_0000);
+    }
 }
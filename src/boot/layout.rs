@@ -0,0 +1,541 @@
+//! ゲストの物理メモリマップ (マシンレイアウト)
+//!
+//! QEMU の `virt` マシンと同じアドレス配置を採用している。
+//! U-Boot の `qemu_arm64_defconfig` はこのレイアウトを前提にしているため、
+//! 追加の設定なしで U-Boot を中間ブートローダーとして起動できる。
+//!
+//! | 領域            | ベースアドレス | サイズ     |
+//! |-----------------|----------------|------------|
+//! | GIC Distributor | 0x0800_0000    | 0x1_0000   |
+//! | GIC CPU I/F     | 0x0801_0000    | 0x2_0000   |
+//! | GICH / GICV     | 0x0803_0000    | 0x3_0000   |
+//! | PL011 UART      | 0x0900_0000    | 0x1000     |
+//! | SMMUv3          | 0x0905_0000    | 0x2_0000   |
+//! | 壁時計          | 0x090c_0000    | 0x1000     |
+//! | SCMI 共有メモリ | 0x090d_0000    | 0x1000     |
+//! | PL330 DMA       | 0x090e_0000    | 0x1000     |
+//! | VirtIO MMIO     | 0x0a00_0000    | 0x200 x スロット数 |
+//! | 共有メモリのドアベル | 0x0b00_0000 | 0x100     |
+//! | RAM             | 0x4000_0000    | 可変       |
+//!
+//! RAM 内の配置:
+//! - RAM 先頭: U-Boot 起動時の DTB (QEMU と同じく RAM 先頭に置く)
+//! - RAM + 0x8_0000: カーネル / U-Boot 本体
+//! - RAM + 0x400_0000: Linux 起動時の DTB
+
+use crate::devices::clock::{WALL_CLOCK_BASE, WALL_CLOCK_SIZE};
+use crate::devices::gic::{
+    GIC_DIST_BASE, GIC_HYP_BASE, GIC_MAINTENANCE_IRQ, GIC_REGION_SIZE, GIC_VCPU_BASE,
+};
+use crate::devices::pl330::{PL330_BASE, PL330_SIZE};
+use crate::devices::scmi::{SCMI_SHMEM_BASE, SCMI_SHMEM_SIZE};
+use crate::devices::shmem::{SHMEM_DOORBELL_BASE, SHMEM_REG_SIZE};
+use crate::devices::smmu::{SMMU_BASE, SMMU_SIZE};
+use crate::devices::timer::{HYP_TIMER_IRQ, PHYS_TIMER_IRQ, SEC_TIMER_IRQ, VIRT_TIMER_IRQ};
+use std::error::Error;
+use std::ops::Range;
+
+/// PL011 UART の領域サイズ
+const UART_REGION_SIZE: u64 = 0x1000;
+/// 既定のアドレスに置かれるその他のデバイスの領域 (ベース, サイズ)
+const FIXED_DEVICE_REGIONS: [(u64, u64); 5] = [
+    (SMMU_BASE, SMMU_SIZE),
+    (WALL_CLOCK_BASE, WALL_CLOCK_SIZE),
+    (SCMI_SHMEM_BASE, SCMI_SHMEM_SIZE),
+    (PL330_BASE, PL330_SIZE),
+    (SHMEM_DOORBELL_BASE, SHMEM_REG_SIZE),
+];
+/// VirtIO MMIO トランスポート 1 スロット分の領域サイズ
+pub const VIRTIO_SLOT_SIZE: u64 = 0x200;
+/// 永続メモリ (pmem) の配置とサイズの単位
+///
+/// Linux は DAX のために pmem を `memremap_pages` で登録し、arm64 のメモリ
+/// セクション (128MB) を RAM と共有する範囲や、半端な範囲を拒否する。
+pub const PMEM_ALIGN: u64 = 0x800_0000;
+
+/// PPI (CPU ごとの割り込み) の先頭 INTID
+pub const PPI_BASE: u32 = 16;
+/// SPI (共有割り込み) の先頭 INTID
+pub const SPI_BASE: u32 = 32;
+/// GIC エミュレーションが扱える INTID の上限 (この値未満)
+pub const MAX_INTID: u32 = 256;
+
+/// 割り込みの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqKind {
+    /// Private Peripheral Interrupt (INTID 16-31)
+    Ppi,
+    /// Shared Peripheral Interrupt (INTID 32-)
+    Spi,
+}
+
+impl IrqKind {
+    /// INTID から種類を判定する (SGI は None)
+    pub fn of(intid: u32) -> Option<Self> {
+        match intid {
+            PPI_BASE..SPI_BASE => Some(IrqKind::Ppi),
+            SPI_BASE..MAX_INTID => Some(IrqKind::Spi),
+            _ => None,
+        }
+    }
+
+    /// Device Tree の `interrupts` の 1 セル目 (0 = SPI, 1 = PPI)
+    pub fn dt_type(self) -> u32 {
+        match self {
+            IrqKind::Spi => 0,
+            IrqKind::Ppi => 1,
+        }
+    }
+
+    /// 種類ごとの先頭 INTID
+    pub fn base(self) -> u32 {
+        match self {
+            IrqKind::Spi => SPI_BASE,
+            IrqKind::Ppi => PPI_BASE,
+        }
+    }
+}
+
+/// 割り込み番号の割り当て (すべて GIC の INTID)
+///
+/// Device Tree の `interrupts` と GIC への注入の両方がこの値を使う。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqMap {
+    /// PL011 UART (SPI)
+    pub uart: u32,
+    /// VirtIO MMIO トランスポート (SPI)
+    pub virtio: u32,
+    /// 仮想タイマー (PPI)
+    pub virt_timer: u32,
+    /// 非セキュア物理タイマー (PPI)
+    pub phys_timer: u32,
+    /// セキュア物理タイマー (PPI)
+    pub sec_phys_timer: u32,
+    /// ハイパーバイザータイマー (PPI)
+    pub hyp_timer: u32,
+    /// GICH のメンテナンス割り込み (PPI)
+    pub gic_maintenance: u32,
+}
+
+impl Default for IrqMap {
+    fn default() -> Self {
+        Self::QEMU_VIRT
+    }
+}
+
+impl IrqMap {
+    /// QEMU `virt` と同じ割り当て (UART = SPI 1, VirtIO = SPI 2)
+    pub const QEMU_VIRT: Self = Self {
+        uart: SPI_BASE + 1,
+        virtio: SPI_BASE + 2,
+        virt_timer: VIRT_TIMER_IRQ,
+        phys_timer: PHYS_TIMER_IRQ,
+        sec_phys_timer: SEC_TIMER_IRQ,
+        hyp_timer: HYP_TIMER_IRQ,
+        gic_maintenance: GIC_MAINTENANCE_IRQ,
+    };
+
+    /// (名前, INTID, 期待する種類) の一覧
+    pub fn entries(&self) -> [(&'static str, u32, IrqKind); 7] {
+        [
+            ("uart", self.uart, IrqKind::Spi),
+            ("virtio", self.virtio, IrqKind::Spi),
+            ("virt-timer", self.virt_timer, IrqKind::Ppi),
+            ("phys-timer", self.phys_timer, IrqKind::Ppi),
+            ("sec-phys-timer", self.sec_phys_timer, IrqKind::Ppi),
+            ("hyp-timer", self.hyp_timer, IrqKind::Ppi),
+            ("gic-maintenance", self.gic_maintenance, IrqKind::Ppi),
+        ]
+    }
+
+    /// `slot` 番目の VirtIO MMIO トランスポートの INTID (QEMU と同じく連番)
+    pub fn virtio_slot(&self, slot: u32) -> u32 {
+        self.virtio + slot
+    }
+
+    /// `count` 個の VirtIO スロットの割り込みが SPI に収まり、UART と重ならないか確認する
+    pub fn validate_virtio_slots(&self, count: u32) -> Result<(), Box<dyn Error>> {
+        if count == 0 {
+            return Ok(());
+        }
+        let last = self.virtio.saturating_add(count - 1);
+        if IrqKind::of(last) != Some(IrqKind::Spi) {
+            return Err(format!(
+                "{} virtio-mmio slots starting at IRQ {} exceed the SPI range (last: {})",
+                count,
+                self.virtio,
+                MAX_INTID - 1
+            )
+            .into());
+        }
+        if (self.virtio..=last).contains(&self.uart) {
+            return Err(format!(
+                "IRQ {} is assigned to both uart and virtio-mmio slot {}",
+                self.uart,
+                self.uart - self.virtio
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// 種類が合っていて、番号が重複していないことを確認する
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let entries = self.entries();
+        for (i, &(name, intid, kind)) in entries.iter().enumerate() {
+            if IrqKind::of(intid) != Some(kind) {
+                return Err(format!(
+                    "IRQ {} for {} is not a valid {:?} (PPI: {}-{}, SPI: {}-{})",
+                    intid,
+                    name,
+                    kind,
+                    PPI_BASE,
+                    SPI_BASE - 1,
+                    SPI_BASE,
+                    MAX_INTID - 1
+                )
+                .into());
+            }
+            if let Some(&(other, _, _)) = entries[..i].iter().find(|e| e.1 == intid) {
+                return Err(
+                    format!("IRQ {} is assigned to both {} and {}", intid, other, name).into(),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// デバイスの割り込みが範囲内で、タイマーの PPI と衝突しないか確認する
+    ///
+    /// SPI (UART / VirtIO) はそれぞれのデバイスが使うため対象外。
+    /// デバイス同士の重複は `MmioManager::validate_irqs` で確認する。
+    pub fn check_device(&self, device: &str, intid: u32) -> Result<(), Box<dyn Error>> {
+        if IrqKind::of(intid).is_none() {
+            return Err(
+                format!("IRQ {} for {} is outside the PPI/SPI range", intid, device).into(),
+            );
+        }
+        match self
+            .entries()
+            .iter()
+            .find(|e| e.1 == intid && e.2 == IrqKind::Ppi)
+        {
+            Some(&(name, _, _)) => Err(format!(
+                "IRQ {} for {} collides with the {} interrupt",
+                intid, device, name
+            )
+            .into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Device Tree の `interrupts` 3 セル (種類, 番号, フラグ)
+///
+/// # Panics
+/// SGI (INTID 0-15) または範囲外の INTID を渡した場合 (`IrqMap::validate` 済みなら起きない)
+pub fn dt_interrupt(intid: u32, flags: u32) -> [u32; 3] {
+    let kind = IrqKind::of(intid).expect("SGIs cannot be described in the device tree");
+    [kind.dt_type(), intid - kind.base(), flags]
+}
+
+/// マシンレイアウト
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MachineLayout {
+    /// RAM ベースアドレス
+    pub ram_base: u64,
+    /// GIC Distributor ベースアドレス
+    pub gic_dist_base: u64,
+    /// GIC CPU Interface ベースアドレス
+    pub gic_cpu_base: u64,
+    /// PL011 UART ベースアドレス
+    pub uart_base: u64,
+    /// VirtIO MMIO トランスポートのベースアドレス (スロット 0)
+    pub virtio_base: u64,
+    /// Device Tree に宣言する VirtIO MMIO トランスポートの数
+    ///
+    /// スロットは `virtio_base` から `VIRTIO_SLOT_SIZE` ごとに並ぶ。
+    pub virtio_slots: u32,
+    /// RAM 先頭からのカーネル配置オフセット
+    pub kernel_offset: u64,
+    /// RAM 先頭からの DTB 配置オフセット (Linux 直接起動時)
+    pub dtb_offset: u64,
+    /// PL011 の参照クロック周波数 (Hz)
+    pub uart_clock_hz: u32,
+    /// 割り込み番号の割り当て
+    pub irqs: IrqMap,
+}
+
+impl Default for MachineLayout {
+    fn default() -> Self {
+        Self::QEMU_VIRT
+    }
+}
+
+impl MachineLayout {
+    /// QEMU `virt` 互換のレイアウト
+    pub const QEMU_VIRT: Self = Self {
+        ram_base: 0x4000_0000,
+        gic_dist_base: 0x0800_0000,
+        gic_cpu_base: 0x0801_0000,
+        uart_base: 0x0900_0000,
+        virtio_base: 0x0a00_0000,
+        virtio_slots: 1,
+        kernel_offset: 0x8_0000,
+        dtb_offset: 0x400_0000,
+        uart_clock_hz: 24_000_000,
+        irqs: IrqMap::QEMU_VIRT,
+    };
+
+    /// カーネルのロードアドレス
+    pub fn kernel_addr(&self) -> u64 {
+        self.ram_base + self.kernel_offset
+    }
+
+    /// Linux 直接起動時の DTB アドレス
+    pub fn dtb_addr(&self) -> u64 {
+        self.ram_base + self.dtb_offset
+    }
+
+    /// U-Boot 起動時の DTB アドレス (RAM 先頭)
+    ///
+    /// U-Boot の qemu-arm ボードは RAM 先頭から DTB を探す。
+    pub fn uboot_dtb_addr(&self) -> u64 {
+        self.ram_base
+    }
+
+    /// GICH (仮想インターフェース制御) のベースアドレス
+    ///
+    /// GIC は GICD から GICV までを 1 つの MMIO 領域で扱うため、GICD からの位置は固定。
+    pub fn gic_hyp_base(&self) -> u64 {
+        self.gic_dist_base + (GIC_HYP_BASE - GIC_DIST_BASE)
+    }
+
+    /// GICV (仮想 CPU インターフェース) のベースアドレス
+    pub fn gic_vcpu_base(&self) -> u64 {
+        self.gic_dist_base + (GIC_VCPU_BASE - GIC_DIST_BASE)
+    }
+
+    /// `slot` 番目の VirtIO MMIO トランスポートのベースアドレス
+    pub fn virtio_slot_base(&self, slot: u32) -> u64 {
+        self.virtio_base + slot as u64 * VIRTIO_SLOT_SIZE
+    }
+
+    /// デバイス MMIO 領域 (GIC / UART / VirtIO と、既定のアドレスのデバイスを含む範囲)
+    ///
+    /// 壁時計・SCMI・PL330・SMMU・共有メモリのドアベルは、それぞれのモジュールの
+    /// 既定のベースアドレスに置かれるものとして含める。
+    pub fn device_window(&self) -> Range<u64> {
+        let regions = [
+            (self.gic_dist_base, GIC_REGION_SIZE),
+            (self.uart_base, UART_REGION_SIZE),
+            (
+                self.virtio_base,
+                self.virtio_slots.max(1) as u64 * VIRTIO_SLOT_SIZE,
+            ),
+        ];
+        let (start, end) = regions
+            .into_iter()
+            .chain(FIXED_DEVICE_REGIONS)
+            .fold((u64::MAX, 0), |(start, end), (base, size)| {
+                (start.min(base), end.max(base + size))
+            });
+        start..end
+    }
+
+    /// ゲスト RAM の配置を検証する
+    ///
+    /// ベースアドレスとサイズがホストのページサイズ (16 KiB) に揃っていること、
+    /// デバイス MMIO 領域と重ならないことを確認する。
+    /// 重なっていると MMIO アクセスが RAM に吸収され、ゲストの異常が
+    /// ずっと後になってから表面化する。
+    pub fn validate_ram(&self, base: u64, size: usize) -> Result<(), Box<dyn Error>> {
+        let page_size = applevisor::PAGE_SIZE as u64;
+        let size = size as u64;
+
+        if size == 0 {
+            return Err("Guest RAM size must not be zero".into());
+        }
+        if !base.is_multiple_of(page_size) {
+            return Err(format!(
+                "Guest RAM base 0x{:x} is not aligned to the host page size (0x{:x})",
+                base, page_size
+            )
+            .into());
+        }
+        if !size.is_multiple_of(page_size) {
+            return Err(format!(
+                "Guest RAM size 0x{:x} is not a multiple of the host page size (0x{:x}); \
+                 use 0x{:x} instead",
+                size,
+                page_size,
+                size.next_multiple_of(page_size)
+            )
+            .into());
+        }
+        let end = base.checked_add(size).ok_or_else(|| {
+            format!(
+                "Guest RAM 0x{:x} + 0x{:x} overflows the address space",
+                base, size
+            )
+        })?;
+
+        let window = self.device_window();
+        if base < window.end && window.start < end {
+            return Err(format!(
+                "Guest RAM 0x{:x}-0x{:x} overlaps the device MMIO window 0x{:x}-0x{:x}",
+                base, end, window.start, window.end
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn デフォルトは_qemu_virt_互換のレイアウト() {
+        let layout = MachineLayout::default();
+        assert_eq!(layout.ram_base, 0x4000_0000);
+        assert_eq!(layout.uart_base, 0x0900_0000);
+        assert_eq!(layout.virtio_base, 0x0a00_0000);
+        assert_eq!(layout.gic_dist_base, 0x0800_0000);
+        assert_eq!(layout.gic_cpu_base, 0x0801_0000);
+        assert_eq!(layout.gic_hyp_base(), 0x0803_0000);
+        assert_eq!(layout.gic_vcpu_base(), 0x0804_0000);
+    }
+
+    #[test]
+    fn カーネルと_dtb_のアドレスは従来の固定値と一致する() {
+        let layout = MachineLayout::default();
+        assert_eq!(layout.kernel_addr(), 0x4008_0000);
+        assert_eq!(layout.dtb_addr(), 0x4400_0000);
+        assert_eq!(layout.uboot_dtb_addr(), 0x4000_0000);
+    }
+
+    #[test]
+    fn デバイス領域は_gic_から共有メモリのドアベルまで() {
+        let layout = MachineLayout::default();
+        assert_eq!(layout.device_window(), 0x0800_0000..0x0b00_0100);
+
+        // UART を動かしても既定のアドレスのデバイスは領域に残る
+        let layout = MachineLayout {
+            gic_dist_base: 0x0100_0000,
+            gic_cpu_base: 0x0101_0000,
+            uart_base: 0x0200_0000,
+            virtio_base: 0x0300_0000,
+            ..MachineLayout::default()
+        };
+        let window = layout.device_window();
+        for base in [
+            SMMU_BASE,
+            WALL_CLOCK_BASE,
+            SCMI_SHMEM_BASE,
+            PL330_BASE,
+            SHMEM_DOORBELL_BASE,
+        ] {
+            assert!(
+                window.contains(&base),
+                "0x{:x} is outside {:x?}",
+                base,
+                window
+            );
+        }
+        assert!(layout.validate_ram(0x090e_0000, 0x4000).is_err());
+    }
+
+    #[test]
+    fn validate_ram_は正しい配置を受け入れる() {
+        let layout = MachineLayout::default();
+        assert!(layout.validate_ram(0x4000_0000, 128 * 1024 * 1024).is_ok());
+        assert!(layout.validate_ram(0x10000, 0x4000).is_ok());
+        // 4GB を超える RAM
+        assert!(layout.validate_ram(0x4000_0000, 0x2_0000_0000).is_ok());
+    }
+
+    #[test]
+    fn validate_ram_はページ境界に揃っていない配置を拒否する() {
+        let layout = MachineLayout::default();
+        let err = layout.validate_ram(0x4000_0000, 0x1000).unwrap_err();
+        assert!(err.to_string().contains("use 0x4000 instead"));
+        assert!(layout.validate_ram(0x4000_1000, 0x4000).is_err());
+        assert!(layout.validate_ram(0x4000_0000, 0).is_err());
+    }
+
+    #[test]
+    fn validate_ram_はデバイス領域との重なりを拒否する() {
+        let layout = MachineLayout::default();
+        let err = layout.validate_ram(0x0900_0000, 0x4000).unwrap_err();
+        assert!(err.to_string().contains("device MMIO window"));
+        // 末尾がデバイス領域に食い込む場合
+        assert!(layout.validate_ram(0x0000_0000, 0x0800_4000).is_err());
+    }
+
+    #[test]
+    fn 割り込みの割り当ては_qemu_virt_と一致する() {
+        let irqs = MachineLayout::default().irqs;
+        assert!(irqs.validate().is_ok());
+        assert_eq!(dt_interrupt(irqs.uart, 0x4), [0, 1, 0x4]);
+        assert_eq!(dt_interrupt(irqs.virtio, 0x1), [0, 2, 0x1]);
+        assert_eq!(dt_interrupt(irqs.virt_timer, 0xf08), [1, 11, 0xf08]);
+    }
+
+    #[test]
+    fn virtio_スロットは連番のアドレスと割り込みを使う() {
+        let layout = MachineLayout {
+            virtio_slots: 4,
+            ..MachineLayout::default()
+        };
+        assert_eq!(layout.virtio_slot_base(3), 0x0a00_0600);
+        assert_eq!(layout.irqs.virtio_slot(3), 37);
+        let window = MachineLayout {
+            uart_base: 0x0c00_0000,
+            ..layout
+        }
+        .device_window();
+        assert_eq!(window.end, 0x0c00_1000);
+        assert!(layout.irqs.validate_virtio_slots(4).is_ok());
+        assert!(layout.irqs.validate_virtio_slots(MAX_INTID).is_err());
+
+        let irqs = IrqMap {
+            uart: 36,
+            ..IrqMap::QEMU_VIRT
+        };
+        let err = irqs.validate_virtio_slots(4).unwrap_err();
+        assert!(err.to_string().contains("virtio-mmio slot 2"));
+    }
+
+    #[test]
+    fn 重複や種類の誤りは拒否する() {
+        let irqs = IrqMap {
+            virtio: 33,
+            ..IrqMap::QEMU_VIRT
+        };
+        let err = irqs.validate().unwrap_err();
+        assert!(err.to_string().contains("both uart and virtio"));
+
+        let irqs = IrqMap {
+            uart: 27,
+            virt_timer: 40,
+            ..IrqMap::QEMU_VIRT
+        };
+        assert!(irqs
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("not a valid"));
+    }
+
+    #[test]
+    fn デバイスの割り込みとの衝突を検出する() {
+        let irqs = IrqMap::QEMU_VIRT;
+        assert!(irqs.check_device("shmem", 40).is_ok());
+        assert!(irqs.check_device("pl011", 33).is_ok());
+        let err = irqs.check_device("shmem", 27).unwrap_err();
+        assert!(err.to_string().contains("virt-timer"));
+        assert!(irqs.check_device("shmem", 5).is_err());
+    }
+}
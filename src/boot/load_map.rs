@@ -0,0 +1,146 @@
+//! ゲスト RAM に配置したイメージの記録
+//!
+//! カーネル・DTB・initrd・ファームウェアを名前付きで記録し、配置が重なった
+//! ときにエラーにする。重なったまま書き込むと後から書いたイメージが前の
+//! イメージを壊し、ゲストが原因の分からない停止をする。
+//!
+//! ```ignore
+//! hv.load_blob("initrd", 0x4500_0000, &initramfs)?;
+//! print!("{}", hv.load_map());
+//! ```
+
+use std::error::Error;
+use std::fmt;
+
+/// 配置済みの領域
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadedRegion {
+    /// 名前 ("kernel", "dtb" など)
+    pub name: String,
+    /// 先頭のゲスト物理アドレス
+    pub addr: u64,
+    /// サイズ (bytes)
+    pub size: usize,
+}
+
+impl LoadedRegion {
+    /// 末尾 (含まない)
+    pub fn end(&self) -> u64 {
+        self.addr + self.size as u64
+    }
+}
+
+/// 配置済みの領域の一覧 (アドレス順)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadMap {
+    regions: Vec<LoadedRegion>,
+}
+
+impl LoadMap {
+    /// 空の一覧を作成
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 領域を追加する
+    ///
+    /// 同じ名前の領域は置き換える (再起動で同じイメージを配置し直す場合)。
+    ///
+    /// # Errors
+    /// 他の領域と重なる場合はエラーを返す
+    pub fn insert(&mut self, name: &str, addr: u64, size: usize) -> Result<(), Box<dyn Error>> {
+        let region = LoadedRegion {
+            name: name.to_string(),
+            addr,
+            size,
+        };
+        if let Some(other) = self
+            .regions
+            .iter()
+            .find(|r| r.name != name && r.addr < region.end() && addr < r.end())
+        {
+            return Err(format!(
+                "{} at 0x{:x}-0x{:x} overlaps {} at 0x{:x}-0x{:x}",
+                name,
+                addr,
+                region.end(),
+                other.name,
+                other.addr,
+                other.end()
+            )
+            .into());
+        }
+        self.regions.retain(|r| r.name != name);
+        let index = self.regions.partition_point(|r| r.addr < addr);
+        self.regions.insert(index, region);
+        Ok(())
+    }
+
+    /// 名前で領域を探す
+    pub fn get(&self, name: &str) -> Option<&LoadedRegion> {
+        self.regions.iter().find(|r| r.name == name)
+    }
+
+    /// 領域の一覧 (アドレス順)
+    pub fn regions(&self) -> &[LoadedRegion] {
+        &self.regions
+    }
+
+    /// すべての記録を消す
+    pub fn clear(&mut self) {
+        self.regions.clear();
+    }
+}
+
+impl fmt::Display for LoadMap {
+    /// 1 行に 1 領域: `0x40080000-0x40a80000    10240 KiB  kernel`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for region in &self.regions {
+            writeln!(
+                f,
+                "0x{:08x}-0x{:08x} {:>8} KiB  {}",
+                region.addr,
+                region.end(),
+                region.size.div_ceil(1024),
+                region.name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn 重なる配置はエラーになる() {
+        let mut map = LoadMap::new();
+        map.insert("kernel", 0x4008_0000, 0x10_0000).unwrap();
+        map.insert("dtb", 0x4000_0000, 0x1000).unwrap();
+        // 隣接は重なりではない
+        map.insert("initrd", 0x4018_0000, 0x2000).unwrap();
+
+        let err = map.insert("firmware", 0x4017_f000, 0x2000).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "firmware at 0x4017f000-0x40181000 overlaps kernel at 0x40080000-0x40180000"
+        );
+        assert!(map.get("firmware").is_none());
+
+        let names: Vec<&str> = map.regions().iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["dtb", "kernel", "initrd"]);
+    }
+
+    #[test]
+    fn 同じ名前は置き換える() {
+        let mut map = LoadMap::new();
+        map.insert("dtb", 0x4400_0000, 0x1000).unwrap();
+        map.insert("dtb", 0x4400_0800, 0x2000).unwrap();
+        assert_eq!(map.regions().len(), 1);
+        assert_eq!(map.get("dtb").unwrap().end(), 0x4400_2800);
+        assert_eq!(map.to_string(), "0x44000800-0x44002800        8 KiB  dtb\n");
+        map.clear();
+        assert!(map.regions().is_empty());
+    }
+}
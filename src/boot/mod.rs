@@ -1,4 +1,17 @@
 //! Boot-related modules
 
+pub mod console;
 pub mod device_tree;
+pub mod fdt;
+pub mod initramfs;
 pub mod kernel;
+pub mod layout;
+pub mod load_map;
+pub mod next_boot;
+pub mod rootfs;
+pub mod stub;
+pub mod validate;
+pub mod vectors;
+pub mod verify;
+
+pub use validate::{validate, BootConfig, BootViolation};
@@ -0,0 +1,201 @@
+//! 次の再起動で起動するカーネルの指定
+//!
+//! カーネルを入れ替えながら同じテストを繰り返す (A/B テスト) とき、ゲストを
+//! 動かしたまま次のカーネル・initrd・コマンドラインを指定しておく。指定する方法は
+//! 3 つある。
+//!
+//! - ホストから [`Hypervisor::set_next_boot`](crate::Hypervisor::set_next_boot)
+//! - モニタの `boot next KERNEL [INITRD] [-- CMDLINE]`
+//! - ゲストから [`host_channel`](crate::host_channel) の HVC (パスは
+//!   [`HostChannel::serve_files`](crate::host_channel::HostChannel::serve_files)
+//!   のディレクトリから)
+//!
+//! ゲストが PSCI SYSTEM_RESET で `run()` から戻ったら、
+//! [`Hypervisor::reboot`](crate::Hypervisor::reboot) がデバイスと vCPU のレジスタを
+//! 作成直後の状態に戻し、指定があればそのカーネルで同じ VM を起動し直す。
+//!
+//! ```ignore
+//! let mut result = hv.boot_linux(&default_kernel, &default_cmdline, None)?;
+//! while result.is_system_reset() {
+//!     result = hv.reboot(&default_kernel, &default_cmdline, None)?;
+//! }
+//! ```
+
+use crate::boot::kernel::KernelImage;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+/// [`NextBoot::load`] で読み込んだイメージ
+#[derive(Debug)]
+pub struct BootImages {
+    /// カーネル
+    pub kernel: KernelImage,
+    /// initrd の内容
+    pub initrd: Option<Vec<u8>>,
+}
+
+/// 次の再起動で起動するカーネル
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NextBoot {
+    /// カーネルイメージのパス
+    pub kernel: PathBuf,
+    /// initrd のパス
+    pub initrd: Option<PathBuf>,
+    /// カーネルコマンドライン (None なら前回と同じ)
+    pub cmdline: Option<String>,
+}
+
+impl NextBoot {
+    /// `kernel` を起動する (initrd なし、コマンドラインは前回と同じ)
+    pub fn new(kernel: impl Into<PathBuf>) -> Self {
+        Self {
+            kernel: kernel.into(),
+            initrd: None,
+            cmdline: None,
+        }
+    }
+
+    /// initrd を指定する
+    pub fn initrd(mut self, initrd: impl Into<PathBuf>) -> Self {
+        self.initrd = Some(initrd.into());
+        self
+    }
+
+    /// コマンドラインを指定する
+    pub fn cmdline(mut self, cmdline: impl Into<String>) -> Self {
+        self.cmdline = Some(cmdline.into());
+        self
+    }
+
+    /// `KERNEL [INITRD] [-- CMDLINE]` の形式を解析する
+    ///
+    /// 単独の `--` だけを区切りとみなすため、パスやコマンドラインに `--` を含められる。
+    /// コマンドラインは `--` の後の文字列を空白も含めてそのまま使う。
+    pub fn parse(args: &str) -> Result<Self, Box<dyn Error>> {
+        let mut paths = Vec::new();
+        let mut cmdline = None;
+        let mut rest = args.trim_start();
+        while !rest.is_empty() {
+            let (word, tail) = rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len()));
+            if word == "--" {
+                cmdline = Some(tail.trim());
+                break;
+            }
+            paths.push(word);
+            rest = tail.trim_start();
+        }
+        let mut boot = match paths.as_slice() {
+            [kernel] => Self::new(kernel),
+            [kernel, initrd] => Self::new(kernel).initrd(initrd),
+            _ => return Err("expected KERNEL [INITRD] [-- CMDLINE]".into()),
+        };
+        if let Some(cmdline) = cmdline {
+            boot = boot.cmdline(cmdline);
+        }
+        Ok(boot)
+    }
+
+    /// ファイルが読めることを確認する
+    ///
+    /// 指定した時点で確認し、再起動してから起動できないことに気づくのを避ける。
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        let files = std::iter::once(&self.kernel).chain(&self.initrd);
+        for path in files {
+            let metadata =
+                fs::metadata(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+            if !metadata.is_file() {
+                return Err(format!("{} is not a regular file", path.display()).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// カーネルと initrd を読み込む
+    pub fn load(&self) -> Result<BootImages, Box<dyn Error>> {
+        let kernel = KernelImage::load(&self.kernel)
+            .map_err(|e| format!("Failed to load kernel {}: {}", self.kernel.display(), e))?;
+        let initrd = match &self.initrd {
+            Some(path) => Some(
+                fs::read(path)
+                    .map_err(|e| format!("Failed to load initrd {}: {}", path.display(), e))?,
+            ),
+            None => None,
+        };
+        Ok(BootImages { kernel, initrd })
+    }
+
+    /// 指定したコマンドライン (なければ `default`)
+    pub fn cmdline_or(&self, default: &str) -> String {
+        self.cmdline.clone().unwrap_or_else(|| default.to_string())
+    }
+}
+
+impl fmt::Display for NextBoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "kernel {}", self.kernel.display())?;
+        if let Some(initrd) = &self.initrd {
+            write!(f, ", initrd {}", initrd.display())?;
+        }
+        match &self.cmdline {
+            Some(cmdline) => write!(f, ", cmdline \"{}\"", cmdline),
+            None => write!(f, ", same cmdline"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn カーネル_initrd_コマンドラインを解析する() {
+        assert_eq!(
+            NextBoot::parse("out/Image-b").unwrap(),
+            NextBoot::new("out/Image-b")
+        );
+        let boot =
+            NextBoot::parse("out/Image-b out/initrd -- console=ttyAMA0  rdinit=/init").unwrap();
+        assert_eq!(
+            boot,
+            NextBoot::new("out/Image-b")
+                .initrd("out/initrd")
+                .cmdline("console=ttyAMA0  rdinit=/init")
+        );
+        assert_eq!(
+            boot.to_string(),
+            "kernel out/Image-b, initrd out/initrd, cmdline \"console=ttyAMA0  rdinit=/init\""
+        );
+        // 単語の一部の "--" は区切りではない
+        assert_eq!(
+            NextBoot::parse("out/Image--b -- rdinit=/init --debug").unwrap(),
+            NextBoot::new("out/Image--b").cmdline("rdinit=/init --debug")
+        );
+        assert!(NextBoot::parse("").is_err());
+        assert!(NextBoot::parse("-- quiet").is_err());
+        assert!(NextBoot::parse("a b c").is_err());
+    }
+
+    #[test]
+    fn 読めないファイルは指定した時点でエラーにする() {
+        let dir = std::env::temp_dir().join(format!("hv-next-boot-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let kernel = dir.join("Image");
+        fs::write(&kernel, [0x00, 0x00, 0x00, 0x14]).unwrap();
+
+        let boot = NextBoot::new(&kernel);
+        boot.check().unwrap();
+        assert_eq!(boot.load().unwrap().kernel.size(), 4);
+        assert_eq!(boot.cmdline_or("console=ttyAMA0"), "console=ttyAMA0");
+
+        let err = boot
+            .clone()
+            .initrd(dir.join("missing"))
+            .check()
+            .unwrap_err();
+        assert!(err.to_string().contains("missing"));
+        assert!(NextBoot::new(&dir).check().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
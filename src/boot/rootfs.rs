@@ -0,0 +1,178 @@
+//! ホストのディレクトリからルートファイルシステムイメージを作る
+//!
+//! initramfs はすべて RAM に展開されるため、CI で使う大きめのルートファイルシステムは
+//! 読み取り専用のイメージにして virtio-blk から渡す。イメージの生成はホストの
+//! `mkfs.erofs` (erofs-utils) または `mksquashfs` (squashfs-tools) に任せる。
+//!
+//! ```ignore
+//! let image = rootfs::pack_directory("output/rootfs", "target/rootfs.img", RootfsFormat::Erofs)?;
+//! hv.register_mmio_handler(Box::new(rootfs::block_device(VIRTIO_BASE, &image)?))?;
+//! let cmdline = format!("console=ttyAMA0 {}", RootfsFormat::Erofs.cmdline());
+//! ```
+
+#[cfg(feature = "virtio-blk")]
+use crate::devices::virtio::VirtioBlockDevice;
+use std::error::Error;
+#[cfg(feature = "virtio-blk")]
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// virtio-blk のセクタサイズ
+const SECTOR_SIZE: u64 = 512;
+
+/// ルートファイルシステムイメージの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootfsFormat {
+    /// EROFS (カーネルの `CONFIG_EROFS_FS` が必要)
+    Erofs,
+    /// squashfs (カーネルの `CONFIG_SQUASHFS` が必要)
+    Squashfs,
+}
+
+impl RootfsFormat {
+    /// イメージを生成するホストのコマンド名
+    pub fn tool(self) -> &'static str {
+        match self {
+            RootfsFormat::Erofs => "mkfs.erofs",
+            RootfsFormat::Squashfs => "mksquashfs",
+        }
+    }
+
+    /// `/dev/vda` をルートとしてマウントするカーネルコマンドライン
+    pub fn cmdline(self) -> &'static str {
+        match self {
+            RootfsFormat::Erofs => "root=/dev/vda rootfstype=erofs ro",
+            RootfsFormat::Squashfs => "root=/dev/vda rootfstype=squashfs ro",
+        }
+    }
+
+    /// イメージを生成するコマンド (所有者はすべて root にする)
+    fn command(self, dir: &Path, output: &Path) -> Command {
+        let mut cmd = Command::new(self.tool());
+        match self {
+            RootfsFormat::Erofs => {
+                cmd.arg("--all-root").arg(output).arg(dir);
+            }
+            RootfsFormat::Squashfs => {
+                cmd.arg(dir)
+                    .arg(output)
+                    .args(["-noappend", "-all-root", "-quiet"]);
+            }
+        }
+        cmd
+    }
+}
+
+/// ホストのディレクトリをイメージにまとめる
+///
+/// virtio-blk はセクタ単位でしかアクセスできないため、イメージの末尾を
+/// 512 バイト境界まで 0 で埋める。
+///
+/// # Returns
+/// 生成したイメージのパス
+///
+/// # Errors
+/// ディレクトリが存在しない場合や、ホストにコマンドがない・失敗した場合はエラーを返す
+pub fn pack_directory(
+    dir: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    format: RootfsFormat,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let (dir, output) = (dir.as_ref(), output.as_ref());
+    if !dir.is_dir() {
+        return Err(format!("Rootfs directory {} does not exist", dir.display()).into());
+    }
+
+    let result = format.command(dir, output).output().map_err(|e| {
+        format!(
+            "Failed to run {} ({}); install {}",
+            format.tool(),
+            e,
+            match format {
+                RootfsFormat::Erofs => "erofs-utils",
+                RootfsFormat::Squashfs => "squashfs-tools",
+            }
+        )
+    })?;
+    if !result.status.success() {
+        return Err(format!(
+            "{} failed ({}): {}",
+            format.tool(),
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        )
+        .into());
+    }
+
+    pad_to_sector(output)?;
+    Ok(output.to_path_buf())
+}
+
+/// イメージを読み込む virtio-blk デバイスを作成する
+#[cfg(feature = "virtio-blk")]
+pub fn block_device(
+    base_addr: u64,
+    image: impl AsRef<Path>,
+) -> Result<VirtioBlockDevice, Box<dyn Error>> {
+    let image = image.as_ref();
+    let file = File::open(image)
+        .map_err(|e| format!("Failed to open rootfs image {}: {}", image.display(), e))?;
+    let capacity = file.metadata()?.len() / SECTOR_SIZE;
+    Ok(VirtioBlockDevice::with_disk_image(
+        base_addr, file, capacity,
+    ))
+}
+
+/// ファイルサイズをセクタ境界に切り上げる
+fn pad_to_sector(path: &Path) -> Result<(), Box<dyn Error>> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    let len = file.metadata()?.len();
+    let padded = len.next_multiple_of(SECTOR_SIZE);
+    if padded != len {
+        file.set_len(padded)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn 形式ごとのコマンドを組み立てる() {
+        let cmd = RootfsFormat::Erofs.command(Path::new("rootfs"), Path::new("out.img"));
+        assert_eq!(cmd.get_program(), "mkfs.erofs");
+        let args: Vec<_> = cmd.get_args().collect();
+        assert_eq!(args, ["--all-root", "out.img", "rootfs"]);
+
+        let cmd = RootfsFormat::Squashfs.command(Path::new("rootfs"), Path::new("out.img"));
+        assert_eq!(cmd.get_program(), "mksquashfs");
+        let args: Vec<_> = cmd.get_args().collect();
+        assert_eq!(
+            args,
+            ["rootfs", "out.img", "-noappend", "-all-root", "-quiet"]
+        );
+
+        assert!(RootfsFormat::Squashfs
+            .cmdline()
+            .contains("rootfstype=squashfs"));
+    }
+
+    #[test]
+    fn 存在しないディレクトリはエラーになる() {
+        let err =
+            pack_directory("/nonexistent/rootfs", "/tmp/out.img", RootfsFormat::Erofs).unwrap_err();
+        assert!(err.to_string().contains("does not exist"));
+    }
+
+    #[test]
+    fn イメージをセクタ境界まで埋める() {
+        let path = std::env::temp_dir().join(format!("rootfs-pad-{}.img", std::process::id()));
+        std::fs::write(&path, [1u8; 700]).unwrap();
+        pad_to_sector(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 1024);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
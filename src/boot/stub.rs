@@ -0,0 +1,368 @@
+//! ブートスタブ (リセットベクタに配置する小さな起動コード)
+//!
+//! Linux 以外のペイロード (U-Boot、ベアメタル RTOS など) は、
+//! エントリー時のレジスタ状態や配置アドレスに独自の前提を持つ。
+//! このモジュールは、レジスタ設定・システムレジスタ初期化・メモリコピー・
+//! ジャンプを行う ARM64 命令列を組み立てる。
+
+/// スタブ内部で作業用に使うレジスタ (X16/X17 は IP0/IP1 で呼び出し規約上 scratch)
+const SCRATCH_REG: u8 = 16;
+const SCRATCH_REG2: u8 = 17;
+
+/// SCTLR_EL1 の安全な初期値 (MMU off、キャッシュ off、RES1 ビットのみ設定)
+pub const SCTLR_EL1_RESET: u64 = 0x30D0_0800;
+
+/// ARM64 命令エンコーダ
+///
+/// スタブ生成に必要な最小限の命令だけをサポートする。
+pub mod encode {
+    /// MOVZ Xd, #imm16, LSL #(hw * 16)
+    pub fn movz(rd: u8, imm16: u16, hw: u8) -> u32 {
+        0xD280_0000 | ((hw as u32 & 0x3) << 21) | ((imm16 as u32) << 5) | (rd as u32 & 0x1F)
+    }
+
+    /// MOVK Xd, #imm16, LSL #(hw * 16)
+    pub fn movk(rd: u8, imm16: u16, hw: u8) -> u32 {
+        0xF280_0000 | ((hw as u32 & 0x3) << 21) | ((imm16 as u32) << 5) | (rd as u32 & 0x1F)
+    }
+
+    /// BR Xn
+    pub fn br(rn: u8) -> u32 {
+        0xD61F_0000 | ((rn as u32 & 0x1F) << 5)
+    }
+
+    /// B #offset (offset は命令単位ではなくバイト単位、4 の倍数)
+    pub fn b(offset: i32) -> u32 {
+        0x1400_0000 | (((offset >> 2) as u32) & 0x03FF_FFFF)
+    }
+
+    /// CBZ Xt, #offset (バイト単位、4 の倍数)
+    pub fn cbz(rt: u8, offset: i32) -> u32 {
+        0xB400_0000 | ((((offset >> 2) as u32) & 0x7_FFFF) << 5) | (rt as u32 & 0x1F)
+    }
+
+    /// LDR Xt, [Xn], #imm (post-index)
+    pub fn ldr_post(rt: u8, rn: u8, imm: i16) -> u32 {
+        0xF840_0400
+            | (((imm as u32) & 0x1FF) << 12)
+            | ((rn as u32 & 0x1F) << 5)
+            | (rt as u32 & 0x1F)
+    }
+
+    /// STR Xt, [Xn], #imm (post-index)
+    pub fn str_post(rt: u8, rn: u8, imm: i16) -> u32 {
+        0xF800_0400
+            | (((imm as u32) & 0x1FF) << 12)
+            | ((rn as u32 & 0x1F) << 5)
+            | (rt as u32 & 0x1F)
+    }
+
+    /// SUB Xd, Xn, #imm12
+    pub fn sub_imm(rd: u8, rn: u8, imm12: u16) -> u32 {
+        0xD100_0000
+            | (((imm12 as u32) & 0xFFF) << 10)
+            | ((rn as u32 & 0x1F) << 5)
+            | (rd as u32 & 0x1F)
+    }
+
+    /// MSR <sysreg>, Xt (op0 は 2 または 3)
+    pub fn msr(op0: u8, op1: u8, crn: u8, crm: u8, op2: u8, rt: u8) -> u32 {
+        0xD500_0000
+            | (((op0 as u32) & 0x3) << 19)
+            | (((op1 as u32) & 0x7) << 16)
+            | (((crn as u32) & 0xF) << 12)
+            | (((crm as u32) & 0xF) << 8)
+            | (((op2 as u32) & 0x7) << 5)
+            | (rt as u32 & 0x1F)
+    }
+
+    /// `MRS Xt, <sysreg>` (op0 は 2 または 3)
+    pub fn mrs(op0: u8, op1: u8, crn: u8, crm: u8, op2: u8, rt: u8) -> u32 {
+        msr(op0, op1, crn, crm, op2, rt) | 1 << 21
+    }
+
+    /// ISB
+    pub const ISB: u32 = 0xD503_3FDF;
+
+    /// BRK #0
+    pub const BRK0: u32 = 0xD420_0000;
+}
+
+/// スタブが実行する個々の操作
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum StubOp {
+    /// 汎用レジスタに 64-bit 即値を設定
+    SetReg { index: u8, value: u64 },
+    /// システムレジスタに 64-bit 即値を書き込む
+    SetSysReg {
+        encoding: (u8, u8, u8, u8, u8),
+        value: u64,
+    },
+    /// src から dst へ len バイトをコピー (8 バイト単位)
+    Copy { src: u64, dst: u64, len: u64 },
+}
+
+/// ブートスタブビルダー
+///
+/// 操作を順番に積み上げ、最後に `jump` または `brk` で終端する。
+///
+/// # Example
+/// ```
+/// use hypervisor::boot::stub::BootStub;
+///
+/// // X0 に DTB アドレスを入れてカーネルへジャンプ
+/// let code = BootStub::linux(0x4400_0000, 0x4008_0000).assemble();
+/// assert!(!code.is_empty());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BootStub {
+    ops: Vec<StubOp>,
+    entry: Option<u64>,
+}
+
+impl BootStub {
+    /// 空のスタブを作成
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Linux ARM64 ブートプロトコルに従ってカーネルへジャンプするスタブ
+    ///
+    /// X0 = DTB アドレス、X1-X3 = 0
+    pub fn linux(dtb_addr: u64, kernel_entry: u64) -> Self {
+        Self::new()
+            .set_reg(0, dtb_addr)
+            .set_reg(1, 0)
+            .set_reg(2, 0)
+            .set_reg(3, 0)
+            .jump(kernel_entry)
+    }
+
+    /// ベアメタルペイロード向けのスタブ
+    ///
+    /// SCTLR_EL1 を既知の状態 (MMU/キャッシュ off) に初期化し、
+    /// `args` を X0 から順に設定してエントリーポイントへジャンプする。
+    pub fn bare_metal(entry: u64, args: &[u64]) -> Self {
+        let mut stub = Self::new().set_sctlr_el1(SCTLR_EL1_RESET);
+        for (i, &arg) in args.iter().take(8).enumerate() {
+            stub = stub.set_reg(i as u8, arg);
+        }
+        stub.jump(entry)
+    }
+
+    /// フラッシュ領域などからイメージを RAM にコピーしてジャンプするスタブ
+    ///
+    /// `len` は 8 の倍数に切り上げてコピーされる。
+    pub fn chain_load(src: u64, dst: u64, len: u64, args: &[u64]) -> Self {
+        let mut stub = Self::new().copy(src, dst, len);
+        for (i, &arg) in args.iter().take(8).enumerate() {
+            stub = stub.set_reg(i as u8, arg);
+        }
+        stub.jump(dst)
+    }
+
+    /// 汎用レジスタ Xn (0-15) に値を設定する
+    ///
+    /// X16/X17 はスタブ内部で使用するため指定できない。
+    ///
+    /// # Panics
+    /// `index` が 16 以上の場合
+    pub fn set_reg(mut self, index: u8, value: u64) -> Self {
+        assert!(
+            index < SCRATCH_REG,
+            "X16 and above are reserved for the stub"
+        );
+        self.ops.push(StubOp::SetReg { index, value });
+        self
+    }
+
+    /// SCTLR_EL1 に値を書き込む
+    pub fn set_sctlr_el1(self, value: u64) -> Self {
+        self.set_sysreg((3, 0, 1, 0, 0), value)
+    }
+
+    /// VBAR_EL1 に値を書き込む
+    pub fn set_vbar_el1(self, value: u64) -> Self {
+        self.set_sysreg((3, 0, 12, 0, 0), value)
+    }
+
+    /// 任意のシステムレジスタ (op0, op1, CRn, CRm, op2) に値を書き込む
+    pub fn set_sysreg(mut self, encoding: (u8, u8, u8, u8, u8), value: u64) -> Self {
+        self.ops.push(StubOp::SetSysReg { encoding, value });
+        self
+    }
+
+    /// src から dst へ len バイトをコピーする
+    ///
+    /// コピーループは X14/X15 を作業用に使うため、これらを引数に使う場合は
+    /// `copy` の後に `set_reg` すること。
+    pub fn copy(mut self, src: u64, dst: u64, len: u64) -> Self {
+        self.ops.push(StubOp::Copy {
+            src,
+            dst,
+            len: len.div_ceil(8) * 8,
+        });
+        self
+    }
+
+    /// 最後にジャンプするエントリーポイントを設定する
+    ///
+    /// 設定しない場合、スタブは BRK #0 で終了する。
+    pub fn jump(mut self, entry: u64) -> Self {
+        self.entry = Some(entry);
+        self
+    }
+
+    /// ジャンプ先エントリーポイントを取得
+    pub fn entry(&self) -> Option<u64> {
+        self.entry
+    }
+
+    /// スタブを ARM64 命令列に変換する
+    pub fn assemble(&self) -> Vec<u32> {
+        let mut code = Vec::new();
+        for op in &self.ops {
+            match *op {
+                StubOp::SetReg { index, value } => emit_mov_imm64(&mut code, index, value),
+                StubOp::SetSysReg {
+                    encoding: (op0, op1, crn, crm, op2),
+                    value,
+                } => {
+                    emit_mov_imm64(&mut code, SCRATCH_REG, value);
+                    code.push(encode::msr(op0, op1, crn, crm, op2, SCRATCH_REG));
+                    code.push(encode::ISB);
+                }
+                StubOp::Copy { src, dst, len } => {
+                    // X16 = src, X17 = dst, X14 = 残りバイト数, X15 = 転送データ
+                    // X14/X15 は破壊されるため、引数の設定はコピーの後に行う
+                    emit_mov_imm64(&mut code, SCRATCH_REG, src);
+                    emit_mov_imm64(&mut code, SCRATCH_REG2, dst);
+                    emit_mov_imm64(&mut code, 14, len);
+                    // loop: cbz x14, done
+                    code.push(encode::cbz(14, 5 * 4));
+                    code.push(encode::ldr_post(15, SCRATCH_REG, 8));
+                    code.push(encode::str_post(15, SCRATCH_REG2, 8));
+                    code.push(encode::sub_imm(14, 14, 8));
+                    code.push(encode::b(-4 * 4));
+                    // done:
+                }
+            }
+        }
+        match self.entry {
+            Some(entry) => {
+                emit_mov_imm64(&mut code, SCRATCH_REG, entry);
+                code.push(encode::br(SCRATCH_REG));
+            }
+            None => code.push(encode::BRK0),
+        }
+        code
+    }
+
+    /// スタブのバイトサイズ
+    pub fn size(&self) -> usize {
+        self.assemble().len() * 4
+    }
+}
+
+/// 64-bit 即値を MOVZ/MOVK の組で Xd に設定する命令を追加する
+fn emit_mov_imm64(code: &mut Vec<u32>, rd: u8, value: u64) {
+    code.push(encode::movz(rd, (value & 0xFFFF) as u16, 0));
+    for hw in 1..4u8 {
+        let chunk = ((value >> (hw as u64 * 16)) & 0xFFFF) as u16;
+        if chunk != 0 {
+            code.push(encode::movk(rd, chunk, hw));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn movz_は既存の手書きエンコーディングと一致する() {
+        // mov x0, #42
+        assert_eq!(encode::movz(0, 42, 0), 0xD280_0540);
+        // movz x1, #0x900, lsl #16
+        assert_eq!(encode::movz(1, 0x900, 1), 0xD2A1_2001);
+    }
+
+    #[test]
+    fn 分岐命令を正しくエンコードする() {
+        assert_eq!(encode::br(16), 0xD61F_0200);
+        // b . (自分自身へのジャンプ)
+        assert_eq!(encode::b(0), 0x1400_0000);
+        // b -16
+        assert_eq!(encode::b(-16), 0x17FF_FFFC);
+    }
+
+    #[test]
+    fn msr_sctlr_el1_を正しくエンコードする() {
+        // msr sctlr_el1, x16
+        assert_eq!(encode::msr(3, 0, 1, 0, 0, 16), 0xD518_1010);
+        // mrs x0, cntvct_el0
+        assert_eq!(encode::mrs(3, 3, 14, 0, 2, 0), 0xD53B_E040);
+    }
+
+    #[test]
+    fn ロードストア命令を正しくエンコードする() {
+        // ldr x15, [x16], #8
+        assert_eq!(encode::ldr_post(15, 16, 8), 0xF840_860F);
+        // str x15, [x17], #8
+        assert_eq!(encode::str_post(15, 17, 8), 0xF800_862F);
+        // sub x14, x14, #8
+        assert_eq!(encode::sub_imm(14, 14, 8), 0xD100_21CE);
+    }
+
+    #[test]
+    fn 即値ゼロのチャンクは_movk_を省略する() {
+        let mut code = Vec::new();
+        emit_mov_imm64(&mut code, 0, 0x4400_0000);
+        assert_eq!(
+            code,
+            vec![encode::movz(0, 0, 0), encode::movk(0, 0x4400, 1)]
+        );
+    }
+
+    #[test]
+    fn linux_スタブは_x0_に_dtb_を設定してジャンプする() {
+        let stub = BootStub::linux(0x4400_0000, 0x4008_0000);
+        let code = stub.assemble();
+        assert_eq!(stub.entry(), Some(0x4008_0000));
+        assert_eq!(code[0], encode::movz(0, 0, 0));
+        assert_eq!(code[1], encode::movk(0, 0x4400, 1));
+        assert_eq!(*code.last().unwrap(), encode::br(16));
+    }
+
+    #[test]
+    fn エントリーなしのスタブは_brk_で終了する() {
+        let code = BootStub::new().set_reg(0, 1).assemble();
+        assert_eq!(*code.last().unwrap(), encode::BRK0);
+    }
+
+    #[test]
+    fn bare_metal_スタブは_sctlr_を初期化する() {
+        let code = BootStub::bare_metal(0x4000_0000, &[]).assemble();
+        assert!(code.contains(&encode::msr(3, 0, 1, 0, 0, 16)));
+        assert!(code.contains(&encode::ISB));
+    }
+
+    #[test]
+    fn chain_load_はコピーループを含み長さを8バイトに切り上げる() {
+        let stub = BootStub::chain_load(0x0, 0x4008_0000, 13, &[0x4400_0000]);
+        assert!(stub.ops.contains(&StubOp::Copy {
+            src: 0,
+            dst: 0x4008_0000,
+            len: 16
+        }));
+        let code = stub.assemble();
+        assert!(code.contains(&encode::ldr_post(15, 16, 8)));
+        assert_eq!(stub.entry(), Some(0x4008_0000));
+    }
+
+    #[test]
+    #[should_panic(expected = "reserved for the stub")]
+    fn スクラッチレジスタは指定できない() {
+        let _ = BootStub::new().set_reg(16, 0);
+    }
+}
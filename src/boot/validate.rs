@@ -0,0 +1,384 @@
+//! ARM64 Linux ブートプロトコルの検証
+//!
+//! カーネルに制御を渡す前に、`Documentation/arch/arm64/booting.rst` の要件を
+//! 満たしているかを確認する。要件を満たさないままブートすると、カーネルは
+//! コンソールを初期化する前に停止することが多く、原因の特定が難しい。
+//! [`validate`] は違反をすべて列挙して返す。
+
+use super::stub::SCTLR_EL1_RESET;
+use std::fmt;
+
+/// カーネルのベースアドレスのアラインメント (2MB)
+const KERNEL_ALIGN: u64 = 0x20_0000;
+/// DTB のアラインメント
+const DTB_ALIGN: u64 = 8;
+/// DTB の最大サイズ (2MB)
+const DTB_MAX_SIZE: usize = 0x20_0000;
+/// カーネル先頭から DTB までの最大距離
+const DTB_MAX_DISTANCE: u64 = 0x8000_0000;
+
+/// SCTLR_EL1.M (MMU 有効)
+const SCTLR_M: u64 = 1 << 0;
+/// SCTLR_EL1.C (データキャッシュ有効)
+const SCTLR_C: u64 = 1 << 2;
+
+/// PSTATE.M[3:0] の EL1h / EL2h
+const PSTATE_MODE_MASK: u64 = 0xf;
+const PSTATE_EL1H: u64 = 0b0101;
+const PSTATE_EL2H: u64 = 0b1001;
+/// PSTATE.DAIF
+const PSTATE_DAIF: u64 = 0xf << 6;
+
+/// ブート時の状態
+#[derive(Debug, Clone)]
+pub struct BootConfig {
+    /// ゲスト RAM の先頭アドレス
+    pub ram_base: u64,
+    /// ゲスト RAM のサイズ (bytes)
+    pub ram_size: u64,
+    /// カーネルイメージの配置アドレス (エントリーポイント)
+    pub kernel_addr: u64,
+    /// カーネルイメージのサイズ (bytes)
+    pub kernel_size: usize,
+    /// Image ヘッダーの text_offset (2MB 境界からのオフセット)
+    pub text_offset: u64,
+    /// DTB の配置アドレス
+    pub dtb_addr: u64,
+    /// DTB のサイズ (bytes)
+    pub dtb_size: usize,
+    /// initramfs の範囲 (start, end)
+    pub initrd: Option<(u64, u64)>,
+    /// エントリー時の PSTATE (CPSR)
+    pub pstate: u64,
+    /// エントリー時の SCTLR_EL1
+    pub sctlr_el1: u64,
+    /// エントリー時の X0-X3
+    pub regs: [u64; 4],
+}
+
+impl BootConfig {
+    /// 要件を満たす既定の状態を作成する
+    ///
+    /// X0 に DTB のアドレスを、PSTATE に EL1h + DAIF マスクを、
+    /// SCTLR_EL1 に MMU/キャッシュ off のリセット値を設定する。
+    pub fn new(
+        ram_base: u64,
+        ram_size: u64,
+        kernel_addr: u64,
+        kernel_size: usize,
+        dtb_addr: u64,
+        dtb_size: usize,
+    ) -> Self {
+        Self {
+            ram_base,
+            ram_size,
+            kernel_addr,
+            kernel_size,
+            text_offset: 0x8_0000,
+            dtb_addr,
+            dtb_size,
+            initrd: None,
+            pstate: PSTATE_DAIF | PSTATE_EL1H,
+            sctlr_el1: SCTLR_EL1_RESET,
+            regs: [dtb_addr, 0, 0, 0],
+        }
+    }
+}
+
+/// ブートプロトコルの違反
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootViolation {
+    /// カーネルのベースアドレス (配置アドレス - text_offset) が 2MB 境界にない
+    KernelMisaligned { kernel_addr: u64, text_offset: u64 },
+    /// DTB が 8 バイト境界にない
+    DtbMisaligned { dtb_addr: u64 },
+    /// DTB が 2MB を超えている
+    DtbTooLarge { dtb_size: usize },
+    /// DTB がカーネルから 2GB 以上離れている
+    DtbTooFar { kernel_addr: u64, dtb_addr: u64 },
+    /// 範囲がゲスト RAM の外にある
+    OutsideRam {
+        what: &'static str,
+        start: u64,
+        end: u64,
+    },
+    /// 2 つの範囲が重なっている
+    Overlap {
+        first: &'static str,
+        second: &'static str,
+    },
+    /// initramfs の終了アドレスが開始アドレス以前
+    InvalidInitrd { start: u64, end: u64 },
+    /// MMU が有効
+    MmuEnabled,
+    /// データキャッシュが有効
+    DcacheEnabled,
+    /// EL1h / EL2h 以外で開始する
+    WrongExceptionLevel { pstate: u64 },
+    /// DAIF がマスクされていない
+    InterruptsUnmasked { pstate: u64 },
+    /// X0 が DTB のアドレスでない
+    X0NotDtb { x0: u64, dtb_addr: u64 },
+    /// X1-X3 が 0 でない (将来の拡張用に予約)
+    ReservedRegNonZero { reg: usize, value: u64 },
+}
+
+impl fmt::Display for BootViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            BootViolation::KernelMisaligned {
+                kernel_addr,
+                text_offset,
+            } => write!(
+                f,
+                "kernel at 0x{:x} is not text_offset (0x{:x}) above a 2MB-aligned base",
+                kernel_addr, text_offset
+            ),
+            BootViolation::DtbMisaligned { dtb_addr } => {
+                write!(f, "DTB at 0x{:x} is not 8-byte aligned", dtb_addr)
+            }
+            BootViolation::DtbTooLarge { dtb_size } => {
+                write!(f, "DTB is 0x{:x} bytes, exceeding the 2MB limit", dtb_size)
+            }
+            BootViolation::DtbTooFar {
+                kernel_addr,
+                dtb_addr,
+            } => write!(
+                f,
+                "DTB at 0x{:x} is more than 2GB away from the kernel at 0x{:x}",
+                dtb_addr, kernel_addr
+            ),
+            BootViolation::OutsideRam { what, start, end } => write!(
+                f,
+                "{} at 0x{:x}-0x{:x} is outside guest RAM",
+                what, start, end
+            ),
+            BootViolation::Overlap { first, second } => {
+                write!(f, "{} overlaps {}", first, second)
+            }
+            BootViolation::InvalidInitrd { start, end } => {
+                write!(f, "initrd end 0x{:x} is not above start 0x{:x}", end, start)
+            }
+            BootViolation::MmuEnabled => write!(f, "MMU must be off (SCTLR_EL1.M = 1)"),
+            BootViolation::DcacheEnabled => {
+                write!(f, "data cache must be off (SCTLR_EL1.C = 1)")
+            }
+            BootViolation::WrongExceptionLevel { pstate } => write!(
+                f,
+                "kernel must be entered at EL1h or EL2h (PSTATE = 0x{:x})",
+                pstate
+            ),
+            BootViolation::InterruptsUnmasked { pstate } => {
+                write!(f, "DAIF must be masked on entry (PSTATE = 0x{:x})", pstate)
+            }
+            BootViolation::X0NotDtb { x0, dtb_addr } => {
+                write!(f, "X0 = 0x{:x} but the DTB is at 0x{:x}", x0, dtb_addr)
+            }
+            BootViolation::ReservedRegNonZero { reg, value } => {
+                write!(f, "X{} = 0x{:x} but must be 0", reg, value)
+            }
+        }
+    }
+}
+
+/// ブート前の状態を検証する
+///
+/// # Returns
+/// 見つかった違反の一覧 (空なら要件を満たしている)
+pub fn validate(config: &BootConfig) -> Vec<BootViolation> {
+    let mut violations = Vec::new();
+
+    // カーネル: 2MB 境界 + text_offset
+    if !config
+        .kernel_addr
+        .wrapping_sub(config.text_offset)
+        .is_multiple_of(KERNEL_ALIGN)
+    {
+        violations.push(BootViolation::KernelMisaligned {
+            kernel_addr: config.kernel_addr,
+            text_offset: config.text_offset,
+        });
+    }
+
+    // DTB
+    if !config.dtb_addr.is_multiple_of(DTB_ALIGN) {
+        violations.push(BootViolation::DtbMisaligned {
+            dtb_addr: config.dtb_addr,
+        });
+    }
+    if config.dtb_size > DTB_MAX_SIZE {
+        violations.push(BootViolation::DtbTooLarge {
+            dtb_size: config.dtb_size,
+        });
+    }
+    if config.dtb_addr.abs_diff(config.kernel_addr) >= DTB_MAX_DISTANCE {
+        violations.push(BootViolation::DtbTooFar {
+            kernel_addr: config.kernel_addr,
+            dtb_addr: config.dtb_addr,
+        });
+    }
+
+    // 配置範囲
+    let mut ranges = vec![
+        (
+            "kernel",
+            config.kernel_addr,
+            config.kernel_addr.saturating_add(config.kernel_size as u64),
+        ),
+        (
+            "DTB",
+            config.dtb_addr,
+            config.dtb_addr.saturating_add(config.dtb_size as u64),
+        ),
+    ];
+    if let Some((start, end)) = config.initrd {
+        if end <= start {
+            violations.push(BootViolation::InvalidInitrd { start, end });
+        } else {
+            ranges.push(("initrd", start, end));
+        }
+    }
+    let ram_end = config.ram_base.saturating_add(config.ram_size);
+    for &(what, start, end) in &ranges {
+        if start < config.ram_base || end > ram_end {
+            violations.push(BootViolation::OutsideRam { what, start, end });
+        }
+    }
+    for (i, &(first, start1, end1)) in ranges.iter().enumerate() {
+        for &(second, start2, end2) in &ranges[i + 1..] {
+            if start1 < end2 && start2 < end1 {
+                violations.push(BootViolation::Overlap { first, second });
+            }
+        }
+    }
+
+    // MMU / キャッシュ
+    if config.sctlr_el1 & SCTLR_M != 0 {
+        violations.push(BootViolation::MmuEnabled);
+    }
+    if config.sctlr_el1 & SCTLR_C != 0 {
+        violations.push(BootViolation::DcacheEnabled);
+    }
+
+    // PSTATE
+    let mode = config.pstate & PSTATE_MODE_MASK;
+    if mode != PSTATE_EL1H && mode != PSTATE_EL2H {
+        violations.push(BootViolation::WrongExceptionLevel {
+            pstate: config.pstate,
+        });
+    }
+    if config.pstate & PSTATE_DAIF != PSTATE_DAIF {
+        violations.push(BootViolation::InterruptsUnmasked {
+            pstate: config.pstate,
+        });
+    }
+
+    // X0-X3
+    if config.regs[0] != config.dtb_addr {
+        violations.push(BootViolation::X0NotDtb {
+            x0: config.regs[0],
+            dtb_addr: config.dtb_addr,
+        });
+    }
+    for (reg, &value) in config.regs.iter().enumerate().skip(1) {
+        if value != 0 {
+            violations.push(BootViolation::ReservedRegNonZero { reg, value });
+        }
+    }
+
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid_config() -> BootConfig {
+        BootConfig::new(
+            0x4000_0000,
+            0x1000_0000,
+            0x4008_0000,
+            0x10_0000,
+            0x4400_0000,
+            0x1000,
+        )
+    }
+
+    #[test]
+    fn 既定の配置は違反なし() {
+        assert!(validate(&valid_config()).is_empty());
+    }
+
+    #[test]
+    fn カーネルと_dtb_のアラインメント違反を検出する() {
+        let mut config = valid_config();
+        config.kernel_addr = 0x4010_0000;
+        config.dtb_addr = 0x4400_0004;
+        config.regs[0] = 0x4400_0004;
+        let violations = validate(&config);
+        assert!(violations.contains(&BootViolation::KernelMisaligned {
+            kernel_addr: 0x4010_0000,
+            text_offset: 0x8_0000,
+        }));
+        assert!(violations.contains(&BootViolation::DtbMisaligned {
+            dtb_addr: 0x4400_0004
+        }));
+        assert_eq!(violations.len(), 2);
+    }
+
+    #[test]
+    fn ram_外と重なりを検出する() {
+        let mut config = valid_config();
+        config.initrd = Some((0x4fff_0000, 0x5001_0000));
+        config.dtb_addr = 0x4008_1000;
+        config.regs[0] = 0x4008_1000;
+        let violations = validate(&config);
+        assert!(violations.contains(&BootViolation::OutsideRam {
+            what: "initrd",
+            start: 0x4fff_0000,
+            end: 0x5001_0000,
+        }));
+        assert!(violations.contains(&BootViolation::Overlap {
+            first: "kernel",
+            second: "DTB",
+        }));
+    }
+
+    #[test]
+    fn cpu_状態とレジスタの違反を検出する() {
+        let mut config = valid_config();
+        config.sctlr_el1 |= SCTLR_M | SCTLR_C;
+        config.pstate = 0x5; // DAIF 未マスク
+        config.regs = [0, 0, 1, 0];
+        let violations = validate(&config);
+        assert!(violations.contains(&BootViolation::MmuEnabled));
+        assert!(violations.contains(&BootViolation::DcacheEnabled));
+        assert!(violations.contains(&BootViolation::InterruptsUnmasked { pstate: 0x5 }));
+        assert!(violations.contains(&BootViolation::X0NotDtb {
+            x0: 0,
+            dtb_addr: 0x4400_0000
+        }));
+        assert!(violations.contains(&BootViolation::ReservedRegNonZero { reg: 2, value: 1 }));
+    }
+
+    #[test]
+    fn 遠すぎる_dtb_と大きすぎる_dtb_を検出する() {
+        let mut config = BootConfig::new(
+            0x4000_0000,
+            0x1_0000_0000,
+            0x4008_0000,
+            0x1000,
+            0xC008_0000,
+            0x30_0000,
+        );
+        config.regs[0] = config.dtb_addr;
+        let violations = validate(&config);
+        assert!(violations.contains(&BootViolation::DtbTooLarge {
+            dtb_size: 0x30_0000
+        }));
+        assert!(violations
+            .iter()
+            .any(|v| matches!(v, BootViolation::DtbTooFar { .. })));
+        assert!(BootViolation::MmuEnabled.to_string().contains("MMU"));
+    }
+}
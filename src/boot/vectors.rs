@@ -0,0 +1,212 @@
+//! 例外ベクタのシム (VBAR を設定しないゲスト用)
+//!
+//! ベアメタルのテストペイロードは VBAR_EL1 を設定しないことが多い。その状態で
+//! 想定外の IRQ や fault が起きると、ゲストは VBAR_EL1 (リセット値 0) 付近の
+//! 中身のないアドレスへ飛び、例外を繰り返して戻ってこなくなる。
+//!
+//! [`RunOptions::vector_shim`](crate::run_options::RunOptions::vector_shim) を
+//! 指定すると、16 個のベクタそれぞれに `BRK #(SHIM_BRK_BASE + 番号)` を置いた
+//! テーブルをゲスト RAM に配置して VBAR_EL1 に設定する。例外はその場で BRK の
+//! VM Exit になり、[`HypervisorResult::shim_vector`](crate::HypervisorResult::shim_vector)
+//! でどのベクタに入ったかが分かる (ESR_EL1 などは `el1` に入る)。
+
+use std::fmt;
+
+/// ベクタテーブルの大きさ (bytes、配置は 2KB アライン)
+pub const VECTOR_TABLE_SIZE: u64 = 0x800;
+
+/// 各ベクタの間隔 (bytes)
+const VECTOR_STRIDE: u64 = 0x80;
+
+/// シムの BRK の即値の先頭 (ベクタ番号 0-15 を足す)
+pub const SHIM_BRK_BASE: u16 = 0x5600;
+
+/// 例外を受けたときの状態 (ベクタテーブルの 4 つのグループ)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorSource {
+    /// 同じ EL、SP_EL0 を使用中 (EL1t)
+    CurrentSp0,
+    /// 同じ EL、SP_ELx を使用中 (EL1h)
+    CurrentSpx,
+    /// 下位の EL (AArch64)
+    LowerA64,
+    /// 下位の EL (AArch32)
+    LowerA32,
+}
+
+impl VectorSource {
+    /// 例外を受けたときの PSTATE (SPSR_EL1 に保存する値) から決める
+    pub fn from_spsr(spsr: u64) -> Self {
+        if spsr & 0x10 != 0 {
+            Self::LowerA32
+        } else if (spsr >> 2) & 0x3 == 0 {
+            Self::LowerA64
+        } else if spsr & 0x1 == 0 {
+            Self::CurrentSp0
+        } else {
+            Self::CurrentSpx
+        }
+    }
+}
+
+/// 例外の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorKind {
+    /// 同期例外 (fault、SVC、未定義命令など)
+    Sync,
+    /// IRQ
+    Irq,
+    /// FIQ
+    Fiq,
+    /// SError
+    SError,
+}
+
+/// シムのベクタ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShimVector {
+    /// 例外を受けたときの状態
+    pub source: VectorSource,
+    /// 例外の種類
+    pub kind: VectorKind,
+}
+
+impl ShimVector {
+    /// ベクタ番号 (0-15、テーブル先頭からのオフセット / 0x80)
+    pub fn index(&self) -> u16 {
+        let source = match self.source {
+            VectorSource::CurrentSp0 => 0,
+            VectorSource::CurrentSpx => 1,
+            VectorSource::LowerA64 => 2,
+            VectorSource::LowerA32 => 3,
+        };
+        let kind = match self.kind {
+            VectorKind::Sync => 0,
+            VectorKind::Irq => 1,
+            VectorKind::Fiq => 2,
+            VectorKind::SError => 3,
+        };
+        source * 4 + kind
+    }
+
+    /// ベクタテーブル先頭からのオフセット (bytes)
+    pub fn offset(&self) -> u64 {
+        self.index() as u64 * VECTOR_STRIDE
+    }
+
+    /// ベクタ番号から作る
+    pub fn from_index(index: u16) -> Option<Self> {
+        let source = match index / 4 {
+            0 => VectorSource::CurrentSp0,
+            1 => VectorSource::CurrentSpx,
+            2 => VectorSource::LowerA64,
+            3 => VectorSource::LowerA32,
+            _ => return None,
+        };
+        let kind = match index % 4 {
+            0 => VectorKind::Sync,
+            1 => VectorKind::Irq,
+            2 => VectorKind::Fiq,
+            _ => VectorKind::SError,
+        };
+        Some(Self { source, kind })
+    }
+
+    /// BRK の VM Exit の ESR_EL2 からシムのベクタを取り出す
+    ///
+    /// シムの BRK でなければ None を返す。
+    pub fn from_syndrome(syndrome: u64) -> Option<Self> {
+        if (syndrome >> 26) & 0x3f != 0x3c {
+            return None;
+        }
+        let imm = (syndrome & 0xffff) as u16;
+        Self::from_index(imm.checked_sub(SHIM_BRK_BASE)?)
+    }
+}
+
+impl fmt::Display for ShimVector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            VectorKind::Sync => "synchronous exception",
+            VectorKind::Irq => "IRQ",
+            VectorKind::Fiq => "FIQ",
+            VectorKind::SError => "SError",
+        };
+        let source = match self.source {
+            VectorSource::CurrentSp0 => "current EL with SP_EL0",
+            VectorSource::CurrentSpx => "current EL with SP_ELx",
+            VectorSource::LowerA64 => "lower EL (AArch64)",
+            VectorSource::LowerA32 => "lower EL (AArch32)",
+        };
+        write!(f, "{} from {}", kind, source)
+    }
+}
+
+/// シムのベクタテーブル ([`VECTOR_TABLE_SIZE`] bytes)
+///
+/// 各ベクタの先頭に BRK を置き、残りは 0 (未定義命令) で埋める。
+pub fn shim_table() -> Vec<u8> {
+    let mut table = vec![0u8; VECTOR_TABLE_SIZE as usize];
+    for (index, entry) in table.chunks_mut(VECTOR_STRIDE as usize).enumerate() {
+        let brk = 0xD420_0000 | (SHIM_BRK_BASE as u32 + index as u32) << 5;
+        entry[..4].copy_from_slice(&brk.to_le_bytes());
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ベクタごとに異なる_brk_を置く() {
+        let table = shim_table();
+        assert_eq!(table.len(), 0x800);
+        // 0x280: 同じ EL・SP_ELx の IRQ
+        let word = u32::from_le_bytes(table[0x280..0x284].try_into().unwrap());
+        assert_eq!(word, 0xD420_0000 | (SHIM_BRK_BASE as u32 + 5) << 5);
+        assert_eq!(table[0x284..0x300], [0; 0x7c]);
+
+        let syndrome = 0x3c << 26 | 1 << 25 | (word as u64 >> 5) & 0xffff;
+        let vector = ShimVector::from_syndrome(syndrome).unwrap();
+        assert_eq!(
+            vector,
+            ShimVector {
+                source: VectorSource::CurrentSpx,
+                kind: VectorKind::Irq
+            }
+        );
+        assert_eq!(vector.to_string(), "IRQ from current EL with SP_ELx");
+    }
+
+    #[test]
+    fn シム以外の_brk_は区別する() {
+        assert_eq!(ShimVector::from_syndrome(0x3c << 26), None);
+        assert_eq!(
+            ShimVector::from_syndrome(0x3c << 26 | (SHIM_BRK_BASE as u64 + 16)),
+            None
+        );
+        // HVC などの VM Exit
+        assert_eq!(
+            ShimVector::from_syndrome(0x16 << 26 | SHIM_BRK_BASE as u64),
+            None
+        );
+        for index in 0..16 {
+            assert_eq!(ShimVector::from_index(index).unwrap().index(), index);
+        }
+    }
+
+    #[test]
+    fn spsr_から例外を受けた状態を決める() {
+        assert_eq!(VectorSource::from_spsr(0x3c5), VectorSource::CurrentSpx);
+        assert_eq!(VectorSource::from_spsr(0x3c4), VectorSource::CurrentSp0);
+        assert_eq!(VectorSource::from_spsr(0x3c0), VectorSource::LowerA64);
+        // AArch32 の User モード
+        assert_eq!(VectorSource::from_spsr(0x10), VectorSource::LowerA32);
+        let sync = ShimVector {
+            source: VectorSource::LowerA64,
+            kind: VectorKind::Sync,
+        };
+        assert_eq!(sync.offset(), 0x400);
+    }
+}
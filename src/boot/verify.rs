@@ -0,0 +1,400 @@
+//! ゲストに渡す前のイメージの検証フック
+//!
+//! 共有の CI ランナーなど、決められたカーネルとディスクだけを起動させたい環境向け。
+//! [`KernelImage::load_verified`](crate::boot::kernel::KernelImage::load_verified) と
+//! `VirtioBlockDevice::open_verified` は、読み込んだバイト列をゲストから見える場所に
+//! 置く前に [`ImageVerifier`] を呼び、エラーならそこで止める。
+//!
+//! SHA-256 の許可リストは [`Sha256AllowList`] で用意している。署名の確認などは
+//! クロージャで書ける。ディスクイメージは大きいためメモリに読み込まず、
+//! ダイジェスト ([`Image::sha256`]) だけを渡す。
+//!
+//! ```ignore
+//! let allow = Sha256AllowList::from_sha256sum(&fs::read_to_string("SHA256SUMS")?)?;
+//! let kernel = KernelImage::load_verified("Image", &allow)?;
+//! let disk = VirtioBlockDevice::open_verified(0, "rootfs.img", true, &|image: &Image<'_>| {
+//!     check_signature(image.path, &image.sha256)
+//! })?;
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// 検証するイメージの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageKind {
+    /// カーネル (またはファームウェア) のイメージ
+    Kernel,
+    /// ディスクイメージ
+    Disk,
+}
+
+impl fmt::Display for ImageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Kernel => "kernel",
+            Self::Disk => "disk",
+        })
+    }
+}
+
+/// 検証するイメージ
+#[derive(Debug, Clone, Copy)]
+pub struct Image<'a> {
+    /// 種類
+    pub kind: ImageKind,
+    /// 読み込んだファイル
+    pub path: &'a Path,
+    /// ファイルの内容 (ディスクイメージは読み込まないため None)
+    pub data: Option<&'a [u8]>,
+    /// ファイルの内容の SHA-256
+    pub sha256: [u8; 32],
+}
+
+impl<'a> Image<'a> {
+    /// 読み込んだ内容からダイジェストを計算して作る
+    pub fn from_bytes(kind: ImageKind, path: &'a Path, data: &'a [u8]) -> Self {
+        Self {
+            kind,
+            path,
+            data: Some(data),
+            sha256: sha256(data),
+        }
+    }
+}
+
+/// イメージをゲストに渡してよいか判断する
+///
+/// `Fn(&Image) -> Result<(), Box<dyn Error>>` のクロージャも実装している。
+pub trait ImageVerifier {
+    /// 渡してよければ `Ok(())`、拒否する場合は理由をエラーで返す
+    fn verify(&self, image: &Image<'_>) -> Result<(), Box<dyn Error>>;
+}
+
+impl<F> ImageVerifier for F
+where
+    F: Fn(&Image<'_>) -> Result<(), Box<dyn Error>>,
+{
+    fn verify(&self, image: &Image<'_>) -> Result<(), Box<dyn Error>> {
+        self(image)
+    }
+}
+
+/// SHA-256 の許可リスト
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sha256AllowList {
+    digests: Vec<[u8; 32]>,
+}
+
+impl Sha256AllowList {
+    /// 空の許可リスト (すべて拒否する)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ダイジェストを 16 進数で追加する
+    ///
+    /// # Errors
+    /// 64 文字の 16 進数でない場合
+    pub fn allow(mut self, hex: &str) -> Result<Self, Box<dyn Error>> {
+        self.digests.push(parse_digest(hex)?);
+        Ok(self)
+    }
+
+    /// `sha256sum` の出力 (`<digest>  <file>` の行) から作る
+    ///
+    /// 空行と `#` で始まる行は無視する。ファイル名は照合に使わない。
+    pub fn from_sha256sum(text: &str) -> Result<Self, Box<dyn Error>> {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .try_fold(Self::new(), |list, line| {
+                list.allow(line.split_whitespace().next().unwrap_or_default())
+            })
+    }
+
+    /// 許可したダイジェストの数
+    pub fn len(&self) -> usize {
+        self.digests.len()
+    }
+
+    /// 空か
+    pub fn is_empty(&self) -> bool {
+        self.digests.is_empty()
+    }
+}
+
+impl ImageVerifier for Sha256AllowList {
+    fn verify(&self, image: &Image<'_>) -> Result<(), Box<dyn Error>> {
+        if self.digests.contains(&image.sha256) {
+            return Ok(());
+        }
+        Err(format!(
+            "{} {}: SHA-256 {} is not in the allow-list",
+            image.kind,
+            image.path.display(),
+            to_hex(&image.sha256)
+        )
+        .into())
+    }
+}
+
+fn parse_digest(hex: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    let invalid = || format!("'{}' is not a SHA-256 digest (64 hex digits)", hex);
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid().into());
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(digest)
+}
+
+/// 16 進数の小文字で表す
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// SHA-256 のラウンド定数
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 の初期値
+const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// 少しずつ入力できる SHA-256
+///
+/// 外部の crate に頼らないための最小限の実装 (起動時に 1 回ずつ使う程度の速度)。
+#[derive(Debug, Clone)]
+pub struct Sha256 {
+    h: [u32; 8],
+    /// 64 bytes に満たない入力の残り
+    block: Vec<u8>,
+    /// 入力したバイト数
+    len: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self {
+            h: H0,
+            block: Vec::with_capacity(64),
+            len: 0,
+        }
+    }
+}
+
+impl Sha256 {
+    /// 空の入力から始める
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 入力を追加する
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if !self.block.is_empty() {
+            let take = data.len().min(64 - self.block.len());
+            self.block.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.block.len() < 64 {
+                return;
+            }
+            let block: [u8; 64] = self.block[..].try_into().unwrap();
+            self.compress(&block);
+            self.block.clear();
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(block.try_into().unwrap());
+        }
+        self.block.extend_from_slice(blocks.remainder());
+    }
+
+    /// `reader` の終わりまで入力する
+    pub fn update_reader<R: Read>(&mut self, mut reader: R) -> io::Result<()> {
+        let mut buf = vec![0u8; 1 << 20];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => self.update(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// 入力したバイト数
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// まだ何も入力していないか
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// ダイジェストを計算する
+    pub fn finish(mut self) -> [u8; 32] {
+        // 末尾に 0x80・0 の詰め物・ビット長を付けて 64 bytes の倍数にする
+        let bits = self.len.wrapping_mul(8);
+        let mut tail = std::mem::take(&mut self.block);
+        tail.push(0x80);
+        while tail.len() % 64 != 56 {
+            tail.push(0);
+        }
+        tail.extend_from_slice(&bits.to_be_bytes());
+        for block in tail.chunks_exact(64) {
+            self.compress(block.try_into().unwrap());
+        }
+
+        let mut digest = [0u8; 32];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.h) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = self.h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in self.h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+/// SHA-256 のダイジェスト
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_は既知のダイジェストと一致する() {
+        assert_eq!(
+            to_hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            to_hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // 詰め物が次のブロックにはみ出す長さ (56 bytes)
+        assert_eq!(
+            to_hex(&sha256(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            to_hex(&sha256(&[b'a'; 1000])),
+            "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+        );
+    }
+
+    #[test]
+    fn 分けて入力しても同じダイジェストになる() {
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        for split in [1, 63, 64, 65, 500] {
+            let mut hasher = Sha256::new();
+            for chunk in data.chunks(split) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.len(), 1000);
+            assert_eq!(hasher.finish(), sha256(&data), "chunks of {}", split);
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update_reader(&data[..]).unwrap();
+        assert_eq!(hasher.finish(), sha256(&data));
+    }
+
+    #[test]
+    fn 許可リストにないイメージを拒否する() {
+        let list = Sha256AllowList::from_sha256sum(
+            "# 許可するカーネル\n\
+             ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  Image\n\n",
+        )
+        .unwrap();
+        assert_eq!(list.len(), 1);
+
+        let path = Path::new("Image");
+        let image = |data| Image::from_bytes(ImageKind::Kernel, path, data);
+        assert!(list.verify(&image(b"abc")).is_ok());
+        let err = list.verify(&image(b"abd")).unwrap_err();
+        assert!(err.to_string().starts_with("kernel Image: SHA-256 "));
+        assert!(Sha256AllowList::new().verify(&image(b"abc")).is_err());
+
+        assert!(Sha256AllowList::new().allow("abc").is_err());
+        assert!(Sha256AllowList::from_sha256sum(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn クロージャで検証できる() {
+        let only_disks = |image: &Image<'_>| -> Result<(), Box<dyn Error>> {
+            match image.kind {
+                ImageKind::Disk => Ok(()),
+                kind => Err(format!("{} images are not allowed", kind).into()),
+            }
+        };
+        let image = Image::from_bytes(ImageKind::Kernel, Path::new("Image"), &[]);
+        assert!(only_disks.verify(&image).is_err());
+        assert!(only_disks
+            .verify(&Image {
+                kind: ImageKind::Disk,
+                ..image
+            })
+            .is_ok());
+    }
+}
@@ -0,0 +1,106 @@
+//! 他のスレッドから vCPU スレッドで操作を実行させるハンドル
+//!
+//! デバイスやレジスタは vCPU スレッドが持っているため、モニタや QMP の
+//! コマンドは [`ControlHandle::call`] で操作を送り、run ループが VM Exit の合間に
+//! 実行する。送った後に vCPU を kick するので、ゲストがループしていても
+//! すぐに実行される。
+//!
+//! ```ignore
+//! let control = hv.control_handle();
+//! std::thread::spawn(move || {
+//!     let pc = control.call(|hv| hv.get_reg(Reg::PC))??;
+//!     println!("guest is at 0x{:x}", pc);
+//! });
+//! hv.run(None, None, None)?;
+//! ```
+//!
+//! run ループの外 (`run()` の前後) では実行されないため、応答は
+//! [`REPLY_TIMEOUT`] で打ち切る。打ち切った操作は後から実行されない。
+//! 打ち切る時点で実行が始まっていた場合は、終わるまで待って結果を返す。
+
+use crate::vcpu_handle::VcpuHandle;
+use crate::Hypervisor;
+use std::error::Error;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// vCPU スレッドの応答を待つ時間
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 操作がまだ実行されていない
+const PENDING: u8 = 0;
+/// run ループが操作の実行を始めた
+const STARTED: u8 = 1;
+/// 呼び出し側が待つのをやめた (操作は実行しない)
+const ABANDONED: u8 = 2;
+
+/// run ループで実行する操作
+pub(crate) type ControlFn = Box<dyn FnOnce(&mut Hypervisor) + Send>;
+
+/// vCPU スレッドで操作を実行させるハンドル (`Send`、clone 可能)
+///
+/// [`Hypervisor::control_handle`](crate::Hypervisor::control_handle) で作成する。
+#[derive(Debug, Clone)]
+pub struct ControlHandle {
+    requests: mpsc::Sender<ControlFn>,
+    vcpu: VcpuHandle,
+}
+
+impl ControlHandle {
+    pub(crate) fn new(requests: mpsc::Sender<ControlFn>, vcpu: VcpuHandle) -> Self {
+        Self { requests, vcpu }
+    }
+
+    /// `f` を run ループで実行し、結果を返す
+    ///
+    /// # Errors
+    /// [`REPLY_TIMEOUT`] 以内に run ループが実行しない場合、または
+    /// `Hypervisor` が破棄された場合
+    pub fn call<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Hypervisor) -> R + Send + 'static,
+    ) -> Result<R, Box<dyn Error>> {
+        let (reply, answer) = mpsc::channel();
+        let state = Arc::new(AtomicU8::new(PENDING));
+        let op_state = Arc::clone(&state);
+        let op: ControlFn = Box::new(move |hv| {
+            let start =
+                op_state.compare_exchange(PENDING, STARTED, Ordering::SeqCst, Ordering::SeqCst);
+            if start.is_ok() {
+                let _ = reply.send(f(hv));
+            }
+        });
+        self.requests
+            .send(op)
+            .map_err(|_| "the hypervisor has been dropped")?;
+        self.vcpu.kick()?;
+        match answer.recv_timeout(REPLY_TIMEOUT) {
+            Ok(result) => Ok(result),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let abandon =
+                    state.compare_exchange(PENDING, ABANDONED, Ordering::SeqCst, Ordering::SeqCst);
+                if abandon.is_ok() {
+                    return Err(format!(
+                        "no reply within {:?}: the guest is not running",
+                        REPLY_TIMEOUT
+                    )
+                    .into());
+                }
+                // 既に実行が始まっているので、終わるまで待つ
+                answer
+                    .recv()
+                    .map_err(|_| "the hypervisor has been dropped".into())
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err("the hypervisor has been dropped".into())
+            }
+        }
+    }
+
+    /// vCPU を止めるハンドル
+    pub fn vcpu(&self) -> &VcpuHandle {
+        &self.vcpu
+    }
+}
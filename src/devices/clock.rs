@@ -0,0 +1,186 @@
+//! ホストの壁時計を公開する MMIO クロックデバイス (ptp_kvm 相当)
+//!
+//! ネットワークなしでゲストの時計をホストに合わせるための最小限のデバイス。
+//! ゲストが `CTRL` に書き込んだ時点のホストの時刻 (UNIX 時刻) をラッチし、
+//! 秒とナノ秒のレジスタから読み出せるようにする。
+//!
+//! # ゲスト側のプロトコル
+//!
+//! Linux には対応するドライバがないため、`/dev/mem` や UIO
+//! (`uio_pdrv_genirq.of_id=hypervisor,wall-clock`) でレジスタをマップして読む。
+//!
+//! 1. `MAGIC` が `0x4b4c_4357` (`"WCLK"`) であることを確認する
+//! 2. `t0 = CNTVCT_EL0` を読む
+//! 3. `CTRL` に 1 を書き込む (ホストの時刻がラッチされる)
+//! 4. `t1 = CNTVCT_EL0` を読む
+//! 5. `SEC_LO` / `SEC_HI` / `NSEC` を読む (`SEC_LO` を 8 バイトで読んでもよい)
+//!
+//! ラッチした時刻は仮想カウンタの `(t0 + t1) / 2` に対応し、誤差は `(t1 - t0) / 2` 以下。
+//! 複数回の測定から周波数のずれを推定すれば、chrony の `refclock` や
+//! `adjtimex` で時計を調整できる (ptp_kvm と同じ考え方)。
+
+use crate::mmio::MmioHandler;
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 壁時計デバイスの既定のベースアドレス (QEMU virt の空き領域)
+pub const WALL_CLOCK_BASE: u64 = 0x090c_0000;
+/// レジスタ領域のサイズ
+pub const WALL_CLOCK_SIZE: u64 = 0x1000;
+/// Device Tree の compatible
+pub const WALL_CLOCK_COMPATIBLE: &str = "hypervisor,wall-clock";
+
+/// `MAGIC` レジスタの値 ("WCLK")
+pub const WALL_CLOCK_MAGIC: u32 = 0x4b4c_4357;
+/// プロトコルのバージョン
+pub const WALL_CLOCK_VERSION: u32 = 1;
+
+/// レジスタオフセット
+pub mod regs {
+    /// マジック (R)
+    pub const MAGIC: u64 = 0x00;
+    /// バージョン (R)
+    pub const VERSION: u64 = 0x04;
+    /// 1 を書き込むと現在時刻をラッチする (W)
+    pub const CTRL: u64 = 0x08;
+    /// ラッチ回数 (R)、読み出し中に別のラッチが入っていないかの確認用
+    pub const SEQ: u64 = 0x0c;
+    /// ラッチした UNIX 時刻の秒 下位 32 bit (R)
+    pub const SEC_LO: u64 = 0x10;
+    /// ラッチした UNIX 時刻の秒 上位 32 bit (R)
+    pub const SEC_HI: u64 = 0x14;
+    /// ラッチした時刻のナノ秒 (R)
+    pub const NSEC: u64 = 0x18;
+}
+
+/// `CTRL` のラッチ要求ビット
+const CTRL_LATCH: u64 = 1;
+
+/// ホストの時刻の取得元
+type TimeSource = Box<dyn Fn() -> Duration + Send + Sync>;
+
+/// ホストの壁時計デバイス
+pub struct WallClockDevice {
+    base_addr: u64,
+    now: TimeSource,
+    latched: Duration,
+    seq: u32,
+}
+
+impl WallClockDevice {
+    /// ホストの `SystemTime` を公開するデバイスを作成
+    pub fn new(base_addr: u64) -> Self {
+        Self::with_time_source(
+            base_addr,
+            Box::new(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+            }),
+        )
+    }
+
+    /// 時刻の取得元を指定して作成 (テストや時刻のずれの再現に使う)
+    pub fn with_time_source(base_addr: u64, now: TimeSource) -> Self {
+        Self {
+            base_addr,
+            now,
+            latched: Duration::ZERO,
+            seq: 0,
+        }
+    }
+}
+
+impl MmioHandler for WallClockDevice {
+    fn name(&self) -> &str {
+        "wall-clock"
+    }
+
+    fn base(&self) -> u64 {
+        self.base_addr
+    }
+
+    fn size(&self) -> u64 {
+        WALL_CLOCK_SIZE
+    }
+
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, Box<dyn Error>> {
+        let secs = self.latched.as_secs();
+        let value = match offset {
+            regs::MAGIC => WALL_CLOCK_MAGIC as u64,
+            regs::VERSION => WALL_CLOCK_VERSION as u64,
+            regs::SEQ => self.seq as u64,
+            regs::SEC_LO if size == 8 => secs,
+            regs::SEC_LO => secs & 0xFFFF_FFFF,
+            regs::SEC_HI => secs >> 32,
+            regs::NSEC => self.latched.subsec_nanos() as u64,
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn reset(&mut self) {
+        self.latched = Duration::ZERO;
+        self.seq = 0;
+    }
+
+    fn write(&mut self, offset: u64, value: u64, _size: usize) -> Result<(), Box<dyn Error>> {
+        if offset == regs::CTRL && value & CTRL_LATCH != 0 {
+            self.latched = (self.now)();
+            self.seq = self.seq.wrapping_add(1);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    fn device_with_clock() -> (WallClockDevice, Arc<AtomicU64>) {
+        let nanos = Arc::new(AtomicU64::new(0));
+        let source = Arc::clone(&nanos);
+        let device = WallClockDevice::with_time_source(
+            WALL_CLOCK_BASE,
+            Box::new(move || Duration::from_nanos(source.load(Ordering::SeqCst))),
+        );
+        (device, nanos)
+    }
+
+    #[test]
+    fn ラッチした時刻を秒とナノ秒で読める() {
+        let (mut device, nanos) = device_with_clock();
+        assert_eq!(device.read(regs::MAGIC, 4).unwrap(), 0x4b4c_4357);
+        assert_eq!(device.read(regs::VERSION, 4).unwrap(), 1);
+
+        // 2^32 秒を超える時刻で上位ワードを確認する
+        nanos.store(
+            (1u64 << 32) * 1_000_000_000 + 5 * 1_000_000_000 + 250,
+            Ordering::SeqCst,
+        );
+        device.write(regs::CTRL, 1, 4).unwrap();
+        assert_eq!(device.read(regs::SEC_LO, 4).unwrap(), 5);
+        assert_eq!(device.read(regs::SEC_HI, 4).unwrap(), 1);
+        assert_eq!(device.read(regs::SEC_LO, 8).unwrap(), (1 << 32) + 5);
+        assert_eq!(device.read(regs::NSEC, 4).unwrap(), 250);
+        assert_eq!(device.read(regs::SEQ, 4).unwrap(), 1);
+    }
+
+    #[test]
+    fn ラッチするまで時刻は変わらない() {
+        let (mut device, nanos) = device_with_clock();
+        nanos.store(7_000_000_000, Ordering::SeqCst);
+        device.write(regs::CTRL, 1, 4).unwrap();
+        nanos.store(9_000_000_000, Ordering::SeqCst);
+        assert_eq!(device.read(regs::SEC_LO, 4).unwrap(), 7);
+
+        // ラッチビット以外の書き込みは無視する
+        device.write(regs::CTRL, 0, 4).unwrap();
+        assert_eq!(device.read(regs::SEC_LO, 4).unwrap(), 7);
+        device.write(regs::CTRL, 1, 4).unwrap();
+        assert_eq!(device.read(regs::SEC_LO, 4).unwrap(), 9);
+        assert_eq!(device.read(regs::SEQ, 4).unwrap(), 2);
+    }
+}
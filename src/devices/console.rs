@@ -0,0 +1,462 @@
+//! コンソール出力のバッファリング
+//!
+//! ゲストが UART_DR に 1 文字書くたびに `print!` + `flush()` すると、
+//! 1 文字ごとにシステムコールが発生し、earlycon の出力が多い起動が大幅に遅くなる。
+//! [`ConsoleSink`] は出力をまとめ、[`FlushPolicy`] に従って書き出す。
+//!
+//! 時間ベースの書き出しは次の文字が書かれた時点で判定するため、
+//! 改行のないプロンプトなどは [`ConsoleSink::flush`] を呼ぶか
+//! 破棄されるまで残ることがある。
+//!
+//! 暴走したゲストが端末を埋めないよう、[`ConsoleSink::set_rate_limit`] で
+//! 一定時間あたりのバイト数を制限できる。捨てた分は出力が再開したときに
+//! `[console: N bytes suppressed]` として知らせる。
+//!
+//! # テスト用の対話 API
+//!
+//! [`Console`] は出力の待ち合わせ (`expect`) と入力の注入 (`send_line`) を行う。
+//! ゲストは別スレッドで実行し、テスト側のスレッドからログインプロンプトや
+//! シェルのコマンドを操作する:
+//!
+//! ```ignore
+//! let console = Console::new();
+//! let uart = Pl011Uart::with_io(UART_BASE, console.sink(), console.input());
+//! // ... 別スレッドでゲストを実行 ...
+//! console.expect("login: ", Duration::from_secs(30))?;
+//! console.send_line("root");
+//! console.expect("# ", Duration::from_secs(5))?;
+//! ```
+
+use crate::rate_limit::RateLimiter;
+use crate::vcpu_handle::VcpuHandle;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::{self, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// バッファがこのサイズに達したら方針に関係なく書き出す
+const MAX_BUFFERED: usize = 4096;
+
+/// コンソール出力の書き出し方針
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// 1 文字ごとに書き出す (デバッグ用、出力が他のログと混ざらない)
+    #[default]
+    Unbuffered,
+    /// 改行ごとに書き出す
+    Line,
+    /// 前回の書き出しから指定時間が経過したら書き出す
+    Interval(Duration),
+}
+
+/// バッファ付きのコンソール出力先
+pub struct ConsoleSink {
+    out: Box<dyn Write + Send + Sync>,
+    buf: Vec<u8>,
+    policy: FlushPolicy,
+    last_flush: Instant,
+    /// バイト数の制限 (None なら制限しない)
+    rate_limit: Option<RateLimiter>,
+}
+
+impl ConsoleSink {
+    /// 出力先と書き出し方針を指定して作成
+    pub fn new(out: Box<dyn Write + Send + Sync>, policy: FlushPolicy) -> Self {
+        Self {
+            out,
+            buf: Vec::with_capacity(MAX_BUFFERED),
+            policy,
+            last_flush: Instant::now(),
+            rate_limit: None,
+        }
+    }
+
+    /// 標準出力に書き出すコンソールを作成
+    pub fn stdout(policy: FlushPolicy) -> Self {
+        Self::new(Box::new(io::stdout()), policy)
+    }
+
+    /// 現在の書き出し方針
+    pub fn policy(&self) -> FlushPolicy {
+        self.policy
+    }
+
+    /// 書き出し方針を変更する (バッファ済みの出力は先に書き出す)
+    pub fn set_policy(&mut self, policy: FlushPolicy) -> io::Result<()> {
+        self.flush()?;
+        self.policy = policy;
+        Ok(())
+    }
+
+    /// 一定時間あたりに出力するバイト数を制限する (None なら制限しない)
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimiter>) {
+        self.rate_limit = limit;
+    }
+
+    /// 制限によって捨てたバイト数
+    pub fn suppressed_bytes(&self) -> u64 {
+        self.rate_limit
+            .as_ref()
+            .map_or(0, RateLimiter::total_suppressed)
+    }
+
+    /// 1 バイト出力する
+    pub fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        if let Some(limit) = &mut self.rate_limit {
+            match limit.check() {
+                None => return Ok(()),
+                Some(suppressed) => self.push_suppressed(suppressed),
+            }
+        }
+        self.buf.push(byte);
+        let due = match self.policy {
+            FlushPolicy::Unbuffered => true,
+            FlushPolicy::Line => byte == b'\n',
+            FlushPolicy::Interval(interval) => self.last_flush.elapsed() >= interval,
+        };
+        if due || self.buf.len() >= MAX_BUFFERED {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// バッファ済みの出力を書き出す
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(suppressed) = self.rate_limit.as_mut().map(RateLimiter::take_suppressed) {
+            self.push_suppressed(suppressed);
+        }
+        if !self.buf.is_empty() {
+            self.out.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        self.out.flush()?;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    fn push_suppressed(&mut self, suppressed: u64) {
+        if suppressed > 0 {
+            let note = format!("\n[console: {} bytes suppressed]\n", suppressed);
+            self.buf.extend_from_slice(note.as_bytes());
+        }
+    }
+}
+
+impl Drop for ConsoleSink {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+type KickFn = Box<dyn Fn() + Send>;
+
+/// ゲストへの入力キュー (UART の受信 FIFO に相当)
+///
+/// clone したハンドルは同じキューを共有する。
+#[derive(Clone, Default)]
+pub struct ConsoleInput {
+    queue: Arc<Mutex<VecDeque<u8>>>,
+    /// 入力が届いたら vCPU を抜けさせ、受信割り込みをすぐに配信する
+    kick: Arc<Mutex<Option<KickFn>>>,
+}
+
+impl ConsoleInput {
+    /// 空の入力キューを作成
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 入力を追加する
+    pub fn push(&self, bytes: &[u8]) {
+        self.queue.lock().unwrap().extend(bytes);
+        if let Some(kick) = self.kick.lock().unwrap().as_ref() {
+            kick();
+        }
+    }
+
+    /// 入力が届いたときに kick する vCPU を設定する (`Hypervisor::vcpu_handle`)
+    ///
+    /// 設定しない場合、入力はゲストが次に VM Exit したときに処理される。
+    pub fn set_kick(&self, handle: VcpuHandle) {
+        *self.kick.lock().unwrap() = Some(Box::new(move || {
+            if let Err(e) = handle.kick() {
+                eprintln!("[DEBUG] Failed to kick vCPU for console input: {}", e);
+            }
+        }));
+    }
+
+    /// 先頭の 1 バイトを取り出す
+    pub fn pop(&self) -> Option<u8> {
+        self.queue.lock().unwrap().pop_front()
+    }
+
+    /// 入力が空かどうか
+    pub fn is_empty(&self) -> bool {
+        self.queue.lock().unwrap().is_empty()
+    }
+}
+
+/// expect 用の出力記録
+#[derive(Default)]
+struct Transcript {
+    /// これまでの全出力
+    data: Vec<u8>,
+    /// expect で読み進めた位置
+    cursor: usize,
+}
+
+/// 出力を記録して待機中の expect を起こす出力先
+struct TranscriptWriter {
+    shared: Arc<(Mutex<Transcript>, Condvar)>,
+}
+
+impl Write for TranscriptWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (lock, cvar) = &*self.shared;
+        lock.lock().unwrap().data.extend_from_slice(buf);
+        cvar.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// テスト用のコンソール (expect / pexpect 相当)
+///
+/// clone したハンドルは同じ出力記録と入力キューを共有する。
+#[derive(Clone, Default)]
+pub struct Console {
+    output: Arc<(Mutex<Transcript>, Condvar)>,
+    input: ConsoleInput,
+}
+
+impl Console {
+    /// 新しいコンソールを作成
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// UART に渡す出力先 (1 文字ごとに記録する)
+    pub fn sink(&self) -> ConsoleSink {
+        let writer = TranscriptWriter {
+            shared: Arc::clone(&self.output),
+        };
+        ConsoleSink::new(Box::new(writer), FlushPolicy::Unbuffered)
+    }
+
+    /// UART に渡す入力キュー
+    pub fn input(&self) -> ConsoleInput {
+        self.input.clone()
+    }
+
+    /// ゲストに文字列を送る
+    pub fn send(&self, text: &str) {
+        self.input.push(text.as_bytes());
+    }
+
+    /// ゲストに 1 行送る (末尾に改行を付ける)
+    pub fn send_line(&self, line: &str) {
+        self.send(line);
+        self.input.push(b"\n");
+    }
+
+    /// `pattern` が出力されるまで待つ
+    ///
+    /// 前回の expect の一致位置より後の出力だけを検索し、一致した位置まで読み進める。
+    ///
+    /// # Returns
+    /// 前回の一致位置から今回の一致の末尾までの出力
+    ///
+    /// # Errors
+    /// `timeout` までに出力されなかった場合はエラーを返す
+    pub fn expect(&self, pattern: &str, timeout: Duration) -> Result<String, Box<dyn Error>> {
+        let deadline = Instant::now() + timeout;
+        let (lock, cvar) = &*self.output;
+        let mut transcript = lock.lock().unwrap();
+        loop {
+            let start = transcript.cursor;
+            if let Some(pos) = find(&transcript.data[start..], pattern.as_bytes()) {
+                let end = start + pos + pattern.len();
+                transcript.cursor = end;
+                return Ok(String::from_utf8_lossy(&transcript.data[start..end]).into_owned());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(format!(
+                    "Timed out after {:?} waiting for {:?}; unmatched output: {:?}",
+                    timeout,
+                    pattern,
+                    String::from_utf8_lossy(&transcript.data[start..])
+                )
+                .into());
+            }
+            transcript = cvar.wait_timeout(transcript, deadline - now).unwrap().0;
+        }
+    }
+
+    /// これまでの全出力
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.output.0.lock().unwrap().data).into_owned()
+    }
+}
+
+/// `haystack` の中で `needle` が最初に現れる位置
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::host_time::ManualClock;
+    use std::thread;
+
+    /// 書き込み回数と内容を記録する出力先
+    #[derive(Clone, Default)]
+    struct Recorder {
+        data: Arc<Mutex<Vec<u8>>>,
+        writes: Arc<Mutex<usize>>,
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            *self.writes.lock().unwrap() += 1;
+            self.data.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn sink(policy: FlushPolicy) -> (ConsoleSink, Recorder) {
+        let recorder = Recorder::default();
+        (
+            ConsoleSink::new(Box::new(recorder.clone()), policy),
+            recorder,
+        )
+    }
+
+    #[test]
+    fn unbuffered_は1文字ごとに書き出す() {
+        let (mut console, recorder) = sink(FlushPolicy::Unbuffered);
+        for &b in b"ok" {
+            console.write_byte(b).unwrap();
+        }
+        assert_eq!(*recorder.writes.lock().unwrap(), 2);
+        assert_eq!(&*recorder.data.lock().unwrap(), b"ok");
+    }
+
+    #[test]
+    fn line_は改行でまとめて書き出す() {
+        let (mut console, recorder) = sink(FlushPolicy::Line);
+        for &b in b"Booting Linux\nlogin: " {
+            console.write_byte(b).unwrap();
+        }
+        assert_eq!(*recorder.writes.lock().unwrap(), 1);
+        assert_eq!(&*recorder.data.lock().unwrap(), b"Booting Linux\n");
+
+        // 改行のない出力は flush で書き出される
+        console.flush().unwrap();
+        assert_eq!(&*recorder.data.lock().unwrap(), b"Booting Linux\nlogin: ");
+    }
+
+    #[test]
+    fn interval_は経過時間で書き出す() {
+        let (mut console, recorder) = sink(FlushPolicy::Interval(Duration::from_secs(3600)));
+        for &b in b"abc\n" {
+            console.write_byte(b).unwrap();
+        }
+        assert!(recorder.data.lock().unwrap().is_empty());
+
+        console
+            .set_policy(FlushPolicy::Interval(Duration::ZERO))
+            .unwrap();
+        assert_eq!(&*recorder.data.lock().unwrap(), b"abc\n");
+        console.write_byte(b'd').unwrap();
+        assert_eq!(&*recorder.data.lock().unwrap(), b"abc\nd");
+    }
+
+    #[test]
+    fn 破棄時に残りを書き出す() {
+        let (mut console, recorder) = sink(FlushPolicy::Line);
+        console.write_byte(b'x').unwrap();
+        drop(console);
+        assert_eq!(&*recorder.data.lock().unwrap(), b"x");
+    }
+
+    #[test]
+    fn 制限を超えた出力を捨てて件数を知らせる() {
+        let clock = ManualClock::new();
+        let (mut console, recorder) = sink(FlushPolicy::Line);
+        console.set_rate_limit(Some(RateLimiter::with_clock(
+            4,
+            Duration::from_secs(1),
+            Arc::new(clock.clone()),
+        )));
+        for &b in b"spam spam\n" {
+            console.write_byte(b).unwrap();
+        }
+        clock.advance(Duration::from_secs(1));
+        for &b in b"ok\n" {
+            console.write_byte(b).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8_lossy(&recorder.data.lock().unwrap()),
+            "spam\n[console: 6 bytes suppressed]\nok\n"
+        );
+
+        // 出力が止んだ後は flush で知らせる
+        for &b in b"again" {
+            console.write_byte(b).unwrap();
+        }
+        console.flush().unwrap();
+        assert!(String::from_utf8_lossy(&recorder.data.lock().unwrap())
+            .ends_with("ok\na\n[console: 4 bytes suppressed]\n"));
+        assert_eq!(console.suppressed_bytes(), 10);
+    }
+
+    #[test]
+    fn expect_は出力を待って読み進める() {
+        let console = Console::new();
+        let mut sink = console.sink();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            for &b in b"Welcome\nlogin: " {
+                sink.write_byte(b).unwrap();
+            }
+        });
+
+        let seen = console.expect("login: ", Duration::from_secs(5)).unwrap();
+        assert_eq!(seen, "Welcome\nlogin: ");
+        writer.join().unwrap();
+
+        // 一致済みの出力は再び一致しない
+        let err = console
+            .expect("login: ", Duration::from_millis(10))
+            .unwrap_err();
+        assert!(err.to_string().contains("Timed out"));
+        assert_eq!(console.output(), "Welcome\nlogin: ");
+    }
+
+    #[test]
+    fn send_line_は改行付きで入力キューに積む() {
+        let console = Console::new();
+        let input = console.input();
+        console.send_line("root");
+
+        let mut received = Vec::new();
+        while let Some(b) = input.pop() {
+            received.push(b);
+        }
+        assert_eq!(received, b"root\n");
+        assert!(input.is_empty());
+    }
+}
@@ -0,0 +1,216 @@
+//! ゲストのカーネルログ (dmesg) の構造化
+//!
+//! earlycon などのコンソール出力を行単位に分解し、printk の
+//! ログレベル (`<6>`) とタイムスタンプ (`[    0.000000]`) を取り出す。
+//! テストで出力全体を部分文字列検索する代わりに、特定のメッセージを
+//! レコード単位で確認できる。
+//!
+//! [`LogCapture`] は [`ConsoleSink`](super::console::ConsoleSink) の出力先として使う:
+//!
+//! ```ignore
+//! let capture = LogCapture::new();
+//! let console = ConsoleSink::new(Box::new(capture.clone()), FlushPolicy::Line);
+//! hv.register_mmio_handler(Box::new(Pl011Uart::with_console(UART_BASE, console)))?;
+//! // ... ゲストを実行 ...
+//! assert!(capture.records().any(|r| r.message.starts_with("Booting Linux")));
+//! ```
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// ログ 1 行分
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// printk のタイムスタンプ (`printk.time=1` の場合のみ)
+    pub timestamp: Option<Duration>,
+    /// ログレベル (0 = KERN_EMERG .. 7 = KERN_DEBUG、行頭に `<N>` がある場合のみ)
+    pub level: Option<u8>,
+    /// タイムスタンプとログレベルを除いた本文
+    pub message: String,
+}
+
+impl LogRecord {
+    /// 1 行を解析する (末尾の改行は含めない)
+    pub fn parse(line: &str) -> Self {
+        let mut rest = line.trim_end_matches('\r');
+
+        let mut level = None;
+        if let Some((n, tail)) = rest.strip_prefix('<').and_then(|s| s.split_once('>')) {
+            if let Some(n) = n.parse::<u8>().ok().filter(|&n| n <= 7) {
+                level = Some(n);
+                rest = tail;
+            }
+        }
+
+        let mut timestamp = None;
+        if let Some((ts, tail)) = rest.strip_prefix('[').and_then(|s| s.split_once(']')) {
+            if let Some(ts) = parse_timestamp(ts.trim()) {
+                timestamp = Some(ts);
+                rest = tail.strip_prefix(' ').unwrap_or(tail);
+            }
+        }
+
+        Self {
+            timestamp,
+            level,
+            message: rest.to_string(),
+        }
+    }
+}
+
+/// `秒.マイクロ秒` 形式のタイムスタンプを解析する
+fn parse_timestamp(s: &str) -> Option<Duration> {
+    let (secs, frac) = s.split_once('.')?;
+    if frac.is_empty() || frac.len() > 9 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let secs = secs.parse::<u64>().ok()?;
+    let nanos = frac.parse::<u32>().ok()? * 10u32.pow(9 - frac.len() as u32);
+    Some(Duration::new(secs, nanos))
+}
+
+/// バイト列をログレコードに分解する
+///
+/// 改行で終わっていない最後の行もレコードとして返す。
+pub fn parse_log(bytes: &[u8]) -> impl Iterator<Item = LogRecord> + '_ {
+    let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    let empty = bytes.is_empty();
+    bytes
+        .split(|&b| b == b'\n')
+        .filter(move |_| !empty)
+        .map(|line| LogRecord::parse(&String::from_utf8_lossy(line)))
+}
+
+struct CaptureState {
+    records: Vec<LogRecord>,
+    partial: Vec<u8>,
+    tee: Option<Box<dyn Write + Send + Sync>>,
+}
+
+/// コンソール出力を受け取ってログレコードに分解する出力先
+///
+/// clone したハンドルは同じ記録を共有する。
+#[derive(Clone)]
+pub struct LogCapture {
+    state: Arc<Mutex<CaptureState>>,
+}
+
+impl LogCapture {
+    /// 出力を記録だけする
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(CaptureState {
+                records: Vec::new(),
+                partial: Vec::new(),
+                tee: None,
+            })),
+        }
+    }
+
+    /// 記録しながら `out` (例: `io::stdout()`) にもそのまま書き出す
+    pub fn tee(out: Box<dyn Write + Send + Sync>) -> Self {
+        let capture = Self::new();
+        capture.state.lock().unwrap().tee = Some(out);
+        capture
+    }
+
+    /// 改行まで受け取った行のレコード
+    pub fn records(&self) -> impl Iterator<Item = LogRecord> {
+        self.state.lock().unwrap().records.clone().into_iter()
+    }
+
+    /// 本文が `pattern` を含む最初のレコード
+    pub fn find(&self, pattern: &str) -> Option<LogRecord> {
+        self.records().find(|r| r.message.contains(pattern))
+    }
+
+    /// まだ改行を受け取っていない行 (プロンプトなど)
+    pub fn partial_line(&self) -> String {
+        String::from_utf8_lossy(&self.state.lock().unwrap().partial).into_owned()
+    }
+}
+
+impl Default for LogCapture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for LogCapture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(tee) = state.tee.as_mut() {
+            tee.write_all(buf)?;
+        }
+        for &b in buf {
+            if b == b'\n' {
+                let line = std::mem::take(&mut state.partial);
+                let record = LogRecord::parse(&String::from_utf8_lossy(&line));
+                state.records.push(record);
+            } else {
+                state.partial.push(b);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.state.lock().unwrap().tee.as_mut() {
+            Some(tee) => tee.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::console::{ConsoleSink, FlushPolicy};
+
+    #[test]
+    fn タイムスタンプとログレベルを取り出す() {
+        let record = LogRecord::parse("<6>[    0.000000] Booting Linux on physical CPU 0x0");
+        assert_eq!(record.level, Some(6));
+        assert_eq!(record.timestamp, Some(Duration::ZERO));
+        assert_eq!(record.message, "Booting Linux on physical CPU 0x0");
+
+        let record = LogRecord::parse("[    1.234567] Run /init as init process\r");
+        assert_eq!(record.level, None);
+        assert_eq!(record.timestamp, Some(Duration::from_micros(1_234_567)));
+        assert_eq!(record.message, "Run /init as init process");
+    }
+
+    #[test]
+    fn 形式に合わない行は本文として扱う() {
+        let record = LogRecord::parse("[OK] <not a level>");
+        assert_eq!(record.timestamp, None);
+        assert_eq!(record.level, None);
+        assert_eq!(record.message, "[OK] <not a level>");
+    }
+
+    #[test]
+    fn バイト列を行ごとに分解する() {
+        let records: Vec<_> = parse_log(b"[    0.000000] a\n[    0.100000] b\nlogin: ").collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1].timestamp, Some(Duration::from_millis(100)));
+        assert_eq!(records[2].message, "login: ");
+
+        assert_eq!(parse_log(b"x\n").count(), 1);
+        assert_eq!(parse_log(b"").count(), 0);
+    }
+
+    #[test]
+    fn コンソール出力をレコードとして記録する() {
+        let capture = LogCapture::new();
+        let mut console = ConsoleSink::new(Box::new(capture.clone()), FlushPolicy::Unbuffered);
+        for &b in b"[    0.000000] Booting Linux\n[    0.500000] Kernel panic\n/ # " {
+            console.write_byte(b).unwrap();
+        }
+
+        assert_eq!(capture.records().count(), 2);
+        let panic = capture.find("panic").unwrap();
+        assert_eq!(panic.timestamp, Some(Duration::from_millis(500)));
+        assert_eq!(capture.partial_line(), "/ # ");
+    }
+}
@@ -0,0 +1,216 @@
+//! デバイスの障害注入
+//!
+//! 統合テストでゲストのドライバのエラー処理を通すため、デバイスを意図的に
+//! 失敗させる。[`FaultInjector`] をデバイスに渡し、ホスト側に残した clone から
+//! 障害を設定する。
+//!
+//! ```ignore
+//! let faults = FaultInjector::new();
+//! let mut disk = VirtioBlockDevice::with_disk_image(VIRTIO_BASE, file, capacity);
+//! disk.set_fault_injector(faults.clone());
+//! hv.register_mmio_handler(Box::new(disk))?;
+//! faults.fail_disk_io(100..108, IoDirection::Read);
+//! // ゲストのセクタ 100 の読み取りは VIRTIO_BLK_S_IOERR で完了する
+//! hv.run(None, None, None)?;
+//! assert_eq!(faults.injected().disk_errors, 1);
+//! ```
+//!
+//! | 障害 | 対象 | ゲストから見える結果 |
+//! |------|------|----------------------|
+//! | [`FaultInjector::fail_disk_io`] | virtio-blk | セクタ範囲の読み書きが失敗する |
+//! | [`FaultInjector::uart_overrun`] | PL011 | 受信文字に Overrun Error (DR.OE, RSR.OE, OEIS) が付く |
+//! | [`FaultInjector::drop_frames`] | virtio-net | 送受信フレームが破棄される |
+
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+/// 障害を起こす I/O の方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoDirection {
+    /// 読み取りのみ
+    Read,
+    /// 書き込みのみ
+    Write,
+    /// 読み書きの両方
+    Both,
+}
+
+impl IoDirection {
+    fn matches(self, write: bool) -> bool {
+        match self {
+            IoDirection::Read => !write,
+            IoDirection::Write => write,
+            IoDirection::Both => true,
+        }
+    }
+}
+
+/// セクタ範囲の I/O エラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DiskFault {
+    sectors: Range<u64>,
+    direction: IoDirection,
+    /// 残りの回数 (None = 解除するまで)
+    remaining: Option<u32>,
+}
+
+/// これまでに注入した障害の回数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaultCounts {
+    /// 失敗させたディスク I/O
+    pub disk_errors: u64,
+    /// Overrun Error を付けた受信文字
+    pub uart_overruns: u64,
+    /// 破棄したネットワークフレーム
+    pub dropped_frames: u64,
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    disk: Vec<DiskFault>,
+    uart_overruns: u32,
+    dropped_frames: u32,
+    injected: FaultCounts,
+}
+
+/// デバイスとテストで共有する障害の設定
+///
+/// clone したハンドルは同じ設定を共有する。
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultInjector {
+    /// 障害なしの状態で作成
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `sectors` と重なる I/O を `clear` するまで失敗させる
+    pub fn fail_disk_io(&self, sectors: Range<u64>, direction: IoDirection) {
+        self.add_disk_fault(sectors, direction, None);
+    }
+
+    /// `sectors` と重なる I/O を `count` 回だけ失敗させる (リトライの確認用)
+    pub fn fail_disk_io_times(&self, sectors: Range<u64>, direction: IoDirection, count: u32) {
+        self.add_disk_fault(sectors, direction, Some(count));
+    }
+
+    fn add_disk_fault(&self, sectors: Range<u64>, direction: IoDirection, remaining: Option<u32>) {
+        self.state.lock().unwrap().disk.push(DiskFault {
+            sectors,
+            direction,
+            remaining,
+        });
+    }
+
+    /// 次に受信する `count` 文字に Overrun Error を付ける
+    pub fn uart_overrun(&self, count: u32) {
+        self.state.lock().unwrap().uart_overruns += count;
+    }
+
+    /// 次の `count` フレームを破棄する
+    pub fn drop_frames(&self, count: u32) {
+        self.state.lock().unwrap().dropped_frames += count;
+    }
+
+    /// 設定したすべての障害を解除する (注入した回数は残す)
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.disk.clear();
+        state.uart_overruns = 0;
+        state.dropped_frames = 0;
+    }
+
+    /// これまでに注入した障害の回数
+    pub fn injected(&self) -> FaultCounts {
+        self.state.lock().unwrap().injected
+    }
+
+    /// `sector` から `count` セクタの I/O を失敗させるか判定する (デバイス側)
+    pub fn check_disk_io(&self, sector: u64, count: u64, write: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let end = sector.saturating_add(count.max(1));
+        let Some(index) = state.disk.iter().position(|f| {
+            f.direction.matches(write) && f.sectors.start < end && sector < f.sectors.end
+        }) else {
+            return false;
+        };
+        if let Some(remaining) = state.disk[index].remaining.as_mut() {
+            *remaining -= 1;
+            if *remaining == 0 {
+                state.disk.remove(index);
+            }
+        }
+        state.injected.disk_errors += 1;
+        true
+    }
+
+    /// 受信文字に Overrun Error を付けるか判定する (デバイス側)
+    pub fn take_uart_overrun(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.uart_overruns == 0 {
+            return false;
+        }
+        state.uart_overruns -= 1;
+        state.injected.uart_overruns += 1;
+        true
+    }
+
+    /// フレームを破棄するか判定する (デバイス側)
+    pub fn take_frame_drop(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.dropped_frames == 0 {
+            return false;
+        }
+        state.dropped_frames -= 1;
+        state.injected.dropped_frames += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn 範囲と方向が一致する_io_だけを失敗させる() {
+        let faults = FaultInjector::new();
+        faults.fail_disk_io(100..108, IoDirection::Read);
+
+        assert!(faults.check_disk_io(104, 1, false));
+        // 範囲の手前から始まり、範囲に重なる I/O
+        assert!(faults.check_disk_io(96, 8, false));
+        assert!(!faults.check_disk_io(108, 1, false));
+        assert!(!faults.check_disk_io(104, 1, true));
+        assert_eq!(faults.injected().disk_errors, 2);
+
+        faults.clear();
+        assert!(!faults.check_disk_io(104, 1, false));
+    }
+
+    #[test]
+    fn 回数を指定した障害は使い切ると解除される() {
+        let faults = FaultInjector::new();
+        faults.fail_disk_io_times(0..1, IoDirection::Both, 2);
+        assert!(faults.check_disk_io(0, 1, true));
+        assert!(faults.check_disk_io(0, 1, false));
+        assert!(!faults.check_disk_io(0, 1, false));
+
+        faults.uart_overrun(1);
+        faults.drop_frames(1);
+        assert!(faults.take_uart_overrun());
+        assert!(!faults.take_uart_overrun());
+        assert!(faults.clone().take_frame_drop());
+        assert!(!faults.take_frame_drop());
+        assert_eq!(
+            faults.injected(),
+            FaultCounts {
+                disk_errors: 2,
+                uart_overruns: 1,
+                dropped_frames: 1,
+            }
+        );
+    }
+}
@@ -3,10 +3,44 @@
 //! ARM GICv2 の基本的なエミュレーションを提供します。
 //! - GICD (Distributor): 割り込みのルーティングと優先度管理
 //! - GICC (CPU Interface): CPU への割り込み配信
+//!
+//! # レジスタ領域
+//!
+//! QEMU virt と同じ配置で、1 つの MMIO ハンドラが以下をまとめて扱う。
+//!
+//! | 領域 | オフセット  | サイズ   |
+//! |------|-------------|----------|
+//! | GICD | 0x0_0000    | 0x1_0000 |
+//! | GICC | 0x1_0000    | 0x2_0000 |
+//! | GICH | 0x3_0000    | 0x1_0000 |
+//! | GICV | 0x4_0000    | 0x2_0000 |
+//!
+//! GICC は 64KB 境界に揃えた GIC-400 の配置をとり、前半 64KB は 8KB の
+//! GICC フレーム (ページ 0 + `GICC_DIR` のページ 1) を 0x2000 ごとに繰り返し、
+//! 後半 64KB はページ 1 を繰り返す。これにより 4KB 配置を前提とするカーネルも
+//! 64KB 配置 (`GICC_DIR` が +0x1_0000) を前提とするカーネルも同じレジスタに届く。
+//!
+//! 仮想化拡張は、KVM を組み込んだゲストカーネルが起動時の確認で止まらない
+//! 程度だけ提供する。GICH はリストレジスタなどを読み書きできる最小限の
+//! レジスタ (保持するだけで仮想割り込みは配信しない) とし、GICV は RAZ/WI。
+//! メンテナンス割り込みはアサートしない。
+//!
+//! レイテンシ計測の時刻は [`HostClock`] から得る。[`Gic`] は `core` / `alloc` に
+//! ある型を `std` 経由で使わない (lint で確認) が、vCPU スレッドとデバイスで共有する
+//! [`SharedGic`] は `std::sync::Mutex` を使い、`no_std` ではビルドしない。
+
+#![deny(clippy::std_instead_of_core, clippy::std_instead_of_alloc)]
 
+use super::host_time::{HostClock, SystemClock};
+#[cfg(feature = "snapshot")]
+use crate::migration::{DeviceState, StateDecoder, StateEncoder};
 use crate::mmio::MmioHandler;
-use std::error::Error;
-use std::sync::{Arc, Mutex};
+use crate::stats::LatencyStats;
+use crate::vm_config::MAX_VCPUS;
+use alloc::sync::Arc;
+use core::error::Error;
+use core::time::Duration;
+use std::sync::Mutex;
 
 /// 共有 GIC タイプ
 pub type SharedGic = Arc<Mutex<Gic>>;
@@ -15,10 +49,25 @@ pub type SharedGic = Arc<Mutex<Gic>>;
 pub const GIC_DIST_BASE: u64 = 0x0800_0000;
 pub const GIC_CPU_BASE: u64 = 0x0801_0000;
 pub const GIC_DIST_SIZE: u64 = 0x1_0000;
-pub const GIC_CPU_SIZE: u64 = 0x1_0000;
+pub const GIC_CPU_SIZE: u64 = 0x2_0000;
+/// GICH (仮想インターフェース制御) のベースアドレスとサイズ
+pub const GIC_HYP_BASE: u64 = 0x0803_0000;
+pub const GIC_HYP_SIZE: u64 = 0x1_0000;
+/// GICV (仮想 CPU インターフェース) のベースアドレスとサイズ
+pub const GIC_VCPU_BASE: u64 = 0x0804_0000;
+pub const GIC_VCPU_SIZE: u64 = 0x2_0000;
+/// GICH のメンテナンス割り込み (PPI 9、QEMU `virt` と同じ)
+pub const GIC_MAINTENANCE_IRQ: u32 = 25;
+/// GICD から GICV 末尾までの MMIO 領域サイズ
+pub const GIC_REGION_SIZE: u64 = GIC_VCPU_BASE + GIC_VCPU_SIZE - GIC_DIST_BASE;
+
+/// GICC フレームのサイズ (ページ 0 + ページ 1)
+const GICC_FRAME_SIZE: u64 = 0x2000;
+/// 64KB 配置での GICC ページ 1 の開始オフセット
+const GICC_PAGE1_64K: u64 = 0x1_0000;
 
 /// サポートする最大割り込み数 (SPIs + PPIs + SGIs)
-const MAX_IRQS: usize = 256;
+pub const MAX_IRQS: usize = 256;
 /// SPI (Shared Peripheral Interrupts) の開始番号
 const SPI_START: usize = 32;
 
@@ -50,7 +99,40 @@ mod gicc_regs {
     pub const EOIR: u64 = 0x010; // End of Interrupt Register
     pub const RPR: u64 = 0x014; // Running Priority Register
     pub const HPPIR: u64 = 0x018; // Highest Priority Pending Interrupt Register
+    pub const APR: u64 = 0x0D0; // Active Priorities Registers (0x0D0-0x0DC)
     pub const IIDR: u64 = 0x00FC; // CPU Interface Identification Register
+    pub const DIR: u64 = 0x1000; // Deactivate Interrupt Register
+}
+
+// GICH レジスタオフセット
+mod gich_regs {
+    pub const HCR: u64 = 0x000; // Hypervisor Control Register
+    pub const VTR: u64 = 0x004; // VGIC Type Register
+    pub const VMCR: u64 = 0x008; // Virtual Machine Control Register
+    pub const MISR: u64 = 0x010; // Maintenance Interrupt Status Register
+    pub const EISR0: u64 = 0x020; // End of Interrupt Status Register
+    pub const ELRSR0: u64 = 0x030; // Empty List Register Status Register
+    pub const APR: u64 = 0x0F0; // Active Priorities Register
+    pub const LR0: u64 = 0x100; // List Registers
+}
+
+/// GICH のリストレジスタの数
+const GICH_LIST_REGS: usize = 4;
+/// GICH_VTR: PRIbits = 5, PREbits = 5, ListRegs = GICH_LIST_REGS
+const GICH_VTR_VALUE: u32 = (4 << 29) | (4 << 26) | (GICH_LIST_REGS as u32 - 1);
+/// GICH_LR の State フィールド (bits [29:28])
+const GICH_LR_STATE_MASK: u32 = 0b11 << 28;
+
+/// GICC_CTLR.EOImodeNS: EOIR は優先度を下げるだけで、非アクティブ化は DIR で行う
+const GICC_CTLR_EOIMODE: u64 = 1 << 9;
+
+/// GICC 領域内のオフセットを 8KB フレーム内のオフセットに変換する
+fn gicc_frame_offset(offset: u64) -> u64 {
+    if offset < GICC_PAGE1_64K {
+        offset % GICC_FRAME_SIZE
+    } else {
+        gicc_regs::DIR + (offset & 0xFFF)
+    }
 }
 
 /// GICv2 Distributor の状態
@@ -72,6 +154,8 @@ pub struct GicDistributor {
     /// 将来の拡張用に保持
     #[allow(dead_code)]
     irq_config: [u32; MAX_IRQS / 16],
+    /// 接続されている CPU の数 (GICD_TYPER.CPUNumber)
+    cpu_count: u32,
 }
 
 impl Default for GicDistributor {
@@ -91,6 +175,7 @@ impl GicDistributor {
             irq_priority: [0xA0; MAX_IRQS], // 中程度の優先度で初期化
             irq_targets: [0x01; MAX_IRQS],  // CPU 0 をターゲット
             irq_config: [0; MAX_IRQS / 16],
+            cpu_count: 1,
         };
         // SGI (0-15) はデフォルトで有効
         dist.irq_enabled[0] = 0xFFFF;
@@ -102,10 +187,11 @@ impl GicDistributor {
     /// TYPER レジスタの値を取得
     fn get_typer(&self) -> u32 {
         // ITLinesNumber: (MAX_IRQS / 32) - 1
-        // CPUNumber: 0 (1 CPU)
+        // CPUNumber: CPU 数 - 1
         // SecurityExtn: 0 (セキュリティ拡張なし)
         let it_lines = ((MAX_IRQS / 32) - 1) as u32;
-        it_lines & 0x1F
+        let cpu_number = (self.cpu_count - 1) & 0x7;
+        (it_lines & 0x1F) | (cpu_number << 5)
     }
 }
 
@@ -122,6 +208,8 @@ pub struct GicCpuInterface {
     running_irq: Option<u32>,
     /// 現在の実行優先度
     running_priority: u8,
+    /// GICC_CTLR.EOImodeNS
+    eoi_mode: bool,
 }
 
 impl Default for GicCpuInterface {
@@ -139,6 +227,63 @@ impl GicCpuInterface {
             binary_point: 0,
             running_irq: None,
             running_priority: 0xFF, // アイドル状態
+            eoi_mode: false,
+        }
+    }
+}
+
+/// GICH (仮想インターフェース制御) の状態
+///
+/// ゲストのハイパーバイザーが書いた値を保持するだけで、仮想割り込みは配信しない。
+#[derive(Debug, Default)]
+pub struct GicHypInterface {
+    /// GICH_HCR
+    hcr: u32,
+    /// GICH_VMCR
+    vmcr: u32,
+    /// GICH_APR
+    apr: u32,
+    /// GICH_LR0-3
+    lrs: [u32; GICH_LIST_REGS],
+}
+
+impl GicHypInterface {
+    /// 空の (State = Invalid の) リストレジスタのビットマップ
+    fn empty_lrs(&self) -> u32 {
+        self.lrs
+            .iter()
+            .enumerate()
+            .filter(|(_, &lr)| lr & GICH_LR_STATE_MASK == 0)
+            .fold(0, |mask, (i, _)| mask | (1 << i))
+    }
+
+    fn read(&self, offset: u64) -> u64 {
+        let value = match offset {
+            gich_regs::HCR => self.hcr,
+            gich_regs::VTR => GICH_VTR_VALUE,
+            gich_regs::VMCR => self.vmcr,
+            gich_regs::ELRSR0 => self.empty_lrs(),
+            gich_regs::APR => self.apr,
+            lr if (gich_regs::LR0..gich_regs::LR0 + 4 * GICH_LIST_REGS as u64).contains(&lr) => {
+                self.lrs[((lr - gich_regs::LR0) / 4) as usize]
+            }
+            // MISR / EISR: メンテナンス割り込みは発生させない
+            gich_regs::MISR | gich_regs::EISR0 => 0,
+            _ => 0,
+        };
+        value as u64
+    }
+
+    fn write(&mut self, offset: u64, value: u64) {
+        let value = value as u32;
+        match offset {
+            gich_regs::HCR => self.hcr = value,
+            gich_regs::VMCR => self.vmcr = value,
+            gich_regs::APR => self.apr = value,
+            lr if (gich_regs::LR0..gich_regs::LR0 + 4 * GICH_LIST_REGS as u64).contains(&lr) => {
+                self.lrs[((lr - gich_regs::LR0) / 4) as usize] = value;
+            }
+            _ => {}
         }
     }
 }
@@ -150,8 +295,16 @@ pub struct Gic {
     pub distributor: GicDistributor,
     /// CPU Interface (単一 CPU をサポート)
     pub cpu_interface: GicCpuInterface,
+    /// 仮想インターフェース制御 (GICH)
+    pub hyp_interface: GicHypInterface,
     /// ベースアドレス (Distributor)
     base_addr: u64,
+    /// レイテンシ計測の時刻源
+    clock: Arc<dyn HostClock>,
+    /// 割り込みがアサートされた時刻 (acknowledge されるまで保持)
+    asserted_at: Vec<Option<Duration>>,
+    /// アサートから acknowledge までの時間 (割り込み番号ごと)
+    latency: Vec<LatencyStats>,
 }
 
 impl Default for Gic {
@@ -163,11 +316,7 @@ impl Default for Gic {
 impl Gic {
     /// 新しい GIC を作成
     pub fn new() -> Self {
-        Self {
-            distributor: GicDistributor::new(),
-            cpu_interface: GicCpuInterface::new(),
-            base_addr: GIC_DIST_BASE,
-        }
+        Self::with_base(GIC_DIST_BASE)
     }
 
     /// カスタムベースアドレスで GIC を作成
@@ -175,55 +324,113 @@ impl Gic {
         Self {
             distributor: GicDistributor::new(),
             cpu_interface: GicCpuInterface::new(),
+            hyp_interface: GicHypInterface::default(),
             base_addr,
+            clock: Arc::new(SystemClock::new()),
+            asserted_at: vec![None; MAX_IRQS],
+            latency: vec![LatencyStats::default(); MAX_IRQS],
         }
     }
 
+    /// レイテンシ計測に使う時刻源を設定する
+    pub fn set_clock(&mut self, clock: Arc<dyn HostClock>) {
+        self.clock = clock;
+    }
+
+    /// レイテンシ計測の時刻源での現在時刻
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    /// 接続する CPU の数を設定する (`VmConfig::vcpus`)
+    ///
+    /// GICD_TYPER.CPUNumber に反映される。範囲外の値は 1 から
+    /// [`MAX_VCPUS`] に丸める。
+    pub fn set_cpu_count(&mut self, cpus: u32) {
+        self.distributor.cpu_count = cpus.clamp(1, MAX_VCPUS);
+    }
+
     /// 割り込みを発生させる (ペンディング状態にする)
     pub fn set_irq_pending(&mut self, irq: u32) {
         if (irq as usize) < MAX_IRQS {
             let idx = irq as usize / 32;
             let bit = irq as usize % 32;
             self.distributor.irq_pending[idx] |= 1 << bit;
+            let now = self.clock.now();
+            self.asserted_at[irq as usize].get_or_insert(now);
         }
     }
 
+    /// `since` ([`Gic::now`] と同じ時刻源) にアサートされた割り込みをペンディングにする
+    ///
+    /// タイマーのように、デバイス側で発火してから GIC に届くまでに遅れが
+    /// ある割り込みで使う。遅れは [`Gic::irq_latency`] に含まれる。
+    pub fn set_irq_pending_since(&mut self, irq: u32, since: Duration) {
+        self.set_irq_pending(irq);
+        if let Some(at) = self.asserted_at.get_mut(irq as usize) {
+            *at = at.map(|at| at.min(since));
+        }
+    }
+
+    /// 割り込みがアサートされてからゲストが acknowledge (GICC_IAR) するまでの時間
+    pub fn irq_latency(&self, irq: u32) -> LatencyStats {
+        self.latency.get(irq as usize).copied().unwrap_or_default()
+    }
+
+    /// 割り込みがペンディングか (ゲストがまだ acknowledge していないか)
+    pub fn is_irq_pending(&self, irq: u32) -> bool {
+        (irq as usize) < MAX_IRQS
+            && self.distributor.irq_pending[irq as usize / 32] & (1 << (irq % 32)) != 0
+    }
+
     /// 割り込みのペンディング状態をクリア
     pub fn clear_irq_pending(&mut self, irq: u32) {
         if (irq as usize) < MAX_IRQS {
             let idx = irq as usize / 32;
             let bit = irq as usize % 32;
             self.distributor.irq_pending[idx] &= !(1 << bit);
+            // 取り下げられた割り込みはレイテンシに数えない
+            self.asserted_at[irq as usize] = None;
         }
     }
 
     /// 最高優先度のペンディング割り込みを取得
+    ///
+    /// 32 個単位のワードで「有効かつペンディングかつ非アクティブ」を求め、
+    /// 立っているビットだけを調べる。ペンディングがなければ 8 ワードの確認で終わる。
+    /// 同じ優先度では番号の小さい割り込みを優先する。
     pub fn get_highest_pending_irq(&self) -> Option<u32> {
         if !self.distributor.enabled || !self.cpu_interface.enabled {
             return None;
         }
 
+        // 優先度マスクと現在の実行優先度の両方より高い (値が小さい) 必要がある
+        let threshold = self
+            .cpu_interface
+            .priority_mask
+            .min(self.cpu_interface.running_priority);
+
         let mut highest_irq: Option<u32> = None;
-        let mut highest_priority: u8 = 0xFF;
+        let mut highest_priority: u8 = threshold;
 
-        for irq in 0..MAX_IRQS {
-            let idx = irq / 32;
-            let bit = irq % 32;
+        for idx in 0..MAX_IRQS / 32 {
+            let mut candidates = self.distributor.irq_enabled[idx]
+                & self.distributor.irq_pending[idx]
+                & !self.distributor.irq_active[idx];
 
-            // 有効かつペンディングかつアクティブでない割り込みをチェック
-            let is_enabled = (self.distributor.irq_enabled[idx] >> bit) & 1 != 0;
-            let is_pending = (self.distributor.irq_pending[idx] >> bit) & 1 != 0;
-            let is_active = (self.distributor.irq_active[idx] >> bit) & 1 != 0;
+            while candidates != 0 {
+                let bit = candidates.trailing_zeros() as usize;
+                candidates &= candidates - 1;
 
-            if is_enabled && is_pending && !is_active {
+                let irq = idx * 32 + bit;
                 let priority = self.distributor.irq_priority[irq];
-                // 優先度マスクと現在の実行優先度をチェック
-                if priority < self.cpu_interface.priority_mask
-                    && priority < self.cpu_interface.running_priority
-                    && priority < highest_priority
-                {
+                if priority < highest_priority {
                     highest_priority = priority;
                     highest_irq = Some(irq as u32);
+                    // これより高い優先度はない
+                    if priority == 0 {
+                        return highest_irq;
+                    }
                 }
             }
         }
@@ -246,6 +453,10 @@ impl Gic {
             self.cpu_interface.running_irq = Some(irq);
             self.cpu_interface.running_priority = self.distributor.irq_priority[irq as usize];
 
+            if let Some(at) = self.asserted_at[irq as usize].take() {
+                self.latency[irq as usize].record(self.clock.now().saturating_sub(at));
+            }
+
             irq
         } else {
             // スプリアス割り込み
@@ -254,28 +465,79 @@ impl Gic {
     }
 
     /// 割り込み処理完了 (EOIR 書き込み時に呼ばれる)
+    ///
+    /// EOImodeNS が有効な場合は優先度を下げるだけで、アクティブ状態は
+    /// [`Gic::deactivate_interrupt`] (GICC_DIR) でクリアされる。
     pub fn end_of_interrupt(&mut self, irq: u32) {
+        if (irq as usize) < MAX_IRQS {
+            if !self.cpu_interface.eoi_mode {
+                self.deactivate_interrupt(irq);
+            }
+
+            // 実行状態をリセット
+            if self.cpu_interface.running_irq == Some(irq) {
+                self.drop_running_priority();
+            }
+        }
+    }
+
+    /// 割り込みを非アクティブにする (DIR 書き込み時に呼ばれる)
+    pub fn deactivate_interrupt(&mut self, irq: u32) {
         if (irq as usize) < MAX_IRQS {
             let idx = irq as usize / 32;
             let bit = irq as usize % 32;
 
             // アクティブ状態をクリア
             self.distributor.irq_active[idx] &= !(1 << bit);
+        }
+    }
 
-            // 実行状態をリセット
-            if self.cpu_interface.running_irq == Some(irq) {
-                self.cpu_interface.running_irq = None;
-                self.cpu_interface.running_priority = 0xFF;
+    /// ICACTIVER でアクティブ状態をまとめてクリアする
+    ///
+    /// kexec / kdump で起動したカーネルは、前のカーネルが EOI しないまま
+    /// 残した割り込みを ICACTIVER で片付ける。処理中の割り込みが含まれていれば
+    /// 実行優先度も戻し、以降の割り込みが配信されるようにする。
+    fn clear_active(&mut self, idx: usize, mask: u32) {
+        self.distributor.irq_active[idx] &= !mask;
+        if let Some(irq) = self.cpu_interface.running_irq {
+            if irq as usize / 32 == idx && mask & (1 << (irq % 32)) != 0 {
+                self.drop_running_priority();
             }
         }
     }
 
+    /// 実行優先度をアイドルに戻す (GICC_APRn へ 0 を書いたとき)
+    fn drop_running_priority(&mut self) {
+        self.cpu_interface.running_irq = None;
+        self.cpu_interface.running_priority = 0xFF;
+    }
+
     /// ペンディング中の割り込みがあるかチェック
     /// GIC が有効でペンディング中の割り込みがあれば true を返す
     pub fn has_pending_interrupt(&self) -> bool {
         self.get_highest_pending_irq().is_some()
     }
 
+    /// 現在の状態をまとめる (モニタの `info gic` 用)
+    pub fn summary(&self) -> GicSummary {
+        let irqs = |bitmap: &[u32; MAX_IRQS / 32]| -> Vec<u32> {
+            (0..MAX_IRQS as u32)
+                .filter(|&irq| bitmap[irq as usize / 32] & (1 << (irq % 32)) != 0)
+                .collect()
+        };
+        GicSummary {
+            distributor_enabled: self.distributor.enabled,
+            cpu_interface_enabled: self.cpu_interface.enabled,
+            priority_mask: self.cpu_interface.priority_mask,
+            running_irq: self.cpu_interface.running_irq,
+            running_priority: self.cpu_interface.running_priority,
+            highest_pending: self.get_highest_pending_irq(),
+            enabled: irqs(&self.distributor.irq_enabled),
+            pending: irqs(&self.distributor.irq_pending),
+            active: irqs(&self.distributor.irq_active),
+        }
+    }
+
     /// GICD (Distributor) の読み取り処理
     fn read_distributor(&mut self, offset: u64) -> u64 {
         match offset {
@@ -298,6 +560,12 @@ impl Gic {
                     0
                 }
             }
+            o if (gicd_regs::ISACTIVER..gicd_regs::ISACTIVER + 0x80).contains(&o)
+                || (gicd_regs::ICACTIVER..gicd_regs::ICACTIVER + 0x80).contains(&o) =>
+            {
+                let idx = (((o - gicd_regs::ISACTIVER) % 0x80) / 4) as usize;
+                self.distributor.irq_active.get(idx).copied().unwrap_or(0) as u64
+            }
             o if (gicd_regs::IPRIORITYR..gicd_regs::IPRIORITYR + 0x400).contains(&o) => {
                 let base_idx = (o - gicd_regs::IPRIORITYR) as usize;
                 let mut value: u32 = 0;
@@ -353,6 +621,18 @@ impl Gic {
                     self.distributor.irq_pending[idx] &= !value;
                 }
             }
+            o if (gicd_regs::ISACTIVER..gicd_regs::ISACTIVER + 0x80).contains(&o) => {
+                let idx = ((o - gicd_regs::ISACTIVER) / 4) as usize;
+                if idx < self.distributor.irq_active.len() {
+                    self.distributor.irq_active[idx] |= value;
+                }
+            }
+            o if (gicd_regs::ICACTIVER..gicd_regs::ICACTIVER + 0x80).contains(&o) => {
+                let idx = ((o - gicd_regs::ICACTIVER) / 4) as usize;
+                if idx < self.distributor.irq_active.len() {
+                    self.clear_active(idx, value);
+                }
+            }
             o if (gicd_regs::IPRIORITYR..gicd_regs::IPRIORITYR + 0x400).contains(&o) => {
                 let base_idx = (o - gicd_regs::IPRIORITYR) as usize;
                 for i in 0..4 {
@@ -387,12 +667,24 @@ impl Gic {
     /// GICC (CPU Interface) の読み取り処理
     fn read_cpu_interface(&mut self, offset: u64) -> u64 {
         match offset {
-            gicc_regs::CTLR => self.cpu_interface.enabled as u64,
+            gicc_regs::CTLR => {
+                let eoi_mode = if self.cpu_interface.eoi_mode {
+                    GICC_CTLR_EOIMODE
+                } else {
+                    0
+                };
+                self.cpu_interface.enabled as u64 | eoi_mode
+            }
             gicc_regs::PMR => self.cpu_interface.priority_mask as u64,
             gicc_regs::BPR => self.cpu_interface.binary_point as u64,
             gicc_regs::IAR => self.acknowledge_irq() as u64,
             gicc_regs::RPR => self.cpu_interface.running_priority as u64,
             gicc_regs::HPPIR => self.get_highest_pending_irq().unwrap_or(1023) as u64,
+            // 処理中の割り込みがあれば、その優先度グループのビットを立てる
+            gicc_regs::APR => match self.cpu_interface.running_irq {
+                Some(_) => 1 << (self.cpu_interface.running_priority >> 3),
+                None => 0,
+            },
             gicc_regs::IIDR => 0x0102_043B, // ARM GIC-400 互換
             _ => 0,
         }
@@ -403,6 +695,7 @@ impl Gic {
         match offset {
             gicc_regs::CTLR => {
                 self.cpu_interface.enabled = (value & 1) != 0;
+                self.cpu_interface.eoi_mode = (value & GICC_CTLR_EOIMODE) != 0;
             }
             gicc_regs::PMR => {
                 self.cpu_interface.priority_mask = (value & 0xFF) as u8;
@@ -413,6 +706,13 @@ impl Gic {
             gicc_regs::EOIR => {
                 self.end_of_interrupt((value & 0x3FF) as u32);
             }
+            gicc_regs::DIR => {
+                self.deactivate_interrupt((value & 0x3FF) as u32);
+            }
+            // Linux は CPU Interface の初期化時に APRn をすべて 0 にする
+            gicc_regs::APR if value == 0 => {
+                self.drop_running_priority();
+            }
             _ => {}
         }
     }
@@ -420,13 +720,31 @@ impl Gic {
 
 /// MmioHandler の実装
 impl MmioHandler for Gic {
+    fn name(&self) -> &str {
+        "gic"
+    }
+
+    #[cfg(feature = "snapshot")]
+    fn as_device_state(&mut self) -> Option<&mut dyn DeviceState> {
+        Some(self)
+    }
+
     fn base(&self) -> u64 {
         self.base_addr
     }
 
     fn size(&self) -> u64 {
-        // Distributor + CPU Interface
-        GIC_DIST_SIZE + GIC_CPU_SIZE
+        // Distributor + CPU Interface + GICH/GICV スタブ
+        GIC_REGION_SIZE
+    }
+
+    fn reset(&mut self) {
+        let cpu_count = self.distributor.cpu_count;
+        self.distributor = GicDistributor::new();
+        self.distributor.cpu_count = cpu_count;
+        self.cpu_interface = GicCpuInterface::new();
+        self.hyp_interface = GicHypInterface::default();
+        self.asserted_at.fill(None);
     }
 
     fn read(&mut self, offset: u64, _size: usize) -> Result<u64, Box<dyn Error>> {
@@ -435,9 +753,14 @@ impl MmioHandler for Gic {
             Ok(self.read_distributor(offset))
         } else if offset < GIC_DIST_SIZE + GIC_CPU_SIZE {
             // GICC 領域
-            let gicc_offset = offset - GIC_DIST_SIZE;
+            let gicc_offset = gicc_frame_offset(offset - GIC_DIST_SIZE);
             Ok(self.read_cpu_interface(gicc_offset))
+        } else if offset < GIC_HYP_BASE - GIC_DIST_BASE + GIC_HYP_SIZE {
+            // GICH 領域
+            let gich_offset = offset - (GIC_HYP_BASE - GIC_DIST_BASE);
+            Ok(self.hyp_interface.read(gich_offset))
         } else {
+            // GICV 領域 (RAZ)
             Ok(0)
         }
     }
@@ -448,8 +771,169 @@ impl MmioHandler for Gic {
             self.write_distributor(offset, value);
         } else if offset < GIC_DIST_SIZE + GIC_CPU_SIZE {
             // GICC 領域
-            let gicc_offset = offset - GIC_DIST_SIZE;
+            let gicc_offset = gicc_frame_offset(offset - GIC_DIST_SIZE);
             self.write_cpu_interface(gicc_offset, value);
+        } else if offset < GIC_HYP_BASE - GIC_DIST_BASE + GIC_HYP_SIZE {
+            // GICH 領域
+            let gich_offset = offset - (GIC_HYP_BASE - GIC_DIST_BASE);
+            self.hyp_interface.write(gich_offset, value);
+        }
+        // GICV 領域への書き込みは無視する (WI)
+        Ok(())
+    }
+}
+
+#[cfg(feature = "snapshot")]
+impl DeviceState for Gic {
+    fn save_state(&self) -> Vec<u8> {
+        let dist = &self.distributor;
+        let cpu = &self.cpu_interface;
+        let mut enc = StateEncoder::new().bool(dist.enabled);
+        for words in [&dist.irq_enabled, &dist.irq_pending, &dist.irq_active] {
+            for &w in words {
+                enc = enc.u32(w);
+            }
+        }
+        for &w in &dist.irq_config {
+            enc = enc.u32(w);
+        }
+        enc = enc
+            .bytes(&dist.irq_priority)
+            .bytes(&dist.irq_targets)
+            .bool(cpu.enabled)
+            .u8(cpu.priority_mask)
+            .u8(cpu.binary_point)
+            .bool(cpu.running_irq.is_some())
+            .u32(cpu.running_irq.unwrap_or(0))
+            .u8(cpu.running_priority)
+            .bool(cpu.eoi_mode);
+        let hyp = &self.hyp_interface;
+        enc = enc.u32(hyp.hcr).u32(hyp.vmcr).u32(hyp.apr);
+        for &lr in &hyp.lrs {
+            enc = enc.u32(lr);
+        }
+        enc.finish()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut dec = StateDecoder::new(state);
+        let mut dist = GicDistributor::new();
+        dist.enabled = dec.bool()?;
+        for words in [
+            &mut dist.irq_enabled,
+            &mut dist.irq_pending,
+            &mut dist.irq_active,
+        ] {
+            for w in words.iter_mut() {
+                *w = dec.u32()?;
+            }
+        }
+        for w in dist.irq_config.iter_mut() {
+            *w = dec.u32()?;
+        }
+        dist.irq_priority.copy_from_slice(dec.bytes(MAX_IRQS)?);
+        dist.irq_targets.copy_from_slice(dec.bytes(MAX_IRQS)?);
+
+        let mut cpu = GicCpuInterface::new();
+        cpu.enabled = dec.bool()?;
+        cpu.priority_mask = dec.u8()?;
+        cpu.binary_point = dec.u8()?;
+        let running = dec.bool()?;
+        let irq = dec.u32()?;
+        cpu.running_irq = running.then_some(irq);
+        cpu.running_priority = dec.u8()?;
+        cpu.eoi_mode = dec.bool()?;
+
+        let mut hyp = GicHypInterface {
+            hcr: dec.u32()?,
+            vmcr: dec.u32()?,
+            apr: dec.u32()?,
+            ..Default::default()
+        };
+        for lr in hyp.lrs.iter_mut() {
+            *lr = dec.u32()?;
+        }
+        dec.finish()?;
+
+        // CPU 数は構成 (VmConfig) から決まるため状態に含めない
+        dist.cpu_count = self.distributor.cpu_count;
+        self.distributor = dist;
+        self.cpu_interface = cpu;
+        self.hyp_interface = hyp;
+        Ok(())
+    }
+}
+
+/// [`Gic::summary`] の結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GicSummary {
+    /// GICD_CTLR.Enable
+    pub distributor_enabled: bool,
+    /// GICC_CTLR.Enable
+    pub cpu_interface_enabled: bool,
+    /// GICC_PMR
+    pub priority_mask: u8,
+    /// 処理中 (acknowledge 済みで EOI 前) の割り込み
+    pub running_irq: Option<u32>,
+    /// 現在の実行優先度
+    pub running_priority: u8,
+    /// 次に acknowledge される割り込み
+    pub highest_pending: Option<u32>,
+    /// 有効な割り込み
+    pub enabled: Vec<u32>,
+    /// ペンディング中の割り込み
+    pub pending: Vec<u32>,
+    /// アクティブな割り込み
+    pub active: Vec<u32>,
+}
+
+impl core::fmt::Display for GicSummary {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+        let irq = |irq: Option<u32>| irq.map_or("none".into(), |irq| irq.to_string());
+        writeln!(
+            f,
+            "distributor: {}  cpu interface: {}",
+            on_off(self.distributor_enabled),
+            on_off(self.cpu_interface_enabled)
+        )?;
+        writeln!(
+            f,
+            "priority mask: 0x{:02x}  running: {} (priority 0x{:02x})  next: {}",
+            self.priority_mask,
+            irq(self.running_irq),
+            self.running_priority,
+            irq(self.highest_pending)
+        )?;
+        writeln!(f, "enabled: {}", IrqRanges(&self.enabled))?;
+        writeln!(f, "pending: {}", IrqRanges(&self.pending))?;
+        write!(f, "active:  {}", IrqRanges(&self.active))
+    }
+}
+
+/// 割り込み番号の列を `0-31 33` のように連続した範囲にまとめて表示する
+struct IrqRanges<'a>(&'a [u32]);
+
+impl core::fmt::Display for IrqRanges<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("-");
+        }
+        let mut i = 0;
+        while i < self.0.len() {
+            let start = self.0[i];
+            while i + 1 < self.0.len() && self.0[i + 1] == self.0[i] + 1 {
+                i += 1;
+            }
+            if self.0[i] != start {
+                write!(f, "{}-{}", start, self.0[i])?;
+            } else {
+                write!(f, "{}", start)?;
+            }
+            i += 1;
+            if i < self.0.len() {
+                f.write_str(" ")?;
+            }
         }
         Ok(())
     }
@@ -476,13 +960,37 @@ impl SharedGicWrapper {
     }
 }
 
+#[cfg(feature = "snapshot")]
+impl DeviceState for SharedGicWrapper {
+    fn save_state(&self) -> Vec<u8> {
+        self.gic.lock().unwrap().save_state()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.gic.lock().unwrap().restore_state(state)
+    }
+}
+
 impl MmioHandler for SharedGicWrapper {
+    fn name(&self) -> &str {
+        "gic"
+    }
+
+    #[cfg(feature = "snapshot")]
+    fn as_device_state(&mut self) -> Option<&mut dyn DeviceState> {
+        Some(self)
+    }
+
     fn base(&self) -> u64 {
         self.base_addr
     }
 
     fn size(&self) -> u64 {
-        GIC_DIST_SIZE + GIC_CPU_SIZE
+        GIC_REGION_SIZE
+    }
+
+    fn reset(&mut self) {
+        self.gic.lock().unwrap().reset();
     }
 
     fn read(&mut self, offset: u64, size: usize) -> Result<u64, Box<dyn Error>> {
@@ -510,6 +1018,7 @@ pub fn create_shared_gic(base_addr: u64) -> SharedGic {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::devices::host_time::ManualClock;
 
     #[test]
     fn gic_new_の初期状態を確認() {
@@ -522,11 +1031,36 @@ mod tests {
         assert_eq!(gic.distributor.irq_priority[0], 0xA0);
     }
 
+    #[test]
+    fn summary_は割り込みの状態を範囲にまとめて表示する() {
+        let mut gic = Gic::new();
+        gic.distributor.enabled = true;
+        gic.cpu_interface.enabled = true;
+        gic.distributor.irq_enabled[1] = 0b1010;
+        gic.set_irq_pending(27);
+        gic.set_irq_pending(33);
+        gic.set_irq_pending(35);
+        gic.acknowledge_irq();
+
+        let summary = gic.summary();
+        assert_eq!(summary.running_irq, Some(27));
+        assert_eq!(summary.highest_pending, None);
+        assert_eq!(summary.pending, vec![33, 35]);
+        assert_eq!(summary.active, vec![27]);
+        let text = summary.to_string();
+        assert!(text.contains("enabled: 0-31 33 35\n"));
+        assert!(text.contains("running: 27 (priority 0xa0)"));
+        assert!(text.ends_with("active:  27"));
+    }
+
     #[test]
     fn set_irq_pending_で割り込みをペンディングにできる() {
         let mut gic = Gic::new();
         gic.set_irq_pending(32);
         assert_eq!(gic.distributor.irq_pending[1], 1);
+        assert!(gic.is_irq_pending(32));
+        assert!(!gic.is_irq_pending(33));
+        assert!(!gic.is_irq_pending(MAX_IRQS as u32));
     }
 
     #[test]
@@ -576,6 +1110,24 @@ mod tests {
         assert_eq!(highest, Some(33));
     }
 
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn 状態を保存して別の_gic_に復元できる() {
+        let mut gic = Gic::new();
+        gic.distributor.enabled = true;
+        gic.cpu_interface.enabled = true;
+        gic.distributor.irq_enabled[1] = 0b10;
+        gic.distributor.irq_pending[1] = 0b10;
+        gic.distributor.irq_priority[33] = 0x40;
+        assert_eq!(gic.acknowledge_irq(), 33);
+
+        let mut restored = Gic::new();
+        restored.restore_state(&gic.save_state()).unwrap();
+        assert_eq!(restored.save_state(), gic.save_state());
+        assert_eq!(restored.cpu_interface.running_irq, Some(33));
+        assert!(restored.restore_state(&[0; 4]).is_err());
+    }
+
     #[test]
     fn acknowledge_irq_で割り込みがアクティブになる() {
         let mut gic = Gic::new();
@@ -594,6 +1146,36 @@ mod tests {
         assert_eq!(gic.distributor.irq_pending[1], 0);
     }
 
+    #[test]
+    fn アサートから_acknowledge_までの時間を記録する() {
+        let clock = ManualClock::new();
+        let mut gic = Gic::new();
+        gic.set_clock(Arc::new(clock.clone()));
+        gic.distributor.enabled = true;
+        gic.cpu_interface.enabled = true;
+        gic.distributor.irq_enabled[0] = 1 << 27;
+
+        // 5ms 前に発火していたタイマー割り込みを 2ms 後に acknowledge する
+        clock.advance(Duration::from_millis(10));
+        gic.set_irq_pending_since(27, gic.now() - Duration::from_millis(5));
+        clock.advance(Duration::from_millis(2));
+        assert_eq!(gic.acknowledge_irq(), 27);
+        gic.end_of_interrupt(27);
+
+        let latency = gic.irq_latency(27);
+        assert_eq!(latency.count, 1);
+        assert_eq!(latency.min, Duration::from_millis(7));
+
+        // acknowledge 前に取り下げた割り込みは数えない
+        gic.set_irq_pending(27);
+        gic.clear_irq_pending(27);
+        gic.set_irq_pending(27);
+        gic.clear_irq_pending(27);
+        assert_eq!(gic.acknowledge_irq(), 1023);
+        assert_eq!(gic.irq_latency(27).count, 1);
+        assert_eq!(gic.irq_latency(1000).count, 0);
+    }
+
     #[test]
     fn acknowledge_irq_はペンディングなしでスプリアスを返す() {
         let mut gic = Gic::new();
@@ -604,6 +1186,44 @@ mod tests {
         assert_eq!(irq, 1023); // スプリアス割り込み
     }
 
+    #[test]
+    fn icactiver_で前のカーネルが残したアクティブ状態を片付けられる() {
+        let mut gic = Gic::new();
+        gic.write(gicd_regs::CTLR, 1, 4).unwrap();
+        gic.write(GIC_DIST_SIZE + gicc_regs::CTLR, 1, 4).unwrap();
+        gic.write(gicd_regs::ISENABLER + 4, 0b11, 4).unwrap();
+        gic.set_irq_pending(32);
+
+        // EOI しないまま kexec した状態
+        assert_eq!(gic.read(GIC_DIST_SIZE + gicc_regs::IAR, 4).unwrap(), 32);
+        assert_eq!(gic.read(gicd_regs::ISACTIVER + 4, 4).unwrap(), 1);
+        assert_ne!(gic.read(GIC_DIST_SIZE + gicc_regs::APR, 4).unwrap(), 0);
+        gic.set_irq_pending(33);
+        assert_eq!(gic.get_highest_pending_irq(), None);
+
+        // 新しいカーネルの GIC 初期化
+        gic.write(gicd_regs::ICACTIVER + 4, 0xFFFF_FFFF, 4).unwrap();
+        assert_eq!(gic.read(gicd_regs::ICACTIVER + 4, 4).unwrap(), 0);
+        assert_eq!(gic.read(GIC_DIST_SIZE + gicc_regs::APR, 4).unwrap(), 0);
+        assert_eq!(gic.get_highest_pending_irq(), Some(33));
+    }
+
+    #[test]
+    fn gicc_apr_への_0_書き込みで実行優先度が戻る() {
+        let mut gic = Gic::new();
+        gic.distributor.enabled = true;
+        gic.cpu_interface.enabled = true;
+        gic.distributor.irq_enabled[1] = 0b11;
+        gic.set_irq_pending(32);
+        gic.acknowledge_irq();
+        gic.set_irq_pending(33);
+        assert_eq!(gic.get_highest_pending_irq(), None);
+
+        gic.write(GIC_DIST_SIZE + gicc_regs::APR, 0, 4).unwrap();
+        assert_eq!(gic.cpu_interface.running_priority, 0xFF);
+        assert_eq!(gic.get_highest_pending_irq(), Some(33));
+    }
+
     #[test]
     fn end_of_interrupt_でアクティブ状態がクリアされる() {
         let mut gic = Gic::new();
@@ -642,6 +1262,13 @@ mod tests {
         let typer = gic.read(gicd_regs::TYPER, 4).unwrap();
         // ITLinesNumber = (256 / 32) - 1 = 7
         assert_eq!(typer & 0x1F, 7);
+        // CPUNumber = 0 (1 CPU)
+        assert_eq!((typer >> 5) & 0x7, 0);
+
+        gic.set_cpu_count(4);
+        let typer = gic.read(gicd_regs::TYPER, 4).unwrap();
+        assert_eq!((typer >> 5) & 0x7, 3);
+        assert_eq!(typer & 0x1F, 7);
     }
 
     #[test]
@@ -699,7 +1326,103 @@ mod tests {
     fn base_とsize_が正しい値を返す() {
         let gic = Gic::new();
         assert_eq!(gic.base(), GIC_DIST_BASE);
-        assert_eq!(gic.size(), GIC_DIST_SIZE + GIC_CPU_SIZE);
+        assert_eq!(gic.size(), GIC_REGION_SIZE);
+        assert_eq!(GIC_DIST_BASE + gic.size(), 0x0806_0000);
+    }
+
+    #[test]
+    fn gicc_のエイリアスページからも_iidr_を読める() {
+        let mut gic = Gic::new();
+        let iidr = gic.read(GIC_DIST_SIZE + gicc_regs::IIDR, 4).unwrap();
+        assert_eq!(iidr, 0x0102_043B);
+        // 0x2000 ごとのエイリアス
+        let alias = gic
+            .read(GIC_DIST_SIZE + 0x2000 + gicc_regs::IIDR, 4)
+            .unwrap();
+        assert_eq!(alias, iidr);
+        // 64KB フレームの末尾付近
+        let top = gic
+            .read(GIC_DIST_SIZE + 0xE000 + gicc_regs::IIDR, 4)
+            .unwrap();
+        assert_eq!(top, iidr);
+    }
+
+    #[test]
+    fn eoimode_では_dir_で非アクティブになる() {
+        let mut gic = Gic::new();
+        gic.distributor.enabled = true;
+        gic.distributor.irq_enabled[1] = 1;
+        gic.distributor.irq_pending[1] = 1;
+        gic.write(GIC_DIST_SIZE + gicc_regs::CTLR, 1 | GICC_CTLR_EOIMODE, 4)
+            .unwrap();
+        assert_eq!(
+            gic.read(GIC_DIST_SIZE + gicc_regs::CTLR, 4).unwrap(),
+            1 | GICC_CTLR_EOIMODE
+        );
+
+        let irq = gic.read(GIC_DIST_SIZE + gicc_regs::IAR, 4).unwrap();
+        assert_eq!(irq, 32);
+
+        // EOIR は優先度を下げるだけ
+        gic.write(GIC_DIST_SIZE + gicc_regs::EOIR, 32, 4).unwrap();
+        assert_eq!(gic.distributor.irq_active[1], 1);
+        assert!(gic.cpu_interface.running_irq.is_none());
+
+        // 64KB 配置の GICC_DIR (+0x1_0000) で非アクティブ化
+        gic.write(GIC_DIST_SIZE + GICC_PAGE1_64K, 32, 4).unwrap();
+        assert_eq!(gic.distributor.irq_active[1], 0);
+    }
+
+    #[test]
+    fn gicc_dir_は_4kb_配置でも書ける() {
+        let mut gic = Gic::new();
+        gic.distributor.irq_active[1] = 1;
+        gic.write(GIC_DIST_SIZE + gicc_regs::DIR, 32, 4).unwrap();
+        assert_eq!(gic.distributor.irq_active[1], 0);
+    }
+
+    #[test]
+    fn gich_と_gicv_は_raz_wi() {
+        let mut gic = Gic::new();
+        for base in [
+            GIC_HYP_BASE + 0x200,
+            GIC_VCPU_BASE,
+            GIC_VCPU_BASE + GIC_VCPU_SIZE - 4,
+        ] {
+            let offset = base - GIC_DIST_BASE;
+            gic.write(offset, 0xFFFF_FFFF, 4).unwrap();
+            assert_eq!(gic.read(offset, 4).unwrap(), 0);
+        }
+        // GICC には影響しない
+        assert!(!gic.cpu_interface.enabled);
+    }
+
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn gich_のリストレジスタを読み書きできる() {
+        let mut gic = Gic::new();
+        let gich = GIC_HYP_BASE - GIC_DIST_BASE;
+
+        // ListRegs = 4 (KVM の vgic-v2 はここからリストレジスタの数を得る)
+        let vtr = gic.read(gich + gich_regs::VTR, 4).unwrap();
+        assert_eq!(vtr & 0x3f, 3);
+        assert_eq!(gic.read(gich + gich_regs::ELRSR0, 4).unwrap(), 0b1111);
+
+        gic.write(gich + gich_regs::HCR, 1, 4).unwrap();
+        // LR1 を Pending (State = 0b01) にする
+        gic.write(gich + gich_regs::LR0 + 4, (1 << 28) | 27, 4)
+            .unwrap();
+        assert_eq!(gic.read(gich + gich_regs::HCR, 4).unwrap(), 1);
+        assert_eq!(gic.read(gich + gich_regs::ELRSR0, 4).unwrap(), 0b1101);
+        assert_eq!(gic.read(gich + gich_regs::MISR, 4).unwrap(), 0);
+
+        // 状態の保存と復元に含まれる
+        let mut restored = Gic::new();
+        restored.restore_state(&gic.save_state()).unwrap();
+        assert_eq!(
+            restored.read(gich + gich_regs::LR0 + 4, 4).unwrap(),
+            (1 << 28) | 27
+        );
     }
 
     #[test]
@@ -707,4 +1430,26 @@ mod tests {
         let gic = Gic::with_base(0x1000_0000);
         assert_eq!(gic.base(), 0x1000_0000);
     }
+
+    #[test]
+    fn 同じ優先度では番号の小さい割り込みを返す() {
+        let mut gic = Gic::new();
+        gic.distributor.enabled = true;
+        gic.cpu_interface.enabled = true;
+        gic.distributor.irq_enabled = [u32::MAX; MAX_IRQS / 32];
+        // IRQ 200 と IRQ 40 が同じ優先度、IRQ 255 はマスクより低い
+        gic.set_irq_pending(200);
+        gic.set_irq_pending(40);
+        gic.set_irq_pending(255);
+        gic.distributor.irq_priority[255] = 0xFF;
+        assert_eq!(gic.get_highest_pending_irq(), Some(40));
+
+        // アクティブな割り込みは対象外
+        gic.distributor.irq_active[1] = 1 << 8;
+        assert_eq!(gic.get_highest_pending_irq(), Some(200));
+
+        // 実行優先度以上の割り込みは対象外
+        gic.cpu_interface.running_priority = 0xA0;
+        assert_eq!(gic.get_highest_pending_irq(), None);
+    }
 }
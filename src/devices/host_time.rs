@@ -0,0 +1,103 @@
+//! デバイスモデルに注入するホストの時刻
+//!
+//! タイマーと GIC のモデルはホストの時計を直接読まず、[`HostClock`] から時刻を得る。
+//! 通常は [`SystemClock`] を使い、テストや決定的に実行したい場合は
+//! [`ManualClock`] で時刻を進める。ホストの時計を読むのは `std::time::Instant` を
+//! 使う [`SystemClock`] だけ。
+//!
+//! ```ignore
+//! let clock = ManualClock::new();
+//! let timer = Timer::with_clock(Arc::new(clock.clone()));
+//! clock.advance(Duration::from_millis(1));
+//! assert_eq!(timer.get_phys_counter(), TIMER_FREQ / 1000);
+//! ```
+
+#![deny(clippy::std_instead_of_core, clippy::std_instead_of_alloc)]
+
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+/// 単調増加する時刻の取得元
+pub trait HostClock: Send + Sync + fmt::Debug {
+    /// 現在の時刻 (起点はクロックごとに任意)
+    fn now(&self) -> Duration;
+}
+
+/// ホストの単調時計 (作成した時点を 0 とする)
+#[derive(Debug)]
+pub struct SystemClock {
+    start: std::time::Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemClock {
+    /// 現在時刻を起点とする時計を作成
+    pub fn new() -> Self {
+        Self {
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+impl HostClock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// 明示的に進める時計
+///
+/// clone したハンドルは同じ時刻を共有するため、デバイスに渡した後も
+/// テストから時刻を進められる。
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    nanos: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    /// 時刻 0 の時計を作成
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 時刻を `delta` だけ進める
+    pub fn advance(&self, delta: Duration) {
+        self.nanos
+            .fetch_add(delta.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl HostClock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_は_clone_と時刻を共有する() {
+        let clock = ManualClock::new();
+        let shared: Arc<dyn HostClock> = Arc::new(clock.clone());
+        assert_eq!(shared.now(), Duration::ZERO);
+        clock.advance(Duration::from_micros(250));
+        clock.advance(Duration::from_micros(750));
+        assert_eq!(shared.now(), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn system_clock_は単調増加する() {
+        let clock = SystemClock::new();
+        let first = clock.now();
+        assert!(clock.now() >= first);
+    }
+}
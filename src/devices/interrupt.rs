@@ -1,9 +1,10 @@
-//! 割り込みコントローラー統合モジュール
+//! 割り込みコントローラー
 //!
-//! GIC と Timer を統合して、タイマー割り込みを自動的に GIC に配信します。
+//! 共有 GIC のペンディング・ACK・EOI をホスト側から操作する。タイマーとの
+//! つなぎ込みは [`super::irqchip::IrqChip`] が行う。
 
 use super::gic::{create_shared_gic, SharedGic, GIC_DIST_BASE, GIC_DIST_SIZE};
-use super::timer::{Timer, PHYS_TIMER_IRQ, VIRT_TIMER_IRQ};
+use super::timer::{PHYS_TIMER_IRQ, VIRT_TIMER_IRQ};
 use crate::mmio::MmioHandler;
 
 // GICD レジスタオフセット
@@ -16,13 +17,11 @@ const GICC_CTLR: u64 = 0x000;
 
 /// 割り込みコントローラー
 ///
-/// GIC と Timer を統合管理し、タイマー割り込みを自動的に GIC にルーティングします。
+/// 共有 GIC をホスト側から操作する。MMIO に登録した GIC と同じものを指す。
 #[derive(Debug)]
 pub struct InterruptController {
     /// 共有 GIC (Generic Interrupt Controller)
     pub gic: SharedGic,
-    /// ARM Generic Timer
-    pub timer: Timer,
 }
 
 impl Default for InterruptController {
@@ -39,28 +38,7 @@ impl InterruptController {
 
     /// 既存の共有 GIC を使って割り込みコントローラーを作成
     pub fn with_gic(gic: SharedGic) -> Self {
-        Self {
-            gic,
-            timer: Timer::new(),
-        }
-    }
-
-    /// タイマー IRQ をポーリングして GIC に反映
-    ///
-    /// タイマーがペンディング状態の場合、対応する IRQ を GIC にセットします。
-    /// VM のメインループで定期的に呼び出す必要があります。
-    pub fn poll_timer_irqs(&mut self) {
-        let mut gic = self.gic.lock().unwrap();
-
-        // 物理タイマー
-        if self.timer.phys_timer_pending() {
-            gic.set_irq_pending(PHYS_TIMER_IRQ);
-        }
-
-        // 仮想タイマー
-        if self.timer.virt_timer_pending() {
-            gic.set_irq_pending(VIRT_TIMER_IRQ);
-        }
+        Self { gic }
     }
 
     /// ペンディング中の IRQ があるかチェック
@@ -103,11 +81,6 @@ impl InterruptController {
             .unwrap();
     }
 
-    /// 次のタイマーイベントまでの時間（ナノ秒）
-    pub fn time_until_next_timer(&self) -> Option<u64> {
-        self.timer.time_until_next_event()
-    }
-
     /// 割り込みを acknowledge して IRQ 番号を返す
     pub fn acknowledge(&mut self) -> u32 {
         let mut gic = self.gic.lock().unwrap();
@@ -132,7 +105,6 @@ impl InterruptController {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::devices::timer::TimerReg;
 
     #[test]
     fn interrupt_controller_new_の初期状態を確認() {
@@ -161,66 +133,17 @@ mod tests {
         assert_ne!(enabled & (1 << 27), 0);
     }
 
-    #[test]
-    fn poll_timer_irqs_で物理タイマーirqがgicにセットされる() {
-        let mut ic = InterruptController::new();
-        ic.enable();
-        ic.enable_timer_irqs();
-
-        // タイマーを過去に設定してペンディング状態にする
-        let counter = ic.timer.get_phys_counter();
-        ic.timer.write_sysreg(TimerReg::CNTP_CTL_EL0, 1).unwrap(); // 有効化
-        ic.timer
-            .write_sysreg(TimerReg::CNTP_CVAL_EL0, counter.saturating_sub(100))
-            .unwrap();
-
-        // ポーリング
-        ic.poll_timer_irqs();
-
-        // GIC に IRQ がセットされている
-        assert!(ic.has_pending_irq());
-        assert_eq!(ic.get_pending_irq(), Some(PHYS_TIMER_IRQ));
-    }
-
-    #[test]
-    fn poll_timer_irqs_で仮想タイマーirqがgicにセットされる() {
-        let mut ic = InterruptController::new();
-        ic.enable();
-        ic.enable_timer_irqs();
-
-        // 仮想タイマーを過去に設定
-        let counter = ic.timer.get_virt_counter();
-        ic.timer.write_sysreg(TimerReg::CNTV_CTL_EL0, 1).unwrap(); // 有効化
-        ic.timer
-            .write_sysreg(TimerReg::CNTV_CVAL_EL0, counter.saturating_sub(100))
-            .unwrap();
-
-        // ポーリング
-        ic.poll_timer_irqs();
-
-        // GIC に IRQ がセットされている
-        assert!(ic.has_pending_irq());
-        assert_eq!(ic.get_pending_irq(), Some(VIRT_TIMER_IRQ));
-    }
-
     #[test]
     fn acknowledge_と_end_of_interrupt_のフローが動作する() {
         let mut ic = InterruptController::new();
         ic.enable();
         ic.enable_timer_irqs();
-
-        // タイマーをペンディング状態にする
-        let counter = ic.timer.get_phys_counter();
-        ic.timer.write_sysreg(TimerReg::CNTP_CTL_EL0, 1).unwrap();
-        ic.timer
-            .write_sysreg(TimerReg::CNTP_CVAL_EL0, counter.saturating_sub(100))
-            .unwrap();
-
-        ic.poll_timer_irqs();
+        ic.gic.lock().unwrap().set_irq_pending(VIRT_TIMER_IRQ);
+        assert_eq!(ic.get_pending_irq(), Some(VIRT_TIMER_IRQ));
 
         // Acknowledge
         let irq = ic.acknowledge();
-        assert_eq!(irq, PHYS_TIMER_IRQ);
+        assert_eq!(irq, VIRT_TIMER_IRQ);
 
         // End of Interrupt
         ic.end_of_interrupt(irq);
@@ -236,29 +159,4 @@ mod tests {
         ic.enable();
         assert!(!ic.has_pending_irq());
     }
-
-    #[test]
-    fn time_until_next_timer_はタイマーが無効の場合noneを返す() {
-        let ic = InterruptController::new();
-        assert!(ic.time_until_next_timer().is_none());
-    }
-
-    #[test]
-    fn time_until_next_timer_は次のイベントまでの時間を返す() {
-        let mut ic = InterruptController::new();
-
-        // 1秒後にタイマーを設定
-        let counter = ic.timer.get_phys_counter();
-        let freq = ic.timer.get_frequency();
-        ic.timer.write_sysreg(TimerReg::CNTP_CTL_EL0, 1).unwrap();
-        ic.timer
-            .write_sysreg(TimerReg::CNTP_CVAL_EL0, counter + freq)
-            .unwrap();
-
-        let time = ic.time_until_next_timer();
-        assert!(time.is_some());
-        // おおよそ 1 秒 (1_000_000_000 ナノ秒)
-        let nanos = time.unwrap();
-        assert!(nanos > 900_000_000 && nanos < 1_100_000_000);
-    }
 }
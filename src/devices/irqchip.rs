@@ -0,0 +1,330 @@
+//! 割り込みまわりの部品をつなぐ IrqChip
+//!
+//! 次の 3 つは互いを所有せず、[`IrqChip`] が順に呼び出してつなぐ。
+//!
+//! - [`InterruptController`] - GIC (ペンディング・ACK・EOI)
+//! - [`Timer`] - ARM Generic Timer のソフトウェアモデル
+//! - [`TimerIrqPolicy`] - タイマーの状態から GIC のどの割り込みを上げ下げするか
+//!
+//! run ループは GIC のロックを取ったままタイマーを読み書きする必要がなく、
+//! ポリシーは `Timer` と `Gic` を渡すだけで単体でテストできる。
+
+use super::gic::{create_shared_gic, Gic, SharedGic, GIC_DIST_BASE};
+use super::interrupt::InterruptController;
+use super::timer::{ticks_to_duration, Timer, PHYS_TIMER_IRQ, VIRT_TIMER_IRQ};
+use crate::boot::layout::IrqMap;
+use std::time::Duration;
+
+/// vCPU から戻ったときの仮想タイマーの割り込み線
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerLine {
+    /// CVAL に達した (割り込みを上げる)
+    Fired,
+    /// 無効化またはマスクされた (レベルトリガなので取り下げる)
+    Lowered,
+    /// 有効だがまだ CVAL に達していない
+    Waiting,
+}
+
+impl TimerLine {
+    /// ゲストの CNTV_CTL_EL0 / CNTV_CVAL_EL0 とハードウェアのカウンタから決める
+    pub fn from_guest(ctl: u64, cval: u64, counter: u64) -> Self {
+        let enabled = ctl & 0x1 != 0;
+        let masked = ctl & 0x2 != 0;
+        if !enabled || masked {
+            Self::Lowered
+        } else if counter >= cval {
+            Self::Fired
+        } else {
+            Self::Waiting
+        }
+    }
+}
+
+/// タイマーの割り込みを GIC のどの INTID に、いつ上げるか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerIrqPolicy {
+    /// 非セキュア物理タイマーの INTID
+    pub phys: u32,
+    /// 仮想タイマーの INTID
+    pub virt: u32,
+}
+
+impl Default for TimerIrqPolicy {
+    fn default() -> Self {
+        Self {
+            phys: PHYS_TIMER_IRQ,
+            virt: VIRT_TIMER_IRQ,
+        }
+    }
+}
+
+impl TimerIrqPolicy {
+    /// 割り込み番号の割り当てに合わせる
+    pub fn from_irq_map(irqs: &IrqMap) -> Self {
+        Self {
+            phys: irqs.phys_timer,
+            virt: irqs.virt_timer,
+        }
+    }
+
+    /// ソフトウェアタイマーの状態を GIC に反映する
+    ///
+    /// 発火したタイマーの割り込みをペンディングにする。仮想タイマーは発火した時刻を
+    /// 割り込みレイテンシの起点にする。
+    pub fn poll(&self, timer: &Timer, gic: &mut Gic) {
+        if timer.phys_timer_pending() {
+            gic.set_irq_pending(self.phys);
+        }
+        if let Some(overdue) = timer.virt_timer_overdue() {
+            let fired = gic.now().saturating_sub(overdue);
+            gic.set_irq_pending_since(self.virt, fired);
+        }
+    }
+
+    /// ゲストが動かしていた仮想タイマーの割り込み線を GIC に反映する
+    ///
+    /// `counter` はゲストの仮想カウンタ (発火からの遅れを求めるのに使う)。
+    pub fn apply_virt(&self, line: TimerLine, counter: u64, cval: u64, gic: &mut Gic) {
+        match line {
+            TimerLine::Fired => {
+                let fired = fired_at(counter, cval, gic.now());
+                gic.set_irq_pending_since(self.virt, fired);
+            }
+            // kexec 後のカーネルが前のカーネルのタイマー割り込みを受けないように
+            TimerLine::Lowered => gic.clear_irq_pending(self.virt),
+            TimerLine::Waiting => {}
+        }
+    }
+}
+
+/// タイマーが CVAL に達した時刻 (`now` にゲストの仮想カウンタが `counter` だった場合)
+///
+/// run ループは VM Exit のたびにタイマーを確認するため、注入は発火より遅れる。
+/// この時刻を割り込みレイテンシの起点にして、遅れを計測に含める。
+fn fired_at(counter: u64, cval: u64, now: Duration) -> Duration {
+    let overdue = ticks_to_duration(counter.saturating_sub(cval));
+    now.saturating_sub(overdue)
+}
+
+/// GIC・タイマー・注入ポリシーをまとめる薄いファサード
+#[derive(Debug)]
+pub struct IrqChip {
+    intc: InterruptController,
+    timer: Timer,
+    policy: TimerIrqPolicy,
+}
+
+impl Default for IrqChip {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IrqChip {
+    /// 内部の GIC を使う IrqChip を作成
+    pub fn new() -> Self {
+        Self::with_gic(create_shared_gic(GIC_DIST_BASE))
+    }
+
+    /// 既存の共有 GIC (MMIO に登録したもの) を使う IrqChip を作成
+    pub fn with_gic(gic: SharedGic) -> Self {
+        Self {
+            intc: InterruptController::with_gic(gic),
+            timer: Timer::new(),
+            policy: TimerIrqPolicy::default(),
+        }
+    }
+
+    /// GIC
+    pub fn intc(&self) -> &InterruptController {
+        &self.intc
+    }
+
+    /// GIC (可変)
+    pub fn intc_mut(&mut self) -> &mut InterruptController {
+        &mut self.intc
+    }
+
+    /// 共有 GIC
+    pub fn gic(&self) -> &SharedGic {
+        &self.intc.gic
+    }
+
+    /// タイマー
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
+
+    /// タイマー (可変)
+    pub fn timer_mut(&mut self) -> &mut Timer {
+        &mut self.timer
+    }
+
+    /// タイマー割り込みの注入ポリシー
+    pub fn policy(&self) -> TimerIrqPolicy {
+        self.policy
+    }
+
+    /// タイマー割り込みの注入ポリシーを変更する
+    ///
+    /// INTID の正は [`crate::Hypervisor`] の [`IrqMap`] で、Device Tree と食い違わないよう
+    /// [`crate::Hypervisor::set_irq_map`] からだけ呼ぶ。
+    pub(crate) fn set_policy(&mut self, policy: TimerIrqPolicy) {
+        self.policy = policy;
+    }
+
+    /// タイマー IRQ をポーリングして GIC に反映
+    ///
+    /// VM のメインループで定期的に呼び出す必要があります。
+    pub fn poll_timer_irqs(&self) {
+        self.policy
+            .poll(&self.timer, &mut self.intc.gic.lock().unwrap());
+    }
+
+    /// ゲストの仮想タイマーの設定をソフトウェアタイマーに写す
+    pub fn sync_virt_timer(&mut self, ctl: u64, cval: u64) {
+        self.timer.virt_timer.write_ctl(ctl);
+        self.timer.virt_timer.write_cval(cval);
+    }
+
+    /// vCPU から戻ったときのゲストの仮想タイマーを GIC に反映する
+    ///
+    /// `hw_counter` はハードウェアのカウンタ。発火の判定に使い、結果を返す。
+    pub fn update_virt_timer(&self, ctl: u64, cval: u64, hw_counter: u64) -> TimerLine {
+        let line = TimerLine::from_guest(ctl, cval, hw_counter);
+        self.raise_virt_timer_line(line, cval);
+        line
+    }
+
+    /// 仮想タイマーの割り込みを上げる (VTIMER_ACTIVATED)
+    pub fn raise_virt_timer(&self, cval: u64) {
+        self.raise_virt_timer_line(TimerLine::Fired, cval);
+    }
+
+    fn raise_virt_timer_line(&self, line: TimerLine, cval: u64) {
+        let counter = self.timer.get_virt_counter();
+        self.policy
+            .apply_virt(line, counter, cval, &mut self.intc.gic.lock().unwrap());
+    }
+
+    /// ペンディング中の IRQ があるかチェック
+    pub fn has_pending_irq(&self) -> bool {
+        self.intc.has_pending_irq()
+    }
+
+    /// 次のタイマーイベントまでの時間（ナノ秒）
+    pub fn time_until_next_timer(&self) -> Option<u64> {
+        self.timer.time_until_next_event()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::timer::TimerReg;
+
+    #[test]
+    fn 仮想タイマーの割り込み線をゲストの設定から決める() {
+        assert_eq!(TimerLine::from_guest(0x1, 100, 100), TimerLine::Fired);
+        assert_eq!(TimerLine::from_guest(0x1, 100, 99), TimerLine::Waiting);
+        // マスク・無効化は取り下げ
+        assert_eq!(TimerLine::from_guest(0x3, 100, 200), TimerLine::Lowered);
+        assert_eq!(TimerLine::from_guest(0x0, 100, 200), TimerLine::Lowered);
+    }
+
+    #[test]
+    fn ポリシーは割り当てた番号に割り込みを上げ下げする() {
+        let policy = TimerIrqPolicy { phys: 30, virt: 20 };
+        let mut gic = Gic::new();
+        policy.apply_virt(TimerLine::Fired, 1_000, 900, &mut gic);
+        assert!(gic.is_irq_pending(20));
+        assert!(!gic.is_irq_pending(VIRT_TIMER_IRQ));
+        policy.apply_virt(TimerLine::Waiting, 1_000, 900, &mut gic);
+        assert!(gic.is_irq_pending(20));
+        policy.apply_virt(TimerLine::Lowered, 1_000, 900, &mut gic);
+        assert!(!gic.is_irq_pending(20));
+
+        assert_eq!(
+            TimerIrqPolicy::from_irq_map(&IrqMap::QEMU_VIRT),
+            TimerIrqPolicy::default()
+        );
+    }
+
+    #[test]
+    fn ポリシーはタイマーを所有せずに_gic_へ反映する() {
+        let mut timer = Timer::new();
+        let counter = timer.get_virt_counter();
+        timer.write_sysreg(TimerReg::CNTV_CTL_EL0, 1).unwrap();
+        timer
+            .write_sysreg(TimerReg::CNTV_CVAL_EL0, counter.saturating_sub(100))
+            .unwrap();
+
+        let mut gic = Gic::new();
+        TimerIrqPolicy::default().poll(&timer, &mut gic);
+        assert!(gic.is_irq_pending(VIRT_TIMER_IRQ));
+        assert!(!gic.is_irq_pending(PHYS_TIMER_IRQ));
+    }
+
+    #[test]
+    fn poll_timer_irqs_で物理タイマーirqがgicにセットされる() {
+        let mut chip = IrqChip::new();
+        chip.intc_mut().enable();
+        chip.intc_mut().enable_timer_irqs();
+
+        // タイマーを過去に設定してペンディング状態にする
+        let counter = chip.timer().get_phys_counter();
+        let timer = chip.timer_mut();
+        timer.write_sysreg(TimerReg::CNTP_CTL_EL0, 1).unwrap(); // 有効化
+        timer
+            .write_sysreg(TimerReg::CNTP_CVAL_EL0, counter.saturating_sub(100))
+            .unwrap();
+
+        chip.poll_timer_irqs();
+
+        assert!(chip.has_pending_irq());
+        assert_eq!(chip.intc().get_pending_irq(), Some(PHYS_TIMER_IRQ));
+
+        // Acknowledge → EOI の後はスプリアス
+        let irq = chip.intc_mut().acknowledge();
+        assert_eq!(irq, PHYS_TIMER_IRQ);
+        chip.intc_mut().end_of_interrupt(irq);
+        assert_eq!(chip.intc_mut().acknowledge(), 1023);
+    }
+
+    #[test]
+    fn ゲストの仮想タイマーを写して反映する() {
+        let mut chip = IrqChip::new();
+        chip.sync_virt_timer(0x1, 500);
+        assert_eq!(chip.timer().virt_timer.read_cval(), 500);
+
+        assert_eq!(chip.update_virt_timer(0x1, 500, 400), TimerLine::Waiting);
+        assert!(!chip.has_pending_irq());
+        assert_eq!(chip.update_virt_timer(0x1, 500, 600), TimerLine::Fired);
+        assert!(chip.gic().lock().unwrap().is_irq_pending(VIRT_TIMER_IRQ));
+        assert_eq!(chip.update_virt_timer(0x3, 500, 600), TimerLine::Lowered);
+        assert!(!chip.gic().lock().unwrap().is_irq_pending(VIRT_TIMER_IRQ));
+
+        chip.raise_virt_timer(500);
+        assert!(chip.gic().lock().unwrap().is_irq_pending(VIRT_TIMER_IRQ));
+    }
+
+    #[test]
+    fn time_until_next_timer_は次のイベントまでの時間を返す() {
+        let mut chip = IrqChip::new();
+        assert!(chip.time_until_next_timer().is_none());
+
+        // 1秒後にタイマーを設定
+        let counter = chip.timer().get_phys_counter();
+        let freq = chip.timer().get_frequency();
+        let timer = chip.timer_mut();
+        timer.write_sysreg(TimerReg::CNTP_CTL_EL0, 1).unwrap();
+        timer
+            .write_sysreg(TimerReg::CNTP_CVAL_EL0, counter + freq)
+            .unwrap();
+
+        // おおよそ 1 秒 (1_000_000_000 ナノ秒)
+        let nanos = chip.time_until_next_timer().unwrap();
+        assert!(nanos > 900_000_000 && nanos < 1_100_000_000);
+    }
+}
@@ -1,7 +1,20 @@
 //! Device emulation modules
 
+pub mod clock;
+pub mod console;
+pub mod dmesg;
+pub mod fault;
 pub mod gic;
+pub mod host_time;
 pub mod interrupt;
+pub mod irqchip;
+pub mod pl330;
+pub mod scmi;
+pub mod sdk;
+pub mod shmem;
+pub mod smmu;
+pub mod testing;
 pub mod timer;
+#[cfg(feature = "uart")]
 pub mod uart;
 pub mod virtio;
@@ -0,0 +1,152 @@
+//! ARM PL330 (DMA-330) DMA コントローラのスタブ
+//!
+//! DMA エンジンのドライバ (`CONFIG_PL330_DMA`) を組み込んだカーネルや、
+//! `dmas = <&pdma N>` を参照する Device Tree を使い回す場合に、プローブが
+//! 失敗したり依存するデバイスが defer され続けたりしないようにするためのデバイス。
+//!
+//! 転送は実行しない。識別レジスタ (PrimeCell ID) と構成レジスタ (CR0〜CR4・CRD) に
+//! 妥当な値を返し、DMA マネージャ・チャネル・デバッグインターフェースは常に停止中
+//! (idle) として見せる。書き込みは無視する。ゲストが転送を要求しても完了しないため、
+//! クライアントのドライバは自身のタイムアウトで PIO などにフォールバックする。
+//!
+//! Device Tree には [`crate::boot::device_tree::DeviceTreeConfig::pl330_base`] で
+//! `arm,pl330` のノードを追加する。
+
+use crate::mmio::MmioHandler;
+use std::error::Error;
+
+/// PL330 スタブの既定のベースアドレス (QEMU virt の空き領域)
+pub const PL330_BASE: u64 = 0x090e_0000;
+/// レジスタ領域のサイズ
+pub const PL330_SIZE: u64 = 0x1000;
+
+/// Peripheral ID (PrimeCell の part number 0x330、designer 0x41 = ARM、revision 0)
+pub const PL330_PERIPH_ID: u32 = 0x0004_1330;
+/// PrimeCell ID
+pub const PRIMECELL_ID: u32 = 0xb105_f00d;
+
+/// 報告する DMA チャネル数
+pub const PL330_CHANNELS: u32 = 8;
+/// 報告するイベント・割り込みの数
+pub const PL330_EVENTS: u32 = 16;
+
+/// レジスタオフセット
+pub mod regs {
+    /// DMA マネージャの状態 (R)
+    pub const DSR: u64 = 0x000;
+    /// チャネル 0 の状態 (R)、以降 8 バイトおき
+    pub const CSR0: u64 = 0x100;
+    /// デバッグ命令の実行状態 (R)
+    pub const DBGSTATUS: u64 = 0xd00;
+    /// 構成レジスタ 0 (R)
+    pub const CR0: u64 = 0xe00;
+    /// DMA 構成レジスタ (R)
+    pub const CRD: u64 = 0xe14;
+    /// Peripheral ID 0 (R)、以降 PERIPH_ID_3 まで 4 バイトおき
+    pub const PERIPH_ID_0: u64 = 0xfe0;
+    /// PrimeCell ID 0 (R)、以降 PCELL_ID_3 まで 4 バイトおき
+    pub const PCELL_ID_0: u64 = 0xff0;
+}
+
+/// CR0 の `num_chnls` フィールドの位置 (チャネル数 - 1)
+const CR0_NUM_CHNLS_SHIFT: u32 = 4;
+/// CR0 の `num_events` フィールドの位置 (イベント数 - 1)
+const CR0_NUM_EVENTS_SHIFT: u32 = 17;
+/// CRD: データバス幅 64 bit (log2 のバイト数)
+const CRD_DATA_WIDTH_64: u32 = 0x3;
+/// CRD の `data_buffer_dep` フィールドの位置 (バッファの深さ - 1)
+const CRD_DATA_BUFFER_DEP_SHIFT: u32 = 20;
+/// 報告するデータバッファの深さ (ライン数)
+const DATA_BUFFER_DEPTH: u32 = 16;
+
+/// PL330 DMA コントローラのスタブ
+pub struct Pl330Stub {
+    base_addr: u64,
+}
+
+impl Pl330Stub {
+    /// 指定したベースアドレスにスタブを作成
+    pub fn new(base_addr: u64) -> Self {
+        Self { base_addr }
+    }
+
+    /// 32 bit の ID を 4 つの 8 bit レジスタに分けて読む
+    fn id_byte(id: u32, index: u64) -> u64 {
+        ((id >> (index * 8)) & 0xff) as u64
+    }
+}
+
+impl MmioHandler for Pl330Stub {
+    fn name(&self) -> &str {
+        "pl330"
+    }
+
+    fn base(&self) -> u64 {
+        self.base_addr
+    }
+
+    fn size(&self) -> u64 {
+        PL330_SIZE
+    }
+
+    fn read(&mut self, offset: u64, _size: usize) -> Result<u64, Box<dyn Error>> {
+        let value = match offset {
+            // DSR・CSRn・DBGSTATUS は 0 (停止中・idle)
+            regs::CR0 => {
+                ((PL330_CHANNELS - 1) << CR0_NUM_CHNLS_SHIFT
+                    | (PL330_EVENTS - 1) << CR0_NUM_EVENTS_SHIFT) as u64
+            }
+            regs::CRD => {
+                (CRD_DATA_WIDTH_64 | (DATA_BUFFER_DEPTH - 1) << CRD_DATA_BUFFER_DEP_SHIFT) as u64
+            }
+            0xfe0..=0xfec if offset.is_multiple_of(4) => {
+                Self::id_byte(PL330_PERIPH_ID, (offset - regs::PERIPH_ID_0) / 4)
+            }
+            0xff0..=0xffc if offset.is_multiple_of(4) => {
+                Self::id_byte(PRIMECELL_ID, (offset - regs::PCELL_ID_0) / 4)
+            }
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, _offset: u64, _value: u64, _size: usize) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_id(device: &mut Pl330Stub, base: u64) -> u32 {
+        (0..4).fold(0, |id, i| {
+            id | (device.read(base + i * 4, 4).unwrap() as u32) << (i * 8)
+        })
+    }
+
+    #[test]
+    fn primecell_の識別レジスタを返す() {
+        let mut device = Pl330Stub::new(PL330_BASE);
+        assert_eq!(read_id(&mut device, regs::PERIPH_ID_0), 0x0004_1330);
+        assert_eq!(read_id(&mut device, regs::PCELL_ID_0), 0xb105_f00d);
+    }
+
+    #[test]
+    fn 構成を報告し常に停止中に見える() {
+        let mut device = Pl330Stub::new(PL330_BASE);
+        let cr0 = device.read(regs::CR0, 4).unwrap();
+        assert_eq!((cr0 >> 4) & 0x7, 7);
+        assert_eq!((cr0 >> 17) & 0x1f, 15);
+        let crd = device.read(regs::CRD, 4).unwrap();
+        assert_eq!(crd & 0x7, 3);
+        assert_eq!((crd >> 20) & 0x3ff, 15);
+
+        // DMAGO などの書き込みは無視し、マネージャもチャネルも停止中のまま
+        device.write(0xd08, 0xa0, 4).unwrap();
+        device.write(0xd04, 0, 4).unwrap();
+        assert_eq!(device.read(regs::DSR, 4).unwrap(), 0);
+        assert_eq!(device.read(regs::CSR0, 4).unwrap(), 0);
+        assert_eq!(device.read(regs::DBGSTATUS, 4).unwrap(), 0);
+    }
+}
@@ -0,0 +1,566 @@
+//! 最小限の SCMI (System Control and Management Interface) ファームウェア
+//!
+//! クロックや電源ドメインを参照する Device Tree では、Linux はそれらの
+//! プロバイダが現れるまでデバイスの probe を保留する。プロバイダがないと
+//! probe が終わらないため、固定のクロックと常に ON にできる電源ドメインを
+//! SCMI で公開する。
+//!
+//! トランスポートは Linux の `arm,scmi-smc` と同じ構成:
+//!
+//! - 共有メモリ (`arm,scmi-shmem`) を MMIO 領域として公開する
+//! - ゲストは共有メモリにメッセージを書いてから [`SCMI_SMC_ID`] で HVC を発行する
+//!   (PSCI と同じ conduit を使う)
+//! - HVC の処理中に応答を共有メモリへ書き戻し、チャネルを free に戻す
+//!
+//! 対応しているプロトコル:
+//!
+//! | ID   | プロトコル | メッセージ |
+//! |------|-----------|------------|
+//! | 0x10 | Base      | VERSION / ATTRIBUTES / MESSAGE_ATTRIBUTES / DISCOVER_* |
+//! | 0x11 | Power     | VERSION / ATTRIBUTES / MESSAGE_ATTRIBUTES / DOMAIN_ATTRIBUTES / STATE_SET / STATE_GET |
+//! | 0x14 | Clock     | VERSION / ATTRIBUTES / MESSAGE_ATTRIBUTES / CLOCK_ATTRIBUTES / DESCRIBE_RATES / RATE_SET / RATE_GET / CONFIG_SET |
+
+use crate::mmio::MmioHandler;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+/// 共有メモリの既定のベースアドレス (壁時計デバイスの次)
+pub const SCMI_SHMEM_BASE: u64 = 0x090d_0000;
+/// 共有メモリのサイズ
+pub const SCMI_SHMEM_SIZE: u64 = 0x1000;
+/// doorbell に使う SMCCC の Function ID (SiP Service, Fast Call, SMC32)
+pub const SCMI_SMC_ID: u32 = 0x8200_0010;
+
+/// プロトコル ID
+pub mod protocol {
+    /// Base プロトコル
+    pub const BASE: u8 = 0x10;
+    /// Power domain management プロトコル
+    pub const POWER: u8 = 0x11;
+    /// Clock management プロトコル
+    pub const CLOCK: u8 = 0x14;
+}
+
+/// 共有メモリのレイアウト (SCMI 仕様 5.1)
+mod shmem {
+    /// bit 0: チャネルが free、bit 1: エラー
+    pub const CHANNEL_STATUS: usize = 0x04;
+    /// メッセージヘッダ + ペイロードのバイト数
+    pub const LENGTH: usize = 0x14;
+    pub const MSG_HEADER: usize = 0x18;
+    pub const PAYLOAD: usize = 0x1c;
+
+    pub const CHANNEL_FREE: u32 = 1 << 0;
+}
+
+/// 共通のメッセージ ID
+const MSG_PROTOCOL_VERSION: u8 = 0x0;
+const MSG_PROTOCOL_ATTRIBUTES: u8 = 0x1;
+const MSG_PROTOCOL_MESSAGE_ATTRIBUTES: u8 = 0x2;
+
+/// Base プロトコルのメッセージ ID
+const BASE_DISCOVER_VENDOR: u8 = 0x3;
+const BASE_DISCOVER_SUB_VENDOR: u8 = 0x4;
+const BASE_DISCOVER_IMPLEMENTATION_VERSION: u8 = 0x5;
+const BASE_DISCOVER_LIST_PROTOCOLS: u8 = 0x6;
+
+/// Power プロトコルのメッセージ ID
+const POWER_DOMAIN_ATTRIBUTES: u8 = 0x3;
+const POWER_STATE_SET: u8 = 0x4;
+const POWER_STATE_GET: u8 = 0x5;
+
+/// Clock プロトコルのメッセージ ID
+const CLOCK_ATTRIBUTES: u8 = 0x3;
+const CLOCK_DESCRIBE_RATES: u8 = 0x4;
+const CLOCK_RATE_SET: u8 = 0x5;
+const CLOCK_RATE_GET: u8 = 0x6;
+const CLOCK_CONFIG_SET: u8 = 0x7;
+
+/// 各プロトコルのバージョン (2.0)
+const PROTOCOL_VERSION_2_0: u32 = 0x2_0000;
+/// 実装バージョン
+const IMPLEMENTATION_VERSION: u32 = 1;
+/// ベンダー名 (16 バイトまで)
+const VENDOR: &str = "hypervisor";
+
+/// 電源ドメインの状態: ON
+pub const POWER_STATE_ON: u32 = 0;
+/// 電源ドメインの状態: OFF (SCMI 仕様の context lost ビット付き)
+pub const POWER_STATE_OFF: u32 = 0x4000_0000;
+
+/// SCMI のステータスコード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+enum Status {
+    Success = 0,
+    NotSupported = -1,
+    InvalidParameters = -2,
+    NotFound = -4,
+}
+
+/// 公開するクロック
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScmiClock {
+    /// クロック名 (15 文字まで)
+    pub name: String,
+    /// 周波数 (Hz)
+    pub rate_hz: u64,
+    /// 有効かどうか
+    pub enabled: bool,
+}
+
+/// 公開する電源ドメイン
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScmiPowerDomain {
+    /// ドメイン名 (15 文字まで)
+    pub name: String,
+    /// 現在の状態 (`POWER_STATE_ON` / `POWER_STATE_OFF`)
+    pub state: u32,
+}
+
+#[derive(Debug)]
+struct ScmiState {
+    shmem: Vec<u8>,
+    clocks: Vec<ScmiClock>,
+    /// 追加したときのクロックの設定 (`reset` で戻す)
+    initial_clocks: Vec<ScmiClock>,
+    power_domains: Vec<ScmiPowerDomain>,
+    /// 処理したメッセージ数
+    messages: u64,
+}
+
+/// チャネルが free の状態の共有メモリ
+fn initial_shmem() -> Vec<u8> {
+    let mut shmem = vec![0; SCMI_SHMEM_SIZE as usize];
+    shmem[shmem::CHANNEL_STATUS..shmem::CHANNEL_STATUS + 4]
+        .copy_from_slice(&shmem::CHANNEL_FREE.to_le_bytes());
+    shmem
+}
+
+/// SCMI の共有メモリ (MMIO ハンドラ)
+pub struct ScmiDevice {
+    base_addr: u64,
+    state: Arc<Mutex<ScmiState>>,
+}
+
+impl ScmiDevice {
+    /// クロックも電源ドメインもないエージェントを作成
+    pub fn new(base_addr: u64) -> Self {
+        Self {
+            base_addr,
+            state: Arc::new(Mutex::new(ScmiState {
+                shmem: initial_shmem(),
+                clocks: Vec::new(),
+                initial_clocks: Vec::new(),
+                power_domains: Vec::new(),
+                messages: 0,
+            })),
+        }
+    }
+
+    /// 固定周波数のクロックを追加する (ID は追加した順に 0 から)
+    pub fn with_clock(self, name: &str, rate_hz: u64) -> Self {
+        let clock = ScmiClock {
+            name: name.to_string(),
+            rate_hz,
+            enabled: true,
+        };
+        let mut state = self.state.lock().unwrap();
+        state.initial_clocks.push(clock.clone());
+        state.clocks.push(clock);
+        drop(state);
+        self
+    }
+
+    /// 電源ドメインを追加する (ID は追加した順に 0 から、初期状態は ON)
+    pub fn with_power_domain(self, name: &str) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .power_domains
+            .push(ScmiPowerDomain {
+                name: name.to_string(),
+                state: POWER_STATE_ON,
+            });
+        self
+    }
+
+    /// HVC から呼ぶ doorbell
+    pub fn doorbell(&self) -> ScmiDoorbell {
+        ScmiDoorbell {
+            base_addr: self.base_addr,
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+impl MmioHandler for ScmiDevice {
+    fn name(&self) -> &str {
+        "scmi-shmem"
+    }
+
+    fn base(&self) -> u64 {
+        self.base_addr
+    }
+
+    fn size(&self) -> u64 {
+        SCMI_SHMEM_SIZE
+    }
+
+    // クロックと電源ドメインも追加したときの設定に戻す (処理したメッセージ数は残す)
+    fn reset(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.shmem = initial_shmem();
+        state.clocks = state.initial_clocks.clone();
+        for domain in &mut state.power_domains {
+            domain.state = POWER_STATE_ON;
+        }
+    }
+
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, Box<dyn Error>> {
+        let state = self.state.lock().unwrap();
+        let bytes = state
+            .shmem
+            .get(offset as usize..offset as usize + size)
+            .ok_or_else(|| format!("SCMI shmem read out of range: 0x{:x}", offset))?;
+        let mut buf = [0u8; 8];
+        buf[..size].copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn write(&mut self, offset: u64, value: u64, size: usize) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        let bytes = state
+            .shmem
+            .get_mut(offset as usize..offset as usize + size)
+            .ok_or_else(|| format!("SCMI shmem write out of range: 0x{:x}", offset))?;
+        bytes.copy_from_slice(&value.to_le_bytes()[..size]);
+        Ok(())
+    }
+}
+
+/// 共有メモリのメッセージを処理するハンドル
+#[derive(Clone)]
+pub struct ScmiDoorbell {
+    base_addr: u64,
+    state: Arc<Mutex<ScmiState>>,
+}
+
+impl ScmiDoorbell {
+    /// 共有メモリのベースアドレス
+    pub fn base(&self) -> u64 {
+        self.base_addr
+    }
+
+    /// 処理したメッセージ数
+    pub fn messages(&self) -> u64 {
+        self.state.lock().unwrap().messages
+    }
+
+    /// クロックの現在の設定
+    pub fn clocks(&self) -> Vec<ScmiClock> {
+        self.state.lock().unwrap().clocks.clone()
+    }
+
+    /// 電源ドメインの現在の状態
+    pub fn power_domains(&self) -> Vec<ScmiPowerDomain> {
+        self.state.lock().unwrap().power_domains.clone()
+    }
+
+    /// 共有メモリのメッセージを処理して応答を書き戻す
+    pub fn ring(&self) {
+        let mut state = self.state.lock().unwrap();
+        let header = state.u32_at(shmem::MSG_HEADER);
+        let length = state.u32_at(shmem::LENGTH) as usize;
+        let max_payload = state.shmem.len() - shmem::PAYLOAD;
+        let args: Vec<u32> = (0..length.saturating_sub(4).min(max_payload) / 4)
+            .map(|i| state.u32_at(shmem::PAYLOAD + i * 4))
+            .collect();
+
+        let protocol_id = ((header >> 10) & 0xff) as u8;
+        let message_id = (header & 0xff) as u8;
+        let response = match state.handle(protocol_id, message_id, &args) {
+            Ok(data) => [&(Status::Success as i32).to_le_bytes()[..], &data].concat(),
+            Err(status) => (status as i32).to_le_bytes().to_vec(),
+        };
+        let response = &response[..response.len().min(max_payload)];
+
+        state.shmem[shmem::PAYLOAD..shmem::PAYLOAD + response.len()].copy_from_slice(response);
+        state.set_u32(shmem::LENGTH, 4 + response.len() as u32);
+        state.set_u32(shmem::CHANNEL_STATUS, shmem::CHANNEL_FREE);
+        state.messages += 1;
+    }
+}
+
+impl ScmiState {
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.shmem[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn set_u32(&mut self, offset: usize, value: u32) {
+        self.shmem[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// 1 つのメッセージを処理し、ステータスに続く戻り値を返す
+    fn handle(&mut self, protocol_id: u8, message_id: u8, args: &[u32]) -> Result<Vec<u8>, Status> {
+        let arg = |i: usize| args.get(i).copied().ok_or(Status::InvalidParameters);
+        match (protocol_id, message_id) {
+            (protocol::BASE | protocol::POWER | protocol::CLOCK, MSG_PROTOCOL_VERSION) => {
+                Ok(words(&[PROTOCOL_VERSION_2_0]))
+            }
+            (
+                protocol::BASE | protocol::POWER | protocol::CLOCK,
+                MSG_PROTOCOL_MESSAGE_ATTRIBUTES,
+            ) => {
+                let message = arg(0)?;
+                if message <= 0xff && supports(protocol_id, message as u8) {
+                    Ok(words(&[0]))
+                } else {
+                    Err(Status::NotFound)
+                }
+            }
+
+            // Base: 他のエージェント数 (0) と Base 以外のプロトコル数
+            (protocol::BASE, MSG_PROTOCOL_ATTRIBUTES) => Ok(words(&[2])),
+            (protocol::BASE, BASE_DISCOVER_VENDOR | BASE_DISCOVER_SUB_VENDOR) => {
+                Ok(short_name(VENDOR).to_vec())
+            }
+            (protocol::BASE, BASE_DISCOVER_IMPLEMENTATION_VERSION) => {
+                Ok(words(&[IMPLEMENTATION_VERSION]))
+            }
+            (protocol::BASE, BASE_DISCOVER_LIST_PROTOCOLS) => {
+                let skip = arg(0)? as usize;
+                let list: Vec<u8> = [protocol::POWER, protocol::CLOCK]
+                    .into_iter()
+                    .skip(skip)
+                    .collect();
+                let mut data = words(&[list.len() as u32]);
+                let mut packed = list.clone();
+                packed.resize(list.len().next_multiple_of(4), 0);
+                data.extend_from_slice(&packed);
+                Ok(data)
+            }
+
+            // Power: ドメイン数 (統計用の共有メモリはなし)
+            (protocol::POWER, MSG_PROTOCOL_ATTRIBUTES) => {
+                Ok(words(&[self.power_domains.len() as u32, 0, 0, 0]))
+            }
+            (protocol::POWER, POWER_DOMAIN_ATTRIBUTES) => {
+                let domain = self.power_domain(arg(0)?)?;
+                // flags: 同期的な状態変更に対応
+                let mut data = words(&[1 << 29]);
+                data.extend_from_slice(&short_name(&domain.name));
+                Ok(data)
+            }
+            (protocol::POWER, POWER_STATE_SET) => {
+                let (domain, state) = (arg(1)?, arg(2)?);
+                if state != POWER_STATE_ON && state != POWER_STATE_OFF {
+                    return Err(Status::InvalidParameters);
+                }
+                self.power_domain(domain)?;
+                self.power_domains[domain as usize].state = state;
+                Ok(Vec::new())
+            }
+            (protocol::POWER, POWER_STATE_GET) => Ok(words(&[self.power_domain(arg(0)?)?.state])),
+
+            // Clock: クロック数、非同期要求は 0
+            (protocol::CLOCK, MSG_PROTOCOL_ATTRIBUTES) => Ok(words(&[self.clocks.len() as u32])),
+            (protocol::CLOCK, CLOCK_ATTRIBUTES) => {
+                let clock = self.clock(arg(0)?)?;
+                let mut data = words(&[clock.enabled as u32]);
+                data.extend_from_slice(&short_name(&clock.name));
+                // clock_enable_latency (2.0 以降)
+                data.extend_from_slice(&0u32.to_le_bytes());
+                Ok(data)
+            }
+            (protocol::CLOCK, CLOCK_DESCRIBE_RATES) => {
+                let (clock, index) = (self.clock(arg(0)?)?, arg(1)?);
+                // 離散値 1 つだけ (num_rates = 1, remaining = 0)
+                if index > 0 {
+                    return Ok(words(&[0]));
+                }
+                let rate = clock.rate_hz;
+                Ok(words(&[1, rate as u32, (rate >> 32) as u32]))
+            }
+            (protocol::CLOCK, CLOCK_RATE_SET) => {
+                let (clock, rate) = (arg(1)?, (arg(3)? as u64) << 32 | arg(2)? as u64);
+                self.clock(clock)?;
+                self.clocks[clock as usize].rate_hz = rate;
+                Ok(Vec::new())
+            }
+            (protocol::CLOCK, CLOCK_RATE_GET) => {
+                let rate = self.clock(arg(0)?)?.rate_hz;
+                Ok(words(&[rate as u32, (rate >> 32) as u32]))
+            }
+            (protocol::CLOCK, CLOCK_CONFIG_SET) => {
+                let (clock, attributes) = (arg(0)?, arg(1)?);
+                self.clock(clock)?;
+                self.clocks[clock as usize].enabled = attributes & 1 != 0;
+                Ok(Vec::new())
+            }
+
+            _ => Err(Status::NotSupported),
+        }
+    }
+
+    fn clock(&self, id: u32) -> Result<&ScmiClock, Status> {
+        self.clocks.get(id as usize).ok_or(Status::NotFound)
+    }
+
+    fn power_domain(&self, id: u32) -> Result<&ScmiPowerDomain, Status> {
+        self.power_domains.get(id as usize).ok_or(Status::NotFound)
+    }
+}
+
+/// プロトコルがメッセージに対応しているか
+fn supports(protocol_id: u8, message_id: u8) -> bool {
+    let last = match protocol_id {
+        protocol::BASE => BASE_DISCOVER_LIST_PROTOCOLS,
+        protocol::POWER => POWER_STATE_GET,
+        protocol::CLOCK => CLOCK_CONFIG_SET,
+        _ => return false,
+    };
+    message_id <= last
+}
+
+fn words(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+/// SCMI の短い名前 (NUL 終端込みで 16 バイト)
+fn short_name(name: &str) -> [u8; 16] {
+    let mut buf = [0u8; 16];
+    let len = name.len().min(15);
+    buf[..len].copy_from_slice(&name.as_bytes()[..len]);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ゲストのドライバと同じ手順でメッセージを送り、(ステータス, 戻り値) を返す
+    fn call(device: &mut ScmiDevice, protocol_id: u8, message_id: u8, args: &[u32]) -> Vec<i64> {
+        let header = (protocol_id as u64) << 10 | message_id as u64;
+        device.write(shmem::CHANNEL_STATUS as u64, 0, 4).unwrap();
+        device
+            .write(shmem::LENGTH as u64, 4 + 4 * args.len() as u64, 4)
+            .unwrap();
+        device.write(shmem::MSG_HEADER as u64, header, 4).unwrap();
+        for (i, &arg) in args.iter().enumerate() {
+            device
+                .write((shmem::PAYLOAD + i * 4) as u64, arg as u64, 4)
+                .unwrap();
+        }
+        device.doorbell().ring();
+
+        assert_eq!(device.read(shmem::CHANNEL_STATUS as u64, 4).unwrap(), 1);
+        assert_eq!(device.read(shmem::MSG_HEADER as u64, 4).unwrap(), header);
+        let length = device.read(shmem::LENGTH as u64, 4).unwrap() as usize;
+        (0..(length - 4) / 4)
+            .map(|i| {
+                let value = device.read((shmem::PAYLOAD + i * 4) as u64, 4).unwrap();
+                if i == 0 {
+                    value as u32 as i32 as i64
+                } else {
+                    value as i64
+                }
+            })
+            .collect()
+    }
+
+    fn device() -> ScmiDevice {
+        ScmiDevice::new(SCMI_SHMEM_BASE)
+            .with_clock("uartclk", 24_000_000)
+            .with_power_domain("pd0")
+    }
+
+    #[test]
+    fn base_プロトコルで対応プロトコルを列挙できる() {
+        let mut scmi = device();
+        assert_eq!(
+            call(&mut scmi, protocol::BASE, MSG_PROTOCOL_VERSION, &[]),
+            [0, 0x2_0000]
+        );
+        assert_eq!(
+            call(
+                &mut scmi,
+                protocol::BASE,
+                BASE_DISCOVER_LIST_PROTOCOLS,
+                &[0]
+            ),
+            [0, 2, 0x1411]
+        );
+        let vendor = call(&mut scmi, protocol::BASE, BASE_DISCOVER_VENDOR, &[]);
+        assert_eq!(vendor[1], i64::from(u32::from_le_bytes(*b"hype")));
+        // 未知のプロトコル
+        assert_eq!(call(&mut scmi, 0x15, MSG_PROTOCOL_VERSION, &[]), [-1]);
+        assert_eq!(
+            call(
+                &mut scmi,
+                protocol::CLOCK,
+                MSG_PROTOCOL_MESSAGE_ATTRIBUTES,
+                &[0x20]
+            ),
+            [-4]
+        );
+    }
+
+    #[test]
+    fn クロックの周波数と有効状態を扱える() {
+        let mut scmi = device();
+        assert_eq!(
+            call(&mut scmi, protocol::CLOCK, MSG_PROTOCOL_ATTRIBUTES, &[]),
+            [0, 1]
+        );
+        assert_eq!(
+            call(&mut scmi, protocol::CLOCK, CLOCK_DESCRIBE_RATES, &[0, 0]),
+            [0, 1, 24_000_000, 0]
+        );
+        assert_eq!(
+            call(
+                &mut scmi,
+                protocol::CLOCK,
+                CLOCK_RATE_SET,
+                &[0, 0, 48_000_000, 0]
+            ),
+            [0]
+        );
+        assert_eq!(
+            call(&mut scmi, protocol::CLOCK, CLOCK_RATE_GET, &[0]),
+            [0, 48_000_000, 0]
+        );
+        assert_eq!(
+            call(&mut scmi, protocol::CLOCK, CLOCK_CONFIG_SET, &[0, 0]),
+            [0]
+        );
+        assert!(!scmi.doorbell().clocks()[0].enabled);
+        assert_eq!(call(&mut scmi, protocol::CLOCK, CLOCK_RATE_GET, &[1]), [-4]);
+        assert_eq!(call(&mut scmi, protocol::CLOCK, CLOCK_RATE_GET, &[]), [-2]);
+    }
+
+    #[test]
+    fn 電源ドメインの状態を切り替えられる() {
+        let mut scmi = device();
+        let attrs = call(&mut scmi, protocol::POWER, POWER_DOMAIN_ATTRIBUTES, &[0]);
+        assert_eq!(attrs.len(), 1 + 1 + 4);
+        assert_eq!(
+            call(
+                &mut scmi,
+                protocol::POWER,
+                POWER_STATE_SET,
+                &[0, 0, POWER_STATE_OFF]
+            ),
+            [0]
+        );
+        assert_eq!(
+            call(&mut scmi, protocol::POWER, POWER_STATE_GET, &[0]),
+            [0, POWER_STATE_OFF as i64]
+        );
+        assert_eq!(
+            call(&mut scmi, protocol::POWER, POWER_STATE_SET, &[0, 0, 7]),
+            [-2]
+        );
+        assert_eq!(scmi.doorbell().messages(), 4);
+    }
+}
@@ -0,0 +1,250 @@
+//! `hypervisor-device` crate のデバイスを MMIO ハンドラとして動かす
+//!
+//! 別の crate で作ったデバイスは [`hypervisor_device::Device`] だけを実装する。
+//! [`crate::Hypervisor::add_device`] が [`SdkDevice`] で包んで MMIO マネージャに登録し、
+//! [`IrqLine`] のレベルを [`MmioHandler::pending_irq`] として GIC に伝える。
+//! `MmioHandler` の統計やマイグレーションのフックは使わない。
+//!
+//! デバイスが [`hypervisor_device::VmControl::request_exit`] で終了を要求すると、
+//! run ループは [`crate::StopReason::DeviceExit`] で戻る。
+
+use crate::memory::GuestRam;
+use crate::mmio::MmioHandler;
+use crate::vcpu_handle::VcpuHandle;
+use hypervisor_device::{Device, DeviceContext, IrqLine, VmControl};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+pub use hypervisor_device as api;
+
+impl hypervisor_device::GuestMemory for GuestRam {
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        GuestRam::read(self, addr, buf).map(|_| ())
+    }
+
+    fn write(&self, addr: u64, buf: &[u8]) -> Result<(), Box<dyn Error>> {
+        GuestRam::write(self, addr, buf).map(|_| ())
+    }
+}
+
+/// デバイスが要求した VM の終了
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceExit {
+    /// 要求したデバイス (`Device::name`)
+    pub device: String,
+    /// 終了コード
+    pub code: u32,
+}
+
+/// デバイスからの終了の要求を run ループに渡す
+///
+/// 複製したハンドルは同じ要求を共有する。要求が重なった場合は最初のものを残す。
+#[derive(Debug, Clone, Default)]
+pub struct ExitRequests(Arc<Mutex<Option<DeviceExit>>>);
+
+impl ExitRequests {
+    /// 要求を取り出す
+    pub fn take(&self) -> Option<DeviceExit> {
+        self.0.lock().unwrap().take()
+    }
+
+    fn control(&self, device: &str, vcpu: &VcpuHandle) -> Arc<dyn VmControl> {
+        Arc::new(DeviceControl {
+            device: device.to_string(),
+            requests: self.clone(),
+            vcpu: vcpu.clone(),
+        })
+    }
+}
+
+struct DeviceControl {
+    device: String,
+    requests: ExitRequests,
+    /// 別のスレッドからの要求でも run ループがすぐに確認するよう kick する
+    vcpu: VcpuHandle,
+}
+
+impl VmControl for DeviceControl {
+    fn request_exit(&self, code: u32) {
+        self.requests
+            .0
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| DeviceExit {
+                device: self.device.clone(),
+                code,
+            });
+        if let Err(e) = self.vcpu.kick() {
+            eprintln!("[HOST] {}: failed to kick the vCPU: {}", self.device, e);
+        }
+    }
+}
+
+/// [`Device`] を [`MmioHandler`] として扱うアダプタ
+pub struct SdkDevice {
+    device: Box<dyn Device>,
+    base: u64,
+    irq: Option<IrqLine>,
+}
+
+impl SdkDevice {
+    /// `base` に配置し、`irq` の割り込み線・ゲスト RAM・終了の要求先を渡す
+    ///
+    /// 割り込みのアサートと終了の要求は `vcpu` を kick して run ループに伝える。
+    pub fn attach(
+        mut device: Box<dyn Device>,
+        base: u64,
+        irq: Option<u32>,
+        memory: Arc<GuestRam>,
+        exits: &ExitRequests,
+        vcpu: &VcpuHandle,
+    ) -> Self {
+        let irq = irq.map(|intid| {
+            let vcpu = vcpu.clone();
+            IrqLine::with_notify(intid, move || {
+                if let Err(e) = vcpu.kick() {
+                    eprintln!("[HOST] IRQ {}: failed to kick the vCPU: {}", intid, e);
+                }
+            })
+        });
+        let control = exits.control(device.name(), vcpu);
+        device.attach(DeviceContext::new(base, irq.clone(), memory, control));
+        Self { device, base, irq }
+    }
+}
+
+impl MmioHandler for SdkDevice {
+    fn base(&self) -> u64 {
+        self.base
+    }
+
+    fn size(&self) -> u64 {
+        self.device.size()
+    }
+
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, Box<dyn Error>> {
+        self.device.read(offset, size)
+    }
+
+    fn write(&mut self, offset: u64, value: u64, size: usize) -> Result<(), Box<dyn Error>> {
+        self.device.write(offset, value, size)
+    }
+
+    fn reset(&mut self) {
+        self.device.reset();
+        if let Some(irq) = &self.irq {
+            irq.lower();
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn irq(&self) -> Option<u32> {
+        self.irq.as_ref().map(IrqLine::intid)
+    }
+
+    fn pending_irq(&self) -> Option<u32> {
+        self.irq
+            .as_ref()
+            .filter(|irq| irq.is_asserted())
+            .map(IrqLine::intid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hypervisor_device::DtDescribe;
+
+    /// 書き込まれたアドレスから 4 バイトを DMA で読み、割り込みを上げるデバイス
+    #[derive(Default)]
+    struct DmaProbe {
+        ctx: Option<DeviceContext>,
+        last: u32,
+    }
+
+    impl DtDescribe for DmaProbe {}
+
+    impl Device for DmaProbe {
+        fn name(&self) -> &str {
+            "dma-probe"
+        }
+
+        fn size(&self) -> u64 {
+            0x100
+        }
+
+        fn read(&mut self, _offset: u64, _size: usize) -> Result<u64, Box<dyn Error>> {
+            Ok(self.last as u64)
+        }
+
+        fn write(&mut self, _offset: u64, value: u64, _size: usize) -> Result<(), Box<dyn Error>> {
+            let ctx = self.ctx.as_ref().ok_or("not attached")?;
+            let mut buf = [0; 4];
+            ctx.memory.read(value, &mut buf)?;
+            self.last = u32::from_le_bytes(buf);
+            if let Some(irq) = &ctx.irq {
+                irq.raise();
+            }
+            if self.last == 0 {
+                ctx.control.request_exit(1);
+            }
+            Ok(())
+        }
+
+        fn attach(&mut self, ctx: DeviceContext) {
+            self.ctx = Some(ctx);
+        }
+    }
+
+    #[test]
+    fn 外部のデバイスがゲスト_ram_を読み割り込みを上げる() {
+        let mut ram = GuestRam::new(0x4000).unwrap();
+        ram.map(&crate::backend::MockVm, 0x4000_0000).unwrap();
+        ram.write(0x4000_0100, &0xcafe_f00du32.to_le_bytes())
+            .unwrap();
+
+        let exits = ExitRequests::default();
+        let vcpu = VcpuHandle::new(None, None);
+        let mut dev = SdkDevice::attach(
+            Box::<DmaProbe>::default(),
+            0x0a10_0000,
+            Some(80),
+            Arc::new(ram),
+            &exits,
+            &vcpu,
+        );
+        assert_eq!(dev.irq(), Some(80));
+        assert_eq!(dev.pending_irq(), None);
+
+        dev.write(0, 0x4000_0100, 8).unwrap();
+        assert_eq!(dev.read(0, 4).unwrap(), 0xcafe_f00d);
+        assert_eq!(dev.pending_irq(), Some(80));
+        // 割り込みを上げたら run ループが確認するよう vCPU を kick する
+        assert_eq!(vcpu.kicks(), 1);
+        assert!(dev.write(0, 0x8000_0000, 8).is_err());
+        assert_eq!(exits.take(), None);
+
+        // 0 を読んだら終了を要求する (アサート済みの割り込みでは kick しない)
+        dev.write(0, 0x4000_0200, 8).unwrap();
+        dev.write(0, 0x4000_0200, 8).unwrap();
+        assert_eq!(vcpu.kicks(), 3);
+        assert_eq!(
+            exits.take(),
+            Some(DeviceExit {
+                device: "dma-probe".into(),
+                code: 1
+            })
+        );
+        assert_eq!(exits.take(), None);
+
+        dev.reset();
+        assert_eq!(dev.pending_irq(), None);
+        assert_eq!(
+            (dev.base(), dev.size(), dev.name()),
+            (0x0a10_0000, 0x100, "dma-probe")
+        );
+    }
+}
@@ -0,0 +1,294 @@
+//! 共有メモリデバイス (ivshmem 相当)
+//!
+//! ホストのファイルを共有マッピングしたメモリ領域 (`GuestRam::from_file`) を
+//! ゲストの物理アドレス空間に配置し、双方向のドアベル割り込みを提供する。
+//! virtio を介さずにフレームバッファやテストデータなどの大きなデータを
+//! ホストのプロセスとゲストでやり取りするために使用する。
+//!
+//! - ゲスト → ホスト: `DOORBELL` レジスタへの書き込みを [`ShmemHostHandle`] で待ち受ける
+//! - ホスト → ゲスト: [`ShmemHostHandle::ring_guest`] で `INTR_STATUS` をセットし IRQ を発生させる
+
+use crate::devices::gic::SharedGic;
+use crate::event_loop::Waker;
+use crate::mmio::MmioHandler;
+use std::error::Error;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// ドアベルレジスタのオフセット
+pub mod regs {
+    /// 割り込みマスク (R/W, 1 = 有効)
+    pub const INTR_MASK: u64 = 0x00;
+    /// 割り込みステータス (R, 書き込んだビットをクリア)
+    pub const INTR_STATUS: u64 = 0x04;
+    /// 共有メモリのゲスト物理アドレス 下位 32 bit (R)
+    pub const SHM_BASE_LO: u64 = 0x08;
+    /// 共有メモリのゲスト物理アドレス 上位 32 bit (R)
+    pub const SHM_BASE_HI: u64 = 0x0C;
+    /// 共有メモリのサイズ 下位 32 bit (R)
+    pub const SHM_SIZE_LO: u64 = 0x10;
+    /// 共有メモリのサイズ 上位 32 bit (R)
+    pub const SHM_SIZE_HI: u64 = 0x14;
+    /// ゲスト → ホストのドアベル (W)
+    pub const DOORBELL: u64 = 0x18;
+    /// ホストが最後に鳴らしたドアベルの値 (R)
+    pub const HOST_DOORBELL: u64 = 0x1C;
+}
+
+/// ドアベルレジスタの既定のベースアドレス (VirtIO MMIO の後ろ)
+pub const SHMEM_DOORBELL_BASE: u64 = 0x0b00_0000;
+/// ドアベルレジスタ領域のサイズ
+pub const SHMEM_REG_SIZE: u64 = 0x100;
+
+/// ゲストとホストで共有する状態
+#[derive(Debug, Default)]
+struct DoorbellState {
+    intr_mask: u32,
+    intr_status: u32,
+    /// ホストが最後に鳴らした値
+    host_value: u32,
+    /// ゲストが鳴らしてまだホストが受け取っていない値
+    guest_values: Vec<u32>,
+}
+
+/// 共有メモリデバイスのドアベルレジスタ
+pub struct SharedMemoryDevice {
+    base_addr: u64,
+    shm_base: u64,
+    shm_size: u64,
+    state: Arc<(Mutex<DoorbellState>, Condvar)>,
+    gic: SharedGic,
+    irq: u32,
+}
+
+/// ホスト側からドアベルを操作するハンドル
+#[derive(Clone)]
+pub struct ShmemHostHandle {
+    state: Arc<(Mutex<DoorbellState>, Condvar)>,
+    gic: SharedGic,
+    irq: u32,
+    /// アイドル待ち (WFI) 中の vCPU を起こす
+    waker: Waker,
+}
+
+impl SharedMemoryDevice {
+    /// 新しい共有メモリデバイスを作成
+    ///
+    /// # Arguments
+    /// * `base_addr` - ドアベルレジスタのベースアドレス
+    /// * `shm_base` - 共有メモリを配置したゲスト物理アドレス
+    /// * `shm_size` - 共有メモリのサイズ (bytes)
+    /// * `gic` - ゲストへの割り込みに使用する GIC
+    /// * `irq` - ホスト → ゲストのドアベルで発生させる IRQ 番号 (SPI)
+    pub fn new(base_addr: u64, shm_base: u64, shm_size: u64, gic: SharedGic, irq: u32) -> Self {
+        Self {
+            base_addr,
+            shm_base,
+            shm_size,
+            state: Arc::new((Mutex::new(DoorbellState::default()), Condvar::new())),
+            gic,
+            irq,
+        }
+    }
+
+    /// ホスト側のハンドルを取得
+    ///
+    /// `waker` には [`Hypervisor::waker`](crate::Hypervisor::waker) を渡す。
+    /// [`ShmemHostHandle::ring_guest`] はこれでアイドル待ち中の vCPU を起こす。
+    pub fn host_handle(&self, waker: Waker) -> ShmemHostHandle {
+        ShmemHostHandle {
+            state: Arc::clone(&self.state),
+            gic: Arc::clone(&self.gic),
+            irq: self.irq,
+            waker,
+        }
+    }
+}
+
+impl ShmemHostHandle {
+    /// ゲストのドアベルを鳴らす
+    ///
+    /// `INTR_STATUS` の bit 0 をセットし、マスクされていなければ IRQ を発生させて
+    /// WFI で待っている vCPU を起こす。
+    pub fn ring_guest(&self, value: u32) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.0.lock().unwrap();
+        state.host_value = value;
+        state.intr_status |= 1;
+        if state.intr_mask & 1 != 0 {
+            self.gic.lock().unwrap().set_irq_pending(self.irq);
+            drop(state);
+            self.waker.wake()?;
+        }
+        Ok(())
+    }
+
+    /// ゲストが鳴らしたドアベルを待つ
+    ///
+    /// # Returns
+    /// ゲストが `DOORBELL` に書き込んだ値。タイムアウトした場合は None
+    pub fn wait_guest(&self, timeout: Duration) -> Option<u32> {
+        let (lock, cvar) = &*self.state;
+        let state = lock.lock().unwrap();
+        let (mut state, _) = cvar
+            .wait_timeout_while(state, timeout, |s| s.guest_values.is_empty())
+            .unwrap();
+        if state.guest_values.is_empty() {
+            None
+        } else {
+            Some(state.guest_values.remove(0))
+        }
+    }
+
+    /// 待たずにゲストのドアベルを取り出す
+    pub fn try_recv_guest(&self) -> Option<u32> {
+        let mut state = self.state.0.lock().unwrap();
+        if state.guest_values.is_empty() {
+            None
+        } else {
+            Some(state.guest_values.remove(0))
+        }
+    }
+}
+
+impl MmioHandler for SharedMemoryDevice {
+    fn name(&self) -> &str {
+        "shmem"
+    }
+
+    fn irq(&self) -> Option<u32> {
+        Some(self.irq)
+    }
+
+    fn base(&self) -> u64 {
+        self.base_addr
+    }
+
+    fn size(&self) -> u64 {
+        SHMEM_REG_SIZE
+    }
+
+    fn read(&mut self, offset: u64, _size: usize) -> Result<u64, Box<dyn Error>> {
+        let state = self.state.0.lock().unwrap();
+        let value = match offset {
+            regs::INTR_MASK => state.intr_mask as u64,
+            regs::INTR_STATUS => state.intr_status as u64,
+            regs::SHM_BASE_LO => self.shm_base & 0xFFFF_FFFF,
+            regs::SHM_BASE_HI => self.shm_base >> 32,
+            regs::SHM_SIZE_LO => self.shm_size & 0xFFFF_FFFF,
+            regs::SHM_SIZE_HI => self.shm_size >> 32,
+            regs::HOST_DOORBELL => state.host_value as u64,
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    // ホストがまだ受け取っていないゲストのドアベルは残す
+    fn reset(&mut self) {
+        let mut state = self.state.0.lock().unwrap();
+        state.intr_mask = 0;
+        state.intr_status = 0;
+        state.host_value = 0;
+        self.gic.lock().unwrap().clear_irq_pending(self.irq);
+    }
+
+    fn write(&mut self, offset: u64, value: u64, _size: usize) -> Result<(), Box<dyn Error>> {
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        match offset {
+            regs::INTR_MASK => {
+                state.intr_mask = value as u32;
+                if state.intr_mask & state.intr_status != 0 {
+                    self.gic.lock().unwrap().set_irq_pending(self.irq);
+                }
+            }
+            regs::INTR_STATUS => {
+                state.intr_status &= !(value as u32);
+                if state.intr_status == 0 {
+                    self.gic.lock().unwrap().clear_irq_pending(self.irq);
+                }
+            }
+            regs::DOORBELL => {
+                state.guest_values.push(value as u32);
+                cvar.notify_all();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::gic::{create_shared_gic, GIC_DIST_BASE};
+    use crate::event_loop::{EventLoop, Wakeup};
+
+    const IRQ: u32 = 40;
+
+    fn irq_pending(gic: &SharedGic) -> bool {
+        // GICD_ISPENDR1 (IRQ 32-63) を読む
+        let pending = gic.lock().unwrap().read(0x200 + 4, 4).unwrap();
+        pending & (1 << (IRQ - 32)) != 0
+    }
+
+    fn new_device() -> (SharedMemoryDevice, SharedGic) {
+        let gic = create_shared_gic(GIC_DIST_BASE);
+        let device = SharedMemoryDevice::new(
+            SHMEM_DOORBELL_BASE,
+            0x5000_0000,
+            0x10_0000,
+            Arc::clone(&gic),
+            IRQ,
+        );
+        (device, gic)
+    }
+
+    #[test]
+    fn 共有メモリの配置をレジスタから読める() {
+        let (mut device, _) = new_device();
+        assert_eq!(device.read(regs::SHM_BASE_LO, 4).unwrap(), 0x5000_0000);
+        assert_eq!(device.read(regs::SHM_BASE_HI, 4).unwrap(), 0);
+        assert_eq!(device.read(regs::SHM_SIZE_LO, 4).unwrap(), 0x10_0000);
+    }
+
+    #[test]
+    fn ゲストのドアベルをホストで受け取れる() {
+        let (mut device, _) = new_device();
+        let handle = device.host_handle(EventLoop::new().unwrap().waker());
+        assert_eq!(handle.try_recv_guest(), None);
+
+        device.write(regs::DOORBELL, 7, 4).unwrap();
+        assert_eq!(handle.wait_guest(Duration::from_millis(10)), Some(7));
+        assert_eq!(handle.wait_guest(Duration::from_millis(1)), None);
+    }
+
+    #[test]
+    fn ホストのドアベルでゲストに割り込みが入る() {
+        let (mut device, gic) = new_device();
+        let events = EventLoop::new().unwrap();
+        let handle = device.host_handle(events.waker());
+        device.write(regs::INTR_MASK, 1, 4).unwrap();
+
+        handle.ring_guest(3).unwrap();
+        assert!(irq_pending(&gic));
+        // WFI で待っている vCPU も起こされる
+        assert_eq!(events.wait(Some(Duration::ZERO)).unwrap(), Wakeup::Woken);
+        assert_eq!(device.read(regs::INTR_STATUS, 4).unwrap(), 1);
+        assert_eq!(device.read(regs::HOST_DOORBELL, 4).unwrap(), 3);
+
+        // ステータスをクリアすると割り込みも取り下げられる
+        device.write(regs::INTR_STATUS, 1, 4).unwrap();
+        assert_eq!(device.read(regs::INTR_STATUS, 4).unwrap(), 0);
+        assert!(!irq_pending(&gic));
+    }
+
+    #[test]
+    fn マスクされていると割り込みは入らない() {
+        let (mut device, gic) = new_device();
+        let handle = device.host_handle(EventLoop::new().unwrap().waker());
+
+        handle.ring_guest(1).unwrap();
+        assert!(!irq_pending(&gic));
+        assert_eq!(device.read(regs::INTR_STATUS, 4).unwrap(), 1);
+    }
+}
@@ -0,0 +1,302 @@
+//! Arm SMMUv3 (IOMMU) のスタブ
+//!
+//! `CONFIG_ARM_SMMU_V3` を組み込んだカーネルや、`arm,smmu-v3` のノードを前提に
+//! した Device Tree を使い回す場合に、ドライバのプローブが失敗しないように
+//! するためのデバイス。
+//!
+//! アドレス変換は行わない。ID レジスタ (IDR0〜IDR5) に stage 1 だけを持つ
+//! 最小構成を返し、制御レジスタの書き込みは ACK レジスタにそのまま反映する。
+//! コマンドキューは書き込まれた瞬間にすべて処理したことにする (CMDQ_CONS は
+//! 常に CMDQ_PROD と同じ値を返す) ため、CMD_SYNC の完了待ちはすぐに終わる。
+//! イベントキュー・PRI キューにエントリを書くことはなく、割り込みも上げない。
+//!
+//! Device Tree には [`crate::boot::device_tree::DeviceTreeConfig::smmu_base`] で
+//! `arm,smmu-v3` のノードを追加する。どのデバイスのノードにも `iommus` を付けない
+//! ので、virtio などの DMA はこれまでどおりゲスト物理アドレスをそのまま使う。
+//! 将来デバイスの分離に使う vIOMMU にするときは、ストリームテーブルを読んで
+//! 変換するところから始める。
+
+use crate::mmio::MmioHandler;
+use std::error::Error;
+
+/// SMMUv3 スタブの既定のベースアドレス (QEMU virt と同じ)
+pub const SMMU_BASE: u64 = 0x0905_0000;
+/// レジスタ領域のサイズ (ページ 0 とページ 1、各 64 KiB)
+pub const SMMU_SIZE: u64 = 0x2_0000;
+
+/// 報告する StreamID のビット数 (線形のストリームテーブルは 256 エントリ)
+pub const SMMU_SID_BITS: u32 = 8;
+/// 報告するコマンドキューの大きさ (log2 のエントリ数)
+pub const SMMU_CMDQ_SHIFT: u32 = 8;
+/// 報告するイベントキューの大きさ (log2 のエントリ数)
+pub const SMMU_EVTQ_SHIFT: u32 = 7;
+
+/// レジスタオフセット
+pub mod regs {
+    /// ID レジスタ 0 (R)
+    pub const IDR0: u64 = 0x00;
+    /// ID レジスタ 1 (R)
+    pub const IDR1: u64 = 0x04;
+    /// ID レジスタ 5 (R)
+    pub const IDR5: u64 = 0x14;
+    /// 実装者 ID (R)
+    pub const IIDR: u64 = 0x18;
+    /// アーキテクチャのバージョン (R)
+    pub const AIDR: u64 = 0x1c;
+    /// 制御レジスタ 0 (RW)
+    pub const CR0: u64 = 0x20;
+    /// CR0 の反映 (R)
+    pub const CR0ACK: u64 = 0x24;
+    /// 制御レジスタ 1 (RW)
+    pub const CR1: u64 = 0x28;
+    /// 制御レジスタ 2 (RW)
+    pub const CR2: u64 = 0x2c;
+    /// SMMU 無効時のトランザクションの扱い (RW)
+    pub const GBPA: u64 = 0x44;
+    /// 割り込みの有効化 (RW)
+    pub const IRQ_CTRL: u64 = 0x50;
+    /// IRQ_CTRL の反映 (R)
+    pub const IRQ_CTRLACK: u64 = 0x54;
+    /// グローバルエラー (R)
+    pub const GERROR: u64 = 0x60;
+    /// グローバルエラーの確認 (RW)
+    pub const GERRORN: u64 = 0x64;
+    /// ストリームテーブルのベースアドレス (RW, 64 bit)
+    pub const STRTAB_BASE: u64 = 0x80;
+    /// ストリームテーブルの構成 (RW)
+    pub const STRTAB_BASE_CFG: u64 = 0x88;
+    /// コマンドキューのベースアドレス (RW, 64 bit)
+    pub const CMDQ_BASE: u64 = 0x90;
+    /// コマンドキューの書き込み位置 (RW)
+    pub const CMDQ_PROD: u64 = 0x98;
+    /// コマンドキューの読み取り位置 (R)
+    pub const CMDQ_CONS: u64 = 0x9c;
+    /// イベントキューのベースアドレス (RW, 64 bit)
+    pub const EVTQ_BASE: u64 = 0xa0;
+    /// イベントキューの書き込み位置 (RW、ページ 1)
+    pub const EVTQ_PROD: u64 = 0x1_00a8;
+    /// イベントキューの読み取り位置 (RW、ページ 1)
+    pub const EVTQ_CONS: u64 = 0x1_00ac;
+}
+
+/// IDR0: stage 1 の変換に対応
+const IDR0_S1P: u32 = 1 << 1;
+/// IDR0: 変換テーブルの形式は AArch64
+const IDR0_TTF_AARCH64: u32 = 0b10 << 2;
+/// IDR0: DMA はキャッシュコヒーレント (Device Tree の `dma-coherent` と一致させる)
+const IDR0_COHACC: u32 = 1 << 4;
+/// IDR0: 16 bit の ASID
+const IDR0_ASID16: u32 = 1 << 12;
+/// IDR0: stall に対応しない
+const IDR0_STALL_MODEL_NONE: u32 = 0b01 << 24;
+
+/// IDR1 の CMDQS フィールドの位置
+const IDR1_CMDQS_SHIFT: u32 = 21;
+/// IDR1 の EVTQS フィールドの位置
+const IDR1_EVTQS_SHIFT: u32 = 16;
+
+/// IDR5: 4 KiB・16 KiB・64 KiB の変換粒度
+const IDR5_GRAN_ALL: u32 = 0b111 << 4;
+/// IDR5: 出力アドレスは 48 bit
+const IDR5_OAS_48: u32 = 0b101;
+
+/// IIDR: 実装者 ARM (JEP106 0x43b)
+const IIDR_ARM: u32 = 0x43b;
+
+/// GBPA の更新要求ビット (書き込み後、反映を待つ間だけ 1 になる)
+const GBPA_UPDATE: u32 = 1 << 31;
+/// GBPA のリセット値 (SMMU 無効時のトランザクションを中止する)
+const GBPA_ABORT: u32 = 1 << 20;
+
+/// Arm SMMUv3 のスタブ
+pub struct SmmuV3Stub {
+    base_addr: u64,
+    cr0: u32,
+    cr1: u32,
+    cr2: u32,
+    gbpa: u32,
+    irq_ctrl: u32,
+    gerrorn: u32,
+    strtab_base: u64,
+    strtab_base_cfg: u32,
+    cmdq_base: u64,
+    cmdq_prod: u32,
+    evtq_base: u64,
+    evtq_prod: u32,
+    evtq_cons: u32,
+}
+
+impl SmmuV3Stub {
+    /// 指定したベースアドレスにスタブを作成
+    pub fn new(base_addr: u64) -> Self {
+        Self {
+            base_addr,
+            cr0: 0,
+            cr1: 0,
+            cr2: 0,
+            gbpa: GBPA_ABORT,
+            irq_ctrl: 0,
+            gerrorn: 0,
+            strtab_base: 0,
+            strtab_base_cfg: 0,
+            cmdq_base: 0,
+            cmdq_prod: 0,
+            evtq_base: 0,
+            evtq_prod: 0,
+            evtq_cons: 0,
+        }
+    }
+
+    /// 64 bit レジスタの下位または上位 32 bit への書き込みを反映する
+    fn write_u64(reg: &mut u64, half: u64, value: u64, size: usize) {
+        *reg = match (size, half) {
+            (8, _) => value,
+            (_, 0) => (*reg & !0xffff_ffff) | (value & 0xffff_ffff),
+            _ => (*reg & 0xffff_ffff) | (value << 32),
+        };
+    }
+
+    /// 64 bit レジスタを読む (上位 32 bit だけの読み取りにも対応)
+    fn read_u64(reg: u64, half: u64, size: usize) -> u64 {
+        match (size, half) {
+            (8, _) => reg,
+            (_, 0) => reg & 0xffff_ffff,
+            _ => reg >> 32,
+        }
+    }
+}
+
+impl MmioHandler for SmmuV3Stub {
+    fn name(&self) -> &str {
+        "smmu-v3"
+    }
+
+    fn base(&self) -> u64 {
+        self.base_addr
+    }
+
+    fn size(&self) -> u64 {
+        SMMU_SIZE
+    }
+
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, Box<dyn Error>> {
+        let value = match offset {
+            regs::IDR0 => {
+                (IDR0_S1P | IDR0_TTF_AARCH64 | IDR0_COHACC | IDR0_ASID16 | IDR0_STALL_MODEL_NONE)
+                    as u64
+            }
+            regs::IDR1 => {
+                (SMMU_CMDQ_SHIFT << IDR1_CMDQS_SHIFT
+                    | SMMU_EVTQ_SHIFT << IDR1_EVTQS_SHIFT
+                    | SMMU_SID_BITS) as u64
+            }
+            regs::IDR5 => (IDR5_GRAN_ALL | IDR5_OAS_48) as u64,
+            regs::IIDR => IIDR_ARM as u64,
+            // AIDR = 0 (SMMUv3.0)、IDR2〜IDR4・GERROR・STATUSR は 0
+            regs::CR0 | regs::CR0ACK => self.cr0 as u64,
+            regs::CR1 => self.cr1 as u64,
+            regs::CR2 => self.cr2 as u64,
+            regs::GBPA => self.gbpa as u64,
+            regs::IRQ_CTRL | regs::IRQ_CTRLACK => self.irq_ctrl as u64,
+            regs::GERRORN => self.gerrorn as u64,
+            0x80 | 0x84 => Self::read_u64(self.strtab_base, offset - regs::STRTAB_BASE, size),
+            regs::STRTAB_BASE_CFG => self.strtab_base_cfg as u64,
+            0x90 | 0x94 => Self::read_u64(self.cmdq_base, offset - regs::CMDQ_BASE, size),
+            // コマンドは書き込まれた時点で処理済み
+            regs::CMDQ_PROD | regs::CMDQ_CONS => self.cmdq_prod as u64,
+            0xa0 | 0xa4 => Self::read_u64(self.evtq_base, offset - regs::EVTQ_BASE, size),
+            regs::EVTQ_PROD => self.evtq_prod as u64,
+            regs::EVTQ_CONS => self.evtq_cons as u64,
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: u64, value: u64, size: usize) -> Result<(), Box<dyn Error>> {
+        let word = value as u32;
+        match offset {
+            regs::CR0 => self.cr0 = word,
+            regs::CR1 => self.cr1 = word,
+            regs::CR2 => self.cr2 = word,
+            regs::GBPA => self.gbpa = word & !GBPA_UPDATE,
+            regs::IRQ_CTRL => self.irq_ctrl = word,
+            regs::GERRORN => self.gerrorn = word,
+            0x80 | 0x84 => Self::write_u64(
+                &mut self.strtab_base,
+                offset - regs::STRTAB_BASE,
+                value,
+                size,
+            ),
+            regs::STRTAB_BASE_CFG => self.strtab_base_cfg = word,
+            0x90 | 0x94 => {
+                Self::write_u64(&mut self.cmdq_base, offset - regs::CMDQ_BASE, value, size)
+            }
+            regs::CMDQ_PROD => self.cmdq_prod = word,
+            0xa0 | 0xa4 => {
+                Self::write_u64(&mut self.evtq_base, offset - regs::EVTQ_BASE, value, size)
+            }
+            regs::EVTQ_PROD => self.evtq_prod = word,
+            regs::EVTQ_CONS => self.evtq_cons = word,
+            // 割り込みの設定 (MSI) などは無視する
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.base_addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stage1_だけの最小構成を報告する() {
+        let mut smmu = SmmuV3Stub::new(SMMU_BASE);
+        let idr0 = smmu.read(regs::IDR0, 4).unwrap();
+        assert_eq!(idr0 & 0x3, 0b10);
+        assert_eq!((idr0 >> 2) & 0x3, 0b10);
+        assert_ne!(idr0 & (1 << 4), 0);
+        let idr1 = smmu.read(regs::IDR1, 4).unwrap();
+        assert_eq!((idr1 >> 21) & 0x1f, 8);
+        assert_eq!(idr1 & 0x3f, 8);
+        assert_eq!(smmu.read(regs::IDR5, 4).unwrap() & 0x7, 0b101);
+        assert_eq!(smmu.read(regs::AIDR, 4).unwrap(), 0);
+    }
+
+    #[test]
+    fn 制御レジスタの書き込みをすぐに反映する() {
+        let mut smmu = SmmuV3Stub::new(SMMU_BASE);
+        assert_eq!(smmu.read(regs::GBPA, 4).unwrap(), 1 << 20);
+        smmu.write(regs::GBPA, (1 << 31) | (1 << 20), 4).unwrap();
+        assert_eq!(smmu.read(regs::GBPA, 4).unwrap(), 1 << 20);
+
+        smmu.write(regs::CR0, 0xd, 4).unwrap();
+        assert_eq!(smmu.read(regs::CR0ACK, 4).unwrap(), 0xd);
+        smmu.write(regs::IRQ_CTRL, 0x5, 4).unwrap();
+        assert_eq!(smmu.read(regs::IRQ_CTRLACK, 4).unwrap(), 0x5);
+
+        smmu.reset();
+        assert_eq!(smmu.read(regs::CR0ACK, 4).unwrap(), 0);
+    }
+
+    #[test]
+    fn コマンドキューは書き込んだ時点で処理済みになる() {
+        let mut smmu = SmmuV3Stub::new(SMMU_BASE);
+        smmu.write(regs::CMDQ_BASE, 0x4000_8000_0000_0008, 8)
+            .unwrap();
+        assert_eq!(smmu.read(regs::CMDQ_BASE + 4, 4).unwrap(), 0x4000_8000);
+        smmu.write(regs::CMDQ_PROD, 0x103, 4).unwrap();
+        assert_eq!(smmu.read(regs::CMDQ_CONS, 4).unwrap(), 0x103);
+
+        smmu.write(regs::STRTAB_BASE + 4, 0x1, 4).unwrap();
+        smmu.write(regs::STRTAB_BASE, 0x4010_0000, 4).unwrap();
+        assert_eq!(smmu.read(regs::STRTAB_BASE, 8).unwrap(), 0x1_4010_0000);
+        // イベントキューはページ 1
+        smmu.write(regs::EVTQ_CONS, 0x80, 4).unwrap();
+        assert_eq!(smmu.read(regs::EVTQ_CONS, 4).unwrap(), 0x80);
+        assert_eq!(smmu.read(regs::EVTQ_PROD, 4).unwrap(), 0);
+    }
+}
@@ -0,0 +1,253 @@
+//! デバイスモデルの適合性テスト
+//!
+//! デバイスが増えても `MmioHandler` の約束事がそろうように、どのデバイスにも
+//! 使える確認をまとめる。
+//!
+//! - [`check_device`] は領域の大きさ、`reset()` 後の読み取りが作成直後と一致すること、
+//!   状態の保存と復元 (`snapshot` feature) で読み取りが変わらないことを確認する
+//! - [`check_layout`] は同時に登録するデバイスの領域が重ならないことを確認する
+//!
+//! ```ignore
+//! let mut uart = Pl011Uart::with_console(UART_BASE, ConsoleSink::new(Box::new(io::sink()), FlushPolicy::Unbuffered));
+//! check_device(&mut uart)?;
+//! ```
+//!
+//! 作成直後のデバイスを渡すこと。すべてのレジスタに書き込むため、
+//! 出力先やディスクはテスト用のものにしておく。
+
+use crate::mmio::MmioHandler;
+use std::error::Error;
+
+/// 読み書きするレジスタ領域の上限 (bytes)
+///
+/// 共有メモリなど大きな領域を持つデバイスでも時間がかかりすぎないようにする。
+pub const MAX_PROBE_SIZE: u64 = 0x10_0000;
+
+/// 状態を変えるために全レジスタに書き込む値
+const PROBE_PATTERN: u64 = 0xffff_ffff;
+
+/// 領域内のすべてのワード (4 bytes) を読む
+///
+/// 読み取りがエラーになるオフセットは None とする。
+fn read_all(dev: &mut dyn MmioHandler) -> Vec<Option<u64>> {
+    probe_offsets(dev)
+        .map(|offset| dev.read(offset, 4).ok())
+        .collect()
+}
+
+fn probe_offsets(dev: &dyn MmioHandler) -> impl Iterator<Item = u64> {
+    (0..dev.size().min(MAX_PROBE_SIZE)).step_by(4)
+}
+
+/// 2 回の読み取り結果を比べ、最初に異なるオフセットをエラーにする
+fn compare(
+    dev: &dyn MmioHandler,
+    expected: &[Option<u64>],
+    actual: &[Option<u64>],
+    what: &str,
+) -> Result<(), Box<dyn Error>> {
+    let fmt = |value: Option<u64>| value.map_or("an error".to_string(), |v| format!("0x{:x}", v));
+    match expected.iter().zip(actual).position(|(e, a)| e != a) {
+        Some(i) => Err(format!(
+            "{}: read at +0x{:x} {} is {} but {} after construction",
+            dev.name(),
+            i * 4,
+            what,
+            fmt(actual[i]),
+            fmt(expected[i])
+        )
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// デバイスが `MmioHandler` の約束事を守っているか確認する
+///
+/// 1. 領域の大きさが 0 でなく、アドレス空間の終わりを越えない
+/// 2. すべてのレジスタに書き込んで状態を変えた後、状態を保存・復元しても
+///    読み取りの結果が変わらない (`snapshot` feature で状態を持つデバイスのみ)
+/// 3. `reset()` 後の読み取りが作成直後の読み取りと一致する
+///
+/// 読み取りに副作用があるデバイスでも、同じ状態から同じ順に読むため比較できる。
+///
+/// # Errors
+/// 最初に見つかった違反を返す
+pub fn check_device(dev: &mut dyn MmioHandler) -> Result<(), Box<dyn Error>> {
+    if dev.size() == 0 {
+        return Err(format!("{}: region size is zero", dev.name()).into());
+    }
+    if dev.base().checked_add(dev.size()).is_none() {
+        return Err(format!(
+            "{}: region 0x{:x}+0x{:x} wraps around the address space",
+            dev.name(),
+            dev.base(),
+            dev.size()
+        )
+        .into());
+    }
+
+    let initial = read_all(dev);
+
+    // 書き込みのエラー (範囲外のキュー通知など) は状態を変えるための副作用として無視する
+    for offset in probe_offsets(dev).collect::<Vec<_>>() {
+        let _ = dev.write(offset, PROBE_PATTERN, 4);
+    }
+
+    #[cfg(feature = "snapshot")]
+    check_state_round_trip(dev)?;
+
+    dev.reset();
+    let after_reset = read_all(dev);
+    compare(dev, &initial, &after_reset, "after reset()")
+}
+
+/// 保存した状態を復元すると、保存した時点と同じ読み取り結果になることを確認する
+#[cfg(feature = "snapshot")]
+fn check_state_round_trip(dev: &mut dyn MmioHandler) -> Result<(), Box<dyn Error>> {
+    let Some(state) = dev.as_device_state() else {
+        return Ok(());
+    };
+    let saved = state.save_state();
+    state.restore_state(&saved)?;
+    if state.save_state() != saved {
+        return Err(format!("{}: save_state() changed after restore_state()", dev.name()).into());
+    }
+
+    let before = read_all(dev);
+    let state = dev.as_device_state().expect("device state disappeared");
+    state.restore_state(&saved)?;
+    let after = read_all(dev);
+    compare(dev, &before, &after, "after restore_state()")
+}
+
+/// 同時に登録するデバイスの領域が重ならないことを確認する
+///
+/// # Errors
+/// 重なっている最初の 2 つのデバイスを返す
+pub fn check_layout(devices: &[&dyn MmioHandler]) -> Result<(), Box<dyn Error>> {
+    for (i, a) in devices.iter().enumerate() {
+        for b in &devices[i + 1..] {
+            if a.base() < b.base() + b.size() && b.base() < a.base() + a.size() {
+                return Err(format!(
+                    "{} at 0x{:x}+0x{:x} overlaps {} at 0x{:x}+0x{:x}",
+                    a.name(),
+                    a.base(),
+                    a.size(),
+                    b.name(),
+                    b.base(),
+                    b.size()
+                )
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::clock::{WallClockDevice, WALL_CLOCK_BASE};
+    use crate::devices::gic::{create_shared_gic, Gic, SharedGicWrapper, GIC_DIST_BASE};
+    use crate::devices::pl330::{Pl330Stub, PL330_BASE};
+    use crate::devices::scmi::{ScmiDevice, SCMI_SHMEM_BASE};
+    use crate::devices::shmem::{SharedMemoryDevice, SHMEM_DOORBELL_BASE};
+    use crate::devices::smmu::{SmmuV3Stub, SMMU_BASE};
+    use crate::devices::virtio::VirtioMmioSlot;
+    use std::sync::Arc;
+
+    /// すべてのレジスタに書き込んだ値を保持し、`reset()` を実装していないデバイス
+    struct ForgetfulDevice {
+        regs: [u64; 4],
+    }
+
+    impl MmioHandler for ForgetfulDevice {
+        fn base(&self) -> u64 {
+            0x1000
+        }
+
+        fn size(&self) -> u64 {
+            0x10
+        }
+
+        fn read(&mut self, offset: u64, _size: usize) -> Result<u64, Box<dyn Error>> {
+            Ok(self.regs[offset as usize / 4])
+        }
+
+        fn write(&mut self, offset: u64, value: u64, _size: usize) -> Result<(), Box<dyn Error>> {
+            self.regs[offset as usize / 4] = value;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reset_で戻らないデバイスを検出する() {
+        let err = check_device(&mut ForgetfulDevice { regs: [0; 4] }).unwrap_err();
+        assert!(err.to_string().contains("+0x0 after reset() is 0xffffffff"));
+    }
+
+    #[test]
+    fn 重なった領域を検出する() {
+        let dma = Pl330Stub::new(0x1000);
+        let overlapping = ForgetfulDevice { regs: [0; 4] };
+        let err = check_layout(&[&dma, &overlapping]).unwrap_err();
+        assert!(err.to_string().contains("overlaps"));
+        assert!(check_layout(&[&dma, &Pl330Stub::new(0x2000)]).is_ok());
+    }
+
+    #[test]
+    fn ツリー内のデバイスが適合性テストを通る() {
+        let gic = create_shared_gic(GIC_DIST_BASE);
+        let mut devices: Vec<Box<dyn MmioHandler>> = vec![
+            Box::new(SharedGicWrapper::new(Arc::clone(&gic), GIC_DIST_BASE)),
+            Box::new(WallClockDevice::new(WALL_CLOCK_BASE)),
+            Box::new(
+                ScmiDevice::new(SCMI_SHMEM_BASE)
+                    .with_clock("uart", 24_000_000)
+                    .with_power_domain("gpu"),
+            ),
+            Box::new(Pl330Stub::new(PL330_BASE)),
+            Box::new(SmmuV3Stub::new(SMMU_BASE)),
+            Box::new(SharedMemoryDevice::new(
+                SHMEM_DOORBELL_BASE,
+                0x5000_0000,
+                0x10_0000,
+                Arc::clone(&gic),
+                40,
+            )),
+            Box::new(VirtioMmioSlot::new(0, 0x0a00_0000, 34).0),
+        ];
+        #[cfg(feature = "uart")]
+        devices.push(Box::new(crate::devices::uart::Pl011Uart::with_console(
+            0x0900_0000,
+            crate::devices::console::ConsoleSink::new(
+                Box::new(std::io::sink()),
+                crate::devices::console::FlushPolicy::Unbuffered,
+            ),
+        )));
+        #[cfg(feature = "virtio-blk")]
+        {
+            let (slot, handle) = VirtioMmioSlot::new(1, 0x0a00_0200, 35);
+            handle
+                .bind(Box::new(crate::devices::virtio::VirtioBlockDevice::new(0)))
+                .unwrap();
+            devices.push(Box::new(slot));
+        }
+        let (slot, handle) = VirtioMmioSlot::new(2, 0x0a00_0400, 36);
+        handle
+            .bind(Box::new(crate::devices::virtio::VirtioNetDevice::new(
+                0,
+                [0x52, 0x54, 0, 0, 0, 1],
+            )))
+            .unwrap();
+        devices.push(Box::new(slot));
+
+        let refs: Vec<&dyn MmioHandler> = devices.iter().map(|d| d.as_ref()).collect();
+        check_layout(&refs).unwrap();
+        for device in &mut devices {
+            check_device(device.as_mut()).unwrap();
+        }
+        // GIC は単体でも確認する
+        check_device(&mut Gic::new()).unwrap();
+    }
+}
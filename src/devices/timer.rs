@@ -5,14 +5,27 @@
 //! - 仮想タイマー (EL1 Virtual Timer)
 //!
 //! Linux カーネルは起動時にタイマーを使用してスケジューリングを行います。
+//!
+//! ホストの時刻は [`HostClock`] から得る。モデルは `core` / `alloc` にある型を
+//! `std` 経由で使わない (lint で確認)。crate 自体は `std` でビルドし、`no_std` では
+//! ビルドしない。
+
+#![deny(clippy::std_instead_of_core, clippy::std_instead_of_alloc)]
 
-use std::error::Error;
-use std::time::Instant;
+use super::host_time::{HostClock, SystemClock};
+use alloc::sync::Arc;
+use core::error::Error;
+use core::time::Duration;
 
 /// タイマー周波数 (Hz)
 /// Apple Silicon のホスト CNTFRQ_EL0 の値と一致させる
 pub const TIMER_FREQ: u64 = 24_000_000; // 24 MHz (Apple Silicon)
 
+/// カウンタのティック数を時間に変換する
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos((ticks as u128 * 1_000_000_000 / TIMER_FREQ as u128) as u64)
+}
+
 /// 物理タイマー IRQ (PPI)
 pub const PHYS_TIMER_IRQ: u32 = 30;
 /// 仮想タイマー IRQ (PPI)
@@ -114,8 +127,8 @@ impl TimerState {
 /// ARM Generic Timer
 #[derive(Debug)]
 pub struct Timer {
-    /// 開始時刻 (カウンタ計算用)
-    start_time: Instant,
+    /// カウンタの時刻源 (作成時が 0)
+    clock: Arc<dyn HostClock>,
     /// 物理タイマー
     pub phys_timer: TimerState,
     /// 仮想タイマー
@@ -133,8 +146,13 @@ impl Default for Timer {
 impl Timer {
     /// 新しいタイマーを作成
     pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock::new()))
+    }
+
+    /// `clock` の時刻をカウンタにするタイマーを作成
+    pub fn with_clock(clock: Arc<dyn HostClock>) -> Self {
         Self {
-            start_time: Instant::now(),
+            clock,
             phys_timer: TimerState::new(),
             virt_timer: TimerState::new(),
             virt_offset: 0,
@@ -143,8 +161,7 @@ impl Timer {
 
     /// 物理カウンタ値を取得 (CNTPCT_EL0)
     pub fn get_phys_counter(&self) -> u64 {
-        let elapsed = self.start_time.elapsed();
-        let nanos = elapsed.as_nanos() as u64;
+        let nanos = self.clock.now().as_nanos() as u64;
         // カウンタ = 経過時間 * 周波数 / 10^9
         nanos * TIMER_FREQ / 1_000_000_000
     }
@@ -179,6 +196,17 @@ impl Timer {
         self.virt_timer.should_interrupt(self.get_virt_counter())
     }
 
+    /// 仮想タイマーが発火条件を満たしてからの経過時間 (発火していなければ None)
+    ///
+    /// run ループはポーリングで発火を検出するため、GIC にペンディングを立てる
+    /// 時刻は CVAL の時刻より遅れる。割り込みレイテンシはこの遅れを含めて計測する。
+    pub fn virt_timer_overdue(&self) -> Option<Duration> {
+        let counter = self.get_virt_counter();
+        self.virt_timer
+            .should_interrupt(counter)
+            .then(|| ticks_to_duration(counter - self.virt_timer.read_cval()))
+    }
+
     /// 仮想タイマーがアサートされているか（IMASK を無視）
     ///
     /// GIC 経由で IRQ を注入する場合、ハードウェア FIQ 防止のために IMASK=1 を強制している。
@@ -333,13 +361,48 @@ impl TimerReg {
             _ => None,
         }
     }
+
+    /// AArch32 の MCR/MRC (CP15) エンコーディングから TimerReg を取得
+    ///
+    /// 32-bit アクセスのみ。カウンタと比較値は MCRR/MRRC でアクセスするため
+    /// [`TimerReg::from_cp15_64`] を使用する。
+    pub fn from_cp15(opc1: u8, crn: u8, crm: u8, opc2: u8) -> Option<Self> {
+        if crn != 14 || opc1 != 0 {
+            return None;
+        }
+
+        match (crm, opc2) {
+            (0, 0) => Some(TimerReg::CNTFRQ_EL0),
+            (2, 0) => Some(TimerReg::CNTP_TVAL_EL0),
+            (2, 1) => Some(TimerReg::CNTP_CTL_EL0),
+            (3, 0) => Some(TimerReg::CNTV_TVAL_EL0),
+            (3, 1) => Some(TimerReg::CNTV_CTL_EL0),
+            _ => None,
+        }
+    }
+
+    /// AArch32 の MCRR/MRRC (CP15) エンコーディングから TimerReg を取得
+    pub fn from_cp15_64(opc1: u8, crm: u8) -> Option<Self> {
+        if crm != 14 {
+            return None;
+        }
+
+        match opc1 {
+            0 => Some(TimerReg::CNTPCT_EL0),
+            1 => Some(TimerReg::CNTVCT_EL0),
+            2 => Some(TimerReg::CNTP_CVAL_EL0),
+            3 => Some(TimerReg::CNTV_CVAL_EL0),
+            4 => Some(TimerReg::CNTVOFF_EL2),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::devices::host_time::ManualClock;
     use std::thread;
-    use std::time::Duration;
 
     #[test]
     fn timer_new_の初期状態を確認() {
@@ -364,6 +427,23 @@ mod tests {
         assert!(c2 > c1);
     }
 
+    #[test]
+    fn 注入した時計の時刻がカウンタになる() {
+        let clock = ManualClock::new();
+        let mut timer = Timer::with_clock(Arc::new(clock.clone()));
+        assert_eq!(timer.get_phys_counter(), 0);
+
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(timer.get_phys_counter(), TIMER_FREQ / 1000);
+        timer.set_virt_offset(4000);
+        assert_eq!(timer.get_virt_counter(), TIMER_FREQ / 1000 - 4000);
+
+        // CVAL の 0.5ms 後
+        timer.virt_timer.write_ctl(ctl_bits::ENABLE);
+        timer.virt_timer.write_cval(TIMER_FREQ / 2000 - 4000);
+        assert_eq!(timer.virt_timer_overdue(), Some(Duration::from_micros(500)));
+    }
+
     #[test]
     fn get_virt_counter_はオフセットを反映する() {
         let mut timer = Timer::new();
@@ -437,6 +517,24 @@ mod tests {
         assert_ne!(ctl & ctl_bits::ISTATUS, 0);
     }
 
+    #[test]
+    fn virt_timer_overdue_は_cval_からの遅れを返す() {
+        let mut timer = Timer::new();
+        // 仮想カウンタを 1 秒分進めておく
+        timer.set_virt_offset(0u64.wrapping_sub(TIMER_FREQ));
+        assert_eq!(timer.virt_timer_overdue(), None);
+
+        let counter = timer.get_virt_counter();
+        timer.virt_timer.write_ctl(ctl_bits::ENABLE);
+        timer.virt_timer.write_cval(counter + TIMER_FREQ);
+        assert_eq!(timer.virt_timer_overdue(), None);
+
+        // 24_000 ティック (1ms) 以上前に発火している
+        timer.virt_timer.write_cval(counter - 24_000);
+        assert!(timer.virt_timer_overdue().unwrap() >= Duration::from_millis(1));
+        assert_eq!(ticks_to_duration(TIMER_FREQ), Duration::from_secs(1));
+    }
+
     #[test]
     fn phys_timer_pending_は正しく判定する() {
         let mut timer = Timer::new();
@@ -580,4 +678,25 @@ mod tests {
         let reg = TimerReg::from_encoding(3, 3, 14, 5, 0);
         assert_eq!(reg, None);
     }
+
+    #[test]
+    fn from_cp15_で_aarch32_の仮想タイマー制御を識別する() {
+        // MRC p15, 0, Rt, c14, c3, 1 (CNTV_CTL)
+        assert_eq!(
+            TimerReg::from_cp15(0, 14, 3, 1),
+            Some(TimerReg::CNTV_CTL_EL0)
+        );
+        // MRC p15, 0, Rt, c14, c0, 0 (CNTFRQ)
+        assert_eq!(TimerReg::from_cp15(0, 14, 0, 0), Some(TimerReg::CNTFRQ_EL0));
+        assert_eq!(TimerReg::from_cp15(0, 1, 0, 0), None);
+    }
+
+    #[test]
+    fn from_cp15_64_で_aarch32_のカウンタと比較値を識別する() {
+        // MRRC p15, 1, Rt, Rt2, c14 (CNTVCT)
+        assert_eq!(TimerReg::from_cp15_64(1, 14), Some(TimerReg::CNTVCT_EL0));
+        // MCRR p15, 3, Rt, Rt2, c14 (CNTV_CVAL)
+        assert_eq!(TimerReg::from_cp15_64(3, 14), Some(TimerReg::CNTV_CVAL_EL0));
+        assert_eq!(TimerReg::from_cp15_64(1, 2), None);
+    }
 }
@@ -2,10 +2,19 @@
 //!
 //! ARM PL011 UART コントローラーのエミュレーション。
 //! Linux カーネルの earlycon および標準 UART ドライバに対応。
+//!
+//! ホストとの入出力は [`ConsoleSink`] と [`ConsoleInput`] として外から渡す。
+//! レジスタの処理は `core` / `alloc` にある型を `std` 経由で使わない (lint で確認) が、
+//! `MmioHandler` などの `std` に依存する型を実装しており、`no_std` ではビルドしない。
+
+#![deny(clippy::std_instead_of_core, clippy::std_instead_of_alloc)]
 
-use crate::mmio::MmioHandler;
-use std::error::Error;
-use std::io::{self, Write};
+use crate::devices::console::{ConsoleInput, ConsoleSink, FlushPolicy};
+use crate::devices::fault::FaultInjector;
+#[cfg(feature = "snapshot")]
+use crate::migration::{DeviceState, StateDecoder, StateEncoder};
+use crate::mmio::{MmioHandler, UnsupportedFeature};
+use core::error::Error;
 
 /// PL011 UART register offsets
 mod regs {
@@ -51,6 +60,9 @@ mod regs {
     pub const CELLID3: u64 = 0xFFC;
 }
 
+/// Default GIC interrupt ID of the UART (SPI 1, see `IrqMap::QEMU_VIRT`)
+pub const UART_IRQ: u32 = 33;
+
 /// Flag Register bits
 #[allow(dead_code)]
 mod fr_bits {
@@ -118,6 +130,24 @@ mod lcr_h_bits {
     pub const SPS: u64 = 1 << 7;
 }
 
+/// Receive error bits (DR bits [11:8] and RSR bits [3:0])
+mod rx_err_bits {
+    /// Overrun Error in RSR
+    pub const RSR_OE: u64 = 1 << 3;
+    /// Overrun Error in DR (RSR bit shifted by 8)
+    pub const DR_OE: u64 = RSR_OE << 8;
+}
+
+/// DMA Control Register bits
+mod dmacr_bits {
+    /// Receive DMA Enable
+    pub const RXDMAE: u64 = 1 << 0;
+    /// Transmit DMA Enable
+    pub const TXDMAE: u64 = 1 << 1;
+    /// DMA On Error
+    pub const DMAONERR: u64 = 1 << 2;
+}
+
 /// Interrupt bits (for IMSC, RIS, MIS, ICR)
 #[allow(dead_code)]
 mod int_bits {
@@ -148,8 +178,9 @@ mod int_bits {
 /// PL011 UART device emulator
 ///
 /// ARM PL011 UART コントローラーをエミュレート。
-/// - UART_DR (0x00) への書き込みは stdout に出力
+/// - UART_DR (0x00) への書き込みは stdout に出力 (書き出し方針は [`FlushPolicy`])
 /// - UART_FR (0x18) の読み取りは TXFE (TX FIFO empty) を返す
+/// - UART_DR (0x00) の読み取りは [`ConsoleInput`] に注入された入力を返す
 /// - 各種制御レジスタをサポート
 pub struct Pl011Uart {
     base_addr: u64,
@@ -167,13 +198,27 @@ pub struct Pl011Uart {
     imsc: u64,
     /// Raw Interrupt Status
     ris: u64,
-    /// DMA Control Register
+    /// DMA Control Register (stored only; DMA is not performed)
     dmacr: u64,
+    /// DMA enable not yet reported (see `MmioHandler::take_unsupported`)
+    unsupported: Option<UnsupportedFeature>,
     /// Receive Status / Error Clear
     rsr: u64,
+    /// Console output
+    console: ConsoleSink,
+    /// Receive FIFO (input injected by the host)
+    input: ConsoleInput,
+    /// Injected receive errors (see `set_fault_injector`)
+    faults: Option<FaultInjector>,
+    /// GIC interrupt ID
+    irq: u32,
 }
 
 impl Pl011Uart {
+    /// Registers whose writes are safe for the MMIO fast path
+    /// (see `Hypervisor::register_fast_mmio_handler`)
+    pub const FAST_WRITE_OFFSETS: &'static [u64] = &[regs::DR];
+
     /// Create a new PL011 UART device
     ///
     /// # Arguments
@@ -191,10 +236,73 @@ impl Pl011Uart {
             imsc: 0,
             ris: int_bits::TXIM, // TX interrupt always asserted (FIFO empty)
             dmacr: 0,
+            unsupported: None,
             rsr: 0,
+            console: ConsoleSink::stdout(FlushPolicy::Unbuffered),
+            input: ConsoleInput::new(),
+            faults: None,
+            irq: UART_IRQ,
+        }
+    }
+
+    /// Create a PL011 UART with the given output sink and input queue
+    ///
+    /// # Arguments
+    /// * `base_addr` - Base address of the UART device
+    /// * `console` - Output sink (e.g. `Console::sink()`)
+    /// * `input` - Receive queue (e.g. `Console::input()`)
+    pub fn with_io(base_addr: u64, console: ConsoleSink, input: ConsoleInput) -> Self {
+        Self {
+            console,
+            input,
+            ..Self::new(base_addr)
+        }
+    }
+
+    /// Handle for injecting input into the receive FIFO
+    pub fn input(&self) -> ConsoleInput {
+        self.input.clone()
+    }
+
+    /// Create a PL011 UART writing to the given console
+    ///
+    /// # Arguments
+    /// * `base_addr` - Base address of the UART device
+    /// * `console` - Output sink (e.g. `ConsoleSink::stdout(FlushPolicy::Line)`)
+    pub fn with_console(base_addr: u64, console: ConsoleSink) -> Self {
+        Self {
+            console,
+            ..Self::new(base_addr)
         }
     }
 
+    /// Change the GIC interrupt ID (must match `IrqMap::uart` in the device tree)
+    pub fn set_irq(&mut self, irq: u32) {
+        self.irq = irq;
+    }
+
+    /// Attach a fault injector
+    ///
+    /// `FaultInjector::uart_overrun` marks received characters with an
+    /// Overrun Error, as if the guest had not drained the FIFO in time.
+    pub fn set_fault_injector(&mut self, faults: FaultInjector) {
+        self.faults = Some(faults);
+    }
+
+    /// Change how console output is flushed
+    ///
+    /// The default is [`FlushPolicy::Unbuffered`] (one write per character).
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) -> Result<(), Box<dyn Error>> {
+        self.console.set_policy(policy)?;
+        Ok(())
+    }
+
+    /// Flush buffered console output
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.console.flush()?;
+        Ok(())
+    }
+
     /// Check if UART is enabled
     #[allow(dead_code)]
     fn is_enabled(&self) -> bool {
@@ -214,8 +322,10 @@ impl Pl011Uart {
         // TX FIFO is always empty (we flush immediately)
         flags |= fr_bits::TXFE;
 
-        // RX FIFO is always empty (no input support yet)
-        flags |= fr_bits::RXFE;
+        // RX FIFO is empty unless input has been injected
+        if self.input.is_empty() {
+            flags |= fr_bits::RXFE;
+        }
 
         // CTS is always asserted (ready to send)
         flags |= fr_bits::CTS;
@@ -229,13 +339,67 @@ impl Pl011Uart {
         flags
     }
 
+    /// Get Raw Interrupt Status (RX is asserted while input is pending)
+    fn get_ris(&self) -> u64 {
+        if self.input.is_empty() {
+            self.ris
+        } else {
+            self.ris | int_bits::RXIM
+        }
+    }
+
     /// Get Masked Interrupt Status
     fn get_mis(&self) -> u64 {
-        self.ris & self.imsc
+        self.get_ris() & self.imsc
+    }
+}
+
+#[cfg(feature = "snapshot")]
+impl DeviceState for Pl011Uart {
+    // The console and pending RX input belong to the host side and are not migrated
+    fn save_state(&self) -> Vec<u8> {
+        [
+            self.ibrd, self.fbrd, self.lcr_h, self.cr, self.ifls, self.imsc, self.ris, self.dmacr,
+            self.rsr,
+        ]
+        .iter()
+        .fold(StateEncoder::new(), |enc, &r| enc.u64(r))
+        .finish()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut dec = StateDecoder::new(state);
+        for reg in [
+            &mut self.ibrd,
+            &mut self.fbrd,
+            &mut self.lcr_h,
+            &mut self.cr,
+            &mut self.ifls,
+            &mut self.imsc,
+            &mut self.ris,
+            &mut self.dmacr,
+            &mut self.rsr,
+        ] {
+            *reg = dec.u64()?;
+        }
+        dec.finish()
     }
 }
 
 impl MmioHandler for Pl011Uart {
+    fn name(&self) -> &str {
+        "pl011"
+    }
+
+    #[cfg(feature = "snapshot")]
+    fn as_device_state(&mut self) -> Option<&mut dyn DeviceState> {
+        Some(self)
+    }
+
+    fn irq(&self) -> Option<u32> {
+        Some(self.irq)
+    }
+
     fn base(&self) -> u64 {
         self.base_addr
     }
@@ -244,11 +408,48 @@ impl MmioHandler for Pl011Uart {
         0x1000 // 4KB memory-mapped region
     }
 
+    fn take_unsupported(&mut self) -> Option<UnsupportedFeature> {
+        self.unsupported.take()
+    }
+
+    fn pending_irq(&self) -> Option<u32> {
+        // Only RX and overrun are routed; the TX interrupt is always raw-asserted
+        // because output is written immediately, so routing it would storm the guest
+        if self.get_mis() & (int_bits::RXIM | int_bits::RTIM | int_bits::OEIM) != 0 {
+            Some(self.irq)
+        } else {
+            None
+        }
+    }
+
+    // The console and pending RX input belong to the host side and are kept
+    fn reset(&mut self) {
+        let fresh = Self::new(self.base_addr);
+        self.ibrd = fresh.ibrd;
+        self.fbrd = fresh.fbrd;
+        self.lcr_h = fresh.lcr_h;
+        self.cr = fresh.cr;
+        self.ifls = fresh.ifls;
+        self.imsc = fresh.imsc;
+        self.ris = fresh.ris;
+        self.dmacr = fresh.dmacr;
+        self.rsr = fresh.rsr;
+        self.unsupported = fresh.unsupported;
+    }
+
     fn read(&mut self, offset: u64, _size: usize) -> Result<u64, Box<dyn Error>> {
         let value = match offset {
             regs::DR => {
-                // No receive data available, return 0
-                0
+                // Pop one received character (0 if the FIFO is empty)
+                match self.input.pop() {
+                    Some(ch) if self.faults.as_ref().is_some_and(|f| f.take_uart_overrun()) => {
+                        self.rsr |= rx_err_bits::RSR_OE;
+                        self.ris |= int_bits::OEIM;
+                        u64::from(ch) | rx_err_bits::DR_OE
+                    }
+                    Some(ch) => u64::from(ch),
+                    None => 0,
+                }
             }
             regs::RSR_ECR => self.rsr,
             regs::FR => self.get_flags(),
@@ -259,7 +460,7 @@ impl MmioHandler for Pl011Uart {
             regs::CR => self.cr,
             regs::IFLS => self.ifls,
             regs::IMSC => self.imsc,
-            regs::RIS => self.ris,
+            regs::RIS => self.get_ris(),
             regs::MIS => self.get_mis(),
             regs::ICR => 0, // Write-only register
             regs::DMACR => self.dmacr,
@@ -288,8 +489,7 @@ impl MmioHandler for Pl011Uart {
                 // Only output if UART and TX are enabled
                 // (但し earlycon 対応のため、無効でも出力する)
                 let ch = (value & 0xFF) as u8;
-                print!("{}", ch as char);
-                io::stdout().flush()?;
+                self.console.write_byte(ch)?;
             }
             regs::RSR_ECR => {
                 // Writing any value clears the error flags
@@ -332,7 +532,20 @@ impl MmioHandler for Pl011Uart {
                 self.ris |= int_bits::TXIM;
             }
             regs::DMACR => {
-                self.dmacr = value & 0x7;
+                // There is no DMA request line to a controller, so data the driver
+                // hands to DMA would silently vanish: report each enable instead
+                let enable = dmacr_bits::RXDMAE | dmacr_bits::TXDMAE;
+                if value & enable != 0 && self.dmacr & enable == 0 {
+                    self.unsupported = Some(UnsupportedFeature {
+                        feature: "PL011 DMA",
+                        offset,
+                        value,
+                        impact: "DMA transfers never complete, so console output and input \
+                                 stop until the driver falls back to PIO \
+                                 (remove `dmas` from the UART's Device Tree node)",
+                    });
+                }
+                self.dmacr = value & (enable | dmacr_bits::DMAONERR);
             }
             _ => {
                 // Ignore writes to unknown registers
@@ -384,6 +597,55 @@ mod tests {
         uart.write(0x100, 0x42, 4).unwrap();
     }
 
+    #[test]
+    fn test_uart_dma_enable_is_reported_as_unsupported() {
+        let mut manager = crate::mmio::MmioManager::new();
+        manager.register(Box::new(Pl011Uart::new(0x09000000)));
+
+        // DMAONERR alone does not start DMA
+        manager
+            .handle_write(0x09000000 + regs::DMACR, dmacr_bits::DMAONERR, 4)
+            .unwrap();
+        assert!(manager.unsupported_features().is_empty());
+
+        // Reported once per enable, not on every write
+        let tx = dmacr_bits::TXDMAE;
+        manager
+            .handle_write(0x09000000 + regs::DMACR, tx, 4)
+            .unwrap();
+        let both = tx | dmacr_bits::RXDMAE;
+        manager
+            .handle_write(0x09000000 + regs::DMACR, both, 4)
+            .unwrap();
+        assert_eq!(manager.unsupported_features().len(), 1);
+        let (device, feature) = &manager.unsupported_features()[0];
+        assert_eq!(device, "pl011");
+        assert_eq!(feature.feature, "PL011 DMA");
+        assert_eq!(feature.value, tx);
+        assert!(feature.to_string().contains("not emulated"));
+        assert_eq!(
+            manager.handle_read(0x09000000 + regs::DMACR, 4).unwrap(),
+            both
+        );
+
+        manager
+            .handle_write(0x09000000 + regs::DMACR, 0, 4)
+            .unwrap();
+        manager
+            .handle_write(0x09000000 + regs::DMACR, tx, 4)
+            .unwrap();
+        assert_eq!(manager.unsupported_features().len(), 2);
+    }
+
+    #[test]
+    fn test_uart_reset_drops_unreported_dma_enable() {
+        let mut uart = Pl011Uart::new(0x09000000);
+        uart.write(regs::DMACR, dmacr_bits::TXDMAE, 4).unwrap();
+        uart.reset();
+        assert_eq!(uart.read(regs::DMACR, 4).unwrap(), 0);
+        assert!(uart.take_unsupported().is_none());
+    }
+
     #[test]
     fn test_uart_cr_read_write() {
         let mut uart = Pl011Uart::new(0x09000000);
@@ -489,4 +751,49 @@ mod tests {
         uart.write(regs::RSR_ECR, 0xFF, 4).unwrap();
         assert_eq!(uart.read(regs::RSR_ECR, 4).unwrap(), 0);
     }
+
+    #[test]
+    fn test_uart_rx_injected_input() {
+        let mut uart = Pl011Uart::new(0x09000000);
+        let input = uart.input();
+        assert_eq!(uart.pending_irq(), None);
+
+        input.push(b"ok");
+        assert_eq!(uart.read(regs::FR, 4).unwrap() & fr_bits::RXFE, 0);
+        assert_ne!(uart.read(regs::RIS, 4).unwrap() & int_bits::RXIM, 0);
+
+        // RX interrupt is raised only when unmasked
+        assert_eq!(uart.pending_irq(), None);
+        uart.write(regs::IMSC, int_bits::RXIM, 4).unwrap();
+        assert_eq!(uart.pending_irq(), Some(UART_IRQ));
+
+        assert_eq!(uart.read(regs::DR, 4).unwrap(), b'o' as u64);
+        assert_eq!(uart.read(regs::DR, 4).unwrap(), b'k' as u64);
+        assert_ne!(uart.read(regs::FR, 4).unwrap() & fr_bits::RXFE, 0);
+        assert_eq!(uart.pending_irq(), None);
+    }
+
+    #[test]
+    fn test_uart_injected_overrun() {
+        let mut uart = Pl011Uart::new(0x09000000);
+        let faults = FaultInjector::new();
+        uart.set_fault_injector(faults.clone());
+        uart.write(regs::IMSC, int_bits::OEIM, 4).unwrap();
+
+        faults.uart_overrun(1);
+        uart.input().push(b"ab");
+        assert_eq!(
+            uart.read(regs::DR, 4).unwrap(),
+            b'a' as u64 | rx_err_bits::DR_OE
+        );
+        assert_eq!(uart.read(regs::RSR_ECR, 4).unwrap(), rx_err_bits::RSR_OE);
+        assert_eq!(uart.pending_irq(), Some(UART_IRQ));
+        assert_eq!(uart.read(regs::DR, 4).unwrap(), b'b' as u64);
+
+        // Clearing the error and the interrupt
+        uart.write(regs::RSR_ECR, 0, 4).unwrap();
+        uart.write(regs::ICR, int_bits::OEIM, 4).unwrap();
+        assert_eq!(uart.read(regs::RSR_ECR, 4).unwrap(), 0);
+        assert_eq!(uart.pending_irq(), None);
+    }
 }
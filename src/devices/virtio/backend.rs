@@ -0,0 +1,219 @@
+//! virtio-blk のディスクのバックエンド
+//!
+//! [`VirtioBlockDevice`](super::VirtioBlockDevice) はセクタの読み書きを
+//! [`BlockBackend`] に任せる。ディスクイメージのファイル ([`File`]) と、
+//! テスト用のメモリ上のディスク ([`RamDisk`]) を用意している。
+//!
+//! ```ignore
+//! let disk = RamDisk::from_bytes(image);
+//! let device = VirtioBlockDevice::with_backend(base, Box::new(disk.clone()), capacity);
+//! // ... ゲストを実行 ...
+//! assert_eq!(&disk.contents()[..4], b"FAT ");
+//! ```
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// ディスクの内容を保持するバックエンド
+pub trait BlockBackend: Send + Sync {
+    /// `offset` から `buf` を埋める
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// `offset` に `data` を書き込む
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// 書き込みを永続化する
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// 現在の内容を `path` に書き出す (既にあれば置き換える)
+    fn snapshot_to(&mut self, path: &Path) -> io::Result<()>;
+
+    /// サイズを `size` バイトに変更する (増えた領域はゼロで埋まる)
+    ///
+    /// サイズを変えられないバックエンドは `Unsupported` を返す。
+    fn resize(&mut self, size: u64) -> io::Result<()> {
+        let _ = size;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this disk backend cannot be resized",
+        ))
+    }
+}
+
+impl BlockBackend for File {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(data)?;
+        Write::flush(self)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    /// APFS では `fclonefileat` でクローン (コピーオンライト) し、使えない
+    /// ファイルシステムでは通常のコピーにフォールバックする
+    fn snapshot_to(&mut self, path: &Path) -> io::Result<()> {
+        self.sync_data()?;
+        remove_if_exists(path)?;
+        if clone_file(self, path).is_ok() {
+            return Ok(());
+        }
+        let mut src = self.try_clone()?;
+        src.seek(SeekFrom::Start(0))?;
+        let mut dst = File::create(path)?;
+        io::copy(&mut src, &mut dst)?;
+        dst.sync_all()
+    }
+
+    fn resize(&mut self, size: u64) -> io::Result<()> {
+        self.set_len(size)?;
+        self.sync_all()
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// `src` を `dst` にクローンする (APFS のみ)
+#[cfg(target_os = "macos")]
+fn clone_file(src: &File, dst: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+
+    let dst = std::ffi::CString::new(dst.as_os_str().as_bytes())?;
+    // SAFETY: dst は NUL 終端された有効なパス
+    let ret = unsafe { libc::fclonefileat(src.as_raw_fd(), libc::AT_FDCWD, dst.as_ptr(), 0) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "macos"))]
+fn clone_file(_src: &File, _dst: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// メモリ上のディスク
+///
+/// clone したハンドルは同じ内容を共有するため、デバイスに渡した後も
+/// [`RamDisk::contents`] でゲストが書いた内容を確認できる。サイズは `resize` で
+/// 変えない限り固定で、末尾を超える読み書きはエラーになる。
+#[derive(Debug, Clone, Default)]
+pub struct RamDisk {
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl RamDisk {
+    /// ゼロで埋めた `size` バイトのディスクを作成
+    pub fn new(size: usize) -> Self {
+        Self::from_bytes(vec![0; size])
+    }
+
+    /// `data` を初期内容とするディスクを作成
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        Self {
+            data: Arc::new(Mutex::new(data)),
+        }
+    }
+
+    /// サイズ (bytes)
+    pub fn len(&self) -> usize {
+        self.data.lock().unwrap().len()
+    }
+
+    /// サイズが 0 か
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 現在の内容のコピー
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+
+    fn range(len: usize, offset: u64, count: usize) -> io::Result<std::ops::Range<usize>> {
+        let start = offset as usize;
+        match start.checked_add(count) {
+            Some(end) if end <= len => Ok(start..end),
+            _ => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "RAM disk access at 0x{:x}+0x{:x} is beyond its size 0x{:x}",
+                    offset, count, len
+                ),
+            )),
+        }
+    }
+}
+
+impl BlockBackend for RamDisk {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let data = self.data.lock().unwrap();
+        buf.copy_from_slice(&data[Self::range(data.len(), offset, buf.len())?]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let range = Self::range(data.len(), offset, buf.len())?;
+        data[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn snapshot_to(&mut self, path: &Path) -> io::Result<()> {
+        remove_if_exists(path)?;
+        fs::write(path, &*self.data.lock().unwrap())
+    }
+
+    fn resize(&mut self, size: u64) -> io::Result<()> {
+        let size = usize::try_from(size)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "RAM disk is too large"))?;
+        self.data.lock().unwrap().resize(size, 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_disk_は_clone_と内容を共有する() {
+        let disk = RamDisk::from_bytes(vec![1, 2, 3, 4]);
+        let mut backend = disk.clone();
+        backend.write_at(1, &[9, 9]).unwrap();
+        assert_eq!(disk.contents(), [1, 9, 9, 4]);
+
+        let mut buf = [0; 2];
+        backend.read_at(2, &mut buf).unwrap();
+        assert_eq!(buf, [9, 4]);
+        assert_eq!(disk.len(), 4);
+    }
+
+    #[test]
+    fn ram_disk_の末尾を超えるアクセスはエラーになる() {
+        let mut disk = RamDisk::new(512);
+        let mut buf = [0; 8];
+        let err = disk.read_at(508, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(disk.write_at(u64::MAX, &buf).is_err());
+        assert!(disk.contents().iter().all(|&b| b == 0));
+    }
+}
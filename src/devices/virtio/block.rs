@@ -1,108 +1,119 @@
 //! VirtIO Block デバイス実装
 //!
 //! VirtIO 1.2 仕様に基づいた Block デバイスのエミュレーション。
+//! legacy (virtio-mmio version 1) のドライバ向けには
+//! [`TransportVersion::Legacy`] でレイアウトを切り替えられる。
 
-use crate::devices::virtio::VirtQueue;
+use crate::boot::layout::IrqMap;
+use crate::boot::verify::{Image, ImageKind, ImageVerifier, Sha256};
+use crate::devices::fault::FaultInjector;
+use crate::devices::virtio::backend::BlockBackend;
+use crate::devices::virtio::dma::DmaValidator;
+#[cfg(feature = "snapshot")]
+use crate::devices::virtio::transport::STATUS_DRIVER_OK;
+use crate::devices::virtio::transport::{
+    configurable_queue, is_legacy_register, latch_queue, legacy_regs, regs, InterruptState,
+    LegacyAccessError, LegacyState, QueueAddrs, QueueConfig, QueueConfigError, StatusWrite,
+    TransportVersion, STATUS_DEVICE_NEEDS_RESET, VIRTIO_F_VERSION_1, VIRT_MAGIC, VIRT_VENDOR,
+};
+use crate::devices::virtio::{Descriptor, GuestQueue};
+use crate::memory::{GuestMemory, GuestMemoryExt};
+#[cfg(feature = "snapshot")]
+use crate::migration::{DeviceState, StateDecoder, StateEncoder};
 use crate::mmio::MmioHandler;
+use crate::stats::{BlockStats, IoCounters, LatencyStats};
 use std::error::Error;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
-
-/// VirtIO MMIO マジック値 ("virt")
-const VIRT_MAGIC: u32 = 0x74726976;
-
-/// VirtIO MMIO バージョン (2 for modern)
-const VIRT_VERSION: u32 = 0x2;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::time::Instant;
 
 /// VirtIO Block デバイス ID
 const VIRTIO_ID_BLOCK: u32 = 0x2;
 
-/// VirtIO Vendor ID ("QEMU")
-const VIRT_VENDOR: u32 = 0x554D4551;
+/// キューの数 (`VIRTIO_BLK_F_MQ` なしなのでリクエストキュー 1 つ)
+const NUM_QUEUES: usize = 1;
+
+/// キューサイズの上限 (QueueNumMax)
+const QUEUE_NUM_MAX: u16 = 16;
+
+/// リクエストヘッダ (struct virtio_blk_req の type, reserved, sector) の大きさ
+const REQ_HEADER_SIZE: u32 = 16;
+
+/// 設定領域の `capacity` の上位 32 ビット
+const CONFIG_CAPACITY_HIGH: u64 = regs::CONFIG + 4;
+
+/// 設定領域の `size_max` (le32)
+const CONFIG_SIZE_MAX: u64 = regs::CONFIG + 8;
+
+/// データの記述子 1 つの大きさの上限 (`size_max` でドライバに伝える)
+const MAX_SEGMENT_SIZE: u32 = 1 << 20;
 
 /// セクタサイズ（512 bytes）
-const SECTOR_SIZE: usize = 512;
+pub const SECTOR_SIZE: usize = 512;
+
+/// Feature bit: 記述子 1 つの大きさに上限がある (`size_max`)
+const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+
+/// Feature bit: 読み取り専用のディスク
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
 
 /// VirtIO Block リクエストタイプ
-#[allow(dead_code)]
 const VIRTIO_BLK_T_IN: u32 = 0; // Read
-#[allow(dead_code)]
 const VIRTIO_BLK_T_OUT: u32 = 1; // Write
-#[allow(dead_code)]
 const VIRTIO_BLK_T_FLUSH: u32 = 4; // Flush
 
 /// VirtIO Block ステータス
-#[allow(dead_code)]
 const VIRTIO_BLK_S_OK: u8 = 0; // Success
-#[allow(dead_code)]
 const VIRTIO_BLK_S_IOERR: u8 = 1; // I/O Error
-#[allow(dead_code)]
 const VIRTIO_BLK_S_UNSUPP: u8 = 2; // Unsupported
 
-/// VirtIO Block リクエスト
-#[allow(dead_code)]
-#[derive(Debug)]
-struct VirtioBlkReq {
-    /// リクエストタイプ（IN, OUT, FLUSH）
-    type_: u32,
-    /// セクタ番号
-    sector: u64,
-    /// データバッファ
-    data: Vec<u8>,
-    /// ステータス（OK, IOERR, UNSUPP）
-    status: u8,
-}
-
-/// VirtIO MMIO レジスタオフセット
-#[allow(dead_code)]
-mod regs {
-    pub const MAGIC_VALUE: u64 = 0x00;
-    pub const VERSION: u64 = 0x04;
-    pub const DEVICE_ID: u64 = 0x08;
-    pub const VENDOR_ID: u64 = 0x0c;
-    pub const DEVICE_FEATURES: u64 = 0x10;
-    pub const DEVICE_FEATURES_SEL: u64 = 0x14;
-    pub const DRIVER_FEATURES: u64 = 0x20;
-    pub const DRIVER_FEATURES_SEL: u64 = 0x24;
-    pub const QUEUE_SEL: u64 = 0x30;
-    pub const QUEUE_NUM_MAX: u64 = 0x34;
-    pub const QUEUE_NUM: u64 = 0x38;
-    pub const QUEUE_READY: u64 = 0x44;
-    pub const QUEUE_NOTIFY: u64 = 0x50;
-    pub const INTERRUPT_STATUS: u64 = 0x60;
-    pub const INTERRUPT_ACK: u64 = 0x64;
-    pub const STATUS: u64 = 0x70;
-    pub const QUEUE_DESC_LOW: u64 = 0x80;
-    pub const QUEUE_DESC_HIGH: u64 = 0x84;
-    pub const QUEUE_DRIVER_LOW: u64 = 0x90;
-    pub const QUEUE_DRIVER_HIGH: u64 = 0x94;
-    pub const QUEUE_DEVICE_LOW: u64 = 0xa0;
-    pub const QUEUE_DEVICE_HIGH: u64 = 0xa4;
-    pub const CONFIG_GENERATION: u64 = 0xfc;
-}
-
 /// VirtIO Block デバイス
 pub struct VirtioBlockDevice {
     /// ベースアドレス
     base_addr: u64,
-    /// VirtQueue（キューサイズ 16）
-    queue: VirtQueue,
     /// デバイスステータス
     status: u32,
     /// 選択中のキューインデックス
     queue_sel: u32,
     /// デバイス Features セレクタ
-    #[allow(dead_code)]
     device_features_sel: u32,
     /// ドライバー Features セレクタ
-    #[allow(dead_code)]
     driver_features_sel: u32,
-    /// ディスクイメージファイル
-    #[allow(dead_code)]
-    disk_image: Option<File>,
-    /// ディスク容量（セクタ数）
-    #[allow(dead_code)]
+    /// ドライバーが受け入れた Features
+    driver_features: u64,
+    /// ディスクの内容 (ディスクイメージファイルなど)
+    disk_image: Option<Box<dyn BlockBackend>>,
+    /// ディスク容量（セクタ数、設定領域の `capacity`）
     capacity: u64,
+    /// InterruptStatus と ConfigGeneration
+    interrupts: InterruptState,
+    /// アサートする割り込み (`attach_irq` で受け取る)
+    irq: u32,
+    /// QueueNotify 1 回分のキュー処理時間
+    queue_latency: LatencyStats,
+    /// ディスクイメージとの I/O 量
+    io: IoCounters,
+    /// リクエストの統計 (`queue_depth` と `rejected` は参照時に埋める)
+    requests: BlockStats,
+    /// 公開するトランスポートのバージョン
+    transport: TransportVersion,
+    /// ドライバがキューごとに設定した値 (QueueSel で選ぶ)
+    queues: [QueueConfig; NUM_QUEUES],
+    /// legacy レジスタの状態 (TransportVersion::Legacy の場合)
+    legacy: LegacyState,
+    /// modern デバイスへの legacy レジスタの書き込み (最初の 1 回)
+    transport_error: Option<LegacyAccessError>,
+    /// QueueReady で確定できなかったキューの設定 (最後の 1 回)
+    queue_error: Option<QueueConfigError>,
+    /// 障害注入 (`set_fault_injector`)
+    faults: Option<FaultInjector>,
+    /// 記述子の検証 (`set_dma_validator`)
+    dma: Option<DmaValidator>,
+    /// 不正な記述子のため IOERR で拒否したリクエスト数
+    rejected_requests: u64,
+    /// 読み取り専用のディスク (`VIRTIO_BLK_F_RO`)
+    read_only: bool,
 }
 
 impl VirtioBlockDevice {
@@ -114,13 +125,27 @@ impl VirtioBlockDevice {
     pub fn new(base_addr: u64) -> Self {
         Self {
             base_addr,
-            queue: VirtQueue::new(16),
             status: 0,
             queue_sel: 0,
             device_features_sel: 0,
             driver_features_sel: 0,
+            driver_features: 0,
             disk_image: None,
             capacity: 0,
+            interrupts: InterruptState::default(),
+            irq: IrqMap::QEMU_VIRT.virtio,
+            queue_latency: LatencyStats::default(),
+            io: IoCounters::default(),
+            requests: BlockStats::default(),
+            transport: TransportVersion::default(),
+            queues: [QueueConfig::new(QUEUE_NUM_MAX); NUM_QUEUES],
+            legacy: LegacyState::default(),
+            transport_error: None,
+            queue_error: None,
+            faults: None,
+            dma: None,
+            rejected_requests: 0,
+            read_only: false,
         }
     }
 
@@ -133,15 +158,268 @@ impl VirtioBlockDevice {
     /// * `capacity` - ディスク容量（セクタ数）
     #[allow(dead_code)]
     pub fn with_disk_image(base_addr: u64, disk_image: File, capacity: u64) -> Self {
+        Self::with_backend(base_addr, Box::new(disk_image), capacity)
+    }
+
+    /// ディスクイメージを検証してから VirtIO Block デバイスを作成
+    ///
+    /// ファイルを先頭から読みながら SHA-256 を計算して `verifier` に渡し、
+    /// 拒否されたらデバイスを作らない。ゲストには検証に使ったのと同じファイル
+    /// ディスクリプタから読ませ、開いている間は `flock` でロックする
+    /// (読み取り専用なら共有ロック、書き込み可能なら排他ロック)。ロックは
+    /// advisory なので、ロックを取らずに書き込むプロセスは防げない。
+    /// 検証するのは接続する時点の内容だけで、書き込みを許可したディスクへの
+    /// ゲストからの以降の変更は確認しない。
+    ///
+    /// `read_only` の場合は `VIRTIO_BLK_F_RO` を提示し、ゲストの書き込みを拒否する。
+    ///
+    /// # Arguments
+    ///
+    /// * `base_addr` - MMIO ベースアドレス
+    /// * `path` - ディスクイメージファイルのパス
+    /// * `read_only` - 読み取り専用で開くか
+    /// * `verifier` - 内容の検証 ([`crate::boot::verify`])
+    pub fn open_verified<P: AsRef<Path>>(
+        base_addr: u64,
+        path: P,
+        read_only: bool,
+        verifier: &dyn ImageVerifier,
+    ) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path)?;
+        let lock = if read_only {
+            libc::LOCK_SH
+        } else {
+            libc::LOCK_EX
+        };
+        // SAFETY: file が開いている fd に flock するだけ
+        if unsafe { libc::flock(file.as_raw_fd(), lock | libc::LOCK_NB) } != 0 {
+            return Err(format!(
+                "Failed to lock disk image {}: {}",
+                path.display(),
+                std::io::Error::last_os_error()
+            )
+            .into());
+        }
+        let mut hasher = Sha256::new();
+        hasher.update_reader(&file)?;
+        let capacity = hasher.len() / SECTOR_SIZE as u64;
+        verifier.verify(&Image {
+            kind: ImageKind::Disk,
+            path,
+            data: None,
+            sha256: hasher.finish(),
+        })?;
+        let mut device = Self::with_disk_image(base_addr, file, capacity);
+        device.set_read_only(read_only);
+        Ok(device)
+    }
+
+    /// 任意のバックエンドをディスクとする VirtIO Block デバイスを作成
+    ///
+    /// テストでは [`RamDisk`](super::RamDisk) を使うとファイルを作らずに済む。
+    ///
+    /// # Arguments
+    ///
+    /// * `base_addr` - MMIO ベースアドレス
+    /// * `backend` - ディスクの内容
+    /// * `capacity` - ディスク容量（セクタ数）
+    pub fn with_backend(base_addr: u64, backend: Box<dyn BlockBackend>, capacity: u64) -> Self {
         Self {
-            base_addr,
-            queue: VirtQueue::new(16),
-            status: 0,
-            queue_sel: 0,
-            device_features_sel: 0,
-            driver_features_sel: 0,
-            disk_image: Some(disk_image),
+            disk_image: Some(backend),
             capacity,
+            ..Self::new(base_addr)
+        }
+    }
+
+    /// 読み取り専用にする
+    ///
+    /// `VIRTIO_BLK_F_RO` を提示し、ゲストの書き込みリクエストを IOERR で拒否する。
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// 公開するトランスポートのバージョンを設定する
+    ///
+    /// legacy (version 1) のみに対応したドライバでは [`TransportVersion::Legacy`] を使う。
+    pub fn set_transport(&mut self, transport: TransportVersion) {
+        self.transport = transport;
+    }
+
+    /// デバイスが提供する Features
+    ///
+    /// modern のトランスポートでは `VIRTIO_F_VERSION_1` を提供しないと
+    /// Linux の virtio_mmio ドライバがデバイスを使わない。
+    fn device_features(&self) -> u64 {
+        let mut features = VIRTIO_BLK_F_SIZE_MAX;
+        if self.read_only {
+            features |= VIRTIO_BLK_F_RO;
+        }
+        if self.transport == TransportVersion::Modern {
+            features |= VIRTIO_F_VERSION_1;
+        }
+        features
+    }
+
+    /// ドライバーが受け入れた Features
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    /// ドライバが設定したキューのゲスト物理アドレス
+    pub fn queue_addrs(&self) -> QueueAddrs {
+        self.queues[0].addrs
+    }
+
+    /// キュー `index` にドライバが設定した値 (存在しないキューは None)
+    pub fn queue_config(&self, index: u32) -> Option<QueueConfig> {
+        self.queues.get(index as usize).copied()
+    }
+
+    /// modern デバイスとして動作中に legacy レジスタが書き込まれていればそのエラー
+    pub fn transport_error(&self) -> Option<LegacyAccessError> {
+        self.transport_error
+    }
+
+    /// QueueReady (legacy では QueuePFN) で確定できなかったキューの設定のエラー
+    pub fn queue_error(&self) -> Option<QueueConfigError> {
+        self.queue_error
+    }
+
+    /// ディスク容量を変更し、ゲストに通知する
+    ///
+    /// バックエンドのイメージを拡張・縮小した後に呼ぶ。ゲストのドライバは
+    /// 設定変更の割り込みを受けて `capacity` を読み直す。
+    pub fn set_capacity(&mut self, capacity: u64) {
+        self.capacity = capacity;
+        self.notify_config_changed();
+    }
+
+    /// ディスクを `new_sectors` セクタに拡張する (オンラインリサイズ)
+    ///
+    /// バックエンドのイメージを伸ばしてから容量を更新し、設定変更の割り込みで
+    /// ゲストに知らせる。Linux は再起動せずに `/dev/vda` の容量を更新するので、
+    /// ゲスト内で `resize2fs` などを実行すればファイルシステムも広げられる。
+    /// 登録した後に呼ぶには、デバイスを [`SharedDevice`](crate::mmio::SharedDevice)
+    /// で包んで登録する。
+    ///
+    /// # Errors
+    /// ディスクがない場合、現在より小さい場合 (ゲストが使用中の領域を失うため)、
+    /// バックエンドがサイズ変更に対応していない場合
+    pub fn resize(&mut self, new_sectors: u64) -> Result<(), Box<dyn Error>> {
+        if new_sectors < self.capacity {
+            return Err(format!(
+                "Cannot shrink virtio-blk disk from {} to {} sectors while the guest is running",
+                self.capacity, new_sectors
+            )
+            .into());
+        }
+        let size = new_sectors
+            .checked_mul(SECTOR_SIZE as u64)
+            .ok_or_else(|| format!("Disk size of {} sectors is too large", new_sectors))?;
+        let disk = self.disk_image.as_mut().ok_or("No disk image attached")?;
+        disk.resize(size)
+            .map_err(|e| format!("Failed to resize disk image to {} bytes: {}", size, e))?;
+        if new_sectors != self.capacity {
+            self.set_capacity(new_sectors);
+        }
+        Ok(())
+    }
+
+    /// 設定領域の変更をゲストに通知する (設定変更の割り込みをアサートする)
+    ///
+    /// 登録した後に呼ぶには、デバイスを [`SharedDevice`](crate::mmio::SharedDevice)
+    /// で包んで登録する。
+    pub fn notify_config_changed(&mut self) {
+        self.interrupts.notify_config_changed();
+    }
+
+    /// 障害注入を設定する
+    ///
+    /// `FaultInjector::fail_disk_io` で指定したセクタ範囲の `read_sectors` /
+    /// `write_sectors` がエラーになる。
+    pub fn set_fault_injector(&mut self, faults: FaultInjector) {
+        self.faults = Some(faults);
+    }
+
+    /// 記述子の検証とキューへのアクセスに使うゲスト RAM を設定する
+    ///
+    /// キューの処理に必須。記述子チェーンのすべてのアドレスを検証し、
+    /// 不正なリクエストは `VIRTIO_BLK_S_IOERR` で完了させる。`Hypervisor` に登録する
+    /// (virtio-mmio スロットに bind する) と [`MmioHandler::attach_dma`] で設定されるため、
+    /// MMIO の穴を指定する場合や登録せずに使う場合だけ呼ぶ。
+    pub fn set_dma_validator(&mut self, dma: DmaValidator) {
+        self.dma = Some(dma);
+    }
+
+    /// 不正な記述子のため拒否したリクエスト数
+    pub fn rejected_requests(&self) -> u64 {
+        self.rejected_requests
+    }
+
+    /// legacy レジスタへの書き込みを処理する
+    fn write_legacy(&mut self, offset: u64, value: u32) {
+        if self.transport == TransportVersion::Modern {
+            // ドライバにはデバイスのリセットが必要と通知し、ホストには原因を表示する
+            if self.transport_error.is_none() {
+                let err = LegacyAccessError { offset };
+                eprintln!("[VIRTIO] virtio-blk: {}", err);
+                self.transport_error = Some(err);
+            }
+            self.status |= STATUS_DEVICE_NEEDS_RESET;
+            return;
+        }
+        match offset {
+            legacy_regs::GUEST_PAGE_SIZE => self.legacy.page_size = value,
+            legacy_regs::QUEUE_ALIGN => self.legacy.align = value,
+            _ => {
+                // QueuePFN: legacy には QueueReady がなく、PFN の書き込みで確定する
+                let Some(queue) = self.queues.get_mut(self.queue_sel as usize) else {
+                    return;
+                };
+                self.legacy.pfn = value;
+                queue.ready = false;
+                if value == 0 {
+                    // 0 はキューの解放
+                    queue.addrs = QueueAddrs::default();
+                } else {
+                    match QueueAddrs::legacy(
+                        value,
+                        self.legacy.page_size,
+                        self.legacy.align,
+                        queue.num,
+                    ) {
+                        Ok(addrs) => {
+                            queue.addrs = addrs;
+                            self.latch_queue();
+                        }
+                        Err(err) => {
+                            eprintln!("[VIRTIO] virtio-blk: queue {}: {}", self.queue_sel, err);
+                            self.queue_error = Some(err);
+                            self.status |= STATUS_DEVICE_NEEDS_RESET;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// 選択中のキューの設定を検証して確定する
+    ///
+    /// 不正な設定はゲスト RAM の外への DMA になるため確定せず、ドライバには
+    /// デバイスのリセットが必要と通知し、ホストには原因を表示する。
+    fn latch_queue(&mut self) {
+        if let Err(err) = latch_queue(
+            &mut self.queues,
+            self.queue_sel,
+            self.dma.as_ref(),
+            "virtio-blk",
+        ) {
+            self.queue_error = Some(err);
+            self.status |= STATUS_DEVICE_NEEDS_RESET;
         }
     }
 
@@ -152,11 +430,19 @@ impl VirtioBlockDevice {
     /// * `sector` - 開始セクタ番号
     /// * `data` - 読み取ったデータを格納するバッファ
     pub fn read_sectors(&mut self, sector: u64, data: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        let start = Instant::now();
+        self.requests.reads += 1;
+        let result = self.read_disk(sector, data);
+        self.complete_request(start, result)
+    }
+
+    fn read_disk(&mut self, sector: u64, data: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        let offset = self.disk_offset(sector, data.len() as u64)?;
+        self.check_injected_fault(sector, data.len(), false)?;
         let disk = self.disk_image.as_mut().ok_or("No disk image attached")?;
 
-        let offset = sector * SECTOR_SIZE as u64;
-        disk.seek(SeekFrom::Start(offset))?;
-        disk.read_exact(data)?;
+        disk.read_at(offset, data)?;
+        self.io.read_bytes += data.len() as u64;
 
         Ok(())
     }
@@ -168,37 +454,423 @@ impl VirtioBlockDevice {
     /// * `sector` - 開始セクタ番号
     /// * `data` - 書き込むデータ
     pub fn write_sectors(&mut self, sector: u64, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let start = Instant::now();
+        self.requests.writes += 1;
+        let result = self.write_disk(sector, data);
+        self.complete_request(start, result)
+    }
+
+    fn write_disk(&mut self, sector: u64, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let offset = self.disk_offset(sector, data.len() as u64)?;
+        self.check_injected_fault(sector, data.len(), true)?;
         let disk = self.disk_image.as_mut().ok_or("No disk image attached")?;
 
-        let offset = sector * SECTOR_SIZE as u64;
-        disk.seek(SeekFrom::Start(offset))?;
-        disk.write_all(data)?;
-        disk.flush()?;
+        disk.write_at(offset, data)?;
+        self.io.written_bytes += data.len() as u64;
+
+        Ok(())
+    }
+
+    /// `sector` からの `len` バイトがディスクに収まるか確かめ、バイト単位の位置を返す
+    ///
+    /// ディスクイメージファイルは末尾を越えて書き込むと伸びるため、ゲストが
+    /// 指定した範囲は必ず容量 (`capacity`) と比べる。
+    fn disk_offset(&self, sector: u64, len: u64) -> Result<u64, Box<dyn Error>> {
+        let count = len.div_ceil(SECTOR_SIZE as u64);
+        sector
+            .checked_add(count)
+            .filter(|&end| end <= self.capacity)
+            .and_then(|_| sector.checked_mul(SECTOR_SIZE as u64))
+            .ok_or_else(|| {
+                format!(
+                    "sectors {}+{} are beyond the end of the disk ({} sectors)",
+                    sector, count, self.capacity
+                )
+                .into()
+            })
+    }
+
+    /// 書き込みをディスクに反映する (`VIRTIO_BLK_T_FLUSH`)
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        let start = Instant::now();
+        self.requests.flushes += 1;
+        let result = match self.disk_image.as_mut() {
+            Some(disk) => disk.flush().map_err(Into::into),
+            None => Err("No disk image attached".into()),
+        };
+        self.complete_request(start, result)
+    }
+
+    /// リクエスト 1 つ分の処理時間と失敗を記録する
+    fn complete_request(
+        &mut self,
+        start: Instant,
+        result: Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.requests.latency.record(start.elapsed());
+        if result.is_err() {
+            self.requests.errors += 1;
+        }
+        result
+    }
 
+    /// ディスクイメージの現在の内容を `path` に複製する
+    ///
+    /// 処理済みのリクエストの書き込みだけが含まれ、キューに残っているリクエストは
+    /// 処理しない。ゲストを止めた状態 (`run` から戻った後) で呼ぶこと。
+    ///
+    /// ディスクイメージファイルの場合、APFS では `fclonefileat` でクローン
+    /// (コピーオンライト) し、使えないファイルシステムでは通常のコピーにフォールバックする。
+    /// `path` が既にあれば置き換える。
+    pub fn snapshot_to(&mut self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let disk = self.disk_image.as_mut().ok_or("No disk image attached")?;
+        disk.snapshot_to(path.as_ref())?;
         Ok(())
     }
 
+    /// 障害注入の対象なら I/O エラーを返す
+    fn check_injected_fault(
+        &self,
+        sector: u64,
+        len: usize,
+        write: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let count = len.div_ceil(SECTOR_SIZE) as u64;
+        match &self.faults {
+            Some(faults) if faults.check_disk_io(sector, count, write) => Err(format!(
+                "Injected I/O error: {} of sector {}",
+                if write { "write" } else { "read" },
+                sector
+            )
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// 拒否したリクエストのステータスに IOERR を書き込む
+    ///
+    /// ステータスはチェーン末尾の書き込み可能な 1 バイトだが、チェーン自体が
+    /// 壊れている場合もあるため、検証を通った記述子にだけ書き込む。
+    ///
+    /// # Returns
+    /// 書き込んだバイト数 (Used Ring の len)
+    fn write_ioerr_status(queue: &GuestQueue, head: u16, dma: &DmaValidator) -> u32 {
+        match queue.chain_tail(head) {
+            Some(desc) if desc.is_write() && desc.len >= 1 && dma.check(desc.addr, 1).is_ok() => {
+                match dma.memory().write_u8(desc.addr, VIRTIO_BLK_S_IOERR) {
+                    Ok(()) => 1,
+                    Err(_) => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
     /// VirtQueue を処理する
     ///
-    /// Available Ring から記述子を取得し、リクエストを処理する。
-    /// 現時点ではスタブ実装。
-    #[allow(dead_code)]
+    /// ゲスト RAM 上の Available Ring からリクエストを取り出し、記述子チェーンを
+    /// 検証してからディスクを読み書きする。不正な記述子を含むリクエストは IOERR で
+    /// 完了させる。処理したリクエストがあれば Used Ring の割り込みを上げる。
     fn process_queue(&mut self) -> Result<(), Box<dyn Error>> {
-        // TODO: ゲストメモリアクセス機能を実装後に完全実装
-        // 現時点では Available Ring をチェックするのみ
-        while let Some(_idx) = self.queue.pop_avail() {
-            // TODO: 記述子チェーンを辿る
-            // TODO: リクエストヘッダを読み取る
-            // TODO: read/write 操作を実行
-            // TODO: ステータスを書き込む
-            // TODO: Used Ring に追加
+        let Some(dma) = self.dma.clone() else {
+            return if self.queues[0].ready {
+                Err("no guest memory attached (set_dma_validator)".into())
+            } else {
+                Ok(())
+            };
+        };
+        let Some(queue) = GuestQueue::latched(self.queues[0], &dma) else {
+            return Ok(());
+        };
+        let mut completed = false;
+        while let Some(head) = queue.next_avail()? {
+            let written = match queue.validate_chain(head) {
+                Ok(chain) => self.handle_request(&chain, dma.memory().as_ref()),
+                Err(err) => {
+                    eprintln!("[VIRTIO] virtio-blk: {}", err);
+                    self.rejected_requests += 1;
+                    Self::write_ioerr_status(&queue, head, &dma)
+                }
+            };
+            queue.push_used(head, written)?;
+            completed = true;
+        }
+        if completed {
+            self.interrupts.notify_used();
+        }
+        Ok(())
+    }
+
+    /// 検証済みの記述子チェーン 1 つ分のリクエストを処理する
+    ///
+    /// チェーンはヘッダ (読み取り専用)、データ、ステータス (書き込み可能な 1 バイト) の順。
+    ///
+    /// # Returns
+    /// ゲストに書き込んだバイト数 (Used Ring の len)
+    fn handle_request(&mut self, chain: &[Descriptor], mem: &dyn GuestMemory) -> u32 {
+        let (Some(header), Some(status)) = (chain.first(), chain.last()) else {
+            return 0;
+        };
+        if chain.len() < 2 || !status.is_write() || status.len < 1 {
+            // ステータスを返す場所がない
+            eprintln!("[VIRTIO] virtio-blk: request without a status byte");
+            return 0;
+        }
+        let data = &chain[1..chain.len() - 1];
+        let (status_value, written) = match Self::read_header(header, mem) {
+            Some((VIRTIO_BLK_T_IN, sector)) => {
+                let start = Instant::now();
+                self.requests.reads += 1;
+                let result = self.read_request(sector, data, mem);
+                let written = result.as_ref().copied().unwrap_or(0);
+                (self.complete_request(start, result.map(|_| ())), written)
+            }
+            Some((VIRTIO_BLK_T_OUT, sector)) => {
+                let start = Instant::now();
+                self.requests.writes += 1;
+                let result = self.write_request(sector, data, mem);
+                (self.complete_request(start, result), 0)
+            }
+            Some((VIRTIO_BLK_T_FLUSH, _)) => (self.flush(), 0),
+            Some(_) => {
+                let _ = mem.write_u8(status.addr, VIRTIO_BLK_S_UNSUPP);
+                return 1;
+            }
+            None => (Err("malformed request header".into()), 0),
+        };
+        let value = match status_value {
+            Ok(()) => VIRTIO_BLK_S_OK,
+            Err(e) => {
+                eprintln!("[VIRTIO] virtio-blk: {}", e);
+                VIRTIO_BLK_S_IOERR
+            }
+        };
+        match mem.write_u8(status.addr, value) {
+            Ok(()) => written + 1,
+            Err(_) => written,
+        }
+    }
+
+    /// リクエストヘッダから (type, sector) を読む
+    fn read_header(header: &Descriptor, mem: &dyn GuestMemory) -> Option<(u32, u64)> {
+        if header.is_write() || header.len < REQ_HEADER_SIZE {
+            return None;
+        }
+        Some((
+            mem.read_u32(header.addr).ok()?,
+            mem.read_u64(header.addr + 8).ok()?,
+        ))
+    }
+
+    /// `VIRTIO_BLK_T_IN`: ディスクを読んでデータの記述子に書き込む
+    ///
+    /// # Returns
+    /// ゲストに書き込んだバイト数
+    fn read_request(
+        &mut self,
+        sector: u64,
+        data: &[Descriptor],
+        mem: &dyn GuestMemory,
+    ) -> Result<u32, Box<dyn Error>> {
+        let total = self.check_request_range(sector, data)?;
+        let written = u32::try_from(total)
+            .map_err(|_| format!("read of {} bytes does not fit in the used ring", total))?;
+        let mut sector = sector;
+        for desc in data {
+            Self::check_data_desc(desc, true)?;
+            let mut buf = vec![0u8; desc.len as usize];
+            self.read_disk(sector, &mut buf)?;
+            mem.write_slice(&buf, desc.addr)?;
+            // 範囲は確かめてあるので容量を越えない
+            sector += desc.len as u64 / SECTOR_SIZE as u64;
+        }
+        Ok(written)
+    }
+
+    /// `VIRTIO_BLK_T_OUT`: データの記述子の内容をディスクに書き込む
+    fn write_request(
+        &mut self,
+        sector: u64,
+        data: &[Descriptor],
+        mem: &dyn GuestMemory,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.read_only {
+            return Err(format!("write to sector {} of a read-only disk", sector).into());
+        }
+        self.check_request_range(sector, data)?;
+        let mut sector = sector;
+        for desc in data {
+            Self::check_data_desc(desc, false)?;
+            let mut buf = vec![0u8; desc.len as usize];
+            mem.read_slice(&mut buf, desc.addr)?;
+            self.write_disk(sector, &buf)?;
+            // 範囲は確かめてあるので容量を越えない
+            sector += desc.len as u64 / SECTOR_SIZE as u64;
+        }
+        Ok(())
+    }
+
+    /// リクエスト全体がディスクに収まるか、読み書きを始める前に確かめる
+    ///
+    /// 末尾をまたぐリクエストの前半だけを書き込まないようにする。
+    ///
+    /// # Returns
+    /// データの記述子の合計バイト数
+    fn check_request_range(&self, sector: u64, data: &[Descriptor]) -> Result<u64, Box<dyn Error>> {
+        let total = data.iter().map(|desc| desc.len as u64).sum();
+        self.disk_offset(sector, total)?;
+        Ok(total)
+    }
+
+    /// データの記述子の向きと長さ (セクタ単位) を確かめる
+    fn check_data_desc(desc: &Descriptor, device_writes: bool) -> Result<(), Box<dyn Error>> {
+        if desc.is_write() != device_writes {
+            return Err(format!(
+                "data buffer at 0x{:x} is {}",
+                desc.addr,
+                if device_writes {
+                    "read-only"
+                } else {
+                    "write-only"
+                }
+            )
+            .into());
+        }
+        if !(desc.len as usize).is_multiple_of(SECTOR_SIZE) {
+            return Err(format!(
+                "data buffer at 0x{:x} is {} bytes, not a multiple of the sector size",
+                desc.addr, desc.len
+            )
+            .into());
+        }
+        // 長さはゲストが決めるので、読み書きのバッファを確保する前に抑える
+        if desc.len > MAX_SEGMENT_SIZE {
+            return Err(format!(
+                "data buffer at 0x{:x} is {} bytes, more than the {}-byte segment limit",
+                desc.addr, desc.len, MAX_SEGMENT_SIZE
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "snapshot")]
+impl DeviceState for VirtioBlockDevice {
+    fn save_state(&self) -> Vec<u8> {
+        StateEncoder::new()
+            .u32(self.status)
+            .u32(self.queue_sel)
+            .u32(self.device_features_sel)
+            .u32(self.driver_features_sel)
+            .u32(self.transport.register_value())
+            .u32(self.queues[0].num as u32)
+            .u64(self.queues[0].addrs.desc)
+            .u64(self.queues[0].addrs.driver)
+            .u64(self.queues[0].addrs.device)
+            .u32(self.legacy.page_size)
+            .u32(self.legacy.align)
+            .u32(self.legacy.pfn)
+            .u32(self.interrupts.status())
+            .u32(self.interrupts.config_generation())
+            .bool(self.queues[0].ready)
+            .u64(self.driver_features)
+            .finish()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut dec = StateDecoder::new(state);
+        let (status, queue_sel) = (dec.u32()?, dec.u32()?);
+        let (device_features_sel, driver_features_sel) = (dec.u32()?, dec.u32()?);
+        let transport = dec.u32()?;
+        if transport != self.transport.register_value() {
+            return Err(format!(
+                "virtio-blk state uses transport version {}, but this device is version {}",
+                transport,
+                self.transport.register_value()
+            )
+            .into());
         }
+        let mut queue = QueueConfig {
+            num: dec.u32()? as u16,
+            addrs: QueueAddrs {
+                desc: dec.u64()?,
+                driver: dec.u64()?,
+                device: dec.u64()?,
+            },
+            ready: false,
+        };
+        let legacy = LegacyState {
+            page_size: dec.u32()?,
+            align: dec.u32()?,
+            pfn: dec.u32()?,
+        };
+        let interrupts = InterruptState::from_raw(dec.u32()?, dec.u32()?);
+        // QUEUE_READY を持たない版の状態では、DRIVER_OK まで進んだキューを有効とみなす
+        queue.ready = dec
+            .optional(|dec| dec.bool())?
+            .unwrap_or(status & STATUS_DRIVER_OK != 0);
+        let driver_features = dec.optional(|dec| dec.u64())?.unwrap_or(0);
+        dec.finish()?;
 
+        self.status = status;
+        self.queue_sel = queue_sel;
+        self.device_features_sel = device_features_sel;
+        self.driver_features_sel = driver_features_sel;
+        self.driver_features = driver_features;
+        self.queues[0] = queue;
+        self.legacy = legacy;
+        self.interrupts = interrupts;
         Ok(())
     }
 }
 
 impl MmioHandler for VirtioBlockDevice {
+    fn name(&self) -> &str {
+        "virtio-blk"
+    }
+
+    #[cfg(feature = "snapshot")]
+    fn as_device_state(&mut self) -> Option<&mut dyn DeviceState> {
+        Some(self)
+    }
+
+    fn pending_irq(&self) -> Option<u32> {
+        self.interrupts.is_asserted().then_some(self.irq)
+    }
+
+    fn queue_latency(&self) -> Option<LatencyStats> {
+        Some(self.queue_latency)
+    }
+
+    fn io_counters(&self) -> Option<IoCounters> {
+        Some(self.io)
+    }
+
+    fn attach_dma(&mut self, dma: DmaValidator) {
+        // 利用者が MMIO の穴などを指定して設定したものを優先する
+        if self.dma.is_none() {
+            self.dma = Some(dma);
+        }
+    }
+
+    fn attach_irq(&mut self, irq: u32) {
+        self.irq = irq;
+    }
+
+    fn block_stats(&self) -> Option<BlockStats> {
+        Some(BlockStats {
+            rejected: self.rejected_requests,
+            queue_depth: self
+                .dma
+                .as_ref()
+                .and_then(|dma| GuestQueue::latched(self.queues[0], dma))
+                .and_then(|queue| queue.pending().ok())
+                .unwrap_or(0),
+            ..self.requests
+        })
+    }
+
     fn base(&self) -> u64 {
         self.base_addr
     }
@@ -207,22 +879,55 @@ impl MmioHandler for VirtioBlockDevice {
         0x200 // VirtIO MMIO レジスタ領域のサイズ
     }
 
+    // ディスク・障害注入・統計はホスト側のものなので残す
+    fn reset(&mut self) {
+        self.status = 0;
+        self.queue_sel = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queues = [QueueConfig::new(QUEUE_NUM_MAX); NUM_QUEUES];
+        self.legacy = LegacyState::default();
+        self.transport_error = None;
+        self.queue_error = None;
+        self.interrupts.reset();
+    }
+
     fn read(&mut self, offset: u64, _size: usize) -> Result<u64, Box<dyn Error>> {
         let value = match offset {
             regs::MAGIC_VALUE => VIRT_MAGIC as u64,
-            regs::VERSION => VIRT_VERSION as u64,
+            regs::VERSION => self.transport.register_value() as u64,
+            legacy_regs::QUEUE_PFN if self.transport == TransportVersion::Legacy => {
+                match self.queue_config(self.queue_sel) {
+                    Some(_) => self.legacy.pfn as u64,
+                    None => 0,
+                }
+            }
             regs::DEVICE_ID => VIRTIO_ID_BLOCK as u64,
             regs::VENDOR_ID => VIRT_VENDOR as u64,
-            regs::QUEUE_NUM_MAX => self.queue.size() as u64,
+            // 存在しないキューは 0 (使えない)
+            regs::QUEUE_NUM_MAX => match self.queue_config(self.queue_sel) {
+                Some(_) => QUEUE_NUM_MAX as u64,
+                None => 0,
+            },
+            regs::QUEUE_READY => self
+                .queue_config(self.queue_sel)
+                .is_some_and(|queue| queue.ready) as u64,
             regs::STATUS => self.status as u64,
             regs::DEVICE_FEATURES => {
-                // 最小限の実装: Features なし
-                0
-            }
-            regs::INTERRUPT_STATUS => {
-                // 割り込みは未実装
-                0
+                let features = self.device_features();
+                match self.device_features_sel {
+                    0 => features & 0xffff_ffff,
+                    1 => features >> 32,
+                    _ => 0,
+                }
             }
+            regs::INTERRUPT_STATUS => self.interrupts.status() as u64,
+            regs::CONFIG_GENERATION => self.interrupts.config_generation() as u64,
+            // struct virtio_blk_config の先頭の capacity (le64)
+            regs::CONFIG => self.capacity & 0xffff_ffff,
+            CONFIG_CAPACITY_HIGH => self.capacity >> 32,
+            CONFIG_SIZE_MAX => MAX_SEGMENT_SIZE as u64,
             _ => {
                 // 未実装のレジスタは 0 を返す
                 0
@@ -234,17 +939,49 @@ impl MmioHandler for VirtioBlockDevice {
 
     fn write(&mut self, offset: u64, value: u64, _size: usize) -> Result<(), Box<dyn Error>> {
         match offset {
-            regs::STATUS => {
-                self.status = value as u32;
-            }
+            regs::STATUS => match StatusWrite::decode(self.status, value as u32) {
+                StatusWrite::Reset => MmioHandler::reset(self),
+                StatusWrite::Set(status) => self.status = status,
+            },
             regs::QUEUE_SEL => {
                 self.queue_sel = value as u32;
             }
+            regs::QUEUE_NUM => {
+                if let Some(queue) = configurable_queue(&mut self.queues, self.queue_sel) {
+                    queue.num = (value as u16).min(QUEUE_NUM_MAX);
+                }
+            }
+            regs::QUEUE_READY if self.transport == TransportVersion::Modern => {
+                if value & 1 == 0 {
+                    if let Some(queue) = self.queues.get_mut(self.queue_sel as usize) {
+                        queue.ready = false;
+                    }
+                } else if configurable_queue(&mut self.queues, self.queue_sel).is_some() {
+                    self.latch_queue();
+                }
+            }
+            offset if is_legacy_register(offset) => {
+                self.write_legacy(offset, value as u32);
+            }
+            regs::QUEUE_DESC_LOW
+            | regs::QUEUE_DESC_HIGH
+            | regs::QUEUE_DRIVER_LOW
+            | regs::QUEUE_DRIVER_HIGH
+            | regs::QUEUE_DEVICE_LOW
+            | regs::QUEUE_DEVICE_HIGH
+                if self.transport == TransportVersion::Modern =>
+            {
+                if let Some(queue) = configurable_queue(&mut self.queues, self.queue_sel) {
+                    queue.write_addr(offset, value as u32);
+                }
+            }
             regs::QUEUE_NOTIFY => {
                 // キュー通知 - VirtQueue を処理
+                let start = Instant::now();
                 if let Err(e) = self.process_queue() {
-                    eprintln!("Failed to process queue: {}", e);
+                    eprintln!("[VIRTIO] virtio-blk: failed to process queue: {}", e);
                 }
+                self.queue_latency.record(start.elapsed());
             }
             regs::DEVICE_FEATURES_SEL => {
                 self.device_features_sel = value as u32;
@@ -252,8 +989,19 @@ impl MmioHandler for VirtioBlockDevice {
             regs::DRIVER_FEATURES_SEL => {
                 self.driver_features_sel = value as u32;
             }
+            regs::DRIVER_FEATURES => {
+                let shift = match self.driver_features_sel {
+                    0 => 0,
+                    1 => 32,
+                    _ => return Ok(()),
+                };
+                let mask = 0xffff_ffffu64 << shift;
+                // デバイスが提供していない Features は受け入れない
+                self.driver_features = (self.driver_features & !mask)
+                    | (((value & 0xffff_ffff) << shift) & self.device_features());
+            }
             regs::INTERRUPT_ACK => {
-                // 割り込み ACK（将来実装）
+                self.interrupts.ack(value as u32);
             }
             _ => {
                 // 未実装のレジスタへの書き込みは無視
@@ -267,6 +1015,9 @@ impl MmioHandler for VirtioBlockDevice {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::devices::fault::IoDirection;
+    use crate::devices::virtio::transport::INTERRUPT_CONFIG;
+    use crate::devices::virtio::RamDisk;
     use std::fs::OpenOptions;
 
     #[test]
@@ -287,7 +1038,7 @@ mod tests {
     fn test_read_version() {
         let mut device = VirtioBlockDevice::new(0x0a00_0000);
         let version = device.read(regs::VERSION, 4).unwrap();
-        assert_eq!(version, VIRT_VERSION as u64);
+        assert_eq!(version, 2);
     }
 
     #[test]
@@ -311,6 +1062,37 @@ mod tests {
         assert_eq!(queue_num_max, 16);
     }
 
+    #[test]
+    fn test_modern_transport_offers_version_1() {
+        let mut device = VirtioBlockDevice::new(0x0a00_0000);
+        device.write(regs::DEVICE_FEATURES_SEL, 1, 4).unwrap();
+        assert_eq!(device.read(regs::DEVICE_FEATURES, 4).unwrap(), 1);
+        device.write(regs::DEVICE_FEATURES_SEL, 0, 4).unwrap();
+        assert_eq!(
+            device.read(regs::DEVICE_FEATURES, 4).unwrap(),
+            VIRTIO_BLK_F_SIZE_MAX
+        );
+
+        // 提供していない Features は受け入れない
+        device.write(regs::DRIVER_FEATURES_SEL, 1, 4).unwrap();
+        device.write(regs::DRIVER_FEATURES, 0xffff_ffff, 4).unwrap();
+        device.write(regs::DRIVER_FEATURES_SEL, 0, 4).unwrap();
+        device.write(regs::DRIVER_FEATURES, 0xffff_ffff, 4).unwrap();
+        assert_eq!(
+            device.driver_features(),
+            VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_SIZE_MAX
+        );
+
+        // リセットで取り消す
+        device.write(regs::STATUS, 0, 4).unwrap();
+        assert_eq!(device.driver_features(), 0);
+
+        // legacy のトランスポートでは提供しない
+        device.set_transport(TransportVersion::Legacy);
+        device.write(regs::DEVICE_FEATURES_SEL, 1, 4).unwrap();
+        assert_eq!(device.read(regs::DEVICE_FEATURES, 4).unwrap(), 0);
+    }
+
     #[test]
     fn test_write_status() {
         let mut device = VirtioBlockDevice::new(0x0a00_0000);
@@ -329,9 +1111,513 @@ mod tests {
     }
 
     #[test]
-    fn test_write_and_read_sectors() {
-        // テスト用ディスクイメージを作成
-        let path = "/tmp/test_virtio_disk.img";
+    fn test_queue_notify_records_latency() {
+        let mut device = VirtioBlockDevice::new(0x0a00_0000);
+        assert_eq!(device.queue_latency().unwrap().count, 0);
+        device.write(regs::QUEUE_NOTIFY, 0, 4).unwrap();
+        device.write(regs::QUEUE_NOTIFY, 0, 4).unwrap();
+        assert_eq!(device.queue_latency().unwrap().count, 2);
+        assert_eq!(device.name(), "virtio-blk");
+    }
+
+    #[test]
+    fn test_modern_queue_addresses() {
+        let mut device = VirtioBlockDevice::new(0x0a00_0000);
+        device.write(regs::QUEUE_DESC_LOW, 0x4800_0000, 4).unwrap();
+        device.write(regs::QUEUE_DESC_HIGH, 0x1, 4).unwrap();
+        device
+            .write(regs::QUEUE_DRIVER_LOW, 0x4800_0100, 4)
+            .unwrap();
+        device
+            .write(regs::QUEUE_DEVICE_LOW, 0x4800_1000, 4)
+            .unwrap();
+
+        let addrs = device.queue_addrs();
+        assert_eq!(addrs.desc, 0x1_4800_0000);
+        assert_eq!(addrs.driver, 0x4800_0100);
+        assert_eq!(addrs.device, 0x4800_1000);
+
+        // 4GB を超える RAM に置いたキュー: HIGH を先に書いても LOW を残す
+        device.write(regs::QUEUE_DRIVER_HIGH, 0x2, 4).unwrap();
+        device.write(regs::QUEUE_DEVICE_HIGH, 0x2, 4).unwrap();
+        device
+            .write(regs::QUEUE_DEVICE_LOW, 0x0000_2000, 4)
+            .unwrap();
+        let addrs = device.queue_addrs();
+        assert_eq!(addrs.driver, 0x2_4800_0100);
+        assert_eq!(addrs.device, 0x2_0000_2000);
+    }
+
+    #[test]
+    fn test_queue_ready_latches_configuration() {
+        use crate::memory::testing::TestMemory;
+        use std::sync::Arc;
+
+        let mut device = VirtioBlockDevice::new(0x0a00_0000);
+        device.set_dma_validator(DmaValidator::new(Arc::new(TestMemory::new(
+            0x4000_0000,
+            0x10000,
+        ))));
+        device.write(regs::QUEUE_SEL, 0, 4).unwrap();
+        device.write(regs::QUEUE_NUM, 8, 4).unwrap();
+        device.write(regs::QUEUE_DESC_LOW, 0x4000_1000, 4).unwrap();
+        device
+            .write(regs::QUEUE_DRIVER_LOW, 0x4000_1080, 4)
+            .unwrap();
+        device
+            .write(regs::QUEUE_DEVICE_LOW, 0x4000_2000, 4)
+            .unwrap();
+        assert_eq!(device.read(regs::QUEUE_READY, 4).unwrap(), 0);
+        device.write(regs::QUEUE_READY, 1, 4).unwrap();
+        assert_eq!(device.read(regs::QUEUE_READY, 4).unwrap(), 1);
+
+        // 確定した後の書き込みは無視する
+        device.write(regs::QUEUE_NUM, 4, 4).unwrap();
+        device.write(regs::QUEUE_DESC_HIGH, 0x1, 4).unwrap();
+        let queue = device.queue_config(0).unwrap();
+        assert_eq!((queue.num, queue.addrs.desc), (8, 0x4000_1000));
+
+        // QueueReady を 0 に戻せば設定し直せる
+        device.write(regs::QUEUE_READY, 0, 4).unwrap();
+        device.write(regs::QUEUE_DESC_LOW, 0x4000_3000, 4).unwrap();
+        assert_eq!(device.queue_addrs().desc, 0x4000_3000);
+        assert_eq!(device.queue_error(), None);
+    }
+
+    #[test]
+    fn test_queue_outside_ram_is_not_latched() {
+        use crate::memory::testing::TestMemory;
+        use std::sync::Arc;
+
+        let mut device = VirtioBlockDevice::new(0x0a00_0000);
+        device.set_dma_validator(DmaValidator::new(Arc::new(TestMemory::new(
+            0x4000_0000,
+            0x10000,
+        ))));
+        device.write(regs::QUEUE_DESC_LOW, 0x4000_1000, 4).unwrap();
+        device
+            .write(regs::QUEUE_DRIVER_LOW, 0x4000_1100, 4)
+            .unwrap();
+        // Used Ring だけ 4GB より上 (RAM の外)
+        device
+            .write(regs::QUEUE_DEVICE_LOW, 0x4000_2000, 4)
+            .unwrap();
+        device.write(regs::QUEUE_DEVICE_HIGH, 0x1, 4).unwrap();
+        device.write(regs::QUEUE_READY, 1, 4).unwrap();
+
+        assert_eq!(device.read(regs::QUEUE_READY, 4).unwrap(), 0);
+        assert!(matches!(
+            device.queue_error(),
+            Some(QueueConfigError::OutsideRam {
+                addr: 0x1_4000_2000,
+                ..
+            })
+        ));
+        assert_ne!(
+            device.read(regs::STATUS, 4).unwrap() as u32 & STATUS_DEVICE_NEEDS_RESET,
+            0
+        );
+        // ドライバが Status を書き直してもリセットまでは残る
+        device.write(regs::STATUS, 0x0f, 4).unwrap();
+        assert_eq!(
+            device.read(regs::STATUS, 4).unwrap() as u32,
+            0x0f | STATUS_DEVICE_NEEDS_RESET
+        );
+
+        device.write(regs::STATUS, 0, 4).unwrap();
+        assert_eq!(device.queue_error(), None);
+    }
+
+    #[test]
+    fn test_missing_queue_is_not_available() {
+        let mut device = VirtioBlockDevice::new(0x0a00_0000);
+        device.write(regs::QUEUE_SEL, 1, 4).unwrap();
+        assert_eq!(device.read(regs::QUEUE_NUM_MAX, 4).unwrap(), 0);
+        device.write(regs::QUEUE_DESC_LOW, 0x4000_1000, 4).unwrap();
+        device.write(regs::QUEUE_READY, 1, 4).unwrap();
+        assert_eq!(device.read(regs::QUEUE_READY, 4).unwrap(), 0);
+        assert_eq!(device.queue_config(1), None);
+        assert_eq!(device.queue_addrs(), QueueAddrs::default());
+    }
+
+    #[test]
+    fn test_status_zero_resets_device() {
+        let disk = RamDisk::new(SECTOR_SIZE * 8);
+        let mut device = VirtioBlockDevice::with_backend(0x0a00_0000, Box::new(disk), 8);
+        device.write_sectors(1, &[0xab; SECTOR_SIZE]).unwrap();
+        device.write(regs::STATUS, 0x0f, 4).unwrap();
+        device.write(regs::QUEUE_SEL, 0, 4).unwrap();
+        device.write(regs::QUEUE_NUM, 4, 4).unwrap();
+        device.write(regs::DRIVER_FEATURES_SEL, 1, 4).unwrap();
+        device.write(regs::QUEUE_DESC_LOW, 0x4800_0000, 4).unwrap();
+        device.write(legacy_regs::QUEUE_PFN, 0x48000, 4).unwrap();
+        assert!(device.transport_error().is_some());
+
+        // ドライバのエラー回復: STATUS に 0 を書いて初期化をやり直す
+        device.write(regs::STATUS, 0, 4).unwrap();
+        assert_eq!(device.read(regs::STATUS, 4).unwrap(), 0);
+        assert_eq!(device.queue_config(0), Some(QueueConfig::new(16)));
+        assert_eq!(device.driver_features_sel, 0);
+        assert_eq!(device.queue_addrs(), QueueAddrs::default());
+        assert_eq!(device.transport_error(), None);
+
+        // ディスクの内容はホスト側のものなので残る
+        let mut buf = vec![0u8; SECTOR_SIZE];
+        device.read_sectors(1, &mut buf).unwrap();
+        assert_eq!(buf, [0xab; SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn test_capacity_change_raises_config_interrupt() {
+        let disk = RamDisk::new(SECTOR_SIZE * 8);
+        let mut device = VirtioBlockDevice::with_backend(0x0a00_0000, Box::new(disk), 8);
+        assert_eq!(device.read(regs::CONFIG, 4).unwrap(), 8);
+        assert_eq!(device.pending_irq(), None);
+
+        device.set_capacity(0x1_0000_0010);
+        assert_eq!(device.read(regs::CONFIG, 4).unwrap(), 0x10);
+        assert_eq!(device.read(regs::CONFIG + 4, 4).unwrap(), 1);
+        assert_eq!(
+            device.read(regs::INTERRUPT_STATUS, 4).unwrap() as u32,
+            INTERRUPT_CONFIG
+        );
+        assert_eq!(device.read(regs::CONFIG_GENERATION, 4).unwrap(), 1);
+        assert_eq!(device.pending_irq(), Some(IrqMap::QEMU_VIRT.virtio));
+        // 登録したときに受け取った割り込みでアサートする
+        device.attach_irq(IrqMap::QEMU_VIRT.virtio_slot(3));
+        assert_eq!(device.pending_irq(), Some(IrqMap::QEMU_VIRT.virtio_slot(3)));
+
+        // ドライバが ACK すると割り込み線が下がる
+        device
+            .write(regs::INTERRUPT_ACK, INTERRUPT_CONFIG as u64, 4)
+            .unwrap();
+        assert_eq!(device.read(regs::INTERRUPT_STATUS, 4).unwrap(), 0);
+        assert_eq!(device.pending_irq(), None);
+    }
+
+    #[test]
+    fn test_resize_grows_disk_online() {
+        let disk = RamDisk::new(SECTOR_SIZE * 8);
+        let mut device = VirtioBlockDevice::with_backend(0x0a00_0000, Box::new(disk.clone()), 8);
+        let mut buf = vec![0u8; SECTOR_SIZE];
+        assert!(device.read_sectors(8, &mut buf).is_err());
+
+        device.resize(16).unwrap();
+        assert_eq!(disk.len(), SECTOR_SIZE * 16);
+        assert_eq!(device.read(regs::CONFIG, 4).unwrap(), 16);
+        assert!(device.pending_irq().is_some());
+        device.write_sectors(15, &[0xcd; SECTOR_SIZE]).unwrap();
+        device.read_sectors(15, &mut buf).unwrap();
+        assert_eq!(buf, [0xcd; SECTOR_SIZE]);
+
+        // 縮小はゲストのデータを失うため拒否する
+        let err = device.resize(4).unwrap_err();
+        assert!(err.to_string().contains("Cannot shrink"));
+        assert_eq!(disk.len(), SECTOR_SIZE * 16);
+        assert!(VirtioBlockDevice::new(0).resize(1).is_err());
+    }
+
+    #[test]
+    fn test_resize_after_registering_the_device() {
+        use crate::mmio::{MmioManager, SharedDevice};
+
+        let disk = RamDisk::new(SECTOR_SIZE * 8);
+        let (device, handle) = SharedDevice::new(VirtioBlockDevice::with_backend(
+            0x0a00_0000,
+            Box::new(disk),
+            8,
+        ));
+        let mut mmio = MmioManager::new();
+        mmio.register(Box::new(device));
+
+        handle.lock().unwrap().resize(16).unwrap();
+        assert_eq!(mmio.handle_read(0x0a00_0000 + regs::CONFIG, 4).unwrap(), 16);
+        assert_eq!(mmio.pending_irqs().count(), 1);
+        assert_eq!(mmio.devices().next().unwrap().name(), "virtio-blk");
+    }
+
+    #[test]
+    fn test_legacy_register_on_modern_device_is_reported() {
+        let mut device = VirtioBlockDevice::new(0x0a00_0000);
+        device.write(legacy_regs::GUEST_PAGE_SIZE, 4096, 4).unwrap();
+        device.write(legacy_regs::QUEUE_PFN, 0x48000, 4).unwrap();
+
+        // 最初の書き込みが記録され、ドライバにはリセットが必要と通知される
+        let err = device.transport_error().unwrap();
+        assert_eq!(err.offset, legacy_regs::GUEST_PAGE_SIZE);
+        assert_ne!(
+            device.read(regs::STATUS, 4).unwrap() as u32 & STATUS_DEVICE_NEEDS_RESET,
+            0
+        );
+        assert_eq!(device.queue_addrs(), QueueAddrs::default());
+    }
+
+    #[test]
+    fn test_legacy_transport_layout() {
+        let mut device = VirtioBlockDevice::new(0x0a00_0000);
+        device.set_transport(TransportVersion::Legacy);
+        assert_eq!(device.read(regs::VERSION, 4).unwrap(), 1);
+
+        device.write(legacy_regs::GUEST_PAGE_SIZE, 4096, 4).unwrap();
+        device.write(regs::QUEUE_NUM, 8, 4).unwrap();
+        device.write(legacy_regs::QUEUE_ALIGN, 4096, 4).unwrap();
+        device.write(legacy_regs::QUEUE_PFN, 0x48000, 4).unwrap();
+
+        assert_eq!(device.transport_error(), None);
+        assert_eq!(device.read(legacy_regs::QUEUE_PFN, 4).unwrap(), 0x48000);
+        assert_eq!(
+            device.queue_addrs(),
+            QueueAddrs::legacy(0x48000, 4096, 4096, 8).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn test_device_state_round_trip() {
+        let mut device = VirtioBlockDevice::new(0x0a00_0000);
+        device.set_transport(TransportVersion::Legacy);
+        device.write(regs::STATUS, 0x7, 4).unwrap();
+        device.write(regs::QUEUE_NUM, 8, 4).unwrap();
+        device.write(legacy_regs::QUEUE_PFN, 0x48000, 4).unwrap();
+        let state = device.save_state();
+
+        let mut restored = VirtioBlockDevice::new(0x0a00_0000);
+        assert!(restored.restore_state(&state).is_err());
+        restored.set_transport(TransportVersion::Legacy);
+        restored.restore_state(&state).unwrap();
+        assert_eq!(restored.read(regs::STATUS, 4).unwrap(), 0x7);
+        assert_eq!(restored.queue_addrs(), device.queue_addrs());
+        assert!(restored.restore_state(&state[1..]).is_err());
+
+        assert_eq!(restored.queue_config(0), device.queue_config(0));
+
+        // QUEUE_READY と Features を追加する前の版の状態 (末尾のフィールドがない) も読める
+        let mut old = VirtioBlockDevice::new(0x0a00_0000);
+        old.set_transport(TransportVersion::Legacy);
+        old.restore_state(&state[..state.len() - 9]).unwrap();
+        assert!(old.queue_config(0).unwrap().ready);
+        old.restore_state(&state[..state.len() - 8]).unwrap();
+        assert_eq!(old.driver_features(), 0);
+
+        // 未 ACK の設定変更の割り込みも引き継ぐ
+        device.notify_config_changed();
+        restored.restore_state(&device.save_state()).unwrap();
+        assert!(restored.pending_irq().is_some());
+    }
+
+    #[test]
+    fn test_injected_disk_errors() {
+        let disk = RamDisk::new(SECTOR_SIZE * 8);
+        let mut device = VirtioBlockDevice::with_backend(0x0a00_0000, Box::new(disk), 8);
+        let faults = FaultInjector::new();
+        device.set_fault_injector(faults.clone());
+        faults.fail_disk_io(2..4, IoDirection::Write);
+
+        let data = vec![0u8; SECTOR_SIZE * 2];
+        let err = device.write_sectors(1, &data).unwrap_err();
+        assert!(err.to_string().contains("Injected I/O error"));
+        device.write_sectors(4, &data).unwrap();
+        let mut buf = vec![0u8; SECTOR_SIZE];
+        device.read_sectors(2, &mut buf).unwrap();
+        device.flush().unwrap();
+        assert_eq!(faults.injected().disk_errors, 1);
+
+        let stats = device.block_stats().unwrap();
+        assert_eq!(
+            (stats.reads, stats.writes, stats.flushes, stats.errors),
+            (1, 2, 1, 1)
+        );
+        assert_eq!(stats.latency.count, 4);
+        assert_eq!(stats.queue_depth, 0);
+        assert_eq!(
+            device.io_counters().unwrap().written_bytes,
+            data.len() as u64
+        );
+    }
+
+    const NEXT: u16 = 1;
+    const WRITE: u16 = 2;
+    const DESC_TABLE: u64 = 0x4000_0000;
+    const AVAIL_RING: u64 = 0x4000_0100;
+    const USED_RING: u64 = 0x4000_0200;
+
+    /// ゲスト RAM (0x4000_0000 から 64KB) にキューを置き、ドライバと同じ手順で確定する
+    fn attach_guest_queue(
+        device: &mut VirtioBlockDevice,
+    ) -> std::sync::Arc<crate::memory::testing::TestMemory> {
+        use crate::memory::testing::TestMemory;
+        use std::sync::Arc;
+
+        let mem = Arc::new(TestMemory::new(0x4000_0000, 0x10000));
+        device.set_dma_validator(DmaValidator::new(mem.clone()));
+        device.write(regs::QUEUE_SEL, 0, 4).unwrap();
+        device.write(regs::QUEUE_DESC_LOW, DESC_TABLE, 4).unwrap();
+        device.write(regs::QUEUE_DRIVER_LOW, AVAIL_RING, 4).unwrap();
+        device.write(regs::QUEUE_DEVICE_LOW, USED_RING, 4).unwrap();
+        device.write(regs::QUEUE_READY, 1, 4).unwrap();
+        mem
+    }
+
+    /// ドライバの代わりに記述子を書く
+    fn put_desc(mem: &dyn GuestMemory, index: u16, desc: Descriptor) {
+        let addr = DESC_TABLE + 16 * index as u64;
+        mem.write_u64(addr, desc.addr).unwrap();
+        mem.write_u32(addr + 8, desc.len).unwrap();
+        mem.write_u16(addr + 12, desc.flags).unwrap();
+        mem.write_u16(addr + 14, desc.next).unwrap();
+    }
+
+    /// ドライバの代わりにチェーンを Available Ring に追加する
+    fn submit(mem: &dyn GuestMemory, head: u16) {
+        let idx = mem.read_u16(AVAIL_RING + 2).unwrap();
+        mem.write_u16(AVAIL_RING + 4 + 2 * (idx % QUEUE_NUM_MAX) as u64, head)
+            .unwrap();
+        mem.write_u16(AVAIL_RING + 2, idx.wrapping_add(1)).unwrap();
+    }
+
+    /// ヘッダ・データ・ステータスの 3 つの記述子のリクエストを組み立てる
+    fn submit_request(mem: &dyn GuestMemory, type_: u32, sector: u64, data: Descriptor) {
+        mem.write_u32(0x4000_1000, type_).unwrap();
+        mem.write_u64(0x4000_1008, sector).unwrap();
+        put_desc(mem, 0, Descriptor::new(0x4000_1000, 16, NEXT, 1));
+        put_desc(mem, 1, Descriptor { next: 2, ..data });
+        put_desc(mem, 2, Descriptor::new(0x4000_2000, 1, WRITE, 0));
+        mem.write_u8(0x4000_2000, 0xff).unwrap();
+        submit(mem, 0);
+    }
+
+    #[test]
+    fn test_notify_on_a_queue_that_is_not_ready_is_ignored() {
+        use crate::memory::testing::TestMemory;
+        use std::sync::Arc;
+
+        // 検証器がなくても、キューが確定する前の通知はエラーにしない
+        let mut device = VirtioBlockDevice::with_backend(0, Box::new(RamDisk::new(SECTOR_SIZE)), 1);
+        assert!(device.process_queue().is_ok());
+
+        let mem = Arc::new(TestMemory::new(0x4000_0000, 0x10000));
+        device.attach_dma(DmaValidator::new(mem.clone()));
+        device.write(regs::QUEUE_DESC_LOW, DESC_TABLE, 4).unwrap();
+        device.write(regs::QUEUE_DRIVER_LOW, AVAIL_RING, 4).unwrap();
+        device.write(regs::QUEUE_DEVICE_LOW, USED_RING, 4).unwrap();
+        let data = Descriptor::new(0x4000_3000, SECTOR_SIZE as u32, NEXT | WRITE, 0);
+        submit_request(mem.as_ref(), VIRTIO_BLK_T_IN, 0, data);
+        device.write(regs::QUEUE_NOTIFY, 0, 4).unwrap();
+        assert_eq!(mem.read_u16(USED_RING + 2).unwrap(), 0);
+        assert!(device.pending_irq().is_none());
+
+        // 確定した後の通知で処理する
+        device.write(regs::QUEUE_READY, 1, 4).unwrap();
+        device.write(regs::QUEUE_NOTIFY, 0, 4).unwrap();
+        assert_eq!(mem.read_u16(USED_RING + 2).unwrap(), 1);
+        assert_eq!(mem.read_u8(0x4000_2000).unwrap(), VIRTIO_BLK_S_OK);
+    }
+
+    #[test]
+    fn test_guest_requests_read_and_write_the_disk() {
+        let disk = RamDisk::new(SECTOR_SIZE * 8);
+        let mut device = VirtioBlockDevice::with_backend(0x0a00_0000, Box::new(disk), 8);
+        let mem = attach_guest_queue(&mut device);
+
+        // 書き込み: ゲストの 0x4000_3000 の 2 セクタをセクタ 3 へ
+        mem.write_slice(&[0x5a; 2 * SECTOR_SIZE], 0x4000_3000)
+            .unwrap();
+        let data = Descriptor::new(0x4000_3000, 2 * SECTOR_SIZE as u32, NEXT, 0);
+        submit_request(mem.as_ref(), VIRTIO_BLK_T_OUT, 3, data);
+        device.write(regs::QUEUE_NOTIFY, 0, 4).unwrap();
+        assert_eq!(mem.read_u8(0x4000_2000).unwrap(), VIRTIO_BLK_S_OK);
+        assert_eq!(mem.read_u16(USED_RING + 2).unwrap(), 1);
+        assert_eq!(mem.read_u32(USED_RING + 8).unwrap(), 1);
+        assert!(device.pending_irq().is_some());
+
+        // 読み取り: セクタ 4 をゲストの 0x4000_4000 へ
+        let data = Descriptor::new(0x4000_4000, SECTOR_SIZE as u32, NEXT | WRITE, 0);
+        submit_request(mem.as_ref(), VIRTIO_BLK_T_IN, 4, data);
+        device.write(regs::QUEUE_NOTIFY, 0, 4).unwrap();
+        assert_eq!(mem.read_u8(0x4000_2000).unwrap(), VIRTIO_BLK_S_OK);
+        let mut buf = [0u8; SECTOR_SIZE];
+        mem.read_slice(&mut buf, 0x4000_4000).unwrap();
+        assert_eq!(buf, [0x5a; SECTOR_SIZE]);
+        assert_eq!(mem.read_u16(USED_RING + 2).unwrap(), 2);
+        assert_eq!(
+            mem.read_u32(USED_RING + 4 + 8 + 4).unwrap(),
+            SECTOR_SIZE as u32 + 1
+        );
+
+        // 範囲外のセクタと未対応のリクエスト
+        submit_request(mem.as_ref(), VIRTIO_BLK_T_IN, 8, data);
+        device.write(regs::QUEUE_NOTIFY, 0, 4).unwrap();
+        assert_eq!(mem.read_u8(0x4000_2000).unwrap(), VIRTIO_BLK_S_IOERR);
+        submit_request(mem.as_ref(), 8, 0, data);
+        device.write(regs::QUEUE_NOTIFY, 0, 4).unwrap();
+        assert_eq!(mem.read_u8(0x4000_2000).unwrap(), VIRTIO_BLK_S_UNSUPP);
+
+        let stats = device.block_stats().unwrap();
+        assert_eq!((stats.reads, stats.writes, stats.errors), (2, 1, 1));
+        assert_eq!(stats.queue_depth, 0);
+
+        // 通知前のリクエストはゲストのリングから数える
+        submit_request(mem.as_ref(), VIRTIO_BLK_T_IN, 4, data);
+        assert_eq!(device.block_stats().unwrap().queue_depth, 1);
+        // ホストからの読み書きも同じカウンタに数える
+        device.read_sectors(0, &mut buf).unwrap();
+        device.flush().unwrap();
+        let stats = device.block_stats().unwrap();
+        assert_eq!((stats.reads, stats.flushes), (3, 1));
+    }
+
+    #[test]
+    fn test_requests_beyond_the_disk_are_rejected() {
+        let disk = RamDisk::new(SECTOR_SIZE * 8);
+        let mut device = VirtioBlockDevice::with_backend(0x0a00_0000, Box::new(disk.clone()), 8);
+        let mem = attach_guest_queue(&mut device);
+        mem.write_slice(&[0x5a; 2 * SECTOR_SIZE], 0x4000_3000)
+            .unwrap();
+        let data = Descriptor::new(0x4000_3000, 2 * SECTOR_SIZE as u32, NEXT, 0);
+
+        // 末尾より後から始まる書き込み (セクタ番号の計算があふれるものも)
+        for sector in [9, u64::MAX / SECTOR_SIZE as u64, u64::MAX] {
+            submit_request(mem.as_ref(), VIRTIO_BLK_T_OUT, sector, data);
+            device.write(regs::QUEUE_NOTIFY, 0, 4).unwrap();
+            assert_eq!(mem.read_u8(0x4000_2000).unwrap(), VIRTIO_BLK_S_IOERR);
+        }
+        assert_eq!(disk.len(), SECTOR_SIZE * 8);
+
+        // 末尾をまたぐ書き込みは前半も書かない
+        submit_request(mem.as_ref(), VIRTIO_BLK_T_OUT, 7, data);
+        device.write(regs::QUEUE_NOTIFY, 0, 4).unwrap();
+        assert_eq!(mem.read_u8(0x4000_2000).unwrap(), VIRTIO_BLK_S_IOERR);
+        assert!(disk.contents().iter().all(|&b| b == 0));
+
+        // 読み取りも同じ
+        let data = Descriptor::new(0x4000_4000, 2 * SECTOR_SIZE as u32, NEXT | WRITE, 0);
+        submit_request(mem.as_ref(), VIRTIO_BLK_T_IN, 7, data);
+        device.write(regs::QUEUE_NOTIFY, 0, 4).unwrap();
+        assert_eq!(mem.read_u8(0x4000_2000).unwrap(), VIRTIO_BLK_S_IOERR);
+        assert_eq!(mem.read_u32(USED_RING + 4 + 4 * 8 + 4).unwrap(), 1);
+        assert_eq!(device.block_stats().unwrap().errors, 5);
+    }
+
+    #[test]
+    fn test_segments_are_capped_at_size_max() {
+        let mut device = VirtioBlockDevice::new(0x0a00_0000);
+        assert_eq!(
+            device.read(CONFIG_SIZE_MAX, 4).unwrap(),
+            MAX_SEGMENT_SIZE as u64
+        );
+
+        // size_max までは受け付け、それより大きい記述子は確保する前に拒否する
+        let desc = Descriptor::new(0x4000_3000, MAX_SEGMENT_SIZE, NEXT | WRITE, 0);
+        assert!(VirtioBlockDevice::check_data_desc(&desc, true).is_ok());
+        let desc = Descriptor::new(0x4000_3000, MAX_SEGMENT_SIZE + 512, NEXT | WRITE, 0);
+        assert!(VirtioBlockDevice::check_data_desc(&desc, true).is_err());
+        let desc = Descriptor::new(0x4000_3000, u32::MAX - 511, NEXT, 0);
+        assert!(VirtioBlockDevice::check_data_desc(&desc, false).is_err());
+    }
+
+    #[test]
+    fn test_file_disk_does_not_grow_past_its_capacity() {
+        let path = "/tmp/test_virtio_disk_capacity.img";
         let file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -339,13 +1625,40 @@ mod tests {
             .truncate(true)
             .open(path)
             .unwrap();
+        file.set_len(4 * SECTOR_SIZE as u64).unwrap();
+        let mut device = VirtioBlockDevice::with_disk_image(0x0a00_0000, file, 4);
+
+        assert!(device.write_sectors(4, &[0xaa; SECTOR_SIZE]).is_err());
+        assert!(device.write_sectors(3, &[0xaa; 2 * SECTOR_SIZE]).is_err());
+        assert_eq!(
+            std::fs::metadata(path).unwrap().len(),
+            4 * SECTOR_SIZE as u64
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_out_of_bounds_descriptor_is_rejected_with_ioerr() {
+        let mut device = VirtioBlockDevice::new(0x0a00_0000);
+        let mem = attach_guest_queue(&mut device);
+
+        // Data buffer points into host memory beyond guest RAM
+        let data = Descriptor::new(0x4001_0000, 512, NEXT | WRITE, 0);
+        submit_request(mem.as_ref(), VIRTIO_BLK_T_IN, 0, data);
+        device.write(regs::QUEUE_NOTIFY, 0, 4).unwrap();
 
-        // ディスクサイズを 1MB に設定
-        file.set_len(1024 * 1024).unwrap();
+        assert_eq!(device.rejected_requests(), 1);
+        assert_eq!(mem.read_u8(0x4000_2000).unwrap(), VIRTIO_BLK_S_IOERR);
+        assert_eq!(mem.read_u16(USED_RING + 2).unwrap(), 1);
+    }
 
-        // VirtioBlockDevice を作成
+    #[test]
+    fn test_write_and_read_sectors() {
+        // 1MB のディスクを作成
+        let disk = RamDisk::new(1024 * 1024);
         let capacity = 1024 * 1024 / SECTOR_SIZE as u64;
-        let mut device = VirtioBlockDevice::with_disk_image(0x0a00_0000, file, capacity);
+        let mut device =
+            VirtioBlockDevice::with_backend(0x0a00_0000, Box::new(disk.clone()), capacity);
 
         // テストデータを作成（512 bytes）
         let mut write_data = vec![0u8; SECTOR_SIZE];
@@ -362,15 +1675,61 @@ mod tests {
 
         // 読み取ったデータを検証
         assert_eq!(write_data, read_data);
+        assert_eq!(
+            device.io_counters(),
+            Some(IoCounters {
+                read_bytes: SECTOR_SIZE as u64,
+                written_bytes: SECTOR_SIZE as u64,
+            })
+        );
+        assert_eq!(disk.contents()[..SECTOR_SIZE], write_data[..]);
+    }
+
+    #[test]
+    fn test_open_verified_rejects_unlisted_disk() {
+        use crate::boot::verify::{sha256, to_hex, Sha256AllowList};
+
+        let path = "/tmp/test_virtio_disk_verified.img";
+        std::fs::write(path, [0x5a; 2 * SECTOR_SIZE]).unwrap();
+        let digest = to_hex(&sha256(&[0x5a; 2 * SECTOR_SIZE]));
+
+        let allow = Sha256AllowList::new().allow(&digest).unwrap();
+        let mut device = VirtioBlockDevice::open_verified(0x0a00_0000, path, true, &allow).unwrap();
+        assert_eq!(device.read(regs::CONFIG, 4).unwrap(), 2);
+        assert_eq!(
+            device.read(regs::DEVICE_FEATURES, 4).unwrap(),
+            VIRTIO_BLK_F_RO | VIRTIO_BLK_F_SIZE_MAX
+        );
+        device
+            .write(regs::DRIVER_FEATURES, VIRTIO_BLK_F_RO, 4)
+            .unwrap();
+        assert_eq!(device.driver_features(), VIRTIO_BLK_F_RO);
+
+        // 検証した fd から読ませ、ゲストの書き込みは拒否する
+        let mem = attach_guest_queue(&mut device);
+        let data = Descriptor::new(0x4000_3000, SECTOR_SIZE as u32, NEXT, 0);
+        submit_request(mem.as_ref(), VIRTIO_BLK_T_OUT, 0, data);
+        device.write(regs::QUEUE_NOTIFY, 0, 4).unwrap();
+        assert_eq!(mem.read_u8(0x4000_2000).unwrap(), VIRTIO_BLK_S_IOERR);
+        assert_eq!(std::fs::read(path).unwrap(), [0x5a; 2 * SECTOR_SIZE]);
+        // 書き込み可能で開くには排他ロックが要る
+        assert!(VirtioBlockDevice::open_verified(0, path, false, &allow).is_err());
+        drop(device);
+        assert!(VirtioBlockDevice::open_verified(0, path, false, &allow).is_ok());
 
-        // クリーンアップ
+        let err = VirtioBlockDevice::open_verified(0, path, true, &Sha256AllowList::new())
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("disk /tmp/test_virtio_disk_verified.img"));
         std::fs::remove_file(path).unwrap();
     }
 
     #[test]
-    fn test_read_write_multiple_sectors() {
-        // テスト用ディスクイメージを作成
-        let path = "/tmp/test_virtio_disk_multi.img";
+    fn test_snapshot_keeps_disk_contents_at_that_point() {
+        let path = "/tmp/test_virtio_disk_snapshot.img";
+        let snapshot = "/tmp/test_virtio_disk_snapshot.snap";
         let file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -378,13 +1737,38 @@ mod tests {
             .truncate(true)
             .open(path)
             .unwrap();
+        file.set_len(4 * SECTOR_SIZE as u64).unwrap();
+        let mut device = VirtioBlockDevice::with_disk_image(0x0a00_0000, file, 4);
+
+        assert!(VirtioBlockDevice::new(0).snapshot_to(snapshot).is_err());
+
+        device.write_sectors(1, &[0xaa; SECTOR_SIZE]).unwrap();
+        device.snapshot_to(snapshot).unwrap();
+        device.write_sectors(1, &[0x55; SECTOR_SIZE]).unwrap();
 
-        // ディスクサイズを 1MB に設定
-        file.set_len(1024 * 1024).unwrap();
+        let saved = std::fs::read(snapshot).unwrap();
+        assert_eq!(saved.len(), 4 * SECTOR_SIZE);
+        assert!(saved[SECTOR_SIZE..2 * SECTOR_SIZE]
+            .iter()
+            .all(|&b| b == 0xaa));
 
-        // VirtioBlockDevice を作成
+        // 既存のスナップショットは置き換える
+        device.snapshot_to(snapshot).unwrap();
+        let saved = std::fs::read(snapshot).unwrap();
+        assert!(saved[SECTOR_SIZE..2 * SECTOR_SIZE]
+            .iter()
+            .all(|&b| b == 0x55));
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(snapshot).unwrap();
+    }
+
+    #[test]
+    fn test_read_write_multiple_sectors() {
+        // 1MB のディスクを作成
+        let disk = RamDisk::new(1024 * 1024);
         let capacity = 1024 * 1024 / SECTOR_SIZE as u64;
-        let mut device = VirtioBlockDevice::with_disk_image(0x0a00_0000, file, capacity);
+        let mut device = VirtioBlockDevice::with_backend(0x0a00_0000, Box::new(disk), capacity);
 
         // テストデータを作成（1024 bytes = 2 セクタ）
         let mut write_data = vec![0u8; SECTOR_SIZE * 2];
@@ -412,7 +1796,21 @@ mod tests {
         // 読み取ったデータを検証
         assert_eq!(write_data, read_data);
 
-        // クリーンアップ
-        std::fs::remove_file(path).unwrap();
+        // ディスクの末尾を超える読み取りはエラー
+        assert!(device.read_sectors(capacity, &mut read_data).is_err());
+    }
+
+    #[test]
+    fn test_ram_disk_snapshot() {
+        let snapshot = "/tmp/test_virtio_ram_disk.snap";
+        let disk = RamDisk::from_bytes(vec![0x11; 2 * SECTOR_SIZE]);
+        let mut device = VirtioBlockDevice::with_backend(0x0a00_0000, Box::new(disk), 2);
+        device.write_sectors(1, &[0x22; SECTOR_SIZE]).unwrap();
+        device.snapshot_to(snapshot).unwrap();
+
+        let saved = std::fs::read(snapshot).unwrap();
+        assert!(saved[..SECTOR_SIZE].iter().all(|&b| b == 0x11));
+        assert!(saved[SECTOR_SIZE..].iter().all(|&b| b == 0x22));
+        std::fs::remove_file(snapshot).unwrap();
     }
 }
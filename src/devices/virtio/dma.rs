@@ -0,0 +1,157 @@
+//! ゲストの DMA アドレスの検証
+//!
+//! virtio の記述子はゲストが自由に書けるため、アドレスと長さをそのまま使うと
+//! ホストのメモリ範囲外を読み書きしてしまう。デバイスは記述子チェーンを辿る前に
+//! [`DmaValidator`] ですべての記述子を検証し、1 つでも不正なら I/O エラーとして扱う。
+
+use crate::memory::GuestMemory;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// 記述子が不正な理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaErrorKind {
+    /// `addr + len` が 64 bit を超える
+    Overflow,
+    /// MMIO 領域と重なる
+    MmioHole,
+    /// ゲスト RAM の外
+    OutsideRam,
+    /// チェーンの `next` がキューの範囲外
+    InvalidNext,
+    /// チェーンがキューサイズより長い (ループしている)
+    ChainLoop,
+}
+
+impl fmt::Display for DmaErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            DmaErrorKind::Overflow => "address overflow",
+            DmaErrorKind::MmioHole => "overlaps MMIO hole",
+            DmaErrorKind::OutsideRam => "outside guest RAM",
+            DmaErrorKind::InvalidNext => "invalid next index",
+            DmaErrorKind::ChainLoop => "descriptor chain loop",
+        };
+        f.write_str(s)
+    }
+}
+
+/// 拒否した記述子
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaError {
+    /// チェーンの先頭の記述子インデックス
+    pub head: u16,
+    /// 不正だった記述子のインデックス
+    pub index: u16,
+    /// 記述子のアドレス
+    pub addr: u64,
+    /// 記述子の長さ
+    pub len: u32,
+    /// 理由
+    pub kind: DmaErrorKind,
+}
+
+impl fmt::Display for DmaError {
+    /// `key=value` 形式で 1 行にまとめる (ログの集計用)
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rejected descriptor chain head={} desc={} addr=0x{:x} len={} reason=\"{}\"",
+            self.head, self.index, self.addr, self.len, self.kind
+        )
+    }
+}
+
+impl std::error::Error for DmaError {}
+
+/// デバイスが DMA でアクセスしてよい範囲
+#[derive(Clone)]
+pub struct DmaValidator {
+    mem: Arc<dyn GuestMemory>,
+    mmio_holes: Vec<Range<u64>>,
+}
+
+impl DmaValidator {
+    /// ゲスト RAM (`Hypervisor::guest_memory`) の範囲だけを許可する
+    pub fn new(mem: Arc<dyn GuestMemory>) -> Self {
+        Self {
+            mem,
+            mmio_holes: Vec::new(),
+        }
+    }
+
+    /// RAM と重ねて配置した MMIO 領域などを明示的に拒否する
+    pub fn with_mmio_hole(mut self, hole: Range<u64>) -> Self {
+        self.mmio_holes.push(hole);
+        self
+    }
+
+    /// 検証に使うゲストメモリ
+    pub fn memory(&self) -> &Arc<dyn GuestMemory> {
+        &self.mem
+    }
+
+    /// `addr` から `len` バイトへのアクセスを検証する
+    pub fn check(&self, addr: u64, len: u32) -> Result<(), DmaErrorKind> {
+        let end = addr.checked_add(len as u64).ok_or(DmaErrorKind::Overflow)?;
+        if self
+            .mmio_holes
+            .iter()
+            .any(|hole| addr < hole.end && hole.start < end.max(addr.saturating_add(1)))
+        {
+            return Err(DmaErrorKind::MmioHole);
+        }
+        if !self.mem.check_range(addr, len as usize) {
+            return Err(DmaErrorKind::OutsideRam);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::testing::TestMemory;
+
+    fn validator() -> DmaValidator {
+        DmaValidator::new(Arc::new(TestMemory::new(0x4000_0000, 0x10000)))
+            .with_mmio_hole(0x4000_8000..0x4000_9000)
+    }
+
+    #[test]
+    fn ram_内のアクセスだけを許可する() {
+        let dma = validator();
+        assert_eq!(dma.check(0x4000_0000, 512), Ok(()));
+        assert_eq!(dma.check(0x4000_f000, 0x1000), Ok(()));
+        assert_eq!(
+            dma.check(0x4000_f000, 0x1001),
+            Err(DmaErrorKind::OutsideRam)
+        );
+        assert_eq!(dma.check(0x0900_0000, 4), Err(DmaErrorKind::OutsideRam));
+        assert_eq!(dma.check(u64::MAX - 4, 16), Err(DmaErrorKind::Overflow));
+    }
+
+    #[test]
+    fn mmio_領域と重なるアクセスは拒否する() {
+        let dma = validator();
+        assert_eq!(dma.check(0x4000_7f00, 0x200), Err(DmaErrorKind::MmioHole));
+        assert_eq!(dma.check(0x4000_8800, 0), Err(DmaErrorKind::MmioHole));
+        assert_eq!(dma.check(0x4000_7f00, 0x100), Ok(()));
+    }
+
+    #[test]
+    fn 拒否した記述子を1行のログにする() {
+        let err = DmaError {
+            head: 3,
+            index: 4,
+            addr: 0x1000,
+            len: 512,
+            kind: DmaErrorKind::OutsideRam,
+        };
+        assert_eq!(
+            err.to_string(),
+            "rejected descriptor chain head=3 desc=4 addr=0x1000 len=512 reason=\"outside guest RAM\""
+        );
+    }
+}
@@ -2,8 +2,28 @@
 //!
 //! VirtIO 1.2 仕様に基づいた仮想 I/O デバイスの実装。
 
+#[cfg(feature = "virtio-blk")]
+pub mod backend;
+#[cfg(feature = "virtio-blk")]
 pub mod block;
+pub mod dma;
+#[cfg(feature = "virtio-blk")]
+pub mod nbd;
+pub mod net;
 pub mod queue;
+pub mod slot;
+pub mod transport;
+pub mod user_net;
 
+#[cfg(feature = "virtio-blk")]
+pub use backend::{BlockBackend, RamDisk};
+#[cfg(feature = "virtio-blk")]
 pub use block::VirtioBlockDevice;
-pub use queue::{Descriptor, VirtQueue};
+pub use dma::DmaValidator;
+#[cfg(feature = "virtio-blk")]
+pub use nbd::NbdDisk;
+pub use net::{NetBackend, VirtioNetDevice};
+pub use queue::{Descriptor, GuestQueue, VirtQueue};
+pub use slot::{VirtioMmioSlot, VirtioSlotHandle};
+pub use transport::TransportVersion;
+pub use user_net::UserNet;
@@ -0,0 +1,365 @@
+//! NBD (Network Block Device) のクライアント
+//!
+//! リモートの NBD サーバー (`qemu-nbd`, `nbdkit` など) が公開するイメージを
+//! [`BlockBackend`] として使う。CI のキャッシュサーバーに置いたイメージから
+//! ゲストを起動する用途を想定している。
+//!
+//! fixed newstyle のハンドシェイクで `NBD_OPT_EXPORT_NAME` を送り、以降は
+//! simple reply でリクエストを 1 つずつ処理する。
+//!
+//! ```ignore
+//! let disk = NbdDisk::connect("cache.example.com:10809", "rootfs")?;
+//! let capacity = disk.size() / 512;
+//! hv.register_mmio_handler(Box::new(VirtioBlockDevice::with_backend(base, Box::new(disk), capacity)))?;
+//! ```
+
+use super::backend::BlockBackend;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943; // "NBDMAGIC"
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054; // "IHAVEOPT"
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+/// ハンドシェイクのフラグ
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+
+const OPT_EXPORT_NAME: u32 = 1;
+
+/// エクスポートのフラグ
+const FLAG_READ_ONLY: u16 = 1 << 1;
+const FLAG_SEND_FLUSH: u16 = 1 << 2;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+
+/// 応答のエラー値 (プロトコルで Linux の errno の値に固定されている)
+const NBD_EPERM: u32 = 1;
+const NBD_EIO: u32 = 5;
+const NBD_ENOMEM: u32 = 12;
+const NBD_EINVAL: u32 = 22;
+const NBD_ENOSPC: u32 = 28;
+const NBD_EOVERFLOW: u32 = 75;
+const NBD_ENOTSUP: u32 = 95;
+const NBD_ESHUTDOWN: u32 = 108;
+
+/// 1 リクエストで転送する最大サイズ (多くのサーバーの上限は 32 MiB)
+const MAX_REQUEST: usize = 1 << 20;
+
+/// NBD サーバーのエクスポート
+pub struct NbdDisk {
+    stream: TcpStream,
+    size: u64,
+    flags: u16,
+    next_handle: u64,
+}
+
+impl NbdDisk {
+    /// サーバーに接続し、`export` をエクスポート名として開く
+    pub fn connect(addr: impl ToSocketAddrs, export: &str) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        if read_u64(&mut stream)? != NBD_MAGIC || read_u64(&mut stream)? != IHAVEOPT {
+            return Err(protocol_error("server does not speak newstyle NBD"));
+        }
+        let server_flags = read_u16(&mut stream)?;
+        if server_flags & FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(protocol_error("server does not support fixed newstyle"));
+        }
+        let client_flags = (server_flags & (FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES)) as u32;
+
+        let mut msg = Vec::with_capacity(20 + export.len());
+        msg.extend_from_slice(&client_flags.to_be_bytes());
+        msg.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        msg.extend_from_slice(&OPT_EXPORT_NAME.to_be_bytes());
+        msg.extend_from_slice(&(export.len() as u32).to_be_bytes());
+        msg.extend_from_slice(export.as_bytes());
+        stream.write_all(&msg)?;
+
+        // 未知のエクスポート名ではサーバーが接続を閉じる
+        let size = read_u64(&mut stream).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("NBD server rejected export {:?}: {}", export, e),
+            )
+        })?;
+        let flags = read_u16(&mut stream)?;
+        if server_flags & FLAG_NO_ZEROES == 0 {
+            stream.read_exact(&mut [0; 124])?;
+        }
+
+        Ok(Self {
+            stream,
+            size,
+            flags,
+            next_handle: 1,
+        })
+    }
+
+    /// エクスポートのサイズ (bytes)
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// 読み取り専用のエクスポートか
+    pub fn is_read_only(&self) -> bool {
+        self.flags & FLAG_READ_ONLY != 0
+    }
+
+    /// リクエストを 1 つ送り、応答のヘッダを受け取る
+    fn request(&mut self, cmd: u16, offset: u64, len: u32, data: &[u8]) -> io::Result<()> {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+
+        let mut msg = Vec::with_capacity(28 + data.len());
+        msg.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        msg.extend_from_slice(&0u16.to_be_bytes());
+        msg.extend_from_slice(&cmd.to_be_bytes());
+        msg.extend_from_slice(&handle.to_be_bytes());
+        msg.extend_from_slice(&offset.to_be_bytes());
+        msg.extend_from_slice(&len.to_be_bytes());
+        msg.extend_from_slice(data);
+        self.stream.write_all(&msg)?;
+
+        if read_u32(&mut self.stream)? != SIMPLE_REPLY_MAGIC {
+            return Err(protocol_error("unexpected reply magic"));
+        }
+        let error = read_u32(&mut self.stream)?;
+        if read_u64(&mut self.stream)? != handle {
+            return Err(protocol_error("reply handle does not match the request"));
+        }
+        if error != 0 {
+            return Err(reply_error(error));
+        }
+        Ok(())
+    }
+
+    /// 範囲がエクスポートに収まっているか
+    fn check_range(&self, offset: u64, len: usize) -> io::Result<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "NBD access at 0x{:x}+0x{:x} is beyond the export size 0x{:x}",
+                    offset, len, self.size
+                ),
+            )),
+        }
+    }
+}
+
+impl BlockBackend for NbdDisk {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.check_range(offset, buf.len())?;
+        let mut pos = offset;
+        for chunk in buf.chunks_mut(MAX_REQUEST) {
+            self.request(CMD_READ, pos, chunk.len() as u32, &[])?;
+            self.stream.read_exact(chunk)?;
+            pos += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if self.is_read_only() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "NBD export is read-only",
+            ));
+        }
+        self.check_range(offset, data.len())?;
+        let mut pos = offset;
+        for chunk in data.chunks(MAX_REQUEST) {
+            self.request(CMD_WRITE, pos, chunk.len() as u32, chunk)?;
+            pos += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.flags & FLAG_SEND_FLUSH == 0 {
+            return Ok(());
+        }
+        self.request(CMD_FLUSH, 0, 0, &[])
+    }
+
+    /// エクスポート全体を読み出してローカルのファイルに書く
+    fn snapshot_to(&mut self, path: &Path) -> io::Result<()> {
+        self.flush()?;
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut dst = BufWriter::new(File::create(path)?);
+        let mut buf = vec![0; MAX_REQUEST];
+        let mut pos = 0;
+        while pos < self.size {
+            let len = (self.size - pos).min(MAX_REQUEST as u64) as usize;
+            self.read_at(pos, &mut buf[..len])?;
+            dst.write_all(&buf[..len])?;
+            pos += len as u64;
+        }
+        dst.into_inner()?.sync_all()
+    }
+}
+
+impl Drop for NbdDisk {
+    /// サーバーに切断を通知する (NBD_CMD_DISC には応答がない)
+    fn drop(&mut self) {
+        let mut msg = Vec::with_capacity(28);
+        msg.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        msg.extend_from_slice(&0u16.to_be_bytes());
+        msg.extend_from_slice(&CMD_DISC.to_be_bytes());
+        msg.extend_from_slice(&[0; 20]);
+        let _ = self.stream.write_all(&msg);
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("NBD: {}", message))
+}
+
+/// 応答のエラー値を変換する
+///
+/// 値は Linux の errno なので、ホスト (macOS) の errno としては解釈しない。
+fn reply_error(error: u32) -> io::Error {
+    let (kind, name) = match error {
+        NBD_EPERM => (io::ErrorKind::PermissionDenied, "EPERM"),
+        NBD_EIO => (io::ErrorKind::Other, "EIO"),
+        NBD_ENOMEM => (io::ErrorKind::OutOfMemory, "ENOMEM"),
+        NBD_EINVAL => (io::ErrorKind::InvalidInput, "EINVAL"),
+        NBD_ENOSPC => (io::ErrorKind::StorageFull, "ENOSPC"),
+        NBD_EOVERFLOW => (io::ErrorKind::InvalidInput, "EOVERFLOW"),
+        NBD_ENOTSUP => (io::ErrorKind::Unsupported, "ENOTSUP"),
+        NBD_ESHUTDOWN => (io::ErrorKind::ConnectionAborted, "ESHUTDOWN"),
+        _ => {
+            return io::Error::other(format!("NBD: server returned unknown error {}", error));
+        }
+    };
+    io::Error::new(kind, format!("NBD: server returned {}", name))
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut b = [0; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_be_bytes(b))
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_be_bytes(b))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut b = [0; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_be_bytes(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// 1 接続だけ処理する最小限の NBD サーバー
+    fn serve_one(mut disk: Vec<u8>, flags: u16) -> (u16, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            s.write_all(&NBD_MAGIC.to_be_bytes()).unwrap();
+            s.write_all(&IHAVEOPT.to_be_bytes()).unwrap();
+            s.write_all(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes())
+                .unwrap();
+            assert_eq!(read_u32(&mut s).unwrap(), 3);
+            assert_eq!(read_u64(&mut s).unwrap(), IHAVEOPT);
+            assert_eq!(read_u32(&mut s).unwrap(), OPT_EXPORT_NAME);
+            let mut name = vec![0; read_u32(&mut s).unwrap() as usize];
+            s.read_exact(&mut name).unwrap();
+            assert_eq!(name, b"disk");
+            s.write_all(&(disk.len() as u64).to_be_bytes()).unwrap();
+            s.write_all(&flags.to_be_bytes()).unwrap();
+
+            loop {
+                assert_eq!(read_u32(&mut s).unwrap(), REQUEST_MAGIC);
+                let _ = read_u16(&mut s).unwrap();
+                let cmd = read_u16(&mut s).unwrap();
+                let handle = read_u64(&mut s).unwrap();
+                let offset = read_u64(&mut s).unwrap() as usize;
+                let len = read_u32(&mut s).unwrap() as usize;
+                let mut reply = SIMPLE_REPLY_MAGIC.to_be_bytes().to_vec();
+                reply.extend_from_slice(&0u32.to_be_bytes());
+                reply.extend_from_slice(&handle.to_be_bytes());
+                match cmd {
+                    CMD_READ => reply.extend_from_slice(&disk[offset..offset + len]),
+                    CMD_WRITE => s.read_exact(&mut disk[offset..offset + len]).unwrap(),
+                    CMD_FLUSH => {}
+                    _ => return disk,
+                }
+                s.write_all(&reply).unwrap();
+            }
+        });
+        (port, server)
+    }
+
+    #[test]
+    fn nbd_サーバーのエクスポートを読み書きできる() {
+        let (port, server) = serve_one(vec![0x11; 4096], FLAG_SEND_FLUSH);
+        let mut disk = NbdDisk::connect(("127.0.0.1", port), "disk").unwrap();
+        assert_eq!(disk.size(), 4096);
+        assert!(!disk.is_read_only());
+
+        disk.write_at(512, &[0x22; 512]).unwrap();
+        disk.flush().unwrap();
+        let mut buf = [0; 1024];
+        disk.read_at(0, &mut buf).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 0x11));
+        assert!(buf[512..].iter().all(|&b| b == 0x22));
+        assert!(disk.read_at(4000, &mut buf).is_err());
+
+        drop(disk);
+        let served = server.join().unwrap();
+        assert_eq!(served[512], 0x22);
+    }
+
+    #[test]
+    fn 応答のエラーは_linux_の_errno_として変換する() {
+        let err = reply_error(NBD_ENOSPC);
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(err.to_string(), "NBD: server returned ENOSPC");
+        // macOS の ENOTSUP は 45 で、95 をホストの errno として読むと別のエラーになる
+        assert_eq!(reply_error(NBD_ENOTSUP).kind(), io::ErrorKind::Unsupported);
+        assert_eq!(
+            reply_error(NBD_EPERM).kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(reply_error(NBD_EINVAL).kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            reply_error(1000).to_string(),
+            "NBD: server returned unknown error 1000"
+        );
+    }
+
+    #[test]
+    fn 読み取り専用のエクスポートには書き込めない() {
+        let (port, server) = serve_one(vec![0; 512], FLAG_READ_ONLY);
+        let mut disk = NbdDisk::connect(("127.0.0.1", port), "disk").unwrap();
+        assert!(disk.is_read_only());
+        let err = disk.write_at(0, &[1; 512]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        // SEND_FLUSH がなければ flush は何もしない
+        disk.flush().unwrap();
+        drop(disk);
+        assert_eq!(server.join().unwrap(), vec![0; 512]);
+    }
+}
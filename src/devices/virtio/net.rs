@@ -0,0 +1,981 @@
+//! VirtIO Net デバイス実装
+//!
+//! VirtIO 1.2 仕様 §5.1 に基づいた Network デバイスのエミュレーション。
+//! 受信 (queue 0) と送信 (queue 1) の 2 つのキューを持ち、modern
+//! (virtio-mmio version 2) のトランスポートだけに対応する。
+//!
+//! テストからフェイルオーバーのシナリオを組めるよう、ゲストの MAC アドレス
+//! (`VIRTIO_NET_F_MAC`)、リンクの up/down (`VIRTIO_NET_F_STATUS`)、MTU
+//! (`VIRTIO_NET_F_MTU`) をホストから設定できる。リンク状態の変化は設定変更の
+//! 割り込みでゲストに知らせる。
+//!
+//! ホストのネットワークにつなぐバックエンド (TAP など) はまだないが、
+//! [`NetBackend`] を設定するとゲストの送信フレームに応答できる
+//! ([`UserNet`](super::user_net::UserNet) は DHCP / TFTP でネットワークブートさせる)。
+//! バックエンドがなければ、テストはプロセス内で Ethernet フレームを直接やり取りできる。[`VirtioNetDevice::send_frame`] はゲストにフレームを
+//! 受信させ、[`VirtioNetDevice::recv_frame`] はゲストが送信したフレームを取り出す。
+//! 登録した後に呼ぶには [`SharedDevice`](crate::mmio::SharedDevice) で包んで登録する。
+//!
+//! ```ignore
+//! let (net, handle) = SharedDevice::new(VirtioNetDevice::new(base, mac));
+//! hv.register_mmio_handler(Box::new(net))?;
+//! // run ループの合間に ARP 要求を送り、応答を待つ
+//! handle.lock().unwrap().send_frame(&arp_request)?;
+//! let reply = handle.lock().unwrap().recv_frame();
+//! ```
+
+use crate::boot::layout::IrqMap;
+use crate::devices::fault::FaultInjector;
+use crate::devices::virtio::dma::DmaValidator;
+use crate::devices::virtio::transport::{
+    configurable_queue, latch_queue, regs, InterruptState, QueueConfig, QueueConfigError,
+    StatusWrite, STATUS_DEVICE_NEEDS_RESET, VIRTIO_F_VERSION_1, VIRT_MAGIC, VIRT_VENDOR,
+};
+use crate::devices::virtio::{Descriptor, GuestQueue};
+use crate::memory::GuestMemory;
+#[cfg(feature = "snapshot")]
+use crate::migration::{DeviceState, StateDecoder, StateEncoder};
+use crate::mmio::MmioHandler;
+use crate::stats::LatencyStats;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::time::Instant;
+
+/// VirtIO Net デバイス ID
+const VIRTIO_ID_NET: u32 = 0x1;
+
+/// 受信キュー
+const RX_QUEUE: usize = 0;
+
+/// 送信キュー
+const TX_QUEUE: usize = 1;
+
+/// キューの数 (`VIRTIO_NET_F_MQ` なしなので受信・送信の 1 組)
+const NUM_QUEUES: usize = 2;
+
+/// キューサイズの上限 (QueueNumMax)
+const QUEUE_NUM_MAX: u16 = 256;
+
+/// Feature: 設定領域の `mtu` が有効
+const VIRTIO_NET_F_MTU: u64 = 1 << 3;
+
+/// Feature: 設定領域の `mac` が有効
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+/// Feature: 設定領域の `status` (リンク状態) が有効
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+/// デバイスが提供する Features
+const DEVICE_FEATURES: u64 =
+    VIRTIO_NET_F_MTU | VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS | VIRTIO_F_VERSION_1;
+
+/// 設定領域の `status`: リンクが up
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// 設定領域 (struct virtio_net_config の mac, status, max_virtqueue_pairs, mtu) の大きさ
+const CONFIG_SIZE: usize = 12;
+
+/// 既定の MTU (Ethernet)
+pub const DEFAULT_MTU: u16 = 1500;
+
+/// 各バッファの先頭の struct virtio_net_hdr の大きさ (VIRTIO_F_VERSION_1 では num_buffers を含む)
+const NET_HDR_SIZE: usize = 12;
+
+/// virtio_net_hdr の `num_buffers` の位置
+const NET_HDR_NUM_BUFFERS: usize = 10;
+
+/// Ethernet ヘッダ (宛先・送信元 MAC, EtherType) の大きさ
+const ETH_HEADER_SIZE: usize = 14;
+
+/// ホストとの間で溜めておくフレームの数 (方向ごと)
+pub const FRAME_QUEUE_LIMIT: usize = 256;
+
+/// ゲストが送信したフレームに応答するバックエンド
+pub trait NetBackend: Send + Sync {
+    /// ゲストが送信したフレーム 1 つを受け取り、ゲストに受信させるフレームを返す
+    fn transmit(&mut self, frame: &[u8]) -> Vec<Vec<u8>>;
+}
+
+/// VirtIO Net デバイス
+pub struct VirtioNetDevice {
+    /// ベースアドレス
+    base_addr: u64,
+    /// デバイスステータス
+    status: u32,
+    /// 選択中のキューインデックス
+    queue_sel: u32,
+    /// デバイス Features セレクタ
+    device_features_sel: u32,
+    /// ドライバー Features セレクタ
+    driver_features_sel: u32,
+    /// ドライバーが受け入れた Features
+    driver_features: u64,
+    /// ドライバがキューごとに設定した値 (QueueSel で選ぶ)
+    queues: [QueueConfig; NUM_QUEUES],
+    /// QueueReady で確定できなかったキューの設定 (最後の 1 回)
+    queue_error: Option<QueueConfigError>,
+    /// InterruptStatus と ConfigGeneration
+    interrupts: InterruptState,
+    /// アサートする割り込み (`attach_irq` で受け取る)
+    irq: u32,
+    /// QueueNotify 1 回分のキュー処理時間
+    queue_latency: LatencyStats,
+    /// ゲストの MAC アドレス
+    mac: [u8; 6],
+    /// リンクが up か
+    link_up: bool,
+    /// MTU
+    mtu: u16,
+    /// 記述子の検証とゲスト RAM へのアクセス (`set_dma_validator`)
+    dma: Option<DmaValidator>,
+    /// ゲストに受信させる、受信バッファを待っているフレーム
+    rx_frames: VecDeque<Vec<u8>>,
+    /// ゲストが送信し、まだ `recv_frame` で取り出していないフレーム
+    tx_frames: VecDeque<Vec<u8>>,
+    /// 送信フレームの渡し先 (`with_backend`。なければ `recv_frame` で取り出す)
+    backend: Option<Box<dyn NetBackend>>,
+    /// 障害注入 (`set_fault_injector`)
+    faults: Option<FaultInjector>,
+}
+
+impl VirtioNetDevice {
+    /// 新しい VirtIO Net デバイスを作成 (リンクは up、MTU は 1500)
+    ///
+    /// # Arguments
+    ///
+    /// * `base_addr` - MMIO ベースアドレス
+    /// * `mac` - ゲストの MAC アドレス
+    pub fn new(base_addr: u64, mac: [u8; 6]) -> Self {
+        Self {
+            base_addr,
+            status: 0,
+            queue_sel: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queues: [QueueConfig::new(QUEUE_NUM_MAX); NUM_QUEUES],
+            queue_error: None,
+            interrupts: InterruptState::default(),
+            irq: IrqMap::QEMU_VIRT.virtio,
+            queue_latency: LatencyStats::default(),
+            mac,
+            link_up: true,
+            mtu: DEFAULT_MTU,
+            dma: None,
+            rx_frames: VecDeque::new(),
+            tx_frames: VecDeque::new(),
+            backend: None,
+            faults: None,
+        }
+    }
+
+    /// ゲストが送信したフレームを `backend` に渡し、応答をゲストに受信させる
+    pub fn with_backend(mut self, backend: Box<dyn NetBackend>) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// ゲストに Ethernet フレームを受信させる
+    ///
+    /// ゲストの受信バッファが空いていればすぐに書き込み、空いていなければ
+    /// ドライバがバッファを追加するまで溜めておく。
+    ///
+    /// # Errors
+    /// リンクが down の場合、フレームが Ethernet ヘッダより短いか MTU を超える場合、
+    /// 受信待ちのフレームが [`FRAME_QUEUE_LIMIT`] 個溜まっている場合はエラーを返す
+    pub fn send_frame(&mut self, frame: &[u8]) -> Result<(), Box<dyn Error>> {
+        if !self.link_up {
+            return Err("virtio-net link is down".into());
+        }
+        if frame.len() < ETH_HEADER_SIZE || frame.len() > ETH_HEADER_SIZE + self.mtu as usize {
+            return Err(format!(
+                "Frame of {} bytes does not fit an Ethernet frame with MTU {}",
+                frame.len(),
+                self.mtu
+            )
+            .into());
+        }
+        if self.rx_frames.len() >= FRAME_QUEUE_LIMIT {
+            return Err(format!(
+                "{} frames are already waiting for guest receive buffers",
+                FRAME_QUEUE_LIMIT
+            )
+            .into());
+        }
+        self.rx_frames.push_back(frame.to_vec());
+        self.process_rx()
+    }
+
+    /// ゲストが送信した Ethernet フレームを送信順に取り出す
+    ///
+    /// 取り出されないまま [`FRAME_QUEUE_LIMIT`] 個を超えた分は古いものから捨てる。
+    /// バックエンドを設定した場合、フレームはバックエンドに渡すためここには来ない。
+    pub fn recv_frame(&mut self) -> Option<Vec<u8>> {
+        self.tx_frames.pop_front()
+    }
+
+    /// ゲストの受信バッファを待っているフレームの数
+    pub fn pending_rx_frames(&self) -> usize {
+        self.rx_frames.len()
+    }
+
+    /// ゲストの MAC アドレス
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    /// ゲストの MAC アドレスを設定する
+    ///
+    /// ドライバは MAC アドレスを初期化時にだけ読むため、ゲストのドライバが
+    /// 次に初期化 (再起動・デバイスのリセット) したときから有効になる。
+    pub fn set_mac(&mut self, mac: [u8; 6]) {
+        self.mac = mac;
+    }
+
+    /// リンクが up か
+    pub fn link_up(&self) -> bool {
+        self.link_up
+    }
+
+    /// リンクを up / down にする
+    ///
+    /// 状態が変わった場合は設定変更の割り込みでゲストに知らせる。Linux の
+    /// virtio-net ドライバは割り込みを受けて設定領域の `status` を読み直し、
+    /// `netif_carrier_on` / `netif_carrier_off` でリンク状態を切り替える。
+    pub fn set_link_up(&mut self, up: bool) {
+        if self.link_up != up {
+            self.link_up = up;
+            self.interrupts.notify_config_changed();
+        }
+    }
+
+    /// MTU
+    pub fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// MTU を設定する
+    ///
+    /// MAC アドレスと同じく、ゲストのドライバが次に初期化したときから有効になる。
+    ///
+    /// # Errors
+    /// 68 (IPv4 の最小 MTU) より小さい場合はエラーを返す
+    pub fn set_mtu(&mut self, mtu: u16) -> Result<(), Box<dyn Error>> {
+        if mtu < 68 {
+            return Err(format!("MTU {} is below the IPv4 minimum of 68", mtu).into());
+        }
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    /// ドライバーが受け入れた Features
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    /// 選択中のキューの設定 (存在しないキューは None)
+    pub fn queue_config(&self, index: u32) -> Option<QueueConfig> {
+        self.queues.get(index as usize).copied()
+    }
+
+    /// QueueReady で確定できなかったキューの設定 (最後の 1 回)
+    pub fn queue_error(&self) -> Option<QueueConfigError> {
+        self.queue_error
+    }
+
+    /// 記述子の検証とキューへのアクセスに使うゲスト RAM を設定する
+    ///
+    /// キューの処理に必須。`Hypervisor` に登録する (virtio-mmio スロットに bind する)
+    /// と [`MmioHandler::attach_dma`] で設定されるため、MMIO の穴を指定する場合や
+    /// 登録せずに使う場合だけ呼ぶ。
+    pub fn set_dma_validator(&mut self, dma: DmaValidator) {
+        self.dma = Some(dma);
+    }
+
+    /// 障害注入を設定する
+    ///
+    /// [`FaultInjector::drop_frames`] で、ゲストが送信・受信するフレームを
+    /// 回線上で失われたように破棄する (記述子は消費しない)。
+    pub fn set_fault_injector(&mut self, faults: FaultInjector) {
+        self.faults = Some(faults);
+    }
+
+    /// 障害注入でフレームを破棄するか
+    fn drop_injected_frame(&self) -> bool {
+        self.faults.as_ref().is_some_and(|f| f.take_frame_drop())
+    }
+
+    /// 設定領域 (struct virtio_net_config) のバイト列
+    fn config_bytes(&self) -> [u8; CONFIG_SIZE] {
+        let status = if self.link_up {
+            VIRTIO_NET_S_LINK_UP
+        } else {
+            0
+        };
+        let mut config = [0u8; CONFIG_SIZE];
+        config[0..6].copy_from_slice(&self.mac);
+        config[6..8].copy_from_slice(&status.to_le_bytes());
+        // max_virtqueue_pairs (VIRTIO_NET_F_MQ なしでは 1 組)
+        config[8..10].copy_from_slice(&1u16.to_le_bytes());
+        config[10..12].copy_from_slice(&self.mtu.to_le_bytes());
+        config
+    }
+
+    /// 設定領域の `offset` から `size` バイトを読む (範囲外は 0)
+    fn read_config(&self, offset: u64, size: usize) -> u64 {
+        let config = self.config_bytes();
+        let start = offset as usize;
+        config.get(start..start + size.min(8)).map_or(0, |bytes| {
+            bytes
+                .iter()
+                .rev()
+                .fold(0u64, |value, &byte| (value << 8) | byte as u64)
+        })
+    }
+
+    /// 選択中のキューの設定を検証して確定する
+    fn latch_queue(&mut self) {
+        if let Err(err) = latch_queue(
+            &mut self.queues,
+            self.queue_sel,
+            self.dma.as_ref(),
+            "virtio-net",
+        ) {
+            self.queue_error = Some(err);
+            self.status |= STATUS_DEVICE_NEEDS_RESET;
+        }
+    }
+
+    /// 溜めているフレームを受信キューのバッファに書き込む
+    ///
+    /// 各バッファは virtio_net_hdr (オフロードなし) とフレームを並べたもので、
+    /// 書き込み可能な記述子チェーン 1 つに 1 フレームを書き込む。
+    fn process_rx(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(dma) = self.dma.clone() else {
+            return Ok(());
+        };
+        let Some(queue) = GuestQueue::latched(self.queues[RX_QUEUE], &dma) else {
+            return Ok(());
+        };
+        let mut completed = false;
+        while !self.rx_frames.is_empty() {
+            if self.drop_injected_frame() {
+                self.rx_frames.pop_front();
+                continue;
+            }
+            let Some(head) = queue.next_avail()? else {
+                break;
+            };
+            let written = match queue.validate_chain(head) {
+                Ok(chain) => match self.rx_frames.front() {
+                    Some(frame) => Self::write_rx_buffer(&chain, frame, dma.memory().as_ref())?,
+                    None => 0,
+                },
+                Err(err) => {
+                    eprintln!("[VIRTIO] virtio-net: {}", err);
+                    0
+                }
+            };
+            if written > 0 {
+                self.rx_frames.pop_front();
+            }
+            queue.push_used(head, written)?;
+            completed = true;
+        }
+        if completed {
+            self.interrupts.notify_used();
+        }
+        Ok(())
+    }
+
+    /// 受信バッファ 1 つにヘッダとフレームを書き込む
+    ///
+    /// # Returns
+    /// 書き込んだバイト数 (バッファが足りず書き込まなかった場合は 0)
+    fn write_rx_buffer(
+        chain: &[Descriptor],
+        frame: &[u8],
+        mem: &dyn GuestMemory,
+    ) -> Result<u32, Box<dyn Error>> {
+        let capacity: usize = chain.iter().map(|desc| desc.len as usize).sum();
+        if chain.iter().any(|desc| !desc.is_write()) || capacity < NET_HDR_SIZE + frame.len() {
+            eprintln!(
+                "[VIRTIO] virtio-net: receive buffer of {} bytes cannot hold a {}-byte frame",
+                capacity,
+                frame.len()
+            );
+            return Ok(0);
+        }
+        let mut hdr = [0u8; NET_HDR_SIZE];
+        hdr[NET_HDR_NUM_BUFFERS..].copy_from_slice(&1u16.to_le_bytes());
+        let data = [&hdr[..], frame].concat();
+        let mut rest = &data[..];
+        for desc in chain {
+            let len = rest.len().min(desc.len as usize);
+            mem.write_slice(&rest[..len], desc.addr)?;
+            rest = &rest[len..];
+        }
+        Ok(data.len() as u32)
+    }
+
+    /// 送信キューを処理する
+    ///
+    /// 各バッファの virtio_net_hdr を除いたフレームをバックエンドに渡すか、
+    /// `recv_frame` 用に溜める。リンクが down の間に送信されたフレームと、
+    /// 障害注入で破棄するフレームは渡さない。
+    fn process_tx(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(dma) = self.dma.clone() else {
+            return if self.queues[TX_QUEUE].ready {
+                Err("no guest memory attached (set_dma_validator)".into())
+            } else {
+                Ok(())
+            };
+        };
+        let Some(queue) = GuestQueue::latched(self.queues[TX_QUEUE], &dma) else {
+            return Ok(());
+        };
+        let mut completed = false;
+        while let Some(head) = queue.next_avail()? {
+            match queue.validate_chain(head) {
+                Ok(chain) => {
+                    let limit = NET_HDR_SIZE + ETH_HEADER_SIZE + self.mtu as usize;
+                    let frame = Self::read_tx_buffer(&chain, limit, dma.memory().as_ref())?;
+                    let lost = !self.link_up || self.drop_injected_frame();
+                    if let Some(frame) = frame.filter(|_| !lost) {
+                        self.transmit(frame);
+                    }
+                }
+                Err(err) => eprintln!("[VIRTIO] virtio-net: {}", err),
+            }
+            queue.push_used(head, 0)?;
+            completed = true;
+        }
+        if completed {
+            self.interrupts.notify_used();
+        }
+        // バックエンドの応答を受信させる
+        self.process_rx()
+    }
+
+    /// ゲストが送信したフレームをバックエンドか `recv_frame` 用のキューに渡す
+    fn transmit(&mut self, frame: Vec<u8>) {
+        let Some(backend) = self.backend.as_mut() else {
+            if self.tx_frames.len() >= FRAME_QUEUE_LIMIT {
+                self.tx_frames.pop_front();
+            }
+            self.tx_frames.push_back(frame);
+            return;
+        };
+        for reply in backend.transmit(&frame) {
+            if self.rx_frames.len() >= FRAME_QUEUE_LIMIT {
+                eprintln!("[VIRTIO] virtio-net: receive queue is full, dropping a reply");
+                break;
+            }
+            self.rx_frames.push_back(reply);
+        }
+    }
+
+    /// 送信バッファ 1 つからフレームを読み出す (不正なバッファは None)
+    ///
+    /// 記述子の長さはゲストが決めるため、ヘッダと MTU 分のフレームが入る
+    /// `limit` バイトを超えるバッファは読み出す前に破棄する。
+    fn read_tx_buffer(
+        chain: &[Descriptor],
+        limit: usize,
+        mem: &dyn GuestMemory,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        if chain.iter().any(|desc| desc.is_write()) {
+            eprintln!("[VIRTIO] virtio-net: transmit buffer contains a device-writable descriptor");
+            return Ok(None);
+        }
+        let len: u64 = chain.iter().map(|desc| desc.len as u64).sum();
+        if len > limit as u64 {
+            eprintln!(
+                "[VIRTIO] virtio-net: transmit buffer of {} bytes exceeds the MTU ({} bytes with headers)",
+                len, limit
+            );
+            return Ok(None);
+        }
+        let mut data = Vec::with_capacity(len as usize);
+        for desc in chain {
+            let start = data.len();
+            data.resize(start + desc.len as usize, 0);
+            mem.read_slice(&mut data[start..], desc.addr)?;
+        }
+        Ok((data.len() > NET_HDR_SIZE).then(|| data.split_off(NET_HDR_SIZE)))
+    }
+}
+
+#[cfg(feature = "snapshot")]
+impl DeviceState for VirtioNetDevice {
+    fn save_state(&self) -> Vec<u8> {
+        let mut enc = StateEncoder::new()
+            .u32(self.status)
+            .u32(self.queue_sel)
+            .u32(self.device_features_sel)
+            .u32(self.driver_features_sel)
+            .u64(self.driver_features)
+            .u32(self.interrupts.status())
+            .u32(self.interrupts.config_generation());
+        for queue in &self.queues {
+            enc = enc
+                .u32(queue.num as u32)
+                .u64(queue.addrs.desc)
+                .u64(queue.addrs.driver)
+                .u64(queue.addrs.device)
+                .bool(queue.ready);
+        }
+        enc.finish()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut dec = StateDecoder::new(state);
+        let (status, queue_sel) = (dec.u32()?, dec.u32()?);
+        let (device_features_sel, driver_features_sel) = (dec.u32()?, dec.u32()?);
+        let driver_features = dec.u64()?;
+        let interrupts = InterruptState::from_raw(dec.u32()?, dec.u32()?);
+        let mut queues = [QueueConfig::new(QUEUE_NUM_MAX); NUM_QUEUES];
+        for queue in &mut queues {
+            queue.num = dec.u32()? as u16;
+            queue.addrs.desc = dec.u64()?;
+            queue.addrs.driver = dec.u64()?;
+            queue.addrs.device = dec.u64()?;
+            queue.ready = dec.bool()?;
+        }
+        dec.finish()?;
+
+        self.status = status;
+        self.queue_sel = queue_sel;
+        self.device_features_sel = device_features_sel;
+        self.driver_features_sel = driver_features_sel;
+        self.driver_features = driver_features;
+        self.interrupts = interrupts;
+        self.queues = queues;
+        Ok(())
+    }
+}
+
+impl MmioHandler for VirtioNetDevice {
+    fn name(&self) -> &str {
+        "virtio-net"
+    }
+
+    #[cfg(feature = "snapshot")]
+    fn as_device_state(&mut self) -> Option<&mut dyn DeviceState> {
+        Some(self)
+    }
+
+    fn pending_irq(&self) -> Option<u32> {
+        self.interrupts.is_asserted().then_some(self.irq)
+    }
+
+    fn queue_latency(&self) -> Option<LatencyStats> {
+        Some(self.queue_latency)
+    }
+
+    fn attach_dma(&mut self, dma: DmaValidator) {
+        // 利用者が MMIO の穴などを指定して設定したものを優先する
+        if self.dma.is_none() {
+            self.dma = Some(dma);
+        }
+    }
+
+    fn attach_irq(&mut self, irq: u32) {
+        self.irq = irq;
+    }
+
+    fn base(&self) -> u64 {
+        self.base_addr
+    }
+
+    fn size(&self) -> u64 {
+        0x200 // VirtIO MMIO レジスタ領域のサイズ
+    }
+
+    // MAC アドレス・リンク状態・MTU はホスト側の設定なので残す
+    fn reset(&mut self) {
+        self.status = 0;
+        self.queue_sel = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queues = [QueueConfig::new(QUEUE_NUM_MAX); NUM_QUEUES];
+        self.queue_error = None;
+        self.interrupts.reset();
+        // ゲストのバッファを待っていたフレームは再起動で失われる
+        self.rx_frames.clear();
+    }
+
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, Box<dyn Error>> {
+        let value = match offset {
+            regs::MAGIC_VALUE => VIRT_MAGIC as u64,
+            regs::VERSION => 2,
+            regs::DEVICE_ID => VIRTIO_ID_NET as u64,
+            regs::VENDOR_ID => VIRT_VENDOR as u64,
+            regs::DEVICE_FEATURES => match self.device_features_sel {
+                0 => DEVICE_FEATURES & 0xffff_ffff,
+                1 => DEVICE_FEATURES >> 32,
+                _ => 0,
+            },
+            // 存在しないキューは 0 (使えない)
+            regs::QUEUE_NUM_MAX => match self.queue_config(self.queue_sel) {
+                Some(_) => QUEUE_NUM_MAX as u64,
+                None => 0,
+            },
+            regs::QUEUE_READY => self
+                .queue_config(self.queue_sel)
+                .is_some_and(|queue| queue.ready) as u64,
+            regs::STATUS => self.status as u64,
+            regs::INTERRUPT_STATUS => self.interrupts.status() as u64,
+            regs::CONFIG_GENERATION => self.interrupts.config_generation() as u64,
+            offset if offset >= regs::CONFIG => self.read_config(offset - regs::CONFIG, size),
+            _ => {
+                // 未実装のレジスタは 0 を返す
+                0
+            }
+        };
+
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: u64, value: u64, _size: usize) -> Result<(), Box<dyn Error>> {
+        match offset {
+            regs::STATUS => match StatusWrite::decode(self.status, value as u32) {
+                StatusWrite::Reset => MmioHandler::reset(self),
+                StatusWrite::Set(status) => self.status = status,
+            },
+            regs::QUEUE_SEL => {
+                self.queue_sel = value as u32;
+            }
+            regs::QUEUE_NUM => {
+                if let Some(queue) = configurable_queue(&mut self.queues, self.queue_sel) {
+                    queue.num = (value as u16).min(QUEUE_NUM_MAX);
+                }
+            }
+            regs::QUEUE_READY => {
+                if value & 1 == 0 {
+                    if let Some(queue) = self.queues.get_mut(self.queue_sel as usize) {
+                        queue.ready = false;
+                    }
+                } else if configurable_queue(&mut self.queues, self.queue_sel).is_some() {
+                    self.latch_queue();
+                }
+            }
+            regs::QUEUE_DESC_LOW
+            | regs::QUEUE_DESC_HIGH
+            | regs::QUEUE_DRIVER_LOW
+            | regs::QUEUE_DRIVER_HIGH
+            | regs::QUEUE_DEVICE_LOW
+            | regs::QUEUE_DEVICE_HIGH => {
+                if let Some(queue) = configurable_queue(&mut self.queues, self.queue_sel) {
+                    queue.write_addr(offset, value as u32);
+                }
+            }
+            regs::QUEUE_NOTIFY => {
+                let start = Instant::now();
+                let result = match value as usize {
+                    RX_QUEUE => self.process_rx(),
+                    TX_QUEUE => self.process_tx(),
+                    _ => Ok(()),
+                };
+                if let Err(e) = result {
+                    eprintln!(
+                        "[VIRTIO] virtio-net: failed to process queue {}: {}",
+                        value, e
+                    );
+                }
+                self.queue_latency.record(start.elapsed());
+            }
+            regs::DEVICE_FEATURES_SEL => {
+                self.device_features_sel = value as u32;
+            }
+            regs::DRIVER_FEATURES_SEL => {
+                self.driver_features_sel = value as u32;
+            }
+            regs::DRIVER_FEATURES => {
+                let shift = match self.driver_features_sel {
+                    0 => 0,
+                    1 => 32,
+                    _ => return Ok(()),
+                };
+                let mask = 0xffff_ffffu64 << shift;
+                // デバイスが提供していない Features は受け入れない
+                self.driver_features = (self.driver_features & !mask)
+                    | (((value & 0xffff_ffff) << shift) & DEVICE_FEATURES);
+            }
+            regs::INTERRUPT_ACK => {
+                self.interrupts.ack(value as u32);
+            }
+            _ => {
+                // 未実装のレジスタ・設定領域 (読み取り専用) への書き込みは無視
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::virtio::transport::INTERRUPT_CONFIG;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    #[test]
+    fn test_features_and_config_space() {
+        let mut device = VirtioNetDevice::new(0x0a00_0000, MAC);
+        assert_eq!(device.read(regs::DEVICE_ID, 4).unwrap(), 1);
+        let low = device.read(regs::DEVICE_FEATURES, 4).unwrap();
+        device.write(regs::DEVICE_FEATURES_SEL, 1, 4).unwrap();
+        let high = device.read(regs::DEVICE_FEATURES, 4).unwrap();
+        assert_eq!(low | (high << 32), DEVICE_FEATURES);
+
+        // Linux は MAC アドレスを 1 バイトずつ読む
+        let mac: Vec<u8> = (0..6)
+            .map(|i| device.read(regs::CONFIG + i, 1).unwrap() as u8)
+            .collect();
+        assert_eq!(mac, MAC);
+        assert_eq!(
+            device.read(regs::CONFIG + 6, 2).unwrap() as u16,
+            VIRTIO_NET_S_LINK_UP
+        );
+        assert_eq!(device.read(regs::CONFIG + 10, 2).unwrap(), 1500);
+        assert_eq!(device.read(regs::CONFIG + 12, 4).unwrap(), 0);
+
+        // 提供していない Features は受け入れない
+        device.write(regs::DRIVER_FEATURES_SEL, 1, 4).unwrap();
+        device.write(regs::DRIVER_FEATURES, 0xffff_ffff, 4).unwrap();
+        assert_eq!(device.driver_features(), VIRTIO_F_VERSION_1);
+    }
+
+    #[test]
+    fn test_link_down_raises_config_interrupt() {
+        let mut device = VirtioNetDevice::new(0x0a00_0000, MAC);
+        device.set_link_up(true);
+        assert_eq!(device.pending_irq(), None);
+
+        device.set_link_up(false);
+        assert!(!device.link_up());
+        assert_eq!(device.read(regs::CONFIG + 6, 2).unwrap(), 0);
+        assert_eq!(
+            device.read(regs::INTERRUPT_STATUS, 4).unwrap() as u32,
+            INTERRUPT_CONFIG
+        );
+        assert_eq!(device.read(regs::CONFIG_GENERATION, 4).unwrap(), 1);
+
+        device
+            .write(regs::INTERRUPT_ACK, INTERRUPT_CONFIG as u64, 4)
+            .unwrap();
+        device.attach_irq(IrqMap::QEMU_VIRT.virtio_slot(1));
+        device.set_link_up(true);
+        assert_eq!(device.read(regs::CONFIG_GENERATION, 4).unwrap(), 2);
+        assert_eq!(device.pending_irq(), Some(IrqMap::QEMU_VIRT.virtio_slot(1)));
+    }
+
+    #[test]
+    fn test_mac_and_mtu_survive_reset() {
+        let mut device = VirtioNetDevice::new(0x0a00_0000, MAC);
+        device.set_mac([0x02, 0, 0, 0, 0, 1]);
+        device.set_mtu(9000).unwrap();
+        assert!(device.set_mtu(60).is_err());
+        device.write(regs::STATUS, 0xf, 4).unwrap();
+        device.write(regs::STATUS, 0, 4).unwrap();
+
+        assert_eq!(device.read(regs::STATUS, 4).unwrap(), 0);
+        assert_eq!(device.read(regs::CONFIG, 1).unwrap(), 0x02);
+        assert_eq!(device.read(regs::CONFIG + 5, 1).unwrap(), 0x01);
+        assert_eq!(device.read(regs::CONFIG + 10, 2).unwrap(), 9000);
+        assert_eq!(device.mtu(), 9000);
+    }
+
+    const DESC_TABLE: u64 = 0x4000_0000;
+    const RX_RING: u64 = 0x4000_1000;
+    const TX_RING: u64 = 0x4000_2000;
+    const WRITE: u16 = 2;
+
+    /// ゲスト RAM に受信・送信キュー (各 4 エントリ) を置き、ドライバと同じ手順で確定する
+    ///
+    /// Descriptor Table は受信が 0x4000_0000、送信が 0x4000_0100。
+    /// Available Ring / Used Ring は各キューの RING + 0 / RING + 0x100。
+    fn attach_guest_queues(
+        device: &mut VirtioNetDevice,
+    ) -> std::sync::Arc<crate::memory::testing::TestMemory> {
+        use crate::memory::testing::TestMemory;
+        use std::sync::Arc;
+
+        let mem = Arc::new(TestMemory::new(0x4000_0000, 0x10000));
+        device.set_dma_validator(DmaValidator::new(mem.clone()));
+        for (index, ring) in [(0u64, RX_RING), (1, TX_RING)] {
+            device.write(regs::QUEUE_SEL, index, 4).unwrap();
+            device.write(regs::QUEUE_NUM, 4, 4).unwrap();
+            device
+                .write(regs::QUEUE_DESC_LOW, DESC_TABLE + 0x100 * index, 4)
+                .unwrap();
+            device.write(regs::QUEUE_DRIVER_LOW, ring, 4).unwrap();
+            device
+                .write(regs::QUEUE_DEVICE_LOW, ring + 0x100, 4)
+                .unwrap();
+            device.write(regs::QUEUE_READY, 1, 4).unwrap();
+        }
+        mem
+    }
+
+    /// ドライバの代わりに 1 つの記述子のバッファを追加する
+    fn add_buffer(mem: &dyn GuestMemory, queue: u64, addr: u64, len: u32, flags: u16) {
+        use crate::memory::GuestMemoryExt;
+
+        let ring = [RX_RING, TX_RING][queue as usize];
+        let idx = mem.read_u16(ring + 2).unwrap();
+        let desc = DESC_TABLE + 0x100 * queue + 16 * (idx % 4) as u64;
+        mem.write_u64(desc, addr).unwrap();
+        mem.write_u32(desc + 8, len).unwrap();
+        mem.write_u16(desc + 12, flags).unwrap();
+        mem.write_u16(ring + 4 + 2 * (idx % 4) as u64, idx % 4)
+            .unwrap();
+        mem.write_u16(ring + 2, idx.wrapping_add(1)).unwrap();
+    }
+
+    /// ARP 要求 (ブロードキャスト) の Ethernet フレーム
+    fn arp_frame() -> Vec<u8> {
+        let mut frame = vec![0xff; 6];
+        frame.extend_from_slice(&MAC);
+        frame.extend_from_slice(&[0x08, 0x06]);
+        frame.resize(60, 0);
+        frame
+    }
+
+    #[test]
+    fn test_send_frame_waits_for_receive_buffers() {
+        use crate::memory::GuestMemoryExt;
+
+        let mut device = VirtioNetDevice::new(0x0a00_0000, MAC);
+        let mem = attach_guest_queues(&mut device);
+        device.send_frame(&arp_frame()).unwrap();
+        assert_eq!(device.pending_rx_frames(), 1);
+        assert_eq!(device.pending_irq(), None);
+
+        // ドライバが受信バッファを追加して通知すると書き込む
+        add_buffer(mem.as_ref(), 0, 0x4000_3000, 1526, WRITE);
+        device.write(regs::QUEUE_NOTIFY, 0, 4).unwrap();
+        assert_eq!(device.pending_rx_frames(), 0);
+        assert!(device.pending_irq().is_some());
+        let used_len = mem.read_u32(RX_RING + 0x100 + 8).unwrap();
+        assert_eq!(used_len as usize, NET_HDR_SIZE + 60);
+        let mut buf = vec![0u8; used_len as usize];
+        mem.read_slice(&mut buf, 0x4000_3000).unwrap();
+        assert_eq!(&buf[NET_HDR_SIZE - 2..NET_HDR_SIZE], [1, 0]);
+        assert_eq!(&buf[NET_HDR_SIZE..], arp_frame());
+
+        // 不正なフレームとリンクが down の間は受け付けない
+        assert!(device.send_frame(&[0; 10]).is_err());
+        assert!(device.send_frame(&vec![0; 1515]).is_err());
+        device.set_link_up(false);
+        assert!(device.send_frame(&arp_frame()).is_err());
+    }
+
+    #[test]
+    fn test_recv_frame_returns_guest_transmissions() {
+        use crate::memory::GuestMemoryExt;
+
+        let mut device = VirtioNetDevice::new(0x0a00_0000, MAC);
+        let mem = attach_guest_queues(&mut device);
+        assert_eq!(device.recv_frame(), None);
+
+        mem.write_slice(&[0; NET_HDR_SIZE], 0x4000_4000).unwrap();
+        mem.write_slice(&arp_frame(), 0x4000_4000 + NET_HDR_SIZE as u64)
+            .unwrap();
+        add_buffer(mem.as_ref(), 1, 0x4000_4000, (NET_HDR_SIZE + 60) as u32, 0);
+        device.write(regs::QUEUE_NOTIFY, 1, 4).unwrap();
+        assert_eq!(device.recv_frame(), Some(arp_frame()));
+        assert_eq!(device.recv_frame(), None);
+        assert_eq!(mem.read_u16(TX_RING + 0x100 + 2).unwrap(), 1);
+
+        // MTU を超えるフレームは読み出さずに破棄する
+        let oversized = (NET_HDR_SIZE + ETH_HEADER_SIZE + 1501) as u32;
+        add_buffer(mem.as_ref(), 1, 0x4000_4000, oversized, 0);
+        device.write(regs::QUEUE_NOTIFY, 1, 4).unwrap();
+        assert_eq!(device.recv_frame(), None);
+        assert_eq!(mem.read_u16(TX_RING + 0x100 + 2).unwrap(), 2);
+        device.set_mtu(1501).unwrap();
+        add_buffer(mem.as_ref(), 1, 0x4000_4000, oversized, 0);
+        device.write(regs::QUEUE_NOTIFY, 1, 4).unwrap();
+        assert!(device.recv_frame().is_some());
+
+        // リンクが down の間の送信は破棄する
+        device.set_link_up(false);
+        add_buffer(mem.as_ref(), 1, 0x4000_4000, (NET_HDR_SIZE + 60) as u32, 0);
+        device.write(regs::QUEUE_NOTIFY, 1, 4).unwrap();
+        assert_eq!(device.recv_frame(), None);
+        assert_eq!(mem.read_u16(TX_RING + 0x100 + 2).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_injected_frame_drops() {
+        use crate::devices::fault::FaultInjector;
+        use crate::memory::GuestMemoryExt;
+
+        let mut device = VirtioNetDevice::new(0x0a00_0000, MAC);
+        let mem = attach_guest_queues(&mut device);
+        let faults = FaultInjector::new();
+        device.set_fault_injector(faults.clone());
+
+        // 送信: 破棄したフレームも記述子は使用済みにする
+        faults.drop_frames(1);
+        mem.write_slice(&[0; NET_HDR_SIZE], 0x4000_4000).unwrap();
+        mem.write_slice(&arp_frame(), 0x4000_4000 + NET_HDR_SIZE as u64)
+            .unwrap();
+        add_buffer(mem.as_ref(), 1, 0x4000_4000, (NET_HDR_SIZE + 60) as u32, 0);
+        device.write(regs::QUEUE_NOTIFY, 1, 4).unwrap();
+        assert_eq!(device.recv_frame(), None);
+        assert_eq!(mem.read_u16(TX_RING + 0x100 + 2).unwrap(), 1);
+
+        // 受信: 破棄したフレームはゲストの受信バッファを使わない
+        faults.drop_frames(1);
+        add_buffer(mem.as_ref(), 0, 0x4000_3000, 1526, WRITE);
+        device.send_frame(&[0xaa; 60]).unwrap();
+        assert_eq!(device.pending_rx_frames(), 0);
+        assert_eq!(mem.read_u16(RX_RING + 0x100 + 2).unwrap(), 0);
+        device.send_frame(&arp_frame()).unwrap();
+        assert_eq!(mem.read_u16(RX_RING + 0x100 + 2).unwrap(), 1);
+        assert_eq!(faults.injected().dropped_frames, 2);
+    }
+
+    #[test]
+    fn test_backend_replies_are_received_by_the_guest() {
+        use crate::devices::virtio::user_net::UserNet;
+        use crate::memory::GuestMemoryExt;
+
+        let mut device =
+            VirtioNetDevice::new(0x0a00_0000, MAC).with_backend(Box::new(UserNet::new()));
+        let mem = attach_guest_queues(&mut device);
+        add_buffer(mem.as_ref(), 0, 0x4000_3000, 1526, WRITE);
+
+        // 10.0.2.2 (ホスト) の MAC アドレスを尋ねる ARP 要求
+        let mut request = arp_frame();
+        request.truncate(14);
+        request.extend_from_slice(&[0, 1, 8, 0, 6, 4, 0, 1]);
+        request.extend_from_slice(&MAC);
+        request.extend_from_slice(&[10, 0, 2, 15, 0, 0, 0, 0, 0, 0, 10, 0, 2, 2]);
+        mem.write_slice(&[0; NET_HDR_SIZE], 0x4000_4000).unwrap();
+        mem.write_slice(&request, 0x4000_4000 + NET_HDR_SIZE as u64)
+            .unwrap();
+        add_buffer(
+            mem.as_ref(),
+            1,
+            0x4000_4000,
+            (NET_HDR_SIZE + request.len()) as u32,
+            0,
+        );
+        device.write(regs::QUEUE_NOTIFY, 1, 4).unwrap();
+
+        assert_eq!(device.recv_frame(), None);
+        assert_eq!(mem.read_u16(RX_RING + 0x100 + 2).unwrap(), 1);
+        let mut reply = [0u8; 8];
+        mem.read_slice(&mut reply, 0x4000_3000 + NET_HDR_SIZE as u64 + 20)
+            .unwrap();
+        // ARP の応答 (opcode 2) と送信元 (ホスト) の MAC アドレス
+        assert_eq!(reply[..2], [0, 2]);
+        assert_eq!(reply[2..], [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+    }
+}
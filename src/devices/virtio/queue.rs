@@ -8,8 +8,17 @@
 //! - Descriptor Table: バッファを記述する記述子のテーブル
 //! - Available Ring: ドライバー（ゲスト）が利用可能にした記述子のインデックス
 //! - Used Ring: デバイス（ホスト）が処理完了した記述子のインデックス
+//!
+//! `core` / `alloc` にある型を `std` 経由で使わない (lint で確認)。ゲスト RAM の
+//! アクセスは `std` に依存する [`DmaValidator`] を通すため、`no_std` ではビルドしない。
+
+#![deny(clippy::std_instead_of_core, clippy::std_instead_of_alloc)]
 
-use std::error::Error;
+use super::dma::{DmaError, DmaErrorKind, DmaValidator};
+use super::transport::QueueConfig;
+use crate::memory::GuestMemoryExt;
+use core::error::Error;
+use core::sync::atomic::{fence, Ordering};
 
 /// Descriptor フラグ: 次の記述子へチェーン
 const VIRTQ_DESC_F_NEXT: u16 = 1;
@@ -20,6 +29,15 @@ const VIRTQ_DESC_F_WRITE: u16 = 2;
 /// Descriptor フラグ: 間接記述子
 const VIRTQ_DESC_F_INDIRECT: u16 = 4;
 
+/// Descriptor Table の 1 要素の大きさ
+const DESC_SIZE: u64 = 16;
+
+/// Used Ring の 1 要素 (id, len) の大きさ
+const USED_ELEM_SIZE: u64 = 8;
+
+/// Available Ring / Used Ring の `ring` の位置 (flags と idx の後)
+const RING_OFFSET: u64 = 4;
+
 /// VirtQueue Descriptor (16 bytes)
 ///
 /// バッファの記述子。複数の記述子を next でチェーンできる。
@@ -214,6 +232,11 @@ impl VirtQueue {
         Some(desc_idx)
     }
 
+    /// ドライバーが追加し、まだ取り出していない記述子の数 (キューの深さ)
+    pub fn pending(&self) -> u16 {
+        self.avail_ring.idx.wrapping_sub(self.last_avail_idx)
+    }
+
     /// Used Ring に処理完了した記述子を追加
     ///
     /// # Arguments
@@ -240,6 +263,24 @@ impl VirtQueue {
         Ok(())
     }
 
+    /// `head` から始まる記述子チェーンを辿り、すべての記述子を検証する
+    ///
+    /// # Returns
+    /// チェーンの記述子 (先頭から順に)
+    ///
+    /// # Errors
+    /// ゲスト RAM の外や MMIO 領域を指す記述子、範囲外の `next`、ループがあれば
+    /// 最初に見つかった不正な記述子を返す
+    pub fn validate_chain(
+        &self,
+        head: u16,
+        dma: &DmaValidator,
+    ) -> Result<Vec<Descriptor>, DmaError> {
+        walk_chain(head, self.num, dma, |index| {
+            self.desc_table.get(index as usize).copied()
+        })
+    }
+
     /// Available Ring に記述子を追加（テスト用）
     #[cfg(test)]
     pub fn push_avail(&mut self, desc_idx: u16) {
@@ -247,6 +288,168 @@ impl VirtQueue {
     }
 }
 
+/// `head` から始まる記述子チェーンを辿り、すべての記述子を検証する
+///
+/// `desc` は記述子を読む関数で、範囲外のインデックスには `None` を返す。
+fn walk_chain(
+    head: u16,
+    num: u16,
+    dma: &DmaValidator,
+    desc: impl Fn(u16) -> Option<Descriptor>,
+) -> Result<Vec<Descriptor>, DmaError> {
+    let mut chain = Vec::new();
+    let mut index = head;
+    loop {
+        let error = |desc: &Descriptor, kind| DmaError {
+            head,
+            index,
+            addr: desc.addr,
+            len: desc.len,
+            kind,
+        };
+        let desc =
+            desc(index).ok_or_else(|| error(&Descriptor::default(), DmaErrorKind::InvalidNext))?;
+        if chain.len() >= num as usize {
+            return Err(error(&desc, DmaErrorKind::ChainLoop));
+        }
+        dma.check(desc.addr, desc.len)
+            .map_err(|kind| error(&desc, kind))?;
+        chain.push(desc);
+        if !desc.has_next() {
+            return Ok(chain);
+        }
+        index = desc.next;
+    }
+}
+
+/// ゲスト RAM 上の Split Virtqueue
+///
+/// ドライバが QueueReady で確定した 3 領域 ([`QueueConfig`]) をゲスト RAM から直接
+/// 読み書きする。3 領域は確定時に [`QueueConfig::validate`] で検証済みだが、
+/// 記述子が指すバッファは [`validate_chain`](Self::validate_chain) で検証してから使う。
+///
+/// デバイスは取り出したリクエストをその場で完了させるため、次に取り出す
+/// Available Ring の位置は常に Used Ring の `idx` と等しい。デバイス側に位置を
+/// 持たないので、リセットやスナップショットからの復元の後もゲスト RAM の内容だけで続きから処理できる。
+pub struct GuestQueue<'a> {
+    config: QueueConfig,
+    dma: &'a DmaValidator,
+}
+
+impl<'a> GuestQueue<'a> {
+    /// 確定したキューの設定と、ゲスト RAM への DMA の検証から作成する
+    pub fn new(config: QueueConfig, dma: &'a DmaValidator) -> Self {
+        Self { config, dma }
+    }
+
+    /// ドライバが確定したキュー (確定していなければ None)
+    pub fn latched(config: QueueConfig, dma: &'a DmaValidator) -> Option<Self> {
+        config.ready.then(|| Self::new(config, dma))
+    }
+
+    /// Available Ring の `idx` (ドライバが次に書き込む位置)
+    fn avail_idx(&self) -> Result<u16, Box<dyn Error>> {
+        let idx = self.dma.memory().read_u16(self.config.addrs.driver + 2)?;
+        // idx を読んでから ring と記述子を読む
+        fence(Ordering::Acquire);
+        Ok(idx)
+    }
+
+    /// Used Ring の `idx` (デバイスが次に書き込む位置)
+    fn used_idx(&self) -> Result<u16, Box<dyn Error>> {
+        self.dma.memory().read_u16(self.config.addrs.device + 2)
+    }
+
+    /// ドライバが追加し、まだ処理していないリクエストの数 (キューの深さ)
+    ///
+    /// # Errors
+    /// ドライバがキューサイズより先まで `idx` を進めている場合はエラーを返す
+    pub fn pending(&self) -> Result<u16, Box<dyn Error>> {
+        let pending = self.avail_idx()?.wrapping_sub(self.used_idx()?);
+        if pending > self.config.num {
+            return Err(format!(
+                "available ring idx is {} entries ahead of the used ring (queue size {})",
+                pending, self.config.num
+            )
+            .into());
+        }
+        Ok(pending)
+    }
+
+    /// 次に処理するリクエストの先頭の記述子インデックス
+    ///
+    /// 取り出した位置は [`push_used`](Self::push_used) で完了させるまで進まない。
+    pub fn next_avail(&self) -> Result<Option<u16>, Box<dyn Error>> {
+        if self.pending()? == 0 {
+            return Ok(None);
+        }
+        let slot = self.used_idx()? % self.config.num;
+        let addr = self.config.addrs.driver + RING_OFFSET + 2 * slot as u64;
+        Ok(Some(self.dma.memory().read_u16(addr)?))
+    }
+
+    /// Descriptor Table から記述子を読む
+    fn desc(&self, index: u16) -> Option<Descriptor> {
+        if index >= self.config.num {
+            return None;
+        }
+        let mem = self.dma.memory();
+        let addr = self.config.addrs.desc + DESC_SIZE * index as u64;
+        Some(Descriptor::new(
+            mem.read_u64(addr).ok()?,
+            mem.read_u32(addr + 8).ok()?,
+            mem.read_u16(addr + 12).ok()?,
+            mem.read_u16(addr + 14).ok()?,
+        ))
+    }
+
+    /// `head` から始まる記述子チェーンを辿り、すべての記述子を検証する
+    ///
+    /// # Errors
+    /// [`VirtQueue::validate_chain`] と同じく、最初に見つかった不正な記述子を返す
+    pub fn validate_chain(&self, head: u16) -> Result<Vec<Descriptor>, DmaError> {
+        walk_chain(head, self.config.num, self.dma, |index| self.desc(index))
+    }
+
+    /// 検証せずにチェーンの末尾の記述子を探す (壊れたチェーンにステータスを返すため)
+    ///
+    /// 範囲外の `next` やループで辿れなくなった場合は、そこまでで最後の記述子を返す。
+    pub fn chain_tail(&self, head: u16) -> Option<Descriptor> {
+        let mut last = None;
+        let mut index = head;
+        for _ in 0..self.config.num {
+            let Some(desc) = self.desc(index) else {
+                break;
+            };
+            last = Some(desc);
+            if !desc.has_next() {
+                break;
+            }
+            index = desc.next;
+        }
+        last
+    }
+
+    /// Used Ring に処理完了したリクエストを追加する
+    ///
+    /// # Arguments
+    ///
+    /// * `head` - リクエストの先頭の記述子インデックス
+    /// * `len` - デバイスが書き込んだバイト数
+    pub fn push_used(&self, head: u16, len: u32) -> Result<(), Box<dyn Error>> {
+        let mem = self.dma.memory();
+        let idx = self.used_idx()?;
+        let addr = self.config.addrs.device
+            + RING_OFFSET
+            + USED_ELEM_SIZE * (idx % self.config.num) as u64;
+        mem.write_u32(addr, head as u32)?;
+        mem.write_u32(addr + 4, len)?;
+        // 要素を書いてから idx を進める
+        fence(Ordering::Release);
+        mem.write_u16(self.config.addrs.device + 2, idx.wrapping_add(1))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -286,12 +489,15 @@ mod tests {
         queue.push_avail(0);
         queue.push_avail(1);
         queue.push_avail(2);
+        assert_eq!(queue.pending(), 3);
 
         // pop_avail で取得
         assert_eq!(queue.pop_avail(), Some(0));
+        assert_eq!(queue.pending(), 2);
         assert_eq!(queue.pop_avail(), Some(1));
         assert_eq!(queue.pop_avail(), Some(2));
         assert_eq!(queue.pop_avail(), None);
+        assert_eq!(queue.pending(), 0);
     }
 
     #[test]
@@ -354,4 +560,115 @@ mod tests {
         }
         assert_eq!(queue.pop_avail(), None);
     }
+
+    #[test]
+    fn test_validate_chain() {
+        use crate::memory::testing::TestMemory;
+        use alloc::sync::Arc;
+
+        let dma = DmaValidator::new(Arc::new(TestMemory::new(0x4000_0000, 0x10000)));
+        let mut queue = VirtQueue::new(4);
+        queue
+            .set_desc(0, Descriptor::new(0x4000_0000, 16, VIRTQ_DESC_F_NEXT, 1))
+            .unwrap();
+        queue
+            .set_desc(
+                1,
+                Descriptor::new(0x4000_1000, 512, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 2),
+            )
+            .unwrap();
+        queue
+            .set_desc(2, Descriptor::new(0x4000_2000, 1, VIRTQ_DESC_F_WRITE, 0))
+            .unwrap();
+        assert_eq!(queue.validate_chain(0, &dma).unwrap().len(), 3);
+
+        // 2 番目の記述子がホストのメモリ (RAM の外) を指す
+        queue
+            .set_desc(
+                1,
+                Descriptor::new(
+                    0x1_0000_0000,
+                    512,
+                    VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+                    2,
+                ),
+            )
+            .unwrap();
+        let err = queue.validate_chain(0, &dma).unwrap_err();
+        assert_eq!(
+            (err.head, err.index, err.kind),
+            (0, 1, DmaErrorKind::OutsideRam)
+        );
+
+        // 自分自身を next に指定したループ
+        queue
+            .set_desc(3, Descriptor::new(0x4000_0000, 16, VIRTQ_DESC_F_NEXT, 3))
+            .unwrap();
+        let err = queue.validate_chain(3, &dma).unwrap_err();
+        assert_eq!(err.kind, DmaErrorKind::ChainLoop);
+
+        queue
+            .set_desc(3, Descriptor::new(0x4000_0000, 16, VIRTQ_DESC_F_NEXT, 9))
+            .unwrap();
+        let err = queue.validate_chain(3, &dma).unwrap_err();
+        assert_eq!((err.index, err.kind), (9, DmaErrorKind::InvalidNext));
+    }
+
+    #[test]
+    fn test_guest_queue_reads_rings_from_guest_memory() {
+        use crate::devices::virtio::transport::QueueAddrs;
+        use crate::memory::testing::TestMemory;
+        use crate::memory::GuestMemoryExt;
+        use alloc::sync::Arc;
+
+        let mem = Arc::new(TestMemory::new(0x4000_0000, 0x10000));
+        let dma = DmaValidator::new(mem.clone());
+        let config = QueueConfig {
+            num: 4,
+            addrs: QueueAddrs {
+                desc: 0x4000_0000,
+                driver: 0x4000_0100,
+                device: 0x4000_0200,
+            },
+            ready: true,
+        };
+        let queue = GuestQueue::new(config, &dma);
+        assert_eq!(queue.next_avail().unwrap(), None);
+
+        // ドライバ: 記述子 2 -> 3 のチェーンを Available Ring に追加する
+        let put_desc = |index: u64, addr: u64, len: u32, flags: u16, next: u16| {
+            let at = 0x4000_0000 + 16 * index;
+            mem.write_u64(at, addr).unwrap();
+            mem.write_u32(at + 8, len).unwrap();
+            mem.write_u16(at + 12, flags).unwrap();
+            mem.write_u16(at + 14, next).unwrap();
+        };
+        put_desc(2, 0x4000_1000, 16, VIRTQ_DESC_F_NEXT, 3);
+        put_desc(3, 0x4000_2000, 1, VIRTQ_DESC_F_WRITE, 0);
+        mem.write_u16(0x4000_0104, 2).unwrap();
+        mem.write_u16(0x4000_0102, 1).unwrap();
+
+        assert_eq!(queue.pending().unwrap(), 1);
+        let head = queue.next_avail().unwrap().unwrap();
+        assert_eq!(head, 2);
+        let chain = queue.validate_chain(head).unwrap();
+        assert_eq!(chain.len(), 2);
+        assert_eq!((chain[1].addr, chain[1].is_write()), (0x4000_2000, true));
+        assert_eq!(queue.chain_tail(head).unwrap().addr, 0x4000_2000);
+
+        queue.push_used(head, 1).unwrap();
+        assert_eq!(mem.read_u16(0x4000_0202).unwrap(), 1);
+        assert_eq!(mem.read_u32(0x4000_0204).unwrap(), 2);
+        assert_eq!(mem.read_u32(0x4000_0208).unwrap(), 1);
+        assert_eq!(queue.next_avail().unwrap(), None);
+
+        // ゲストが書いた記述子がホストのメモリを指す
+        put_desc(3, 0x1_0000_0000, 1, VIRTQ_DESC_F_WRITE, 0);
+        let err = queue.validate_chain(2).unwrap_err();
+        assert_eq!((err.index, err.kind), (3, DmaErrorKind::OutsideRam));
+
+        // キューサイズより先まで進んだ idx
+        mem.write_u16(0x4000_0102, 6).unwrap();
+        assert!(queue.pending().is_err());
+    }
 }
@@ -0,0 +1,301 @@
+//! 後からデバイスを挿せる virtio-mmio スロット
+//!
+//! QEMU の `virt` マシンと同じく、Device Tree には空の virtio-mmio
+//! トランスポートをあらかじめ宣言しておき、デバイスは実行時にスロットへ
+//! bind する。登録するデバイスの数が変わっても Device Tree のレイアウトを
+//! 作り直す必要がない。
+//!
+//! 空のスロットは Device ID 0 を返すため、ゲストのドライバはそのスロットを
+//! 無視する。Linux の virtio-mmio ドライバは起動時に 1 度だけ probe するので、
+//! ゲストから使うデバイスはドライバの probe より前 (カーネルの起動前か、
+//! 起動直後のシリアル出力を待つ間) に bind すること。
+//!
+//! ```ignore
+//! let slots = hv.add_virtio_slots(4)?;
+//! slots[0].bind(Box::new(VirtioBlockDevice::with_disk_image(0, root, capacity)))?;
+//! hv.boot_linux(&kernel, cmdline, None)?;
+//! ```
+
+use crate::devices::virtio::transport::{regs, TransportVersion, VIRT_MAGIC, VIRT_VENDOR};
+use crate::devices::virtio::DmaValidator;
+#[cfg(feature = "snapshot")]
+use crate::migration::{DeviceState, StateDecoder, StateEncoder};
+use crate::mmio::MmioHandler;
+use crate::stats::{BlockStats, IoCounters, LatencyStats};
+use std::error::Error;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+type SlotDevice = Arc<Mutex<Option<Box<dyn MmioHandler>>>>;
+
+/// スロットへのデバイスの抜き差しを行うハンドル
+///
+/// clone したハンドルは同じスロットを指す。vCPU を動かしているスレッドとは
+/// 別のスレッドからも bind できる。
+#[derive(Clone)]
+pub struct VirtioSlotHandle {
+    index: u32,
+    base: u64,
+    irq: u32,
+    device: SlotDevice,
+    /// 登録したときに受け取った DMA の検証 (bind したデバイスに渡す)
+    dma: Arc<Mutex<Option<DmaValidator>>>,
+}
+
+impl VirtioSlotHandle {
+    /// スロット番号 (Device Tree の並び順)
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// トランスポートのベースアドレス
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// トランスポートの割り込み (GIC の INTID)
+    pub fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    /// デバイスを挿す
+    ///
+    /// デバイスのレジスタにはスロットのベースアドレスからのオフセットで
+    /// アクセスするため、デバイス自身のベースアドレスは使われない。
+    /// デバイスにスロットの割り込み ([`MmioHandler::attach_irq`]) を渡し、
+    /// スロットが登録済みならゲスト RAM ([`MmioHandler::attach_dma`]) も渡す。
+    ///
+    /// # Errors
+    /// すでにデバイスが挿さっている場合はエラーを返す
+    pub fn bind(&self, device: Box<dyn MmioHandler>) -> Result<(), Box<dyn Error>> {
+        let mut slot = self.lock();
+        if let Some(current) = slot.as_ref() {
+            return Err(format!(
+                "virtio-mmio slot {} at 0x{:x} is already bound to {}",
+                self.index,
+                self.base,
+                current.name()
+            )
+            .into());
+        }
+        let mut device = device;
+        if let Some(dma) = self.dma.lock().unwrap().clone() {
+            device.attach_dma(dma);
+        }
+        device.attach_irq(self.irq);
+        *slot = Some(device);
+        Ok(())
+    }
+
+    /// デバイスを抜いて返す (空なら None)
+    pub fn unbind(&self) -> Option<Box<dyn MmioHandler>> {
+        self.lock().take()
+    }
+
+    /// デバイスが挿さっているか
+    pub fn is_bound(&self) -> bool {
+        self.lock().is_some()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Box<dyn MmioHandler>>> {
+        self.device.lock().unwrap()
+    }
+}
+
+/// virtio-mmio トランスポートのスロット (MMIO ハンドラとして登録する側)
+pub struct VirtioMmioSlot {
+    handle: VirtioSlotHandle,
+}
+
+impl VirtioMmioSlot {
+    /// 空のスロットと、そのハンドルを作成
+    pub fn new(index: u32, base: u64, irq: u32) -> (Self, VirtioSlotHandle) {
+        let handle = VirtioSlotHandle {
+            index,
+            base,
+            irq,
+            device: Arc::new(Mutex::new(None)),
+            dma: Arc::default(),
+        };
+        (
+            Self {
+                handle: handle.clone(),
+            },
+            handle,
+        )
+    }
+}
+
+impl MmioHandler for VirtioMmioSlot {
+    fn base(&self) -> u64 {
+        self.handle.base
+    }
+
+    fn size(&self) -> u64 {
+        crate::boot::layout::VIRTIO_SLOT_SIZE
+    }
+
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, Box<dyn Error>> {
+        if let Some(device) = self.handle.lock().as_mut() {
+            return device.read(offset, size);
+        }
+        // 空のスロット: Device ID 0 (デバイスなし) のトランスポート
+        let value = match offset {
+            regs::MAGIC_VALUE => VIRT_MAGIC,
+            regs::VERSION => TransportVersion::Modern.register_value(),
+            regs::VENDOR_ID => VIRT_VENDOR,
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    fn reset(&mut self) {
+        if let Some(device) = self.handle.lock().as_mut() {
+            device.reset();
+        }
+    }
+
+    fn write(&mut self, offset: u64, value: u64, size: usize) -> Result<(), Box<dyn Error>> {
+        match self.handle.lock().as_mut() {
+            Some(device) => device.write(offset, value, size),
+            None => Ok(()),
+        }
+    }
+
+    fn name(&self) -> &str {
+        "virtio-mmio"
+    }
+
+    fn irq(&self) -> Option<u32> {
+        Some(self.handle.irq)
+    }
+
+    fn pending_irq(&self) -> Option<u32> {
+        // デバイス自身の割り込み番号ではなくスロットの番号でアサートする
+        let slot = self.handle.lock();
+        slot.as_ref()?.pending_irq().map(|_| self.handle.irq)
+    }
+
+    fn queue_latency(&self) -> Option<LatencyStats> {
+        self.handle.lock().as_ref()?.queue_latency()
+    }
+
+    fn io_counters(&self) -> Option<IoCounters> {
+        self.handle.lock().as_ref()?.io_counters()
+    }
+
+    fn block_stats(&self) -> Option<BlockStats> {
+        self.handle.lock().as_ref()?.block_stats()
+    }
+
+    fn attach_dma(&mut self, dma: DmaValidator) {
+        *self.handle.dma.lock().unwrap() = Some(dma.clone());
+        if let Some(device) = self.handle.lock().as_mut() {
+            device.attach_dma(dma);
+        }
+    }
+
+    #[cfg(feature = "snapshot")]
+    fn as_device_state(&mut self) -> Option<&mut dyn DeviceState> {
+        Some(self)
+    }
+}
+
+#[cfg(feature = "snapshot")]
+impl DeviceState for VirtioMmioSlot {
+    /// 挿さっているか (bool) + デバイスの状態
+    fn save_state(&self) -> Vec<u8> {
+        let mut slot = self.handle.lock();
+        let device_state = slot
+            .as_mut()
+            .and_then(|device| device.as_device_state())
+            .map(|state| state.save_state());
+        let enc = StateEncoder::new().bool(slot.is_some());
+        match device_state {
+            Some(state) => enc.bytes(&state).finish(),
+            None => enc.finish(),
+        }
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut dec = StateDecoder::new(state);
+        let was_bound = dec.bool()?;
+        let mut slot = self.handle.lock();
+        match slot.as_mut() {
+            None if was_bound => Err(format!(
+                "virtio-mmio slot {} at 0x{:x} was bound on the source but is empty here",
+                self.handle.index, self.handle.base
+            )
+            .into()),
+            None => dec.finish(),
+            Some(device) => match device.as_device_state() {
+                Some(device_state) => device_state.restore_state(&state[1..]),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(all(feature = "virtio-blk", feature = "snapshot"))]
+    use crate::devices::virtio::VirtioBlockDevice;
+
+    #[test]
+    fn 空のスロットはデバイスなしのトランスポートに見える() {
+        let (mut slot, handle) = VirtioMmioSlot::new(1, 0x0a00_0200, 35);
+        assert_eq!(slot.read(regs::MAGIC_VALUE, 4).unwrap(), VIRT_MAGIC as u64);
+        assert_eq!(slot.read(regs::VERSION, 4).unwrap(), 2);
+        assert_eq!(slot.read(regs::DEVICE_ID, 4).unwrap(), 0);
+        slot.write(regs::STATUS, 0xf, 4).unwrap();
+        assert_eq!(slot.read(regs::STATUS, 4).unwrap(), 0);
+        assert_eq!(slot.irq(), Some(35));
+        assert!(!handle.is_bound());
+    }
+
+    #[test]
+    #[cfg(all(feature = "virtio-blk", feature = "snapshot"))]
+    fn bind_したデバイスにアクセスが渡る() {
+        let (mut slot, handle) = VirtioMmioSlot::new(0, 0x0a00_0000, 34);
+        // デバイスのベースアドレスはスロットと無関係
+        handle.bind(Box::new(VirtioBlockDevice::new(0))).unwrap();
+        assert_eq!(slot.read(regs::DEVICE_ID, 4).unwrap(), 2);
+        assert!(handle
+            .clone()
+            .bind(Box::new(VirtioBlockDevice::new(0)))
+            .is_err());
+
+        let saved = slot.save_state();
+        assert_eq!(saved[0], 1);
+        assert!(slot.restore_state(&saved).is_ok());
+
+        assert!(handle.unbind().is_some());
+        assert_eq!(slot.read(regs::DEVICE_ID, 4).unwrap(), 0);
+        // 挿さっていたスロットの状態は空のスロットに戻せない
+        assert!(slot.restore_state(&saved).is_err());
+    }
+
+    #[test]
+    fn 登録されたスロットは_bind_したデバイスにゲスト_ram_と割り込みを渡す() {
+        use crate::devices::virtio::VirtioNetDevice;
+        use crate::memory::testing::TestMemory;
+        use crate::mmio::SharedDevice;
+
+        let (mut slot, handle) = VirtioMmioSlot::new(2, 0x0a00_0400, 36);
+        slot.attach_dma(DmaValidator::new(Arc::new(TestMemory::new(
+            0x4000_0000,
+            0x10000,
+        ))));
+        let (device, net) = SharedDevice::new(VirtioNetDevice::new(0, [0x52, 0x54, 0, 0, 0, 1]));
+        handle.bind(Box::new(device)).unwrap();
+
+        // ゲスト RAM の外を指すキューは QueueReady で拒否される (検証器が渡っている)
+        slot.write(regs::QUEUE_SEL, 0, 4).unwrap();
+        slot.write(regs::QUEUE_DESC_LOW, 0x1000, 4).unwrap();
+        slot.write(regs::QUEUE_READY, 1, 4).unwrap();
+        assert!(net.lock().unwrap().queue_error().is_some());
+
+        net.lock().unwrap().set_link_up(false);
+        assert_eq!(net.lock().unwrap().pending_irq(), Some(36));
+    }
+}
@@ -0,0 +1,610 @@
+//! virtio-mmio トランスポートのバージョン処理
+//!
+//! virtio-mmio には 2 つのレジスタレイアウトがある:
+//!
+//! | Version | 仕様 | キューの配置 |
+//! |---------|------|--------------|
+//! | 1 (legacy) | virtio 0.9.5 | `GuestPageSize` と `QueuePFN` から 3 領域を連続配置 |
+//! | 2 (modern) | virtio 1.0+ | `QueueDesc/Driver/Device{Low,High}` で個別に指定 |
+//!
+//! Linux 4.0 より前のカーネルや一部のブートローダは legacy のみに対応する。
+//! modern デバイスに対して legacy のレジスタを書き込まれると、キューの位置が
+//! 分からないまま動作してしまうため、ここで検出して明示的なエラーにする。
+
+use super::dma::{DmaErrorKind, DmaValidator};
+use std::fmt;
+
+/// VirtIO MMIO マジック値 ("virt")
+pub(crate) const VIRT_MAGIC: u32 = 0x74726976;
+
+/// VirtIO Vendor ID ("QEMU")
+pub(crate) const VIRT_VENDOR: u32 = 0x554D4551;
+
+/// VirtIO MMIO レジスタオフセット
+#[allow(dead_code)]
+pub(crate) mod regs {
+    pub const MAGIC_VALUE: u64 = 0x00;
+    pub const VERSION: u64 = 0x04;
+    pub const DEVICE_ID: u64 = 0x08;
+    pub const VENDOR_ID: u64 = 0x0c;
+    pub const DEVICE_FEATURES: u64 = 0x10;
+    pub const DEVICE_FEATURES_SEL: u64 = 0x14;
+    pub const DRIVER_FEATURES: u64 = 0x20;
+    pub const DRIVER_FEATURES_SEL: u64 = 0x24;
+    pub const QUEUE_SEL: u64 = 0x30;
+    pub const QUEUE_NUM_MAX: u64 = 0x34;
+    pub const QUEUE_NUM: u64 = 0x38;
+    pub const QUEUE_READY: u64 = 0x44;
+    pub const QUEUE_NOTIFY: u64 = 0x50;
+    pub const INTERRUPT_STATUS: u64 = 0x60;
+    pub const INTERRUPT_ACK: u64 = 0x64;
+    pub const STATUS: u64 = 0x70;
+    pub const QUEUE_DESC_LOW: u64 = 0x80;
+    pub const QUEUE_DESC_HIGH: u64 = 0x84;
+    pub const QUEUE_DRIVER_LOW: u64 = 0x90;
+    pub const QUEUE_DRIVER_HIGH: u64 = 0x94;
+    pub const QUEUE_DEVICE_LOW: u64 = 0xa0;
+    pub const QUEUE_DEVICE_HIGH: u64 = 0xa4;
+    pub const CONFIG_GENERATION: u64 = 0xfc;
+    /// デバイス固有の設定領域の先頭
+    pub const CONFIG: u64 = 0x100;
+}
+
+/// legacy (version 1) のみに存在するレジスタ
+pub mod legacy_regs {
+    /// ゲストのページサイズ (WO)
+    pub const GUEST_PAGE_SIZE: u64 = 0x28;
+    /// Used Ring のアラインメント (WO)
+    pub const QUEUE_ALIGN: u64 = 0x3c;
+    /// キューのゲスト物理ページ番号 (RW)
+    pub const QUEUE_PFN: u64 = 0x40;
+}
+
+/// Status レジスタ: DRIVER_OK
+pub const STATUS_DRIVER_OK: u32 = 0x4;
+
+/// Status レジスタ: DEVICE_NEEDS_RESET
+pub const STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+
+/// InterruptStatus: キューに使用済みバッファを返した
+pub const INTERRUPT_VRING: u32 = 0x1;
+/// InterruptStatus: デバイスの設定領域が変わった
+pub const INTERRUPT_CONFIG: u32 = 0x2;
+
+/// Feature: virtio 1.0 以降のデバイス (modern のトランスポートでは必須)
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// legacy の GuestPageSize が書き込まれない場合の既定値
+const DEFAULT_GUEST_PAGE_SIZE: u32 = 4096;
+/// legacy の QueueAlign が書き込まれない場合の既定値
+const DEFAULT_QUEUE_ALIGN: u32 = 4096;
+
+/// デバイスが公開するトランスポートのバージョン
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportVersion {
+    /// Version 1 (legacy) のレイアウトをエミュレートする
+    Legacy,
+    /// Version 2 (modern)
+    #[default]
+    Modern,
+}
+
+impl TransportVersion {
+    /// VERSION レジスタの値
+    pub fn register_value(self) -> u32 {
+        match self {
+            TransportVersion::Legacy => 1,
+            TransportVersion::Modern => 2,
+        }
+    }
+}
+
+/// キューの 3 領域のゲスト物理アドレス
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QueueAddrs {
+    /// Descriptor Table
+    pub desc: u64,
+    /// Available Ring (driver area)
+    pub driver: u64,
+    /// Used Ring (device area)
+    pub device: u64,
+}
+
+impl QueueAddrs {
+    /// legacy レイアウトでの 3 領域の位置を求める
+    ///
+    /// Descriptor Table (16 bytes x num) の直後に Available Ring
+    /// (flags, idx, ring[num], used_event) が続き、Used Ring は `align` に揃えて配置される。
+    /// 値はすべてゲストが書くため、アドレスの計算があふれる配置はエラーにする。
+    pub fn legacy(
+        pfn: u32,
+        page_size: u32,
+        align: u32,
+        num: u16,
+    ) -> Result<Self, QueueConfigError> {
+        let overflow = QueueConfigError::LegacyOverflow {
+            pfn,
+            page_size,
+            align,
+        };
+        let desc = (pfn as u64).checked_mul(page_size as u64).ok_or(overflow)?;
+        let driver = desc.checked_add(16 * num as u64).ok_or(overflow)?;
+        let device = driver
+            .checked_add(6 + 2 * num as u64)
+            .and_then(|end| end.checked_next_multiple_of(align.max(1) as u64))
+            .ok_or(overflow)?;
+        Ok(Self {
+            desc,
+            driver,
+            device,
+        })
+    }
+}
+
+/// ドライバが 1 つのキューに設定した値
+///
+/// QueueSel で選んだキューごとに持つ。ドライバは QueueNum と 3 領域のアドレスを
+/// 書いてから QueueReady に 1 を書き、デバイスはその時点で設定を検証して確定する。
+/// 確定したキューへの QueueNum・アドレスの書き込みは、ドライバが QueueReady を 0 に
+/// 戻すかデバイスをリセットするまで無視する (virtio 1.2 §4.2.2.2)。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// QueueNum
+    pub num: u16,
+    /// 3 領域のゲスト物理アドレス
+    pub addrs: QueueAddrs,
+    /// QueueReady (設定を確定したか)
+    pub ready: bool,
+}
+
+impl QueueConfig {
+    /// キューサイズ `num` の未設定のキュー
+    pub fn new(num: u16) -> Self {
+        Self {
+            num,
+            addrs: QueueAddrs::default(),
+            ready: false,
+        }
+    }
+
+    /// modern のキューアドレスレジスタ (Low/High) への書き込み
+    ///
+    /// 64 ビットのアドレスの、`offset` が指す半分だけを置き換える。
+    pub fn write_addr(&mut self, offset: u64, value: u32) {
+        let (addr, high) = match offset {
+            regs::QUEUE_DESC_LOW => (&mut self.addrs.desc, false),
+            regs::QUEUE_DESC_HIGH => (&mut self.addrs.desc, true),
+            regs::QUEUE_DRIVER_LOW => (&mut self.addrs.driver, false),
+            regs::QUEUE_DRIVER_HIGH => (&mut self.addrs.driver, true),
+            regs::QUEUE_DEVICE_LOW => (&mut self.addrs.device, false),
+            _ => (&mut self.addrs.device, true),
+        };
+        *addr = if high {
+            (*addr & 0xFFFF_FFFF) | ((value as u64) << 32)
+        } else {
+            (*addr & !0xFFFF_FFFF) | value as u64
+        };
+    }
+
+    /// 3 領域の (名前, アドレス, 長さ, アラインメント) (virtio 1.2 §2.7)
+    fn areas(&self) -> [(&'static str, u64, u32, u64); 3] {
+        let num = self.num as u32;
+        [
+            ("descriptor table", self.addrs.desc, 16 * num, 16),
+            ("driver area", self.addrs.driver, 6 + 2 * num, 2),
+            ("device area", self.addrs.device, 6 + 8 * num, 4),
+        ]
+    }
+
+    /// 設定を検証する
+    ///
+    /// キューサイズと 3 領域のアラインメントを確かめ、`dma` があれば 3 領域が
+    /// ゲスト RAM に収まることも確かめる。
+    pub fn validate(&self, dma: Option<&DmaValidator>) -> Result<(), QueueConfigError> {
+        if !self.num.is_power_of_two() {
+            return Err(QueueConfigError::InvalidSize(self.num));
+        }
+        for (area, addr, len, align) in self.areas() {
+            if !addr.is_multiple_of(align) {
+                return Err(QueueConfigError::Misaligned { area, addr, align });
+            }
+            if let Some(Err(kind)) = dma.map(|dma| dma.check(addr, len)) {
+                return Err(QueueConfigError::OutsideRam {
+                    area,
+                    addr,
+                    len,
+                    kind,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// QueueReady で確定できなかったキューの設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueConfigError {
+    /// QueueNum が 0 か 2 のべき乗でない
+    InvalidSize(u16),
+    /// 領域がアラインメントに揃っていない
+    Misaligned {
+        /// 領域の名前
+        area: &'static str,
+        /// 書き込まれたアドレス
+        addr: u64,
+        /// 必要なアラインメント
+        align: u64,
+    },
+    /// 領域がゲスト RAM に収まらない
+    OutsideRam {
+        /// 領域の名前
+        area: &'static str,
+        /// 書き込まれたアドレス
+        addr: u64,
+        /// 領域の長さ
+        len: u32,
+        /// 理由
+        kind: DmaErrorKind,
+    },
+    /// legacy レイアウトのアドレスが 64 ビットを越える
+    LegacyOverflow {
+        /// QueuePFN
+        pfn: u32,
+        /// GuestPageSize
+        page_size: u32,
+        /// QueueAlign
+        align: u32,
+    },
+}
+
+impl fmt::Display for QueueConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidSize(num) => {
+                write!(f, "queue size {} is not a non-zero power of two", num)
+            }
+            Self::Misaligned { area, addr, align } => write!(
+                f,
+                "queue {} at 0x{:x} is not aligned to {} bytes",
+                area, addr, align
+            ),
+            Self::OutsideRam {
+                area,
+                addr,
+                len,
+                kind,
+            } => write!(
+                f,
+                "queue {} at 0x{:x} (+0x{:x}) is rejected: {}",
+                area, addr, len, kind
+            ),
+            Self::LegacyOverflow {
+                pfn,
+                page_size,
+                align,
+            } => write!(
+                f,
+                "legacy queue at pfn 0x{:x} (page size {}, align {}) overflows the address space",
+                pfn, page_size, align
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QueueConfigError {}
+
+/// modern デバイスに対する legacy レジスタの書き込み
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LegacyAccessError {
+    /// 書き込まれたレジスタのオフセット
+    pub offset: u64,
+}
+
+impl fmt::Display for LegacyAccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.offset {
+            legacy_regs::GUEST_PAGE_SIZE => "GuestPageSize",
+            legacy_regs::QUEUE_ALIGN => "QueueAlign",
+            _ => "QueuePFN",
+        };
+        write!(
+            f,
+            "guest wrote legacy virtio-mmio register {} (0x{:03x}) to a version 2 device; \
+             the driver only supports virtio-mmio version 1 (Linux < 4.0?). \
+             Use TransportVersion::Legacy for this guest",
+            name, self.offset
+        )
+    }
+}
+
+impl std::error::Error for LegacyAccessError {}
+
+/// legacy レジスタの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LegacyState {
+    /// GuestPageSize
+    pub page_size: u32,
+    /// QueueAlign
+    pub align: u32,
+    /// QueuePFN (0 = キュー未使用)
+    pub pfn: u32,
+}
+
+impl Default for LegacyState {
+    fn default() -> Self {
+        Self {
+            page_size: DEFAULT_GUEST_PAGE_SIZE,
+            align: DEFAULT_QUEUE_ALIGN,
+            pfn: 0,
+        }
+    }
+}
+
+/// InterruptStatus と ConfigGeneration
+///
+/// ディスクのサイズ変更やリンク状態の変化など、設定領域を書き換えたデバイスは
+/// [`notify_config_changed`](Self::notify_config_changed) を呼ぶ。ドライバは割り込みを
+/// 受けて設定領域を読み直し、読んでいる間に ConfigGeneration が変わっていれば読み直す。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InterruptState {
+    status: u32,
+    config_generation: u32,
+}
+
+impl InterruptState {
+    /// 設定領域の変更を通知する (ConfigGeneration を進め、割り込みをアサートする)
+    pub fn notify_config_changed(&mut self) {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        self.status |= INTERRUPT_CONFIG;
+    }
+
+    /// Used Ring の更新を通知する (割り込みをアサートする)
+    pub fn notify_used(&mut self) {
+        self.status |= INTERRUPT_VRING;
+    }
+
+    /// ドライバの InterruptACK の書き込みで、`bits` の割り込みを下げる
+    pub fn ack(&mut self, bits: u32) {
+        self.status &= !bits;
+    }
+
+    /// InterruptStatus の値
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    /// ConfigGeneration の値
+    pub fn config_generation(&self) -> u32 {
+        self.config_generation
+    }
+
+    /// 割り込み線をアサートしているか
+    pub fn is_asserted(&self) -> bool {
+        self.status != 0
+    }
+
+    /// 割り込みを下げる (デバイスのリセット。ConfigGeneration は設定領域と同じく残す)
+    pub fn reset(&mut self) {
+        self.status = 0;
+    }
+
+    /// 保存した状態から復元する
+    pub fn from_raw(status: u32, config_generation: u32) -> Self {
+        Self {
+            status: status & (INTERRUPT_VRING | INTERRUPT_CONFIG),
+            config_generation,
+        }
+    }
+}
+
+/// ドライバによる Status レジスタへの書き込み
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusWrite {
+    /// デバイスのリセット要求 (0 の書き込み)
+    Reset,
+    /// ステータスビットの更新 (書き込み後の Status の値)
+    Set(u32),
+}
+
+impl StatusWrite {
+    /// 現在の Status `current` への書き込み `value` を解釈する
+    ///
+    /// virtio 1.2 §4.2.2.1: Status への 0 の書き込みはリセット要求で、デバイスは
+    /// キュー・ネゴシエーション済みの機能・割り込みをすべて初期状態に戻す。
+    /// カーネルのエラー回復 (`virtio_reset_device`) はこれを前提に初期化をやり直すため、
+    /// 値をそのまま保存すると前回のキューの状態が残ってしまう。
+    ///
+    /// DEVICE_NEEDS_RESET はデバイスが立てるビットで、ドライバの書き込みでは
+    /// 下がらない (§2.1.2)。ドライバが値を読み直さずに書き込んでも、リセットまで残す。
+    pub fn decode(current: u32, value: u32) -> Self {
+        match value {
+            0 => Self::Reset,
+            bits => Self::Set(bits | (current & STATUS_DEVICE_NEEDS_RESET)),
+        }
+    }
+}
+
+/// legacy レジスタかどうか
+pub fn is_legacy_register(offset: u64) -> bool {
+    matches!(
+        offset,
+        legacy_regs::GUEST_PAGE_SIZE | legacy_regs::QUEUE_ALIGN | legacy_regs::QUEUE_PFN
+    )
+}
+
+/// QueueSel が選んだ、まだ確定していないキュー (範囲外か確定済みなら None)
+pub fn configurable_queue(queues: &mut [QueueConfig], sel: u32) -> Option<&mut QueueConfig> {
+    queues.get_mut(sel as usize).filter(|queue| !queue.ready)
+}
+
+/// QueueSel が選んだキューの設定を検証して確定する
+///
+/// 不正な設定はゲスト RAM の外への DMA になるため確定せず、ホストには `device` の
+/// 名前で原因を表示する。デバイスは返したエラーを覚え、ドライバにリセットが
+/// 必要と通知する。
+pub fn latch_queue(
+    queues: &mut [QueueConfig],
+    sel: u32,
+    dma: Option<&DmaValidator>,
+    device: &str,
+) -> Result<(), QueueConfigError> {
+    let Some(queue) = queues.get_mut(sel as usize) else {
+        return Ok(());
+    };
+    queue.validate(dma).inspect_err(|err| {
+        eprintln!("[VIRTIO] {}: queue {}: {}", device, sel, err);
+    })?;
+    queue.ready = true;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_レイアウトは3領域を連続配置する() {
+        let addrs = QueueAddrs::legacy(0x48000, 4096, 4096, 16).unwrap();
+        assert_eq!(addrs.desc, 0x4800_0000);
+        assert_eq!(addrs.driver, 0x4800_0100);
+        // avail: 6 + 2*16 = 38 bytes の後、4096 に揃える
+        assert_eq!(addrs.device, 0x4800_1000);
+
+        let addrs = QueueAddrs::legacy(1, 4096, 4, 16).unwrap();
+        assert_eq!(addrs.device, 0x1000 + 0x100 + 40);
+
+        // ゲストが書ける最大の値でもアドレスの計算はあふれない
+        let addrs = QueueAddrs::legacy(u32::MAX, u32::MAX, u32::MAX, u16::MAX).unwrap();
+        assert_eq!(addrs.desc, (u32::MAX as u64).pow(2));
+        assert_eq!(addrs.device, (u32::MAX as u64) << 32);
+        let err = QueueConfigError::LegacyOverflow {
+            pfn: 1,
+            page_size: 4096,
+            align: 4096,
+        };
+        assert!(err.to_string().contains("overflows the address space"));
+    }
+
+    #[test]
+    fn 確定したキューは設定を変えられない() {
+        let mut queues = [QueueConfig::new(16), QueueConfig::new(16)];
+        queues[1].num = 3;
+        assert_eq!(
+            latch_queue(&mut queues, 1, None, "virtio-test"),
+            Err(QueueConfigError::InvalidSize(3))
+        );
+        assert!(configurable_queue(&mut queues, 1).is_some());
+
+        assert_eq!(latch_queue(&mut queues, 0, None, "virtio-test"), Ok(()));
+        assert!(queues[0].ready);
+        assert!(configurable_queue(&mut queues, 0).is_none());
+        // 存在しないキューは選べない
+        assert!(configurable_queue(&mut queues, 2).is_none());
+        assert_eq!(latch_queue(&mut queues, 2, None, "virtio-test"), Ok(()));
+    }
+
+    #[test]
+    fn キューアドレスは_low_と_high_を別々に書ける() {
+        let mut queue = QueueConfig::new(16);
+        queue.write_addr(regs::QUEUE_DESC_HIGH, 0x2);
+        queue.write_addr(regs::QUEUE_DESC_LOW, 0x4800_0000);
+        queue.write_addr(regs::QUEUE_DRIVER_LOW, 0x4800_0100);
+        queue.write_addr(regs::QUEUE_DEVICE_HIGH, 0x1);
+        assert_eq!(
+            queue.addrs,
+            QueueAddrs {
+                desc: 0x2_4800_0000,
+                driver: 0x4800_0100,
+                device: 0x1_0000_0000,
+            }
+        );
+        queue.write_addr(regs::QUEUE_DESC_LOW, 0x4900_0000);
+        assert_eq!(queue.addrs.desc, 0x2_4900_0000);
+    }
+
+    #[test]
+    fn キューの設定をゲスト_ram_と照らして検証する() {
+        use crate::memory::testing::TestMemory;
+        use std::sync::Arc;
+
+        let dma = DmaValidator::new(Arc::new(TestMemory::new(0x4000_0000, 0x10000)));
+        let mut queue = QueueConfig {
+            num: 16,
+            addrs: QueueAddrs::legacy(0x40000, 4096, 4096, 16).unwrap(),
+            ready: false,
+        };
+        assert_eq!(queue.validate(Some(&dma)), Ok(()));
+
+        // Used Ring の末尾が RAM からはみ出す
+        queue.addrs.device = 0x4000_fff0;
+        let err = queue.validate(Some(&dma)).unwrap_err();
+        assert_eq!(
+            err,
+            QueueConfigError::OutsideRam {
+                area: "device area",
+                addr: 0x4000_fff0,
+                len: 6 + 8 * 16,
+                kind: DmaErrorKind::OutsideRam,
+            }
+        );
+        // RAM が分からなければアラインメントだけを見る
+        assert_eq!(queue.validate(None), Ok(()));
+
+        queue.addrs.desc = 0x4000_0008;
+        assert!(queue
+            .validate(None)
+            .unwrap_err()
+            .to_string()
+            .contains("16 bytes"));
+        queue.num = 12;
+        assert_eq!(queue.validate(None), Err(QueueConfigError::InvalidSize(12)));
+    }
+
+    #[test]
+    fn legacy_レジスタの誤用はレジスタ名入りのエラーにする() {
+        assert!(is_legacy_register(legacy_regs::QUEUE_PFN));
+        assert!(!is_legacy_register(0x44));
+
+        let err = LegacyAccessError {
+            offset: legacy_regs::QUEUE_PFN,
+        };
+        assert!(err.to_string().contains("QueuePFN"));
+        assert_eq!(TransportVersion::default().register_value(), 2);
+    }
+
+    #[test]
+    fn 設定の変更は割り込みと_config_generation_で通知する() {
+        let mut irq = InterruptState::default();
+        assert!(!irq.is_asserted());
+
+        irq.notify_config_changed();
+        assert_eq!(irq.status(), INTERRUPT_CONFIG);
+        assert_eq!(irq.config_generation(), 1);
+
+        // ACK したビットだけ下がる
+        irq.ack(INTERRUPT_VRING);
+        assert_eq!(irq.status(), INTERRUPT_CONFIG);
+        irq.ack(INTERRUPT_CONFIG);
+        assert!(!irq.is_asserted());
+
+        irq.notify_config_changed();
+        irq.reset();
+        assert!(!irq.is_asserted());
+        assert_eq!(irq.config_generation(), 2);
+    }
+
+    #[test]
+    fn status_への_0_の書き込みはリセット要求() {
+        assert_eq!(StatusWrite::decode(0x0f, 0), StatusWrite::Reset);
+        assert_eq!(StatusWrite::decode(0, 0x0f), StatusWrite::Set(0x0f));
+        // DEVICE_NEEDS_RESET はドライバの書き込みでは下がらない
+        assert_eq!(
+            StatusWrite::decode(0x4f, 0x0f),
+            StatusWrite::Set(0x0f | STATUS_DEVICE_NEEDS_RESET)
+        );
+        assert_eq!(
+            StatusWrite::decode(STATUS_DEVICE_NEEDS_RESET, 0),
+            StatusWrite::Reset
+        );
+    }
+}
@@ -0,0 +1,607 @@
+//! ユーザーモードのネットワークバックエンド (DHCP / TFTP によるネットワークブート)
+//!
+//! ホストのネットワークを使わず、virtio-net のゲストにだけ見える小さなネットワークを
+//! プロセス内に作る。QEMU の user ネットワーク (slirp) と同じアドレスを使い、
+//! ホスト (ゲートウェイ兼サーバー) は 10.0.2.2、ゲストは 10.0.2.15 になる。
+//!
+//! 応答するのは次の 3 つだけで、それ以外のフレームは捨てる。
+//! - ホストのアドレスへの ARP 要求
+//! - DHCP (DISCOVER / REQUEST に OFFER / ACK を返す。ブートファイル名と TFTP サーバーを含む)
+//! - TFTP の読み出し (RFC 1350、512 バイトのブロック、オプションは無視する)
+//!
+//! ディスクイメージを作り直さずにカーネルを差し替えられるよう、U-Boot の `dhcp` /
+//! `tftpboot` (または `pxe get` / `pxe boot`) でカーネルを読み込ませる。
+//!
+//! ```ignore
+//! let mut net = UserNet::new();
+//! net.add_file("Image", std::fs::read("Image")?);
+//! net.set_boot_file("Image");
+//! let device = VirtioNetDevice::new(0, mac).with_backend(Box::new(net));
+//! slots[0].bind(Box::new(device))?;
+//! hv.boot_uboot(&uboot, "console=ttyAMA0")?; // bootcmd: dhcp; booti ${loadaddr} - ${fdtcontroladdr}
+//! ```
+
+use crate::devices::virtio::net::NetBackend;
+use std::collections::HashMap;
+
+/// EtherType: IPv4
+const ETHERTYPE_IPV4: u16 = 0x0800;
+/// EtherType: ARP
+const ETHERTYPE_ARP: u16 = 0x0806;
+/// IPv4 のプロトコル番号: UDP
+const IPPROTO_UDP: u8 = 17;
+
+/// DHCP サーバーのポート
+const DHCP_SERVER_PORT: u16 = 67;
+/// DHCP クライアントのポート
+const DHCP_CLIENT_PORT: u16 = 68;
+/// TFTP サーバーのポート
+const TFTP_PORT: u16 = 69;
+/// 転送ごとにサーバー側で使うポート (TID) の最初の番号
+const TFTP_FIRST_TID: u16 = 0x8000;
+
+/// Ethernet ヘッダの大きさ
+const ETH_HEADER: usize = 14;
+/// IPv4 ヘッダの大きさ (オプションなし)
+const IPV4_HEADER: usize = 20;
+/// UDP ヘッダの大きさ
+const UDP_HEADER: usize = 8;
+/// パディングを含む Ethernet フレームの最小の大きさ (FCS を除く)
+const ETH_MIN_FRAME: usize = 60;
+
+/// DHCP メッセージの固定部分 (BOOTP) の大きさ
+const BOOTP_SIZE: usize = 236;
+/// DHCP の magic cookie
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+/// DHCP メッセージタイプ
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+/// DHCP のリース期間 (秒)
+const DHCP_LEASE_SECS: u32 = 86400;
+
+/// TFTP のオペコード
+const TFTP_RRQ: u16 = 1;
+const TFTP_DATA: u16 = 3;
+const TFTP_ACK: u16 = 4;
+const TFTP_ERROR: u16 = 5;
+/// TFTP のブロックの大きさ
+const TFTP_BLOCK_SIZE: usize = 512;
+/// TFTP のエラーコード: ファイルがない
+const TFTP_ENOTFOUND: u16 = 1;
+/// TFTP のエラーコード: 不正な操作
+const TFTP_EBADOP: u16 = 4;
+
+/// 進行中の TFTP の読み出し
+struct Transfer {
+    /// ゲスト側のポート
+    client_port: u16,
+    /// 読み出しているファイル
+    file: String,
+    /// 最後に送ったブロックの通し番号 (1 から。パケットのブロック番号はこの下位 16 bit)
+    block: usize,
+}
+
+/// ユーザーモードのネットワーク (DHCP / TFTP サーバー)
+pub struct UserNet {
+    /// ホスト (ゲートウェイ) の MAC アドレス
+    host_mac: [u8; 6],
+    /// ホストの IPv4 アドレス
+    host_ip: [u8; 4],
+    /// ゲストに割り当てる IPv4 アドレス
+    guest_ip: [u8; 4],
+    /// サブネットマスク
+    netmask: [u8; 4],
+    /// DHCP で知らせるブートファイル名
+    boot_file: Option<String>,
+    /// TFTP で読み出せるファイル
+    files: HashMap<String, Vec<u8>>,
+    /// サーバー側のポート (TID) ごとの転送
+    transfers: HashMap<u16, Transfer>,
+    /// 次の転送に使うポート
+    next_tid: u16,
+}
+
+impl UserNet {
+    /// 10.0.2.0/24 のネットワークを作成 (ホスト 10.0.2.2、ゲスト 10.0.2.15)
+    pub fn new() -> Self {
+        Self {
+            host_mac: [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02],
+            host_ip: [10, 0, 2, 2],
+            guest_ip: [10, 0, 2, 15],
+            netmask: [255, 255, 255, 0],
+            boot_file: None,
+            files: HashMap::new(),
+            transfers: HashMap::new(),
+            next_tid: TFTP_FIRST_TID,
+        }
+    }
+
+    /// TFTP で読み出せるファイルを追加する (同じ名前のファイルは置き換える)
+    pub fn add_file(&mut self, name: &str, data: Vec<u8>) {
+        self.files
+            .insert(name.trim_start_matches('/').to_string(), data);
+    }
+
+    /// DHCP で知らせるブートファイル名を設定する (U-Boot の `dhcp` が自動で読み込む)
+    ///
+    /// 長い名前は BOOTP の `file` (127 バイト) と option 67 (255 バイト) に収まるよう切り詰めて渡す。
+    pub fn set_boot_file(&mut self, name: &str) {
+        self.boot_file = Some(name.to_string());
+    }
+
+    /// ゲストに割り当てる IPv4 アドレス
+    pub fn guest_ip(&self) -> [u8; 4] {
+        self.guest_ip
+    }
+
+    /// ホスト (DHCP / TFTP サーバー) の IPv4 アドレス
+    pub fn host_ip(&self) -> [u8; 4] {
+        self.host_ip
+    }
+
+    /// 進行中の TFTP の転送の数
+    pub fn active_transfers(&self) -> usize {
+        self.transfers.len()
+    }
+
+    /// ARP 要求に応答する
+    fn handle_arp(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        let arp = frame.get(ETH_HEADER..ETH_HEADER + 28)?;
+        // Ethernet / IPv4 の要求 (opcode 1) で、ホストのアドレスを尋ねるものだけ
+        if arp[0..8] != [0, 1, 8, 0, 6, 4, 0, 1] || arp[24..28] != self.host_ip {
+            return None;
+        }
+        let (sender_mac, sender_ip) = (&arp[8..14], &arp[14..18]);
+        let mut reply = ethernet_header(sender_mac, &self.host_mac, ETHERTYPE_ARP);
+        reply.extend_from_slice(&[0, 1, 8, 0, 6, 4, 0, 2]);
+        reply.extend_from_slice(&self.host_mac);
+        reply.extend_from_slice(&self.host_ip);
+        reply.extend_from_slice(sender_mac);
+        reply.extend_from_slice(sender_ip);
+        Some(pad(reply))
+    }
+
+    /// DHCP の DISCOVER / REQUEST に応答する
+    fn handle_dhcp(&self, msg: &[u8]) -> Option<Vec<u8>> {
+        // BOOTREQUEST (op 1) の Ethernet (htype 1, hlen 6) だけ
+        if msg.len() < BOOTP_SIZE + 4 || msg[0..3] != [1, 1, 6] {
+            return None;
+        }
+        if msg[BOOTP_SIZE..BOOTP_SIZE + 4] != DHCP_MAGIC {
+            return None;
+        }
+        let reply_type = match dhcp_message_type(&msg[BOOTP_SIZE + 4..])? {
+            DHCPDISCOVER => DHCPOFFER,
+            DHCPREQUEST => DHCPACK,
+            _ => return None,
+        };
+
+        let mut bootp = vec![0u8; BOOTP_SIZE];
+        bootp[0..4].copy_from_slice(&[2, 1, 6, 0]);
+        // xid, secs, flags はそのまま返す
+        bootp[4..12].copy_from_slice(&msg[4..12]);
+        bootp[16..20].copy_from_slice(&self.guest_ip);
+        // siaddr: 次に使うサーバー (TFTP)
+        bootp[20..24].copy_from_slice(&self.host_ip);
+        bootp[28..44].copy_from_slice(&msg[28..44]);
+        if let Some(file) = &self.boot_file {
+            let len = file.len().min(127);
+            bootp[108..108 + len].copy_from_slice(&file.as_bytes()[..len]);
+        }
+        bootp.extend_from_slice(&DHCP_MAGIC);
+        bootp.extend_from_slice(&[53, 1, reply_type]);
+        push_option(&mut bootp, 54, &self.host_ip);
+        push_option(&mut bootp, 51, &DHCP_LEASE_SECS.to_be_bytes());
+        push_option(&mut bootp, 1, &self.netmask);
+        push_option(&mut bootp, 3, &self.host_ip);
+        let server_name = self.host_ip.map(|b| b.to_string()).join(".");
+        push_option(&mut bootp, 66, server_name.as_bytes());
+        if let Some(file) = &self.boot_file {
+            push_option(&mut bootp, 67, file.as_bytes());
+        }
+        bootp.push(255);
+
+        // ゲストはまだアドレスを持たないため、ブロードキャストで返す
+        Some(udp_frame(
+            &[0xff; 6],
+            &self.host_mac,
+            self.host_ip,
+            [255; 4],
+            DHCP_SERVER_PORT,
+            DHCP_CLIENT_PORT,
+            &bootp,
+        ))
+    }
+
+    /// TFTP の読み出し要求 (RRQ) を受けて転送を始める
+    fn handle_tftp_request(
+        &mut self,
+        client_mac: &[u8],
+        client_port: u16,
+        msg: &[u8],
+    ) -> Option<Vec<u8>> {
+        if u16::from_be_bytes([*msg.first()?, *msg.get(1)?]) != TFTP_RRQ {
+            return Some(self.tftp_error(
+                client_mac,
+                TFTP_PORT,
+                client_port,
+                TFTP_EBADOP,
+                "only read requests are supported",
+            ));
+        }
+        // ファイル名 NUL モード NUL (その後のオプションは無視する)
+        let name = msg[2..].split(|&b| b == 0).next()?;
+        let name = String::from_utf8_lossy(name)
+            .trim_start_matches('/')
+            .to_string();
+        if !self.files.contains_key(&name) {
+            return Some(self.tftp_error(
+                client_mac,
+                TFTP_PORT,
+                client_port,
+                TFTP_ENOTFOUND,
+                "file not found",
+            ));
+        }
+        let tid = self.next_tid;
+        self.next_tid = self.next_tid.checked_add(1).unwrap_or(TFTP_FIRST_TID);
+        self.transfers.insert(
+            tid,
+            Transfer {
+                client_port,
+                file: name,
+                block: 1,
+            },
+        );
+        self.tftp_data(client_mac, tid)
+    }
+
+    /// 転送中のポートへの ACK / ERROR を処理する
+    fn handle_tftp_transfer(&mut self, client_mac: &[u8], tid: u16, msg: &[u8]) -> Option<Vec<u8>> {
+        let opcode = u16::from_be_bytes([*msg.first()?, *msg.get(1)?]);
+        let block = u16::from_be_bytes([*msg.get(2)?, *msg.get(3)?]);
+        if opcode != TFTP_ACK {
+            // ERROR などで中止された
+            self.transfers.remove(&tid);
+            return None;
+        }
+        let transfer = self.transfers.get_mut(&tid)?;
+        if block != transfer.block as u16 {
+            // 重複した ACK: 最後のブロックを送り直す
+            return self.tftp_data(client_mac, tid);
+        }
+        let len = self.files.get(&transfer.file).map_or(0, Vec::len);
+        if transfer.block * TFTP_BLOCK_SIZE > len {
+            // 512 バイト未満のブロックの ACK で転送は終わり
+            self.transfers.remove(&tid);
+            return None;
+        }
+        // 32MB を超えるファイルではブロック番号が 0 に戻る (U-Boot も Linux の tftp も対応する)
+        transfer.block += 1;
+        self.tftp_data(client_mac, tid)
+    }
+
+    /// 転送中のブロックを DATA パケットにする
+    fn tftp_data(&self, client_mac: &[u8], tid: u16) -> Option<Vec<u8>> {
+        let transfer = self.transfers.get(&tid)?;
+        let file = self.files.get(&transfer.file)?;
+        let start = (transfer.block - 1) * TFTP_BLOCK_SIZE;
+        let end = (start + TFTP_BLOCK_SIZE).min(file.len());
+        let mut data = Vec::with_capacity(4 + TFTP_BLOCK_SIZE);
+        data.extend_from_slice(&TFTP_DATA.to_be_bytes());
+        data.extend_from_slice(&(transfer.block as u16).to_be_bytes());
+        data.extend_from_slice(&file[start.min(end)..end]);
+        Some(udp_frame(
+            client_mac,
+            &self.host_mac,
+            self.host_ip,
+            self.guest_ip,
+            tid,
+            transfer.client_port,
+            &data,
+        ))
+    }
+
+    /// TFTP の ERROR パケット
+    fn tftp_error(
+        &self,
+        client_mac: &[u8],
+        src_port: u16,
+        client_port: u16,
+        code: u16,
+        message: &str,
+    ) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&TFTP_ERROR.to_be_bytes());
+        data.extend_from_slice(&code.to_be_bytes());
+        data.extend_from_slice(message.as_bytes());
+        data.push(0);
+        udp_frame(
+            client_mac,
+            &self.host_mac,
+            self.host_ip,
+            self.guest_ip,
+            src_port,
+            client_port,
+            &data,
+        )
+    }
+
+    /// IPv4 / UDP のフレームを振り分ける
+    fn handle_udp(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        let ip = frame.get(ETH_HEADER..)?;
+        let header_len = ((*ip.first()? & 0xf) as usize) * 4;
+        if ip[0] >> 4 != 4 || *ip.get(9)? != IPPROTO_UDP || header_len < IPV4_HEADER {
+            return None;
+        }
+        let total_len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+        let udp = ip.get(header_len..total_len)?;
+        let src_port = u16::from_be_bytes([*udp.first()?, *udp.get(1)?]);
+        let dst_port = u16::from_be_bytes([*udp.get(2)?, *udp.get(3)?]);
+        let payload = udp.get(UDP_HEADER..)?;
+        let client_mac = &frame[6..12];
+        match dst_port {
+            DHCP_SERVER_PORT => self.handle_dhcp(payload),
+            TFTP_PORT => self.handle_tftp_request(client_mac, src_port, payload),
+            tid if self.transfers.contains_key(&tid) => {
+                self.handle_tftp_transfer(client_mac, tid, payload)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for UserNet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NetBackend for UserNet {
+    fn transmit(&mut self, frame: &[u8]) -> Vec<Vec<u8>> {
+        let Some(ethertype) = frame.get(12..14) else {
+            return Vec::new();
+        };
+        let reply = match u16::from_be_bytes([ethertype[0], ethertype[1]]) {
+            ETHERTYPE_ARP => self.handle_arp(frame),
+            ETHERTYPE_IPV4 => self.handle_udp(frame),
+            _ => None,
+        };
+        reply.into_iter().collect()
+    }
+}
+
+/// Ethernet ヘッダ
+fn ethernet_header(dst: &[u8], src: &[u8], ethertype: u16) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ETH_MIN_FRAME);
+    frame.extend_from_slice(dst);
+    frame.extend_from_slice(src);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame
+}
+
+/// Ethernet の最小の大きさまで 0 で埋める
+fn pad(mut frame: Vec<u8>) -> Vec<u8> {
+    if frame.len() < ETH_MIN_FRAME {
+        frame.resize(ETH_MIN_FRAME, 0);
+    }
+    frame
+}
+
+/// DHCP オプションを追加する
+///
+/// 長さは 1 バイトなので、255 バイトを超える値 (長いブートファイル名) は切り詰める。
+fn push_option(buf: &mut Vec<u8>, code: u8, value: &[u8]) {
+    let value = &value[..value.len().min(u8::MAX as usize)];
+    buf.push(code);
+    buf.push(value.len() as u8);
+    buf.extend_from_slice(value);
+}
+
+/// DHCP オプションからメッセージタイプ (option 53) を探す
+fn dhcp_message_type(options: &[u8]) -> Option<u8> {
+    let mut rest = options;
+    loop {
+        match *rest.first()? {
+            // pad
+            0 => rest = &rest[1..],
+            255 => return None,
+            code => {
+                let len = *rest.get(1)? as usize;
+                let value = rest.get(2..2 + len)?;
+                if code == 53 && len == 1 {
+                    return Some(value[0]);
+                }
+                rest = &rest[2 + len..];
+            }
+        }
+    }
+}
+
+/// IPv4 ヘッダのチェックサム (RFC 791)
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let sum = header
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+    let folded = (sum & 0xffff) + (sum >> 16);
+    !(((folded & 0xffff) + (folded >> 16)) as u16)
+}
+
+/// Ethernet / IPv4 / UDP のフレームを組み立てる (UDP のチェックサムは省略する)
+fn udp_frame(
+    dst_mac: &[u8],
+    src_mac: &[u8],
+    src_ip: [u8; 4],
+    dst_ip: [u8; 4],
+    src_port: u16,
+    dst_port: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = (UDP_HEADER + payload.len()) as u16;
+    let total_len = IPV4_HEADER as u16 + udp_len;
+    let mut ip = vec![0x45, 0];
+    ip.extend_from_slice(&total_len.to_be_bytes());
+    // identification, flags (DF), fragment offset, TTL 64, UDP, checksum (後で埋める)
+    ip.extend_from_slice(&[0, 0, 0x40, 0, 64, IPPROTO_UDP, 0, 0]);
+    ip.extend_from_slice(&src_ip);
+    ip.extend_from_slice(&dst_ip);
+    let checksum = ipv4_checksum(&ip);
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut frame = ethernet_header(dst_mac, src_mac, ETHERTYPE_IPV4);
+    frame.extend_from_slice(&ip);
+    frame.extend_from_slice(&src_port.to_be_bytes());
+    frame.extend_from_slice(&dst_port.to_be_bytes());
+    frame.extend_from_slice(&udp_len.to_be_bytes());
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(payload);
+    pad(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    /// ゲストから送る UDP のフレーム
+    fn guest_udp(src_ip: [u8; 4], dst_ip: [u8; 4], src: u16, dst: u16, data: &[u8]) -> Vec<u8> {
+        udp_frame(&[0xff; 6], &GUEST_MAC, src_ip, dst_ip, src, dst, data)
+    }
+
+    /// 応答のフレームから UDP の (送信元ポート, 宛先ポート, ペイロード) を取り出す
+    fn parse_udp(frame: &[u8]) -> (u16, u16, Vec<u8>) {
+        let ip = &frame[ETH_HEADER..];
+        assert_eq!(ipv4_checksum(&ip[..IPV4_HEADER]), 0);
+        let len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+        let udp = &ip[IPV4_HEADER..len];
+        (
+            u16::from_be_bytes([udp[0], udp[1]]),
+            u16::from_be_bytes([udp[2], udp[3]]),
+            udp[UDP_HEADER..].to_vec(),
+        )
+    }
+
+    #[test]
+    fn ホストのアドレスへの_arp_に応答する() {
+        let net = UserNet::new();
+        let mut request = ethernet_header(&[0xff; 6], &GUEST_MAC, ETHERTYPE_ARP);
+        request.extend_from_slice(&[0, 1, 8, 0, 6, 4, 0, 1]);
+        request.extend_from_slice(&GUEST_MAC);
+        request.extend_from_slice(&[10, 0, 2, 15]);
+        request.extend_from_slice(&[0; 6]);
+        request.extend_from_slice(&[10, 0, 2, 2]);
+
+        let mut net = net;
+        let replies = net.transmit(&pad(request.clone()));
+        assert_eq!(replies.len(), 1);
+        let reply = &replies[0];
+        assert_eq!(&reply[0..6], GUEST_MAC);
+        // opcode 2 (応答) と、ホストの MAC アドレス
+        assert_eq!(&reply[20..22], [0, 2]);
+        assert_eq!(&reply[22..28], net.host_mac);
+
+        // 他のアドレスは尋ねられても答えない
+        request[38..42].copy_from_slice(&[10, 0, 2, 3]);
+        assert!(net.transmit(&pad(request)).is_empty());
+    }
+
+    #[test]
+    fn dhcp_でアドレスとブートファイルを渡す() {
+        let mut net = UserNet::new();
+        net.set_boot_file("Image");
+        let mut discover = vec![0u8; BOOTP_SIZE];
+        discover[0..3].copy_from_slice(&[1, 1, 6]);
+        discover[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        discover[28..34].copy_from_slice(&GUEST_MAC);
+        discover.extend_from_slice(&DHCP_MAGIC);
+        discover.extend_from_slice(&[53, 1, DHCPDISCOVER, 255]);
+
+        let replies = net.transmit(&guest_udp([0; 4], [255; 4], 68, 67, &discover));
+        let (src, dst, offer) = parse_udp(&replies[0]);
+        assert_eq!((src, dst), (67, 68));
+        assert_eq!(&offer[4..8], [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(&offer[16..20], net.guest_ip());
+        assert_eq!(&offer[20..24], net.host_ip());
+        assert_eq!(&offer[108..114], b"Image\0");
+        assert_eq!(dhcp_message_type(&offer[BOOTP_SIZE + 4..]), Some(DHCPOFFER));
+
+        let mut request = discover.clone();
+        request[BOOTP_SIZE + 6] = DHCPREQUEST;
+        let replies = net.transmit(&guest_udp([0; 4], [255; 4], 68, 67, &request));
+        let (_, _, ack) = parse_udp(&replies[0]);
+        assert_eq!(dhcp_message_type(&ack[BOOTP_SIZE + 4..]), Some(DHCPACK));
+    }
+
+    #[test]
+    fn 長いブートファイル名は切り詰めて渡す() {
+        let mut net = UserNet::new();
+        let name = "a".repeat(300);
+        net.set_boot_file(&name);
+        let mut discover = vec![0u8; BOOTP_SIZE];
+        discover[0..3].copy_from_slice(&[1, 1, 6]);
+        discover[28..34].copy_from_slice(&GUEST_MAC);
+        discover.extend_from_slice(&DHCP_MAGIC);
+        discover.extend_from_slice(&[53, 1, DHCPDISCOVER, 255]);
+
+        let replies = net.transmit(&guest_udp([0; 4], [255; 4], 68, 67, &discover));
+        let (_, _, offer) = parse_udp(&replies[0]);
+        // file は NUL 終端を残した 127 バイトまで
+        assert_eq!(&offer[108..235], &name.as_bytes()[..127]);
+        assert_eq!(offer[235], 0);
+
+        // option 67 は 255 バイトまで。後ろのオプションも正しく読める
+        let mut options = &offer[BOOTP_SIZE + 4..];
+        while options[0] != 67 {
+            options = &options[2 + options[1] as usize..];
+        }
+        assert_eq!(options[1], 255);
+        assert_eq!(&options[2..257], &name.as_bytes()[..255]);
+        assert_eq!(options[257], 255);
+    }
+
+    #[test]
+    fn tftp_でファイルをブロックごとに送る() {
+        let mut net = UserNet::new();
+        let file: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        net.add_file("/Image", file.clone());
+        let guest = [10, 0, 2, 15];
+        let host = [10, 0, 2, 2];
+
+        let rrq = b"\x00\x01Image\x00octet\x00blksize\x001468\x00";
+        let replies = net.transmit(&guest_udp(guest, host, 1234, 69, rrq));
+        let (tid, dst, data) = parse_udp(&replies[0]);
+        assert_eq!(dst, 1234);
+        assert_eq!(&data[0..4], [0, 3, 0, 1]);
+        assert_eq!(&data[4..], &file[..512]);
+        assert_eq!(net.active_transfers(), 1);
+
+        // 重複した ACK 0 には同じブロックを送り直す
+        let replies = net.transmit(&guest_udp(guest, host, 1234, tid, &[0, 4, 0, 0]));
+        assert_eq!(&parse_udp(&replies[0]).2[0..4], [0, 3, 0, 1]);
+
+        let replies = net.transmit(&guest_udp(guest, host, 1234, tid, &[0, 4, 0, 1]));
+        let (_, _, data) = parse_udp(&replies[0]);
+        assert_eq!(&data[0..4], [0, 3, 0, 2]);
+        assert_eq!(&data[4..], &file[512..]);
+
+        // 512 バイト未満の最後のブロックの ACK で終わる
+        assert!(net
+            .transmit(&guest_udp(guest, host, 1234, tid, &[0, 4, 0, 2]))
+            .is_empty());
+        assert_eq!(net.active_transfers(), 0);
+
+        let replies = net.transmit(&guest_udp(
+            guest,
+            host,
+            1235,
+            69,
+            b"\x00\x01nope\x00octet\x00",
+        ));
+        let (_, _, error) = parse_udp(&replies[0]);
+        assert_eq!(&error[0..4], [0, 5, 0, 1]);
+    }
+}
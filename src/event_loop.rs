@@ -0,0 +1,292 @@
+//! run ループのアイドル待ち
+//!
+//! ゲストが WFI や PSCI CPU_SUSPEND でアイドルになったとき、以前は固定時間
+//! (100us) の `thread::sleep` を挟んで再実行していた。これでは短い期限のタイマーには
+//! 最大 100us 遅れ、長くアイドルなゲストでは 1 秒に 1 万回 VM Exit が起きる。
+//!
+//! [`EventLoop`] は kqueue で次のタイマーの期限まで待ち、期限より前に
+//! コンソール入力・停止要求・モニタや QMP のコマンドが届いたら [`Waker::wake`]
+//! ですぐに待ちを解除する。[`VcpuHandle::kick`](crate::vcpu_handle::VcpuHandle::kick)
+//! も同じ `Waker` を起こすため、kick を使う入力元はそのまま待ちを解除できる。
+//! kqueue のないホスト (Linux でのテスト用のビルド) では `Condvar` で同じように待つ。
+//!
+//! アイドルになった直後の WFI は、すぐに割り込みが来ることが多い (ロックの
+//! 待ちやデバイスの完了待ちなど)。[`backoff`] は割り込みのないまま続いた WFI の数に
+//! 応じて、スレッドを譲るだけ → 短く待つ → 期限まで待つ、と段階的に待ちを長くする。
+//!
+//! ```ignore
+//! let waker = hv.waker();
+//! std::thread::spawn(move || {
+//!     backend.wait_for_completion();
+//!     waker.wake().unwrap();
+//! });
+//! hv.run(None, None, None)?;
+//! ```
+
+use crate::stats::IdleState;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// この数までの連続した WFI ではスレッドを譲るだけにする
+pub const YIELD_WFIS: u64 = 2;
+
+/// この数までの連続した WFI では [`NAP_TIMEOUT`] だけ待つ
+pub const NAP_WFIS: u64 = 16;
+
+/// 短い待ちの長さ
+pub const NAP_TIMEOUT: Duration = Duration::from_micros(100);
+
+/// 割り込みのないまま続いた WFI の数から、ホストの待ち方を決める
+pub fn backoff(consecutive_wfis: u64) -> IdleState {
+    match consecutive_wfis {
+        0 => IdleState::Running,
+        n if n <= YIELD_WFIS => IdleState::Yielding,
+        n if n <= NAP_WFIS => IdleState::Napping,
+        _ => IdleState::Blocked,
+    }
+}
+
+/// アイドル待ちが終わった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wakeup {
+    /// 期限に達した
+    Deadline,
+    /// [`Waker::wake`] で起こされた
+    Woken,
+}
+
+/// kqueue の EVFILT_USER イベントで待ち・起こす
+#[cfg(target_os = "macos")]
+mod sys {
+    use std::io;
+    use std::ptr;
+    use std::time::Duration;
+
+    /// wake に使う EVFILT_USER イベントの識別子
+    const WAKE_IDENT: usize = 1;
+
+    fn user_event(flags: u16, fflags: u32) -> libc::kevent {
+        libc::kevent {
+            ident: WAKE_IDENT,
+            filter: libc::EVFILT_USER,
+            flags,
+            fflags,
+            data: 0,
+            udata: ptr::null_mut(),
+        }
+    }
+
+    /// kqueue のファイルディスクリプタ (最後の参照が破棄されたら閉じる)
+    #[derive(Debug)]
+    pub struct Notifier(i32);
+
+    impl Notifier {
+        /// kqueue を作成し、wake 用のイベントを登録する
+        pub fn new() -> io::Result<Self> {
+            // SAFETY: 引数のない kqueue() を呼ぶだけ
+            let fd = unsafe { libc::kqueue() };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let notifier = Notifier(fd);
+            notifier.change(user_event(libc::EV_ADD | libc::EV_CLEAR, 0))?;
+            Ok(notifier)
+        }
+
+        fn change(&self, change: libc::kevent) -> io::Result<()> {
+            // SAFETY: change は 1 件の有効な kevent、イベントは受け取らない
+            let ret = unsafe { libc::kevent(self.0, &change, 1, ptr::null_mut(), 0, ptr::null()) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        pub fn wake(&self) -> io::Result<()> {
+            self.change(user_event(0, libc::NOTE_TRIGGER))
+        }
+
+        /// 起こされたら true、期限に達したら false
+        pub fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+            let timespec = timeout.map(|t| libc::timespec {
+                tv_sec: t.as_secs() as libc::time_t,
+                tv_nsec: t.subsec_nanos() as libc::c_long,
+            });
+            let timeout_ptr = timespec.as_ref().map_or(ptr::null(), |t| t as *const _);
+            let mut event = user_event(0, 0);
+            loop {
+                // SAFETY: event は 1 件分の出力先、timeout_ptr は null か有効な timespec
+                let ret =
+                    unsafe { libc::kevent(self.0, ptr::null(), 0, &mut event, 1, timeout_ptr) };
+                if ret >= 0 {
+                    return Ok(ret > 0);
+                }
+                let err = io::Error::last_os_error();
+                // シグナルで中断された場合は待ち直す (期限は多少延びる)
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    impl Drop for Notifier {
+        fn drop(&mut self) {
+            // SAFETY: kqueue() が返した fd で、他に閉じる箇所はない
+            unsafe { libc::close(self.0) };
+        }
+    }
+}
+
+/// kqueue のないホスト (テスト用のビルド) では Condvar で同じ動きをする
+#[cfg(not(target_os = "macos"))]
+mod sys {
+    use std::io;
+    use std::sync::{Condvar, Mutex};
+    use std::time::{Duration, Instant};
+
+    #[derive(Debug, Default)]
+    pub struct Notifier {
+        /// EV_CLEAR と同じく、受け取るまでの wake は 1 回分にまとめる
+        triggered: Mutex<bool>,
+        cvar: Condvar,
+    }
+
+    impl Notifier {
+        pub fn new() -> io::Result<Self> {
+            Ok(Self::default())
+        }
+
+        pub fn wake(&self) -> io::Result<()> {
+            *self.triggered.lock().unwrap() = true;
+            self.cvar.notify_one();
+            Ok(())
+        }
+
+        /// 起こされたら true、期限に達したら false
+        pub fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+            let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+            let mut triggered = self.triggered.lock().unwrap();
+            while !*triggered {
+                triggered = match deadline {
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return Ok(false);
+                        }
+                        self.cvar.wait_timeout(triggered, deadline - now).unwrap().0
+                    }
+                    None => self.cvar.wait(triggered).unwrap(),
+                };
+            }
+            *triggered = false;
+            Ok(true)
+        }
+    }
+}
+
+/// 他のスレッドからアイドル待ちを解除するハンドル (`Send + Sync`、clone 可能)
+///
+/// 待っていないときに起こした場合、次の [`EventLoop::wait`] がすぐに戻る。
+#[derive(Debug, Clone)]
+pub struct Waker {
+    notifier: Arc<sys::Notifier>,
+}
+
+impl Waker {
+    /// アイドル待ちを解除する
+    pub fn wake(&self) -> Result<(), Box<dyn Error>> {
+        self.notifier
+            .wake()
+            .map_err(|e| format!("Failed to wake the event loop: {}", e).into())
+    }
+}
+
+/// vCPU スレッドのアイドル待ち
+#[derive(Debug)]
+pub struct EventLoop {
+    notifier: Arc<sys::Notifier>,
+}
+
+impl EventLoop {
+    /// kqueue を作成し、wake 用のイベントを登録する
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let notifier =
+            sys::Notifier::new().map_err(|e| format!("Failed to create the event loop: {}", e))?;
+        Ok(Self {
+            notifier: Arc::new(notifier),
+        })
+    }
+
+    /// 待ちを解除するハンドル
+    pub fn waker(&self) -> Waker {
+        Waker {
+            notifier: Arc::clone(&self.notifier),
+        }
+    }
+
+    /// `timeout` が経過するか起こされるまで待つ (None なら起こされるまで)
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<Wakeup, Box<dyn Error>> {
+        match self.notifier.wait(timeout) {
+            Ok(true) => Ok(Wakeup::Woken),
+            Ok(false) => Ok(Wakeup::Deadline),
+            Err(e) => Err(format!("Failed to wait on the event loop: {}", e).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn 連続した_wfi_が増えるほど待ちを長くする() {
+        assert_eq!(backoff(0), IdleState::Running);
+        assert_eq!(backoff(1), IdleState::Yielding);
+        assert_eq!(backoff(YIELD_WFIS), IdleState::Yielding);
+        assert_eq!(backoff(YIELD_WFIS + 1), IdleState::Napping);
+        assert_eq!(backoff(NAP_WFIS), IdleState::Napping);
+        assert_eq!(backoff(NAP_WFIS + 1), IdleState::Blocked);
+    }
+
+    #[test]
+    fn 期限まで待つ() {
+        let events = EventLoop::new().unwrap();
+        let start = Instant::now();
+        assert_eq!(
+            events.wait(Some(Duration::from_millis(20))).unwrap(),
+            Wakeup::Deadline
+        );
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn 起こされたら期限前に戻る() {
+        let events = EventLoop::new().unwrap();
+        let waker = events.waker();
+        let start = Instant::now();
+        let wake = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            waker.wake().unwrap();
+        });
+        assert_eq!(events.wait(None).unwrap(), Wakeup::Woken);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        wake.join().unwrap();
+    }
+
+    #[test]
+    fn 待つ前に起こされた分は次の待ちで受け取る() {
+        let events = EventLoop::new().unwrap();
+        events.waker().wake().unwrap();
+        events.waker().wake().unwrap();
+        assert_eq!(
+            events.wait(Some(Duration::from_secs(5))).unwrap(),
+            Wakeup::Woken
+        );
+        // まとめて 1 回分になる
+        assert_eq!(events.wait(Some(Duration::ZERO)).unwrap(), Wakeup::Deadline);
+    }
+}
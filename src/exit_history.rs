@@ -0,0 +1,262 @@
+//! 直近の VM Exit の履歴
+//!
+//! ゲストが異常終了したとき、直前に何が起きていたかは [`crate::trace`] を有効に
+//! していないと分からない。[`ExitHistory`] は最後の N 回の VM Exit について PC・
+//! Exit の種類・フォルトしたアドレスと、指定した汎用レジスタだけを固定長の
+//! リングに残す。run ループが予期しない例外やエラーで終わると、標準エラーに
+//! まとめて出力する。
+//!
+//! ```text
+//! [EXIT HISTORY] the last 50 exits were all data abort at 0x801000c
+//! #10201 pc=0x40080a10 data abort addr=0x801000c x0=0x801000c
+//! ...
+//! ```
+//!
+//! 汎用レジスタは VM Exit ごとに読み出し済みのものを写すだけなので、追加の
+//! ホスト呼び出しはない。
+
+use crate::run_options::GPR_COUNT;
+use std::collections::VecDeque;
+use std::fmt;
+
+/// 既定で残す VM Exit の数
+pub const DEFAULT_CAPACITY: usize = 64;
+
+/// VM Exit 1 回分の記録
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitRecord {
+    /// 何回目の VM Exit か (`ExitStats::exit_count`)
+    pub exit: u64,
+    /// VM Exit したときの PC
+    pub pc: u64,
+    /// Exit の種類 (トレースのイベント名と同じ)
+    pub class: &'static str,
+    /// ESR_EL2 (例外による Exit のみ)
+    pub syndrome: Option<u64>,
+    /// フォルトしたゲスト物理アドレス (Data Abort のみ)
+    pub fault_addr: Option<u64>,
+    /// 記録した汎用レジスタ (番号, 値)
+    pub registers: Vec<(usize, u64)>,
+}
+
+impl ExitRecord {
+    /// 同じ Exit が続いているかの判定に使う場所 (Data Abort はアドレス、それ以外は PC)
+    fn location(&self) -> u64 {
+        self.fault_addr.unwrap_or(self.pc)
+    }
+}
+
+impl fmt::Display for ExitRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{} pc=0x{:x} {}", self.exit, self.pc, self.class)?;
+        if let Some(addr) = self.fault_addr {
+            write!(f, " addr=0x{:x}", addr)?;
+        } else if let Some(syndrome) = self.syndrome {
+            write!(f, " esr=0x{:x}", syndrome)?;
+        }
+        for (index, value) in &self.registers {
+            write!(f, " x{}=0x{:x}", index, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// 直近の VM Exit のリング
+#[derive(Debug, Clone)]
+pub struct ExitHistory {
+    capacity: usize,
+    registers: Vec<usize>,
+    records: VecDeque<ExitRecord>,
+}
+
+impl Default for ExitHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl ExitHistory {
+    /// 最後の `capacity` 回を残す履歴 (0 なら記録しない)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            registers: Vec::new(),
+            records: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// 一緒に記録する汎用レジスタ (X0-X30 の番号、範囲外は無視する)
+    pub fn registers(mut self, indices: &[usize]) -> Self {
+        self.registers = indices
+            .iter()
+            .copied()
+            .filter(|&index| index < GPR_COUNT)
+            .collect();
+        self
+    }
+
+    /// 残す VM Exit の数
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// VM Exit を記録する (古いものから捨てる)
+    ///
+    /// # Arguments
+    /// * `gprs` - VM Exit 時の X0-X30
+    pub(crate) fn record(
+        &mut self,
+        exit: u64,
+        pc: u64,
+        class: &'static str,
+        syndrome: Option<u64>,
+        fault_addr: Option<u64>,
+        gprs: &[u64; GPR_COUNT],
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        let registers = self.registers.iter().map(|&i| (i, gprs[i])).collect();
+        self.records.push_back(ExitRecord {
+            exit,
+            pc,
+            class,
+            syndrome,
+            fault_addr,
+            registers,
+        });
+    }
+
+    /// 記録した VM Exit (古い順)
+    pub fn records(&self) -> impl Iterator<Item = &ExitRecord> + '_ {
+        self.records.iter()
+    }
+
+    /// 記録を消す
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    /// 最後の VM Exit と同じ種類・同じ場所の Exit がいくつ続いたか
+    pub fn repeated(&self) -> usize {
+        let Some(last) = self.records.back() else {
+            return 0;
+        };
+        self.records
+            .iter()
+            .rev()
+            .take_while(|r| r.class == last.class && r.location() == last.location())
+            .count()
+    }
+
+    /// 1 行の要約
+    pub fn summary(&self) -> String {
+        let Some(last) = self.records.back() else {
+            return "no exits recorded".to_string();
+        };
+        match self.repeated() {
+            1 => format!(
+                "the last exit was {} at 0x{:x}",
+                last.class,
+                last.location()
+            ),
+            n if n == self.records.len() => format!(
+                "the last {} exits were all {} at 0x{:x}",
+                n,
+                last.class,
+                last.location()
+            ),
+            n => format!(
+                "the last {} of {} exits were {} at 0x{:x}",
+                n,
+                self.records.len(),
+                last.class,
+                last.location()
+            ),
+        }
+    }
+
+    /// 要約と記録を標準エラーに出力する
+    pub(crate) fn dump(&self) {
+        if self.records.is_empty() {
+            return;
+        }
+        eprintln!("[EXIT HISTORY] {}", self.summary());
+        for record in &self.records {
+            eprintln!("{}", record);
+        }
+    }
+}
+
+impl fmt::Display for ExitHistory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.summary())?;
+        for record in &self.records {
+            writeln!(f, "{}", record)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gprs() -> [u64; GPR_COUNT] {
+        std::array::from_fn(|i| i as u64 * 0x10)
+    }
+
+    #[test]
+    fn 古い_exit_から捨てる() {
+        let mut history = ExitHistory::new(3).registers(&[0, 30, 31]);
+        for exit in 1..=5 {
+            history.record(
+                exit,
+                0x4000_0000 + exit * 4,
+                "hvc",
+                Some(0x5a00_0000),
+                None,
+                &gprs(),
+            );
+        }
+        let exits: Vec<u64> = history.records().map(|r| r.exit).collect();
+        assert_eq!(exits, [3, 4, 5]);
+        let last = history.records().last().unwrap();
+        assert_eq!(last.registers, [(0, 0), (30, 0x1e0)]);
+        assert_eq!(
+            last.to_string(),
+            "#5 pc=0x40000014 hvc esr=0x5a000000 x0=0x0 x30=0x1e0"
+        );
+
+        history.clear();
+        assert_eq!(history.summary(), "no exits recorded");
+        let mut off = ExitHistory::new(0);
+        off.record(1, 0, "wfi/wfe", None, None, &gprs());
+        assert_eq!(off.records().count(), 0);
+    }
+
+    #[test]
+    fn 同じ場所の_exit_が続いたことを要約する() {
+        let mut history = ExitHistory::new(50);
+        for exit in 0..50 {
+            let pc = 0x4008_0000 + (exit % 2) * 4;
+            history.record(exit, pc, "data abort", None, Some(0x0801_000c), &gprs());
+        }
+        assert_eq!(history.repeated(), 50);
+        assert_eq!(
+            history.summary(),
+            "the last 50 exits were all data abort at 0x801000c"
+        );
+
+        history.record(50, 0x4008_0100, "brk", Some(0xf200_0000), None, &gprs());
+        assert_eq!(history.summary(), "the last exit was brk at 0x40080100");
+        history.record(51, 0x4008_0100, "brk", Some(0xf200_0000), None, &gprs());
+        assert_eq!(
+            history.summary(),
+            "the last 2 of 50 exits were brk at 0x40080100"
+        );
+    }
+}
@@ -0,0 +1,238 @@
+//! ホストの Hypervisor.framework が扱える VM の範囲
+//!
+//! IPA (ゲストの物理アドレス) の幅、stage-2 のページサイズ、vCPU の最大数は
+//! ホストのチップと macOS のバージョンで変わる。範囲外の構成は `hv_vm_map` や
+//! `hv_vcpu_create` の汎用的なエラーで初めて失敗するため、
+//! [`Hypervisor::with_vm_config`](crate::Hypervisor::with_vm_config) は VM を作る前に
+//! [`HostCapabilities`] と照合して、どの値が範囲外かを示すエラーを返す。
+//!
+//! # IPA 幅
+//!
+//! applevisor 0.1 は構成オブジェクトなしで `hv_vm_create` するため、VM の IPA 幅は
+//! 既定値 ([`HostCapabilities::ipa_bits`]) になる。構成で広げられる上限
+//! ([`HostCapabilities::max_ipa_bits`]) は参考として報告する。
+//! 問い合わせ API は macOS 13 以降にしかないため、リンク時ではなく実行時に
+//! `dlsym` で探し、見つからない・失敗した場合は以前の固定値の 36 bit とする。
+//!
+//! # ページ粒度
+//!
+//! stage-2 の変換はホストのページ (16KB) 単位で、ゲスト RAM の配置もこれに揃える。
+//! ゲストの stage-1 粒度はホストの ID_AA64MMFR0_EL1 で決まり、vCPU からしか読めないため
+//! [`Hypervisor::host_capabilities`](crate::Hypervisor::host_capabilities) でだけ分かる。
+
+use crate::addressing::{granule_supported, PageGranule};
+use std::error::Error;
+use std::ffi::CStr;
+
+/// Hypervisor.framework の戻り値 (成功)
+const HV_SUCCESS: i32 = 0;
+
+/// macOS 13 より前の VM の IPA 幅
+const LEGACY_IPA_BITS: u8 = 36;
+
+/// `hv_vm_config_get_*_ipa_size` の型
+type IpaSizeQuery = unsafe extern "C" fn(ipa_bit_length: *mut u32) -> i32;
+
+/// ホストが扱える VM の範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostCapabilities {
+    /// 作成する VM の IPA 幅 (bits)
+    pub ipa_bits: u8,
+    /// 構成で指定できる最大の IPA 幅 (bits)
+    pub max_ipa_bits: u8,
+    /// stage-2 のページサイズ (`hv_vm_map` の単位)
+    pub stage2_page_size: u64,
+    /// vCPU の最大数
+    pub max_vcpus: u32,
+    /// ゲストが読むホストの ID_AA64MMFR0_EL1 (vCPU がない場合は `None`)
+    pub mmfr0: Option<u64>,
+}
+
+impl HostCapabilities {
+    /// Hypervisor.framework に問い合わせる
+    ///
+    /// VM を作成していなくても呼べる。ID_AA64MMFR0_EL1 は読めないため `mmfr0` は `None`。
+    pub fn query() -> Result<Self, Box<dyn Error>> {
+        let mut max_vcpus = 0u32;
+        // SAFETY: 出力先として有効なポインタを渡している
+        let ret = unsafe { applevisor_sys::hv_vm_get_max_vcpu_count(&mut max_vcpus) };
+        if ret != HV_SUCCESS {
+            return Err(format!("hv_vm_get_max_vcpu_count failed (error 0x{:x})", ret).into());
+        }
+        let ipa_bits = query_ipa_size(c"hv_vm_config_get_default_ipa_size");
+        Ok(Self {
+            ipa_bits,
+            max_ipa_bits: query_ipa_size(c"hv_vm_config_get_max_ipa_size").max(ipa_bits),
+            stage2_page_size: applevisor::PAGE_SIZE as u64,
+            max_vcpus,
+            mmfr0: None,
+        })
+    }
+
+    /// ゲストが読む ID_AA64MMFR0_EL1 を設定する
+    pub fn with_mmfr0(mut self, mmfr0: u64) -> Self {
+        self.mmfr0 = Some(mmfr0);
+        self
+    }
+
+    /// VM の IPA 空間の大きさ (bytes)
+    pub fn ipa_limit(&self) -> u64 {
+        1u64 << self.ipa_bits
+    }
+
+    /// ゲストが使える stage-1 粒度 (ID_AA64MMFR0_EL1 が分からなければ `None`)
+    pub fn guest_granules(&self) -> Option<Vec<PageGranule>> {
+        let mmfr0 = self.mmfr0?;
+        Some(
+            [
+                PageGranule::Size4K,
+                PageGranule::Size16K,
+                PageGranule::Size64K,
+            ]
+            .into_iter()
+            .filter(|&granule| granule_supported(mmfr0, granule, false))
+            .collect(),
+        )
+    }
+
+    /// vCPU 数がホストの上限以内か確認する
+    pub fn check_vcpus(&self, vcpus: u32) -> Result<(), Box<dyn Error>> {
+        if vcpus > self.max_vcpus {
+            return Err(format!(
+                "{} vCPUs requested but Hypervisor.framework on this host supports at most {}",
+                vcpus, self.max_vcpus
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// ゲスト RAM が stage-2 のページに揃い、IPA 空間に収まるか確認する
+    pub fn check_ram(&self, base: u64, size: usize) -> Result<(), Box<dyn Error>> {
+        let size = size as u64;
+        if !base.is_multiple_of(self.stage2_page_size)
+            || !size.is_multiple_of(self.stage2_page_size)
+        {
+            return Err(format!(
+                "Guest RAM 0x{:x} (+0x{:x}) is not aligned to the host's 0x{:x}-byte stage-2 pages",
+                base, size, self.stage2_page_size
+            )
+            .into());
+        }
+        if base as u128 + size as u128 > self.ipa_limit() as u128 {
+            return Err(format!(
+                "Guest RAM 0x{:x} (+0x{:x}) exceeds the host's {}-bit IPA space (ends at 0x{:x})",
+                base,
+                size,
+                self.ipa_bits,
+                self.ipa_limit()
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// ゲストの stage-1 粒度をホストが扱えるか確認する
+    ///
+    /// ID_AA64MMFR0_EL1 が分からない場合は確認できないため成功とする。
+    pub fn check_granule(&self, granule: PageGranule) -> Result<(), Box<dyn Error>> {
+        match self.guest_granules() {
+            Some(granules) if !granules.contains(&granule) => Err(format!(
+                "Host does not support {} guest pages (supported: {})",
+                granule,
+                granules
+                    .iter()
+                    .map(|g| g.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 古い macOS にはないかもしれない Hypervisor.framework の関数を探す
+///
+/// 直接リンクすると、シンボルのない macOS ではバイナリ自体が起動しなくなる。
+pub(crate) fn framework_symbol(name: &CStr) -> Option<*mut libc::c_void> {
+    // SAFETY: name は NUL 終端された文字列
+    let symbol = unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) };
+    (!symbol.is_null()).then_some(symbol)
+}
+
+/// IPA 幅を問い合わせる (API がない・失敗した場合は 36 bit)
+fn query_ipa_size(name: &CStr) -> u8 {
+    let Some(symbol) = framework_symbol(name) else {
+        return LEGACY_IPA_BITS;
+    };
+    // SAFETY: Hypervisor.framework のヘッダーで宣言された型の関数
+    let query: IpaSizeQuery = unsafe { std::mem::transmute(symbol) };
+    let mut bits = 0u32;
+    // SAFETY: 出力先として有効なポインタを渡している
+    let ret = unsafe { query(&mut bits) };
+    if ret == HV_SUCCESS && bits != 0 {
+        bits as u8
+    } else {
+        LEGACY_IPA_BITS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Apple M2 相当: TGran4 / TGran16 サポート, TGran64 非サポート
+    const APPLE_MMFR0: u64 = (0xf << 24) | (0x1 << 20) | 0x2;
+
+    fn capabilities() -> HostCapabilities {
+        HostCapabilities {
+            ipa_bits: 36,
+            max_ipa_bits: 40,
+            stage2_page_size: 0x4000,
+            max_vcpus: 8,
+            mmfr0: None,
+        }
+    }
+
+    #[test]
+    fn ipa_空間を超える_ram_を拒否する() {
+        let caps = capabilities();
+        assert!(caps.check_ram(0x4000_0000, 0x4000_0000).is_ok());
+        // 36 bit = 64GB の末尾ちょうどまでは使える
+        assert!(caps.check_ram(0x4000_0000, 0xf_c000_0000).is_ok());
+
+        let err = caps.check_ram(0x4000_0000, 0x10_0000_0000).unwrap_err();
+        assert!(err.to_string().contains("36-bit IPA space"));
+        let err = caps.check_ram(0x4000_1000, 0x4000).unwrap_err();
+        assert!(err.to_string().contains("stage-2 pages"));
+    }
+
+    #[test]
+    fn 見つからない関数は_none_になる() {
+        assert_eq!(framework_symbol(c"hv_no_such_function"), None);
+        assert_eq!(query_ipa_size(c"hv_no_such_function"), LEGACY_IPA_BITS);
+    }
+
+    #[test]
+    fn vcpu_数と粒度をホストの範囲と照合する() {
+        let caps = capabilities();
+        assert!(caps.check_vcpus(8).is_ok());
+        let err = caps.check_vcpus(9).unwrap_err();
+        assert!(err.to_string().contains("at most 8"));
+
+        // ID_AA64MMFR0_EL1 が分からなければ粒度は確認しない
+        assert_eq!(caps.guest_granules(), None);
+        assert!(caps.check_granule(PageGranule::Size64K).is_ok());
+
+        let caps = caps.with_mmfr0(APPLE_MMFR0);
+        assert_eq!(
+            caps.guest_granules().unwrap(),
+            [PageGranule::Size4K, PageGranule::Size16K]
+        );
+        let err = caps.check_granule(PageGranule::Size64K).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("64KB guest pages (supported: 4KB, 16KB)"));
+    }
+}
@@ -0,0 +1,391 @@
+//! ゲストのテストコードからホストへのイベント通知
+//!
+//! CI でゲストの中のテストを動かすとき、結果をシリアルの出力から拾うのは
+//! 壊れやすい。[`HOST_CHANNEL_HVC_ID`] の HVC で、信頼できるゲストのコードが
+//! 構造化したイベントをホストに送れるようにする。[`crate::Hypervisor::attach_host_channel`]
+//! で有効にしたときだけ処理し、それ以外は未対応の PSCI 関数として扱う。
+//!
+//! | X1 | イベント | 引数 |
+//! |----|----------|------|
+//! | 0 | [`GuestEvent::Phase`] | X2/X3 = 名前 (UTF-8) のアドレス/長さ |
+//! | 1 | [`GuestEvent::TestPassed`] | X2/X3 = テスト名 |
+//! | 2 | [`GuestEvent::TestFailed`] | X2/X3 = テスト名、X4 = 失敗コード |
+//! | 3 | [`GuestEvent::FileRequest`] | X2/X3 = パス、X4/X5 = 読み込み先のアドレス/長さ |
+//! | 4 | [`GuestEvent::Custom`] | X2/X3 = 任意の値 |
+//! | 5 | [`GuestEvent::BootNext`] | X2/X3 = `KERNEL [INITRD] [-- CMDLINE]` |
+//!
+//! X0 には成功なら 0 (ファイルの要求は書き込んだバイト数)、失敗なら
+//! [`INVALID_PARAMETERS`] などの負の値を返す。ファイルは
+//! [`HostChannel::serve_files`] で指定したディレクトリの中からだけ読む。
+//! 次に起動するカーネルの指定もこのディレクトリからの相対パスで、受け付けると
+//! [`crate::Hypervisor::set_next_boot`] と同じく次の再起動で使われる。
+//!
+//! ```ignore
+//! hv.attach_host_channel(HostChannel::new().on_event(Box::new(|event| {
+//!     if let GuestEvent::TestFailed { name, code } = event {
+//!         eprintln!("{} failed ({})", name, code);
+//!     }
+//! })));
+//! hv.run(None, None, None)?;
+//! assert!(hv.guest_events().iter().all(|e| !e.is_failure()));
+//! ```
+
+use crate::boot::next_boot::NextBoot;
+use crate::memory::GuestMemory;
+use std::error::Error;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+/// ホストへのイベント通知の HVC 関数 ID (SMCCC のベンダー固有ハイパーバイザーサービス, SMC64)
+pub const HOST_CHANNEL_HVC_ID: u64 = 0xC600_0100;
+
+/// 未知のイベント
+pub const NOT_SUPPORTED: i64 = -1;
+/// 文字列やバッファがゲストメモリ外、または UTF-8 でない
+pub const INVALID_PARAMETERS: i64 = -2;
+/// 要求されたファイルを読めない
+pub const FILE_ERROR: i64 = -3;
+
+/// ゲストから受け取る文字列の最大長 (bytes)
+pub const MAX_STRING_LEN: u64 = 4096;
+
+/// ゲストから届いたイベント
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuestEvent {
+    /// テストのフェーズの開始
+    Phase(String),
+    /// テストが成功した
+    TestPassed(String),
+    /// テストが失敗した
+    TestFailed {
+        /// テスト名
+        name: String,
+        /// ゲストが渡した失敗コード
+        code: u64,
+    },
+    /// ファイルの要求
+    FileRequest {
+        /// 要求されたパス ([`HostChannel::serve_files`] のディレクトリからの相対パス)
+        path: String,
+        /// ゲストに書き込んだバイト数 (読めなかった場合は None)
+        served: Option<usize>,
+    },
+    /// 任意の値
+    Custom(u64, u64),
+    /// 次の再起動で起動するカーネルの指定 (パスはホスト上の位置に解決済み)
+    BootNext(NextBoot),
+}
+
+impl GuestEvent {
+    /// テストの失敗か
+    pub fn is_failure(&self) -> bool {
+        matches!(self, Self::TestFailed { .. })
+    }
+}
+
+impl fmt::Display for GuestEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Phase(name) => write!(f, "phase {}", name),
+            Self::TestPassed(name) => write!(f, "PASS {}", name),
+            Self::TestFailed { name, code } => write!(f, "FAIL {} (code {})", name, code),
+            Self::FileRequest {
+                path,
+                served: Some(len),
+            } => write!(f, "file {} ({} bytes)", path, len),
+            Self::FileRequest { path, served: None } => write!(f, "file {} (not served)", path),
+            Self::Custom(a, b) => write!(f, "custom 0x{:x} 0x{:x}", a, b),
+            Self::BootNext(next) => write!(f, "next boot: {}", next),
+        }
+    }
+}
+
+type EventCallback = Box<dyn FnMut(&GuestEvent) + Send>;
+
+/// イベントの受け口と記録
+#[derive(Default)]
+pub struct HostChannel {
+    file_root: Option<PathBuf>,
+    callback: Option<EventCallback>,
+    log: bool,
+    events: Vec<GuestEvent>,
+    next_boot: Option<NextBoot>,
+}
+
+impl HostChannel {
+    /// イベントを記録するだけのチャネル (ファイルの要求には応じない)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ファイルの要求に `root` の中のファイルで応じる
+    pub fn serve_files<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.file_root = Some(root.into());
+        self
+    }
+
+    /// イベントごとにコールバックを呼ぶ (run ループのスレッドで呼ばれる)
+    pub fn on_event(mut self, callback: EventCallback) -> Self {
+        self.callback = Some(callback);
+        self
+    }
+
+    /// イベントを `[GUEST]` 付きで標準エラー出力に出す
+    pub fn log(mut self, log: bool) -> Self {
+        self.log = log;
+        self
+    }
+
+    /// 受け取ったイベント (古い順)
+    pub fn events(&self) -> &[GuestEvent] {
+        &self.events
+    }
+
+    /// 受け取ったイベントを取り出す
+    pub fn take_events(&mut self) -> Vec<GuestEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// ゲストが指定した次に起動するカーネルを取り出す
+    pub(crate) fn take_next_boot(&mut self) -> Option<NextBoot> {
+        self.next_boot.take()
+    }
+
+    /// HVC を処理し、X0 に返す値を返す
+    ///
+    /// # Arguments
+    /// * `args` - X1-X5
+    pub(crate) fn handle(&mut self, args: [u64; 5], mem: &dyn GuestMemory) -> u64 {
+        let [kind, a, b, c, d] = args;
+        let string = || read_string(mem, a, b);
+        let (event, ret) = match kind {
+            0 => match string() {
+                Ok(name) => (GuestEvent::Phase(name), 0),
+                Err(_) => return INVALID_PARAMETERS as u64,
+            },
+            1 => match string() {
+                Ok(name) => (GuestEvent::TestPassed(name), 0),
+                Err(_) => return INVALID_PARAMETERS as u64,
+            },
+            2 => match string() {
+                Ok(name) => (GuestEvent::TestFailed { name, code: c }, 0),
+                Err(_) => return INVALID_PARAMETERS as u64,
+            },
+            3 => {
+                let Ok(path) = string() else {
+                    return INVALID_PARAMETERS as u64;
+                };
+                if !mem.check_range(c, d as usize) {
+                    return INVALID_PARAMETERS as u64;
+                }
+                let served = self
+                    .read_file(&path)
+                    .and_then(|data| {
+                        let len = data.len().min(d as usize);
+                        mem.write_slice(&data[..len], c)?;
+                        Ok(len)
+                    })
+                    .ok();
+                let ret = served.map_or(FILE_ERROR as u64, |len| len as u64);
+                (GuestEvent::FileRequest { path, served }, ret)
+            }
+            4 => (GuestEvent::Custom(a, b), 0),
+            5 => {
+                let Ok(next) = string().and_then(|args| NextBoot::parse(&args)) else {
+                    return INVALID_PARAMETERS as u64;
+                };
+                let Ok(next) = self.resolve_next_boot(next) else {
+                    return FILE_ERROR as u64;
+                };
+                self.next_boot = Some(next.clone());
+                (GuestEvent::BootNext(next), 0)
+            }
+            _ => return NOT_SUPPORTED as u64,
+        };
+        if self.log {
+            eprintln!("[GUEST] {}", event);
+        }
+        if let Some(callback) = &mut self.callback {
+            callback(&event);
+        }
+        self.events.push(event);
+        ret
+    }
+
+    /// `file_root` の中のファイルを読む
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(std::fs::read(self.resolve(path)?)?)
+    }
+
+    /// カーネルと initrd のパスを `file_root` の中に解決し、読めることを確認する
+    fn resolve_next_boot(&self, next: NextBoot) -> Result<NextBoot, Box<dyn Error>> {
+        let mut resolved = NextBoot::new(self.resolve(&next.kernel.to_string_lossy())?);
+        if let Some(initrd) = &next.initrd {
+            resolved = resolved.initrd(self.resolve(&initrd.to_string_lossy())?);
+        }
+        resolved.cmdline = next.cmdline;
+        resolved.check()?;
+        Ok(resolved)
+    }
+
+    /// `file_root` の中のパスにする (ディレクトリの外を指すパスは拒否する)
+    fn resolve(&self, path: &str) -> Result<PathBuf, Box<dyn Error>> {
+        let root = self
+            .file_root
+            .as_ref()
+            .ok_or("host channel does not serve files")?;
+        let relative = Path::new(path);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(format!("'{}' is not a relative path inside the file root", path).into());
+        }
+        Ok(root.join(relative))
+    }
+}
+
+/// ゲストメモリから UTF-8 の文字列を読む
+fn read_string(mem: &dyn GuestMemory, addr: u64, len: u64) -> Result<String, Box<dyn Error>> {
+    if len > MAX_STRING_LEN {
+        return Err(format!("string of {} bytes is too long", len).into());
+    }
+    let mut buf = vec![0; len as usize];
+    mem.read_slice(&mut buf, addr)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::GuestRam;
+    use std::sync::{Arc, Mutex};
+
+    const BASE: u64 = 0x4000_0000;
+
+    fn mapped_ram() -> GuestRam {
+        let mut ram = GuestRam::new(0x4000).unwrap();
+        ram.map(&crate::backend::MockVm, BASE).unwrap();
+        ram
+    }
+
+    #[test]
+    fn テストの結果をイベントとして受け取る() {
+        let ram = mapped_ram();
+        ram.write_slice(b"boot", BASE).unwrap();
+        ram.write_slice(b"net", BASE + 0x10).unwrap();
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let mut channel = HostChannel::new().on_event(Box::new(move |event| {
+            sink.lock().unwrap().push(event.to_string());
+        }));
+        assert_eq!(channel.handle([0, BASE, 4, 0, 0], &ram), 0);
+        assert_eq!(channel.handle([1, BASE + 0x10, 3, 0, 0], &ram), 0);
+        assert_eq!(channel.handle([2, BASE + 0x10, 3, 7, 0], &ram), 0);
+        assert_eq!(channel.handle([4, 1, 2, 0, 0], &ram), 0);
+
+        assert_eq!(
+            channel.events(),
+            [
+                GuestEvent::Phase("boot".into()),
+                GuestEvent::TestPassed("net".into()),
+                GuestEvent::TestFailed {
+                    name: "net".into(),
+                    code: 7
+                },
+                GuestEvent::Custom(1, 2),
+            ]
+        );
+        assert_eq!(seen.lock().unwrap()[2], "FAIL net (code 7)");
+        assert!(channel.take_events()[2].is_failure());
+        assert!(channel.events().is_empty());
+
+        // 範囲外の文字列と未知のイベントは記録しない
+        assert_eq!(
+            channel.handle([0, BASE + 0x3fff, 4, 0, 0], &ram),
+            INVALID_PARAMETERS as u64
+        );
+        assert_eq!(
+            channel.handle([0, BASE, MAX_STRING_LEN + 1, 0, 0], &ram),
+            INVALID_PARAMETERS as u64
+        );
+        assert_eq!(channel.handle([9, 0, 0, 0, 0], &ram), NOT_SUPPORTED as u64);
+        assert!(channel.events().is_empty());
+    }
+
+    #[test]
+    fn 要求されたファイルをゲストメモリに書き込む() {
+        let root = std::env::temp_dir().join("hv-host-channel-test");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("input.txt"), b"dataset").unwrap();
+
+        let ram = mapped_ram();
+        ram.write_slice(b"input.txt", BASE).unwrap();
+        ram.write_slice(b"../input.txt", BASE + 0x10).unwrap();
+        let mut channel = HostChannel::new().serve_files(&root);
+
+        assert_eq!(channel.handle([3, BASE, 9, BASE + 0x100, 4], &ram), 4);
+        assert_eq!(ram.snapshot(BASE + 0x100..BASE + 0x105).unwrap(), b"data\0");
+        assert_eq!(channel.handle([3, BASE, 9, BASE + 0x100, 0x100], &ram), 7);
+        assert_eq!(
+            channel.events()[1],
+            GuestEvent::FileRequest {
+                path: "input.txt".into(),
+                served: Some(7)
+            }
+        );
+
+        // ディレクトリの外は読まない
+        assert_eq!(
+            channel.handle([3, BASE + 0x10, 12, BASE + 0x100, 0x100], &ram),
+            FILE_ERROR as u64
+        );
+        assert_eq!(
+            channel.events()[2].to_string(),
+            "file ../input.txt (not served)"
+        );
+        // 読み込み先がゲストメモリ外
+        assert_eq!(
+            channel.handle([3, BASE, 9, BASE + 0x3ff0, 0x100], &ram),
+            INVALID_PARAMETERS as u64
+        );
+        assert_eq!(
+            HostChannel::new().handle([3, BASE, 9, BASE + 0x100, 0x100], &ram),
+            FILE_ERROR as u64
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn 次に起動するカーネルをディレクトリの中から指定する() {
+        let root = std::env::temp_dir().join("hv-host-channel-next-boot");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("Image-b"), [0x00, 0x00, 0x00, 0x14]).unwrap();
+
+        let ram = mapped_ram();
+        let request = |at: u64, text: &[u8]| {
+            ram.write_slice(text, at).unwrap();
+            [5, at, text.len() as u64, 0, 0]
+        };
+        let mut channel = HostChannel::new().serve_files(&root);
+
+        let args = request(BASE, b"Image-b -- console=ttyAMA0 quiet");
+        assert_eq!(channel.handle(args, &ram), 0);
+        let next = NextBoot::new(root.join("Image-b")).cmdline("console=ttyAMA0 quiet");
+        assert_eq!(channel.events(), [GuestEvent::BootNext(next.clone())]);
+        assert_eq!(channel.take_next_boot(), Some(next));
+        assert_eq!(channel.take_next_boot(), None);
+
+        // ディレクトリの外・存在しないファイル・解析できない指定は受け付けない
+        let args = request(BASE + 0x100, b"../Image-b");
+        assert_eq!(channel.handle(args, &ram), FILE_ERROR as u64);
+        let args = request(BASE + 0x200, b"Image-b missing-initrd");
+        assert_eq!(channel.handle(args, &ram), FILE_ERROR as u64);
+        let args = request(BASE + 0x300, b"-- quiet");
+        assert_eq!(channel.handle(args, &ram), INVALID_PARAMETERS as u64);
+        assert_eq!(channel.take_next_boot(), None);
+        assert_eq!(channel.events().len(), 1);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
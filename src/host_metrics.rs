@@ -0,0 +1,158 @@
+//! VM が使っているホストの資源
+//!
+//! Hypervisor.framework はプロセスごとに VM を 1 つしか作れないため、プロセス全体の
+//! CPU 時間と RSS がそのまま VM 1 つ分になる。長時間動かすゲストの監視に使う。
+//!
+//! ```ignore
+//! let metrics = hv.host_metrics();
+//! std::fs::write("/var/lib/node_exporter/vm.prom", metrics.to_prometheus("vm0"))?;
+//! ```
+
+use std::fmt::Write as _;
+use std::mem::MaybeUninit;
+use std::time::Duration;
+
+/// ホスト側から見た VM の資源使用量
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HostMetrics {
+    /// vCPU スレッド (`run` を呼んだスレッド) の CPU 時間 (ゲストの実行時間を含む)
+    pub vcpu_cpu_time: Duration,
+    /// プロセス全体の CPU 時間 (ユーザー + システム)
+    pub process_cpu_time: Duration,
+    /// プロセスの最大 RSS (bytes)
+    pub max_rss: u64,
+    /// ゲスト RAM の宣言サイズ (bytes)
+    pub guest_memory_declared: u64,
+    /// ゲスト RAM のうちホストの物理メモリに常駐しているサイズ (bytes)
+    pub guest_memory_resident: u64,
+    /// デバイスがバックエンドから読み取ったバイト数の合計
+    pub io_read_bytes: u64,
+    /// デバイスがバックエンドへ書き込んだバイト数の合計
+    pub io_written_bytes: u64,
+}
+
+impl HostMetrics {
+    /// Prometheus のテキスト形式で書き出す (`vm` ラベル付き)
+    pub fn to_prometheus(&self, vm: &str) -> String {
+        let label = vm.replace('\\', "\\\\").replace('"', "\\\"");
+        let metrics: [(&str, &str, &str, f64); 7] = [
+            (
+                "hypervisor_vcpu_cpu_seconds_total",
+                "counter",
+                "CPU time of the vCPU thread, including guest execution",
+                self.vcpu_cpu_time.as_secs_f64(),
+            ),
+            (
+                "hypervisor_process_cpu_seconds_total",
+                "counter",
+                "CPU time of the VMM process",
+                self.process_cpu_time.as_secs_f64(),
+            ),
+            (
+                "hypervisor_process_max_rss_bytes",
+                "gauge",
+                "Peak resident set size of the VMM process",
+                self.max_rss as f64,
+            ),
+            (
+                "hypervisor_guest_memory_bytes",
+                "gauge",
+                "Declared size of guest RAM",
+                self.guest_memory_declared as f64,
+            ),
+            (
+                "hypervisor_guest_memory_resident_bytes",
+                "gauge",
+                "Guest RAM resident in host memory",
+                self.guest_memory_resident as f64,
+            ),
+            (
+                "hypervisor_io_read_bytes_total",
+                "counter",
+                "Bytes read by devices from their host backends",
+                self.io_read_bytes as f64,
+            ),
+            (
+                "hypervisor_io_written_bytes_total",
+                "counter",
+                "Bytes written by devices to their host backends",
+                self.io_written_bytes as f64,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{}{{vm=\"{}\"}} {}", name, label, value);
+        }
+        out
+    }
+}
+
+/// 呼び出したスレッドの CPU 時間
+pub fn thread_cpu_time() -> Duration {
+    let mut ts = MaybeUninit::<libc::timespec>::uninit();
+    // SAFETY: ts は clock_gettime が埋める
+    let ret = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, ts.as_mut_ptr()) };
+    if ret != 0 {
+        return Duration::ZERO;
+    }
+    let ts = unsafe { ts.assume_init() };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// プロセスの CPU 時間と最大 RSS (bytes)
+pub fn process_usage() -> (Duration, u64) {
+    let mut usage = MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: usage は getrusage が埋める
+    let ret = unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) };
+    if ret != 0 {
+        return (Duration::ZERO, 0);
+    }
+    let usage = unsafe { usage.assume_init() };
+    let timeval = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    let cpu = timeval(usage.ru_utime) + timeval(usage.ru_stime);
+    // ru_maxrss の単位は macOS では bytes、Linux では KiB
+    let max_rss = if cfg!(target_os = "macos") {
+        usage.ru_maxrss as u64
+    } else {
+        usage.ru_maxrss as u64 * 1024
+    };
+    (cpu, max_rss)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn スレッドとプロセスの使用量を取得できる() {
+        let start = thread_cpu_time();
+        let mut x = 0u64;
+        for i in 0..2_000_000u64 {
+            x = x.wrapping_add(std::hint::black_box(i));
+        }
+        std::hint::black_box(x);
+        assert!(thread_cpu_time() > start);
+
+        let (cpu, max_rss) = process_usage();
+        assert!(cpu >= thread_cpu_time() - start);
+        assert!(max_rss > 0);
+    }
+
+    #[test]
+    fn prometheus_形式で書き出す() {
+        let metrics = HostMetrics {
+            vcpu_cpu_time: Duration::from_millis(1500),
+            guest_memory_declared: 128 << 20,
+            io_read_bytes: 512,
+            ..Default::default()
+        };
+        let text = metrics.to_prometheus("vm\"0");
+        assert!(text.contains("# TYPE hypervisor_vcpu_cpu_seconds_total counter\n"));
+        assert!(text.contains("hypervisor_vcpu_cpu_seconds_total{vm=\"vm\\\"0\"} 1.5\n"));
+        assert!(text.contains("hypervisor_guest_memory_bytes{vm=\"vm\\\"0\"} 134217728\n"));
+        assert!(text.contains("hypervisor_io_read_bytes_total{vm=\"vm\\\"0\"} 512\n"));
+        assert_eq!(text.lines().count(), 7 * 3);
+    }
+}
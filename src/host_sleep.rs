@@ -0,0 +1,158 @@
+//! ホストのスリープ検出とゲスト時刻の補正
+//!
+//! Mac がスリープすると `mach_absolute_time` (CNTVCT_EL0 と同じカウンタ) は止まるが、
+//! `mach_continuous_time` はスリープ中も進む。両者の差が増えていればその分だけ
+//! ホストがスリープしていたことになる。
+//!
+//! IOKit の電源通知 (`IORegisterForSystemPower`) は CFRunLoop を回すスレッドが必要で、
+//! vCPU スレッドから同期的に扱えない。差分の確認は VM Exit ごとに 2 回の
+//! 関数呼び出しで済むため、run ループ内でポーリングする。
+//!
+//! ゲストの仮想カウンタはホストのカウンタと一緒に止まるため、何もしなければ
+//! ゲストの時刻はスリープ中の分だけ遅れる ([`GuestTimePolicy::Freeze`])。
+//! [`GuestTimePolicy::JumpForward`] では vtimer offset を減らしてスリープ時間分
+//! カウンタを進め、期限を過ぎたタイマーを復帰直後に発火させる。
+
+use std::time::Duration;
+
+extern "C" {
+    fn mach_absolute_time() -> u64;
+    fn mach_continuous_time() -> u64;
+    fn mach_timebase_info(info: *mut MachTimebaseInfo) -> i32;
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MachTimebaseInfo {
+    numer: u32,
+    denom: u32,
+}
+
+/// ホストのスリープ後にゲストの時刻をどう扱うか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GuestTimePolicy {
+    /// スリープ中はゲストの時刻も止める (ゲストからはスリープが見えない)
+    #[default]
+    Freeze,
+    /// 復帰時にスリープ時間分ゲストのカウンタを進める (壁時計と一致させる)
+    JumpForward,
+}
+
+/// 検出したホストのスリープ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostSleep {
+    /// スリープしていた時間
+    pub duration: Duration,
+    /// ゲストのカウンタを進めた量 (ticks、`Freeze` なら 0)
+    pub guest_ticks_skipped: u64,
+}
+
+/// スリープとみなす最短の時間
+///
+/// 差はスリープ以外 (省電力状態のわずかなずれなど) でも増えることがあるため、
+/// これより短い増加は無視する。ゲストの時刻を数秒以内のずれで動かさないための値。
+pub const MIN_SLEEP: Duration = Duration::from_secs(3);
+
+/// `mach_continuous_time` と `mach_absolute_time` の差からスリープを検出する
+#[derive(Debug, Clone)]
+pub struct SleepDetector {
+    /// 前回確認したときの差 (mach ticks)
+    last_gap: Option<u64>,
+    /// mach ticks から ns への変換 (numer, denom)
+    timebase: (u32, u32),
+}
+
+impl SleepDetector {
+    /// ホストの timebase を使う検出器を作成
+    pub fn new() -> Self {
+        let mut info = MachTimebaseInfo::default();
+        // SAFETY: 出力先として有効なポインタを渡している
+        let ret = unsafe { mach_timebase_info(&mut info) };
+        if ret != 0 || info.denom == 0 {
+            // Apple Silicon の値 (24 MHz)
+            info = MachTimebaseInfo {
+                numer: 125,
+                denom: 3,
+            };
+        }
+        Self::with_timebase(info.numer, info.denom)
+    }
+
+    /// timebase を指定して作成
+    pub fn with_timebase(numer: u32, denom: u32) -> Self {
+        Self {
+            last_gap: None,
+            timebase: (numer, denom.max(1)),
+        }
+    }
+
+    /// ホストの時刻を読んでスリープを確認する
+    pub fn poll(&mut self) -> Option<Duration> {
+        // SAFETY: 引数なしの読み取り専用 API
+        let (absolute, continuous) = unsafe { (mach_absolute_time(), mach_continuous_time()) };
+        self.observe(absolute, continuous)
+    }
+
+    /// 2 つの時刻 (mach ticks) からスリープを確認する
+    ///
+    /// 最初の呼び出しは基準を記録するだけで None を返す。前回からの差の増加が
+    /// [`MIN_SLEEP`] より短い場合も None を返す。
+    pub fn observe(&mut self, absolute: u64, continuous: u64) -> Option<Duration> {
+        let gap = continuous.saturating_sub(absolute);
+        let slept = self
+            .last_gap
+            .map(|last| gap.saturating_sub(last))
+            .unwrap_or(0);
+        self.last_gap = Some(gap);
+        let (numer, denom) = self.timebase;
+        let nanos = slept as u128 * numer as u128 / denom as u128;
+        let slept = Duration::from_nanos(nanos.min(u64::MAX as u128) as u64);
+        (slept >= MIN_SLEEP).then_some(slept)
+    }
+}
+
+impl Default for SleepDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// スリープ時間をゲストのカウンタ (ticks) に換算する
+pub fn guest_ticks(duration: Duration, frequency: u64) -> u64 {
+    (duration.as_nanos() * frequency as u128 / 1_000_000_000).min(u64::MAX as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn 差分が増えた分をスリープとして検出する() {
+        // 24 MHz: 1 tick = 125/3 ns
+        let mut detector = SleepDetector::with_timebase(125, 3);
+        assert_eq!(detector.observe(1_000, 1_500), None);
+        // 実行中は両方が同じだけ進む
+        assert_eq!(detector.observe(2_000, 2_500), None);
+        // 4 秒 (96M ticks) スリープ
+        assert_eq!(
+            detector.observe(3_000, 3_500 + 96_000_000),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(detector.observe(4_000, 4_500 + 96_000_000), None);
+        // MIN_SLEEP より短い差の増加は無視する
+        assert_eq!(
+            detector.observe(5_000, 5_500 + 96_000_000 + 48_000_000),
+            None
+        );
+        assert_eq!(
+            detector.observe(6_000, 6_500 + 96_000_000 + 48_000_000),
+            None
+        );
+    }
+
+    #[test]
+    fn スリープ時間をゲストのカウンタに換算する() {
+        assert_eq!(guest_ticks(Duration::from_secs(2), 24_000_000), 48_000_000);
+        assert_eq!(guest_ticks(Duration::from_micros(1), 24_000_000), 24);
+    }
+}
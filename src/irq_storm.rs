@@ -0,0 +1,213 @@
+//! 割り込みストームの検出と抑制
+//!
+//! レベルトリガの割り込みを出すデバイスが、ゲストの処理後も割り込み線を
+//! 下げない (エミュレーションのバグでステータスがクリアされない) と、run ループは
+//! VM Exit のたびに同じ割り込みをペンディングに戻す。ゲストは acknowledge と EOI を
+//! 繰り返すだけで先に進めず、何も出力しないまま止まったように見える。
+//!
+//! [`IrqStormGuard`] は、割り込み線が下がらないまま acknowledge 済みの割り込みを
+//! 再びペンディングにした回数を数え、しきい値を超えたらストームとして報告し、
+//! 以降の注入を一定間隔に 1 回に絞る。割り込み線が下がればストームは終わる。
+//!
+//! run ループは VM Exit ごとに次のように使う:
+//!
+//! ```ignore
+//! guard.begin_poll();
+//! for irq in mmio_manager.pending_irqs() {
+//!     match guard.admit(irq, gic.is_irq_pending(irq)) {
+//!         Admission::Inject => gic.set_irq_pending(irq),
+//!         Admission::Throttled => {}
+//!         Admission::Storm(repends) => { /* 報告してから注入 */ }
+//!     }
+//! }
+//! guard.end_poll();
+//! ```
+
+use crate::devices::gic::MAX_IRQS;
+use crate::devices::host_time::{HostClock, SystemClock};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// ストームとみなす連続した再ペンディングの回数
+pub const STORM_THRESHOLD: u64 = 10_000;
+
+/// ストーム中に注入する間隔
+pub const THROTTLE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// 検出した割り込みストーム
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IrqStorm {
+    /// 割り込み番号 (INTID)
+    pub irq: u32,
+    /// 割り込みをアサートしていたデバイス (`MmioHandler::name`)
+    pub device: Option<String>,
+    /// 割り込み線が下がらないまま再びペンディングにした回数
+    pub repends: u64,
+}
+
+/// 割り込みを注入するかどうかの判定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// 注入する
+    Inject,
+    /// ストーム中のため今回は注入しない
+    Throttled,
+    /// ストームを検出した (再ペンディングの回数)。今回は注入する
+    Storm(u64),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct LineState {
+    /// 最後にアサートされていたポーリングの番号
+    seen: u64,
+    /// 割り込み線が下がらないまま再びペンディングにした回数
+    repends: u64,
+    /// ストーム中なら最後に注入した時刻
+    throttled_since: Option<Duration>,
+}
+
+/// 割り込み線ごとに再ペンディングを数え、ストームを抑える
+#[derive(Debug)]
+pub struct IrqStormGuard {
+    threshold: u64,
+    interval: Duration,
+    clock: Arc<dyn HostClock>,
+    /// 現在のポーリングの番号
+    poll: u64,
+    lines: Vec<LineState>,
+}
+
+impl IrqStormGuard {
+    /// [`STORM_THRESHOLD`] と [`THROTTLE_INTERVAL`] で作成
+    pub fn new() -> Self {
+        Self::with_clock(
+            STORM_THRESHOLD,
+            THROTTLE_INTERVAL,
+            Arc::new(SystemClock::new()),
+        )
+    }
+
+    /// しきい値・注入間隔・時刻源を指定して作成
+    pub fn with_clock(threshold: u64, interval: Duration, clock: Arc<dyn HostClock>) -> Self {
+        Self {
+            threshold,
+            interval,
+            clock,
+            // 最初のポーリングで「前回もアサートされていた」と誤判定しないよう 1 から始める
+            poll: 1,
+            lines: vec![LineState::default(); MAX_IRQS],
+        }
+    }
+
+    /// アサートされている割り込みの確認を始める
+    pub fn begin_poll(&mut self) {
+        self.poll += 1;
+    }
+
+    /// アサートされている割り込み `irq` を注入するか判定する
+    ///
+    /// `pending` は GIC でまだペンディングのままか (ゲストが acknowledge していないか)。
+    pub fn admit(&mut self, irq: u32, pending: bool) -> Admission {
+        let Some(line) = self.lines.get_mut(irq as usize) else {
+            return Admission::Inject;
+        };
+        let held = line.seen == self.poll - 1;
+        line.seen = self.poll;
+        if pending {
+            return Admission::Inject;
+        }
+        line.repends = if held { line.repends + 1 } else { 0 };
+
+        let now = self.clock.now();
+        match line.throttled_since {
+            Some(last) if now.saturating_sub(last) < self.interval => Admission::Throttled,
+            Some(_) => {
+                line.throttled_since = Some(now);
+                Admission::Inject
+            }
+            None if line.repends >= self.threshold => {
+                line.throttled_since = Some(now);
+                Admission::Storm(line.repends)
+            }
+            None => Admission::Inject,
+        }
+    }
+
+    /// 確認を終える (今回アサートされていなかった割り込みのストームを解除する)
+    pub fn end_poll(&mut self) {
+        for line in &mut self.lines {
+            if line.seen != self.poll {
+                line.repends = 0;
+                line.throttled_since = None;
+            }
+        }
+    }
+
+    /// ストームとして注入を絞っている割り込み
+    pub fn throttled(&self) -> impl Iterator<Item = u32> + '_ {
+        self.lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| line.throttled_since.is_some())
+            .map(|(irq, _)| irq as u32)
+    }
+}
+
+impl Default for IrqStormGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::host_time::ManualClock;
+
+    /// 1 回の VM Exit で `irq` だけがアサートされている
+    fn poll(guard: &mut IrqStormGuard, irq: u32, pending: bool) -> Admission {
+        guard.begin_poll();
+        let admission = guard.admit(irq, pending);
+        guard.end_poll();
+        admission
+    }
+
+    #[test]
+    fn 割り込み線が下がらないまま再ペンディングが続くとストームとして絞る() {
+        let clock = ManualClock::new();
+        let mut guard =
+            IrqStormGuard::with_clock(3, Duration::from_millis(1), Arc::new(clock.clone()));
+
+        // 最初の注入と、ゲストが acknowledge する前の確認
+        assert_eq!(poll(&mut guard, 33, false), Admission::Inject);
+        assert_eq!(poll(&mut guard, 33, true), Admission::Inject);
+        // acknowledge されるたびにすぐ戻る
+        assert_eq!(poll(&mut guard, 33, false), Admission::Inject);
+        assert_eq!(poll(&mut guard, 33, false), Admission::Inject);
+        assert_eq!(poll(&mut guard, 33, false), Admission::Storm(3));
+        assert_eq!(guard.throttled().collect::<Vec<_>>(), [33]);
+
+        assert_eq!(poll(&mut guard, 33, false), Admission::Throttled);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(poll(&mut guard, 33, false), Admission::Inject);
+        assert_eq!(poll(&mut guard, 33, false), Admission::Throttled);
+
+        // 割り込み線が下がればストームは終わる
+        guard.begin_poll();
+        guard.end_poll();
+        assert_eq!(guard.throttled().count(), 0);
+        assert_eq!(poll(&mut guard, 33, false), Admission::Inject);
+    }
+
+    #[test]
+    fn 毎回下がる割り込み線はストームにならない() {
+        let clock = ManualClock::new();
+        let mut guard = IrqStormGuard::with_clock(3, Duration::from_millis(1), Arc::new(clock));
+        for _ in 0..10 {
+            assert_eq!(poll(&mut guard, 40, false), Admission::Inject);
+            guard.begin_poll();
+            guard.end_poll();
+        }
+        assert_eq!(guard.throttled().count(), 0);
+    }
+}
@@ -1,15 +1,76 @@
 //! macOS Hypervisor.framework を使ったハイパーバイザーの共通ライブラリ
 
+extern crate alloc;
+
+pub mod aarch32;
+pub mod addressing;
+#[cfg(feature = "async")]
+pub mod async_vm;
+pub mod availability;
+pub mod backend;
 pub mod boot;
+pub mod control;
 pub mod devices;
+pub mod event_loop;
+pub mod exit_history;
+pub mod host_capabilities;
+pub mod host_channel;
+pub mod host_metrics;
+pub mod host_sleep;
+pub mod irq_storm;
+pub mod livelock;
+pub mod memory;
+#[cfg(feature = "snapshot")]
+pub mod migration;
 pub mod mmio;
-
-use applevisor::{InterruptType, Mappable, Mapping, MemPerms, Reg, Vcpu, VirtualMachine};
+pub mod monitor;
+#[cfg(feature = "nested")]
+pub mod nested;
+#[cfg(feature = "uart")]
+pub mod orchestration;
+pub mod payloads;
+pub mod qmp;
+pub mod rate_limit;
+pub mod run_options;
+pub mod selftest;
+pub mod stats;
+pub mod trace;
+pub mod vcpu_handle;
+pub mod vm_config;
+pub mod watch;
+
+#[cfg(feature = "snapshot")]
+use applevisor::SimdFpReg;
+use applevisor::{InterruptType, Reg, Vcpu};
+use backend::{HvfVm, VcpuBackend, VmBackend};
+use boot::layout::{IrqMap, MachineLayout};
+use boot::load_map::LoadMap;
+use boot::vectors::ShimVector;
+use control::{ControlFn, ControlHandle};
 use devices::gic::{create_shared_gic, SharedGicWrapper, GIC_DIST_BASE};
 use devices::interrupt::InterruptController;
+use devices::irqchip::{IrqChip, TimerIrqPolicy, TimerLine};
 use devices::timer::TimerReg;
+use event_loop::{EventLoop, Waker};
+use exit_history::ExitHistory;
+use host_channel::{GuestEvent, HostChannel};
+use host_sleep::{GuestTimePolicy, HostSleep, SleepDetector};
+use irq_storm::{Admission, IrqStorm, IrqStormGuard};
+use livelock::{Livelock, LivelockDetector};
+use memory::{GuestMemory, GuestMemoryExt, GuestRam, RamBacking};
 use mmio::MmioManager;
+use monitor::{MonitorCommand, MonitorHandle};
+use run_options::{BrkPolicy, RunOptions};
 use std::mem::ManuallyDrop;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use trace::{Tracer, Track};
+use vcpu_handle::VcpuHandle;
+use vm_config::VmConfig;
+use watch::{WatchAction, WatchHit, WriteWatches};
+
+/// WFI で待つ最長時間 (タイマーの期限がない場合も、ホストのスリープ検出などを続ける)
+const MAX_IDLE_WAIT: Duration = Duration::from_secs(1);
 
 /// レジスタインデックスから Reg enum への変換テーブル
 const REGISTER_TABLE: [Reg; 31] = [
@@ -46,92 +107,117 @@ const REGISTER_TABLE: [Reg; 31] = [
     Reg::X30,
 ];
 
-/// デバッグ統計情報
-#[derive(Debug, Default)]
-struct DebugStats {
-    exit_count: u64,
-    wfi_count: u64,
-    mmio_count: u64,
-    vtimer_activated_count: u64,
-    other_exception_count: u64,
-    timer_pending_count: u64,
-    timer_sync_count: u64,
-    sw_timer_fire_count: u64,
+/// ゲストが書き換える EL1/EL0 のシステムレジスタ
+///
+/// 再起動 ([`Hypervisor::reboot`]) では作成直後の値に戻し、移送では送り先に書き戻す。
+pub(crate) const GUEST_SYS_REGS: [applevisor::SysReg; 24] = [
+    applevisor::SysReg::SP_EL0,
+    applevisor::SysReg::SP_EL1,
+    applevisor::SysReg::ELR_EL1,
+    applevisor::SysReg::SPSR_EL1,
+    applevisor::SysReg::SCTLR_EL1,
+    applevisor::SysReg::CPACR_EL1,
+    applevisor::SysReg::TCR_EL1,
+    applevisor::SysReg::TTBR0_EL1,
+    applevisor::SysReg::TTBR1_EL1,
+    applevisor::SysReg::MAIR_EL1,
+    applevisor::SysReg::AMAIR_EL1,
+    applevisor::SysReg::VBAR_EL1,
+    applevisor::SysReg::ESR_EL1,
+    applevisor::SysReg::FAR_EL1,
+    applevisor::SysReg::PAR_EL1,
+    applevisor::SysReg::AFSR0_EL1,
+    applevisor::SysReg::AFSR1_EL1,
+    applevisor::SysReg::CONTEXTIDR_EL1,
+    applevisor::SysReg::TPIDR_EL0,
+    applevisor::SysReg::TPIDR_EL1,
+    applevisor::SysReg::TPIDRRO_EL0,
+    applevisor::SysReg::CNTKCTL_EL1,
+    applevisor::SysReg::CNTV_CTL_EL0,
+    applevisor::SysReg::CNTV_CVAL_EL0,
+];
+
+/// [`GUEST_SYS_REGS`] の現在の値を読む
+fn read_guest_sys_regs(
+    vcpu: &dyn VcpuBackend,
+) -> Result<Vec<(applevisor::SysReg, u64)>, Box<dyn std::error::Error>> {
+    GUEST_SYS_REGS
+        .iter()
+        .map(|&reg| Ok((reg, vcpu.get_sys_reg(reg)?)))
+        .collect()
 }
 
-impl DebugStats {
-    fn log_timer_pending(&mut self) {
-        self.timer_pending_count += 1;
-        if self.timer_pending_count <= 10 {
-            eprintln!("[TIMER] IRQ pending #{}", self.timer_pending_count);
-        }
-    }
+/// 汎用レジスタの番号 (0-30) に対応する [`Reg`]
+///
+/// 31 (XZR / SP) 以上は None を返す。
+pub fn gpr(index: usize) -> Option<Reg> {
+    REGISTER_TABLE.get(index).copied()
+}
 
-    fn log_timer_sync(&mut self, guest_ctl: u64, guest_cval: u64, virt_counter: u64) {
-        self.timer_sync_count += 1;
-        if self.timer_sync_count <= 20 || self.timer_sync_count.is_multiple_of(5000) {
-            let enabled = (guest_ctl & 0x1) != 0;
-            let imask = (guest_ctl & 0x2) != 0;
-            eprintln!(
-                "[TIMER_SYNC #{}] guest_ctl=0x{:x} (enabled={}, imask={}), guest_cval=0x{:x}, sw_counter=0x{:x}",
-                self.timer_sync_count, guest_ctl, enabled, imask, guest_cval, virt_counter
-            );
-        }
-    }
+/// SIMD/FP レジスタのインデックスから SimdFpReg enum への変換テーブル
+#[cfg(feature = "snapshot")]
+const SIMD_REGISTER_TABLE: [SimdFpReg; 32] = [
+    SimdFpReg::Q0,
+    SimdFpReg::Q1,
+    SimdFpReg::Q2,
+    SimdFpReg::Q3,
+    SimdFpReg::Q4,
+    SimdFpReg::Q5,
+    SimdFpReg::Q6,
+    SimdFpReg::Q7,
+    SimdFpReg::Q8,
+    SimdFpReg::Q9,
+    SimdFpReg::Q10,
+    SimdFpReg::Q11,
+    SimdFpReg::Q12,
+    SimdFpReg::Q13,
+    SimdFpReg::Q14,
+    SimdFpReg::Q15,
+    SimdFpReg::Q16,
+    SimdFpReg::Q17,
+    SimdFpReg::Q18,
+    SimdFpReg::Q19,
+    SimdFpReg::Q20,
+    SimdFpReg::Q21,
+    SimdFpReg::Q22,
+    SimdFpReg::Q23,
+    SimdFpReg::Q24,
+    SimdFpReg::Q25,
+    SimdFpReg::Q26,
+    SimdFpReg::Q27,
+    SimdFpReg::Q28,
+    SimdFpReg::Q29,
+    SimdFpReg::Q30,
+    SimdFpReg::Q31,
+];
 
-    fn log_sw_timer_fire(&mut self, hw_counter: u64, cval: u64) {
-        self.sw_timer_fire_count += 1;
-        if self.sw_timer_fire_count <= 20 || self.sw_timer_fire_count.is_multiple_of(1000) {
-            eprintln!(
-                "[SW_TIMER_FIRE #{}] counter=0x{:x} >= cval=0x{:x} -> injecting IRQ via GIC",
-                self.sw_timer_fire_count, hw_counter, cval
-            );
-        }
-    }
+/// ゲスト RAM の初期化パターン
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RamFill {
+    /// ゼロで埋める
+    #[default]
+    Zero,
+    /// 0xAA で埋める (未初期化メモリの読み取りを検出しやすくする)
+    Poison,
+}
 
-    fn log_vtimer_activated(&mut self) {
-        self.vtimer_activated_count += 1;
-        if self.vtimer_activated_count <= 10 {
-            eprintln!(
-                "[VTIMER_ACTIVATED #{}] Timer fired!",
-                self.vtimer_activated_count
-            );
+impl RamFill {
+    /// 埋めるバイト値
+    pub fn byte(self) -> u8 {
+        match self {
+            RamFill::Zero => 0x00,
+            RamFill::Poison => 0xAA,
         }
     }
-
-    fn log_exit_summary(
-        &self,
-        post_run_ctl: u64,
-        post_run_cval: u64,
-        hw_counter: u64,
-        gic_pending: bool,
-    ) {
-        let timer_enabled = (post_run_ctl & 0x1) != 0;
-        let timer_imask = (post_run_ctl & 0x2) != 0;
-        let istatus = timer_enabled && hw_counter >= post_run_cval;
-        eprintln!(
-            "[TIMER STATE @{}] CTL=0x{:x} (enable={}, imask={}, istatus={}), CVAL=0x{:x}, counter=0x{:x}",
-            self.exit_count, post_run_ctl, timer_enabled, timer_imask, istatus, post_run_cval, hw_counter
-        );
-        eprintln!(
-            "[STATS @{}] WFI={}, MMIO={}, VTIMER_ACTIVATED={}, OTHER_EXC={}, GIC_pending={}",
-            self.exit_count,
-            self.wfi_count,
-            self.mmio_count,
-            self.vtimer_activated_count,
-            self.other_exception_count,
-            gic_pending
-        );
-    }
 }
 
-/// ハードウェアカウンタを読み取る
-fn read_hardware_counter() -> u64 {
-    let counter: u64;
-    unsafe {
-        std::arch::asm!("mrs {}, cntvct_el0", out(reg) counter);
-    }
-    counter
+/// ゲスト RAM の使用量
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// 宣言したサイズ (bytes)
+    pub declared: usize,
+    /// ホストの物理メモリに常駐しているサイズ (bytes)
+    pub resident: usize,
 }
 
 /// ハイパーバイザーの実行結果
@@ -144,17 +230,214 @@ pub struct HypervisorResult {
     pub exit_reason: applevisor::ExitReason,
     /// 例外情報 (EXCEPTION の場合のみ)
     pub exception_syndrome: Option<u64>,
+    /// ゲスト EL1 のコンテキスト (予期しない例外・VM Exit で終了した場合のみ)
+    ///
+    /// 終了の理由を優先して返すため、レジスタを読めなかった場合も `None` になる。
+    pub el1: Option<El1Context>,
+    /// run ループが戻った理由
+    pub stop_reason: StopReason,
+}
+
+/// run ループが戻った理由
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum StopReason {
+    /// ゲストの VM Exit (`exit_reason` と `exception_syndrome` を参照)
+    #[default]
+    Exit,
+    /// 同じ VM Exit を繰り返すだけで先に進まなくなった ([`livelock`])
+    Livelock(Livelock),
+    /// `add_device` で追加したデバイスが終了を要求した (`exit_reason` は CANCELED)
+    DeviceExit(devices::sdk::DeviceExit),
+}
+
+impl HypervisorResult {
+    /// 例外ベクタのシム ([`RunOptions::vector_shim`]) で止まった場合、ゲストが入ったベクタ
+    ///
+    /// ゲストの ESR_EL1・ELR_EL1 などは `el1` に入っている。
+    pub fn shim_vector(&self) -> Option<ShimVector> {
+        self.exception_syndrome.and_then(ShimVector::from_syndrome)
+    }
+
+    /// ゲストが PSCI SYSTEM_RESET で再起動を要求して終了したか
+    ///
+    /// SYSTEM_RESET の HVC はゲストに戻らずに終了するため、X0 に関数 ID が残っている。
+    pub fn is_system_reset(&self) -> bool {
+        self.exception_syndrome
+            .is_some_and(|esr| (esr >> 26) & 0x3f == 0x16)
+            && self.registers[0] == 0x8400_0009
+    }
+}
+
+/// 予期しない終了時のゲスト EL1 のコンテキスト
+///
+/// ゲストの中で起きた例外は本来 EL1 のベクタで処理されるため ESR_EL1 などに
+/// 痕跡が残る。EL2 に回ってきた例外と見比べると、ゲスト内部の fault (ベクタの
+/// 未設定や再帰的な例外) か、ハイパーバイザーが処理できなかった VM Exit かを区別できる。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct El1Context {
+    /// ESR_EL1 (ゲストが最後に EL1 で受けた例外の syndrome)
+    pub esr: u64,
+    /// FAR_EL1 (ゲストが最後に受けた fault のアドレス)
+    pub far: u64,
+    /// ELR_EL1 (EL1 の例外からの戻り先)
+    pub elr: u64,
+    /// SPSR_EL1 (EL1 の例外を受けたときの PSTATE)
+    pub spsr: u64,
+    /// SP_EL0
+    pub sp_el0: u64,
+    /// SP_EL1
+    pub sp_el1: u64,
+}
+
+impl El1Context {
+    /// vCPU から EL1 のシステムレジスタを読み取る
+    fn capture(vcpu: &dyn VcpuBackend) -> Result<Self, Box<dyn std::error::Error>> {
+        use applevisor::SysReg;
+        Ok(Self {
+            esr: vcpu.get_sys_reg(SysReg::ESR_EL1)?,
+            far: vcpu.get_sys_reg(SysReg::FAR_EL1)?,
+            elr: vcpu.get_sys_reg(SysReg::ELR_EL1)?,
+            spsr: vcpu.get_sys_reg(SysReg::SPSR_EL1)?,
+            sp_el0: vcpu.get_sys_reg(SysReg::SP_EL0)?,
+            sp_el1: vcpu.get_sys_reg(SysReg::SP_EL1)?,
+        })
+    }
+
+    /// ESR_EL1 の例外クラス (EC)
+    pub fn exception_class(&self) -> u64 {
+        (self.esr >> 26) & 0x3f
+    }
+
+    /// ゲストが EL1 で例外を受けた痕跡があるか
+    ///
+    /// リセット後の ESR_EL1 は 0 のため、0 以外ならゲストの中で例外が起きている。
+    pub fn has_guest_exception(&self) -> bool {
+        self.esr != 0
+    }
+}
+
+impl std::fmt::Display for El1Context {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ESR_EL1=0x{:x} (EC=0x{:x}) FAR_EL1=0x{:x} ELR_EL1=0x{:x} SPSR_EL1=0x{:x} \
+             SP_EL0=0x{:x} SP_EL1=0x{:x}",
+            self.esr,
+            self.exception_class(),
+            self.far,
+            self.elr,
+            self.spsr,
+            self.sp_el0,
+            self.sp_el1
+        )
+    }
+}
+
+/// トレースに記録する VM Exit のイベント名
+fn exit_event_name(exit_info: &applevisor::VcpuExit) -> &'static str {
+    match exit_info.reason {
+        applevisor::ExitReason::EXCEPTION => match (exit_info.exception.syndrome >> 26) & 0x3f {
+            0x01 => "wfi/wfe",
+            0x12 | 0x16 => "hvc",
+            0x18 => "sysreg",
+            0x03..=0x05 | 0x0c => "coproc",
+            0x24 => "data abort",
+            0x38 | 0x3c => "brk",
+            _ => "exception",
+        },
+        applevisor::ExitReason::VTIMER_ACTIVATED => "vtimer",
+        _ => "exit",
+    }
 }
 
 /// ゲストプログラムを実行するハイパーバイザー
 pub struct Hypervisor {
-    _vm: ManuallyDrop<VirtualMachine>,
-    vcpu: ManuallyDrop<Vcpu>,
-    mem: Mapping,
+    vm: &'static dyn VmBackend,
+    vcpu: ManuallyDrop<Box<dyn VcpuBackend>>,
+    /// ゲスト RAM (デバイスと共有するため Arc で保持)
+    mem: Arc<GuestRam>,
+    /// 追加のメモリ領域 (共有メモリなど)
+    regions: Vec<GuestRam>,
+    /// ホストのメモリで裏付けた MMIO 領域 (名前, メモリ)
+    memory_mmio: Vec<(String, Arc<GuestRam>)>,
     guest_addr: u64,
     mmio_manager: MmioManager,
-    interrupt_controller: InterruptController,
-    debug_stats: DebugStats,
+    /// GIC・タイマー・タイマー割り込みの注入ポリシー
+    irq_chip: IrqChip,
+    exit_stats: stats::ExitStats,
+    /// 直近の VM Exit の履歴 (異常終了時に出力する)
+    exit_history: ExitHistory,
+    /// 最後にゲストへ渡した DTB
+    device_tree: Option<Vec<u8>>,
+    /// run ループのイベントの記録先
+    tracer: Option<Tracer>,
+    /// マイグレーション送信中の変更ページの追跡
+    #[cfg(feature = "snapshot")]
+    dirty_log: Option<migration::DirtyLog>,
+    /// ホストのスリープ検出
+    sleep_detector: SleepDetector,
+    /// ホストのスリープ後のゲスト時刻の扱い
+    time_policy: GuestTimePolicy,
+    /// `boot_linux` でコマンドラインに補うコンソール
+    guest_console: boot::console::GuestConsole,
+    /// ゲストの BRK の扱い (`run_with` ごとに設定)
+    brk_policy: BrkPolicy,
+    /// 処理した BRK を出力するか
+    log_brk: bool,
+    /// 実行中に検出したホストのスリープ
+    host_sleeps: Vec<HostSleep>,
+    /// デバイス割り込みのストーム検出
+    irq_guard: IrqStormGuard,
+    /// 実行中に検出した割り込みストーム
+    irq_storms: Vec<IrqStorm>,
+    /// 同じ VM Exit の繰り返しの検出
+    livelock: LivelockDetector,
+    /// 次の再起動で起動するカーネル (`set_next_boot`)
+    next_boot: Option<boot::next_boot::NextBoot>,
+    /// 作成直後の [`GUEST_SYS_REGS`] の値 (`reboot` で戻す)
+    reset_sys_regs: Vec<(applevisor::SysReg, u64)>,
+    /// `load_initrd` で配置した initrd (開始, 終了)
+    initrd: Option<(u64, u64)>,
+    /// 割り込み番号の割り当て (Device Tree と GIC への注入で共通)
+    irqs: IrqMap,
+    /// vCPU 数などの構成 (Device Tree・MPIDR・GICD_TYPER で共通)
+    vm_config: VmConfig,
+    /// Device Tree に宣言した virtio-mmio スロット
+    virtio_slots: Vec<devices::virtio::VirtioSlotHandle>,
+    /// SCMI の doorbell (HVC で呼ばれる)
+    scmi: Option<devices::scmi::ScmiDoorbell>,
+    /// ゲストのテストコードからのイベント通知 (HVC で呼ばれる)
+    host_channel: Option<HostChannel>,
+    /// PL330 スタブのベースアドレス
+    pl330_base: Option<u64>,
+    /// SMMUv3 スタブのベースアドレス
+    smmu_base: Option<u64>,
+    /// 永続メモリ (ベースアドレス, サイズ, 書き込みをファイルに残さないか)
+    pmem: Vec<(u64, u64, bool)>,
+    /// `add_device` で追加したデバイスの Device Tree のノード
+    device_nodes: Vec<hypervisor_device::DtNode>,
+    /// `add_device` で追加したデバイスからの終了の要求
+    device_exits: devices::sdk::ExitRequests,
+    /// 他のスレッドから vCPU を抜けさせるハンドル
+    vcpu_handle: VcpuHandle,
+    /// WFI などでゲストがアイドルの間の待ち
+    event_loop: EventLoop,
+    /// ゲストのアイドル状態と待ちの統計
+    idle: stats::IdleStats,
+    /// 他のスレッドから run ループに送る操作 (`ControlHandle`)
+    control_tx: std::sync::mpsc::Sender<ControlFn>,
+    control_rx: std::sync::mpsc::Receiver<ControlFn>,
+    /// 一時停止中 (run ループはゲストを実行せずに操作を待つ)
+    paused: bool,
+    /// ゲスト RAM への書き込みの監視
+    watches: WriteWatches,
+    /// `load_blob` で配置したイメージ
+    load_map: LoadMap,
+    /// `shutdown()` 済みかどうか
+    shut_down: bool,
+    /// EL2 シャドウレジスタ (nested feature)
+    #[cfg(feature = "nested")]
+    el2_regs: nested::El2SysRegs,
 }
 
 impl Hypervisor {
@@ -163,9 +446,137 @@ impl Hypervisor {
     /// # Arguments
     /// * `guest_addr` - ゲストコードを配置するアドレス
     /// * `mem_size` - ゲストメモリのサイズ (bytes)
+    ///
+    /// RAM は 4GB を超えてもよい (Device Tree の `memory` ノード、virtio の
+    /// キューアドレス、MMIO のフォルトアドレスはいずれも 64 ビットで扱う)。
+    /// 上限はホストの IPA 幅で、確保は遅延されるため触れたページだけが常駐する。
+    ///
+    /// # Errors
+    /// 同じプロセス内に別の Hypervisor が存在する場合はエラーを返す。
+    /// 再作成するには既存のインスタンスを `shutdown()` するか破棄すること。
     pub fn new(guest_addr: u64, mem_size: usize) -> Result<Self, Box<dyn std::error::Error>> {
-        let _vm = ManuallyDrop::new(VirtualMachine::new()?);
-        let vcpu = ManuallyDrop::new(Vcpu::new()?);
+        Self::with_ram_fill(guest_addr, mem_size, RamFill::Zero)
+    }
+
+    /// ゲスト RAM の初期化パターンを指定してハイパーバイザーを作成する
+    ///
+    /// `RamFill::Zero` の場合は追加の書き込みを行わない。ゲスト RAM は匿名 `mmap`
+    /// で確保されるためゼロであることが保証されており、
+    /// 明示的に書き込むと全ページがコミットされてしまう。
+    ///
+    /// # Arguments
+    /// * `guest_addr` - ゲストコードを配置するアドレス
+    /// * `mem_size` - ゲストメモリのサイズ (bytes)
+    /// * `fill` - ゲスト RAM の初期化パターン
+    pub fn with_ram_fill(
+        guest_addr: u64,
+        mem_size: usize,
+        fill: RamFill,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        MachineLayout::default().validate_ram(guest_addr, mem_size)?;
+        Self::with_guest_ram(guest_addr, GuestRam::new(mem_size)?, fill)
+    }
+
+    /// このプロセスで Hypervisor.framework の VM を作成できるか確認する
+    ///
+    /// VM を作成してすぐに破棄する。署名や entitlement の不足で失敗する場合は、
+    /// 原因と対処を含むエラーを返す。ゲスト RAM やカーネルを用意する前に呼ぶとよい。
+    ///
+    /// ```ignore
+    /// if let Err(e) = Hypervisor::check_availability() {
+    ///     eprintln!("{e}");
+    ///     std::process::exit(1);
+    /// }
+    /// ```
+    pub fn check_availability() -> Result<(), availability::AvailabilityError> {
+        HvfVm.probe()
+    }
+
+    /// VM の構成を指定してハイパーバイザーを作成する
+    ///
+    /// vCPU 数は Device Tree の `cpus` ノードと GICD_TYPER.CPUNumber に反映される。
+    ///
+    /// VM を作成する前に、構成と RAM の配置をホストの範囲
+    /// ([`HostCapabilities`](host_capabilities::HostCapabilities)) と照合する。
+    /// ホストへの問い合わせに失敗した場合は警告を出し、構成だけを検証する。
+    ///
+    /// # Errors
+    /// 構成が不正な場合 (`smp` feature なしで 2 以上の vCPU など) や、
+    /// ホストの vCPU の上限・IPA 空間を超える場合はエラーを返す
+    pub fn with_vm_config(
+        guest_addr: u64,
+        mem_size: usize,
+        config: VmConfig,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        // 問い合わせは 1 回だけにし、結果を RAM の配置の確認にも使う
+        let host = Self::probe_host(&HvfVm);
+        match &host {
+            Some(host) => config.validate_for_host(host, guest_addr, mem_size)?,
+            None => config.validate()?,
+        }
+        MachineLayout::default().validate_ram(guest_addr, mem_size)?;
+        let ram = GuestRam::new(mem_size)?;
+        let mut hv = Self::with_guest_ram_on_host(guest_addr, ram, RamFill::Zero, host.as_ref())?;
+        hv.irq_chip
+            .gic()
+            .lock()
+            .unwrap()
+            .set_cpu_count(config.vcpu_count());
+        hv.vm_config = config;
+        Ok(hv)
+    }
+
+    /// 確保済みのゲスト RAM を使ってハイパーバイザーを作成する
+    ///
+    /// 大きなページで確保したい場合に使用する:
+    ///
+    /// ```no_run
+    /// use hypervisor::memory::GuestRam;
+    /// use hypervisor::{Hypervisor, RamFill};
+    ///
+    /// let ram = GuestRam::with_huge_pages(4 * 1024 * 1024 * 1024).unwrap();
+    /// let hv = Hypervisor::with_guest_ram(0x4000_0000, ram, RamFill::Zero).unwrap();
+    /// println!("RAM backing: {:?}", hv.ram_backing());
+    /// ```
+    ///
+    /// # Arguments
+    /// * `guest_addr` - ゲスト RAM を配置するアドレス
+    /// * `ram` - ゲスト RAM (未マッピング)
+    /// * `fill` - ゲスト RAM の初期化パターン
+    pub fn with_guest_ram(
+        guest_addr: u64,
+        ram: GuestRam,
+        fill: RamFill,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let host = Self::probe_host(&HvfVm);
+        Self::with_guest_ram_on_host(guest_addr, ram, fill, host.as_ref())
+    }
+
+    /// 問い合わせ済みのホストの範囲 (`host`) で RAM の配置を確かめて作成する
+    fn with_guest_ram_on_host(
+        guest_addr: u64,
+        ram: GuestRam,
+        fill: RamFill,
+        host: Option<&host_capabilities::HostCapabilities>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        HvfVm.create()?;
+        let vcpu = match Self::create_vcpu() {
+            Ok(vcpu) => vcpu,
+            Err(e) => {
+                let _ = HvfVm.destroy();
+                return Err(e);
+            }
+        };
+        let mut hv = Self::with_backend_on_host(guest_addr, ram, &HvfVm, Box::new(vcpu), host)?;
+        if fill != RamFill::Zero {
+            hv.scrub_ram(fill)?;
+        }
+        Ok(hv)
+    }
+
+    /// Hypervisor.framework の vCPU を作成し、仮想タイマーを初期化する
+    fn create_vcpu() -> Result<Vcpu, Box<dyn std::error::Error>> {
+        let vcpu = Vcpu::new()?;
 
         // 仮想タイマー割り込みをマスクして FIQ 配信を抑制
         // これにより VTIMER_ACTIVATED イベントで GIC 経由の IRQ として配信できる
@@ -180,10 +591,7 @@ impl Hypervisor {
         // FIQ 問題の根本原因: vtimer_offset=0 だと、ゲストは生のハードウェアカウンタ
         // (~40兆 ticks、システム起動から19日) を見てしまう
         // Linux がタイマーを設定すると、カウンタ >> CVAL となり即座に FIQ が発生
-        let hw_counter: u64;
-        unsafe {
-            std::arch::asm!("mrs {}, cntvct_el0", out(reg) hw_counter);
-        }
+        let hw_counter = vcpu.hardware_counter();
         vcpu.set_vtimer_offset(hw_counter)?;
         eprintln!(
             "[DEBUG] vtimer_offset set to hw_counter: 0x{:x} (guest counter starts from 0)",
@@ -206,8 +614,63 @@ impl Hypervisor {
         let verified_offset = vcpu.get_vtimer_offset().unwrap_or(0);
         eprintln!("[DEBUG] vtimer_offset verified: 0x{:x}", verified_offset);
 
-        let mut mem = Mapping::new(mem_size)?;
-        mem.map(guest_addr, MemPerms::RWX)?;
+        // Device Tree の cpu@0 と同じアフィニティを見せる
+        vcpu.set_sys_reg(applevisor::SysReg::MPIDR_EL1, vm_config::mpidr(0))?;
+        Ok(vcpu)
+    }
+
+    /// 作成済みの VM と vCPU を使ってハイパーバイザーを作成する
+    ///
+    /// ゲスト RAM は `vm` にマッピングする。Hypervisor.framework を使わずに
+    /// run ループを動かす場合は [`backend::MockVm`] と [`backend::MockVcpu`] を渡す。
+    /// 失敗した場合と破棄した場合は `vm.destroy()` を呼ぶ。
+    ///
+    /// # Arguments
+    /// * `guest_addr` - ゲスト RAM を配置するアドレス
+    /// * `mem` - ゲスト RAM (未マッピング)
+    /// * `vm` - 作成済みの VM
+    /// * `vcpu` - `vm` の vCPU
+    pub fn with_backend(
+        guest_addr: u64,
+        mem: GuestRam,
+        vm: &'static dyn VmBackend,
+        vcpu: Box<dyn VcpuBackend>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let host = Self::probe_host(vm);
+        Self::with_backend_on_host(guest_addr, mem, vm, vcpu, host.as_ref())
+    }
+
+    /// ホストが扱える VM の範囲を問い合わせる
+    ///
+    /// 範囲の確認は補助的なものなので、問い合わせに失敗した場合は警告を出して
+    /// None を返し、VM は作る。
+    fn probe_host(vm: &dyn VmBackend) -> Option<host_capabilities::HostCapabilities> {
+        vm.capabilities()
+            .inspect_err(|e| eprintln!("[HOST] Skipping host capability checks: {}", e))
+            .ok()
+    }
+
+    /// 問い合わせ済みのホストの範囲 (`host`) で RAM の配置を確かめて作成する
+    fn with_backend_on_host(
+        guest_addr: u64,
+        mut mem: GuestRam,
+        vm: &'static dyn VmBackend,
+        vcpu: Box<dyn VcpuBackend>,
+        host: Option<&host_capabilities::HostCapabilities>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mapped = MachineLayout::default()
+            .validate_ram(guest_addr, mem.get_size())
+            .and_then(|()| host.map_or(Ok(()), |host| host.check_ram(guest_addr, mem.get_size())))
+            .and_then(|()| mem.map(vm, guest_addr))
+            .and_then(|()| Ok((EventLoop::new()?, read_guest_sys_regs(&*vcpu)?)));
+        let (event_loop, reset_sys_regs) = match mapped {
+            Ok(created) => created,
+            Err(e) => {
+                drop(vcpu);
+                let _ = vm.destroy();
+                return Err(e);
+            }
+        };
 
         // 共有 GIC を作成
         let shared_gic = create_shared_gic(GIC_DIST_BASE);
@@ -217,17 +680,58 @@ impl Hypervisor {
         let gic_wrapper = SharedGicWrapper::new(shared_gic.clone(), GIC_DIST_BASE);
         mmio_manager.register(Box::new(gic_wrapper));
 
-        // InterruptController は同じ GIC を使用
-        let interrupt_controller = InterruptController::with_gic(shared_gic);
+        // IrqChip は同じ GIC を使用
+        let irq_chip = IrqChip::with_gic(shared_gic);
+        let (control_tx, control_rx) = std::sync::mpsc::channel();
 
         Ok(Self {
-            _vm,
-            vcpu,
-            mem,
+            vm,
+            vcpu_handle: VcpuHandle::new(vcpu.instance(), Some(event_loop.waker())),
+            event_loop,
+            idle: stats::IdleStats::default(),
+            vcpu: ManuallyDrop::new(vcpu),
+            mem: Arc::new(mem),
             guest_addr,
+            regions: Vec::new(),
+            memory_mmio: Vec::new(),
             mmio_manager,
-            interrupt_controller,
-            debug_stats: DebugStats::default(),
+            irq_chip,
+            exit_stats: stats::ExitStats::default(),
+            exit_history: ExitHistory::default(),
+            device_tree: None,
+            tracer: None,
+            #[cfg(feature = "snapshot")]
+            dirty_log: None,
+            sleep_detector: SleepDetector::new(),
+            time_policy: GuestTimePolicy::default(),
+            guest_console: boot::console::GuestConsole::default(),
+            brk_policy: BrkPolicy::Exit,
+            log_brk: false,
+            host_sleeps: Vec::new(),
+            irq_guard: IrqStormGuard::new(),
+            irq_storms: Vec::new(),
+            livelock: LivelockDetector::default(),
+            next_boot: None,
+            reset_sys_regs,
+            initrd: None,
+            irqs: IrqMap::QEMU_VIRT,
+            vm_config: VmConfig::new(),
+            virtio_slots: Vec::new(),
+            scmi: None,
+            host_channel: None,
+            pl330_base: None,
+            smmu_base: None,
+            pmem: Vec::new(),
+            device_nodes: Vec::new(),
+            device_exits: devices::sdk::ExitRequests::default(),
+            control_tx,
+            control_rx,
+            paused: false,
+            watches: WriteWatches::new(applevisor::PAGE_SIZE as u64),
+            load_map: LoadMap::new(),
+            shut_down: false,
+            #[cfg(feature = "nested")]
+            el2_regs: nested::El2SysRegs::new(),
         })
     }
 
@@ -241,9 +745,8 @@ impl Hypervisor {
         offset: u64,
         instruction: u32,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        self.mem
-            .write_dword(self.guest_addr + offset, instruction)?;
-        Ok(())
+        self.ensure_active()?;
+        self.mem.write_u32(self.guest_addr + offset, instruction)
     }
 
     /// ゲストメモリに複数の ARM64 命令を書き込む
@@ -260,14 +763,37 @@ impl Hypervisor {
         Ok(())
     }
 
+    /// ブートスタブをゲストメモリに配置する
+    ///
+    /// # Arguments
+    /// * `addr` - スタブを配置するアドレス（絶対アドレス）
+    /// * `stub` - 配置するブートスタブ
+    ///
+    /// # Returns
+    /// `run` の `initial_pc` に渡すべきスタブの先頭アドレス
+    pub fn install_boot_stub(
+        &mut self,
+        addr: u64,
+        stub: &crate::boot::stub::BootStub,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        if addr < self.guest_addr || addr & 0x3 != 0 {
+            return Err(format!("Invalid boot stub address: 0x{:x}", addr).into());
+        }
+        let base_offset = addr - self.guest_addr;
+        for (i, &instruction) in stub.assemble().iter().enumerate() {
+            self.write_instruction(base_offset + (i * 4) as u64, instruction)?;
+        }
+        Ok(addr)
+    }
+
     /// ゲストメモリにデータを書き込む (64-bit)
     ///
     /// # Arguments
     /// * `offset` - guest_addr からのオフセット (bytes)
     /// * `data` - 書き込むデータ (64-bit)
     pub fn write_data(&mut self, offset: u64, data: u64) -> Result<(), Box<dyn std::error::Error>> {
-        self.mem.write_qword(self.guest_addr + offset, data)?;
-        Ok(())
+        self.ensure_active()?;
+        self.mem.write_u64(self.guest_addr + offset, data)
     }
 
     /// ゲストメモリからデータを読み取る (64-bit)
@@ -275,7 +801,39 @@ impl Hypervisor {
     /// # Arguments
     /// * `offset` - guest_addr からのオフセット (bytes)
     pub fn read_data(&self, offset: u64) -> Result<u64, Box<dyn std::error::Error>> {
-        Ok(self.mem.read_qword(self.guest_addr + offset)?)
+        self.ensure_active()?;
+        self.mem.read_u64(self.guest_addr + offset)
+    }
+
+    /// ゲスト RAM の範囲をまとめて読み取る
+    ///
+    /// DMA の結果やゲストが書いたデータ構造を `read_data` を繰り返さずに確認する。
+    ///
+    /// # Arguments
+    /// * `range` - ゲスト物理アドレスの範囲
+    pub fn memory_snapshot(
+        &self,
+        range: std::ops::Range<u64>,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        self.mem.snapshot(range)
+    }
+
+    /// ゲスト RAM の内容が期待値と一致するか確認する
+    ///
+    /// # Arguments
+    /// * `addr` - 先頭のゲスト物理アドレス
+    /// * `expected` - 期待する内容
+    ///
+    /// # Errors
+    /// 一致しない場合は最初に異なる位置を含むエラーを返す
+    pub fn compare_region(
+        &self,
+        addr: u64,
+        expected: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        self.mem.compare(addr, expected)
     }
 
     /// ゲストメモリにバイトデータを書き込む
@@ -283,35 +841,18 @@ impl Hypervisor {
     /// # Arguments
     /// * `addr` - 書き込むアドレス（絶対アドレス）
     /// * `byte` - 書き込むバイト
-    ///
-    /// # Note
-    /// `Mapping` は 4-byte 単位の read/write のみサポートするため、
-    /// 4-byte 単位で読み書きして部分更新を行う
     pub fn write_byte(&mut self, addr: u64, byte: u8) -> Result<(), Box<dyn std::error::Error>> {
-        let aligned_addr = addr & !0x3;
-        let offset = (addr & 0x3) as usize;
-        let mut word = self.mem.read_dword(aligned_addr)?;
-        let mut bytes = word.to_le_bytes();
-        bytes[offset] = byte;
-        word = u32::from_le_bytes(bytes);
-        self.mem.write_dword(aligned_addr, word)?;
-        Ok(())
+        self.ensure_active()?;
+        self.mem.write_u8(addr, byte)
     }
 
     /// ゲストメモリからバイトデータを読み取る
     ///
     /// # Arguments
     /// * `addr` - 読み取るアドレス（絶対アドレス）
-    ///
-    /// # Note
-    /// `Mapping` は 4-byte 単位の read/write のみサポートするため、
-    /// 4-byte 単位で読み書きして部分更新を行う
     pub fn read_byte(&self, addr: u64) -> Result<u8, Box<dyn std::error::Error>> {
-        let aligned_addr = addr & !0x3;
-        let offset = (addr & 0x3) as usize;
-        let word = self.mem.read_dword(aligned_addr)?;
-        let bytes = word.to_le_bytes();
-        Ok(bytes[offset])
+        self.ensure_active()?;
+        self.mem.read_u8(addr)
     }
 
     /// vCPU のレジスタを設定する
@@ -320,6 +861,7 @@ impl Hypervisor {
     /// * `reg` - 設定するレジスタ
     /// * `value` - 設定する値
     pub fn set_reg(&self, reg: Reg, value: u64) -> Result<(), Box<dyn std::error::Error>> {
+        self.ensure_active()?;
         self.vcpu.set_reg(reg, value)?;
         Ok(())
     }
@@ -329,153 +871,1360 @@ impl Hypervisor {
     /// # Arguments
     /// * `reg` - 取得するレジスタ
     pub fn get_reg(&self, reg: Reg) -> Result<u64, Box<dyn std::error::Error>> {
+        self.ensure_active()?;
         Ok(self.vcpu.get_reg(reg)?)
     }
 
-    /// MMIO デバイスハンドラを登録する
+    /// 汎用レジスタ X0-X30 を番号順に取得する
+    ///
+    /// [`Hypervisor::get_reg`] を X0 から X30 まで呼ぶのと同じ。
+    pub fn read_gprs(&self) -> Result<[u64; 31], Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        Ok(self.vcpu.read_gprs()?)
+    }
+
+    /// VM を明示的に破棄する
+    ///
+    /// vCPU・ゲストメモリのマッピング・VM を順に破棄し、同じプロセス内で
+    /// 新しい `Hypervisor` を作成できる状態に戻す。以降このインスタンスの
+    /// 操作はすべてエラーを返す。
+    ///
+    /// # Errors
+    /// 既に shutdown 済みの場合、または Hypervisor.framework が破棄に失敗した場合
+    pub fn shutdown(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        self.shut_down = true;
+        self.teardown()
+    }
+
+    /// ゲスト RAM 全体を指定したパターンで埋める
+    ///
+    /// リブート時に前回のゲストの内容を消去するために使用する。
+    pub fn scrub_ram(&mut self, fill: RamFill) -> Result<(), Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+
+        const CHUNK_SIZE: usize = 0x1_0000;
+        let chunk = vec![fill.byte(); CHUNK_SIZE];
+        let mem_size = self.mem.get_size();
+        let mut offset = 0;
+        while offset < mem_size {
+            let len = CHUNK_SIZE.min(mem_size - offset);
+            self.mem
+                .write(self.guest_addr + offset as u64, &chunk[..len])?;
+            offset += len;
+        }
+        self.load_map.clear();
+        // 前回のゲストのバッファを指したままのデバイスを検出する
+        self.mem.invalidate_accessors();
+        Ok(())
+    }
+
+    /// 名前を付けてイメージをゲスト RAM に配置する
+    ///
+    /// 配置は [`Hypervisor::load_map`] に記録され、既に配置した別の名前の
+    /// イメージと重なる場合は書き込まずにエラーを返す。同じ名前で配置し直すと
+    /// 前の記録を置き換える。
     ///
     /// # Arguments
-    /// * `handler` - 登録する MMIO ハンドラ
-    pub fn register_mmio_handler(&mut self, handler: Box<dyn crate::mmio::MmioHandler>) {
-        self.mmio_manager.register(handler);
+    /// * `name` - 名前 ("kernel", "dtb", "initrd" など)
+    /// * `addr` - 配置するゲスト物理アドレス
+    /// * `data` - イメージ
+    pub fn load_blob(
+        &mut self,
+        name: &str,
+        addr: u64,
+        data: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        self.check_guest_range(addr, data.len(), name)?;
+        self.load_map.insert(name, addr, data.len())?;
+        self.mem.write(addr, data)?;
+        Ok(())
     }
 
-    /// ゲストプログラムを実行する
+    /// `load_blob` と `boot_*` で配置したイメージの一覧
+    pub fn load_map(&self) -> &LoadMap {
+        &self.load_map
+    }
+
+    /// ゲスト RAM とは別のメモリ領域をゲストの物理アドレス空間に追加する
+    ///
+    /// `GuestRam::from_file` で作成した共有メモリを `SharedMemoryDevice` と
+    /// 組み合わせて配置する用途を想定している。
     ///
     /// # Arguments
-    /// * `initial_cpsr` - 初期 CPSR 値 (デフォルト: 0x3c4 = EL1h)
-    /// * `trap_debug` - デバッグ例外をトラップするか (デフォルト: true)
-    /// * `initial_pc` - 初期 PC 値 (デフォルト: self.guest_addr)
+    /// * `guest_addr` - 配置するゲスト物理アドレス
+    /// * `region` - 配置するメモリ (未マッピング)
     ///
-    /// # Returns
-    /// 実行結果 (HypervisorResult)
-    pub fn run(
+    /// # Errors
+    /// RAM・メモリ領域・登録済みの MMIO デバイスと重なる場合はエラーを返す
+    pub fn add_memory_region(
         &mut self,
-        initial_cpsr: Option<u64>,
-        trap_debug: Option<bool>,
-        initial_pc: Option<u64>,
-    ) -> Result<HypervisorResult, Box<dyn std::error::Error>> {
-        // PC を設定
-        let pc = initial_pc.unwrap_or(self.guest_addr);
-        self.vcpu.set_reg(Reg::PC, pc)?;
+        guest_addr: u64,
+        mut region: GuestRam,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        MachineLayout::default().validate_ram(guest_addr, region.get_size())?;
+
+        self.check_memory_overlap(guest_addr, region.get_size())?;
+        let end = guest_addr + region.get_size() as u64;
+        if let Some(device) = self.mmio_device_overlapping(guest_addr, end) {
+            // マッピングした範囲へのアクセスは VM Exit にならず、デバイスに届かなくなる
+            return Err(format!(
+                "Memory region at 0x{:x}-0x{:x} overlaps MMIO device '{}' at 0x{:x}",
+                guest_addr,
+                end,
+                device.name(),
+                device.base()
+            )
+            .into());
+        }
 
-        // CPSR を設定 (デフォルト: EL1h mode)
-        let cpsr = initial_cpsr.unwrap_or(0x3c4);
-        self.vcpu.set_reg(Reg::CPSR, cpsr)?;
+        region.map(self.vm, guest_addr)?;
+        self.regions.push(region);
+        Ok(())
+    }
 
-        // デバッグ例外のトラップを設定
-        if trap_debug.unwrap_or(true) {
-            self.vcpu.set_trap_debug_exceptions(true)?;
+    /// ホストのファイルをゲストの永続メモリとして追加する
+    ///
+    /// ファイルをゲストの物理アドレス空間に直接マッピングし、`boot_linux` /
+    /// `boot_uboot` が生成する Device Tree に `pmem-region` のノードを追加する。
+    /// `CONFIG_OF_PMEM` を有効にしたカーネルでは `/dev/pmemN` として見え、
+    /// DAX で VM Exit なしに読み書きできる。`read_only` ならファイルには書き戻さず
+    /// (ゲストの書き込みは実行中だけ残る)、ノードに `volatile` を付ける。
+    ///
+    /// # Arguments
+    /// * `path` - ファイルのパス (大きさは [`boot::layout::PMEM_ALIGN`] の倍数)
+    /// * `guest_addr` - 配置するゲスト物理アドレス ([`boot::layout::PMEM_ALIGN`] 境界)
+    /// * `read_only` - ファイルに書き戻さないか
+    ///
+    /// # Errors
+    /// アドレスや大きさが [`boot::layout::PMEM_ALIGN`] に揃っていない場合、
+    /// RAM などと重なる場合はエラーを返す
+    pub fn add_pmem<P: AsRef<std::path::Path>>(
+        &mut self,
+        path: P,
+        guest_addr: u64,
+        read_only: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        let region = GuestRam::map_file(path, read_only)?;
+        let size = region.get_size() as u64;
+        let align = boot::layout::PMEM_ALIGN;
+        if !guest_addr.is_multiple_of(align) || !size.is_multiple_of(align) {
+            return Err(format!(
+                "Persistent memory at 0x{:x} + 0x{:x} is not aligned to 0x{:x} (required for DAX)",
+                guest_addr, size, align
+            )
+            .into());
         }
+        self.add_memory_region(guest_addr, region)?;
+        self.pmem.push((guest_addr, size, read_only));
+        Ok(())
+    }
 
-        // ゲストプログラムを実行
-        loop {
-            // タイマー IRQ をポーリング
-            let had_pending_before = self.interrupt_controller.has_pending_irq();
-            self.interrupt_controller.poll_timer_irqs();
-            let has_pending_after = self.interrupt_controller.has_pending_irq();
+    /// ホストのメモリで裏付けた MMIO 領域を追加する
+    ///
+    /// フレームバッファや共有リングバッファのように、アクセスごとの副作用が
+    /// 要らないデバイスのメモリをゲストの物理アドレス空間に直接マッピングする。
+    /// ゲストの読み書きは VM Exit にならないため、[`Hypervisor::register_mmio_handler`]
+    /// と違ってアクセスを捕捉できない。ホストは返したメモリで内容を読み書きする。
+    ///
+    /// # Arguments
+    /// * `name` - 領域の名前 ([`Hypervisor::memory_mmio`] で引く)
+    /// * `base` - ゲスト物理アドレス (ホストのページ境界)
+    /// * `size` - サイズ (ホストのページサイズの倍数)
+    ///
+    /// # Errors
+    /// アドレスやサイズがページに揃っていない場合、名前が登録済みの場合、
+    /// 領域がアドレス空間の終端を越える場合、
+    /// RAM・メモリ領域・登録済みの MMIO デバイスと重なる場合はエラーを返す
+    pub fn add_memory_mmio(
+        &mut self,
+        name: &str,
+        base: u64,
+        size: usize,
+    ) -> Result<Arc<GuestRam>, Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        let page_size = applevisor::PAGE_SIZE;
+        if size == 0 || !size.is_multiple_of(page_size) || !base.is_multiple_of(page_size as u64) {
+            return Err(format!(
+                "Memory-backed MMIO region '{}' at 0x{:x} + 0x{:x} is not aligned to the host page size (0x{:x})",
+                name, base, size, page_size
+            )
+            .into());
+        }
+        if self.memory_mmio(name).is_some() {
+            return Err(format!("Memory-backed MMIO region '{}' already exists", name).into());
+        }
+        self.check_memory_overlap(base, size)?;
+        let end = base + size as u64;
+        if let Some(device) = self.mmio_device_overlapping(base, end) {
+            return Err(format!(
+                "Memory-backed MMIO region '{}' at 0x{:x}-0x{:x} overlaps MMIO device '{}' at 0x{:x}",
+                name,
+                base,
+                end,
+                device.name(),
+                device.base()
+            )
+            .into());
+        }
 
-            if !had_pending_before && has_pending_after {
-                self.debug_stats.log_timer_pending();
-            }
+        let mut region = GuestRam::new(size)?;
+        region.map(self.vm, base)?;
+        let region = Arc::new(region);
+        self.memory_mmio.push((name.to_string(), region.clone()));
+        Ok(region)
+    }
 
-            // FIQ をクリアし、IRQ 状態を更新
-            self.vcpu.set_pending_interrupt(InterruptType::FIQ, false)?;
-            self.vcpu.set_pending_interrupt(
-                InterruptType::IRQ,
-                self.interrupt_controller.has_pending_irq(),
-            )?;
+    /// [`Hypervisor::add_memory_mmio`] で追加した領域
+    pub fn memory_mmio(&self, name: &str) -> Option<Arc<GuestRam>> {
+        self.memory_mmio
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, region)| region.clone())
+    }
 
-            // ゲストのタイマー設定を読み取りソフトウェアタイマーに同期
-            let guest_ctl = self
-                .vcpu
-                .get_sys_reg(applevisor::SysReg::CNTV_CTL_EL0)
-                .unwrap_or(0);
-            let guest_cval = self
-                .vcpu
-                .get_sys_reg(applevisor::SysReg::CNTV_CVAL_EL0)
-                .unwrap_or(i64::MAX as u64);
+    /// `base..end` と重なる登録済みの MMIO デバイス
+    fn mmio_device_overlapping(
+        &self,
+        base: u64,
+        end: u64,
+    ) -> Option<&dyn crate::mmio::MmioHandler> {
+        self.mmio_manager
+            .devices()
+            .find(|d| base < d.base() + d.size() && d.base() < end)
+    }
 
-            let virt_counter = self.interrupt_controller.timer.get_virt_counter();
-            self.interrupt_controller
-                .timer
-                .virt_timer
-                .write_ctl(guest_ctl);
-            self.interrupt_controller
-                .timer
-                .virt_timer
-                .write_cval(guest_cval);
-
-            self.debug_stats
-                .log_timer_sync(guest_ctl, guest_cval, virt_counter);
+    /// RAM・追加したメモリ領域・メモリで裏付けた MMIO 領域との重なりを確認する
+    fn check_memory_overlap(
+        &self,
+        guest_addr: u64,
+        size: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let end = guest_addr.checked_add(size as u64).ok_or_else(|| {
+            format!(
+                "Memory region at 0x{:x} + 0x{:x} overflows the address space",
+                guest_addr, size
+            )
+        })?;
+        let existing = std::iter::once((self.guest_addr, self.mem.get_size())).chain(
+            self.regions
+                .iter()
+                .chain(self.memory_mmio.iter().map(|(_, r)| &**r))
+                .filter_map(|r| r.get_guest_addr().map(|addr| (addr, r.get_size()))),
+        );
+        for (base, size) in existing {
+            if guest_addr < base + size as u64 && base < end {
+                return Err(format!(
+                    "Memory region 0x{:x}-0x{:x} overlaps existing memory at 0x{:x}-0x{:x}",
+                    guest_addr,
+                    end,
+                    base,
+                    base + size as u64
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
 
-            // FIQ 防止: ハードウェアタイマーを無効化して vcpu.run() を実行
-            self.vcpu
-                .set_sys_reg(applevisor::SysReg::CNTV_CTL_EL0, 0x2)?;
-            self.vcpu
-                .set_sys_reg(applevisor::SysReg::CNTV_CVAL_EL0, i64::MAX as u64)?;
-            self.vcpu.set_pending_interrupt(InterruptType::FIQ, false)?;
+    /// ゲスト RAM の使用量 (宣言サイズと常駐サイズ)
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            declared: self.mem.get_size(),
+            resident: self.mem.resident_size(),
+        }
+    }
 
-            self.vcpu.run()?;
+    /// ホストの資源使用量 (CPU 時間・RSS・ゲスト RAM の常駐量・デバイスの I/O 量)
+    ///
+    /// vCPU の CPU 時間は呼び出したスレッドの値なので、`run` と同じスレッドから呼ぶこと。
+    pub fn host_metrics(&self) -> host_metrics::HostMetrics {
+        let (process_cpu_time, max_rss) = host_metrics::process_usage();
+        let usage = self.memory_usage();
+        let io = self
+            .mmio_manager
+            .device_stats()
+            .into_iter()
+            .flat_map(|d| d.io);
+        let (io_read_bytes, io_written_bytes) = io.fold((0, 0), |(r, w), io| {
+            (r + io.read_bytes, w + io.written_bytes)
+        });
+        host_metrics::HostMetrics {
+            vcpu_cpu_time: host_metrics::thread_cpu_time(),
+            process_cpu_time,
+            max_rss,
+            guest_memory_declared: usage.declared as u64,
+            guest_memory_resident: usage.resident as u64,
+            io_read_bytes,
+            io_written_bytes,
+        }
+    }
 
-            // ゲストが設定したタイマー値を再読み取り
-            let post_run_ctl = self
-                .vcpu
-                .get_sys_reg(applevisor::SysReg::CNTV_CTL_EL0)
-                .unwrap_or(0);
-            let post_run_cval = self
-                .vcpu
-                .get_sys_reg(applevisor::SysReg::CNTV_CVAL_EL0)
-                .unwrap_or(i64::MAX as u64);
+    /// ゲスト RAM のホスト側の確保方法 (大きなページが得られたか)
+    pub fn ram_backing(&self) -> RamBacking {
+        self.mem.backing()
+    }
 
-            let timer_enabled = (post_run_ctl & 0x1) != 0;
-            let timer_imask = (post_run_ctl & 0x2) != 0;
-            let hw_counter = read_hardware_counter();
+    /// ホストが扱える VM の範囲を取得する
+    ///
+    /// [`HostCapabilities::query`](host_capabilities::HostCapabilities::query) の内容に、
+    /// vCPU から読んだ ID_AA64MMFR0_EL1 (ゲストが使える stage-1 粒度) を加える。
+    pub fn host_capabilities(
+        &self,
+    ) -> Result<host_capabilities::HostCapabilities, Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        let mmfr0 = self
+            .vcpu
+            .get_sys_reg(applevisor::SysReg::ID_AA64MMFR0_EL1)?;
+        Ok(self.vm.capabilities()?.with_mmfr0(mmfr0))
+    }
 
-            // タイマー発火条件をチェックし GIC 経由で IRQ を注入
-            if timer_enabled && !timer_imask && hw_counter >= post_run_cval {
-                self.debug_stats
-                    .log_sw_timer_fire(hw_counter, post_run_cval);
-                let mut gic = self.interrupt_controller.gic.lock().unwrap();
-                gic.set_irq_pending(devices::timer::VIRT_TIMER_IRQ);
-            }
+    /// ゲストのページ粒度と物理アドレス幅をホストが扱えるか確認する
+    ///
+    /// 64KB ページや 52-bit 物理アドレスでビルドしたカーネルを起動する前に呼ぶ。
+    /// ゲストの見る値は変えない (変えるには [`Hypervisor::set_address_config`])。
+    ///
+    /// # Returns
+    /// 設定に合わせた ID_AA64MMFR0_EL1 の値
+    pub fn check_address_config(
+        &self,
+        config: &addressing::AddressConfig,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        addressing::validate_ram(config, self.guest_addr, self.mem.get_size() as u64)?;
+        let host = self
+            .vcpu
+            .get_sys_reg(applevisor::SysReg::ID_AA64MMFR0_EL1)?;
+        addressing::synthesize_mmfr0(host, config)
+    }
 
-            let exit_info = self.vcpu.get_exit_info();
+    /// ゲストのページ粒度と物理アドレス幅を確認し、ゲストの ID_AA64MMFR0_EL1 に反映する
+    ///
+    /// [`Hypervisor::check_address_config`] の値を vCPU に設定するため、ゲストには
+    /// `config.pa_bits` に切り詰めた PARange が見える。再起動しても戻らない。
+    ///
+    /// # Returns
+    /// 設定した ID_AA64MMFR0_EL1 の値
+    pub fn set_address_config(
+        &mut self,
+        config: &addressing::AddressConfig,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let mmfr0 = self.check_address_config(config)?;
+        self.vcpu
+            .set_sys_reg(applevisor::SysReg::ID_AA64MMFR0_EL1, mmfr0)?;
+        Ok(mmfr0)
+    }
 
-            // IRQ 状態を更新
-            self.vcpu.set_pending_interrupt(
-                InterruptType::IRQ,
-                self.interrupt_controller.has_pending_irq(),
-            )?;
+    /// ホストの Hypervisor.framework の動作を確認する
+    ///
+    /// システムレジスタのトラップ、CNTVCT_EL0、WFI/WFE、MMIO アクセスの
+    /// syndrome を調べて表にまとめる。ゲストの起動前に呼ぶこと。書き換えた
+    /// RAM の先頭とレジスタは戻してから返す。
+    pub fn selftest(&mut self) -> Result<selftest::SelfTestReport, Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        let scratch = self.guest_addr..self.guest_addr + selftest::SCRATCH_SIZE;
+        self.check_guest_range(scratch.start, selftest::SCRATCH_SIZE as usize, "selftest")?;
+        let saved_ram = self.mem.snapshot(scratch.clone())?;
+        let saved_regs = [Reg::PC, Reg::CPSR, Reg::X0, Reg::X1, Reg::X2, Reg::X3]
+            .map(|reg| self.vcpu.get_reg(reg).map(|value| (reg, value)));
+        let saved_sys_regs = [applevisor::SysReg::VBAR_EL1, applevisor::SysReg::ESR_EL1]
+            .map(|reg| self.vcpu.get_sys_reg(reg).map(|value| (reg, value)));
+
+        let report = selftest::run(
+            &**self.vcpu,
+            &self.mem,
+            self.guest_addr,
+            MachineLayout::default().virtio_base,
+        );
 
-            // exit reason を記録
-            self.debug_stats.exit_count += 1;
+        self.mem.write(scratch.start, &saved_ram)?;
+        for saved in saved_regs {
+            let (reg, value) = saved?;
+            self.vcpu.set_reg(reg, value)?;
+        }
+        for saved in saved_sys_regs {
+            let (reg, value) = saved?;
+            self.vcpu.set_sys_reg(reg, value)?;
+        }
+        report
+    }
+
+    /// 実行統計 (デバイスごとの MMIO 処理時間など)
+    pub fn stats(&self) -> stats::HypervisorStats {
+        stats::HypervisorStats {
+            devices: self.mmio_manager.device_stats(),
+            exits: self.exit_stats,
+            timer_irq_latency: self
+                .irq_chip
+                .gic()
+                .lock()
+                .unwrap()
+                .irq_latency(self.irqs.virt_timer),
+            idle: self.idle,
+        }
+    }
+
+    /// run ループとデバイスのイベント記録を開始する
+    ///
+    /// VM Exit の処理・IRQ 注入・MMIO アクセスを記録し、返した [`Tracer`] から
+    /// Chrome trace / Perfetto 形式で書き出せる。既に記録中の場合は同じ記録先を返す。
+    pub fn enable_tracing(&mut self) -> Tracer {
+        let tracer = self.tracer.get_or_insert_with(Tracer::new).clone();
+        self.mmio_manager.set_tracer(Some(tracer.clone()));
+        tracer
+    }
+
+    /// イベント記録を停止する
+    pub fn disable_tracing(&mut self) {
+        self.tracer = None;
+        self.mmio_manager.set_tracer(None);
+    }
+
+    /// ゲスト RAM の範囲への書き込みを監視する
+    ///
+    /// 範囲を含むページを stage-2 で書き込み禁止にし、ゲストが範囲に書き込むと
+    /// `action` を実行する。制限は [`watch`] モジュールを参照。
+    ///
+    /// # Errors
+    /// 範囲がゲスト RAM の外にある場合、同じ名前の範囲がある場合はエラーを返す
+    pub fn watch_writes(
+        &mut self,
+        name: &str,
+        range: std::ops::Range<u64>,
+        action: WatchAction,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        let ram_end = self.guest_addr + self.mem.get_size() as u64;
+        if range.start < self.guest_addr || range.end > ram_end {
+            return Err(format!(
+                "watch range {} (0x{:x}-0x{:x}) is outside guest RAM 0x{:x}-0x{:x}",
+                name, range.start, range.end, self.guest_addr, ram_end
+            )
+            .into());
+        }
+        let page_size = applevisor::PAGE_SIZE;
+        for page in self.watches.add(name, range, action)? {
+            self.mem.set_writable(page, page_size, false)?;
+        }
+        Ok(())
+    }
+
+    /// 書き込みの監視を止める
+    ///
+    /// # Errors
+    /// 範囲が登録されていない場合はエラーを返す
+    pub fn unwatch_writes(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        let page_size = applevisor::PAGE_SIZE;
+        for page in self.watches.remove(name)? {
+            self.mem.set_writable(page, page_size, true)?;
+        }
+        Ok(())
+    }
+
+    /// 監視範囲への書き込みの記録
+    pub fn watch_hits(&self, name: &str) -> &[WatchHit] {
+        self.watches.hits(name)
+    }
+
+    /// 割り込み番号の割り当てを変更する
+    ///
+    /// `boot_linux` / `boot_uboot` が生成する Device Tree と、タイマー割り込みの
+    /// 注入に使われる。UART などのデバイス側の番号 (`Pl011Uart::set_irq`) も合わせること。
+    pub fn set_irq_map(&mut self, irqs: IrqMap) -> Result<(), Box<dyn std::error::Error>> {
+        irqs.validate()?;
+        self.irqs = irqs;
+        self.irq_chip
+            .set_policy(TimerIrqPolicy::from_irq_map(&irqs));
+        Ok(())
+    }
+
+    /// VM の構成
+    pub fn vm_config(&self) -> VmConfig {
+        self.vm_config
+    }
+
+    /// 割り込み番号の割り当て
+    pub fn irq_map(&self) -> IrqMap {
+        self.irqs
+    }
+
+    /// 登録したデバイスの割り込みが重複していないか確認する
+    ///
+    /// `run()` の開始時にも呼ばれる。
+    pub fn validate_irqs(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.mmio_manager.validate_irqs(&self.irqs)
+    }
+
+    /// `boot_linux` がコマンドラインに補うコンソールを設定する ([`boot::console`])
+    ///
+    /// 既定の [`GuestConsole::Auto`](boot::console::GuestConsole::Auto) はマシンレイアウトの
+    /// 位置に登録した PL011 を使い、登録していなければ補わない。
+    /// `Off` でコマンドラインをそのまま渡す。
+    pub fn set_guest_console(&mut self, console: boot::console::GuestConsole) {
+        self.guest_console = console;
+    }
+
+    /// ホストのスリープ後にゲストの時刻をどう扱うかを設定する
+    pub fn set_guest_time_policy(&mut self, policy: GuestTimePolicy) {
+        self.time_policy = policy;
+    }
+
+    /// 現在のゲスト時刻のポリシー
+    pub fn guest_time_policy(&self) -> GuestTimePolicy {
+        self.time_policy
+    }
+
+    /// `run()` 中に検出したホストのスリープ
+    pub fn host_sleeps(&self) -> &[HostSleep] {
+        &self.host_sleeps
+    }
+
+    /// 割り込みストームの検出に使う設定 (しきい値・注入間隔) を変更する
+    pub fn set_irq_storm_guard(&mut self, guard: IrqStormGuard) {
+        self.irq_guard = guard;
+    }
+
+    /// `run()` 中に検出した割り込みストーム
+    pub fn irq_storms(&self) -> &[IrqStorm] {
+        &self.irq_storms
+    }
+
+    /// livelock とみなす同じ VM Exit の回数を変更する (0 で検出しない)
+    ///
+    /// 既定は [`livelock::LIVELOCK_THRESHOLD`] 回。
+    pub fn set_livelock_threshold(&mut self, threshold: u64) {
+        self.livelock = LivelockDetector::new(threshold);
+    }
+
+    /// VM Exit の履歴の長さと記録するレジスタを変更する (記録済みの履歴は捨てる)
+    ///
+    /// 既定は直近 [`exit_history::DEFAULT_CAPACITY`] 回で、レジスタは記録しない。
+    pub fn set_exit_history(&mut self, history: ExitHistory) {
+        self.exit_history = history;
+    }
+
+    /// 直近の VM Exit の履歴
+    pub fn exit_history(&self) -> &ExitHistory {
+        &self.exit_history
+    }
+
+    /// ゲストからのイベント通知を有効にする ([`host_channel`])
+    ///
+    /// 以後、ゲストが `HOST_CHANNEL_HVC_ID` で発行した HVC をイベントとして
+    /// 受け取る。信頼できるゲストのテストコードにだけ使うこと。
+    pub fn attach_host_channel(&mut self, channel: HostChannel) {
+        self.host_channel = Some(channel);
+    }
+
+    /// ゲストから受け取ったイベント (古い順、通知を有効にしていなければ空)
+    pub fn guest_events(&self) -> &[GuestEvent] {
+        self.host_channel
+            .as_ref()
+            .map_or(&[], |channel| channel.events())
+    }
+
+    /// ゲストから受け取ったイベントを取り出す
+    pub fn take_guest_events(&mut self) -> Vec<GuestEvent> {
+        self.host_channel
+            .as_mut()
+            .map(HostChannel::take_events)
+            .unwrap_or_default()
+    }
+
+    /// 次の再起動で起動するカーネルを指定する (None で指定を取り消す)
+    ///
+    /// ゲストが SYSTEM_RESET で `run()` から戻った後、[`Hypervisor::reboot`] で
+    /// 起動し直すときに使われる (詳細は [`boot::next_boot`])。
+    ///
+    /// # Errors
+    /// カーネルや initrd のファイルが読めない場合
+    pub fn set_next_boot(
+        &mut self,
+        next: Option<boot::next_boot::NextBoot>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(next) = &next {
+            next.check()?;
+        }
+        self.next_boot = next;
+        Ok(())
+    }
+
+    /// 次の再起動で起動するカーネル
+    pub fn next_boot(&self) -> Option<&boot::next_boot::NextBoot> {
+        self.next_boot.as_ref()
+    }
+
+    /// 次の再起動で起動するカーネルを取り出す (指定は取り消される)
+    pub fn take_next_boot(&mut self) -> Option<boot::next_boot::NextBoot> {
+        self.next_boot.take()
+    }
+
+    /// ゲストの再起動 (PSCI SYSTEM_RESET) の後、同じ VM で Linux を起動し直す
+    ///
+    /// デバイスと vCPU のレジスタを作成直後の状態に戻し、[`Hypervisor::set_next_boot`]
+    /// の指定があればそのカーネル・initrd・コマンドラインで、なければ `kernel` と
+    /// `cmdline` で [`Hypervisor::boot_linux`] する。ホスト側の接続 (コンソール・
+    /// ディスク・統計など) はそのまま残る。
+    ///
+    /// # Errors
+    /// 指定されたカーネルや initrd を読み込めない場合 (指定は取り消さない)
+    pub fn reboot(
+        &mut self,
+        kernel: &crate::boot::kernel::KernelImage,
+        cmdline: &str,
+        dtb_addr: Option<u64>,
+    ) -> Result<HypervisorResult, Box<dyn std::error::Error>> {
+        let images = self
+            .next_boot
+            .as_ref()
+            .map(boot::next_boot::NextBoot::load)
+            .transpose()?;
+        let next = self.next_boot.take();
+        self.reset_guest()?;
+        let kernel = match &images {
+            Some(images) => {
+                if let Some(initrd) = &images.initrd {
+                    self.load_initrd(initrd)?;
+                }
+                &images.kernel
+            }
+            None => kernel,
+        };
+        let cmdline = next.map_or_else(|| cmdline.to_string(), |next| next.cmdline_or(cmdline));
+        self.boot_linux(kernel, &cmdline, dtb_addr)
+    }
+
+    /// デバイスと vCPU のレジスタを作成直後の状態に戻す
+    ///
+    /// ゲスト RAM の内容は実機のウォームリセットと同じく残す。
+    fn reset_guest(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.mmio_manager.reset_devices();
+        for &(reg, value) in &self.reset_sys_regs {
+            self.vcpu.set_sys_reg(reg, value)?;
+        }
+        for reg in REGISTER_TABLE {
+            self.vcpu.set_reg(reg, 0)?;
+        }
+        self.load_map.clear();
+        self.initrd = None;
+        Ok(())
+    }
+
+    /// initrd をゲスト RAM の末尾に配置し、`boot_linux` の Device Tree で渡す
+    ///
+    /// # Errors
+    /// ゲスト RAM に収まらない場合や、配置済みのイメージと重なる場合
+    pub fn load_initrd(&mut self, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let ram_end = self.guest_addr + self.mem.get_size() as u64;
+        let start = ram_end
+            .checked_sub(data.len() as u64)
+            .ok_or_else(|| format!("initrd of {} bytes is larger than guest RAM", data.len()))?
+            & !0xfff;
+        self.load_blob("initrd", start, data)?;
+        self.initrd = Some((start, start + data.len() as u64));
+        Ok(())
+    }
+
+    /// ゲストが有効にした、エミュレーションしていない機能 (デバイス名, 機能)
+    ///
+    /// 例えば PL011 の DMA を有効にしたカーネルはコンソール出力が止まる。
+    pub fn unsupported_features(&self) -> &[(String, mmio::UnsupportedFeature)] {
+        self.mmio_manager.unsupported_features()
+    }
+
+    /// マイグレーションの事前コピーとして RAM を送る (実験的)
+    ///
+    /// 最初の呼び出しでストリームのヘッダーと全ページを、以降の呼び出しでは前回から
+    /// 変更されたページだけを書き込む。最初の `run()` の後は `resume()` でゲストを
+    /// 動かしながら合間に繰り返し呼び、送るページが十分少なくなったら `migrate_out` で
+    /// 完了させる。
+    ///
+    /// # Returns
+    /// データ付きで送ったページ数
+    #[cfg(feature = "snapshot")]
+    pub fn precopy_ram(
+        &mut self,
+        w: &mut dyn std::io::Write,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        let (base, size) = (self.guest_addr, self.mem.get_size());
+        if self.dirty_log.is_none() {
+            migration::write_header(w, base, size)?;
+        }
+        let log = self.dirty_log.get_or_insert_with(migration::DirtyLog::new);
+        let pages = log.collect(self.mem.as_ref(), base, size)?;
+        migration::write_ram(w, self.mem.as_ref(), base, size, &pages)
+    }
+
+    /// 停止中の VM をストリームに書き出す (実験的)
+    ///
+    /// `precopy_ram` の後に呼ぶと残りの変更ページだけを送る。続けて
+    /// [`Hypervisor::add_memory_region`] / [`Hypervisor::add_pmem`] と
+    /// [`Hypervisor::add_memory_mmio`] の領域、vCPU と
+    /// [`DeviceState`](migration::DeviceState) を実装したデバイスの状態を書き込む。
+    /// ファイルに書き戻す領域は配置だけを送り、内容は受信側が同じファイルを
+    /// マッピングして引き継ぐ。それ以外の領域は全ページを送る。
+    /// `run()` や `resume()` から戻った後 (ゲスト停止中) に呼ぶこと。
+    #[cfg(feature = "snapshot")]
+    pub fn migrate_out(
+        &mut self,
+        w: &mut dyn std::io::Write,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.mmio_manager.drain_coalesced()?;
+        self.precopy_ram(w)?;
+        self.dirty_log = None;
+
+        let regions = self
+            .regions
+            .iter()
+            .chain(self.memory_mmio.iter().map(|(_, r)| &**r));
+        for region in regions {
+            let base = region
+                .get_guest_addr()
+                .ok_or("Memory region is not mapped")?;
+            let size = region.get_size();
+            let pages: Vec<u64> = if region.backing() == RamBacking::SharedFile {
+                Vec::new()
+            } else {
+                (0..size.div_ceil(migration::PAGE_SIZE) as u64).collect()
+            };
+            migration::write_region(w, region, base, size, &pages)?;
+        }
+        migration::write_vcpu(w, &self.save_vcpu_state()?)?;
+        for (name, base, state) in self.mmio_manager.save_device_states() {
+            migration::write_device(w, &name, base, &state)?;
+        }
+        migration::write_end(w)
+    }
+
+    /// `migrate_out` で書き出した VM を読み込む (実験的)
+    ///
+    /// 送信側と同じ RAM 配置で作成し、同じデバイス・メモリ領域・永続メモリ・
+    /// メモリで裏付けた MMIO 領域を追加してから呼ぶ。
+    /// 読み込み後は `run()` の代わりに `resume()` で送信側の続きから実行する。
+    /// 古い形式のストリームも読め、新しい版にしかないセクションは読み飛ばす。
+    #[cfg(feature = "snapshot")]
+    pub fn migrate_in(
+        &mut self,
+        r: &mut dyn std::io::Read,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        let regions: Vec<(u64, usize, &dyn GuestMemory)> = self
+            .regions
+            .iter()
+            .chain(self.memory_mmio.iter().map(|(_, r)| &**r))
+            .filter_map(|r| {
+                r.get_guest_addr()
+                    .map(|addr| (addr, r.get_size(), r as &dyn GuestMemory))
+            })
+            .collect();
+        let incoming = migration::read_stream(
+            r,
+            self.mem.as_ref(),
+            self.guest_addr,
+            self.mem.get_size(),
+            &regions,
+        )?;
+        let vcpu = incoming.vcpu.ok_or("Migration stream has no vCPU state")?;
+        for device in &incoming.devices {
+            self.mmio_manager
+                .restore_device_state(&device.name, device.base, &device.state)?;
+        }
+        self.restore_vcpu_state(&vcpu)
+    }
+
+    /// 現在の PC と PSTATE のまま実行を再開する
+    ///
+    /// `migrate_in` の後など、`run()` のように entry point を設定し直したくない場合に使う。
+    pub fn resume(&mut self) -> Result<HypervisorResult, Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        let result = self.run_loop();
+        self.mmio_manager.drain_coalesced()?;
+        self.mmio_manager.flush_warnings();
+        result
+    }
+
+    /// vCPU のレジスタを読み出す
+    #[cfg(feature = "snapshot")]
+    fn save_vcpu_state(&self) -> Result<migration::VcpuState, Box<dyn std::error::Error>> {
+        let gprs = self.vcpu.read_gprs()?;
+        let sys_regs = migration::MIGRATED_SYS_REGS
+            .iter()
+            .map(|&reg| self.vcpu.get_sys_reg(reg))
+            .collect::<Result<_, _>>()?;
+        let simd = (0..32)
+            .map(|i| self.vcpu.get_simd_fp_reg(SIMD_REGISTER_TABLE[i]))
+            .collect::<Result<_, _>>()?;
+        Ok(migration::VcpuState {
+            gprs,
+            pc: self.vcpu.get_reg(Reg::PC)?,
+            cpsr: self.vcpu.get_reg(Reg::CPSR)?,
+            fpcr: self.vcpu.get_reg(Reg::FPCR)?,
+            fpsr: self.vcpu.get_reg(Reg::FPSR)?,
+            virtual_counter: self
+                .vcpu
+                .hardware_counter()
+                .wrapping_sub(self.vcpu.get_vtimer_offset()?),
+            sys_regs,
+            simd,
+        })
+    }
+
+    /// vCPU のレジスタを書き戻す
+    #[cfg(feature = "snapshot")]
+    fn restore_vcpu_state(
+        &mut self,
+        state: &migration::VcpuState,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for (i, &value) in state.gprs.iter().enumerate() {
+            self.set_register_by_index(i as u8, value)?;
+        }
+        for (&reg, &value) in migration::MIGRATED_SYS_REGS.iter().zip(&state.sys_regs) {
+            self.vcpu.set_sys_reg(reg, value)?;
+        }
+        for (&reg, &value) in SIMD_REGISTER_TABLE.iter().zip(&state.simd) {
+            self.vcpu.set_simd_fp_reg(reg, value)?;
+        }
+        self.vcpu.set_reg(Reg::PC, state.pc)?;
+        self.vcpu.set_reg(Reg::CPSR, state.cpsr)?;
+        self.vcpu.set_reg(Reg::FPCR, state.fpcr)?;
+        self.vcpu.set_reg(Reg::FPSR, state.fpsr)?;
+        self.vcpu.set_vtimer_offset(
+            self.vcpu
+                .hardware_counter()
+                .wrapping_sub(state.virtual_counter),
+        )?;
+        Ok(())
+    }
+
+    /// デバイスに渡すゲストメモリ
+    ///
+    /// virtio デバイスなどがディスクリプタやバッファを読み書きするために使う。
+    /// 対象はメイン RAM のみで、`add_memory_region` で追加した領域は含まない。
+    /// `shutdown()` 後はマッピングが解除されるため、アクセスはエラーになる。
+    pub fn guest_memory(&self) -> Arc<dyn GuestMemory> {
+        Arc::clone(&self.mem) as Arc<dyn GuestMemory>
+    }
+
+    /// 最後にゲストへ渡した DTB
+    ///
+    /// `boot_linux` / `boot_uboot` が生成してゲストメモリに配置したものと同じバイト列。
+    /// `boot::fdt::to_dts` で DTS テキストに変換できる。
+    /// まだ Device Tree を配置していなければ None を返す。
+    pub fn dump_device_tree(&self) -> Option<&[u8]> {
+        self.device_tree.as_deref()
+    }
+
+    /// `shutdown()` されていなければ true
+    pub fn is_active(&self) -> bool {
+        !self.shut_down
+    }
+
+    /// shutdown 済みならエラーを返す
+    fn ensure_active(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.shut_down {
+            return Err("Hypervisor has been shut down".into());
+        }
+        Ok(())
+    }
+
+    /// vCPU・メモリマッピング・VM を破棄する
+    ///
+    /// applevisor は破棄に失敗すると panic するため、panic をキャッチしてエラーに変換する。
+    fn teardown(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        // Vcpu を先に破棄
+        let vcpu_result = catch_unwind(AssertUnwindSafe(|| unsafe {
+            ManuallyDrop::drop(&mut self.vcpu);
+        }));
+
+        // VM 破棄前にゲストメモリのマッピングを解除
+        let _ = self.mem.unmap();
+        for region in &mut self.regions {
+            let _ = region.unmap();
+        }
+        for (_, region) in &self.memory_mmio {
+            let _ = region.unmap();
+        }
+
+        // VM を破棄
+        let vm_result = self.vm.destroy();
+
+        if vcpu_result.is_err() {
+            return Err("Failed to destroy vCPU".into());
+        }
+        vm_result
+    }
+
+    /// MMIO デバイスハンドラを登録する
+    ///
+    /// virtio デバイスなどがキューを処理できるよう、DMA に使うゲスト RAM を
+    /// [`mmio::MmioHandler::attach_dma`] で渡す。Device Tree の virtio-mmio
+    /// トランスポートの位置に登録したハンドラには、そのスロットの割り込みを
+    /// [`mmio::MmioHandler::attach_irq`] で渡す。
+    ///
+    /// # Arguments
+    /// * `handler` - 登録する MMIO ハンドラ
+    ///
+    /// # Errors
+    /// ハンドラの範囲が RAM・メモリ領域・[`Hypervisor::add_memory_mmio`] の領域と
+    /// 重なる場合はエラーを返す
+    pub fn register_mmio_handler(
+        &mut self,
+        mut handler: Box<dyn crate::mmio::MmioHandler>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_mmio_placement(handler.as_ref())?;
+        handler.attach_dma(devices::virtio::DmaValidator::new(self.guest_memory()));
+        let layout = MachineLayout::default();
+        let offset = handler.base().wrapping_sub(layout.virtio_base);
+        if offset.is_multiple_of(boot::layout::VIRTIO_SLOT_SIZE)
+            && offset / boot::layout::VIRTIO_SLOT_SIZE < self.virtio_slots.len().max(1) as u64
+        {
+            let index = (offset / boot::layout::VIRTIO_SLOT_SIZE) as u32;
+            handler.attach_irq(self.irqs.virtio_slot(index));
+        }
+        self.mmio_manager.register(handler);
+        Ok(())
+    }
+
+    /// MMIO ハンドラの範囲がゲストのメモリと重ならないことを確認する
+    ///
+    /// RAM・メモリ領域・メモリで裏付けた MMIO 領域へのアクセスは VM Exit に
+    /// ならないため、重なった範囲はハンドラに届かない。
+    fn check_mmio_placement(
+        &self,
+        handler: &dyn crate::mmio::MmioHandler,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_memory_overlap(handler.base(), handler.size() as usize)
+            .map_err(|e| format!("MMIO device '{}': {}", handler.name(), e).into())
+    }
+
+    /// `hypervisor-device` crate で作ったデバイスを追加する ([`devices::sdk`])
+    ///
+    /// デバイスに割り込み線とゲスト RAM を渡して MMIO ハンドラとして登録し、
+    /// [`hypervisor_device::DtDescribe::dt_node`] が返すノードを `boot_linux` /
+    /// `boot_uboot` が生成する Device Tree に追加する。
+    ///
+    /// # Arguments
+    /// * `base` - 配置するゲスト物理アドレス
+    /// * `irq` - 割り当てる割り込み (GIC の INTID、SPI のみ)
+    /// * `device` - デバイス
+    ///
+    /// # Errors
+    /// 割り込みが SPI でない場合、ゲストメモリや他のデバイスと重なる場合はエラーを返す
+    pub fn add_device<D: hypervisor_device::Device + 'static>(
+        &mut self,
+        base: u64,
+        irq: Option<u32>,
+        device: D,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        if let Some(intid) = irq {
+            if boot::layout::IrqKind::of(intid) != Some(boot::layout::IrqKind::Spi) {
+                return Err(format!(
+                    "Device '{}' interrupt {} is not an SPI",
+                    device.name(),
+                    intid
+                )
+                .into());
+            }
+        }
+        let size = device.size();
+        self.check_memory_overlap(base, size as usize)?;
+        let end = base + size;
+        if let Some(other) = self.mmio_device_overlapping(base, end) {
+            return Err(format!(
+                "Device '{}' at 0x{:x}-0x{:x} overlaps MMIO device '{}' at 0x{:x}",
+                device.name(),
+                base,
+                end,
+                other.name(),
+                other.base()
+            )
+            .into());
+        }
+
+        if let Some(node) = device.dt_node(base, irq) {
+            self.device_nodes.push(node);
+        }
+        let device = devices::sdk::SdkDevice::attach(
+            Box::new(device),
+            base,
+            irq,
+            self.mem.clone(),
+            &self.device_exits,
+            &self.vcpu_handle,
+        );
+        self.mmio_manager.register(Box::new(device));
+        Ok(())
+    }
+
+    /// 空の virtio-mmio スロットを `count` 個追加する
+    ///
+    /// スロットは `MachineLayout::virtio_base` から順に並び、`boot_linux` /
+    /// `boot_uboot` が生成する Device Tree にすべて宣言される。返したハンドルで
+    /// 実行時にデバイスを bind する。スロットを追加しない場合、Device Tree には
+    /// `virtio_base` のトランスポートが 1 つだけ宣言される (従来の動作)。
+    ///
+    /// # Errors
+    /// スロットの割り込みが SPI の範囲を超えるか、UART と重なる場合、
+    /// スロットがゲストのメモリと重なる場合はエラーを返す
+    pub fn add_virtio_slots(
+        &mut self,
+        count: u32,
+    ) -> Result<Vec<devices::virtio::VirtioSlotHandle>, Box<dyn std::error::Error>> {
+        let first = self.virtio_slots.len() as u32;
+        self.irqs.validate_virtio_slots(first + count)?;
+        let layout = MachineLayout::default();
+        let mut handles = Vec::new();
+        for index in first..first + count {
+            let (mut slot, handle) = devices::virtio::VirtioMmioSlot::new(
+                index,
+                layout.virtio_slot_base(index),
+                self.irqs.virtio_slot(index),
+            );
+            self.check_mmio_placement(&slot)?;
+            mmio::MmioHandler::attach_dma(
+                &mut slot,
+                devices::virtio::DmaValidator::new(self.guest_memory()),
+            );
+            self.mmio_manager.register(Box::new(slot));
+            self.virtio_slots.push(handle.clone());
+            handles.push(handle);
+        }
+        Ok(handles)
+    }
+
+    /// SCMI ファームウェアを登録する
+    ///
+    /// 共有メモリを MMIO ハンドラとして登録し、`SCMI_SMC_ID` の HVC を
+    /// doorbell として扱う。`boot_linux` / `boot_uboot` が生成する Device Tree に
+    /// SCMI と PSCI のノードが追加される。
+    ///
+    /// # Errors
+    /// 共有メモリがゲストのメモリと重なる場合はエラーを返す
+    pub fn attach_scmi(
+        &mut self,
+        scmi: devices::scmi::ScmiDevice,
+    ) -> Result<devices::scmi::ScmiDoorbell, Box<dyn std::error::Error>> {
+        self.check_mmio_placement(&scmi)?;
+        let doorbell = scmi.doorbell();
+        self.mmio_manager.register(Box::new(scmi));
+        self.scmi = Some(doorbell.clone());
+        Ok(doorbell)
+    }
+
+    /// PL330 DMA コントローラのスタブを登録する
+    ///
+    /// `boot_linux` / `boot_uboot` が生成する Device Tree に `arm,pl330` のノードが
+    /// 追加される。転送は行わない ([`devices::pl330`])。
+    ///
+    /// # Errors
+    /// レジスタの範囲がゲストのメモリと重なる場合はエラーを返す
+    pub fn attach_pl330(
+        &mut self,
+        dma: devices::pl330::Pl330Stub,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_mmio_placement(&dma)?;
+        self.pl330_base = Some(mmio::MmioHandler::base(&dma));
+        self.mmio_manager.register(Box::new(dma));
+        Ok(())
+    }
+
+    /// SMMUv3 のスタブを登録する
+    ///
+    /// `boot_linux` / `boot_uboot` が生成する Device Tree に `arm,smmu-v3` のノードが
+    /// 追加される。アドレス変換は行わず、デバイスもその配下に置かない
+    /// ([`devices::smmu`])。
+    ///
+    /// # Errors
+    /// レジスタの範囲がゲストのメモリと重なる場合はエラーを返す
+    pub fn attach_smmu(
+        &mut self,
+        smmu: devices::smmu::SmmuV3Stub,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_mmio_placement(&smmu)?;
+        self.smmu_base = Some(mmio::MmioHandler::base(&smmu));
+        self.mmio_manager.register(Box::new(smmu));
+        Ok(())
+    }
+
+    /// `index` 番目の virtio-mmio スロット
+    pub fn virtio_slot(&self, index: u32) -> Option<devices::virtio::VirtioSlotHandle> {
+        self.virtio_slots.get(index as usize).cloned()
+    }
+
+    /// デバイスが挿さっていない最初の virtio-mmio スロット
+    pub fn free_virtio_slot(&self) -> Option<devices::virtio::VirtioSlotHandle> {
+        self.virtio_slots
+            .iter()
+            .find(|slot| !slot.is_bound())
+            .cloned()
+    }
+
+    /// 書き込みをまとめる MMIO 範囲を登録する
+    ///
+    /// 範囲内への書き込みはリングに記録するだけで VM Exit の処理を終え、
+    /// デバイスの読み取り・範囲外への書き込み・WFI・`run()` の終了時に
+    /// まとめてデバイスに渡す。詳細は [`mmio::MmioManager::register_coalesced`]。
+    ///
+    /// # Arguments
+    /// * `base` - 範囲のベースアドレス
+    /// * `size` - 範囲のサイズ
+    pub fn register_coalesced_mmio(&mut self, base: u64, size: u64) {
+        self.mmio_manager.register_coalesced(base, size);
+    }
+
+    /// 高速パス付きで MMIO デバイスハンドラを登録する
+    ///
+    /// `fast_write_offsets` のレジスタへの書き込みは、Data Abort の
+    /// ISS 解析とデバイスの範囲検索を省略して直接ハンドラに渡す。
+    /// 起動中に最も多い VM Exit である UART_DR への書き込み向け。
+    ///
+    /// # Arguments
+    /// * `handler` - 登録する MMIO ハンドラ
+    /// * `fast_write_offsets` - 高速パスで処理するレジスタのオフセット (例: UART_DR = 0x00)
+    ///
+    /// # Errors
+    /// ハンドラの範囲がゲストのメモリと重なる場合はエラーを返す
+    pub fn register_fast_mmio_handler(
+        &mut self,
+        handler: Box<dyn crate::mmio::MmioHandler>,
+        fast_write_offsets: &[u64],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_mmio_placement(handler.as_ref())?;
+        let mut handler = handler;
+        handler.attach_dma(devices::virtio::DmaValidator::new(self.guest_memory()));
+        self.mmio_manager.register_fast(handler, fast_write_offsets);
+        Ok(())
+    }
+
+    /// ゲストプログラムを実行する
+    ///
+    /// # Arguments
+    /// * `initial_cpsr` - 初期 CPSR 値 (デフォルト: 0x3c4 = EL1t)
+    /// * `trap_debug` - デバッグ例外をトラップするか (デフォルト: true)
+    /// * `initial_pc` - 初期 PC 値 (デフォルト: self.guest_addr)
+    ///
+    /// 汎用レジスタや例外レベルも指定する場合は [`Hypervisor::run_with`] を使う。
+    ///
+    /// # Returns
+    /// 実行結果 (HypervisorResult)
+    pub fn run(
+        &mut self,
+        initial_cpsr: Option<u64>,
+        trap_debug: Option<bool>,
+        initial_pc: Option<u64>,
+    ) -> Result<HypervisorResult, Box<dyn std::error::Error>> {
+        let mut options = RunOptions::new()
+            .cpsr(initial_cpsr.unwrap_or(0x3c4))
+            .trap_debug(trap_debug.unwrap_or(true));
+        if let Some(pc) = initial_pc {
+            options = options.pc(pc);
+        }
+        self.run_with(&options)
+    }
+
+    /// 初期レジスタを指定してゲストプログラムを実行する
+    ///
+    /// [`RunOptions`] で指定したレジスタを設定してから実行する。
+    /// 指定しなかったレジスタは現在の値のまま (PC はゲストの先頭アドレス)。
+    ///
+    /// # Errors
+    /// [`RunOptions::validate`] に失敗した場合はゲストを実行せずにエラーを返す
+    pub fn run_with(
+        &mut self,
+        options: &RunOptions,
+    ) -> Result<HypervisorResult, Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        self.validate_irqs()?;
+        options.validate()?;
+
+        for &(index, value) in options.gprs() {
+            self.vcpu.set_reg(REGISTER_TABLE[index], value)?;
+        }
+        if let Some(sp) = options.initial_sp() {
+            let reg = if options.uses_sp_el0() {
+                applevisor::SysReg::SP_EL0
+            } else {
+                applevisor::SysReg::SP_EL1
+            };
+            self.vcpu.set_sys_reg(reg, sp)?;
+        }
+        if let Some(vbar) = options.initial_vbar() {
+            self.vcpu.set_sys_reg(applevisor::SysReg::VBAR_EL1, vbar)?;
+        }
+        if let Some(addr) = options.vector_shim_addr() {
+            self.load_blob("vector-shim", addr, &boot::vectors::shim_table())?;
+            self.vcpu.set_sys_reg(applevisor::SysReg::VBAR_EL1, addr)?;
+        }
+        if let Some(sctlr) = options.initial_sctlr() {
+            self.vcpu
+                .set_sys_reg(applevisor::SysReg::SCTLR_EL1, sctlr)?;
+        }
+
+        // PC と CPSR を設定
+        let pc = options.initial_pc().unwrap_or(self.guest_addr);
+        self.vcpu.set_reg(Reg::PC, pc)?;
+        self.vcpu.set_reg(Reg::CPSR, options.initial_cpsr())?;
+
+        // デバッグ例外のトラップを設定
+        if options.traps_debug() {
+            self.vcpu.set_trap_debug_exceptions(true)?;
+        }
+        self.brk_policy = options.brk_handling();
+        self.log_brk = options.logs_brk();
+
+        // ゲストプログラムを実行
+        let result = self.run_loop();
+        if result.is_err() {
+            self.exit_history.dump();
+        }
+        // まとめていた MMIO 書き込みを VM Exit 前に反映する
+        self.mmio_manager.drain_coalesced()?;
+        // 出力を抑えていた警告の件数を知らせる
+        self.mmio_manager.flush_warnings();
+        result
+    }
+
+    /// VM Exit を処理しながらゲストを実行する
+    fn run_loop(&mut self) -> Result<HypervisorResult, Box<dyn std::error::Error>> {
+        // 直前の VM Exit (処理開始時刻, イベント名)
+        let mut pending_exit: Option<(Instant, &'static str)> = None;
+        loop {
+            if let (Some(tracer), Some((start, name))) = (&self.tracer, pending_exit.take()) {
+                tracer.complete(Track::Vcpu(0), "exit", name, start);
+            }
+
+            self.check_host_sleep()?;
+
+            // ゲストの外にいる間に届いた停止要求
+            if self.vcpu_handle.take_stop_request() {
+                return self.canceled_result();
+            }
+            // 直前の VM Exit でデバイスが要求した終了
+            if let Some(exit) = self.device_exits.take() {
+                return Ok(HypervisorResult {
+                    stop_reason: StopReason::DeviceExit(exit),
+                    ..self.canceled_result()?
+                });
+            }
+
+            self.serve_control();
+            if self.paused && self.wait_while_paused()? {
+                return self.canceled_result();
+            }
+
+            // タイマー IRQ をポーリング
+            let had_pending_before = self.irq_chip.has_pending_irq();
+            self.irq_chip.poll_timer_irqs();
+            self.inject_device_irqs();
+            let has_pending_after = self.irq_chip.has_pending_irq();
+
+            if !had_pending_before && has_pending_after {
+                self.exit_stats.log_timer_pending();
+            }
+
+            // FIQ をクリアし、IRQ 状態を更新
+            self.vcpu.set_pending_interrupt(InterruptType::FIQ, false)?;
+            self.vcpu
+                .set_pending_interrupt(InterruptType::IRQ, self.irq_chip.has_pending_irq())?;
+
+            // ゲストのタイマー設定を読み取りソフトウェアタイマーに同期
+            let guest_ctl = self
+                .vcpu
+                .get_sys_reg(applevisor::SysReg::CNTV_CTL_EL0)
+                .unwrap_or(0);
+            let guest_cval = self
+                .vcpu
+                .get_sys_reg(applevisor::SysReg::CNTV_CVAL_EL0)
+                .unwrap_or(i64::MAX as u64);
+
+            let virt_counter = self.irq_chip.timer().get_virt_counter();
+            self.irq_chip.sync_virt_timer(guest_ctl, guest_cval);
+
+            self.exit_stats
+                .log_timer_sync(guest_ctl, guest_cval, virt_counter);
+
+            // FIQ 防止: ハードウェアタイマーを無効化して vcpu.run() を実行
+            self.vcpu
+                .set_sys_reg(applevisor::SysReg::CNTV_CTL_EL0, 0x2)?;
+            self.vcpu
+                .set_sys_reg(applevisor::SysReg::CNTV_CVAL_EL0, i64::MAX as u64)?;
+            self.vcpu.set_pending_interrupt(InterruptType::FIQ, false)?;
+
+            let run_start = Instant::now();
+            self.vcpu.run()?;
+            // 書き込みを 1 回通すために外した書き込み禁止を戻す
+            for page in self.watches.take_rearm() {
+                self.mem.set_writable(page, applevisor::PAGE_SIZE, false)?;
+            }
+            if let Some(tracer) = &self.tracer {
+                tracer.complete(Track::Vcpu(0), "guest", "run", run_start);
+            }
+            let exit_start = Instant::now();
+
+            // ゲストが設定したタイマー値を再読み取り
+            let post_run_ctl = self
+                .vcpu
+                .get_sys_reg(applevisor::SysReg::CNTV_CTL_EL0)
+                .unwrap_or(0);
+            let post_run_cval = self
+                .vcpu
+                .get_sys_reg(applevisor::SysReg::CNTV_CVAL_EL0)
+                .unwrap_or(i64::MAX as u64);
+
+            let hw_counter = self.vcpu.hardware_counter();
+
+            // タイマー発火条件をチェックし GIC 経由で IRQ を注入
+            // (レベルトリガなので、止めたタイマーの割り込みは取り下げる)
+            let line = self
+                .irq_chip
+                .update_virt_timer(post_run_ctl, post_run_cval, hw_counter);
+            if line == TimerLine::Fired {
+                self.exit_stats.log_sw_timer_fire(hw_counter, post_run_cval);
+                self.trace_irq_injection(self.irqs.virt_timer);
+            }
+
+            let exit_info = self.vcpu.get_exit_info();
+            pending_exit = Some((exit_start, exit_event_name(&exit_info)));
+
+            // IRQ 状態を更新
+            self.vcpu
+                .set_pending_interrupt(InterruptType::IRQ, self.irq_chip.has_pending_irq())?;
+
+            // exit reason を記録
+            self.exit_stats.exit_count += 1;
+            // WFI と HVC (PSCI CPU_SUSPEND) 以外の Exit はゲストが動いている
+            let mut idle_exit = false;
             match exit_info.reason {
                 applevisor::ExitReason::EXCEPTION => {
                     let ec = (exit_info.exception.syndrome >> 26) & 0x3f;
+                    idle_exit = ec == 0x01 || ec == 0x16;
                     match ec {
-                        0x01 => {
-                            self.debug_stats.wfi_count += 1;
-                            let wfi_count = self.debug_stats.wfi_count;
-                            let exit_count = self.debug_stats.exit_count;
-                            if wfi_count <= 5 || wfi_count.is_multiple_of(10000) {
-                                eprintln!("[WFI #{}] at exit #{}", wfi_count, exit_count);
-                            }
-                        }
-                        0x24 => self.debug_stats.mmio_count += 1,
-                        _ => self.debug_stats.other_exception_count += 1,
+                        0x01 => self.exit_stats.log_wfi(),
+                        0x24 => self.exit_stats.mmio_count += 1,
+                        _ => self.exit_stats.other_exception_count += 1,
                     }
                 }
                 applevisor::ExitReason::VTIMER_ACTIVATED => {
-                    eprintln!("[EXIT #{}] VTIMER_ACTIVATED!", self.debug_stats.exit_count);
+                    eprintln!("[EXIT #{}] VTIMER_ACTIVATED!", self.exit_stats.exit_count);
                 }
                 _ => {}
             }
+            if !idle_exit {
+                self.idle.wake();
+            }
 
             // 定期的にサマリーを出力
-            if self.debug_stats.exit_count.is_multiple_of(5000) {
-                let gic_pending = self.interrupt_controller.has_pending_irq();
-                self.debug_stats.log_exit_summary(
+            if self.exit_stats.exit_count.is_multiple_of(5000) {
+                let gic_pending = self.irq_chip.has_pending_irq();
+                self.exit_stats.log_exit_summary(
                     post_run_ctl,
                     post_run_cval,
                     hw_counter,
@@ -484,42 +2233,21 @@ impl Hypervisor {
             }
 
             // 汎用レジスタを取得
-            let registers = [
-                self.vcpu.get_reg(Reg::X0)?,
-                self.vcpu.get_reg(Reg::X1)?,
-                self.vcpu.get_reg(Reg::X2)?,
-                self.vcpu.get_reg(Reg::X3)?,
-                self.vcpu.get_reg(Reg::X4)?,
-                self.vcpu.get_reg(Reg::X5)?,
-                self.vcpu.get_reg(Reg::X6)?,
-                self.vcpu.get_reg(Reg::X7)?,
-                self.vcpu.get_reg(Reg::X8)?,
-                self.vcpu.get_reg(Reg::X9)?,
-                self.vcpu.get_reg(Reg::X10)?,
-                self.vcpu.get_reg(Reg::X11)?,
-                self.vcpu.get_reg(Reg::X12)?,
-                self.vcpu.get_reg(Reg::X13)?,
-                self.vcpu.get_reg(Reg::X14)?,
-                self.vcpu.get_reg(Reg::X15)?,
-                self.vcpu.get_reg(Reg::X16)?,
-                self.vcpu.get_reg(Reg::X17)?,
-                self.vcpu.get_reg(Reg::X18)?,
-                self.vcpu.get_reg(Reg::X19)?,
-                self.vcpu.get_reg(Reg::X20)?,
-                self.vcpu.get_reg(Reg::X21)?,
-                self.vcpu.get_reg(Reg::X22)?,
-                self.vcpu.get_reg(Reg::X23)?,
-                self.vcpu.get_reg(Reg::X24)?,
-                self.vcpu.get_reg(Reg::X25)?,
-                self.vcpu.get_reg(Reg::X26)?,
-                self.vcpu.get_reg(Reg::X27)?,
-                self.vcpu.get_reg(Reg::X28)?,
-                self.vcpu.get_reg(Reg::X29)?,
-                self.vcpu.get_reg(Reg::X30)?,
-            ];
+            let registers = self.vcpu.read_gprs()?;
 
             let pc = self.vcpu.get_reg(Reg::PC)?;
 
+            let is_exception = exit_info.reason == applevisor::ExitReason::EXCEPTION;
+            let is_data_abort = is_exception && (exit_info.exception.syndrome >> 26) & 0x3f == 0x24;
+            self.exit_history.record(
+                self.exit_stats.exit_count,
+                pc,
+                exit_event_name(&exit_info),
+                is_exception.then_some(exit_info.exception.syndrome),
+                is_data_abort.then_some(exit_info.exception.physical_address),
+                &registers,
+            );
+
             // 例外処理
             if let applevisor::ExitReason::EXCEPTION = exit_info.reason {
                 let syndrome = exit_info.exception.syndrome;
@@ -534,6 +2262,8 @@ impl Hypervisor {
                                 registers,
                                 exit_reason: exit_info.reason,
                                 exception_syndrome: Some(syndrome),
+                                el1: None,
+                                stop_reason: StopReason::Exit,
                             });
                         }
                     }
@@ -545,6 +2275,8 @@ impl Hypervisor {
                                 registers,
                                 exit_reason: exit_info.reason,
                                 exception_syndrome: Some(syndrome),
+                                el1: None,
+                                stop_reason: StopReason::Exit,
                             });
                         }
                     }
@@ -556,6 +2288,47 @@ impl Hypervisor {
                                 registers,
                                 exit_reason: exit_info.reason,
                                 exception_syndrome: Some(syndrome),
+                                el1: None,
+                                stop_reason: StopReason::Exit,
+                            });
+                        }
+                    }
+                    0x03 | 0x05 => {
+                        // MCR/MRC (AArch32 CP15 / CP14)
+                        if !self.handle_coproc_access(syndrome, ec == 0x03)? {
+                            return Ok(HypervisorResult {
+                                pc,
+                                registers,
+                                exit_reason: exit_info.reason,
+                                exception_syndrome: Some(syndrome),
+                                el1: None,
+                                stop_reason: StopReason::Exit,
+                            });
+                        }
+                    }
+                    0x04 | 0x0c => {
+                        // MCRR/MRRC (AArch32 CP15 / CP14)
+                        if !self.handle_coproc_access64(syndrome, ec == 0x04)? {
+                            return Ok(HypervisorResult {
+                                pc,
+                                registers,
+                                exit_reason: exit_info.reason,
+                                exception_syndrome: Some(syndrome),
+                                el1: None,
+                                stop_reason: StopReason::Exit,
+                            });
+                        }
+                    }
+                    0x12 => {
+                        // HVC (AArch32) - PSCI
+                        if !self.handle_hvc(syndrome)? {
+                            return Ok(HypervisorResult {
+                                pc,
+                                registers,
+                                exit_reason: exit_info.reason,
+                                exception_syndrome: Some(syndrome),
+                                el1: None,
+                                stop_reason: StopReason::Exit,
                             });
                         }
                     }
@@ -569,16 +2342,26 @@ impl Hypervisor {
                                 registers,
                                 exit_reason: exit_info.reason,
                                 exception_syndrome: Some(syndrome),
+                                el1: None,
+                                stop_reason: StopReason::Exit,
                             });
                         }
                     }
-                    0x3c => {
-                        // BRK instruction (AArch64)
+                    0x38 | 0x3c => {
+                        // BKPT instruction (AArch32) / BRK instruction (AArch64)
+                        // 例外ベクタのシムの BRK ならゲストが受けた例外の情報を付ける
+                        let el1 = match ShimVector::from_syndrome(syndrome) {
+                            Some(_) => El1Context::capture(&**self.vcpu).ok(),
+                            None if self.handle_brk(syndrome, pc)? => continue,
+                            None => None,
+                        };
                         return Ok(HypervisorResult {
                             pc,
                             registers,
                             exit_reason: exit_info.reason,
                             exception_syndrome: Some(syndrome),
+                            el1,
+                            stop_reason: StopReason::Exit,
                         });
                     }
                     _ => {
@@ -588,27 +2371,62 @@ impl Hypervisor {
                         //     "Unknown exception: EC=0x{:x}, syndrome=0x{:x}",
                         //     ec, syndrome
                         // );
+                        self.exit_history.dump();
                         return Ok(HypervisorResult {
                             pc,
                             registers,
                             exit_reason: exit_info.reason,
                             exception_syndrome: Some(syndrome),
+                            el1: El1Context::capture(&**self.vcpu).ok(),
+                            stop_reason: StopReason::Exit,
                         });
                     }
                 }
-            } else if let applevisor::ExitReason::VTIMER_ACTIVATED = exit_info.reason {
-                // 仮想タイマーがアクティブになった - GIC 経由で IRQ を注入
-                self.debug_stats.log_vtimer_activated();
-                self.interrupt_controller.poll_timer_irqs();
 
-                {
-                    let mut gic = self.interrupt_controller.gic.lock().unwrap();
-                    gic.set_irq_pending(devices::timer::VIRT_TIMER_IRQ);
+                // ハンドラが PC を進めた VM Exit は、同じ命令の繰り返しでも
+                // MMIO のポーリングのような正常なループなので数えない
+                if idle_exit || self.vcpu.get_reg(Reg::PC)? != pc {
+                    self.livelock.reset();
+                } else if let Some(livelock) = self.livelock.observe(
+                    pc,
+                    syndrome,
+                    is_data_abort.then_some(exit_info.exception.physical_address),
+                    &registers,
+                ) {
+                    eprintln!("[LIVELOCK] {}", livelock);
+                    self.exit_history.dump();
+                    self.livelock.reset();
+                    return Ok(HypervisorResult {
+                        pc,
+                        registers,
+                        exit_reason: exit_info.reason,
+                        exception_syndrome: Some(syndrome),
+                        el1: El1Context::capture(&**self.vcpu).ok(),
+                        stop_reason: StopReason::Livelock(livelock),
+                    });
                 }
+            } else if let applevisor::ExitReason::VTIMER_ACTIVATED = exit_info.reason {
+                // 仮想タイマーがアクティブになった - GIC 経由で IRQ を注入
+                self.exit_stats.log_vtimer_activated();
+                self.irq_chip.poll_timer_irqs();
+                self.irq_chip.raise_virt_timer(post_run_cval);
+                self.trace_irq_injection(self.irqs.virt_timer);
 
-                if self.interrupt_controller.has_pending_irq() {
+                if self.irq_chip.has_pending_irq() {
                     self.vcpu.set_pending_interrupt(InterruptType::IRQ, true)?;
                 }
+            } else if let applevisor::ExitReason::CANCELED = exit_info.reason {
+                // VcpuHandle::kick: 停止要求がなければ割り込みを確認して再開する
+                if self.vcpu_handle.take_stop_request() {
+                    return Ok(HypervisorResult {
+                        pc,
+                        registers,
+                        exit_reason: exit_info.reason,
+                        exception_syndrome: None,
+                        el1: None,
+                        stop_reason: StopReason::Exit,
+                    });
+                }
             } else {
                 // 予期しない VM Exit
                 return Ok(HypervisorResult {
@@ -616,76 +2434,333 @@ impl Hypervisor {
                     registers,
                     exit_reason: exit_info.reason,
                     exception_syndrome: None,
+                    el1: El1Context::capture(&**self.vcpu).ok(),
+                    stop_reason: StopReason::Exit,
                 });
             }
         }
     }
 
-    /// Data Abort 例外を処理する
+    /// 停止要求で run ループを抜けるときの結果
+    fn canceled_result(&self) -> Result<HypervisorResult, Box<dyn std::error::Error>> {
+        Ok(HypervisorResult {
+            pc: self.vcpu.get_reg(Reg::PC)?,
+            registers: self.vcpu.read_gprs()?,
+            exit_reason: applevisor::ExitReason::CANCELED,
+            exception_syndrome: None,
+            el1: None,
+            stop_reason: StopReason::Exit,
+        })
+    }
+
+    /// ホストのスリープを検出し、ポリシーに従ってゲストのカウンタを補正する
+    fn check_host_sleep(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(duration) = self.sleep_detector.poll() else {
+            return Ok(());
+        };
+        let mut skipped = 0;
+        if self.time_policy == GuestTimePolicy::JumpForward {
+            let timer = self.irq_chip.timer_mut();
+            skipped = host_sleep::guest_ticks(duration, timer.get_frequency());
+            // offset を減らすとゲストのカウンタ (ホスト - offset) が進む
+            let offset = self.vcpu.get_vtimer_offset()?;
+            self.vcpu.set_vtimer_offset(offset.wrapping_sub(skipped))?;
+            let virt_offset = timer.get_virt_offset();
+            timer.set_virt_offset(virt_offset.wrapping_sub(skipped));
+        }
+        eprintln!(
+            "[TIMER] Host slept for {:?}; guest counter advanced by {} ticks ({:?})",
+            duration, skipped, self.time_policy
+        );
+        if let Some(tracer) = &self.tracer {
+            tracer.instant(Track::Vcpu(0), "host", "host-sleep");
+        }
+        self.host_sleeps.push(HostSleep {
+            duration,
+            guest_ticks_skipped: skipped,
+        });
+        Ok(())
+    }
+
+    /// MMIO デバイスがアサートしている割り込みを GIC に設定する
+    ///
+    /// 割り込み線が下がらないまま再ペンディングが続く割り込みはストームとして
+    /// 報告し、注入を絞る (詳細は [`irq_storm`])。
+    fn inject_device_irqs(&mut self) {
+        let mut storms = Vec::new();
+        let mut gic = self.irq_chip.gic().lock().unwrap();
+        self.irq_guard.begin_poll();
+        for irq in self.mmio_manager.pending_irqs() {
+            match self.irq_guard.admit(irq, gic.is_irq_pending(irq)) {
+                Admission::Inject => gic.set_irq_pending(irq),
+                Admission::Throttled => {}
+                Admission::Storm(repends) => {
+                    gic.set_irq_pending(irq);
+                    storms.push((irq, repends));
+                }
+            }
+        }
+        self.irq_guard.end_poll();
+        drop(gic);
+
+        for (irq, repends) in storms {
+            self.report_irq_storm(irq, repends);
+        }
+    }
+
+    /// 割り込みストームを記録する
+    fn report_irq_storm(&mut self, irq: u32, repends: u64) {
+        let device = self
+            .mmio_manager
+            .devices()
+            .find(|d| d.irq() == Some(irq))
+            .map(|d| d.name().to_string());
+        eprintln!(
+            "[IRQ] Interrupt storm on IRQ {} ({}): re-pended {} times without the line dropping; \
+             throttling injection to once per {:?}",
+            irq,
+            device.as_deref().unwrap_or("unknown device"),
+            repends,
+            irq_storm::THROTTLE_INTERVAL
+        );
+        if let Some(tracer) = &self.tracer {
+            tracer.instant(Track::Vcpu(0), "irq", format!("IRQ {} storm", irq));
+        }
+        self.irq_storms.push(IrqStorm {
+            irq,
+            device,
+            repends,
+        });
+    }
+
+    /// IRQ 注入をトレースに記録する
+    fn trace_irq_injection(&self, irq: u32) {
+        if let Some(tracer) = &self.tracer {
+            tracer.instant(Track::Vcpu(0), "irq", format!("inject IRQ {}", irq));
+        }
+    }
+
+    /// Data Abort 例外を処理する
+    ///
+    /// ISS (Instruction Specific Syndrome) フィールドの構造:
+    /// - [24]: ISV - Instruction Syndrome Valid
+    /// - [23:22]: SAS - Syndrome Access Size (0=byte, 1=halfword, 2=word, 3=doubleword)
+    /// - [21]: SSE - Syndrome Sign Extend
+    /// - [20:16]: SRT - Syndrome Register Transfer (転送元/先レジスタ番号)
+    ///   AArch32 からの例外でも AArch64 側のレジスタ番号で報告される
+    /// - [15]: SF - Sixty-Four bit register (0 の場合は 32-bit レジスタ)
+    /// - [9]: FnV - FAR not Valid
+    /// - [6]: WnR - Write not Read
+    ///
+    /// # Arguments
+    /// * `syndrome` - ESR_EL2 の値
+    /// * `fault_ipa` - フォールトした IPA (Intermediate Physical Address)
+    ///
+    /// # Returns
+    /// 続行する場合は true、VM Exit する場合は false
+    fn handle_data_abort(
+        &mut self,
+        syndrome: u64,
+        fault_ipa: u64,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let iss = syndrome & 0x1FF_FFFF; // ISS は下位 25 ビット
+
+        // 書き込みを監視している RAM のページ
+        if iss & (1 << 6) != 0 && self.watches.is_watched_page(fault_ipa) {
+            return self.handle_watched_write(syndrome, fault_ipa);
+        }
+
+        // 高速パス: 登録済みレジスタへの書き込み (ISV=1, WnR=1) は
+        // 符号拡張やレジスタ幅の解析をせずにそのまま転送する
+        if iss & (1 << 24) != 0 && iss & (1 << 6) != 0 && self.mmio_manager.is_fast_write(fault_ipa)
+        {
+            let srt = ((iss >> 16) & 0x1F) as u8;
+            let size = 1 << ((iss >> 22) & 0x3);
+            let value = self.get_register_by_index(srt)?;
+            self.mmio_manager.fast_write(fault_ipa, value, size)?;
+            self.advance_pc(syndrome)?;
+            return Ok(true);
+        }
+
+        // ISV (Instruction Syndrome Valid) ビット [24]
+        let isv = (iss >> 24) & 0x1;
+
+        // WnR ビット [6]: 0 = read, 1 = write
+        let is_write = (iss & (1 << 6)) != 0;
+
+        // SAS (Syndrome Access Size) ビット [23:22]
+        // 0b00 = byte, 0b01 = halfword, 0b10 = word, 0b11 = doubleword
+        let sas = (iss >> 22) & 0x3;
+        let size = 1 << sas; // 1, 2, 4, 8 bytes
+
+        // SRT (Syndrome Register Transfer) ビット [20:16]
+        // 転送元/先レジスタ番号 (0-30 = X0-X30, 31 = XZR)
+        let srt = if isv != 0 {
+            ((iss >> 16) & 0x1F) as u8
+        } else {
+            // ISV が無効の場合、X0 をデフォルトとして使用
+            // (LDP/STP や書き戻し付きのアクセスは ISV=0 になる。`selftest` で確認できる)
+            0
+        };
+
+        // SSE ビット [21]: 読み取り値を符号拡張する
+        let sign_extend = (iss & (1 << 21)) != 0;
+
+        // SF ビット [15]: 0 = 32-bit レジスタ (Wn または AArch32 の Rn)
+        let reg_is_64bit = (iss & (1 << 15)) != 0;
+
+        // fault_ipa は Hypervisor.framework が提供する IPA
+        let fault_addr = fault_ipa;
+
+        // MMIO ハンドリング
+        if is_write {
+            // 書き込み: SRT で指定されたレジスタから値を取得
+            let value = self.get_register_by_index(srt)?;
+            self.mmio_manager.handle_write(fault_addr, value, size)?;
+        } else {
+            // 読み取り: MMIO デバイスから値を読み取って SRT レジスタに設定
+            let mut value = self.mmio_manager.handle_read(fault_addr, size)?;
+            if sign_extend && size < 8 {
+                let shift = 64 - size as u32 * 8;
+                value = (((value << shift) as i64) >> shift) as u64;
+            }
+            if isv != 0 && !reg_is_64bit {
+                value &= 0xFFFF_FFFF;
+            }
+            self.set_register_by_index(srt, value)?;
+        }
+
+        // PC を進める (Thumb の 16-bit 命令では 2 バイト)
+        self.advance_pc(syndrome)?;
+
+        Ok(true) // 続行
+    }
+
+    /// AArch32 の MCR/MRC 例外を処理する
+    ///
+    /// CP15 の Generic Timer レジスタは Timer エミュレーションに転送し、
+    /// それ以外の CP15 / CP14 レジスタは RAZ/WI として扱う。
+    ///
+    /// # Arguments
+    /// * `syndrome` - ESR_EL2 の値
+    /// * `is_cp15` - CP15 (EC=0x03) なら true、CP14 (EC=0x05) なら false
+    ///
+    /// # Returns
+    /// 続行する場合は true、VM Exit する場合は false
+    fn handle_coproc_access(
+        &mut self,
+        syndrome: u64,
+        is_cp15: bool,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let access = aarch32::CoprocAccess::decode(syndrome);
+        let timer_reg = if is_cp15 {
+            TimerReg::from_cp15(access.opc1, access.crn, access.crm, access.opc2)
+        } else {
+            None
+        };
+
+        match timer_reg {
+            Some(reg) if access.is_read => {
+                let value = self.irq_chip.timer().read_sysreg(reg)?;
+                self.set_register_by_index(access.rt, value & 0xFFFF_FFFF)?;
+            }
+            Some(reg) => {
+                let value = self.get_register_by_index(access.rt)? & 0xFFFF_FFFF;
+                // TVAL は符号付き 32-bit
+                let value = match reg {
+                    TimerReg::CNTP_TVAL_EL0 | TimerReg::CNTV_TVAL_EL0 => {
+                        value as u32 as i32 as i64 as u64
+                    }
+                    _ => value,
+                };
+                self.irq_chip.timer_mut().write_sysreg(reg, value)?;
+            }
+            None if access.is_read => self.set_register_by_index(access.rt, 0)?,
+            None => {}
+        }
+
+        self.advance_pc(syndrome)?;
+
+        Ok(true) // 続行
+    }
+
+    /// AArch32 の MCRR/MRRC 例外を処理する
     ///
-    /// ISS (Instruction Specific Syndrome) フィールドの構造:
-    /// - [24]: ISV - Instruction Syndrome Valid
-    /// - [23:22]: SAS - Syndrome Access Size (0=byte, 1=halfword, 2=word, 3=doubleword)
-    /// - [21]: SSE - Syndrome Sign Extend
-    /// - [20:16]: SRT - Syndrome Register Transfer (転送元/先レジスタ番号)
-    /// - [15]: SF - Sixty-Four bit register
-    /// - [9]: FnV - FAR not Valid
-    /// - [6]: WnR - Write not Read
+    /// 64-bit 値は Rt (下位 32 bit) と Rt2 (上位 32 bit) に分割して転送する。
     ///
     /// # Arguments
     /// * `syndrome` - ESR_EL2 の値
-    /// * `fault_ipa` - フォールトした IPA (Intermediate Physical Address)
+    /// * `is_cp15` - CP15 (EC=0x04) なら true、CP14 (EC=0x0C) なら false
     ///
     /// # Returns
     /// 続行する場合は true、VM Exit する場合は false
-    fn handle_data_abort(
+    fn handle_coproc_access64(
         &mut self,
         syndrome: u64,
-        fault_ipa: u64,
+        is_cp15: bool,
     ) -> Result<bool, Box<dyn std::error::Error>> {
-        let iss = syndrome & 0x1FF_FFFF; // ISS は下位 25 ビット
-
-        // ISV (Instruction Syndrome Valid) ビット [24]
-        let isv = (iss >> 24) & 0x1;
-
-        // WnR ビット [6]: 0 = read, 1 = write
-        let is_write = (iss & (1 << 6)) != 0;
-
-        // SAS (Syndrome Access Size) ビット [23:22]
-        // 0b00 = byte, 0b01 = halfword, 0b10 = word, 0b11 = doubleword
-        let sas = (iss >> 22) & 0x3;
-        let size = 1 << sas; // 1, 2, 4, 8 bytes
-
-        // SRT (Syndrome Register Transfer) ビット [20:16]
-        // 転送元/先レジスタ番号 (0-30 = X0-X30, 31 = XZR)
-        let srt = if isv != 0 {
-            ((iss >> 16) & 0x1F) as u8
+        let access = aarch32::CoprocAccess64::decode(syndrome);
+        let timer_reg = if is_cp15 {
+            TimerReg::from_cp15_64(access.opc1, access.crm)
         } else {
-            // ISV が無効の場合、X0 をデフォルトとして使用
-            0
+            None
         };
 
-        // fault_ipa は Hypervisor.framework が提供する IPA
-        let fault_addr = fault_ipa;
-
-        // MMIO ハンドリング
-        if is_write {
-            // 書き込み: SRT で指定されたレジスタから値を取得
-            let value = self.get_register_by_index(srt)?;
-            self.mmio_manager.handle_write(fault_addr, value, size)?;
-        } else {
-            // 読み取り: MMIO デバイスから値を読み取って SRT レジスタに設定
-            let value = self.mmio_manager.handle_read(fault_addr, size)?;
-            self.set_register_by_index(srt, value)?;
+        if access.is_read {
+            let value = match timer_reg {
+                Some(reg) => self.irq_chip.timer().read_sysreg(reg)?,
+                None => 0,
+            };
+            self.set_register_by_index(access.rt, value & 0xFFFF_FFFF)?;
+            self.set_register_by_index(access.rt2, value >> 32)?;
+        } else if let Some(reg) = timer_reg {
+            let low = self.get_register_by_index(access.rt)? & 0xFFFF_FFFF;
+            let high = self.get_register_by_index(access.rt2)? & 0xFFFF_FFFF;
+            self.irq_chip
+                .timer_mut()
+                .write_sysreg(reg, (high << 32) | low)?;
         }
 
-        // PC を進める
-        let pc = self.vcpu.get_reg(Reg::PC)?;
-        self.vcpu.set_reg(Reg::PC, pc + 4)?;
+        self.advance_pc(syndrome)?;
 
         Ok(true) // 続行
     }
 
+    /// 書き込み禁止にした RAM への書き込みを記録し、書き込みを完了させる
+    fn handle_watched_write(
+        &mut self,
+        syndrome: u64,
+        fault_ipa: u64,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let iss = syndrome & 0x1FF_FFFF;
+        let pc = self.vcpu.get_reg(Reg::PC)?;
+        if iss & (1 << 24) == 0 {
+            // 値とサイズが分からない: ページを書き込み可能にして命令を再実行させる
+            let page = self.watches.page_of(fault_ipa);
+            self.mem.set_writable(page, applevisor::PAGE_SIZE, true)?;
+            self.watches.defer_rearm(page);
+            self.watches.record(fault_ipa, None, None, pc);
+            return Ok(true);
+        }
+        let srt = ((iss >> 16) & 0x1F) as u8;
+        let size = 1usize << ((iss >> 22) & 0x3);
+        let value = self.get_register_by_index(srt)?;
+        self.mem.write(fault_ipa, &value.to_le_bytes()[..size])?;
+        self.watches.record(fault_ipa, Some(size), Some(value), pc);
+        self.advance_pc(syndrome)?;
+        Ok(true)
+    }
+
+    /// トラップした命令の長さだけ PC を進める
+    ///
+    /// ESR の IL ビット [25] が 0 なら 16-bit 命令 (Thumb)、1 なら 32-bit 命令。
+    fn advance_pc(&self, syndrome: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let len = if (syndrome >> 25) & 0x1 != 0 { 4 } else { 2 };
+        let pc = self.vcpu.get_reg(Reg::PC)?;
+        self.vcpu.set_reg(Reg::PC, pc + len)?;
+        Ok(())
+    }
+
     /// システムレジスタアクセス (MSR/MRS) 例外を処理する
     ///
     /// # Arguments
@@ -718,7 +2793,7 @@ impl Hypervisor {
         if let Some(timer_reg) = TimerReg::from_encoding(op0, op1, crn, crm, op2) {
             if direction == 0 {
                 // MRS (read): Timer レジスタの値を Rt に設定
-                let value = self.interrupt_controller.timer.read_sysreg(timer_reg)?;
+                let value = self.irq_chip.timer().read_sysreg(timer_reg)?;
                 if rt < 31 {
                     self.set_register_by_index(rt, value)?;
                 }
@@ -730,9 +2805,7 @@ impl Hypervisor {
                 } else {
                     0 // XZR
                 };
-                self.interrupt_controller
-                    .timer
-                    .write_sysreg(timer_reg, value)?;
+                self.irq_chip.timer_mut().write_sysreg(timer_reg, value)?;
             }
 
             // PC を進める
@@ -742,6 +2815,29 @@ impl Hypervisor {
             return Ok(true); // 続行
         }
 
+        // EL2 レジスタ (HCR_EL2, CNTHCTL_EL2 など) はシャドウレジスタで仮想化
+        #[cfg(feature = "nested")]
+        if let Some(el2_reg) = nested::El2Reg::from_encoding(op0, op1, crn, crm, op2) {
+            if direction == 0 {
+                let value = self.el2_regs.read(el2_reg);
+                if rt < 31 {
+                    self.set_register_by_index(rt, value)?;
+                }
+            } else {
+                let value = if rt < 31 {
+                    self.get_register_by_index(rt)?
+                } else {
+                    0 // XZR
+                };
+                self.el2_regs.write(el2_reg, value);
+            }
+
+            let pc = self.vcpu.get_reg(Reg::PC)?;
+            self.vcpu.set_reg(Reg::PC, pc + 4)?;
+
+            return Ok(true);
+        }
+
         // 未対応のシステムレジスタ
         // Linux カーネル起動のためにエミュレート
 
@@ -772,23 +2868,23 @@ impl Hypervisor {
     /// # Returns
     /// 続行する場合は true、VM Exit する場合は false
     fn handle_wfi_wfe(&mut self, _syndrome: u64) -> Result<bool, Box<dyn std::error::Error>> {
+        // ゲストがアイドルになったので、まとめていた MMIO 書き込みを反映する
+        self.mmio_manager.drain_coalesced()?;
+
         // タイマー IRQ をポーリング
-        self.interrupt_controller.poll_timer_irqs();
+        self.irq_chip.poll_timer_irqs();
 
         // ペンディング IRQ があれば即座に続行
-        if self.interrupt_controller.has_pending_irq() {
+        if self.irq_chip.has_pending_irq() {
+            self.idle.wake();
             // PC を進める（WFI/WFE 命令の次へ）
             let pc = self.vcpu.get_reg(Reg::PC)?;
             self.vcpu.set_reg(Reg::PC, pc + 4)?;
             return Ok(true);
         }
 
-        // WFI をスキップせずに再実行して、VTIMER_ACTIVATED を待つ
-        // ハードウェア vtimer の発火を検出するために、短いスリープ後に再実行
-        // (ソフトウェアタイマーは使用されていないため time_until_next_event() は None)
-
-        // CPU を過度に使用しないよう短いスリープを入れる
-        std::thread::sleep(std::time::Duration::from_micros(100));
+        // 次のタイマーの期限か、入力・停止要求などで起こされるまで待つ
+        self.wait_for_event()?;
 
         // PC を進めて次の命令へ
         // 注: Linux はタイマー割り込みがなければすぐに WFI を再実行する
@@ -809,6 +2905,29 @@ impl Hypervisor {
         // PSCI Function ID は X0 に格納される
         let function_id = self.vcpu.get_reg(Reg::X0)?;
 
+        // SCMI の doorbell (応答は共有メモリに書き戻される)
+        if let Some(scmi) = &self.scmi {
+            if function_id == devices::scmi::SCMI_SMC_ID as u64 {
+                scmi.ring();
+                self.vcpu.set_reg(Reg::X0, 0)?;
+                return Ok(true);
+            }
+        }
+
+        // テストコードからのイベント通知
+        if function_id == host_channel::HOST_CHANNEL_HVC_ID {
+            if let Some(channel) = &mut self.host_channel {
+                let gprs = self.vcpu.read_gprs()?;
+                let args = [gprs[1], gprs[2], gprs[3], gprs[4], gprs[5]];
+                let ret = channel.handle(args, &*self.mem);
+                if let Some(next) = channel.take_next_boot() {
+                    self.next_boot = Some(next);
+                }
+                self.vcpu.set_reg(Reg::X0, ret)?;
+                return Ok(true);
+            }
+        }
+
         // PSCI 戻り値（デフォルト: SUCCESS）
         let result = match function_id {
             // PSCI_VERSION (0x84000000)
@@ -820,9 +2939,13 @@ impl Hypervisor {
 
             // PSCI_CPU_SUSPEND (0xC4000001) - 64-bit
             // Args: X1=power_state, X2=entry_point, X3=context_id
-            // CPU をスリープ状態にする（簡易実装: 短いスリープ）
+            // CPU をスリープ状態にする（簡易実装: WFI と同じく割り込みまで待つ）
             0xC400_0001 => {
-                std::thread::sleep(std::time::Duration::from_micros(100));
+                if self.irq_chip.has_pending_irq() {
+                    self.idle.wake();
+                } else {
+                    self.wait_for_event()?;
+                }
                 0 // PSCI_SUCCESS
             }
 
@@ -895,6 +3018,59 @@ impl Hypervisor {
         Ok(true) // 続行
     }
 
+    /// [`BrkPolicy`] に従って BRK / BKPT を処理する
+    ///
+    /// # Returns
+    /// 実行を続ける場合は true、run ループを抜ける場合は false
+    fn handle_brk(&mut self, syndrome: u64, pc: u64) -> Result<bool, Box<dyn std::error::Error>> {
+        let policy = self.brk_policy;
+        if self.log_brk {
+            eprintln!(
+                "[BRK] #0x{:x} at 0x{:x}: {:?}",
+                syndrome & 0xffff,
+                pc,
+                policy
+            );
+        }
+        match policy {
+            BrkPolicy::Exit => Ok(false),
+            BrkPolicy::Skip => {
+                // IL=0 は 16 ビットの T32 BKPT
+                let len = if syndrome & (1 << 25) != 0 { 4 } else { 2 };
+                self.vcpu.set_reg(Reg::PC, pc + len)?;
+                Ok(true)
+            }
+            BrkPolicy::Forward => {
+                self.forward_sync_exception(syndrome, pc)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// トラップした同期例外をゲストの EL1 が受けたことにする
+    ///
+    /// ESR_EL1・ELR_EL1・SPSR_EL1 を設定し、EL1h (DAIF はマスク) で
+    /// VBAR_EL1 の同期例外のベクタへ分岐させる。
+    fn forward_sync_exception(
+        &mut self,
+        syndrome: u64,
+        pc: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let spsr = self.vcpu.get_reg(Reg::CPSR)?;
+        let vector = ShimVector {
+            source: boot::vectors::VectorSource::from_spsr(spsr),
+            kind: boot::vectors::VectorKind::Sync,
+        };
+        let vbar = self.vcpu.get_sys_reg(applevisor::SysReg::VBAR_EL1)?;
+        self.vcpu
+            .set_sys_reg(applevisor::SysReg::ESR_EL1, syndrome & 0xffff_ffff)?;
+        self.vcpu.set_sys_reg(applevisor::SysReg::ELR_EL1, pc)?;
+        self.vcpu.set_sys_reg(applevisor::SysReg::SPSR_EL1, spsr)?;
+        self.vcpu.set_reg(Reg::CPSR, 0x3c5)?;
+        self.vcpu.set_reg(Reg::PC, vbar + vector.offset())?;
+        Ok(())
+    }
+
     /// レジスタインデックスから値を取得
     fn get_register_by_index(&self, index: u8) -> Result<u64, Box<dyn std::error::Error>> {
         if index < 31 {
@@ -919,26 +3095,231 @@ impl Hypervisor {
 
     /// Timer への参照を取得
     pub fn timer(&self) -> &devices::timer::Timer {
-        &self.interrupt_controller.timer
+        self.irq_chip.timer()
     }
 
     /// Timer への可変参照を取得
     pub fn timer_mut(&mut self) -> &mut devices::timer::Timer {
-        &mut self.interrupt_controller.timer
+        self.irq_chip.timer_mut()
     }
 
-    /// InterruptController への参照を取得
+    /// InterruptController (GIC) への参照を取得
     pub fn interrupt_controller(&self) -> &InterruptController {
-        &self.interrupt_controller
+        self.irq_chip.intc()
+    }
+
+    /// IrqChip (GIC・タイマー・注入ポリシー) への参照を取得
+    pub fn irq_chip(&self) -> &IrqChip {
+        &self.irq_chip
+    }
+
+    /// WFI で待っている vCPU を起こすハンドル
+    ///
+    /// ゲストに割り込みを上げるバックエンドのスレッドは、状態を更新した後に
+    /// `wake` して run ループに確認させる。[`VcpuHandle::kick`] も同じ待ちを解除する。
+    pub fn waker(&self) -> Waker {
+        self.event_loop.waker()
+    }
+
+    /// 他のスレッドから vCPU を kick / 停止するハンドル
+    pub fn vcpu_handle(&self) -> VcpuHandle {
+        self.vcpu_handle.clone()
+    }
+
+    /// 他のスレッドから run ループで操作を実行させるハンドル
+    pub fn control_handle(&self) -> ControlHandle {
+        ControlHandle::new(self.control_tx.clone(), self.vcpu_handle.clone())
+    }
+
+    /// 他のスレッドからモニタのコマンドを実行するハンドル
+    ///
+    /// コマンドは run ループが VM Exit の合間に実行する。
+    pub fn monitor(&self) -> MonitorHandle {
+        MonitorHandle::new(self.control_handle())
+    }
+
+    /// ゲストを一時停止する / 再開する
+    ///
+    /// 一時停止中の run ループはゲストを実行せず、[`ControlHandle`] の操作と
+    /// 停止要求だけを処理する。停止していた間はゲストの仮想カウンタも止める
+    /// (QEMU の `stop` / `cont` と同じ)。`run()` の前に一時停止すると、
+    /// ゲストを実行する前に再開を待つ。
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// 一時停止中か
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// モニタのコマンドをこのスレッドで実行する
+    ///
+    /// `run()` が戻った後にゲストを調べる場合に使う。
+    ///
+    /// # Errors
+    /// 範囲外のメモリや割り込み番号を指定した場合
+    pub fn monitor_command(
+        &mut self,
+        command: MonitorCommand,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        self.ensure_active()?;
+        let text = match command {
+            MonitorCommand::InfoGic => self.irq_chip.gic().lock().unwrap().summary().to_string(),
+            MonitorCommand::InfoTimer => monitor::describe_timer(self.irq_chip.timer()),
+            MonitorCommand::InfoMmio => monitor::describe_mmio(&self.mmio_manager),
+            MonitorCommand::InfoRegisters => {
+                let mut text = format!(
+                    "PC=0x{:016x} CPSR=0x{:08x}\n",
+                    self.vcpu.get_reg(Reg::PC)?,
+                    self.vcpu.get_reg(Reg::CPSR)?
+                );
+                for (index, &reg) in REGISTER_TABLE.iter().enumerate() {
+                    let separator = if index % 4 == 3 { "\n" } else { " " };
+                    text += &format!(
+                        "X{:<2}=0x{:016x}{}",
+                        index,
+                        self.vcpu.get_reg(reg)?,
+                        separator
+                    );
+                }
+                text + &format!("\n{}", El1Context::capture(&**self.vcpu)?)
+            }
+            MonitorCommand::DumpMem { addr, len } => {
+                if len > monitor::MAX_DUMP_LEN {
+                    return Err(format!(
+                        "dump length 0x{:x} exceeds 0x{:x}",
+                        len,
+                        monitor::MAX_DUMP_LEN
+                    )
+                    .into());
+                }
+                let mut bytes = vec![0u8; len as usize];
+                self.mem.read(addr, &mut bytes)?;
+                monitor::hexdump(addr, &bytes)
+            }
+            MonitorCommand::InjectIrq(irq) => {
+                if irq as usize >= devices::gic::MAX_IRQS {
+                    return Err(format!(
+                        "IRQ {} is out of range (the GIC has {} interrupts)",
+                        irq,
+                        devices::gic::MAX_IRQS
+                    )
+                    .into());
+                }
+                self.irq_chip.gic().lock().unwrap().set_irq_pending(irq);
+                format!("IRQ {} is pending", irq)
+            }
+            MonitorCommand::InfoBoot => match &self.next_boot {
+                Some(next) => format!("next boot: {}", next),
+                None => "next boot: unchanged".to_string(),
+            },
+            MonitorCommand::BootNext(next) => {
+                let text = match &next {
+                    Some(next) => format!("next boot: {}", next),
+                    None => "next boot: unchanged".to_string(),
+                };
+                self.set_next_boot(next)?;
+                text
+            }
+            MonitorCommand::Help => monitor::HELP.to_string(),
+        };
+        Ok(text)
+    }
+
+    /// 他のスレッドから届いた操作を実行する
+    fn serve_control(&mut self) {
+        while let Ok(op) = self.control_rx.try_recv() {
+            op(self);
+        }
+    }
+
+    /// 再開されるまで操作を処理しながら待つ
+    ///
+    /// # Returns
+    /// 一時停止中に停止要求が届いた場合は true
+    fn wait_while_paused(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        let start = Instant::now();
+        while self.paused {
+            if self.vcpu_handle.take_stop_request() {
+                return Ok(true);
+            }
+            self.serve_control();
+            if self.paused {
+                // 停止要求と制御要求は送った後に kick されるので、起こされるまで待つ
+                self.event_loop.wait(None)?;
+            }
+        }
+        // 停止していた間の分だけ offset を増やし、ゲストのカウンタを止めて見せる
+        let timer = self.irq_chip.timer_mut();
+        let ticks = host_sleep::guest_ticks(start.elapsed(), timer.get_frequency());
+        let offset = self.vcpu.get_vtimer_offset()?;
+        self.vcpu.set_vtimer_offset(offset.wrapping_add(ticks))?;
+        let virt_offset = timer.get_virt_offset();
+        timer.set_virt_offset(virt_offset.wrapping_add(ticks));
+        Ok(false)
+    }
+
+    /// ゲストがアイドルの間、次のタイマーの期限まで待つ
+    ///
+    /// 期限はゲストが設定した仮想タイマーとソフトウェアタイマーの早い方で、
+    /// [`MAX_IDLE_WAIT`] で打ち切る。期限の前に [`Waker`] で起こされたら戻る。
+    /// アイドルになった直後はスレッドを譲るか短く待つだけにする ([`event_loop::backoff`])。
+    fn wait_for_event(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let start = Instant::now();
+        let mut timeout = match self.idle.enter(event_loop::backoff) {
+            stats::IdleState::Yielding => {
+                std::thread::yield_now();
+                self.idle.record(start.elapsed());
+                return Ok(());
+            }
+            stats::IdleState::Napping => event_loop::NAP_TIMEOUT,
+            stats::IdleState::Running | stats::IdleState::Blocked => MAX_IDLE_WAIT,
+        };
+        let ctl = self
+            .vcpu
+            .get_sys_reg(applevisor::SysReg::CNTV_CTL_EL0)
+            .unwrap_or(0);
+        if ctl & 0x3 == 0x1 {
+            let cval = self
+                .vcpu
+                .get_sys_reg(applevisor::SysReg::CNTV_CVAL_EL0)
+                .unwrap_or(i64::MAX as u64);
+            let counter = self
+                .vcpu
+                .hardware_counter()
+                .wrapping_sub(self.vcpu.get_vtimer_offset()?);
+            let ticks = cval.saturating_sub(counter);
+            timeout = timeout.min(devices::timer::ticks_to_duration(ticks));
+        }
+        if let Some(nanos) = self.irq_chip.timer().time_until_next_event() {
+            timeout = timeout.min(Duration::from_nanos(nanos));
+        }
+        if !timeout.is_zero() {
+            self.event_loop.wait(Some(timeout))?;
+        }
+        self.idle.record(start.elapsed());
+        Ok(())
     }
 
-    /// InterruptController への可変参照を取得
+    /// InterruptController (GIC) への可変参照を取得
     pub fn interrupt_controller_mut(&mut self) -> &mut InterruptController {
-        &mut self.interrupt_controller
+        self.irq_chip.intc_mut()
+    }
+
+    /// IrqChip への可変参照を取得
+    pub fn irq_chip_mut(&mut self) -> &mut IrqChip {
+        &mut self.irq_chip
     }
 
     /// Linux カーネルをブートする
     ///
+    /// `cmdline` に `console=` や `earlycon` がなければ、[`Hypervisor::set_guest_console`] の
+    /// UART から作って補う。カーネルは [`KernelImage::load_address`] (2MB 境界 +
+    /// text_offset) に配置し、そこから実行する。
+    ///
+    /// [`KernelImage::load_address`]: crate::boot::kernel::KernelImage::load_address
+    ///
     /// # Arguments
     /// * `kernel` - カーネルイメージ
     /// * `cmdline` - カーネルコマンドライン
@@ -961,32 +3342,33 @@ impl Hypervisor {
         cmdline: &str,
         dtb_addr: Option<u64>,
     ) -> Result<HypervisorResult, Box<dyn std::error::Error>> {
-        // 1. Device Tree 生成
-        let dtb = crate::boot::device_tree::generate_device_tree(
-            &crate::boot::device_tree::DeviceTreeConfig {
-                memory_base: self.guest_addr,
-                memory_size: self.mem.get_size() as u64,
-                uart_base: 0x0900_0000,
-                virtio_base: 0x0a00_0000,
-                gic_dist_base: 0x0800_0000,
-                gic_cpu_base: 0x0801_0000,
-                cmdline: cmdline.to_string(),
-                initrd_start: None,
-                initrd_end: None,
-            },
-        )?;
+        let layout = MachineLayout {
+            ram_base: self.guest_addr,
+            irqs: self.irqs,
+            virtio_slots: self.virtio_slots.len().max(1) as u32,
+            ..MachineLayout::default()
+        };
+        let console = match self.guest_console {
+            boot::console::GuestConsole::Auto => self
+                .mmio_manager
+                .devices()
+                .any(|d| d.base() == layout.uart_base && d.name() == "pl011")
+                .then_some((boot::console::UartType::Pl011, layout.uart_base)),
+            boot::console::GuestConsole::Uart { kind, base } => Some((kind, base)),
+            boot::console::GuestConsole::Off => None,
+        };
+        let cmdline = match console {
+            Some((kind, base)) => boot::console::complete_cmdline(cmdline, kind, base),
+            None => cmdline.to_string(),
+        };
 
-        // 2. Device Tree をメモリに配置
-        let dtb_addr = dtb_addr.unwrap_or(0x4400_0000);
-        for (i, &byte) in dtb.iter().enumerate() {
-            self.write_byte(dtb_addr + i as u64, byte)?;
-        }
+        // 1-2. Device Tree を生成してメモリに配置
+        let dtb_addr = dtb_addr.unwrap_or(MachineLayout::default().dtb_addr());
+        let dtb_size = self.place_device_tree(&layout, &cmdline, dtb_addr)?;
 
-        // 3. カーネルをメモリに配置
-        let kernel_addr = kernel.entry_point();
-        for (i, &byte) in kernel.data().iter().enumerate() {
-            self.write_byte(kernel_addr + i as u64, byte)?;
-        }
+        // 3. カーネルをメモリに配置 (2MB 境界 + text_offset)
+        let kernel_addr = kernel.load_address();
+        self.load_blob("kernel", kernel_addr, kernel.data())?;
 
         // 4. ARM64 Linux ブート条件を設定
         // 参考: https://docs.kernel.org/arch/arm64/booting.html
@@ -999,27 +3381,260 @@ impl Hypervisor {
         // 0x3c5 = 0b001111000101
         //   M[4:0] = 0b00101 = EL1h
         //   DAIF = 0b1111 = すべての割り込みをマスク
+        let cpsr = 0x3c5;
+
+        // ブートプロトコルの要件を満たしているか確認 (違反したままだと原因不明の停止になる)
+        let boot_config = crate::boot::BootConfig {
+            text_offset: kernel.text_offset(),
+            initrd: self.initrd,
+            pstate: cpsr,
+            sctlr_el1: self.vcpu.get_sys_reg(applevisor::SysReg::SCTLR_EL1)?,
+            regs: [
+                self.get_reg(Reg::X0)?,
+                self.get_reg(Reg::X1)?,
+                self.get_reg(Reg::X2)?,
+                self.get_reg(Reg::X3)?,
+            ],
+            ..crate::boot::BootConfig::new(
+                self.guest_addr,
+                self.mem.get_size() as u64,
+                kernel_addr,
+                kernel.data().len(),
+                dtb_addr,
+                dtb_size,
+            )
+        };
+        let violations = crate::boot::validate(&boot_config);
+        if !violations.is_empty() {
+            let list: Vec<String> = violations.iter().map(|v| format!("  - {}", v)).collect();
+            return Err(format!("Boot protocol violations:\n{}", list.join("\n")).into());
+        }
 
         // デバッグ例外のトラップを有効化
         self.vcpu.set_trap_debug_exceptions(true)?;
 
         // 5. VM Exit ループ (PC をカーネルエントリーポイントに設定)
-        self.run(Some(0x3c5), Some(true), Some(kernel_addr))
+        self.run(Some(cpsr), Some(true), Some(kernel_addr))
+    }
+
+    /// U-Boot を中間ブートローダーとして起動する
+    ///
+    /// U-Boot は `qemu_arm64_defconfig` でビルドされていることを想定する。
+    /// DTB は QEMU と同じく RAM 先頭に配置され、U-Boot はそこから
+    /// デバイス情報 (UART、VirtIO Block、GIC) を読み取ったうえで、
+    /// VirtIO ディスクから OS をロードする。
+    /// アドレス配置は [`MachineLayout`] を参照。
+    ///
+    /// U-Boot はタイマーの周波数を CNTFRQ_EL0 から、PL011 のクロックを DTB の
+    /// `apb-pclk` から読む。CNTFRQ_EL0 はホストの値がそのまま見えるため、
+    /// タイマーのエミュレーションが前提とする [`TIMER_FREQ`](devices::timer::TIMER_FREQ)
+    /// と異なるホストではエラーを返す。
+    ///
+    /// # Arguments
+    /// * `uboot` - U-Boot イメージ (`u-boot.bin`)。エントリーポイントにロードされる
+    /// * `bootargs` - U-Boot が OS に引き渡すカーネルコマンドライン
+    ///
+    /// # Returns
+    /// 実行結果 (HypervisorResult)
+    ///
+    /// # Example
+    /// ```no_run
+    /// use hypervisor::{Hypervisor, boot::kernel::KernelImage};
+    ///
+    /// let mut hv = Hypervisor::new(0x40000000, 256 * 1024 * 1024).unwrap();
+    /// let uboot = KernelImage::load("u-boot.bin").unwrap();
+    /// hv.boot_uboot(&uboot, "console=ttyAMA0 root=/dev/vda rw").unwrap();
+    /// ```
+    pub fn boot_uboot(
+        &mut self,
+        uboot: &crate::boot::kernel::KernelImage,
+        bootargs: &str,
+    ) -> Result<HypervisorResult, Box<dyn std::error::Error>> {
+        let layout = MachineLayout {
+            ram_base: self.guest_addr,
+            irqs: self.irqs,
+            virtio_slots: self.virtio_slots.len().max(1) as u32,
+            ..MachineLayout::default()
+        };
+        let counter_frequency = self.vcpu.counter_frequency();
+        if counter_frequency != devices::timer::TIMER_FREQ {
+            return Err(format!(
+                "Guest CNTFRQ_EL0 is {} Hz but the timer emulation assumes {} Hz",
+                counter_frequency,
+                devices::timer::TIMER_FREQ
+            )
+            .into());
+        }
+        let dtb_addr = layout.uboot_dtb_addr();
+        let dtb_size = self.place_device_tree(&layout, bootargs, dtb_addr)?;
+
+        let uboot_addr = uboot.entry_point();
+        if uboot_addr < dtb_addr + dtb_size as u64 {
+            return Err(format!(
+                "U-Boot entry point 0x{:x} overlaps the DTB at 0x{:x}-0x{:x}",
+                uboot_addr,
+                dtb_addr,
+                dtb_addr + dtb_size as u64
+            )
+            .into());
+        }
+        self.load_blob("u-boot", uboot_addr, uboot.data())?;
+
+        // U-Boot も Linux と同じエントリー条件 (X0 = DTB) を受け付ける
+        self.set_reg(Reg::X0, dtb_addr)?;
+        self.set_reg(Reg::X1, 0)?;
+        self.set_reg(Reg::X2, 0)?;
+        self.set_reg(Reg::X3, 0)?;
+        self.vcpu.set_trap_debug_exceptions(true)?;
+
+        self.run(Some(0x3c5), Some(true), Some(uboot_addr))
+    }
+
+    /// Linux 以外のベアメタル / RTOS イメージ (Zephyr、FreeRTOS など) を起動する
+    ///
+    /// Device Tree は生成しない (これらの OS はビルド時にボード定義を埋め込む)。
+    /// SCTLR_EL1 を既知の状態 (MMU/キャッシュ off) に初期化し、
+    /// `args` を X0 から順に設定してエントリーポイントから EL1h で実行する。
+    ///
+    /// # Arguments
+    /// * `image` - イメージ。エントリーポイントにロードされ、そこから実行される
+    /// * `args` - X0-X7 に設定する値 (最大 8 個)
+    ///
+    /// # Returns
+    /// 実行結果 (HypervisorResult)
+    pub fn boot_bare_metal(
+        &mut self,
+        image: &crate::boot::kernel::KernelImage,
+        args: &[u64],
+    ) -> Result<HypervisorResult, Box<dyn std::error::Error>> {
+        if args.len() > 8 {
+            return Err(format!("Too many boot arguments: {} (max 8)", args.len()).into());
+        }
+
+        let entry = image.entry_point();
+        self.load_blob("image", entry, image.data())?;
+
+        self.vcpu.set_sys_reg(
+            applevisor::SysReg::SCTLR_EL1,
+            crate::boot::stub::SCTLR_EL1_RESET,
+        )?;
+        for (i, &arg) in args.iter().enumerate() {
+            self.set_reg(REGISTER_TABLE[i], arg)?;
+        }
+        self.vcpu.set_trap_debug_exceptions(true)?;
+
+        self.run(Some(0x3c5), Some(true), Some(entry))
+    }
+
+    /// 32-bit ARM カーネル / ペイロードを AArch32 EL1 (SVC モード) で起動する
+    ///
+    /// エントリーポイントの bit 0 が立っていれば Thumb で開始する。
+    /// ホストが AArch32 EL1 をサポートしない場合 (Apple Silicon はすべて該当)
+    /// はエラーを返す。
+    ///
+    /// # Arguments
+    /// * `image` - イメージ。エントリーポイント (bit 0 を除く) にロードされる
+    /// * `args` - R0-R3 に設定する値 (最大 4 個)
+    ///
+    /// # Returns
+    /// 実行結果 (HypervisorResult)
+    pub fn boot_aarch32(
+        &mut self,
+        image: &crate::boot::kernel::KernelImage,
+        args: &[u64],
+    ) -> Result<HypervisorResult, Box<dyn std::error::Error>> {
+        if args.len() > 4 {
+            return Err(format!("Too many boot arguments: {} (max 4)", args.len()).into());
+        }
+
+        let pfr0 = self.vcpu.get_sys_reg(applevisor::SysReg::ID_AA64PFR0_EL1)?;
+        if !aarch32::el1_supports_aarch32(pfr0) {
+            return Err(format!(
+                "AArch32 is not supported at EL1 on this host (ID_AA64PFR0_EL1=0x{:x})",
+                pfr0
+            )
+            .into());
+        }
+
+        let (entry, cpsr) = aarch32::entry_state(image.entry_point());
+        self.load_blob("image", entry, image.data())?;
+
+        for (i, &arg) in args.iter().enumerate() {
+            self.set_reg(REGISTER_TABLE[i], arg & 0xFFFF_FFFF)?;
+        }
+
+        self.run(Some(cpsr), Some(true), Some(entry))
+    }
+
+    /// Device Tree を生成してゲストメモリに配置する
+    ///
+    /// # Returns
+    /// 配置した DTB のサイズ (bytes)
+    fn place_device_tree(
+        &mut self,
+        layout: &MachineLayout,
+        cmdline: &str,
+        dtb_addr: u64,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let dtb = crate::boot::device_tree::generate_device_tree(
+            &crate::boot::device_tree::DeviceTreeConfig {
+                dtb_addr: Some(dtb_addr),
+                cpus: self.vm_config.vcpu_count(),
+                scmi_shmem_base: self.scmi.as_ref().map(|scmi| scmi.base()),
+                pl330_base: self.pl330_base,
+                smmu_base: self.smmu_base,
+                pmem_regions: self.pmem.clone(),
+                device_nodes: self.device_nodes.clone(),
+                initrd_start: self.initrd.map(|(start, _)| start),
+                initrd_end: self.initrd.map(|(_, end)| end),
+                ..crate::boot::device_tree::DeviceTreeConfig::from_layout(
+                    layout,
+                    self.mem.get_size() as u64,
+                    cmdline,
+                )
+            },
+        )?;
+        self.load_blob("dtb", dtb_addr, &dtb)?;
+        let len = dtb.len();
+        self.device_tree = Some(dtb);
+        Ok(len)
+    }
+
+    /// 配置先がゲスト RAM に収まるか確認する
+    ///
+    /// 収まらない場合は必要な RAM サイズを含むエラーを返す。
+    fn check_guest_range(
+        &self,
+        addr: u64,
+        len: usize,
+        what: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let ram_end = self.guest_addr + self.mem.get_size() as u64;
+        let end = addr + len as u64;
+        if addr < self.guest_addr || end > ram_end {
+            return Err(format!(
+                "{} at 0x{:x}-0x{:x} does not fit in guest RAM 0x{:x}-0x{:x} \
+                 (need at least 0x{:x} bytes of RAM)",
+                what,
+                addr,
+                end,
+                self.guest_addr,
+                ram_end,
+                end.saturating_sub(self.guest_addr)
+            )
+            .into());
+        }
+        Ok(())
     }
 }
 
 impl Drop for Hypervisor {
     fn drop(&mut self) {
-        use std::panic::{catch_unwind, AssertUnwindSafe};
-
-        // Vcpu を先に破棄（panic をキャッチして無視）
-        let _ = catch_unwind(AssertUnwindSafe(|| unsafe {
-            ManuallyDrop::drop(&mut self.vcpu);
-        }));
+        if self.shut_down {
+            return;
+        }
 
-        // VirtualMachine を破棄（panic をキャッチして無視）
-        let _ = catch_unwind(AssertUnwindSafe(|| unsafe {
-            ManuallyDrop::drop(&mut self._vm);
-        }));
+        // 破棄中のエラーは無視する
+        let _ = self.teardown();
     }
 }
@@ -0,0 +1,173 @@
+//! 同じ VM Exit を繰り返すだけの状態 (livelock) の検出
+//!
+//! システムレジスタや MMIO のエミュレーションが PC を進め忘れる、あるいは
+//! ハンドラが処理できずにゲストへ戻すと、ゲストは同じ命令で何度でも VM Exit する。
+//! run ループは止まらず、ゲストは何も出力しないまま固まったように見える。
+//!
+//! [`LivelockDetector`] は、ハンドラが PC を進めずにゲストへ戻した VM Exit が
+//! 同じ PC・同じ ESR・同じフォルトアドレスで、汎用レジスタも変わらないまま続いた
+//! 回数を数える。しきい値に達したら run ループは
+//! [`StopReason::Livelock`](crate::StopReason::Livelock) で戻る。
+//! ハンドラが命令をエミュレートして PC を進めた VM Exit は、UART の FR や
+//! 共有メモリのレジスタのポーリングのように同じ命令を正常に繰り返すため数えない。
+//! WFI と HVC も待ちや PSCI の呼び出しで正常に繰り返すため、数えずにリセットする。
+
+use crate::run_options::GPR_COUNT;
+use std::fmt;
+
+/// livelock とみなす連続した同じ VM Exit の回数
+pub const LIVELOCK_THRESHOLD: u64 = 10_000;
+
+/// 検出した livelock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Livelock {
+    /// 繰り返し VM Exit した命令の PC
+    pub pc: u64,
+    /// ESR_EL2
+    pub syndrome: u64,
+    /// フォルトしたゲスト物理アドレス (Data Abort のみ)
+    pub fault_addr: Option<u64>,
+    /// 同じ VM Exit が続いた回数
+    pub repeats: u64,
+}
+
+impl fmt::Display for Livelock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} identical exits at pc=0x{:x} (ESR=0x{:x}, EC=0x{:x})",
+            self.repeats,
+            self.pc,
+            self.syndrome,
+            (self.syndrome >> 26) & 0x3f
+        )?;
+        if let Some(addr) = self.fault_addr {
+            write!(f, " addr=0x{:x}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ExitKey {
+    pc: u64,
+    syndrome: u64,
+    fault_addr: Option<u64>,
+    gprs: [u64; GPR_COUNT],
+}
+
+/// 同じ VM Exit の繰り返しを数える
+#[derive(Debug, Clone)]
+pub struct LivelockDetector {
+    threshold: u64,
+    last: Option<ExitKey>,
+    repeats: u64,
+}
+
+impl Default for LivelockDetector {
+    fn default() -> Self {
+        Self::new(LIVELOCK_THRESHOLD)
+    }
+}
+
+impl LivelockDetector {
+    /// `threshold` 回続いたら livelock とみなす (0 なら検出しない)
+    pub fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            last: None,
+            repeats: 0,
+        }
+    }
+
+    /// しきい値
+    pub fn threshold(&self) -> u64 {
+        self.threshold
+    }
+
+    /// 例外による VM Exit を数え、しきい値に達したら livelock を返す
+    pub fn observe(
+        &mut self,
+        pc: u64,
+        syndrome: u64,
+        fault_addr: Option<u64>,
+        gprs: &[u64; GPR_COUNT],
+    ) -> Option<Livelock> {
+        if self.threshold == 0 {
+            return None;
+        }
+        let key = ExitKey {
+            pc,
+            syndrome,
+            fault_addr,
+            gprs: *gprs,
+        };
+        if self.last.as_ref() == Some(&key) {
+            self.repeats += 1;
+        } else {
+            self.last = Some(key);
+            self.repeats = 1;
+        }
+        (self.repeats >= self.threshold).then_some(Livelock {
+            pc,
+            syndrome,
+            fault_addr,
+            repeats: self.repeats,
+        })
+    }
+
+    /// ゲストが先に進んだ (WFI・HVC など) ので数え直す
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.repeats = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYSREG: u64 = 0x18 << 26 | 1 << 25;
+
+    #[test]
+    fn 同じ_vm_exit_が続いたら検出する() {
+        let mut detector = LivelockDetector::new(3);
+        let gprs = [0; GPR_COUNT];
+        assert_eq!(detector.observe(0x4000_0000, SYSREG, None, &gprs), None);
+        assert_eq!(detector.observe(0x4000_0000, SYSREG, None, &gprs), None);
+        let livelock = detector.observe(0x4000_0000, SYSREG, None, &gprs).unwrap();
+        assert_eq!(livelock.repeats, 3);
+        assert_eq!(
+            livelock.to_string(),
+            "3 identical exits at pc=0x40000000 (ESR=0x62000000, EC=0x18)"
+        );
+
+        detector.reset();
+        assert_eq!(detector.observe(0x4000_0000, SYSREG, None, &gprs), None);
+    }
+
+    #[test]
+    fn ゲストが進んでいれば検出しない() {
+        let mut detector = LivelockDetector::new(2);
+        let mut gprs = [0; GPR_COUNT];
+        for i in 0..10 {
+            // ポーリングのループ: 同じ PC でもレジスタが変わる
+            gprs[0] = i;
+            assert_eq!(detector.observe(0x4000_0000, SYSREG, None, &gprs), None);
+        }
+        let abort = 0x24 << 26;
+        assert_eq!(
+            detector.observe(0x4000_0000, abort, Some(0x900_0000), &gprs),
+            None
+        );
+        assert_eq!(
+            detector.observe(0x4000_0000, abort, Some(0x900_0004), &gprs),
+            None
+        );
+
+        let mut off = LivelockDetector::new(0);
+        for _ in 0..10 {
+            assert_eq!(off.observe(0x4000_0000, SYSREG, None, &gprs), None);
+        }
+    }
+}
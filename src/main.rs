@@ -24,7 +24,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // ハイパーバイザーを初期化
     println!("[1] ハイパーバイザーを初期化中...");
     let guest_addr = 0x10000;
-    let mut hv = Hypervisor::new(guest_addr, 0x1000)?;
+    let mut hv = Hypervisor::new(guest_addr, 0x4000)?;
     println!("    ✓ ゲストアドレス: 0x{:x}", guest_addr);
 
     // ゲストコードを書き込む
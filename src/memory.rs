@@ -0,0 +1,1040 @@
+//! ゲスト RAM
+//!
+//! ホスト側のメモリを `mmap` で確保し、[`VmBackend`] (通常は `hv_vm_map`) で
+//! ゲストの物理アドレス空間にマッピングする。applevisor の `Mapping` はグローバルアロケータで確保するため
+//! 確保方法を選べないが、こちらは大きなページでの確保を試みることができる。
+//!
+//! # 大きなページ
+//!
+//! 数 GB のゲストでは stage-2 の TLB ミスが増えるため、以下の順に確保を試みる。
+//! 1. `VM_FLAGS_SUPERPAGE_SIZE_2MB` によるスーパーページ
+//!    (Apple Silicon の macOS では現状サポートされず失敗する)
+//! 2. 2MB 境界に揃えた通常ページ
+//!    (カーネルが stage-2 でブロックマッピングを使える可能性がある)
+//! 3. 通常ページ
+//!
+//! 実際に得られた確保方法は [`GuestRam::backing`] で確認できる。
+//!
+//! # 遅延確保
+//!
+//! 匿名 `mmap` はページに最初にアクセスした時点で物理メモリを割り当てる。
+//! ゲストの初回アクセスは stage-2 フォルトとしてホストカーネルが処理するため、
+//! 8GB と宣言したゲストでも実際に使用した分しか常駐しない。
+//! ただし `RamFill::Poison` や `scrub_ram` は全ページに書き込むため全量が常駐する。
+//! 常駐量は [`GuestRam::resident_size`] で確認できる。
+
+use crate::backend::VmBackend;
+use std::error::Error;
+use std::ffi::c_void;
+use std::fs::OpenOptions;
+use std::marker::PhantomData;
+use std::mem::{size_of, MaybeUninit};
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 未マッピングを表す guest_addr の値
+const NOT_MAPPED: u64 = u64::MAX;
+
+/// 2MB
+const SUPERPAGE_SIZE: usize = 0x20_0000;
+
+/// macOS の `VM_FLAGS_SUPERPAGE_SIZE_2MB` (SUPERPAGE_SIZE_2MB << VM_FLAGS_SUPERPAGE_SHIFT)
+///
+/// 匿名 `mmap` の fd 引数として渡す。
+const VM_FLAGS_SUPERPAGE_SIZE_2MB: i32 = 2 << 16;
+
+/// ゲスト RAM のホスト側の確保方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RamBacking {
+    /// 通常ページ
+    Normal,
+    /// 2MB 境界に揃えた通常ページ
+    Aligned2M,
+    /// 2MB スーパーページ
+    Superpage2M,
+    /// ホストのファイルを共有マッピングしたもの
+    SharedFile,
+    /// ホストのファイルをコピーオンライトでマッピングしたもの (書き込みはファイルに届かない)
+    PrivateFile,
+}
+
+/// ゲスト RAM
+pub struct GuestRam {
+    host_addr: *mut u8,
+    /// munmap するときのサイズ (size を確保の単位に切り上げたもの)
+    alloc_size: usize,
+    size: usize,
+    /// マッピング先 (未マッピングなら NOT_MAPPED)
+    guest_addr: AtomicU64,
+    /// マッピングしたバックエンド (未マッピングなら None)
+    vm: Option<&'static dyn VmBackend>,
+    backing: RamBacking,
+    /// 取得済みの [`VolatileSlice`] を無効にするたびに進める世代
+    generation: AtomicU64,
+}
+
+// host_addr は GuestRam が所有する mmap 領域を指す
+unsafe impl Send for GuestRam {}
+unsafe impl Sync for GuestRam {}
+
+impl GuestRam {
+    /// 通常ページでゲスト RAM を確保する
+    ///
+    /// # Arguments
+    /// * `size` - サイズ (bytes)
+    pub fn new(size: usize) -> Result<Self, Box<dyn Error>> {
+        let alloc_size = size.next_multiple_of(applevisor::PAGE_SIZE);
+        let host_addr = mmap_anonymous(alloc_size, -1)
+            .ok_or_else(|| format!("Failed to allocate 0x{:x} bytes of guest RAM", size))?;
+        Ok(Self {
+            host_addr,
+            alloc_size,
+            size,
+            guest_addr: AtomicU64::new(NOT_MAPPED),
+            vm: None,
+            backing: RamBacking::Normal,
+            generation: AtomicU64::new(0),
+        })
+    }
+
+    /// 大きなページでゲスト RAM を確保する
+    ///
+    /// スーパーページ、2MB 境界の通常ページ、通常ページの順に試す。
+    ///
+    /// # Arguments
+    /// * `size` - サイズ (bytes)
+    pub fn with_huge_pages(size: usize) -> Result<Self, Box<dyn Error>> {
+        let alloc_size = size.next_multiple_of(SUPERPAGE_SIZE);
+
+        if let Some(host_addr) = mmap_anonymous(alloc_size, VM_FLAGS_SUPERPAGE_SIZE_2MB) {
+            return Ok(Self {
+                host_addr,
+                alloc_size,
+                size,
+                guest_addr: AtomicU64::new(NOT_MAPPED),
+                vm: None,
+                backing: RamBacking::Superpage2M,
+                generation: AtomicU64::new(0),
+            });
+        }
+
+        if let Some(host_addr) = mmap_aligned(alloc_size, SUPERPAGE_SIZE) {
+            return Ok(Self {
+                host_addr,
+                alloc_size,
+                size,
+                guest_addr: AtomicU64::new(NOT_MAPPED),
+                vm: None,
+                backing: RamBacking::Aligned2M,
+                generation: AtomicU64::new(0),
+            });
+        }
+
+        Self::new(size)
+    }
+
+    /// ホストのファイルを共有マッピングしてゲスト RAM として使用する
+    ///
+    /// ホストの他のプロセスが同じファイルを `mmap` すれば、ゲストとデータを
+    /// 直接やり取りできる (ivshmem 相当)。ファイルが存在しなければ作成し、
+    /// `size` に合わせて伸縮する。
+    ///
+    /// # Arguments
+    /// * `path` - 共有メモリファイルのパス (例: `/tmp/hv-shmem`)
+    /// * `size` - サイズ (bytes)。ページサイズの倍数であること
+    pub fn from_file<P: AsRef<Path>>(path: P, size: usize) -> Result<Self, Box<dyn Error>> {
+        if size == 0 || !size.is_multiple_of(applevisor::PAGE_SIZE) {
+            return Err(format!(
+                "Shared memory size 0x{:x} must be a non-zero multiple of 0x{:x}",
+                size,
+                applevisor::PAGE_SIZE
+            )
+            .into());
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.as_ref())?;
+        file.set_len(size as u64)?;
+
+        Self::mmap_file(&file, path.as_ref(), size, RamBacking::SharedFile)
+    }
+
+    /// 既存のホストのファイルをそのままの大きさでマッピングする (永続メモリ向け)
+    ///
+    /// [`GuestRam::from_file`] と違ってファイルを作成・伸縮しない。
+    /// `read_only` ならファイルを読み取り専用で開いてコピーオンライトでマッピングし、
+    /// ゲストの書き込みはこのマッピングの中だけに残る
+    /// ([`RamBacking::PrivateFile`])。
+    ///
+    /// # Arguments
+    /// * `path` - ファイルのパス。大きさはページサイズの倍数であること
+    /// * `read_only` - ファイルに書き戻さないか
+    pub fn map_file<P: AsRef<Path>>(path: P, read_only: bool) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        let size = file.metadata()?.len() as usize;
+        if size == 0 || !size.is_multiple_of(applevisor::PAGE_SIZE) {
+            return Err(format!(
+                "{} is 0x{:x} bytes; it must be a non-zero multiple of 0x{:x}",
+                path.display(),
+                size,
+                applevisor::PAGE_SIZE
+            )
+            .into());
+        }
+        let backing = if read_only {
+            RamBacking::PrivateFile
+        } else {
+            RamBacking::SharedFile
+        };
+        Self::mmap_file(&file, path, size, backing)
+    }
+
+    fn mmap_file(
+        file: &std::fs::File,
+        path: &Path,
+        size: usize,
+        backing: RamBacking,
+    ) -> Result<Self, Box<dyn Error>> {
+        let flags = match backing {
+            RamBacking::PrivateFile => libc::MAP_PRIVATE,
+            _ => libc::MAP_SHARED,
+        };
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(format!("Failed to map file {}", path.display()).into());
+        }
+
+        // マッピングはファイルを閉じても残る
+        Ok(Self {
+            host_addr: addr as *mut u8,
+            alloc_size: size,
+            size,
+            guest_addr: AtomicU64::new(NOT_MAPPED),
+            vm: None,
+            backing,
+            generation: AtomicU64::new(0),
+        })
+    }
+
+    /// ゲストの物理アドレス空間にマッピングする (RWX)
+    ///
+    /// # Arguments
+    /// * `vm` - マッピング先の VM (解除にも使う)
+    /// * `guest_addr` - ゲスト物理アドレス
+    pub fn map(
+        &mut self,
+        vm: &'static dyn VmBackend,
+        guest_addr: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.get_guest_addr().is_some() {
+            return Err("Guest RAM is already mapped".into());
+        }
+        vm.map(self.host_addr, guest_addr, self.mapped_size())?;
+        self.vm = Some(vm);
+        self.guest_addr.store(guest_addr, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// ゲストからの書き込みを許可・禁止する (読み取りと実行は常に許可)
+    ///
+    /// 書き込みを禁止したページへのゲストの書き込みは stage-2 の権限フォルトになり、
+    /// Data Abort として run ループに届く。ホスト側 ([`GuestRam::write`] や
+    /// デバイスの DMA) からの書き込みは影響を受けない。
+    ///
+    /// # Arguments
+    /// * `addr` - ゲスト物理アドレス (ホストのページ境界)
+    /// * `len` - サイズ (ホストのページサイズの倍数)
+    /// * `writable` - 書き込みを許可するか
+    pub fn set_writable(
+        &self,
+        addr: u64,
+        len: usize,
+        writable: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let base = self.get_guest_addr().ok_or("Guest RAM is not mapped")?;
+        // 末尾のページはサイズを切り上げたマッピング全体で確認する
+        guest_offset(base, self.mapped_size(), addr, len)?;
+        let vm = self.vm.ok_or("Guest RAM is not mapped")?;
+        vm.protect(addr, len, writable)
+    }
+
+    /// マッピングを解除する
+    ///
+    /// デバイスが `Arc` で共有していても VM 破棄前に解除できるよう `&self` を取る。
+    /// 解除後の読み書きはエラーになり、取得済みの [`VolatileSlice`] は無効になる
+    /// ([`GuestRam::invalidate_accessors`])。
+    pub fn unmap(&self) -> Result<(), Box<dyn Error>> {
+        let guest_addr = self.get_guest_addr().ok_or("Guest RAM is not mapped")?;
+        let vm = self.vm.ok_or("Guest RAM is not mapped")?;
+        vm.unmap(guest_addr, self.mapped_size())?;
+        self.guest_addr.store(NOT_MAPPED, Ordering::SeqCst);
+        self.invalidate_accessors();
+        Ok(())
+    }
+
+    /// 取得済みの [`VolatileSlice`] を無効にする
+    ///
+    /// デバイスのバックエンドがスライスを保持したままマッピングの解除やリブート
+    /// (`scrub_ram`) をまたぐと、ゲストから見えなくなった領域や消去済みの内容を
+    /// 黙って読み書きしてしまう。デバッグビルドでは、無効にした後のスライスへの
+    /// アクセスを panic で知らせる。新しく取得したスライスは影響を受けない。
+    pub fn invalidate_accessors(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// サイズ (bytes)
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    /// ホスト側の先頭アドレス
+    pub fn get_host_addr(&self) -> *const u8 {
+        self.host_addr
+    }
+
+    /// マッピング先のゲスト物理アドレス
+    pub fn get_guest_addr(&self) -> Option<u64> {
+        match self.guest_addr.load(Ordering::SeqCst) {
+            NOT_MAPPED => None,
+            addr => Some(addr),
+        }
+    }
+
+    /// ゲストにマッピングするサイズ (size を stage-2 のページ境界に切り上げたもの)
+    ///
+    /// 大きなページで確保した場合も、2MB に切り上げた余りはゲストに見せない。
+    fn mapped_size(&self) -> usize {
+        self.size.next_multiple_of(applevisor::PAGE_SIZE)
+    }
+
+    /// 実際に得られた確保方法
+    pub fn backing(&self) -> RamBacking {
+        self.backing
+    }
+
+    /// ホストの物理メモリに常駐しているサイズ (bytes)
+    ///
+    /// 取得に失敗した場合は宣言サイズを返す。
+    pub fn resident_size(&self) -> usize {
+        resident_bytes(self.host_addr, self.alloc_size)
+            .unwrap_or(self.alloc_size)
+            .min(self.size)
+    }
+
+    /// ゲストアドレスから RAM 先頭からのオフセットに変換する (範囲チェック付き)
+    fn offset_of(&self, addr: u64, len: usize) -> Result<usize, Box<dyn Error>> {
+        let base = self.get_guest_addr().ok_or("Guest RAM is not mapped")?;
+        guest_offset(base, self.size, addr, len)
+    }
+
+    /// ゲストメモリから読み取る
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> Result<usize, Box<dyn Error>> {
+        let offset = self.offset_of(addr, data.len())?;
+        unsafe {
+            ptr::copy_nonoverlapping(self.host_addr.add(offset), data.as_mut_ptr(), data.len());
+        }
+        Ok(data.len())
+    }
+
+    /// ゲストメモリに書き込む
+    pub fn write(&self, addr: u64, data: &[u8]) -> Result<usize, Box<dyn Error>> {
+        let offset = self.offset_of(addr, data.len())?;
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), self.host_addr.add(offset), data.len());
+        }
+        Ok(data.len())
+    }
+}
+
+/// ゲストメモリとの間でバイト列としてコピーできる型
+///
+/// vm-memory の `ByteValued` 相当。
+///
+/// # Safety
+/// 任意のバイト列が有効な値となり、パディングを含まない型にのみ実装すること。
+pub unsafe trait ByteValued: Copy + Send + Sync + 'static {}
+
+macro_rules! impl_byte_valued {
+    ($($t:ty),*) => {
+        $(unsafe impl ByteValued for $t {})*
+    };
+}
+
+impl_byte_valued!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+unsafe impl<T: ByteValued, const N: usize> ByteValued for [T; N] {}
+
+/// ゲストメモリ上の連続領域
+///
+/// ゲストの vCPU や他のデバイスが同時に書き換える可能性があるため、
+/// Rust の参照 (`&[u8]`) は作らず、コピーは `ptr::copy` で行う。
+#[derive(Debug, Clone, Copy)]
+pub struct VolatileSlice<'a> {
+    addr: *mut u8,
+    len: usize,
+    /// 取得元の世代と、取得した時点の値 (`GuestRam::invalidate_accessors`)
+    generation: Option<(&'a AtomicU64, u64)>,
+    _marker: PhantomData<&'a u8>,
+}
+
+impl<'a> VolatileSlice<'a> {
+    /// # Safety
+    /// [addr, addr + len) が 'a の間有効なメモリであること
+    unsafe fn new(addr: *mut u8, len: usize) -> Self {
+        Self {
+            addr,
+            len,
+            generation: None,
+            _marker: PhantomData,
+        }
+    }
+
+    /// 取得元が `generation` を進めたら無効になるスライスにする
+    fn with_generation(self, generation: &'a AtomicU64) -> Self {
+        Self {
+            generation: Some((generation, generation.load(Ordering::Acquire))),
+            ..self
+        }
+    }
+
+    /// 無効になったスライスへのアクセスを検出する (デバッグビルドのみ)
+    fn assert_live(&self) {
+        if let Some((current, taken)) = self.generation {
+            debug_assert_eq!(
+                current.load(Ordering::Acquire),
+                taken,
+                "stale guest memory access at host address {:p} (+0x{:x}): the slice was \
+                 obtained before the guest RAM was unmapped or reset",
+                self.addr,
+                self.len
+            );
+        }
+    }
+
+    /// 長さ (bytes)
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// 長さが 0 か
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// ホスト側の先頭アドレス
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.assert_live();
+        self.addr
+    }
+
+    /// [offset, offset + len) の部分領域を取得する
+    pub fn subslice(&self, offset: usize, len: usize) -> Result<VolatileSlice<'a>, Box<dyn Error>> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(Self {
+                addr: unsafe { self.addr.add(offset) },
+                len,
+                ..*self
+            }),
+            _ => Err(format!(
+                "Subslice 0x{:x} (+0x{:x}) is outside slice of 0x{:x} bytes",
+                offset, len, self.len
+            )
+            .into()),
+        }
+    }
+
+    /// 先頭から buf にコピーする
+    ///
+    /// # Returns
+    /// コピーしたバイト数 (buf とスライスの短い方)
+    pub fn copy_to(&self, buf: &mut [u8]) -> usize {
+        self.assert_live();
+        let len = self.len.min(buf.len());
+        unsafe {
+            ptr::copy(self.addr, buf.as_mut_ptr(), len);
+        }
+        len
+    }
+
+    /// 先頭に buf をコピーする
+    ///
+    /// # Returns
+    /// コピーしたバイト数 (buf とスライスの短い方)
+    pub fn copy_from(&self, buf: &[u8]) -> usize {
+        self.assert_live();
+        let len = self.len.min(buf.len());
+        unsafe {
+            ptr::copy(buf.as_ptr(), self.addr, len);
+        }
+        len
+    }
+}
+
+/// デバイスからゲストメモリへアクセスするためのインターフェース
+///
+/// vm-memory の `GuestMemory` / `Bytes<GuestAddress>` を参考に、
+/// virtio デバイスの DMA (ディスクリプタやバッファの読み書き) に必要な
+/// 最小限の操作に絞っている。アドレスはすべてゲスト物理アドレスで、
+/// 範囲外へのアクセスはエラーになる。
+pub trait GuestMemory: Send + Sync {
+    /// [addr, addr + len) がすべてゲストメモリ内か
+    fn check_range(&self, addr: u64, len: usize) -> bool;
+
+    /// [addr, addr + len) の領域を取得する
+    fn get_slice(&self, addr: u64, len: usize) -> Result<VolatileSlice<'_>, Box<dyn Error>>;
+
+    /// ゲストメモリから buf を埋める
+    fn read_slice(&self, buf: &mut [u8], addr: u64) -> Result<(), Box<dyn Error>> {
+        self.get_slice(addr, buf.len())?.copy_to(buf);
+        Ok(())
+    }
+
+    /// buf をゲストメモリに書き込む
+    fn write_slice(&self, buf: &[u8], addr: u64) -> Result<(), Box<dyn Error>> {
+        self.get_slice(addr, buf.len())?.copy_from(buf);
+        Ok(())
+    }
+
+    /// [range.start, range.end) の内容をコピーして返す
+    fn snapshot(&self, range: Range<u64>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let len = range.end.checked_sub(range.start).ok_or_else(|| {
+            format!(
+                "Invalid guest memory range 0x{:x}-0x{:x}",
+                range.start, range.end
+            )
+        })?;
+        // 範囲外の大きな長さで確保しないよう、先に範囲を確認する
+        let slice = self.get_slice(range.start, len as usize)?;
+        let mut buf = vec![0; len as usize];
+        slice.copy_to(&mut buf);
+        Ok(buf)
+    }
+
+    /// addr からの内容が expected と一致するか確認する
+    ///
+    /// # Errors
+    /// 一致しない場合は、異なるバイト数と最初に異なる位置を含むエラーを返す
+    fn compare(&self, addr: u64, expected: &[u8]) -> Result<(), Box<dyn Error>> {
+        let end = addr.checked_add(expected.len() as u64).ok_or_else(|| {
+            format!(
+                "Guest memory range at 0x{:x} (+0x{:x}) overflows",
+                addr,
+                expected.len()
+            )
+        })?;
+        let actual = self.snapshot(addr..end)?;
+        let mut diffs = actual
+            .iter()
+            .zip(expected)
+            .enumerate()
+            .filter(|(_, (a, e))| a != e);
+        let Some((first, (got, want))) = diffs.next() else {
+            return Ok(());
+        };
+        Err(format!(
+            "Guest memory at 0x{:x}-0x{:x} differs in {} of {} bytes \
+             (first at 0x{:x}: got 0x{:02x}, expected 0x{:02x})",
+            addr,
+            end,
+            diffs.count() + 1,
+            expected.len(),
+            addr + first as u64,
+            got,
+            want
+        )
+        .into())
+    }
+}
+
+/// [`GuestMemory`] に型付きの読み書きを追加する
+///
+/// ジェネリックメソッドを含むため `dyn GuestMemory` でも使えるよう別トレイトにしている。
+/// ゲストはリトルエンディアンのため、`read_u16` などの整数の読み書きは
+/// ホストのバイト順によらずリトルエンディアンで変換する。
+pub trait GuestMemoryExt: GuestMemory {
+    /// addr から T を読み取る (アラインメント不要)
+    fn read_obj<T: ByteValued>(&self, addr: u64) -> Result<T, Box<dyn Error>> {
+        let slice = self.get_slice(addr, size_of::<T>())?;
+        let mut value = MaybeUninit::<T>::uninit();
+        // SAFETY: T は ByteValued なので任意のバイト列が有効な値
+        unsafe {
+            ptr::copy(
+                slice.as_ptr(),
+                value.as_mut_ptr() as *mut u8,
+                size_of::<T>(),
+            );
+            Ok(value.assume_init())
+        }
+    }
+
+    /// addr に T を書き込む (アラインメント不要)
+    fn write_obj<T: ByteValued>(&self, value: T, addr: u64) -> Result<(), Box<dyn Error>> {
+        let slice = self.get_slice(addr, size_of::<T>())?;
+        unsafe {
+            ptr::copy(
+                &value as *const T as *const u8,
+                slice.as_ptr(),
+                size_of::<T>(),
+            );
+        }
+        Ok(())
+    }
+
+    /// 8-bit 値を読み取る
+    fn read_u8(&self, addr: u64) -> Result<u8, Box<dyn Error>> {
+        self.read_obj(addr)
+    }
+
+    /// 16-bit 値を読み取る (リトルエンディアン、アラインメント不要)
+    fn read_u16(&self, addr: u64) -> Result<u16, Box<dyn Error>> {
+        Ok(u16::from_le(self.read_obj(addr)?))
+    }
+
+    /// 32-bit 値を読み取る (リトルエンディアン、アラインメント不要)
+    fn read_u32(&self, addr: u64) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_le(self.read_obj(addr)?))
+    }
+
+    /// 64-bit 値を読み取る (リトルエンディアン、アラインメント不要)
+    fn read_u64(&self, addr: u64) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::from_le(self.read_obj(addr)?))
+    }
+
+    /// 8-bit 値を書き込む
+    fn write_u8(&self, addr: u64, value: u8) -> Result<(), Box<dyn Error>> {
+        self.write_obj(value, addr)
+    }
+
+    /// 16-bit 値を書き込む (リトルエンディアン、アラインメント不要)
+    fn write_u16(&self, addr: u64, value: u16) -> Result<(), Box<dyn Error>> {
+        self.write_obj(value.to_le(), addr)
+    }
+
+    /// 32-bit 値を書き込む (リトルエンディアン、アラインメント不要)
+    fn write_u32(&self, addr: u64, value: u32) -> Result<(), Box<dyn Error>> {
+        self.write_obj(value.to_le(), addr)
+    }
+
+    /// 64-bit 値を書き込む (リトルエンディアン、アラインメント不要)
+    fn write_u64(&self, addr: u64, value: u64) -> Result<(), Box<dyn Error>> {
+        self.write_obj(value.to_le(), addr)
+    }
+}
+
+impl<M: GuestMemory + ?Sized> GuestMemoryExt for M {}
+
+impl GuestMemory for GuestRam {
+    fn check_range(&self, addr: u64, len: usize) -> bool {
+        self.offset_of(addr, len).is_ok()
+    }
+
+    fn get_slice(&self, addr: u64, len: usize) -> Result<VolatileSlice<'_>, Box<dyn Error>> {
+        let offset = self.offset_of(addr, len)?;
+        // SAFETY: 範囲チェック済みで、確保した領域は self が破棄されるまで解放されない
+        let slice = unsafe { VolatileSlice::new(self.host_addr.add(offset), len) };
+        Ok(slice.with_generation(&self.generation))
+    }
+}
+
+impl Drop for GuestRam {
+    fn drop(&mut self) {
+        if self.get_guest_addr().is_some() {
+            let _ = self.unmap();
+        }
+        unsafe {
+            libc::munmap(self.host_addr as *mut c_void, self.alloc_size);
+        }
+    }
+}
+
+/// [base, base + size) の範囲内なら addr の base からのオフセットを返す
+fn guest_offset(base: u64, size: usize, addr: u64, len: usize) -> Result<usize, Box<dyn Error>> {
+    let end = addr.checked_add(len as u64);
+    match end {
+        Some(end) if addr >= base && end <= base + size as u64 => Ok((addr - base) as usize),
+        _ => Err(format!(
+            "Guest address 0x{:x} (+0x{:x}) is outside guest RAM 0x{:x}-0x{:x}",
+            addr,
+            len,
+            base,
+            base + size as u64
+        )
+        .into()),
+    }
+}
+
+/// [addr, addr + len) のうちホストの物理メモリに常駐しているバイト数を返す
+fn resident_bytes(addr: *const u8, len: usize) -> Option<usize> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page_size <= 0 {
+        return None;
+    }
+    let page_size = page_size as usize;
+    let mut vec = vec![0; len.div_ceil(page_size)];
+    let ret = unsafe { libc::mincore(addr as *mut c_void, len, vec.as_mut_ptr() as *mut _) };
+    if ret != 0 {
+        return None;
+    }
+    Some(vec.iter().filter(|&&v| v & 1 != 0).count() * page_size)
+}
+
+/// 匿名メモリを mmap で確保する (ゼロ初期化済み)
+fn mmap_anonymous(size: usize, fd: i32) -> Option<*mut u8> {
+    let addr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANON,
+            fd,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        None
+    } else {
+        Some(addr as *mut u8)
+    }
+}
+
+/// align 境界に揃えた匿名メモリを確保する
+///
+/// 余分に確保してから前後の端数を munmap する。
+fn mmap_aligned(size: usize, align: usize) -> Option<*mut u8> {
+    let raw = mmap_anonymous(size + align, -1)? as usize;
+    let aligned = raw.next_multiple_of(align);
+    let head = aligned - raw;
+    let tail = align - head;
+    unsafe {
+        if head > 0 {
+            libc::munmap(raw as *mut c_void, head);
+        }
+        if tail > 0 {
+            libc::munmap((aligned + size) as *mut c_void, tail);
+        }
+    }
+    Some(aligned as *mut u8)
+}
+
+/// テスト用の Vec で確保したゲストメモリ
+///
+/// `GuestRam` は破棄時に `hv_vm_unmap` を呼ぶため、ユニットテストではこちらを使う。
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::{guest_offset, GuestMemory, VolatileSlice};
+    use std::error::Error;
+
+    pub(crate) struct TestMemory {
+        base: u64,
+        buf: std::cell::UnsafeCell<Vec<u8>>,
+    }
+
+    unsafe impl Sync for TestMemory {}
+
+    impl TestMemory {
+        pub(crate) fn new(base: u64, size: usize) -> Self {
+            Self {
+                base,
+                buf: std::cell::UnsafeCell::new(vec![0; size]),
+            }
+        }
+    }
+
+    impl GuestMemory for TestMemory {
+        fn check_range(&self, addr: u64, len: usize) -> bool {
+            let size = unsafe { (*self.buf.get()).len() };
+            guest_offset(self.base, size, addr, len).is_ok()
+        }
+
+        fn get_slice(&self, addr: u64, len: usize) -> Result<VolatileSlice<'_>, Box<dyn Error>> {
+            let buf = unsafe { &mut *self.buf.get() };
+            let offset = guest_offset(self.base, buf.len(), addr, len)?;
+            Ok(unsafe { VolatileSlice::new(buf.as_mut_ptr().add(offset), len) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::TestMemory;
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn snapshot_と_compare_で範囲をまとめて確認できる() {
+        let mem = TestMemory::new(0x4000_0000, 0x1000);
+        mem.write_slice(&[1, 2, 3, 4], 0x4000_0010).unwrap();
+        assert_eq!(
+            mem.snapshot(0x4000_000f..0x4000_0015).unwrap(),
+            [0, 1, 2, 3, 4, 0]
+        );
+        assert!(mem.snapshot(0x4000_0ff0..0x4000_1010).is_err());
+        assert!(mem.snapshot(0x4000_0000..u64::MAX).is_err());
+        assert!(mem.compare(u64::MAX, &[0, 0]).is_err());
+
+        mem.compare(0x4000_0010, &[1, 2, 3, 4]).unwrap();
+        let err = mem.compare(0x4000_0010, &[1, 9, 3, 9]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Guest memory at 0x40000010-0x40000014 differs in 2 of 4 bytes \
+             (first at 0x40000011: got 0x02, expected 0x09)"
+        );
+    }
+
+    /// マッピングしたサイズを記録する VM
+    struct RecordingVm(Mutex<Vec<(u64, usize)>>);
+
+    impl VmBackend for RecordingVm {
+        fn create(&self) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn destroy(&self) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn map(&self, _: *const u8, guest_addr: u64, size: usize) -> Result<(), Box<dyn Error>> {
+            self.0.lock().unwrap().push((guest_addr, size));
+            Ok(())
+        }
+
+        fn unmap(&self, guest_addr: u64, size: usize) -> Result<(), Box<dyn Error>> {
+            self.0.lock().unwrap().push((guest_addr, size));
+            Ok(())
+        }
+
+        fn protect(&self, _: u64, _: usize, _: bool) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn capabilities(
+            &self,
+        ) -> Result<crate::host_capabilities::HostCapabilities, Box<dyn Error>> {
+            crate::backend::MockVm.capabilities()
+        }
+    }
+
+    #[test]
+    fn 大きなページの切り上げた余りはゲストにマッピングしない() {
+        static VM: RecordingVm = RecordingVm(Mutex::new(Vec::new()));
+        let size = SUPERPAGE_SIZE + applevisor::PAGE_SIZE;
+        let mut ram = GuestRam::with_huge_pages(size).unwrap();
+        ram.map(&VM, 0x4000_0000).unwrap();
+        assert!(ram
+            .set_writable(0x4000_0000 + size as u64, applevisor::PAGE_SIZE, false)
+            .is_err());
+        ram.unmap().unwrap();
+        assert_eq!(
+            *VM.0.lock().unwrap(),
+            [(0x4000_0000, size), (0x4000_0000, size)]
+        );
+    }
+
+    fn mapped_ram() -> GuestRam {
+        let mut ram = GuestRam::new(0x4000).unwrap();
+        ram.map(&crate::backend::MockVm, 0x4000_0000).unwrap();
+        ram
+    }
+
+    #[test]
+    fn 読み取り専用のファイルへの書き込みはファイルに届かない() {
+        let path = std::env::temp_dir().join("hv-map-file-test");
+        std::fs::write(&path, vec![0x11; applevisor::PAGE_SIZE]).unwrap();
+
+        let mut ram = GuestRam::map_file(&path, true).unwrap();
+        assert_eq!(ram.backing(), RamBacking::PrivateFile);
+        assert_eq!(ram.get_size(), applevisor::PAGE_SIZE);
+        ram.map(&crate::backend::MockVm, 0x1_0000_0000).unwrap();
+        assert_eq!(ram.read_u8(0x1_0000_0000).unwrap(), 0x11);
+        ram.write_u8(0x1_0000_0000, 0x22).unwrap();
+        assert_eq!(ram.read_u8(0x1_0000_0000).unwrap(), 0x22);
+        assert_eq!(std::fs::read(&path).unwrap()[0], 0x11);
+
+        let mut ram = GuestRam::map_file(&path, false).unwrap();
+        ram.map(&crate::backend::MockVm, 0x1_0000_0000).unwrap();
+        ram.write_u8(0x1_0000_0000, 0x33).unwrap();
+        drop(ram);
+        assert_eq!(std::fs::read(&path).unwrap()[0], 0x33);
+
+        // ファイルの大きさはページの倍数
+        std::fs::write(&path, [0; 0x1000]).unwrap();
+        assert!(GuestRam::map_file(&path, true).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn 無効化の後に取得したスライスは使える() {
+        let ram = mapped_ram();
+        ram.write_slice(&[1, 2], 0x4000_0000).unwrap();
+        ram.invalidate_accessors();
+        let slice = ram.get_slice(0x4000_0000, 2).unwrap();
+        let mut buf = [0; 2];
+        slice.subslice(0, 2).unwrap().copy_to(&mut buf);
+        assert_eq!(buf, [1, 2]);
+
+        // 解除後は新しいスライスも取得できない
+        ram.unmap().unwrap();
+        assert!(ram.get_slice(0x4000_0000, 2).is_err());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "stale guest memory access")]
+    fn 解除をまたいで保持したスライスへのアクセスは_panic_する() {
+        let ram = mapped_ram();
+        let slice = ram.get_slice(0x4000_0000, 4).unwrap();
+        ram.unmap().unwrap();
+        slice.copy_from(&[0xff; 4]);
+    }
+
+    #[test]
+    fn mmap_aligned_は_2mb_境界のアドレスを返す() {
+        let addr = mmap_aligned(SUPERPAGE_SIZE, SUPERPAGE_SIZE).unwrap();
+        assert_eq!(addr as usize % SUPERPAGE_SIZE, 0);
+        unsafe {
+            // 確保した領域全体に書き込める
+            *addr = 0x55;
+            *addr.add(SUPERPAGE_SIZE - 1) = 0xAA;
+            libc::munmap(addr as *mut c_void, SUPERPAGE_SIZE);
+        }
+    }
+
+    #[test]
+    fn 匿名メモリはゼロ初期化されている() {
+        let addr = mmap_anonymous(0x4000, -1).unwrap();
+        let bytes = unsafe { std::slice::from_raw_parts(addr, 0x4000) };
+        assert!(bytes.iter().all(|&b| b == 0));
+        unsafe {
+            libc::munmap(addr as *mut c_void, 0x4000);
+        }
+    }
+
+    #[test]
+    fn guest_offset_は範囲内のアドレスを変換する() {
+        assert_eq!(
+            guest_offset(0x4000_0000, 0x4000, 0x4000_0000, 4).unwrap(),
+            0
+        );
+        assert_eq!(
+            guest_offset(0x4000_0000, 0x4000, 0x4000_3ff8, 8).unwrap(),
+            0x3ff8
+        );
+    }
+
+    #[test]
+    fn guest_offset_は範囲外のアクセスを拒否する() {
+        // 末尾をまたぐ
+        assert!(guest_offset(0x4000_0000, 0x4000, 0x4000_3ffc, 8).is_err());
+        // 先頭より前
+        assert!(guest_offset(0x4000_0000, 0x4000, 0x3fff_fffc, 4).is_err());
+        // オーバーフロー
+        assert!(guest_offset(0x4000_0000, 0x4000, u64::MAX, 4).is_err());
+    }
+
+    #[test]
+    fn 触れたページだけが常駐する() {
+        let size = 0x100_0000;
+        let addr = mmap_anonymous(size, -1).unwrap();
+        assert_eq!(resident_bytes(addr, size), Some(0));
+
+        unsafe {
+            *addr = 1;
+        }
+        let resident = resident_bytes(addr, size).unwrap();
+        assert!(resident > 0 && resident < size);
+
+        unsafe {
+            libc::munmap(addr as *mut c_void, size);
+        }
+    }
+
+    #[test]
+    fn read_obj_で_write_obj_の値を読み戻せる() {
+        let mem = TestMemory::new(0x4000_0000, 0x1000);
+        // アラインされていないアドレスでも読み書きできる
+        mem.write_obj(0x1122_3344_5566_7788u64, 0x4000_0003)
+            .unwrap();
+        assert_eq!(
+            mem.read_obj::<u64>(0x4000_0003).unwrap(),
+            0x1122_3344_5566_7788
+        );
+        assert_eq!(mem.read_obj::<u8>(0x4000_0003).unwrap(), 0x88);
+        assert_eq!(
+            mem.read_obj::<[u16; 2]>(0x4000_0003).unwrap(),
+            [0x7788, 0x5566]
+        );
+    }
+
+    #[test]
+    fn 整数はリトルエンディアンで読み書きする() {
+        let mem = TestMemory::new(0x4000_0000, 0x100);
+        mem.write_u32(0x4000_0010, 0x1122_3344).unwrap();
+        let mut bytes = [0u8; 4];
+        mem.read_slice(&mut bytes, 0x4000_0010).unwrap();
+        assert_eq!(bytes, [0x44, 0x33, 0x22, 0x11]);
+        // アラインしていないアドレスも読める
+        assert_eq!(mem.read_u16(0x4000_0011).unwrap(), 0x2233);
+        assert_eq!(mem.read_u8(0x4000_0013).unwrap(), 0x11);
+
+        mem.write_u64(0x4000_0020, 0x0102_0304_0506_0708).unwrap();
+        mem.write_u8(0x4000_0020, 0xff).unwrap();
+        mem.write_u16(0x4000_0026, 0xaabb).unwrap();
+        assert_eq!(mem.read_u64(0x4000_0020).unwrap(), 0xaabb_0304_0506_07ff);
+        assert_eq!(mem.read_u32(0x4000_0024).unwrap(), 0xaabb_0304);
+
+        // 末尾をまたぐアクセスはエラー
+        assert!(mem.read_u64(0x4000_00fc).is_err());
+        assert!(mem.write_u32(0x4000_00fe, 0).is_err());
+    }
+
+    #[test]
+    fn 範囲外の_guest_memory_アクセスはエラーになる() {
+        let mem = TestMemory::new(0x4000_0000, 0x1000);
+        assert!(mem.check_range(0x4000_0ffc, 4));
+        assert!(!mem.check_range(0x4000_0ffc, 8));
+        assert!(mem.read_obj::<u64>(0x4000_0ffc).is_err());
+        assert!(mem.write_obj(0u32, 0x3fff_fffe).is_err());
+        assert!(mem.write_slice(&[0; 0x1001], 0x4000_0000).is_err());
+    }
+
+    #[test]
+    fn dyn_guest_memory_経由で読み書きできる() {
+        let mem: std::sync::Arc<dyn GuestMemory> =
+            std::sync::Arc::new(TestMemory::new(0x4000_0000, 0x1000));
+        mem.write_slice(b"virtio", 0x4000_0100).unwrap();
+        let mut buf = [0u8; 6];
+        mem.read_slice(&mut buf, 0x4000_0100).unwrap();
+        assert_eq!(&buf, b"virtio");
+        assert_eq!(mem.read_obj::<u32>(0x4000_0100).unwrap(), 0x7472_6976);
+    }
+
+    #[test]
+    fn subslice_は元の範囲内に制限される() {
+        let mem = TestMemory::new(0x4000_0000, 0x1000);
+        let slice = mem.get_slice(0x4000_0000, 0x100).unwrap();
+        let sub = slice.subslice(0x80, 0x80).unwrap();
+        assert_eq!(sub.len(), 0x80);
+        assert_eq!(sub.copy_from(&[0xAB; 0x100]), 0x80);
+        assert_eq!(mem.read_obj::<u8>(0x4000_00ff).unwrap(), 0xAB);
+        assert_eq!(mem.read_obj::<u8>(0x4000_0100).unwrap(), 0);
+        assert!(slice.subslice(0x80, 0x81).is_err());
+        assert!(slice.subslice(usize::MAX, 2).is_err());
+    }
+}
@@ -0,0 +1,961 @@
+//! ライブマイグレーション (実験的)
+//!
+//! 一時停止した VM を、同じホスト上の別プロセスに移すためのストリーム形式。
+//! 送信側は `Hypervisor::precopy_ram` で RAM を送りながらゲストを動かし続け、
+//! 最後に `Hypervisor::migrate_out` で残りの変更ページ・vCPU・デバイスの状態を送る。
+//! 受信側は同じ RAM 配置とデバイス構成で作成した Hypervisor で `migrate_in` する。
+//!
+//! # ストリーム形式
+//!
+//! 数値はすべてリトルエンディアン。
+//!
+//! ヘッダーの後は、タグ (u8)・本体の長さ (u64)・本体が並ぶセクションの列になる。
+//!
+//! | 部分 | 内容 |
+//! |------|------|
+//! | ヘッダー | magic `HVMIGR\0\0`, version (u32), RAM base (u64), RAM size (u64), page size (u32) |
+//! | RAM セクション (tag 1) | ページ数 (u64) と、ページごとに index (u64), 種別 (u8: 0 = ゼロ, 1 = データ), データ |
+//! | vCPU セクション (tag 2) | [`VcpuState`] |
+//! | デバイスセクション (tag 3) | 名前 (u32 長 + UTF-8), base (u64) と [`DeviceState::save_state`] の内容 |
+//! | メモリ領域セクション (tag 4) | 領域の base (u64), size (u64) と、RAM セクションと同じ形式のページ (index は領域の先頭から) |
+//! | 終端 (tag 0xff) | (本体なし) |
+//!
+//! RAM セクションは複数回現れてよく、後のものが前のものを上書きする。
+//! メモリ領域セクションは RAM 以外の領域 (メモリで裏付けた MMIO 領域など) を運び、
+//! 受信側に同じ base と size の領域がなければエラーになる。
+//!
+//! # 互換性
+//!
+//! 読み込み側は知らないタグのセクションを長さを使って読み飛ばすため、新しい版で
+//! セクションの種類が増えても古い版で読める。デバイスは新しいフィールドを状態の末尾に
+//! 追加し、[`StateDecoder::optional`] で読むことで、古い版で保存した状態も復元できる。
+//!
+//! [`MIN_MIGRATION_VERSION`] 以降のストリームを読める。バージョン 2 はセクションに
+//! 長さがなく (vCPU とデバイスの状態だけが長さを持つ)、未知のタグは読み飛ばせない。
+//!
+//! # 変更ページの追跡
+//!
+//! Hypervisor.framework には KVM の dirty log に相当する API がないため、
+//! [`DirtyLog`] は前回読んだ RAM の複製とページごとに比較して変更を検出する。
+//! 比較のたびに RAM 全体を読み、RAM と同じ大きさの複製を持つが、書き込み保護による
+//! トラップは発生しない。
+
+use crate::memory::GuestMemory;
+use applevisor::SysReg;
+use std::error::Error;
+use std::io::{Read, Write};
+
+/// ストリームの先頭
+pub const MIGRATION_MAGIC: [u8; 8] = *b"HVMIGR\0\0";
+/// ストリーム形式のバージョン
+pub const MIGRATION_VERSION: u32 = 3;
+/// 読み込める最も古いストリーム形式のバージョン
+pub const MIN_MIGRATION_VERSION: u32 = 2;
+/// 転送と変更追跡の単位
+pub const PAGE_SIZE: usize = 0x1000;
+
+const TAG_RAM: u8 = 1;
+const TAG_VCPU: u8 = 2;
+const TAG_DEVICE: u8 = 3;
+const TAG_REGION: u8 = 4;
+const TAG_END: u8 = 0xff;
+
+const PAGE_ZERO: u8 = 0;
+const PAGE_DATA: u8 = 1;
+
+/// 1 つの RAM セクションに入れる最大ページ数 (本体をメモリ上で組み立てるため)
+const RAM_SECTION_PAGES: usize = 256;
+
+/// 移送する EL1/EL0 のシステムレジスタ
+///
+/// ID レジスタはホストの値が見えるため含めない。
+pub const MIGRATED_SYS_REGS: [SysReg; 24] = crate::GUEST_SYS_REGS;
+
+/// 保存・復元できるデバイスの状態
+///
+/// 形式はデバイスごとに自由だが、古い版で保存した状態も読めるように、
+/// フィールドを追加するときは末尾に置き [`StateDecoder::optional`] で読む。
+/// [`StateEncoder`] / [`StateDecoder`] を使うと長さの検証が簡単になる。
+pub trait DeviceState {
+    /// 現在の状態をバイト列にする
+    fn save_state(&self) -> Vec<u8>;
+
+    /// `save_state` の結果から状態を戻す
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn Error>>;
+}
+
+/// デバイス状態の書き込み
+#[derive(Debug, Default)]
+pub struct StateEncoder {
+    buf: Vec<u8>,
+}
+
+impl StateEncoder {
+    /// 空のエンコーダを作成
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// u8 を追加
+    pub fn u8(mut self, value: u8) -> Self {
+        self.buf.push(value);
+        self
+    }
+
+    /// bool を追加
+    pub fn bool(self, value: bool) -> Self {
+        self.u8(value as u8)
+    }
+
+    /// u32 を追加
+    pub fn u32(mut self, value: u32) -> Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// u64 を追加
+    pub fn u64(mut self, value: u64) -> Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// u128 を追加
+    pub fn u128(mut self, value: u128) -> Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// バイト列をそのまま追加
+    pub fn bytes(mut self, data: &[u8]) -> Self {
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    /// 書き込んだバイト列
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// デバイス状態の読み取り
+pub struct StateDecoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateDecoder<'a> {
+    /// バイト列から読み取りを始める
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// 先頭から len バイト取り出す
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if self.data.len() < len {
+            return Err(format!(
+                "Truncated device state: need {} more bytes, have {}",
+                len,
+                self.data.len()
+            )
+            .into());
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    /// u8 を読む
+    pub fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// bool を読む
+    pub fn bool(&mut self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.u8()? != 0)
+    }
+
+    /// u32 を読む
+    pub fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// u64 を読む
+    pub fn u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// u128 を読む
+    pub fn u128(&mut self) -> Result<u128, Box<dyn Error>> {
+        Ok(u128::from_le_bytes(self.bytes(16)?.try_into().unwrap()))
+    }
+
+    /// 後の版で末尾に追加したフィールドを読む
+    ///
+    /// 残りがなければ (追加前の版で保存された状態なら) `None` を返す。
+    pub fn optional<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T, Box<dyn Error>>,
+    ) -> Result<Option<T>, Box<dyn Error>> {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+        read(self).map(Some)
+    }
+
+    /// すべて読み終えたことを確認する
+    pub fn finish(self) -> Result<(), Box<dyn Error>> {
+        if !self.data.is_empty() {
+            return Err(format!(
+                "{} unexpected trailing bytes in device state",
+                self.data.len()
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// vCPU のレジスタ状態
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VcpuState {
+    /// X0-X30
+    pub gprs: [u64; 31],
+    /// PC
+    pub pc: u64,
+    /// CPSR (PSTATE)
+    pub cpsr: u64,
+    /// FPCR
+    pub fpcr: u64,
+    /// FPSR
+    pub fpsr: u64,
+    /// ゲストから見た仮想カウンタ (CNTVCT_EL0)
+    ///
+    /// 受信側で vtimer offset を合わせ、ゲストの時刻が巻き戻らないようにする。
+    pub virtual_counter: u64,
+    /// [`MIGRATED_SYS_REGS`] の順のシステムレジスタ
+    pub sys_regs: Vec<u64>,
+    /// Q0-Q31
+    pub simd: Vec<u128>,
+}
+
+impl VcpuState {
+    /// バイト列にする
+    pub fn encode(&self) -> Vec<u8> {
+        let mut enc = StateEncoder::new();
+        for &r in &self.gprs {
+            enc = enc.u64(r);
+        }
+        enc = enc
+            .u64(self.pc)
+            .u64(self.cpsr)
+            .u64(self.fpcr)
+            .u64(self.fpsr)
+            .u64(self.virtual_counter);
+        enc = enc.u32(self.sys_regs.len() as u32);
+        for &r in &self.sys_regs {
+            enc = enc.u64(r);
+        }
+        enc = enc.u32(self.simd.len() as u32);
+        for &q in &self.simd {
+            enc = enc.u128(q);
+        }
+        enc.finish()
+    }
+
+    /// `encode` の結果から戻す
+    pub fn decode(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut dec = StateDecoder::new(data);
+        let mut gprs = [0u64; 31];
+        for r in gprs.iter_mut() {
+            *r = dec.u64()?;
+        }
+        let (pc, cpsr, fpcr, fpsr) = (dec.u64()?, dec.u64()?, dec.u64()?, dec.u64()?);
+        let virtual_counter = dec.u64()?;
+        let count = dec.u32()? as usize;
+        if count != MIGRATED_SYS_REGS.len() {
+            return Err(format!(
+                "vCPU state has {} system registers, expected {}",
+                count,
+                MIGRATED_SYS_REGS.len()
+            )
+            .into());
+        }
+        let sys_regs = (0..count).map(|_| dec.u64()).collect::<Result<_, _>>()?;
+        let count = dec.u32()? as usize;
+        if count != 32 {
+            return Err(format!("vCPU state has {} SIMD registers, expected 32", count).into());
+        }
+        let simd = (0..count).map(|_| dec.u128()).collect::<Result<_, _>>()?;
+        dec.finish()?;
+        Ok(Self {
+            gprs,
+            pc,
+            cpsr,
+            fpcr,
+            fpsr,
+            virtual_counter,
+            sys_regs,
+            simd,
+        })
+    }
+}
+
+/// RAM の変更ページの追跡
+///
+/// 前回の `collect` で読んだ RAM の複製とページごとに比較するため、変更を
+/// 見落とさない。その代わり追跡中はゲスト RAM と同じ大きさのメモリを使う。
+#[derive(Debug, Clone, Default)]
+pub struct DirtyLog {
+    /// 前回読んだ RAM の内容 (未収集なら空)
+    shadow: Vec<u8>,
+}
+
+impl DirtyLog {
+    /// 追跡を開始する (最初の `collect` は全ページを返す)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 前回の `collect` 以降に変更されたページの番号を返す
+    pub fn collect(
+        &mut self,
+        mem: &dyn GuestMemory,
+        base: u64,
+        size: usize,
+    ) -> Result<Vec<u64>, Box<dyn Error>> {
+        let first = self.shadow.len() != size;
+        if first {
+            self.shadow = vec![0; size];
+        }
+        let mut dirty = Vec::new();
+        let mut page = vec![0u8; PAGE_SIZE];
+        for index in 0..size.div_ceil(PAGE_SIZE) {
+            let offset = index * PAGE_SIZE;
+            let len = PAGE_SIZE.min(size - offset);
+            mem.read_slice(&mut page[..len], base + offset as u64)?;
+            let saved = &mut self.shadow[offset..offset + len];
+            if first || saved != &page[..len] {
+                saved.copy_from_slice(&page[..len]);
+                dirty.push(index as u64);
+            }
+        }
+        Ok(dirty)
+    }
+}
+
+/// ストリームのヘッダーを書き込む
+pub fn write_header(
+    w: &mut dyn Write,
+    ram_base: u64,
+    ram_size: usize,
+) -> Result<(), Box<dyn Error>> {
+    w.write_all(&MIGRATION_MAGIC)?;
+    w.write_all(&MIGRATION_VERSION.to_le_bytes())?;
+    w.write_all(&ram_base.to_le_bytes())?;
+    w.write_all(&(ram_size as u64).to_le_bytes())?;
+    w.write_all(&(PAGE_SIZE as u32).to_le_bytes())?;
+    Ok(())
+}
+
+/// RAM セクションを書き込む
+///
+/// # Returns
+/// データ付きで送ったページ数 (ゼロページは種別だけを送る)
+pub fn write_ram(
+    w: &mut dyn Write,
+    mem: &dyn GuestMemory,
+    ram_base: u64,
+    ram_size: usize,
+    pages: &[u64],
+) -> Result<usize, Box<dyn Error>> {
+    write_pages(w, TAG_RAM, &[], mem, ram_base, ram_size, pages)
+}
+
+/// RAM 以外のメモリ領域のセクションを書き込む
+///
+/// `pages` は領域の先頭からのページ番号。空でも領域の配置だけは送る。
+///
+/// # Returns
+/// データ付きで送ったページ数
+pub fn write_region(
+    w: &mut dyn Write,
+    mem: &dyn GuestMemory,
+    base: u64,
+    size: usize,
+    pages: &[u64],
+) -> Result<usize, Box<dyn Error>> {
+    let mut header = base.to_le_bytes().to_vec();
+    header.extend_from_slice(&(size as u64).to_le_bytes());
+    if pages.is_empty() {
+        header.extend_from_slice(&0u64.to_le_bytes());
+        write_section(w, TAG_REGION, &header)?;
+        return Ok(0);
+    }
+    write_pages(w, TAG_REGION, &header, mem, base, size, pages)
+}
+
+/// `header` に続けてページを並べたセクションを書き込む
+fn write_pages(
+    w: &mut dyn Write,
+    tag: u8,
+    header: &[u8],
+    mem: &dyn GuestMemory,
+    ram_base: u64,
+    ram_size: usize,
+    pages: &[u64],
+) -> Result<usize, Box<dyn Error>> {
+    let mut page = vec![0u8; PAGE_SIZE];
+    let mut sent = 0;
+    for chunk in pages.chunks(RAM_SECTION_PAGES) {
+        let mut body = header.to_vec();
+        body.extend_from_slice(&(chunk.len() as u64).to_le_bytes());
+        for &index in chunk {
+            let offset = index as usize * PAGE_SIZE;
+            let len = PAGE_SIZE.min(ram_size.saturating_sub(offset));
+            page[len..].fill(0);
+            mem.read_slice(&mut page[..len], ram_base + offset as u64)?;
+            body.extend_from_slice(&index.to_le_bytes());
+            if page.iter().all(|&b| b == 0) {
+                body.push(PAGE_ZERO);
+            } else {
+                body.push(PAGE_DATA);
+                body.extend_from_slice(&page);
+                sent += 1;
+            }
+        }
+        write_section(w, tag, &body)?;
+    }
+    Ok(sent)
+}
+
+/// vCPU セクションを書き込む
+pub fn write_vcpu(w: &mut dyn Write, state: &VcpuState) -> Result<(), Box<dyn Error>> {
+    write_section(w, TAG_VCPU, &state.encode())
+}
+
+/// デバイスセクションを書き込む
+pub fn write_device(
+    w: &mut dyn Write,
+    name: &str,
+    base: u64,
+    state: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let mut body = (name.len() as u32).to_le_bytes().to_vec();
+    body.extend_from_slice(name.as_bytes());
+    body.extend_from_slice(&base.to_le_bytes());
+    body.extend_from_slice(state);
+    write_section(w, TAG_DEVICE, &body)
+}
+
+/// 終端を書き込む
+pub fn write_end(w: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    write_section(w, TAG_END, &[])?;
+    w.flush()?;
+    Ok(())
+}
+
+/// タグと長さ付きのセクションを書き込む
+///
+/// 読み込み側が知らないタグは長さを使って読み飛ばされる。
+pub fn write_section(w: &mut dyn Write, tag: u8, body: &[u8]) -> Result<(), Box<dyn Error>> {
+    w.write_all(&[tag])?;
+    w.write_all(&(body.len() as u64).to_le_bytes())?;
+    w.write_all(body)?;
+    Ok(())
+}
+
+/// 受信したデバイスの状態
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedDevice {
+    /// デバイス名 (`MmioHandler::name`)
+    pub name: String,
+    /// MMIO ベースアドレス
+    pub base: u64,
+    /// [`DeviceState::save_state`] の内容
+    pub state: Vec<u8>,
+}
+
+/// 受信した RAM 以外の状態
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IncomingState {
+    /// vCPU の状態
+    pub vcpu: Option<VcpuState>,
+    /// デバイスの状態
+    pub devices: Vec<SavedDevice>,
+    /// 書き込んだページ数 (重複を含む)
+    pub pages: usize,
+    /// ストリームの形式のバージョン
+    pub version: u32,
+    /// 読み飛ばした未知のセクションのタグ (出現順)
+    pub skipped_sections: Vec<u8>,
+}
+
+/// ストリームを読み、RAM ページは `mem` に、メモリ領域のページは `regions` に書き込む
+///
+/// ヘッダーの RAM 配置が `ram_base` / `ram_size` と一致しない場合と、
+/// `regions` (base, size, メモリ) に同じ配置のないメモリ領域セクションがある場合は
+/// エラーを返す。未知のタグのセクションは読み飛ばし、
+/// [`IncomingState::skipped_sections`] に記録する。
+pub fn read_stream(
+    r: &mut dyn Read,
+    mem: &dyn GuestMemory,
+    ram_base: u64,
+    ram_size: usize,
+    regions: &[(u64, usize, &dyn GuestMemory)],
+) -> Result<IncomingState, Box<dyn Error>> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if magic != MIGRATION_MAGIC {
+        return Err("Not a migration stream (bad magic)".into());
+    }
+    let version = read_u32(r)?;
+    if !(MIN_MIGRATION_VERSION..=MIGRATION_VERSION).contains(&version) {
+        return Err(format!(
+            "Unsupported migration stream version {} (expected {} to {})",
+            version, MIN_MIGRATION_VERSION, MIGRATION_VERSION
+        )
+        .into());
+    }
+    let (base, size, page_size) = (read_u64(r)?, read_u64(r)?, read_u32(r)?);
+    if base != ram_base || size != ram_size as u64 || page_size as usize != PAGE_SIZE {
+        return Err(format!(
+            "Migration stream RAM 0x{:x} (+0x{:x}, {}-byte pages) does not match this VM's RAM 0x{:x} (+0x{:x})",
+            base, size, page_size, ram_base, ram_size
+        )
+        .into());
+    }
+
+    let mut incoming = IncomingState {
+        version,
+        ..Default::default()
+    };
+    loop {
+        let mut tag = [0u8; 1];
+        r.read_exact(&mut tag)?;
+        if version < 3 {
+            // セクションに長さがない旧形式
+            match tag[0] {
+                TAG_RAM => read_pages(r, mem, ram_base, ram_size, &mut incoming)?,
+                TAG_VCPU => incoming.vcpu = Some(VcpuState::decode(&read_vec(r)?)?),
+                TAG_DEVICE => {
+                    let (name, base) = read_device_header(r)?;
+                    let state = read_vec(r)?;
+                    incoming.devices.push(SavedDevice { name, base, state });
+                }
+                TAG_END => return Ok(incoming),
+                other => return Err(format!("Unknown migration section tag 0x{:x}", other).into()),
+            }
+            continue;
+        }
+
+        let len = read_u64(r)?;
+        if !matches!(
+            tag[0],
+            TAG_RAM | TAG_VCPU | TAG_DEVICE | TAG_REGION | TAG_END
+        ) {
+            // 新しい版のセクションは大きくても読み捨てるだけにする
+            if std::io::copy(&mut r.take(len), &mut std::io::sink())? != len {
+                return Err("Truncated migration stream".into());
+            }
+            incoming.skipped_sections.push(tag[0]);
+            continue;
+        }
+        let body = read_exact_vec(r, len)?;
+        let mut reader = body.as_slice();
+        match tag[0] {
+            TAG_RAM => {
+                read_pages(&mut reader, mem, ram_base, ram_size, &mut incoming)?;
+                if !reader.is_empty() {
+                    return Err(format!(
+                        "{} unexpected trailing bytes in RAM section",
+                        reader.len()
+                    )
+                    .into());
+                }
+            }
+            TAG_VCPU => incoming.vcpu = Some(VcpuState::decode(&body)?),
+            TAG_DEVICE => {
+                let (name, base) = read_device_header(&mut reader)?;
+                let state = reader.to_vec();
+                incoming.devices.push(SavedDevice { name, base, state });
+            }
+            TAG_REGION => {
+                let (base, size) = (read_u64(&mut reader)?, read_u64(&mut reader)?);
+                let &(_, size, region) = regions
+                    .iter()
+                    .find(|&&(b, s, _)| b == base && s as u64 == size)
+                    .ok_or_else(|| {
+                        format!(
+                            "Migration stream has a memory region at 0x{:x} (+0x{:x}) that this VM does not have",
+                            base, size
+                        )
+                    })?;
+                read_pages(&mut reader, region, base, size, &mut incoming)?;
+                if !reader.is_empty() {
+                    return Err(format!(
+                        "{} unexpected trailing bytes in memory region section",
+                        reader.len()
+                    )
+                    .into());
+                }
+            }
+            TAG_END => return Ok(incoming),
+            other => unreachable!("section tag 0x{:x} was skipped above", other),
+        }
+    }
+}
+
+/// RAM セクションのページを `mem` に書き込む
+fn read_pages(
+    r: &mut dyn Read,
+    mem: &dyn GuestMemory,
+    ram_base: u64,
+    ram_size: usize,
+    incoming: &mut IncomingState,
+) -> Result<(), Box<dyn Error>> {
+    let mut page = vec![0u8; PAGE_SIZE];
+    for _ in 0..read_u64(r)? {
+        let index = read_u64(r)?;
+        let mut kind = [0u8; 1];
+        r.read_exact(&mut kind)?;
+        match kind[0] {
+            PAGE_ZERO => page.fill(0),
+            PAGE_DATA => r.read_exact(&mut page)?,
+            other => return Err(format!("Unknown page kind {}", other).into()),
+        }
+        let offset = usize::try_from(index)
+            .ok()
+            .and_then(|index| index.checked_mul(PAGE_SIZE))
+            .filter(|&offset| offset < ram_size)
+            .ok_or_else(|| format!("Page {} is outside guest RAM", index))?;
+        let len = PAGE_SIZE.min(ram_size - offset);
+        mem.write_slice(&page[..len], ram_base + offset as u64)?;
+        incoming.pages += 1;
+    }
+    Ok(())
+}
+
+/// デバイスセクションの名前とベースアドレスを読む
+fn read_device_header(r: &mut dyn Read) -> Result<(String, u64), Box<dyn Error>> {
+    let name_len = read_u32(r)? as usize;
+    let mut name = Vec::new();
+    r.take(name_len as u64).read_to_end(&mut name)?;
+    if name.len() != name_len {
+        return Err("Truncated migration stream".into());
+    }
+    Ok((String::from_utf8(name)?, read_u64(r)?))
+}
+
+fn read_u32(r: &mut dyn Read) -> Result<u32, Box<dyn Error>> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut dyn Read) -> Result<u64, Box<dyn Error>> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// 長さ (u64) 付きのバイト列を読む
+fn read_vec(r: &mut dyn Read) -> Result<Vec<u8>, Box<dyn Error>> {
+    let len = read_u64(r)?;
+    read_exact_vec(r, len)
+}
+
+fn read_exact_vec(r: &mut dyn Read, len: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data = Vec::new();
+    r.take(len).read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return Err("Truncated migration stream".into());
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::testing::TestMemory;
+
+    const BASE: u64 = 0x4000_0000;
+    const SIZE: usize = 4 * PAGE_SIZE;
+
+    fn vcpu_state() -> VcpuState {
+        let mut gprs = [0u64; 31];
+        gprs[0] = 0x4800_0000;
+        VcpuState {
+            gprs,
+            pc: 0x4008_0000,
+            cpsr: 0x3c5,
+            fpcr: 0,
+            fpsr: 0x10,
+            virtual_counter: 0x1234_5678,
+            sys_regs: (0..MIGRATED_SYS_REGS.len() as u64).collect(),
+            simd: (0..32u128).map(|q| q << 64).collect(),
+        }
+    }
+
+    #[test]
+    fn 変更されたページだけを検出する() {
+        let mem = TestMemory::new(BASE, SIZE);
+        let mut log = DirtyLog::new();
+        assert_eq!(log.collect(&mem, BASE, SIZE).unwrap(), [0, 1, 2, 3]);
+        assert!(log.collect(&mem, BASE, SIZE).unwrap().is_empty());
+
+        mem.write_slice(&[1], BASE + 2 * PAGE_SIZE as u64 + 7)
+            .unwrap();
+        assert_eq!(log.collect(&mem, BASE, SIZE).unwrap(), [2]);
+    }
+
+    #[test]
+    fn ram_vcpu_デバイスを送受信できる() {
+        let src = TestMemory::new(BASE, SIZE);
+        src.write_slice(b"kernel", BASE).unwrap();
+        src.write_slice(b"dtb", BASE + 3 * PAGE_SIZE as u64)
+            .unwrap();
+
+        let mut stream = Vec::new();
+        let mut log = DirtyLog::new();
+        write_header(&mut stream, BASE, SIZE).unwrap();
+        let pages = log.collect(&src, BASE, SIZE).unwrap();
+        // ゼロページはデータを送らない
+        assert_eq!(write_ram(&mut stream, &src, BASE, SIZE, &pages).unwrap(), 2);
+
+        // ゲストが動き続けて 1 ページ変更した後の 2 回目
+        src.write_slice(b"KERNEL", BASE).unwrap();
+        let pages = log.collect(&src, BASE, SIZE).unwrap();
+        assert_eq!(pages, [0]);
+        write_ram(&mut stream, &src, BASE, SIZE, &pages).unwrap();
+
+        write_vcpu(&mut stream, &vcpu_state()).unwrap();
+        write_device(&mut stream, "pl011", 0x0900_0000, &[1, 2, 3]).unwrap();
+        write_end(&mut stream).unwrap();
+
+        let dst = TestMemory::new(BASE, SIZE);
+        dst.write_slice(&[0xff], BASE + PAGE_SIZE as u64).unwrap();
+        let incoming = read_stream(&mut stream.as_slice(), &dst, BASE, SIZE, &[]).unwrap();
+
+        assert_eq!(incoming.pages, 5);
+        let mut buf = [0u8; 6];
+        dst.read_slice(&mut buf, BASE).unwrap();
+        assert_eq!(&buf, b"KERNEL");
+        dst.read_slice(&mut buf[..3], BASE + 3 * PAGE_SIZE as u64)
+            .unwrap();
+        assert_eq!(&buf[..3], b"dtb");
+        // ゼロページも上書きされる
+        dst.read_slice(&mut buf[..1], BASE + PAGE_SIZE as u64)
+            .unwrap();
+        assert_eq!(buf[0], 0);
+
+        assert_eq!(incoming.vcpu, Some(vcpu_state()));
+        assert_eq!(
+            incoming.devices,
+            [SavedDevice {
+                name: "pl011".to_string(),
+                base: 0x0900_0000,
+                state: vec![1, 2, 3],
+            }]
+        );
+    }
+
+    #[test]
+    fn ram_配置が異なるストリームは拒否する() {
+        let mut stream = Vec::new();
+        write_header(&mut stream, BASE, SIZE).unwrap();
+        write_end(&mut stream).unwrap();
+
+        let dst = TestMemory::new(BASE, 2 * SIZE);
+        let err = read_stream(&mut stream.as_slice(), &dst, BASE, 2 * SIZE, &[]).unwrap_err();
+        assert!(err.to_string().contains("does not match"));
+
+        let err = read_stream(&mut &b"garbage!"[..], &dst, BASE, SIZE, &[]).unwrap_err();
+        assert!(err.to_string().contains("bad magic"));
+    }
+
+    #[test]
+    fn デバイス状態の長さを検証する() {
+        let state = StateEncoder::new().u32(7).bool(true).finish();
+        let mut dec = StateDecoder::new(&state);
+        assert_eq!(dec.u32().unwrap(), 7);
+        assert!(dec.bool().unwrap());
+        dec.finish().unwrap();
+
+        let mut dec = StateDecoder::new(&state);
+        assert!(dec.u64().is_err());
+
+        let mut dec = StateDecoder::new(&state);
+        dec.u8().unwrap();
+        assert!(dec.finish().is_err());
+
+        // 末尾に追加したフィールドは、古い状態にはなくても読める
+        let mut dec = StateDecoder::new(&state[..4]);
+        assert_eq!(dec.u32().unwrap(), 7);
+        assert_eq!(dec.optional(|dec| dec.bool()).unwrap(), None);
+        let mut dec = StateDecoder::new(&state);
+        dec.u32().unwrap();
+        assert_eq!(dec.optional(|dec| dec.bool()).unwrap(), Some(true));
+
+        let mut encoded = vcpu_state().encode();
+        encoded.pop();
+        assert!(VcpuState::decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn 未知のセクションは読み飛ばす() {
+        let src = TestMemory::new(BASE, SIZE);
+        src.write_slice(b"kernel", BASE).unwrap();
+
+        let mut stream = Vec::new();
+        write_header(&mut stream, BASE, SIZE).unwrap();
+        write_section(&mut stream, 0x40, b"from a newer version").unwrap();
+        write_ram(&mut stream, &src, BASE, SIZE, &[0]).unwrap();
+        write_device(&mut stream, "pl011", 0x0900_0000, &[1, 2, 3]).unwrap();
+        write_end(&mut stream).unwrap();
+
+        let dst = TestMemory::new(BASE, SIZE);
+        let incoming = read_stream(&mut stream.as_slice(), &dst, BASE, SIZE, &[]).unwrap();
+        assert_eq!(incoming.version, MIGRATION_VERSION);
+        assert_eq!(incoming.skipped_sections, [0x40]);
+        assert_eq!(incoming.pages, 1);
+        assert_eq!(incoming.devices[0].state, [1, 2, 3]);
+        let mut buf = [0u8; 6];
+        dst.read_slice(&mut buf, BASE).unwrap();
+        assert_eq!(&buf, b"kernel");
+
+        // 長さの足りないセクションは拒否する
+        stream.truncate(stream.len() - 10);
+        assert!(read_stream(&mut stream.as_slice(), &dst, BASE, SIZE, &[]).is_err());
+    }
+
+    #[test]
+    fn 大きな未知のセクションは読み込まずに捨てる() {
+        const HUGE: u64 = 64 << 20;
+        let mut head = Vec::new();
+        write_header(&mut head, BASE, SIZE).unwrap();
+        head.push(0x40);
+        head.extend_from_slice(&HUGE.to_le_bytes());
+        let mut tail = Vec::new();
+        write_end(&mut tail).unwrap();
+
+        let dst = TestMemory::new(BASE, SIZE);
+        let mut stream = head
+            .as_slice()
+            .chain(std::io::repeat(0).take(HUGE))
+            .chain(tail.as_slice());
+        let incoming = read_stream(&mut stream, &dst, BASE, SIZE, &[]).unwrap();
+        assert_eq!(incoming.skipped_sections, [0x40]);
+
+        // 宣言した長さに届かずに終わったストリームは拒否する
+        let mut stream = head.as_slice().chain(std::io::repeat(0).take(10));
+        let err = read_stream(&mut stream, &dst, BASE, SIZE, &[]).unwrap_err();
+        assert_eq!(err.to_string(), "Truncated migration stream");
+    }
+
+    #[test]
+    fn ram_以外のメモリ領域を送受信できる() {
+        const REGION: u64 = 0x1000_0000;
+        let ram = TestMemory::new(BASE, SIZE);
+        let src = TestMemory::new(REGION, 2 * PAGE_SIZE);
+        src.write_slice(b"framebuffer", REGION + PAGE_SIZE as u64)
+            .unwrap();
+
+        let mut stream = Vec::new();
+        write_header(&mut stream, BASE, SIZE).unwrap();
+        assert_eq!(
+            write_region(&mut stream, &src, REGION, 2 * PAGE_SIZE, &[0, 1]).unwrap(),
+            1
+        );
+        write_end(&mut stream).unwrap();
+
+        let dst = TestMemory::new(REGION, 2 * PAGE_SIZE);
+        let incoming = read_stream(
+            &mut stream.as_slice(),
+            &ram,
+            BASE,
+            SIZE,
+            &[(REGION, 2 * PAGE_SIZE, &dst)],
+        )
+        .unwrap();
+        assert_eq!(incoming.pages, 2);
+        let mut buf = [0u8; 11];
+        dst.read_slice(&mut buf, REGION + PAGE_SIZE as u64).unwrap();
+        assert_eq!(&buf, b"framebuffer");
+
+        // 受信側に同じ配置の領域がなければ拒否する
+        let err = read_stream(&mut stream.as_slice(), &ram, BASE, SIZE, &[]).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("memory region at 0x10000000 (+0x2000) that this VM does not have"));
+        let smaller = TestMemory::new(REGION, PAGE_SIZE);
+        assert!(read_stream(
+            &mut stream.as_slice(),
+            &ram,
+            BASE,
+            SIZE,
+            &[(REGION, PAGE_SIZE, &smaller)],
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn バージョン_2_のストリームを読める() {
+        let mut stream = MIGRATION_MAGIC.to_vec();
+        stream.extend_from_slice(&2u32.to_le_bytes());
+        stream.extend_from_slice(&BASE.to_le_bytes());
+        stream.extend_from_slice(&(SIZE as u64).to_le_bytes());
+        stream.extend_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+        // RAM セクションは長さを持たない
+        stream.push(TAG_RAM);
+        stream.extend_from_slice(&1u64.to_le_bytes());
+        stream.extend_from_slice(&2u64.to_le_bytes());
+        stream.push(PAGE_DATA);
+        stream.extend_from_slice(&[0xaa; PAGE_SIZE]);
+        let vcpu = vcpu_state().encode();
+        stream.push(TAG_VCPU);
+        stream.extend_from_slice(&(vcpu.len() as u64).to_le_bytes());
+        stream.extend_from_slice(&vcpu);
+        stream.push(TAG_DEVICE);
+        stream.extend_from_slice(&5u32.to_le_bytes());
+        stream.extend_from_slice(b"pl011");
+        stream.extend_from_slice(&0x0900_0000u64.to_le_bytes());
+        stream.extend_from_slice(&2u64.to_le_bytes());
+        stream.extend_from_slice(&[4, 5]);
+        stream.push(TAG_END);
+
+        let dst = TestMemory::new(BASE, SIZE);
+        let incoming = read_stream(&mut stream.as_slice(), &dst, BASE, SIZE, &[]).unwrap();
+        assert_eq!(incoming.version, 2);
+        assert_eq!(incoming.pages, 1);
+        assert_eq!(incoming.vcpu, Some(vcpu_state()));
+        assert_eq!(
+            incoming.devices,
+            [SavedDevice {
+                name: "pl011".to_string(),
+                base: 0x0900_0000,
+                state: vec![4, 5],
+            }]
+        );
+        let mut buf = [0u8; 1];
+        dst.read_slice(&mut buf, BASE + 2 * PAGE_SIZE as u64 + 9)
+            .unwrap();
+        assert_eq!(buf[0], 0xaa);
+
+        // 番号がオフセットとして溢れるページは拒否する
+        let mut overflow = stream.clone();
+        overflow[41..49].copy_from_slice(&u64::MAX.to_le_bytes());
+        let err = read_stream(&mut overflow.as_slice(), &dst, BASE, SIZE, &[]).unwrap_err();
+        assert!(err.to_string().contains("outside guest RAM"));
+
+        // 旧形式では未知のタグを読み飛ばせない
+        let pos = stream.len() - 1;
+        stream[pos] = 0x40;
+        let err = read_stream(&mut stream.as_slice(), &dst, BASE, SIZE, &[]).unwrap_err();
+        assert!(err.to_string().contains("Unknown migration section tag"));
+
+        stream[8..12].copy_from_slice(&1u32.to_le_bytes());
+        let err = read_stream(&mut stream.as_slice(), &dst, BASE, SIZE, &[]).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Unsupported migration stream version 1"));
+    }
+}
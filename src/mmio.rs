@@ -1,8 +1,48 @@
 //! MMIO (Memory-Mapped I/O) handling infrastructure
 
+use crate::boot::layout::IrqMap;
+use crate::devices::virtio::DmaValidator;
+#[cfg(feature = "snapshot")]
+use crate::migration::DeviceState;
+use crate::rate_limit::RateLimiter;
+use crate::stats::{BlockStats, DeviceStats, IoCounters, LatencyStats};
+use crate::trace::{Tracer, Track};
+use std::collections::VecDeque;
 use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// 書き込みをまとめる (coalesced MMIO) リングの容量
+///
+/// リングが一杯になった時点でまとめて処理する。
+pub const COALESCED_RING_SIZE: usize = 256;
+
+/// 未登録アドレスへのアクセスの警告を 1 秒あたりに出力する件数
+pub const UNHANDLED_WARNINGS_PER_SEC: u32 = 10;
+
+/// リングに記録された MMIO 書き込み
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoalescedWrite {
+    /// 書き込み先の絶対アドレス
+    pub addr: u64,
+    /// 書き込む値
+    pub value: u64,
+    /// 書き込みサイズ (bytes)
+    pub size: usize,
+}
 
 /// MMIO デバイスハンドラの trait
+///
+/// # DMA とキャッシュの一貫性
+///
+/// ゲスト RAM はホストの通常のメモリをそのままマップしているため、デバイスが
+/// ホスト側からゲスト RAM を読み書きしても CPU のキャッシュと常に一貫している。
+/// Device Tree の virtio-mmio ノードには `dma-coherent` を付けており、ゲストは
+/// DMA の前後にキャッシュのメンテナンスを行わない。ハンドラは書き込みの順序だけ
+/// 守ればよい (ディスクリプタより先にデータを書き、used リングは最後に更新する)。
+/// IOMMU はなく、デバイスが見るアドレスはゲスト物理アドレスそのもの (`dma-ranges` は 1:1)。
+/// SMMUv3 のスタブ ([`crate::devices::smmu`]) を登録しても、デバイスはその配下に置かない。
 pub trait MmioHandler: Send + Sync {
     /// デバイスのベースアドレスを返す
     fn base(&self) -> u64;
@@ -24,11 +64,245 @@ pub trait MmioHandler: Send + Sync {
     /// * `value` - 書き込む値
     /// * `size` - 書き込むサイズ (1, 2, 4, 8 bytes)
     fn write(&mut self, offset: u64, value: u64, size: usize) -> Result<(), Box<dyn Error>>;
+
+    /// デバイスを作成直後の状態に戻す (ゲストの再起動)
+    ///
+    /// [`Hypervisor::reboot`](crate::Hypervisor::reboot) が
+    /// [`MmioManager::reset_devices`] を通して呼ぶ。ゲストから見えるレジスタだけを戻し、ホスト側の接続 (コンソール・ディスク・
+    /// 統計・障害注入など) はそのまま残す。状態を持つデバイスは必ず実装すること
+    /// ([`crate::devices::testing::check_device`] で確認できる)。
+    fn reset(&mut self) {}
+
+    /// 統計に表示するデバイス名
+    fn name(&self) -> &str {
+        "mmio"
+    }
+
+    /// デバイスが使う割り込み (GIC の INTID)
+    ///
+    /// 登録済みのデバイス同士やタイマーとの重複の確認に使う。
+    fn irq(&self) -> Option<u32> {
+        None
+    }
+
+    /// デバイスが現在アサートしている割り込み (GIC の INTID、レベルトリガ)
+    ///
+    /// run ループが VM Exit ごとに確認し、アサート中は GIC にペンディングとして設定する。
+    fn pending_irq(&self) -> Option<u32> {
+        None
+    }
+
+    /// virtio キュー処理の処理時間 (virtio デバイスのみ)
+    fn queue_latency(&self) -> Option<LatencyStats> {
+        None
+    }
+
+    /// バックエンド (ディスクなど) とのホスト側の I/O 量
+    fn io_counters(&self) -> Option<IoCounters> {
+        None
+    }
+
+    /// virtio-blk のリクエストの統計 (virtio-blk のみ)
+    fn block_stats(&self) -> Option<BlockStats> {
+        None
+    }
+
+    /// ゲストが有効にした、エミュレーションしていない機能を取り出す
+    ///
+    /// 書き込みのたびに [`MmioManager`] が確認し、ゲストの出力が止まった理由などを
+    /// 利用者に知らせる。同じ機能は有効にされるたびに 1 回だけ返すこと。
+    fn take_unsupported(&mut self) -> Option<UnsupportedFeature> {
+        None
+    }
+
+    /// DMA に使うゲスト RAM を受け取る
+    ///
+    /// [`Hypervisor::register_mmio_handler`](crate::Hypervisor::register_mmio_handler)
+    /// で登録したとき (virtio-mmio スロットでは bind したとき) に呼ばれる。
+    /// ゲスト RAM 上のキューを処理する virtio デバイスはこれで検証器を受け取る。
+    fn attach_dma(&mut self, dma: DmaValidator) {
+        let _ = dma;
+    }
+
+    /// 割り当てた割り込み (GIC の INTID) を受け取る
+    ///
+    /// virtio-mmio スロットに bind したとき (スロットの割り込み) と、
+    /// [`Hypervisor::register_mmio_handler`](crate::Hypervisor::register_mmio_handler)
+    /// で virtio-mmio トランスポートの位置に登録したときに呼ばれる。
+    /// 割り込みを固定していない virtio デバイスは、これで
+    /// [`pending_irq`](Self::pending_irq) が返す番号を受け取る。
+    fn attach_irq(&mut self, irq: u32) {
+        let _ = irq;
+    }
+
+    /// マイグレーションで状態を保存・復元できるデバイスなら `Some` を返す
+    #[cfg(feature = "snapshot")]
+    fn as_device_state(&mut self) -> Option<&mut dyn DeviceState> {
+        None
+    }
+}
+
+/// ゲストが有効にしたが、エミュレーションしていない機能
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedFeature {
+    /// 機能の名前 (例: "PL011 DMA")
+    pub feature: &'static str,
+    /// 有効にしたレジスタのオフセット
+    pub offset: u64,
+    /// 書き込まれた値
+    pub value: u64,
+    /// ゲストから見える影響
+    pub impact: &'static str,
+}
+
+impl fmt::Display for UnsupportedFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "guest enabled {} (write 0x{:x} to +0x{:x}), which is not emulated: {}",
+            self.feature, self.value, self.offset, self.impact
+        )
+    }
+}
+
+/// 登録した後もホストから操作できるデバイス
+///
+/// [`MmioManager`] に登録したデバイスは `Box<dyn MmioHandler>` として所有されるため、
+/// ディスクのオンラインリサイズ ([`VirtioBlockDevice::resize`]) のようなデバイス固有の
+/// 操作を呼べなくなる。`SharedDevice` で包んで登録し、返されたハンドルから操作する。
+///
+/// ```ignore
+/// let (device, disk) = SharedDevice::new(VirtioBlockDevice::with_disk_image(base, file, sectors));
+/// hv.register_mmio_handler(Box::new(device))?;
+/// // ゲストの実行中 (run ループの合間) に
+/// disk.lock().unwrap().resize(new_sectors)?;
+/// ```
+///
+/// [`VirtioBlockDevice::resize`]: crate::devices::virtio::VirtioBlockDevice::resize
+pub struct SharedDevice<T> {
+    device: Arc<Mutex<T>>,
+    /// `name` は参照を返すため、登録時の名前を持っておく
+    name: String,
+}
+
+impl<T: MmioHandler> SharedDevice<T> {
+    /// `device` を包み、ホストから操作するためのハンドルと一緒に返す
+    pub fn new(device: T) -> (Self, Arc<Mutex<T>>) {
+        let name = device.name().to_string();
+        let device = Arc::new(Mutex::new(device));
+        (
+            Self {
+                device: device.clone(),
+                name,
+            },
+            device,
+        )
+    }
+
+    fn lock(&self) -> MutexGuard<'_, T> {
+        self.device.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<T: MmioHandler> MmioHandler for SharedDevice<T> {
+    fn base(&self) -> u64 {
+        self.lock().base()
+    }
+
+    fn size(&self) -> u64 {
+        self.lock().size()
+    }
+
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, Box<dyn Error>> {
+        self.lock().read(offset, size)
+    }
+
+    fn write(&mut self, offset: u64, value: u64, size: usize) -> Result<(), Box<dyn Error>> {
+        self.lock().write(offset, value, size)
+    }
+
+    fn reset(&mut self) {
+        self.lock().reset();
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn irq(&self) -> Option<u32> {
+        self.lock().irq()
+    }
+
+    fn pending_irq(&self) -> Option<u32> {
+        self.lock().pending_irq()
+    }
+
+    fn queue_latency(&self) -> Option<LatencyStats> {
+        self.lock().queue_latency()
+    }
+
+    fn io_counters(&self) -> Option<IoCounters> {
+        self.lock().io_counters()
+    }
+
+    fn block_stats(&self) -> Option<BlockStats> {
+        self.lock().block_stats()
+    }
+
+    fn take_unsupported(&mut self) -> Option<UnsupportedFeature> {
+        self.lock().take_unsupported()
+    }
+
+    fn attach_dma(&mut self, dma: DmaValidator) {
+        self.lock().attach_dma(dma);
+    }
+
+    fn attach_irq(&mut self, irq: u32) {
+        self.lock().attach_irq(irq);
+    }
+
+    #[cfg(feature = "snapshot")]
+    fn as_device_state(&mut self) -> Option<&mut dyn DeviceState> {
+        // 包んだデバイスが状態を持たない場合は保存しない
+        self.lock().as_device_state()?;
+        Some(self)
+    }
+}
+
+#[cfg(feature = "snapshot")]
+impl<T: MmioHandler> DeviceState for SharedDevice<T> {
+    fn save_state(&self) -> Vec<u8> {
+        self.lock()
+            .as_device_state()
+            .map(|state| state.save_state())
+            .unwrap_or_default()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn Error>> {
+        match self.lock().as_device_state() {
+            Some(device_state) => device_state.restore_state(state),
+            None => Ok(()),
+        }
+    }
 }
 
 /// MMIO デバイスマネージャ
 pub struct MmioManager {
     handlers: Vec<Box<dyn MmioHandler>>,
+    /// ハンドラごとの (読み取り, 書き込み) 処理時間
+    latency: Vec<(LatencyStats, LatencyStats)>,
+    /// 高速パスで処理する書き込み先 (絶対アドレス, handlers のインデックス)
+    fast_writes: Vec<(u64, usize)>,
+    /// 書き込みをまとめる範囲 (ベースアドレス, サイズ)
+    coalesced_zones: Vec<(u64, u64)>,
+    /// まだデバイスに渡していない書き込み
+    coalesced_ring: VecDeque<CoalescedWrite>,
+    /// MMIO アクセスの記録先
+    tracer: Option<Tracer>,
+    /// 未登録アドレスへのアクセスの警告の流量制限
+    warnings: RateLimiter,
+    /// デバイスが報告した未対応の機能 (デバイス名, 機能)
+    unsupported: Vec<(String, UnsupportedFeature)>,
 }
 
 impl MmioManager {
@@ -36,15 +310,228 @@ impl MmioManager {
     pub fn new() -> Self {
         Self {
             handlers: Vec::new(),
+            latency: Vec::new(),
+            fast_writes: Vec::new(),
+            coalesced_zones: Vec::new(),
+            coalesced_ring: VecDeque::with_capacity(COALESCED_RING_SIZE),
+            tracer: None,
+            warnings: RateLimiter::new(UNHANDLED_WARNINGS_PER_SEC, Duration::from_secs(1)),
+            unsupported: Vec::new(),
         }
     }
 
+    /// 未登録アドレスへのアクセスの警告の流量制限を変更する
+    ///
+    /// 既定は 1 秒あたり [`UNHANDLED_WARNINGS_PER_SEC`] 件。
+    pub fn set_warning_limit(&mut self, limiter: RateLimiter) {
+        self.warnings = limiter;
+    }
+
+    /// 流量制限で出力しなかった警告の件数
+    pub fn suppressed_warnings(&self) -> u64 {
+        self.warnings.total_suppressed()
+    }
+
+    /// ゲストが有効にした、エミュレーションしていない機能 (デバイス名, 機能)
+    pub fn unsupported_features(&self) -> &[(String, UnsupportedFeature)] {
+        &self.unsupported
+    }
+
+    /// 出力しなかった警告があれば、その件数を標準エラー出力に書く
+    pub fn flush_warnings(&mut self) {
+        self.warnings.flush("MMIO");
+    }
+
+    /// MMIO アクセスをトレースに記録する (None で停止)
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
     /// MMIO デバイスハンドラを登録する
     ///
     /// # Arguments
     /// * `handler` - 登録する MMIO ハンドラ
     pub fn register(&mut self, handler: Box<dyn MmioHandler>) {
         self.handlers.push(handler);
+        self.latency.push(Default::default());
+    }
+
+    /// 高速パス付きで MMIO デバイスハンドラを登録する
+    ///
+    /// `fast_write_offsets` に指定したレジスタへの書き込みは、範囲検索と
+    /// 処理時間の計測を行わず、アドレスの一致だけでハンドラに渡す。
+    /// UART_DR のように頻繁に書き込まれ、副作用が単純なレジスタ向け。
+    /// 高速パスの書き込みは `device_stats()` の `writes` に集計されない。
+    ///
+    /// # Arguments
+    /// * `handler` - 登録する MMIO ハンドラ
+    /// * `fast_write_offsets` - 高速パスで処理するレジスタのオフセット
+    pub fn register_fast(&mut self, handler: Box<dyn MmioHandler>, fast_write_offsets: &[u64]) {
+        let index = self.handlers.len();
+        let base = handler.base();
+        self.fast_writes.extend(
+            fast_write_offsets
+                .iter()
+                .map(|&offset| (base + offset, index)),
+        );
+        self.register(handler);
+    }
+
+    /// 書き込みをまとめる範囲を登録する (KVM の coalesced MMIO 相当)
+    ///
+    /// 範囲内への書き込みはデバイスに渡さずリングに記録し、後でまとめて処理する。
+    /// 書き込み順序は保たれ、次のいずれかでリングを処理する:
+    /// - リングが一杯になった
+    /// - 任意のデバイスからの読み取り、または範囲外への書き込み
+    /// - [`MmioManager::drain_coalesced`] の呼び出し
+    ///
+    /// 読み取りで副作用がなく、書き込みの反映が遅れても問題ない
+    /// フレームバッファや UART 出力などに使用する。
+    pub fn register_coalesced(&mut self, base: u64, size: u64) {
+        self.coalesced_zones.push((base, size));
+    }
+
+    /// リングに溜まっている書き込みの数
+    pub fn pending_coalesced(&self) -> usize {
+        self.coalesced_ring.len()
+    }
+
+    /// リングに溜まっている書き込みを記録順にデバイスへ渡す
+    pub fn drain_coalesced(&mut self) -> Result<(), Box<dyn Error>> {
+        while let Some(w) = self.coalesced_ring.pop_front() {
+            self.dispatch_write(w.addr, w.value, w.size)?;
+        }
+        Ok(())
+    }
+
+    fn is_coalesced(&self, addr: u64) -> bool {
+        self.coalesced_zones
+            .iter()
+            .any(|&(base, size)| addr >= base && addr - base < size)
+    }
+
+    /// 指定されたアドレスが高速パスで書き込めるか
+    pub fn is_fast_write(&self, addr: u64) -> bool {
+        self.fast_writes.iter().any(|&(a, _)| a == addr)
+    }
+
+    /// 高速パスで書き込む
+    ///
+    /// 高速パスに登録されていないアドレスは通常の `handle_write` で処理する。
+    pub fn fast_write(&mut self, addr: u64, value: u64, size: usize) -> Result<(), Box<dyn Error>> {
+        match self.fast_writes.iter().find(|&&(a, _)| a == addr) {
+            Some(&(_, index)) if self.coalesced_ring.is_empty() => {
+                let handler = &mut self.handlers[index];
+                let offset = addr - handler.base();
+                handler.write(offset, value, size)
+            }
+            _ => self.handle_write(addr, value, size),
+        }
+    }
+
+    /// 登録済みデバイスの割り込みが重複していないか確認する
+    ///
+    /// # Errors
+    /// 2 つのデバイスが同じ INTID を使っている場合や、`irqs` のタイマーの PPI と
+    /// 重なる場合はエラーを返す
+    pub fn validate_irqs(&self, irqs: &IrqMap) -> Result<(), Box<dyn Error>> {
+        let mut claimed: Vec<(u32, &str, u64)> = Vec::new();
+        for handler in &self.handlers {
+            let Some(irq) = handler.irq() else {
+                continue;
+            };
+            irqs.check_device(handler.name(), irq)?;
+            if let Some((_, name, base)) = claimed.iter().find(|c| c.0 == irq) {
+                return Err(format!(
+                    "IRQ {} is used by both {} at 0x{:x} and {} at 0x{:x}",
+                    irq,
+                    name,
+                    base,
+                    handler.name(),
+                    handler.base()
+                )
+                .into());
+            }
+            claimed.push((irq, handler.name(), handler.base()));
+        }
+        Ok(())
+    }
+
+    /// デバイスがアサートしている割り込み
+    pub fn pending_irqs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.handlers.iter().filter_map(|h| h.pending_irq())
+    }
+
+    /// すべてのデバイスを作成直後の状態に戻す (ゲストの再起動)
+    ///
+    /// まだデバイスに渡していない書き込みは再起動前のものなので捨てる。
+    pub fn reset_devices(&mut self) {
+        self.coalesced_ring.clear();
+        for handler in &mut self.handlers {
+            handler.reset();
+        }
+    }
+
+    /// 状態を保存できるデバイスの (名前, ベースアドレス, 状態)
+    #[cfg(feature = "snapshot")]
+    pub fn save_device_states(&mut self) -> Vec<(String, u64, Vec<u8>)> {
+        self.handlers
+            .iter_mut()
+            .filter_map(|h| {
+                let (name, base) = (h.name().to_string(), h.base());
+                h.as_device_state()
+                    .map(|state| (name, base, state.save_state()))
+            })
+            .collect()
+    }
+
+    /// 名前とベースアドレスが一致するデバイスに状態を戻す
+    ///
+    /// # Errors
+    /// 該当するデバイスがない場合や、状態の形式が不正な場合はエラーを返す
+    #[cfg(feature = "snapshot")]
+    pub fn restore_device_state(
+        &mut self,
+        name: &str,
+        base: u64,
+        state: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let handler = self
+            .handlers
+            .iter_mut()
+            .find(|h| h.name() == name && h.base() == base)
+            .ok_or_else(|| format!("No device {} at 0x{:x} to restore state into", name, base))?;
+        handler
+            .as_device_state()
+            .ok_or_else(|| {
+                format!(
+                    "Device {} at 0x{:x} does not support state restore",
+                    name, base
+                )
+            })?
+            .restore_state(state)
+    }
+
+    /// 登録されているデバイス (登録順)
+    pub fn devices(&self) -> impl Iterator<Item = &dyn MmioHandler> + '_ {
+        self.handlers.iter().map(|handler| handler.as_ref())
+    }
+
+    /// デバイスごとの処理時間を取得する
+    pub fn device_stats(&self) -> Vec<DeviceStats> {
+        self.handlers
+            .iter()
+            .zip(&self.latency)
+            .map(|(handler, (reads, writes))| DeviceStats {
+                name: handler.name().to_string(),
+                base: handler.base(),
+                reads: *reads,
+                writes: *writes,
+                queue: handler.queue_latency(),
+                io: handler.io_counters(),
+                block: handler.block_stats(),
+            })
+            .collect()
     }
 
     /// 指定されたアドレスからデータを読み取る
@@ -56,21 +543,31 @@ impl MmioManager {
     /// # Returns
     /// 読み取った値
     pub fn handle_read(&mut self, addr: u64, size: usize) -> Result<u64, Box<dyn Error>> {
+        // 読み取りより前の書き込みを先に反映する
+        self.drain_coalesced()?;
+
         // 該当するハンドラを検索
-        for handler in &mut self.handlers {
+        for (handler, (reads, _)) in self.handlers.iter_mut().zip(&mut self.latency) {
             let base = handler.base();
             let handler_size = handler.size();
 
             if addr >= base && addr < base + handler_size {
                 let offset = addr - base;
-                return handler.read(offset, size);
+                let start = Instant::now();
+                let result = handler.read(offset, size);
+                reads.record(start.elapsed());
+                if let Some(tracer) = &self.tracer {
+                    let track = Track::Device(handler.name().to_string());
+                    tracer.complete(track, "mmio", format!("read +0x{:x}", offset), start);
+                }
+                return result;
             }
         }
 
         // ハンドラが見つからない場合は 0 を返す
-        eprintln!(
-            "MMIO read from unhandled address: 0x{:x} (size: {})",
-            addr, size
+        self.warnings.warn(
+            "MMIO",
+            format_args!("read from unhandled address: 0x{:x} (size: {})", addr, size),
         );
         Ok(0)
     }
@@ -87,21 +584,53 @@ impl MmioManager {
         value: u64,
         size: usize,
     ) -> Result<(), Box<dyn Error>> {
+        if self.is_coalesced(addr) {
+            self.coalesced_ring
+                .push_back(CoalescedWrite { addr, value, size });
+            if self.coalesced_ring.len() >= COALESCED_RING_SIZE {
+                self.drain_coalesced()?;
+            }
+            return Ok(());
+        }
+        self.drain_coalesced()?;
+        self.dispatch_write(addr, value, size)
+    }
+
+    fn dispatch_write(&mut self, addr: u64, value: u64, size: usize) -> Result<(), Box<dyn Error>> {
         // 該当するハンドラを検索
-        for handler in &mut self.handlers {
+        for (handler, (_, writes)) in self.handlers.iter_mut().zip(&mut self.latency) {
             let base = handler.base();
             let handler_size = handler.size();
 
             if addr >= base && addr < base + handler_size {
                 let offset = addr - base;
-                return handler.write(offset, value, size);
+                let start = Instant::now();
+                let result = handler.write(offset, value, size);
+                writes.record(start.elapsed());
+                if let Some(tracer) = &self.tracer {
+                    let track = Track::Device(handler.name().to_string());
+                    tracer.complete(track, "mmio", format!("write +0x{:x}", offset), start);
+                }
+                if let Some(feature) = handler.take_unsupported() {
+                    let name = handler.name().to_string();
+                    eprintln!("[MMIO] {}: {}", name, feature);
+                    if let Some(tracer) = &self.tracer {
+                        let track = Track::Device(name.clone());
+                        tracer.instant(track, "mmio", format!("unsupported {}", feature.feature));
+                    }
+                    self.unsupported.push((name, feature));
+                }
+                return result;
             }
         }
 
         // ハンドラが見つからない場合は警告を出す
-        eprintln!(
-            "MMIO write to unhandled address: 0x{:x} = 0x{:x} (size: {})",
-            addr, value, size
+        self.warnings.warn(
+            "MMIO",
+            format_args!(
+                "write to unhandled address: 0x{:x} = 0x{:x} (size: {})",
+                addr, value, size
+            ),
         );
         Ok(())
     }
@@ -174,6 +703,118 @@ mod tests {
         assert_eq!(value, 0x42);
     }
 
+    #[test]
+    fn test_mmio_manager_device_stats() {
+        let mut manager = MmioManager::new();
+        manager.register(Box::new(DummyDevice {
+            base: 0x1000,
+            size: 0x100,
+            data: 0,
+        }));
+
+        manager.handle_write(0x1000, 0x42, 4).unwrap();
+        manager.handle_read(0x1000, 4).unwrap();
+        manager.handle_read(0x1004, 4).unwrap();
+        // 未登録のアドレスは集計しない
+        manager.handle_read(0x9999, 4).unwrap();
+
+        let stats = manager.device_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].name, "mmio");
+        assert_eq!(stats[0].base, 0x1000);
+        assert_eq!(stats[0].reads.count, 2);
+        assert_eq!(stats[0].writes.count, 1);
+        assert!(stats[0].reads.min <= stats[0].reads.max);
+        assert_eq!(stats[0].queue, None);
+    }
+
+    #[test]
+    fn test_mmio_manager_fast_write() {
+        let mut manager = MmioManager::new();
+        manager.register_fast(
+            Box::new(DummyDevice {
+                base: 0x1000,
+                size: 0x100,
+                data: 0,
+            }),
+            &[0x0],
+        );
+
+        assert!(manager.is_fast_write(0x1000));
+        assert!(!manager.is_fast_write(0x1004));
+
+        manager.fast_write(0x1000, 0x41, 1).unwrap();
+        assert_eq!(manager.handle_read(0x1000, 4).unwrap(), 0x41);
+        // 高速パスの書き込みは集計しない
+        assert_eq!(manager.device_stats()[0].writes.count, 0);
+
+        // 高速パス以外のアドレスは通常の経路で処理する
+        manager.fast_write(0x1004, 0x42, 4).unwrap();
+        assert_eq!(manager.handle_read(0x1000, 4).unwrap(), 0x42);
+        assert_eq!(manager.device_stats()[0].writes.count, 1);
+    }
+
+    #[test]
+    fn test_mmio_manager_coalesced_writes() {
+        let mut manager = MmioManager::new();
+        manager.register(Box::new(DummyDevice {
+            base: 0x1000,
+            size: 0x100,
+            data: 0,
+        }));
+        manager.register(Box::new(DummyDevice {
+            base: 0x2000,
+            size: 0x100,
+            data: 0,
+        }));
+        manager.register_coalesced(0x1000, 0x100);
+
+        manager.handle_write(0x1000, 0x1, 4).unwrap();
+        manager.handle_write(0x1000, 0x2, 4).unwrap();
+        assert_eq!(manager.pending_coalesced(), 2);
+        assert_eq!(manager.device_stats()[0].writes.count, 0);
+
+        // 範囲外への書き込みの前にリングを処理する
+        manager.handle_write(0x2000, 0x3, 4).unwrap();
+        assert_eq!(manager.pending_coalesced(), 0);
+        assert_eq!(manager.device_stats()[0].writes.count, 2);
+
+        // 読み取りは最後に記録された書き込みを反映した値を返す
+        manager.handle_write(0x1000, 0x4, 4).unwrap();
+        assert_eq!(manager.handle_read(0x1000, 4).unwrap(), 0x4);
+
+        // リングが一杯になったらまとめて処理する
+        for i in 0..COALESCED_RING_SIZE as u64 {
+            manager.handle_write(0x1000, i, 4).unwrap();
+        }
+        assert_eq!(manager.pending_coalesced(), 0);
+        assert_eq!(
+            manager.device_stats()[0].writes.count,
+            3 + COALESCED_RING_SIZE as u64
+        );
+    }
+
+    #[test]
+    fn test_mmio_manager_trace() {
+        let mut manager = MmioManager::new();
+        manager.register(Box::new(DummyDevice {
+            base: 0x1000,
+            size: 0x100,
+            data: 0,
+        }));
+        let tracer = Tracer::new();
+        manager.set_tracer(Some(tracer.clone()));
+
+        manager.handle_write(0x1050, 0x0, 4).unwrap();
+        manager.handle_read(0x1000, 4).unwrap();
+
+        let events = tracer.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].name, "write +0x50");
+        assert_eq!(events[0].track, Track::Device("mmio".to_string()));
+        assert_eq!(events[1].name, "read +0x0");
+    }
+
     #[test]
     fn test_mmio_manager_unhandled_address() {
         let mut manager = MmioManager::new();
@@ -185,4 +826,52 @@ mod tests {
         // 未登録のアドレスへの書き込み（エラーにならない）
         manager.handle_write(0x9999, 0x42, 4).unwrap();
     }
+
+    #[test]
+    fn test_mmio_manager_unhandled_warnings_are_rate_limited() {
+        use crate::devices::host_time::ManualClock;
+
+        let clock = ManualClock::new();
+        let mut manager = MmioManager::new();
+        manager.set_warning_limit(RateLimiter::with_clock(
+            3,
+            Duration::from_secs(1),
+            std::sync::Arc::new(clock.clone()),
+        ));
+
+        // A runaway guest polling an unmapped register
+        for _ in 0..100 {
+            manager.handle_read(0x9999, 4).unwrap();
+        }
+        assert_eq!(manager.suppressed_warnings(), 97);
+
+        clock.advance(Duration::from_secs(1));
+        manager.handle_write(0x9999, 0x42, 4).unwrap();
+        assert_eq!(manager.suppressed_warnings(), 97);
+    }
+
+    #[test]
+    #[cfg(feature = "uart")]
+    fn test_mmio_manager_irq_collisions() {
+        use crate::devices::uart::Pl011Uart;
+
+        let irqs = IrqMap::QEMU_VIRT;
+        let mut manager = MmioManager::new();
+        manager.register(Box::new(Pl011Uart::new(0x0900_0000)));
+        assert!(manager.validate_irqs(&irqs).is_ok());
+
+        let mut second = Pl011Uart::new(0x0904_0000);
+        second.set_irq(irqs.virt_timer);
+        manager.register(Box::new(second));
+        let err = manager.validate_irqs(&irqs).unwrap_err();
+        assert!(err.to_string().contains("virt-timer"));
+
+        let mut manager = MmioManager::new();
+        manager.register(Box::new(Pl011Uart::new(0x0900_0000)));
+        manager.register(Box::new(Pl011Uart::new(0x0904_0000)));
+        let err = manager.validate_irqs(&irqs).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("IRQ 33 is used by both pl011 at 0x9000000 and pl011 at 0x9040000"));
+    }
 }
@@ -0,0 +1,429 @@
+//! 実行中のゲストを調べるモニタ (QEMU の HMP に相当)
+//!
+//! 起動の途中で止まったゲストを、止めずに対話的に調べるためのコマンド。
+//!
+//! | コマンド | 内容 |
+//! |----------|------|
+//! | `info gic` | GIC の有効・ペンディング・アクティブな割り込み |
+//! | `info timer` | カウンタと物理・仮想タイマーの設定 |
+//! | `info mmio` | 登録されている MMIO デバイスとアクセス回数 |
+//! | `info registers` | 汎用レジスタと EL1 のシステムレジスタ |
+//! | `dump mem ADDR LEN` | ゲスト RAM の 16 進ダンプ (最大 [`MAX_DUMP_LEN`] bytes) |
+//! | `inject irq N` | 割り込み N をペンディングにする |
+//! | `info boot` | 次の再起動で起動するカーネル |
+//! | `boot next KERNEL [INITRD] [-- CMDLINE]` | 次の再起動で起動するカーネルを指定する ([`crate::boot::next_boot`]) |
+//! | `boot next off` | 指定を取り消す |
+//! | `help` | コマンドの一覧 |
+//!
+//! コマンドは [`ControlHandle`] で vCPU スレッドの run ループに送り、VM Exit の合間に
+//! 実行する。ゲストがループしていてもすぐに応答が返る。
+//!
+//! ```ignore
+//! let monitor = hv.monitor();
+//! std::thread::spawn(move || serve(&monitor, io::stdin().lock(), io::stdout()));
+//! // または: listen(hv.monitor(), "/tmp/hv-monitor.sock")? で `nc -U` から接続する
+//! let result = hv.run(None, None, None)?;
+//! ```
+
+use crate::boot::next_boot::NextBoot;
+use crate::control::ControlHandle;
+use crate::devices::timer::{Timer, TimerState};
+use crate::mmio::MmioManager;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// `dump mem` で一度に読む最大の長さ (bytes)
+pub const MAX_DUMP_LEN: u64 = 0x1000;
+
+/// 対話的に入力するときのプロンプト
+pub const PROMPT: &str = "(hv) ";
+
+/// `help` の出力
+pub const HELP: &str = "\
+info gic            show enabled, pending and active interrupts
+info timer          show the counters and the physical/virtual timers
+info mmio           list MMIO devices with their access counts
+info registers      show general-purpose and EL1 system registers
+dump mem ADDR LEN   hex dump guest RAM (LEN up to 0x1000)
+inject irq N        make interrupt N pending
+info boot           show the kernel selected for the next reboot
+boot next KERNEL [INITRD] [-- CMDLINE]
+                    boot KERNEL on the next reboot
+boot next off       keep the current kernel on the next reboot
+help                show this list";
+
+/// モニタのコマンド
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MonitorCommand {
+    /// `info gic`
+    InfoGic,
+    /// `info timer`
+    InfoTimer,
+    /// `info mmio`
+    InfoMmio,
+    /// `info registers`
+    InfoRegisters,
+    /// `dump mem ADDR LEN`
+    DumpMem {
+        /// 先頭のゲスト物理アドレス
+        addr: u64,
+        /// 長さ (bytes)
+        len: u64,
+    },
+    /// `inject irq N`
+    InjectIrq(u32),
+    /// `info boot`
+    InfoBoot,
+    /// `boot next KERNEL [INITRD] [-- CMDLINE]` (`boot next off` なら None)
+    BootNext(Option<NextBoot>),
+    /// `help`
+    Help,
+}
+
+impl MonitorCommand {
+    /// 1 行のコマンドを解析する
+    ///
+    /// 数値は 10 進か `0x` で始まる 16 進で指定する。
+    pub fn parse(line: &str) -> Result<Self, Box<dyn Error>> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words.as_slice() {
+            ["info", "gic"] => Self::InfoGic,
+            ["info", "timer"] => Self::InfoTimer,
+            ["info", "mmio"] => Self::InfoMmio,
+            ["info", "registers"] => Self::InfoRegisters,
+            ["dump", "mem", addr, len] => Self::DumpMem {
+                addr: parse_number(addr)?,
+                len: parse_number(len)?,
+            },
+            ["inject", "irq", irq] => Self::InjectIrq(
+                u32::try_from(parse_number(irq)?)
+                    .map_err(|_| format!("interrupt number {} is out of range", irq))?,
+            ),
+            ["info", "boot"] => Self::InfoBoot,
+            ["boot", "next", "off"] => Self::BootNext(None),
+            ["boot", "next", ..] => Self::BootNext(Some(NextBoot::parse(skip_words(line, 2))?)),
+            ["help"] => Self::Help,
+            _ => {
+                return Err(format!("unknown command '{}' (try 'help')", line.trim()).into());
+            }
+        };
+        Ok(command)
+    }
+}
+
+/// 先頭の `count` 語を読み飛ばした残り (残りの中の空白はそのまま)
+fn skip_words(line: &str, count: usize) -> &str {
+    let mut rest = line;
+    for _ in 0..count {
+        rest = rest.trim_start();
+        rest = &rest[rest.find(char::is_whitespace).unwrap_or(rest.len())..];
+    }
+    rest
+}
+
+fn parse_number(text: &str) -> Result<u64, Box<dyn Error>> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("'{}' is not a number", text).into())
+}
+
+/// 他のスレッドからモニタのコマンドを実行するハンドル (`Send`、clone 可能)
+///
+/// [`Hypervisor::monitor`](crate::Hypervisor::monitor) で作成する。
+#[derive(Debug, Clone)]
+pub struct MonitorHandle {
+    control: ControlHandle,
+}
+
+impl MonitorHandle {
+    /// `control` でコマンドを実行するハンドルを作成する
+    pub fn new(control: ControlHandle) -> Self {
+        Self { control }
+    }
+
+    /// コマンドを run ループで実行し、出力を返す
+    ///
+    /// # Errors
+    /// コマンドの失敗、または [`REPLY_TIMEOUT`](crate::control::REPLY_TIMEOUT) 以内に run ループが応答しない場合
+    pub fn execute(&self, command: MonitorCommand) -> Result<String, Box<dyn Error>> {
+        if command == MonitorCommand::Help {
+            return Ok(HELP.to_string());
+        }
+        self.control
+            .call(move |hv| hv.monitor_command(command).map_err(|e| e.to_string()))?
+            .map_err(Into::into)
+    }
+}
+
+/// `input` から 1 行ずつコマンドを読み、結果を `output` に書く
+///
+/// コマンドのエラーは `error: ...` として書き、続けて次の行を読む。
+/// `quit` か `input` の終わりで戻る。
+pub fn serve(
+    handle: &MonitorHandle,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> Result<(), Box<dyn Error>> {
+    write!(output, "{}", PROMPT)?;
+    output.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line == "quit" {
+            break;
+        }
+        if !line.is_empty() {
+            match MonitorCommand::parse(line).and_then(|command| handle.execute(command)) {
+                Ok(text) => writeln!(output, "{}", text)?,
+                Err(e) => writeln!(output, "error: {}", e)?,
+            }
+        }
+        write!(output, "{}", PROMPT)?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Unix ソケット `path` で接続を待ち、接続ごとに [`serve`] する
+///
+/// 接続は 1 つずつ処理する。`path` に残っている古いソケットは作り直す。
+pub fn listen(
+    handle: MonitorHandle,
+    path: impl AsRef<Path>,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let path = path.as_ref();
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let Ok(reader) = stream.try_clone() else {
+                continue;
+            };
+            let _ = serve(&handle, BufReader::new(reader), stream);
+        }
+    }))
+}
+
+/// ゲスト RAM の内容を `xxd` と同じ形式で 16 bytes ずつ表示する
+pub fn hexdump(addr: u64, bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let _ = write!(text, "{:016x}:", addr + i as u64 * 16);
+        for pair in line.chunks(2) {
+            text.push(' ');
+            for byte in pair {
+                let _ = write!(text, "{:02x}", byte);
+            }
+        }
+        // ASCII 列をそろえる
+        let width = 16 * 2 + 8;
+        let used = line.len() * 2 + line.len().div_ceil(2);
+        text.push_str(&" ".repeat(width - used + 2));
+        text.extend(line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        text.push('\n');
+    }
+    text.pop();
+    text
+}
+
+/// `info mmio` の出力
+pub fn describe_mmio(manager: &MmioManager) -> String {
+    let mut text = String::new();
+    for (device, stats) in manager.devices().zip(manager.device_stats()) {
+        let irq = device
+            .irq()
+            .map_or("-".to_string(), |irq| format!("irq {}", irq));
+        let _ = writeln!(
+            text,
+            "{:016x}-{:016x} {:<12} {:<8} reads {} writes {}",
+            device.base(),
+            device.base() + device.size() - 1,
+            device.name(),
+            irq,
+            stats.reads.count,
+            stats.writes.count
+        );
+    }
+    text.pop();
+    text
+}
+
+/// `info timer` の出力
+pub fn describe_timer(timer: &Timer) -> String {
+    let phys = timer.get_phys_counter();
+    let virt = timer.get_virt_counter();
+    let line = |name: &str, state: &TimerState, counter: u64| {
+        format!(
+            "{}: ctl 0x{:x} ({}{}{}) cval 0x{:x} tval {}",
+            name,
+            state.read_ctl(counter),
+            if state.is_enabled() {
+                "enabled"
+            } else {
+                "disabled"
+            },
+            if state.is_masked() { ", masked" } else { "" },
+            if state.is_asserted(counter) {
+                ", asserted"
+            } else {
+                ""
+            },
+            state.read_cval(),
+            state.read_tval(counter) as i64
+        )
+    };
+    let next = timer
+        .time_until_next_event()
+        .map_or("none".to_string(), |nanos| {
+            format!("in {:?}", Duration::from_nanos(nanos))
+        });
+    format!(
+        "frequency: {} Hz\n\
+         physical counter: 0x{:x}\n\
+         virtual counter:  0x{:x} (offset 0x{:x})\n\
+         {}\n\
+         {}\n\
+         next event: {}",
+        timer.get_frequency(),
+        phys,
+        virt,
+        timer.get_virt_offset(),
+        line("physical timer", &timer.phys_timer, phys),
+        line("virtual timer ", &timer.virt_timer, virt),
+        next
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::host_time::ManualClock;
+    use crate::devices::pl330::{Pl330Stub, PL330_BASE};
+    use crate::vcpu_handle::VcpuHandle;
+    use std::sync::Arc;
+
+    #[test]
+    fn コマンドを解析する() {
+        assert_eq!(
+            MonitorCommand::parse("info gic").unwrap(),
+            MonitorCommand::InfoGic
+        );
+        assert_eq!(
+            MonitorCommand::parse("  dump mem 0x40000000 64 ").unwrap(),
+            MonitorCommand::DumpMem {
+                addr: 0x4000_0000,
+                len: 64
+            }
+        );
+        assert_eq!(
+            MonitorCommand::parse("inject irq 33").unwrap(),
+            MonitorCommand::InjectIrq(33)
+        );
+        assert!(MonitorCommand::parse("info cpus")
+            .unwrap_err()
+            .to_string()
+            .contains("unknown command 'info cpus'"));
+        assert!(MonitorCommand::parse("dump mem 0x4000zz 4")
+            .unwrap_err()
+            .to_string()
+            .contains("'0x4000zz' is not a number"));
+        assert!(MonitorCommand::parse("inject irq 0x100000000").is_err());
+        assert_eq!(
+            MonitorCommand::parse("boot next out/Image-b -- console=ttyAMA0").unwrap(),
+            MonitorCommand::BootNext(Some(
+                NextBoot::new("out/Image-b").cmdline("console=ttyAMA0")
+            ))
+        );
+        // "boot" や "next" で始まるパスもそのまま渡す
+        assert_eq!(
+            MonitorCommand::parse("boot  next next/bootImage").unwrap(),
+            MonitorCommand::BootNext(Some(NextBoot::new("next/bootImage")))
+        );
+        assert_eq!(
+            MonitorCommand::parse("boot next off").unwrap(),
+            MonitorCommand::BootNext(None)
+        );
+        assert!(MonitorCommand::parse("boot next").is_err());
+    }
+
+    #[test]
+    fn hexdump_は_xxd_の形式で表示する() {
+        let text = hexdump(0x4000_0000, b"Linux version 6.1\x00\x01");
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "0000000040000000: 4c69 6e75 7820 7665 7273 696f 6e20 362e  Linux version 6."
+        );
+        assert_eq!(
+            lines[1],
+            "0000000040000010: 3100 01                                  1.."
+        );
+    }
+
+    #[test]
+    fn info_mmio_はデバイスの範囲を表示する() {
+        let mut manager = MmioManager::new();
+        manager.register(Box::new(Pl330Stub::new(PL330_BASE)));
+        manager.handle_read(PL330_BASE, 4).unwrap();
+        assert_eq!(
+            describe_mmio(&manager),
+            "00000000090e0000-00000000090e0fff pl330        -        reads 1 writes 0"
+        );
+    }
+
+    #[test]
+    fn info_timer_はタイマーの設定と次のイベントを表示する() {
+        let clock = ManualClock::new();
+        let mut timer = Timer::with_clock(Arc::new(clock.clone()));
+        clock.advance(Duration::from_secs(1));
+        timer.virt_timer.write_cval(24_000_000 + 2_400);
+        timer.virt_timer.write_ctl(1);
+
+        let text = describe_timer(&timer);
+        assert!(text.contains("physical counter: 0x16e3600\n"));
+        assert!(text.contains("physical timer: ctl 0x0 (disabled) cval 0x0"));
+        assert!(text.contains("virtual timer : ctl 0x1 (enabled) cval 0x16e3f60 tval 2400"));
+        assert!(text.ends_with("next event: in 100µs"));
+
+        clock.advance(Duration::from_millis(1));
+        assert!(describe_timer(&timer).contains("ctl 0x5 (enabled, asserted)"));
+    }
+
+    #[test]
+    fn serve_はエラーを表示して次のコマンドを読む() {
+        // 受け口のない (Hypervisor が破棄された) ハンドル
+        let (requests, _) = std::sync::mpsc::channel();
+        let handle = MonitorHandle::new(ControlHandle::new(requests, VcpuHandle::new(None, None)));
+
+        let mut output = Vec::new();
+        let input = "bogus\ninfo gic\n\nhelp\nquit\ninfo timer\n";
+        serve(&handle, input.as_bytes(), &mut output).unwrap();
+
+        // quit の後のコマンドは読まない
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "(hv) error: unknown command 'bogus' (try 'help')\n\
+                 (hv) error: the hypervisor has been dropped\n\
+                 (hv) (hv) {}\n\
+                 (hv) ",
+                HELP
+            )
+        );
+    }
+}
@@ -0,0 +1,195 @@
+//! ネスト仮想化 (ゲスト EL2) の調査的サポート
+//!
+//! `nested` feature を有効にした場合のみビルドされる。
+//!
+//! # 調査結果と制限
+//!
+//! - Hypervisor.framework は macOS 15 以降かつ M3 以降のチップでのみ
+//!   `hv_vm_config_set_el2_enabled` によるゲスト EL2 をサポートする。
+//!   applevisor 0.1 は VM 作成時に設定オブジェクトを渡せないため、
+//!   現時点ではゲストを実際に EL2 で起動することはできない。
+//! - そのため、このモジュールは以下の 2 点に限定している。
+//!   1. ホストが EL2 をサポートするかの問い合わせ ([`el2_supported`])
+//!   2. EL1 で動作するゲストが EL2 レジスタ (HCR_EL2、CNTHCTL_EL2 など) に
+//!      アクセスしてトラップされた場合のシャドウレジスタ ([`El2SysRegs`])。
+//!      読み取りは最後に書き込まれた値 (初期値はリセット値) を返し、
+//!      ゲストの KVM 初期化コードが EL2 の存在確認で停止しないようにする。
+//! - GICv3 の仮想 CPU インターフェース (ICH_*_EL2) は Hypervisor.framework が
+//!   公開していないため対象外。
+
+use crate::host_capabilities::framework_symbol;
+
+/// Hypervisor.framework の戻り値 (成功)
+const HV_SUCCESS: i32 = 0;
+
+/// `hv_vm_config_get_el2_supported` (macOS 15 以降) の型
+type El2SupportedQuery = unsafe extern "C" fn(el2_supported: *mut bool) -> i32;
+
+/// ホストの Hypervisor.framework がゲスト EL2 をサポートするか問い合わせる
+///
+/// API がない (macOS 14 以前) か失敗した場合は false を返す。
+/// 直接リンクすると古い macOS でバイナリが起動しなくなるため、実行時に探す。
+pub fn el2_supported() -> bool {
+    let Some(symbol) = framework_symbol(c"hv_vm_config_get_el2_supported") else {
+        return false;
+    };
+    // SAFETY: Hypervisor.framework のヘッダーで宣言された型の関数
+    let query: El2SupportedQuery = unsafe { std::mem::transmute(symbol) };
+    let mut supported = false;
+    // SAFETY: 出力先として有効なポインタを渡している
+    let ret = unsafe { query(&mut supported) };
+    ret == HV_SUCCESS && supported
+}
+
+/// エミュレートする EL2 システムレジスタ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum El2Reg {
+    /// Hypervisor Configuration Register
+    HCR_EL2,
+    /// Counter-timer Hypervisor Control Register
+    CNTHCTL_EL2,
+    /// System Control Register (EL2)
+    SCTLR_EL2,
+    /// Vector Base Address Register (EL2)
+    VBAR_EL2,
+    /// Virtualization Translation Table Base Register
+    VTTBR_EL2,
+    /// Exception Link Register (EL2)
+    ELR_EL2,
+    /// Saved Program Status Register (EL2)
+    SPSR_EL2,
+}
+
+impl El2Reg {
+    /// システムレジスタエンコーディングから El2Reg を取得
+    ///
+    /// EL2 レジスタは Op0=3, Op1=4 が共通。
+    pub fn from_encoding(op0: u8, op1: u8, crn: u8, crm: u8, op2: u8) -> Option<Self> {
+        if op0 != 3 || op1 != 4 {
+            return None;
+        }
+        match (crn, crm, op2) {
+            (1, 1, 0) => Some(El2Reg::HCR_EL2),
+            (14, 1, 0) => Some(El2Reg::CNTHCTL_EL2),
+            (1, 0, 0) => Some(El2Reg::SCTLR_EL2),
+            (12, 0, 0) => Some(El2Reg::VBAR_EL2),
+            (2, 1, 0) => Some(El2Reg::VTTBR_EL2),
+            (4, 0, 1) => Some(El2Reg::ELR_EL2),
+            (4, 0, 0) => Some(El2Reg::SPSR_EL2),
+            _ => None,
+        }
+    }
+}
+
+/// HCR_EL2.RW: EL1 は AArch64
+const HCR_EL2_RW: u64 = 1 << 31;
+/// CNTHCTL_EL2.EL1PCTEN | EL1PCEN: EL1 から物理カウンタ/タイマーにアクセス可能
+const CNTHCTL_EL2_RESET: u64 = 0x3;
+/// SCTLR_EL2 の RES1 ビット
+const SCTLR_EL2_RES1: u64 = 0x30C5_0830;
+
+/// EL2 シャドウレジスタ
+#[derive(Debug, Clone)]
+pub struct El2SysRegs {
+    hcr_el2: u64,
+    cnthctl_el2: u64,
+    sctlr_el2: u64,
+    vbar_el2: u64,
+    vttbr_el2: u64,
+    elr_el2: u64,
+    spsr_el2: u64,
+}
+
+impl Default for El2SysRegs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl El2SysRegs {
+    /// リセット値で初期化されたシャドウレジスタを作成
+    pub fn new() -> Self {
+        Self {
+            hcr_el2: HCR_EL2_RW,
+            cnthctl_el2: CNTHCTL_EL2_RESET,
+            sctlr_el2: SCTLR_EL2_RES1,
+            vbar_el2: 0,
+            vttbr_el2: 0,
+            elr_el2: 0,
+            spsr_el2: 0,
+        }
+    }
+
+    /// レジスタを読み取る
+    pub fn read(&self, reg: El2Reg) -> u64 {
+        match reg {
+            El2Reg::HCR_EL2 => self.hcr_el2,
+            El2Reg::CNTHCTL_EL2 => self.cnthctl_el2,
+            El2Reg::SCTLR_EL2 => self.sctlr_el2,
+            El2Reg::VBAR_EL2 => self.vbar_el2,
+            El2Reg::VTTBR_EL2 => self.vttbr_el2,
+            El2Reg::ELR_EL2 => self.elr_el2,
+            El2Reg::SPSR_EL2 => self.spsr_el2,
+        }
+    }
+
+    /// レジスタに書き込む
+    ///
+    /// HCR_EL2.RW は固定 (AArch32 EL1 は未サポート)、SCTLR_EL2 の RES1 ビットは保持する。
+    pub fn write(&mut self, reg: El2Reg, value: u64) {
+        match reg {
+            El2Reg::HCR_EL2 => self.hcr_el2 = value | HCR_EL2_RW,
+            El2Reg::CNTHCTL_EL2 => self.cnthctl_el2 = value,
+            El2Reg::SCTLR_EL2 => self.sctlr_el2 = value | SCTLR_EL2_RES1,
+            El2Reg::VBAR_EL2 => self.vbar_el2 = value & !0x7FF,
+            El2Reg::VTTBR_EL2 => self.vttbr_el2 = value,
+            El2Reg::ELR_EL2 => self.elr_el2 = value,
+            El2Reg::SPSR_EL2 => self.spsr_el2 = value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_encoding_で_el2_レジスタを識別する() {
+        assert_eq!(El2Reg::from_encoding(3, 4, 1, 1, 0), Some(El2Reg::HCR_EL2));
+        assert_eq!(
+            El2Reg::from_encoding(3, 4, 14, 1, 0),
+            Some(El2Reg::CNTHCTL_EL2)
+        );
+        assert_eq!(
+            El2Reg::from_encoding(3, 4, 12, 0, 0),
+            Some(El2Reg::VBAR_EL2)
+        );
+    }
+
+    #[test]
+    fn from_encoding_は_el1_レジスタに_none_を返す() {
+        // SCTLR_EL1 (Op1=0)
+        assert_eq!(El2Reg::from_encoding(3, 0, 1, 0, 0), None);
+        // CNTVOFF_EL2 は TimerReg が扱う
+        assert_eq!(El2Reg::from_encoding(3, 4, 14, 0, 3), None);
+    }
+
+    #[test]
+    fn リセット値は_aarch64_el1_を示す() {
+        let regs = El2SysRegs::new();
+        assert_ne!(regs.read(El2Reg::HCR_EL2) & HCR_EL2_RW, 0);
+        assert_eq!(regs.read(El2Reg::CNTHCTL_EL2), CNTHCTL_EL2_RESET);
+    }
+
+    #[test]
+    fn 書き込んだ値を読み戻せる() {
+        let mut regs = El2SysRegs::new();
+        regs.write(El2Reg::VBAR_EL2, 0x4000_0800);
+        assert_eq!(regs.read(El2Reg::VBAR_EL2), 0x4000_0800);
+
+        // RW ビットはクリアできない
+        regs.write(El2Reg::HCR_EL2, 0);
+        assert_eq!(regs.read(El2Reg::HCR_EL2), HCR_EL2_RW);
+    }
+}
@@ -0,0 +1,253 @@
+//! 複数 VM の管理 (1 プロセス 1 VM のワーカープール)
+//!
+//! Hypervisor.framework はプロセスごとに VM を 1 つしか作れないため、VM ごとに
+//! ワーカープロセス (`vm-worker` バイナリ) を起動し、標準入出力の
+//! [制御プロトコル](protocol) で起動・停止・コンソール・状態の問い合わせを行う。
+//! 1 つのテストから複数のゲストを同時に動かすテストファームを想定している。
+//!
+//! 暴走したゲストが他の VM の CPU を奪わないよう、VM ごとに
+//! [CPU 使用量の上限](quota) を設定できる。
+//!
+//! ワーカーのバイナリも Hypervisor.framework の entitlements で署名しておくこと
+//! (`codesign -s - --entitlements entitlements.plist --force target/debug/vm-worker`)。
+//!
+//! ```ignore
+//! let mut pool = VmPool::new(env!("CARGO_BIN_EXE_vm-worker"));
+//! for name in ["a", "b"] {
+//!     pool.spawn(name, &VmSpec { memory_size: 128 << 20, kernel: "output/Image".into(), cmdline: cmdline.clone() })?;
+//! }
+//! pool.get("a").unwrap().console().expect("login: ", Duration::from_secs(30))?;
+//! pool.stop_all()?;
+//! ```
+
+pub mod protocol;
+pub mod quota;
+pub mod worker;
+
+use crate::devices::console::Console;
+use protocol::{Event, Request, VmSpec, WorkerState};
+use quota::CpuQuota;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// ワーカーの応答を待つ時間
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 1 つの VM を動かすワーカープロセス
+pub struct VmWorker {
+    name: String,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    replies: Receiver<Event>,
+    console: Console,
+    exit_reason: Arc<Mutex<Option<String>>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl VmWorker {
+    /// ワーカープロセスを起動する (VM はまだ作成しない)
+    pub fn spawn(worker_bin: &Path, name: &str) -> Result<Self, Box<dyn Error>> {
+        let mut child = Command::new(worker_bin)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to start worker {:?}: {}", worker_bin, e))?;
+        let stdout = child.stdout.take().ok_or("Worker stdout is not piped")?;
+        let console = Console::new();
+        let exit_reason = Arc::new(Mutex::new(None));
+        let (tx, replies) = mpsc::channel();
+
+        // 通知はその場で処理し、応答だけを要求側に渡す
+        let reader = {
+            let (mut sink, exit_reason) = (console.sink(), exit_reason.clone());
+            let name = name.to_string();
+            thread::spawn(move || {
+                for line in BufReader::new(stdout).lines() {
+                    let Ok(line) = line else { break };
+                    match Event::parse(&line) {
+                        Ok(Event::Console(data)) => {
+                            for b in data {
+                                let _ = sink.write_byte(b);
+                            }
+                        }
+                        Ok(Event::Exited(reason)) => *exit_reason.lock().unwrap() = Some(reason),
+                        Ok(reply) => {
+                            if tx.send(reply).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => eprintln!("[{}] Ignoring malformed worker output: {}", name, e),
+                    }
+                }
+            })
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            stdin: child.stdin.take(),
+            child,
+            replies,
+            console,
+            exit_reason,
+            reader: Some(reader),
+        })
+    }
+
+    /// ワーカーの名前
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// VM を作成してカーネルを起動する
+    pub fn start(&mut self, spec: &VmSpec) -> Result<(), Box<dyn Error>> {
+        self.request(&Request::Start(spec.clone())).map(|_| ())
+    }
+
+    /// 実行中のゲストを止める
+    pub fn stop(&mut self) -> Result<(), Box<dyn Error>> {
+        self.request(&Request::Stop).map(|_| ())
+    }
+
+    /// CPU 使用量の上限を設定する (None で解除)
+    ///
+    /// 上限に達したゲストは一時停止され、状態が [`WorkerState::Throttled`] になる。
+    pub fn set_cpu_quota(&mut self, quota: Option<CpuQuota>) -> Result<(), Box<dyn Error>> {
+        self.request(&Request::Quota(quota)).map(|_| ())
+    }
+
+    /// ワーカーの状態
+    pub fn status(&mut self) -> Result<WorkerState, Box<dyn Error>> {
+        match self.request(&Request::Status)? {
+            Event::Status(state) => Ok(state),
+            other => Err(format!("Unexpected reply to STATUS: {:?}", other).into()),
+        }
+    }
+
+    /// ゲストのコンソールに 1 行送る (末尾に改行を付ける)
+    pub fn send_line(&mut self, line: &str) -> Result<(), Box<dyn Error>> {
+        let mut data = line.as_bytes().to_vec();
+        data.push(b'\n');
+        self.request(&Request::Input(data)).map(|_| ())
+    }
+
+    /// ゲストのコンソール出力 (`expect` で待ち合わせる)
+    pub fn console(&self) -> &Console {
+        &self.console
+    }
+
+    /// ゲストの終了理由 (終了していなければ None)
+    pub fn exit_reason(&self) -> Option<String> {
+        self.exit_reason.lock().unwrap().clone()
+    }
+
+    /// 要求を送り、応答を待つ
+    fn request(&mut self, request: &Request) -> Result<Event, Box<dyn Error>> {
+        let stdin = self.stdin.as_mut().ok_or("Worker has been shut down")?;
+        writeln!(stdin, "{}", request.encode())?;
+        stdin.flush()?;
+        let reply = self.replies.recv_timeout(REPLY_TIMEOUT).map_err(|e| {
+            format!(
+                "Worker {} did not reply to {:?}: {}",
+                self.name,
+                request.encode(),
+                e
+            )
+        })?;
+        match reply {
+            Event::Error(message) => Err(format!("Worker {}: {}", self.name, message).into()),
+            reply => Ok(reply),
+        }
+    }
+}
+
+impl Drop for VmWorker {
+    /// 標準入力を閉じてワーカーを終了させる (ゲストは停止される)
+    fn drop(&mut self) {
+        self.stdin = None;
+        let _ = self.child.wait();
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+/// 名前付きのワーカーの集まり
+pub struct VmPool {
+    worker_bin: PathBuf,
+    workers: Vec<VmWorker>,
+    /// 新しく起動する VM に設定する CPU 使用量の上限
+    default_quota: Option<CpuQuota>,
+}
+
+impl VmPool {
+    /// `worker_bin` をワーカーとして使うプールを作成
+    pub fn new(worker_bin: impl Into<PathBuf>) -> Self {
+        Self {
+            worker_bin: worker_bin.into(),
+            workers: Vec::new(),
+            default_quota: None,
+        }
+    }
+
+    /// 以降に [`VmPool::spawn`] で起動する VM に CPU 使用量の上限を設定する
+    ///
+    /// 起動済みの VM は [`VmWorker::set_cpu_quota`] で個別に変更する。
+    pub fn set_default_cpu_quota(&mut self, quota: Option<CpuQuota>) {
+        self.default_quota = quota;
+    }
+
+    /// ワーカーを起動して VM を開始する
+    ///
+    /// # Errors
+    /// 同じ名前のワーカーがある場合、ワーカーの起動や VM の開始に失敗した場合はエラーを返す
+    pub fn spawn(&mut self, name: &str, spec: &VmSpec) -> Result<&mut VmWorker, Box<dyn Error>> {
+        if self.get(name).is_some() {
+            return Err(format!("Worker {} already exists", name).into());
+        }
+        let mut worker = VmWorker::spawn(&self.worker_bin, name)?;
+        worker.start(spec)?;
+        if self.default_quota.is_some() {
+            worker.set_cpu_quota(self.default_quota)?;
+        }
+        self.workers.push(worker);
+        Ok(self.workers.last_mut().unwrap())
+    }
+
+    /// 名前でワーカーを探す
+    pub fn get(&mut self, name: &str) -> Option<&mut VmWorker> {
+        self.workers.iter_mut().find(|w| w.name == name)
+    }
+
+    /// すべてのワーカーの状態
+    pub fn statuses(&mut self) -> Vec<(String, Result<WorkerState, String>)> {
+        self.workers
+            .iter_mut()
+            .map(|w| (w.name.clone(), w.status().map_err(|e| e.to_string())))
+            .collect()
+    }
+
+    /// すべてのゲストを止めてワーカーを終了する
+    ///
+    /// # Errors
+    /// 停止に失敗したワーカーがあれば最初のエラーを返す (ワーカーはすべて終了する)
+    pub fn stop_all(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut first_error = None;
+        for mut worker in self.workers.drain(..) {
+            if matches!(
+                worker.status(),
+                Ok(WorkerState::Running | WorkerState::Throttled)
+            ) {
+                if let Err(e) = worker.stop() {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
@@ -0,0 +1,306 @@
+//! ワーカープロセスとの制御プロトコル
+//!
+//! 1 行に 1 メッセージのテキスト形式で、ワーカーの標準入力に [`Request`] を書き、
+//! 標準出力から [`Event`] を読む。`CONSOLE` と `EXITED` はリクエストと無関係に
+//! 届き、それ以外の Event は直前の Request への応答。
+//!
+//! ```text
+//! > START memory=134217728 kernel=output/Image cmdline=console=ttyAMA0%20rdinit=/init
+//! < OK
+//! < CONSOLE Booting%20Linux...%0a
+//! > STATUS
+//! < STATUS running
+//! > QUOTA cpu=50 burst_ms=200
+//! < OK
+//! > STOP
+//! < OK
+//! < EXITED CANCELED
+//! ```
+//!
+//! 文字列とバイト列は空白・改行・`%` と ASCII 以外を `%XX` でエスケープする。
+
+use super::quota::CpuQuota;
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// ワーカーで起動する VM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmSpec {
+    /// ゲスト RAM のサイズ (bytes)
+    pub memory_size: usize,
+    /// Linux カーネルイメージのパス (ワーカーから見たパス)
+    pub kernel: PathBuf,
+    /// カーネルコマンドライン
+    pub cmdline: String,
+}
+
+/// ワーカーへの要求
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// VM を作成してカーネルを起動する
+    Start(VmSpec),
+    /// 実行中のゲストを止める
+    Stop,
+    /// ワーカーの状態を問い合わせる
+    Status,
+    /// ゲストのコンソールに入力する
+    Input(Vec<u8>),
+    /// CPU 使用量の上限を設定する (None で解除)
+    Quota(Option<CpuQuota>),
+}
+
+/// ワーカーの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerState {
+    /// VM を起動していない
+    Idle,
+    /// ゲストを実行中
+    Running,
+    /// CPU 使用量の上限に達したため、ゲストを一時停止している
+    Throttled,
+    /// ゲストが終了した (`run` から戻った)
+    Exited,
+}
+
+/// ワーカーからの応答と通知
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// 要求を処理した
+    Ok,
+    /// 要求を処理できなかった
+    Error(String),
+    /// `STATUS` への応答
+    Status(WorkerState),
+    /// ゲストのコンソール出力 (通知)
+    Console(Vec<u8>),
+    /// ゲストが終了した (通知、終了理由かエラー)
+    Exited(String),
+}
+
+impl WorkerState {
+    fn as_str(self) -> &'static str {
+        match self {
+            WorkerState::Idle => "idle",
+            WorkerState::Running => "running",
+            WorkerState::Throttled => "throttled",
+            WorkerState::Exited => "exited",
+        }
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn Error>> {
+        match s {
+            "idle" => Ok(WorkerState::Idle),
+            "running" => Ok(WorkerState::Running),
+            "throttled" => Ok(WorkerState::Throttled),
+            "exited" => Ok(WorkerState::Exited),
+            _ => Err(format!("Unknown worker state: {:?}", s).into()),
+        }
+    }
+}
+
+impl fmt::Display for WorkerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Request {
+    /// 1 行 (改行なし) にエンコードする
+    pub fn encode(&self) -> String {
+        match self {
+            Request::Start(spec) => format!(
+                "START memory={} kernel={} cmdline={}",
+                spec.memory_size,
+                escape(spec.kernel.to_string_lossy().as_bytes()),
+                escape(spec.cmdline.as_bytes())
+            ),
+            Request::Stop => "STOP".to_string(),
+            Request::Status => "STATUS".to_string(),
+            Request::Input(data) => format!("INPUT {}", escape(data)),
+            Request::Quota(None) => "QUOTA off".to_string(),
+            Request::Quota(Some(quota)) => format!(
+                "QUOTA cpu={} burst_ms={}",
+                quota.max_percent,
+                quota.burst.as_millis()
+            ),
+        }
+    }
+
+    /// 1 行をデコードする
+    pub fn parse(line: &str) -> Result<Self, Box<dyn Error>> {
+        let (command, args) = split_command(line);
+        match command {
+            "START" => {
+                let (mut memory_size, mut kernel, mut cmdline) = (None, None, String::new());
+                for arg in args.split(' ').filter(|a| !a.is_empty()) {
+                    let (key, value) = arg
+                        .split_once('=')
+                        .ok_or_else(|| format!("Malformed START argument: {:?}", arg))?;
+                    match key {
+                        "memory" => memory_size = Some(value.parse()?),
+                        "kernel" => kernel = Some(PathBuf::from(unescape_string(value)?)),
+                        "cmdline" => cmdline = unescape_string(value)?,
+                        _ => return Err(format!("Unknown START argument: {:?}", key).into()),
+                    }
+                }
+                Ok(Request::Start(VmSpec {
+                    memory_size: memory_size.ok_or("START requires memory=")?,
+                    kernel: kernel.ok_or("START requires kernel=")?,
+                    cmdline,
+                }))
+            }
+            "STOP" => Ok(Request::Stop),
+            "STATUS" => Ok(Request::Status),
+            "INPUT" => Ok(Request::Input(unescape(args)?)),
+            "QUOTA" if args == "off" => Ok(Request::Quota(None)),
+            "QUOTA" => {
+                let (mut max_percent, mut burst) = (None, Duration::ZERO);
+                for arg in args.split(' ').filter(|a| !a.is_empty()) {
+                    let (key, value) = arg
+                        .split_once('=')
+                        .ok_or_else(|| format!("Malformed QUOTA argument: {:?}", arg))?;
+                    match key {
+                        "cpu" => max_percent = Some(value.parse()?),
+                        "burst_ms" => burst = Duration::from_millis(value.parse()?),
+                        _ => return Err(format!("Unknown QUOTA argument: {:?}", key).into()),
+                    }
+                }
+                let max_percent = max_percent.ok_or("QUOTA requires cpu= or off")?;
+                Ok(Request::Quota(Some(CpuQuota::new(max_percent, burst)?)))
+            }
+            _ => Err(format!("Unknown request: {:?}", line).into()),
+        }
+    }
+}
+
+impl Event {
+    /// 1 行 (改行なし) にエンコードする
+    pub fn encode(&self) -> String {
+        match self {
+            Event::Ok => "OK".to_string(),
+            Event::Error(message) => format!("ERROR {}", escape(message.as_bytes())),
+            Event::Status(state) => format!("STATUS {}", state),
+            Event::Console(data) => format!("CONSOLE {}", escape(data)),
+            Event::Exited(reason) => format!("EXITED {}", escape(reason.as_bytes())),
+        }
+    }
+
+    /// 1 行をデコードする
+    pub fn parse(line: &str) -> Result<Self, Box<dyn Error>> {
+        let (command, args) = split_command(line);
+        match command {
+            "OK" => Ok(Event::Ok),
+            "ERROR" => Ok(Event::Error(unescape_string(args)?)),
+            "STATUS" => Ok(Event::Status(WorkerState::parse(args)?)),
+            "CONSOLE" => Ok(Event::Console(unescape(args)?)),
+            "EXITED" => Ok(Event::Exited(unescape_string(args)?)),
+            _ => Err(format!("Unknown event: {:?}", line).into()),
+        }
+    }
+
+    /// リクエストと無関係に届く通知か
+    pub fn is_notification(&self) -> bool {
+        matches!(self, Event::Console(_) | Event::Exited(_))
+    }
+}
+
+fn split_command(line: &str) -> (&str, &str) {
+    let line = line.trim_end_matches(['\r', '\n']);
+    line.split_once(' ').unwrap_or((line, ""))
+}
+
+/// 空白・改行・`%`・ASCII 以外を `%XX` にする
+fn escape(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len());
+    for &b in data {
+        if b.is_ascii_graphic() && b != b'%' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02x}", b));
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s
+                .get(i + 1..i + 3)
+                .ok_or_else(|| format!("Truncated escape in {:?}", s))?;
+            out.push(u8::from_str_radix(hex, 16)?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(out)
+}
+
+fn unescape_string(s: &str) -> Result<String, Box<dyn Error>> {
+    Ok(String::from_utf8(unescape(s)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn 要求はエンコードしてデコードすると元に戻る() {
+        let requests = [
+            Request::Start(VmSpec {
+                memory_size: 128 * 1024 * 1024,
+                kernel: PathBuf::from("/tmp/my kernel/Image"),
+                cmdline: "console=ttyAMA0 rdinit=/init 100%".to_string(),
+            }),
+            Request::Stop,
+            Request::Status,
+            Request::Input(b"root\n".to_vec()),
+            Request::Quota(Some(CpuQuota::new(50, Duration::from_millis(200)).unwrap())),
+            Request::Quota(None),
+        ];
+        for request in requests {
+            let line = request.encode();
+            assert!(!line.contains('\n'));
+            assert_eq!(Request::parse(&line).unwrap(), request);
+        }
+        assert_eq!(
+            Request::Input(b"ls -l\n".to_vec()).encode(),
+            "INPUT ls%20-l%0a"
+        );
+    }
+
+    #[test]
+    fn 応答と通知はエンコードしてデコードすると元に戻る() {
+        let events = [
+            Event::Ok,
+            Event::Error("A Hypervisor already exists".to_string()),
+            Event::Status(WorkerState::Running),
+            Event::Status(WorkerState::Throttled),
+            Event::Console(vec![b'[', 0x1b, 0xff, b'\n']),
+            Event::Exited("CANCELED".to_string()),
+        ];
+        for event in events {
+            assert_eq!(Event::parse(&event.encode()).unwrap(), event);
+        }
+        assert!(Event::Console(Vec::new()).is_notification());
+        assert!(!Event::Ok.is_notification());
+    }
+
+    #[test]
+    fn 不正な行はエラーになる() {
+        assert!(Request::parse("REBOOT").is_err());
+        assert!(Request::parse("START kernel=Image").is_err());
+        assert!(Request::parse("START memory=abc kernel=Image").is_err());
+        assert!(Request::parse("INPUT %4").is_err());
+        assert!(Request::parse("QUOTA burst_ms=100").is_err());
+        assert!(Request::parse("QUOTA cpu=0").is_err());
+        assert!(Event::parse("STATUS sleeping").is_err());
+    }
+}
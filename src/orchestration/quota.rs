@@ -0,0 +1,112 @@
+//! ワーカーごとの CPU 使用量の上限
+//!
+//! 共有の CI ホストで複数の VM を動かすと、暴走したゲストが 1 コアを使い切り、
+//! 他の VM の起動やテストを遅らせる。[`CpuQuota`] は VM ごとにホストの CPU 時間の
+//! 割合 (`max_percent`) と、一時的に超えてよい量 (`burst`) を決める。
+//!
+//! ワーカーは [`QUOTA_PERIOD`] ごとにプロセスの CPU 時間を測って [`QuotaBucket`] に
+//! 記録し、残高が尽きたらゲストを一時停止 (`Hypervisor::set_paused`) して、
+//! 残高が戻ったら再開する。一時停止中はゲストの仮想カウンタも止まるため、
+//! 絞られたゲストからは時間がゆっくり進むように見える。
+
+use std::time::Duration;
+
+/// CPU 時間を測る間隔
+pub const QUOTA_PERIOD: Duration = Duration::from_millis(50);
+
+/// VM が使ってよいホストの CPU 時間
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuQuota {
+    /// 1 コアに対する割合 (%)。デバイスのスレッドを含むため 100 を超えてもよい
+    pub max_percent: u32,
+    /// 平均を超えて連続で使ってよい CPU 時間 (起動直後などの一時的な負荷用)
+    pub burst: Duration,
+}
+
+impl CpuQuota {
+    /// `max_percent` と `burst` を指定して作成
+    ///
+    /// # Errors
+    /// `max_percent` が 0 の場合
+    pub fn new(max_percent: u32, burst: Duration) -> Result<Self, String> {
+        if max_percent == 0 {
+            return Err("CPU quota must be at least 1%".to_string());
+        }
+        Ok(Self { max_percent, burst })
+    }
+}
+
+/// CPU 時間の残高 (トークンバケット)
+///
+/// 経過時間の `max_percent` % ずつ増え、使った CPU 時間だけ減る。残高は `burst` を
+/// 上限とし、負になったらゲストを止める。
+#[derive(Debug, Clone)]
+pub struct QuotaBucket {
+    quota: CpuQuota,
+    /// 残高 (ns、負なら使いすぎ)
+    balance: i128,
+}
+
+impl QuotaBucket {
+    /// 残高を `burst` まで満たした状態で作成
+    pub fn new(quota: CpuQuota) -> Self {
+        Self {
+            quota,
+            balance: quota.burst.as_nanos() as i128,
+        }
+    }
+
+    /// 上限を変更する (残高は新しい `burst` に丸める)
+    pub fn set_quota(&mut self, quota: CpuQuota) {
+        self.quota = quota;
+        self.balance = self.balance.min(quota.burst.as_nanos() as i128);
+    }
+
+    /// `elapsed` の間に `used` の CPU 時間を使ったことを記録する
+    ///
+    /// # Returns
+    /// ゲストを実行してよければ true (残高が負なら false)
+    pub fn account(&mut self, elapsed: Duration, used: Duration) -> bool {
+        let earned = elapsed.as_nanos() as i128 * self.quota.max_percent as i128 / 100;
+        self.balance = (self.balance + earned - used.as_nanos() as i128)
+            .min(self.quota.burst.as_nanos() as i128);
+        self.balance >= 0
+    }
+
+    /// 現在の残高 (使いすぎなら 0)
+    pub fn remaining(&self) -> Duration {
+        Duration::from_nanos(self.balance.clamp(0, u64::MAX as i128) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn 残高を使い切ると止め_割合に応じて戻る() {
+        let quota = CpuQuota::new(25, Duration::from_millis(100)).unwrap();
+        let mut bucket = QuotaBucket::new(quota);
+
+        // 1 コアを使い切るゲスト: 100ms ごとに 25ms 分しか増えない
+        assert!(bucket.account(Duration::from_millis(100), Duration::from_millis(100)));
+        assert_eq!(bucket.remaining(), Duration::from_millis(25));
+        assert!(!bucket.account(Duration::from_millis(100), Duration::from_millis(100)));
+
+        // 止めている間は残高が戻る
+        assert!(!bucket.account(Duration::from_millis(100), Duration::ZERO));
+        assert!(bucket.account(Duration::from_millis(100), Duration::ZERO));
+
+        // アイドルなゲストでも burst を超えては貯まらない
+        bucket.account(Duration::from_secs(10), Duration::ZERO);
+        assert_eq!(bucket.remaining(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn 上限の変更で残高を新しい_burst_に丸める() {
+        let mut bucket = QuotaBucket::new(CpuQuota::new(50, Duration::from_secs(1)).unwrap());
+        bucket.set_quota(CpuQuota::new(50, Duration::from_millis(10)).unwrap());
+        assert_eq!(bucket.remaining(), Duration::from_millis(10));
+        assert!(CpuQuota::new(0, Duration::ZERO).is_err());
+    }
+}
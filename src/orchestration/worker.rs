@@ -0,0 +1,235 @@
+//! ワーカープロセス側: 1 つの VM を制御プロトコルで操作する
+//!
+//! `vm-worker` バイナリは標準入出力で [`serve`] を呼ぶだけ。
+
+use super::protocol::{Event, Request, VmSpec, WorkerState};
+use super::quota::{CpuQuota, QuotaBucket, QUOTA_PERIOD};
+use crate::boot::kernel::KernelImage;
+use crate::boot::layout::MachineLayout;
+use crate::control::ControlHandle;
+use crate::devices::console::{ConsoleInput, ConsoleSink, FlushPolicy};
+use crate::devices::uart::Pl011Uart;
+use crate::host_metrics;
+use crate::vcpu_handle::VcpuHandle;
+use crate::Hypervisor;
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+/// Event の書き込み先 (vCPU スレッドと制御ループで共有する)
+#[derive(Clone)]
+struct EventWriter {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl EventWriter {
+    fn send(&self, event: &Event) -> io::Result<()> {
+        let mut out = self.out.lock().unwrap();
+        writeln!(out, "{}", event.encode())?;
+        out.flush()
+    }
+}
+
+/// UART の出力を `CONSOLE` 通知として送る
+struct ConsoleEvents(EventWriter);
+
+impl Write for ConsoleEvents {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(&Event::Console(buf.to_vec()))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// 起動した VM
+struct RunningVm {
+    vcpu: VcpuHandle,
+    input: ConsoleInput,
+    state: Arc<Mutex<WorkerState>>,
+    /// CPU 使用量の上限 (quota スレッドが読む)
+    quota: Arc<Mutex<Option<CpuQuota>>>,
+    thread: JoinHandle<()>,
+}
+
+/// 制御プロトコルの要求を `input` から読み、応答と通知を `output` に書く
+///
+/// `input` が閉じられたら実行中のゲストを止めて戻る。
+pub fn serve(
+    input: impl BufRead,
+    output: impl Write + Send + 'static,
+) -> Result<(), Box<dyn Error>> {
+    let events = EventWriter {
+        out: Arc::new(Mutex::new(Box::new(output))),
+    };
+    let mut vm: Option<RunningVm> = None;
+    for line in input.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let reply = match Request::parse(&line) {
+            Ok(request) => handle(request, &mut vm, &events),
+            Err(e) => Err(e),
+        };
+        events.send(&reply.unwrap_or_else(|e| Event::Error(e.to_string())))?;
+    }
+    if let Some(vm) = vm {
+        vm.vcpu.request_stop()?;
+        let _ = vm.thread.join();
+    }
+    Ok(())
+}
+
+fn handle(
+    request: Request,
+    vm: &mut Option<RunningVm>,
+    events: &EventWriter,
+) -> Result<Event, Box<dyn Error>> {
+    match request {
+        Request::Start(spec) => {
+            if vm.is_some() {
+                return Err("This worker has already started a VM".into());
+            }
+            *vm = Some(start(spec, events.clone())?);
+            Ok(Event::Ok)
+        }
+        Request::Stop => {
+            running(vm)?.vcpu.request_stop()?;
+            Ok(Event::Ok)
+        }
+        Request::Status => Ok(Event::Status(
+            vm.as_ref()
+                .map_or(WorkerState::Idle, |vm| *vm.state.lock().unwrap()),
+        )),
+        Request::Input(data) => {
+            running(vm)?.input.push(&data);
+            Ok(Event::Ok)
+        }
+        Request::Quota(quota) => {
+            *running(vm)?.quota.lock().unwrap() = quota;
+            Ok(Event::Ok)
+        }
+    }
+}
+
+fn running(vm: &Option<RunningVm>) -> Result<&RunningVm, Box<dyn Error>> {
+    vm.as_ref()
+        .ok_or_else(|| "No VM has been started in this worker".into())
+}
+
+/// vCPU スレッドで VM を作成してカーネルを起動する
+///
+/// vCPU は作成したスレッドでしか実行できないため、作成から実行まで同じスレッドで行う。
+fn start(spec: VmSpec, events: EventWriter) -> Result<RunningVm, Box<dyn Error>> {
+    let kernel = KernelImage::load(&spec.kernel)
+        .map_err(|e| format!("Failed to read kernel {:?}: {}", spec.kernel, e))?;
+    let input = ConsoleInput::new();
+    let state = Arc::new(Mutex::new(WorkerState::Running));
+    let (ready_tx, ready) = mpsc::channel();
+
+    let thread = {
+        let (input, state) = (input.clone(), state.clone());
+        thread::Builder::new()
+            .name("vcpu0".to_string())
+            .spawn(move || {
+                let layout = MachineLayout::default();
+                let mut hv = match Hypervisor::new(layout.ram_base, spec.memory_size) {
+                    Ok(hv) => hv,
+                    Err(e) => return ready_tx.send(Err(e.to_string())).unwrap_or(()),
+                };
+                let sink =
+                    ConsoleSink::new(Box::new(ConsoleEvents(events.clone())), FlushPolicy::Line);
+                let uart = Pl011Uart::with_io(layout.uart_base, sink, input.clone());
+                if let Err(e) = hv.register_mmio_handler(Box::new(uart)) {
+                    return ready_tx.send(Err(e.to_string())).unwrap_or(());
+                }
+                input.set_kick(hv.vcpu_handle());
+                let _ = ready_tx.send(Ok((hv.vcpu_handle(), hv.control_handle())));
+
+                let reason = match hv.boot_linux(&kernel, &spec.cmdline, None) {
+                    Ok(result) => format!("{:?}", result.exit_reason),
+                    Err(e) => format!("error: {}", e),
+                };
+                // UART にバッファされた出力を EXITED より先に送る
+                drop(hv);
+                *state.lock().unwrap() = WorkerState::Exited;
+                let _ = events.send(&Event::Exited(reason));
+            })?
+    };
+
+    let (vcpu, control) = ready
+        .recv()
+        .map_err(|_| "vCPU thread exited before the VM was created")??;
+    let quota = Arc::new(Mutex::new(None));
+    {
+        let (quota, state) = (quota.clone(), state.clone());
+        thread::Builder::new()
+            .name("cpu-quota".to_string())
+            .spawn(move || enforce_quota(&control, &quota, &state))?;
+    }
+    Ok(RunningVm {
+        vcpu,
+        input,
+        state,
+        quota,
+        thread,
+    })
+}
+
+/// ゲストが終了するまで、[`QUOTA_PERIOD`] ごとに CPU 時間を測って上限を守らせる
+///
+/// ワーカーは 1 プロセス 1 VM なので、プロセス全体の CPU 時間を VM の使用量とする。
+fn enforce_quota(
+    control: &ControlHandle,
+    quota: &Mutex<Option<CpuQuota>>,
+    state: &Mutex<WorkerState>,
+) {
+    let mut bucket: Option<QuotaBucket> = None;
+    let (mut last_cpu, _) = host_metrics::process_usage();
+    let mut last_tick = Instant::now();
+    loop {
+        thread::sleep(QUOTA_PERIOD);
+        let current = *state.lock().unwrap();
+        if current == WorkerState::Exited {
+            return;
+        }
+        let (cpu, _) = host_metrics::process_usage();
+        let (used, elapsed) = (cpu.saturating_sub(last_cpu), last_tick.elapsed());
+        (last_cpu, last_tick) = (cpu, Instant::now());
+
+        let allowed = match (*quota.lock().unwrap(), bucket.as_mut()) {
+            (None, _) => {
+                bucket = None;
+                true
+            }
+            (Some(quota), Some(bucket)) => {
+                bucket.set_quota(quota);
+                bucket.account(elapsed, used)
+            }
+            (Some(quota), None) => bucket
+                .insert(QuotaBucket::new(quota))
+                .account(elapsed, used),
+        };
+        let throttled = current == WorkerState::Throttled;
+        if allowed == throttled {
+            let next = if allowed {
+                WorkerState::Running
+            } else {
+                WorkerState::Throttled
+            };
+            // run ループの外 (起動前・終了後) では応答がないので、次の周期でやり直す
+            if control.call(move |hv| hv.set_paused(!allowed)).is_ok() {
+                let mut state = state.lock().unwrap();
+                if *state != WorkerState::Exited {
+                    *state = next;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,385 @@
+//! テスト用のベアメタルペイロード
+//!
+//! デバイスや run ループを変更したときの動作確認に使う、アセンブル済みのゲスト
+//! プログラム。命令を手でエンコードした配列より読みやすく、同じペイロードを
+//! 複数のテストで使い回せる。
+//!
+//! | ペイロード | 確認すること |
+//! |---|---|
+//! | [`TIMER_IRQ_LOOP`] | 仮想タイマー割り込みの GIC 経由の配信と EOI |
+//! | [`MMIO_TORTURE`] | MMIO のアクセス幅・符号拡張・XZR の扱い |
+//! | [`PSCI_EXERCISER`] | PSCI の戻り値 |
+//! | [`MEMORY_PATTERN`] | ゲスト RAM の書き込みと読み戻し |
+//!
+//! ソースは `src/payloads/*.S` にあり、`scripts/build-payloads.sh` で `.bin` を
+//! 作り直す。どのペイロードも入力を X0-X3 で受け取り、結果をレジスタに残して
+//! `BRK #0` で止まる。`run_*` は配置・実行・結果の確認をまとめて行う。
+//!
+//! ```ignore
+//! let mut hv = Hypervisor::new(0x4000_0000, 0x10_0000)?;
+//! payloads::run_psci_exerciser(&mut hv, 0x4000_0000)?;
+//! ```
+//!
+//! 実行には Hypervisor.framework の entitlements が必要。
+
+use crate::mmio::MmioHandler;
+use crate::run_options::RunOptions;
+use crate::{Hypervisor, HypervisorResult};
+use std::error::Error;
+
+/// アセンブル済みのペイロード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Payload {
+    /// 名前 ([`Hypervisor::load_map`] に記録する)
+    pub name: &'static str,
+    /// 機械語 (先頭から実行する)
+    pub code: &'static [u8],
+    /// 配置するアドレスのアライメント (bytes)
+    pub align: u64,
+}
+
+/// 仮想タイマー割り込みを X0 回受け取る
+///
+/// 入力: X0 = 回数, X1 = 間隔 (tick)。結果: X0 = タイマー割り込みの回数,
+/// X1 = タイマー以外の INTID の回数, X2 = 最後の INTID。
+/// ベクタテーブルを先頭から 0x800 に持つため、2KB アラインで配置する。
+pub const TIMER_IRQ_LOOP: Payload = Payload {
+    name: "timer_irq_loop",
+    code: include_bytes!("timer_irq_loop.bin"),
+    align: 0x800,
+};
+
+/// [`ScratchDevice`] にアクセス幅を変えて読み書きする
+///
+/// 入力: X0 = デバイスのベースアドレス。
+/// 結果: X0 = 失敗した確認のビットマスク, X1 = 確認の数。
+pub const MMIO_TORTURE: Payload = Payload {
+    name: "mmio_torture",
+    code: include_bytes!("mmio_torture.bin"),
+    align: 4,
+};
+
+/// PSCI の関数を順に呼ぶ
+///
+/// 結果: X0 = VERSION, X1 = FEATURES(VERSION), X2 = FEATURES(未定義),
+/// X3 = AFFINITY_INFO(0, 0), X4 = CPU_ON(1, 0, 0)。
+pub const PSCI_EXERCISER: Payload = Payload {
+    name: "psci_exerciser",
+    code: include_bytes!("psci_exerciser.bin"),
+    align: 4,
+};
+
+/// ゲスト RAM に [`memory_pattern`] を書いて読み戻す
+///
+/// 入力: X0 = 先頭アドレス, X1 = 長さ, X2 = シード。
+/// 結果: X0 = 一致しなかったワード数, X1 = 確認したワード数。
+pub const MEMORY_PATTERN: Payload = Payload {
+    name: "memory_pattern",
+    code: include_bytes!("memory_pattern.bin"),
+    align: 4,
+};
+
+/// すべてのペイロード
+pub const ALL: [Payload; 4] = [TIMER_IRQ_LOOP, MMIO_TORTURE, PSCI_EXERCISER, MEMORY_PATTERN];
+
+/// BRK の例外クラス (EC)
+const EC_BRK: u64 = 0x3c;
+
+impl Payload {
+    /// ゲスト RAM の `addr` に配置する
+    pub fn load(&self, hv: &mut Hypervisor, addr: u64) -> Result<(), Box<dyn Error>> {
+        if !addr.is_multiple_of(self.align) {
+            return Err(format!(
+                "{} must be loaded at a 0x{:x}-aligned address (got 0x{:x})",
+                self.name, self.align, addr
+            )
+            .into());
+        }
+        hv.load_blob(self.name, addr, self.code)
+    }
+
+    /// `addr` に配置し、X0 から順に `args` を設定して実行する
+    ///
+    /// # Errors
+    /// `BRK #0` 以外で止まった場合はエラーを返す
+    pub fn run(
+        &self,
+        hv: &mut Hypervisor,
+        addr: u64,
+        args: &[u64],
+    ) -> Result<HypervisorResult, Box<dyn Error>> {
+        self.load(hv, addr)?;
+        let mut options = RunOptions::new().pc(addr);
+        for (index, &value) in args.iter().enumerate() {
+            options = options.gpr(index, value);
+        }
+        let result = hv.run_with(&options)?;
+        let ec = result.exception_syndrome.map(|s| (s >> 26) & 0x3f);
+        if ec != Some(EC_BRK) {
+            return Err(format!(
+                "{} stopped at PC 0x{:x} without reaching BRK ({:?}, ESR {:x?})",
+                self.name, result.pc, result.exit_reason, result.exception_syndrome
+            )
+            .into());
+        }
+        Ok(result)
+    }
+
+    /// 機械語の命令列
+    pub fn instructions(&self) -> impl Iterator<Item = u32> + '_ {
+        self.code
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+    }
+}
+
+/// [`TIMER_IRQ_LOOP`] で `count` 回のタイマー割り込みを受け取れることを確認する
+///
+/// # Arguments
+/// * `addr` - 配置するアドレス (2KB アライン)
+/// * `count` - 受け取る割り込みの回数
+/// * `interval` - 割り込みの間隔 (tick)
+pub fn run_timer_irq_loop(
+    hv: &mut Hypervisor,
+    addr: u64,
+    count: u64,
+    interval: u64,
+) -> Result<(), Box<dyn Error>> {
+    let result = TIMER_IRQ_LOOP.run(hv, addr, &[count, interval])?;
+    let [taken, other, last] = [0, 1, 2].map(|i| result.registers[i]);
+    if taken != count || other != 0 {
+        return Err(format!(
+            "timer_irq_loop: took {} of {} timer IRQs, {} other IRQs (last INTID {})",
+            taken, count, other, last
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// [`MMIO_TORTURE`] の読み書きがすべて一致することを確認する
+///
+/// 事前に `device_base` に [`ScratchDevice`] を登録しておくこと。
+///
+/// # Arguments
+/// * `addr` - 配置するアドレス
+/// * `device_base` - [`ScratchDevice`] のベースアドレス
+pub fn run_mmio_torture(
+    hv: &mut Hypervisor,
+    addr: u64,
+    device_base: u64,
+) -> Result<(), Box<dyn Error>> {
+    let result = MMIO_TORTURE.run(hv, addr, &[device_base])?;
+    let (failed, checks) = (result.registers[0], result.registers[1]);
+    if failed != 0 {
+        let which: Vec<_> = (0..checks).filter(|i| failed & (1 << i) != 0).collect();
+        return Err(format!(
+            "mmio_torture: checks {:?} of {} failed (see src/payloads/mmio_torture.S)",
+            which, checks
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// [`PSCI_EXERCISER`] の戻り値が PSCI 1.0 の単一 vCPU として正しいことを確認する
+pub fn run_psci_exerciser(hv: &mut Hypervisor, addr: u64) -> Result<(), Box<dyn Error>> {
+    const PSCI_1_0: u64 = 0x1_0000;
+    const SUCCESS: u64 = 0;
+    const NOT_SUPPORTED: u64 = -1_i64 as u64;
+    const ALREADY_ON: u64 = -4_i64 as u64;
+    const ON: u64 = 0;
+
+    let result = PSCI_EXERCISER.run(hv, addr, &[])?;
+    let checks = [
+        ("PSCI_VERSION", PSCI_1_0),
+        ("PSCI_FEATURES(PSCI_VERSION)", SUCCESS),
+        ("PSCI_FEATURES(0x840000ff)", NOT_SUPPORTED),
+        ("AFFINITY_INFO(0, 0)", ON),
+        ("CPU_ON(1, 0, 0)", ALREADY_ON),
+    ];
+    for (i, (call, expected)) in checks.into_iter().enumerate() {
+        if result.registers[i] != expected {
+            return Err(format!(
+                "psci_exerciser: {} returned 0x{:x}, expected 0x{:x}",
+                call, result.registers[i], expected
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// [`MEMORY_PATTERN`] で `[start, start + len)` を書いて読み戻す
+///
+/// ゲストの読み戻しに加えて、ホストから見た内容も [`memory_pattern`] と比べる。
+///
+/// # Arguments
+/// * `addr` - 配置するアドレス (`start..start + len` と重ならないこと)
+/// * `start` - 書き込む先頭アドレス (8 bytes アライン)
+/// * `len` - 長さ (8 の倍数)
+/// * `seed` - パターンのシード
+pub fn run_memory_pattern(
+    hv: &mut Hypervisor,
+    addr: u64,
+    start: u64,
+    len: u64,
+    seed: u64,
+) -> Result<(), Box<dyn Error>> {
+    if !start.is_multiple_of(8) || !len.is_multiple_of(8) {
+        return Err(format!(
+            "memory_pattern: start 0x{:x} and len 0x{:x} must be multiples of 8",
+            start, len
+        )
+        .into());
+    }
+    let code_end = addr + MEMORY_PATTERN.code.len() as u64;
+    if start < code_end && addr < start + len {
+        return Err(format!(
+            "memory_pattern: region 0x{:x}-0x{:x} overlaps the payload at 0x{:x}",
+            start,
+            start + len,
+            addr
+        )
+        .into());
+    }
+    let result = MEMORY_PATTERN.run(hv, addr, &[start, len, seed])?;
+    let (mismatches, words) = (result.registers[0], result.registers[1]);
+    if mismatches != 0 || words != len / 8 {
+        return Err(format!(
+            "memory_pattern: {} of {} words read back wrong in the guest (expected {} words)",
+            mismatches,
+            words,
+            len / 8
+        )
+        .into());
+    }
+    hv.compare_region(start, &memory_pattern(start, len, seed))
+}
+
+/// [`MEMORY_PATTERN`] が書く内容 (各 8 bytes にアドレス ^ シード)
+pub fn memory_pattern(start: u64, len: u64, seed: u64) -> Vec<u8> {
+    (start..start + len)
+        .step_by(8)
+        .flat_map(|addr| (addr ^ seed).to_le_bytes())
+        .collect()
+}
+
+/// 書き込んだ値をそのまま読み返すデバイス ([`MMIO_TORTURE`] 用)
+///
+/// RAM と同じくリトルエンディアンのバイト列として保持し、幅の異なる
+/// アクセスが重なっても RAM と同じ結果になる。
+pub struct ScratchDevice {
+    base: u64,
+    data: Vec<u8>,
+}
+
+impl ScratchDevice {
+    /// [`MMIO_TORTURE`] が使う大きさ (bytes)
+    pub const SIZE: u64 = 0x40;
+
+    /// `base` に配置する
+    pub fn new(base: u64) -> Self {
+        Self {
+            base,
+            data: vec![0; Self::SIZE as usize],
+        }
+    }
+
+    fn range(&self, offset: u64, size: usize) -> Result<std::ops::Range<usize>, Box<dyn Error>> {
+        let start = offset as usize;
+        if !matches!(size, 1 | 2 | 4 | 8) || start + size > self.data.len() {
+            return Err(format!(
+                "scratch: invalid access of {} bytes at +0x{:x}",
+                size, offset
+            )
+            .into());
+        }
+        Ok(start..start + size)
+    }
+}
+
+impl MmioHandler for ScratchDevice {
+    fn base(&self) -> u64 {
+        self.base
+    }
+
+    fn size(&self) -> u64 {
+        Self::SIZE
+    }
+
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, Box<dyn Error>> {
+        let range = self.range(offset, size)?;
+        let mut bytes = [0u8; 8];
+        bytes[..size].copy_from_slice(&self.data[range]);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn write(&mut self, offset: u64, value: u64, size: usize) -> Result<(), Box<dyn Error>> {
+        let range = self.range(offset, size)?;
+        self.data[range].copy_from_slice(&value.to_le_bytes()[..size]);
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.data.fill(0);
+    }
+
+    fn name(&self) -> &str {
+        "scratch"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::gic::{GIC_CPU_BASE, GIC_DIST_BASE};
+
+    /// `BRK #0` の命令
+    const BRK_0: u32 = 0xD420_0000;
+
+    #[test]
+    fn どのペイロードも_brk_で止まる() {
+        for payload in ALL {
+            assert!(!payload.code.is_empty(), "{}", payload.name);
+            assert_eq!(payload.code.len() % 4, 0, "{}", payload.name);
+            assert!(
+                payload.instructions().any(|inst| inst == BRK_0),
+                "{} has no BRK #0",
+                payload.name
+            );
+        }
+    }
+
+    #[test]
+    fn タイマーペイロードは_qemu_virt_の_gic_を使う() {
+        // timer_irq_loop.S に直接書いているアドレス
+        assert_eq!(GIC_DIST_BASE, 0x0800_0000);
+        assert_eq!(GIC_CPU_BASE, 0x0801_0000);
+        // EL1t と EL1h の IRQ ベクタは B 命令
+        for vector in [0x880, 0xa80] {
+            let inst = TIMER_IRQ_LOOP.instructions().nth(vector / 4).unwrap();
+            assert_eq!(inst >> 26, 0b000101, "vector +0x{:x}", vector);
+        }
+    }
+
+    #[test]
+    fn メモリパターンはアドレスとシードから決まる() {
+        let pattern = memory_pattern(0x4000_1000, 16, 0xff);
+        assert_eq!(pattern.len(), 16);
+        assert_eq!(pattern[..8], 0x4000_10ff_u64.to_le_bytes());
+        assert_eq!(pattern[8..], 0x4000_10f7_u64.to_le_bytes());
+    }
+
+    #[test]
+    fn スクラッチデバイスは幅の異なるアクセスを_ram_と同じに扱う() {
+        let mut dev = ScratchDevice::new(0x0a00_0000);
+        dev.write(0x18, 0x8877_6655_4433_2211, 8).unwrap();
+        dev.write(0x19, 0xff, 1).unwrap();
+        assert_eq!(dev.read(0x18, 8).unwrap(), 0x8877_6655_4433_ff11);
+        assert_eq!(dev.read(0x1a, 2).unwrap(), 0x4433);
+        assert!(dev.read(0x3c, 8).is_err());
+        assert!(dev.write(0, 0, 3).is_err());
+        crate::devices::testing::check_device(&mut ScratchDevice::new(0x0a00_0000)).unwrap();
+    }
+}
@@ -0,0 +1,398 @@
+//! QMP (QEMU Machine Protocol) 風の JSON 制御ソケット
+//!
+//! 外部のツールから VM を操作するための、1 行に 1 つの JSON を送受信するプロトコル。
+//! QEMU の QMP と同じく、接続すると挨拶 (`{"QMP": ...}`) が届き、
+//! `qmp_capabilities` を送ってからコマンドを実行する。
+//!
+//! ```text
+//! < {"QMP": {"version": {"package": "hypervisor 0.1.0"}, "capabilities": []}}
+//! > {"execute": "qmp_capabilities"}
+//! < {"return": {}}
+//! > {"execute": "stop", "id": 1}
+//! < {"return": {}, "id": 1}
+//! > {"execute": "query-status"}
+//! < {"return": {"running": false, "status": "paused"}}
+//! ```
+//!
+//! | コマンド | 引数 | 内容 |
+//! |----------|------|------|
+//! | `query-status` | | 実行中か一時停止中か |
+//! | `stop` / `cont` | | ゲストの一時停止と再開 ([`Hypervisor::set_paused`]) |
+//! | `device_add` | `driver`, `id`, `file`, `read-only` | 空いている virtio-mmio スロットにデバイスを挿す |
+//! | `device_del` | `id` | `device_add` で挿したデバイスを抜く |
+//! | `migrate` | `uri` (`file:PATH`) | VM の状態をファイルに書き出す (`snapshot` feature) |
+//! | `migrate-incoming` | `uri` (`file:PATH`) | 書き出した状態を読み込む (`snapshot` feature) |
+//! | `screendump` | `filename` | フレームバッファがないため常にエラー |
+//! | `query-commands` | | コマンドの一覧 |
+//! | `quit` | | `run()` を `CANCELED` で終わらせる |
+//!
+//! `device_add` の `driver` は `virtio-blk-device` (`virtio-blk` feature) のみ。
+//! QEMU と違い、非同期のイベント (`STOP` など) は送らない。
+//!
+//! コマンドは [`ControlHandle`] で run ループに送るため、`run()` の実行中だけ応答する。
+
+pub mod json;
+
+use crate::control::ControlHandle;
+use crate::Hypervisor;
+use json::Json;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// `query-commands` で返すコマンド
+pub const COMMANDS: &[&str] = &[
+    "qmp_capabilities",
+    "query-status",
+    "query-commands",
+    "stop",
+    "cont",
+    "device_add",
+    "device_del",
+    "migrate",
+    "migrate-incoming",
+    "screendump",
+    "quit",
+];
+
+/// QMP のエラー (`{"error": {"class": ..., "desc": ...}}`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QmpError {
+    /// エラーの種類 (`GenericError`・`CommandNotFound` など)
+    pub class: &'static str,
+    /// 説明
+    pub desc: String,
+}
+
+impl QmpError {
+    fn generic(desc: impl Into<String>) -> Self {
+        Self {
+            class: "GenericError",
+            desc: desc.into(),
+        }
+    }
+
+    fn command_not_found(command: &str) -> Self {
+        Self {
+            class: "CommandNotFound",
+            desc: format!("The command {} has not been found", command),
+        }
+    }
+
+    fn to_json(&self) -> Json {
+        Json::object([
+            ("class", self.class.into()),
+            ("desc", self.desc.as_str().into()),
+        ])
+    }
+}
+
+impl From<Box<dyn Error>> for QmpError {
+    fn from(e: Box<dyn Error>) -> Self {
+        Self::generic(e.to_string())
+    }
+}
+
+/// QMP のサーバー (clone すると `device_add` の ID を共有する)
+#[derive(Clone)]
+pub struct QmpServer {
+    control: ControlHandle,
+    /// `device_add` の ID と virtio-mmio スロット番号
+    devices: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+impl QmpServer {
+    /// `control` でコマンドを実行するサーバーを作成する
+    pub fn new(control: ControlHandle) -> Self {
+        Self {
+            control,
+            devices: Arc::default(),
+        }
+    }
+
+    /// 1 つの接続を処理する
+    ///
+    /// 挨拶を送り、`input` の終わりまで 1 行ずつ JSON のコマンドを読んで応答する。
+    pub fn serve(&self, input: impl BufRead, mut output: impl Write) -> Result<(), Box<dyn Error>> {
+        let greeting = Json::object([(
+            "QMP",
+            Json::object([
+                (
+                    "version",
+                    Json::object([(
+                        "package",
+                        format!("hypervisor {}", env!("CARGO_PKG_VERSION")).into(),
+                    )]),
+                ),
+                ("capabilities", Json::Array(Vec::new())),
+            ]),
+        )]);
+        writeln!(output, "{}", greeting)?;
+        output.flush()?;
+
+        let mut negotiated = false;
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let reply = self.handle_line(&line, &mut negotiated);
+            writeln!(output, "{}", reply)?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Unix ソケット `path` で接続を待ち、接続ごとに [`QmpServer::serve`] する
+    ///
+    /// 接続は 1 つずつ処理する。`path` に残っている古いソケットは作り直す。
+    pub fn listen(self, path: impl AsRef<Path>) -> Result<JoinHandle<()>, Box<dyn Error>> {
+        let path = path.as_ref();
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let Ok(reader) = stream.try_clone() else {
+                    continue;
+                };
+                let _ = self.serve(BufReader::new(reader), stream);
+            }
+        }))
+    }
+
+    /// 1 行の要求を処理して応答を返す
+    fn handle_line(&self, line: &str, negotiated: &mut bool) -> Json {
+        let request = match Json::parse(line) {
+            Ok(request @ Json::Object(_)) => request,
+            Ok(_) => {
+                return error_reply(&QmpError::generic("QMP input must be a JSON object"), None)
+            }
+            Err(e) => return error_reply(&QmpError::generic(e.to_string()), None),
+        };
+        let id = request.get("id").cloned();
+        let Some(command) = request.get("execute").and_then(Json::as_str) else {
+            return error_reply(&QmpError::generic("QMP input lacks member 'execute'"), id);
+        };
+        let empty = Json::empty_object();
+        let args = request.get("arguments").unwrap_or(&empty);
+
+        let result = if command == "qmp_capabilities" {
+            if *negotiated {
+                Err(QmpError {
+                    class: "CommandNotFound",
+                    desc: "Capabilities negotiation is already complete, command ignored"
+                        .to_string(),
+                })
+            } else {
+                *negotiated = true;
+                Ok(Json::empty_object())
+            }
+        } else if !*negotiated {
+            Err(QmpError {
+                class: "CommandNotFound",
+                desc: "Expecting capabilities negotiation with 'qmp_capabilities'".to_string(),
+            })
+        } else {
+            self.execute(command, args)
+        };
+
+        match result {
+            Ok(value) => with_id(Json::object([("return", value)]), id),
+            Err(e) => error_reply(&e, id),
+        }
+    }
+
+    /// 交渉後のコマンドを実行する
+    pub fn execute(&self, command: &str, args: &Json) -> Result<Json, QmpError> {
+        match command {
+            "query-status" => {
+                let paused = self.control.call(|hv| hv.is_paused())?;
+                Ok(Json::object([
+                    ("running", (!paused).into()),
+                    ("status", if paused { "paused" } else { "running" }.into()),
+                ]))
+            }
+            "query-commands" => Ok(Json::Array(
+                COMMANDS
+                    .iter()
+                    .map(|&name| Json::object([("name", name.into())]))
+                    .collect(),
+            )),
+            "stop" => self.set_paused(true),
+            "cont" => self.set_paused(false),
+            "device_add" => self.device_add(args),
+            "device_del" => self.device_del(args),
+            "migrate" => self.migrate(args, false),
+            "migrate-incoming" => self.migrate(args, true),
+            "screendump" => Err(QmpError::generic(
+                "screendump is not available: this machine has no framebuffer",
+            )),
+            "quit" => {
+                self.control.vcpu().request_stop()?;
+                Ok(Json::empty_object())
+            }
+            _ => Err(QmpError::command_not_found(command)),
+        }
+    }
+
+    fn set_paused(&self, paused: bool) -> Result<Json, QmpError> {
+        self.control.call(move |hv| hv.set_paused(paused))?;
+        Ok(Json::empty_object())
+    }
+
+    fn device_add(&self, args: &Json) -> Result<Json, QmpError> {
+        let driver = required_str(args, "driver")?;
+        let id = required_str(args, "id")?.to_string();
+        if self.devices.lock().unwrap().contains_key(&id) {
+            return Err(QmpError::generic(format!("Duplicate device ID '{}'", id)));
+        }
+        let device = new_device(driver, args)?;
+        let slot = self.control.call(move |hv| plug(hv, device))??;
+        self.devices.lock().unwrap().insert(id, slot);
+        Ok(Json::empty_object())
+    }
+
+    fn device_del(&self, args: &Json) -> Result<Json, QmpError> {
+        let id = required_str(args, "id")?;
+        let Some(&slot) = self.devices.lock().unwrap().get(id) else {
+            return Err(QmpError {
+                class: "DeviceNotFound",
+                desc: format!("Device '{}' not found", id),
+            });
+        };
+        self.control.call(move |hv| {
+            if let Some(handle) = hv.virtio_slot(slot) {
+                handle.unbind();
+            }
+        })?;
+        self.devices.lock().unwrap().remove(id);
+        Ok(Json::empty_object())
+    }
+
+    #[cfg(feature = "snapshot")]
+    fn migrate(&self, args: &Json, incoming: bool) -> Result<Json, QmpError> {
+        let uri = required_str(args, "uri")?;
+        let Some(path) = uri.strip_prefix("file:").map(std::path::PathBuf::from) else {
+            return Err(QmpError::generic(format!(
+                "unsupported migration URI '{}' (only file:PATH is supported)",
+                uri
+            )));
+        };
+        self.control
+            .call(move |hv| -> Result<(), String> {
+                let result = if incoming {
+                    std::fs::File::open(&path)
+                        .map_err(Into::into)
+                        .and_then(|mut file| hv.migrate_in(&mut file))
+                } else {
+                    std::fs::File::create(&path)
+                        .map_err(Into::into)
+                        .and_then(|mut file| hv.migrate_out(&mut file))
+                };
+                result.map_err(|e| format!("{}: {}", path.display(), e))
+            })?
+            .map_err(QmpError::generic)?;
+        Ok(Json::empty_object())
+    }
+
+    #[cfg(not(feature = "snapshot"))]
+    fn migrate(&self, _args: &Json, _incoming: bool) -> Result<Json, QmpError> {
+        Err(QmpError::generic(
+            "migration is not available: built without the snapshot feature",
+        ))
+    }
+}
+
+/// 応答に要求の `id` を付ける
+fn with_id(reply: Json, id: Option<Json>) -> Json {
+    match (reply, id) {
+        (Json::Object(mut members), Some(id)) => {
+            members.push(("id".to_string(), id));
+            Json::Object(members)
+        }
+        (reply, _) => reply,
+    }
+}
+
+fn error_reply(error: &QmpError, id: Option<Json>) -> Json {
+    with_id(Json::object([("error", error.to_json())]), id)
+}
+
+fn required_str<'a>(args: &'a Json, name: &str) -> Result<&'a str, QmpError> {
+    match args.get(name) {
+        Some(value) => value.as_str().ok_or_else(|| {
+            QmpError::generic(format!(
+                "Invalid parameter type for '{}', expected: string",
+                name
+            ))
+        }),
+        None => Err(QmpError::generic(format!(
+            "Parameter '{}' is missing",
+            name
+        ))),
+    }
+}
+
+/// `device_add` の `driver` からデバイスを作成する (QMP のスレッドで実行する)
+#[cfg(feature = "virtio-blk")]
+fn new_device(driver: &str, args: &Json) -> Result<HotplugDevice, QmpError> {
+    use crate::devices::virtio::VirtioBlockDevice;
+
+    if driver != "virtio-blk-device" {
+        return Err(QmpError::generic(format!(
+            "'{}' is not a valid device model name",
+            driver
+        )));
+    }
+    let file = required_str(args, "file")?;
+    let read_only = args
+        .get("read-only")
+        .and_then(Json::as_bool)
+        .unwrap_or(false);
+    let image = std::fs::OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .open(file)
+        .map_err(|e| QmpError::generic(format!("Could not open '{}': {}", file, e)))?;
+    let capacity = image
+        .metadata()
+        .map_err(|e| QmpError::generic(e.to_string()))?
+        .len()
+        / crate::devices::virtio::block::SECTOR_SIZE as u64;
+    Ok(Box::new(VirtioBlockDevice::with_disk_image(
+        0, image, capacity,
+    )))
+}
+
+#[cfg(not(feature = "virtio-blk"))]
+fn new_device(driver: &str, _args: &Json) -> Result<HotplugDevice, QmpError> {
+    Err(QmpError::generic(format!(
+        "'{}' is not a valid device model name",
+        driver
+    )))
+}
+
+#[cfg(feature = "virtio-blk")]
+type HotplugDevice = Box<crate::devices::virtio::VirtioBlockDevice>;
+#[cfg(not(feature = "virtio-blk"))]
+type HotplugDevice = Box<dyn crate::mmio::MmioHandler>;
+
+/// 空いている virtio-mmio スロットにデバイスを挿す (run ループで実行する)
+///
+/// ゲスト RAM はスロットが bind のときにデバイスに渡す。
+///
+/// # Returns
+/// 挿したスロットの番号
+fn plug(hv: &mut Hypervisor, device: HotplugDevice) -> Result<u32, QmpError> {
+    let slot = hv.free_virtio_slot().ok_or_else(|| {
+        QmpError::generic("no free virtio-mmio slot (add slots with add_virtio_slots)")
+    })?;
+    slot.bind(device)?;
+    Ok(slot.index())
+}
@@ -0,0 +1,160 @@
+//! ログとコンソール出力の流量制限
+//!
+//! 暴走したゲストは UART に大量の文字を書いたり、未登録の MMIO に
+//! アクセスし続けたりして、端末を警告で埋めてしまう。[`RateLimiter`] は
+//! 一定時間ごとに出力する件数を制限し、捨てた件数を次に出力するときに
+//! 「N messages suppressed」としてまとめて知らせる。
+//!
+//! ```ignore
+//! let mut warnings = RateLimiter::new(10, Duration::from_secs(1));
+//! warnings.warn("MMIO", format_args!("read from unhandled address 0x{:x}", addr));
+//! ```
+//!
+//! [`MmioManager`](crate::mmio::MmioManager) の警告、
+//! [`Tracer`](crate::trace::Tracer) のイベント (カテゴリごと)、
+//! [`ConsoleSink`](crate::devices::console::ConsoleSink) の出力 (バイト単位) に使う。
+
+use crate::devices::host_time::{HostClock, SystemClock};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// 一定時間あたりの件数を制限する
+///
+/// clone すると同じ設定と時計で、件数を数え直す新しい制限になる
+/// (カテゴリごとに制限する場合のひな形として使える)。
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// 1 区間で出力する件数
+    limit: u32,
+    /// 区間の長さ
+    window: Duration,
+    clock: Arc<dyn HostClock>,
+    /// 現在の区間の開始時刻
+    window_start: Duration,
+    /// 現在の区間で出力した件数
+    emitted: u32,
+    /// まだ知らせていない捨てた件数
+    suppressed: u64,
+    /// これまでに捨てた件数
+    total_suppressed: u64,
+}
+
+impl Clone for RateLimiter {
+    fn clone(&self) -> Self {
+        Self::with_clock(self.limit, self.window, Arc::clone(&self.clock))
+    }
+}
+
+impl RateLimiter {
+    /// `window` ごとに `limit` 件まで出力する
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self::with_clock(limit, window, Arc::new(SystemClock::new()))
+    }
+
+    /// 区間の判定に `clock` を使う
+    pub fn with_clock(limit: u32, window: Duration, clock: Arc<dyn HostClock>) -> Self {
+        let window_start = clock.now();
+        Self {
+            limit,
+            window,
+            clock,
+            window_start,
+            emitted: 0,
+            suppressed: 0,
+            total_suppressed: 0,
+        }
+    }
+
+    /// 1 件を出力してよいか判定する
+    ///
+    /// # Returns
+    /// 出力してよければ、その前に捨てた (まだ知らせていない) 件数。
+    /// 捨てる場合は None
+    pub fn check(&mut self) -> Option<u64> {
+        let now = self.clock.now();
+        if now.saturating_sub(self.window_start) >= self.window {
+            self.window_start = now;
+            self.emitted = 0;
+        }
+        if self.emitted < self.limit {
+            self.emitted += 1;
+            Some(self.take_suppressed())
+        } else {
+            self.suppressed += 1;
+            self.total_suppressed += 1;
+            None
+        }
+    }
+
+    /// まだ知らせていない捨てた件数を取り出す
+    ///
+    /// 出力が止んだ後 (run の終了時など) にまとめて知らせるために使う。
+    pub fn take_suppressed(&mut self) -> u64 {
+        std::mem::take(&mut self.suppressed)
+    }
+
+    /// これまでに捨てた件数
+    pub fn total_suppressed(&self) -> u64 {
+        self.total_suppressed
+    }
+
+    /// 制限内なら `[source] message` を標準エラー出力に書く
+    ///
+    /// 捨てた件数があれば先に `[source] N messages suppressed` を書く。
+    pub fn warn(&mut self, source: &str, message: impl fmt::Display) {
+        if let Some(suppressed) = self.check() {
+            report_suppressed(source, suppressed);
+            eprintln!("[{}] {}", source, message);
+        }
+    }
+
+    /// まだ知らせていない捨てた件数があれば標準エラー出力に書く
+    pub fn flush(&mut self, source: &str) {
+        report_suppressed(source, self.take_suppressed());
+    }
+}
+
+fn report_suppressed(source: &str, suppressed: u64) {
+    if suppressed > 0 {
+        eprintln!("[{}] {} messages suppressed", source, suppressed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::host_time::ManualClock;
+
+    #[test]
+    fn 区間ごとに件数を制限し捨てた件数を次の出力で返す() {
+        let clock = ManualClock::new();
+        let mut limiter =
+            RateLimiter::with_clock(2, Duration::from_secs(1), Arc::new(clock.clone()));
+        assert_eq!(limiter.check(), Some(0));
+        assert_eq!(limiter.check(), Some(0));
+        assert_eq!(limiter.check(), None);
+        assert_eq!(limiter.check(), None);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.check(), Some(2));
+        assert_eq!(limiter.check(), Some(0));
+        assert_eq!(limiter.check(), None);
+        assert_eq!(limiter.take_suppressed(), 1);
+        assert_eq!(limiter.take_suppressed(), 0);
+        assert_eq!(limiter.total_suppressed(), 3);
+    }
+
+    #[test]
+    fn clone_は件数を数え直す() {
+        let clock = ManualClock::new();
+        let mut limiter =
+            RateLimiter::with_clock(1, Duration::from_secs(1), Arc::new(clock.clone()));
+        assert_eq!(limiter.check(), Some(0));
+        assert_eq!(limiter.check(), None);
+
+        let mut fresh = limiter.clone();
+        assert_eq!(fresh.check(), Some(0));
+        assert_eq!(fresh.total_suppressed(), 0);
+    }
+}
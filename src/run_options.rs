@@ -0,0 +1,342 @@
+//! `Hypervisor::run_with` に渡す初期レジスタの設定
+//!
+//! [`Hypervisor::run`](crate::Hypervisor::run) は PC と CPSR しか指定できない。
+//! ファームウェアの起動、特定のアドレスを前提にしたテストペイロード、
+//! スナップショットからの再開では汎用レジスタ・SP・VBAR・SCTLR や
+//! 開始する例外レベルも指定する必要があるため、[`RunOptions`] にまとめる。
+//!
+//! ```ignore
+//! let options = RunOptions::new()
+//!     .pc(0x4000_0000)
+//!     .level(ExceptionLevel::El0)
+//!     .gpr(0, dtb_addr)
+//!     .sp(0x4010_0000);
+//! let result = hv.run_with(&options)?;
+//! ```
+//!
+//! 指定しなかったレジスタは vCPU の現在の値のまま実行する。
+
+use crate::boot::vectors::VECTOR_TABLE_SIZE;
+use std::error::Error;
+
+/// 汎用レジスタの数 (X0-X30)
+pub const GPR_COUNT: usize = 31;
+
+/// ゲストの実行を開始する例外レベル
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExceptionLevel {
+    /// EL1h (SP_EL1 を使う)
+    #[default]
+    El1,
+    /// EL0t (SP_EL0 を使う)
+    El0,
+}
+
+impl ExceptionLevel {
+    /// この例外レベルで開始する CPSR (DAIF はすべてマスク)
+    pub fn cpsr(self) -> u64 {
+        match self {
+            Self::El1 => 0x3c5,
+            Self::El0 => 0x3c0,
+        }
+    }
+}
+
+/// ゲストの BRK (AArch32 では BKPT) の扱い
+///
+/// デバッグ例外をトラップしているときだけ意味を持つ。例外ベクタのシムの BRK は
+/// どの設定でも VM Exit で戻る。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BrkPolicy {
+    /// run ループを抜けてホストに戻る
+    #[default]
+    Exit,
+    /// 命令を読み飛ばして実行を続ける
+    Skip,
+    /// ゲストの EL1 に同期例外として渡す (VBAR_EL1 のベクタへ入る)
+    ///
+    /// KASAN や BUG() のように BRK を自分で処理するカーネル、ゲスト内のデバッガ、
+    /// `__builtin_trap` を使うプログラム向け。
+    Forward,
+}
+
+/// `run_with` の初期状態
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunOptions {
+    pc: Option<u64>,
+    level: ExceptionLevel,
+    cpsr: Option<u64>,
+    gprs: Vec<(usize, u64)>,
+    sp: Option<u64>,
+    vbar: Option<u64>,
+    vector_shim: Option<u64>,
+    sctlr: Option<u64>,
+    trap_debug: bool,
+    brk_policy: BrkPolicy,
+    log_brk: bool,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunOptions {
+    /// ゲストの先頭アドレスから EL1h で開始する設定
+    pub fn new() -> Self {
+        Self {
+            pc: None,
+            level: ExceptionLevel::El1,
+            cpsr: None,
+            gprs: Vec::new(),
+            sp: None,
+            vbar: None,
+            vector_shim: None,
+            sctlr: None,
+            trap_debug: true,
+            brk_policy: BrkPolicy::Exit,
+            log_brk: false,
+        }
+    }
+
+    /// 開始アドレス (既定: ゲストの先頭アドレス)
+    pub fn pc(mut self, pc: u64) -> Self {
+        self.pc = Some(pc);
+        self
+    }
+
+    /// 開始する例外レベル (既定: EL1)
+    pub fn level(mut self, level: ExceptionLevel) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// CPSR を直接指定する
+    ///
+    /// 例外レベルから決まる値より優先し、そのまま設定する (AArch32 のモードも指定できる)。
+    pub fn cpsr(mut self, cpsr: u64) -> Self {
+        self.cpsr = Some(cpsr);
+        self
+    }
+
+    /// 汎用レジスタ X`index` の初期値 (検証は [`RunOptions::validate`] で行う)
+    pub fn gpr(mut self, index: usize, value: u64) -> Self {
+        self.gprs.push((index, value));
+        self
+    }
+
+    /// 開始時に使うスタックポインタ
+    ///
+    /// CPSR の SPSel に従い、EL1h なら SP_EL1、EL1t と EL0 なら SP_EL0 に設定する。
+    pub fn sp(mut self, sp: u64) -> Self {
+        self.sp = Some(sp);
+        self
+    }
+
+    /// VBAR_EL1 (EL1 の例外ベクタ)
+    pub fn vbar(mut self, vbar: u64) -> Self {
+        self.vbar = Some(vbar);
+        self
+    }
+
+    /// `addr` に例外ベクタのシムを配置して VBAR_EL1 に設定する
+    ///
+    /// VBAR を設定しないペイロードで想定外の例外が起きたとき、例外を繰り返す
+    /// 代わりに BRK の VM Exit で戻る ([`crate::boot::vectors`])。
+    /// [`RunOptions::vbar`] とは同時に指定できない。
+    pub fn vector_shim(mut self, addr: u64) -> Self {
+        self.vector_shim = Some(addr);
+        self
+    }
+
+    /// SCTLR_EL1 (MMU・キャッシュ・アラインメント検査など)
+    pub fn sctlr(mut self, sctlr: u64) -> Self {
+        self.sctlr = Some(sctlr);
+        self
+    }
+
+    /// デバッグ例外 (BRK など) を EL2 にトラップするか (既定: true)
+    pub fn trap_debug(mut self, trap: bool) -> Self {
+        self.trap_debug = trap;
+        self
+    }
+
+    /// ゲストの BRK の扱い (既定: [`BrkPolicy::Exit`])
+    pub fn brk_policy(mut self, policy: BrkPolicy) -> Self {
+        self.brk_policy = policy;
+        self
+    }
+
+    /// run ループの中で処理した BRK を標準エラーに 1 行ずつ出力するか (既定: false)
+    pub fn log_brk(mut self, log: bool) -> Self {
+        self.log_brk = log;
+        self
+    }
+
+    /// 開始アドレス
+    pub fn initial_pc(&self) -> Option<u64> {
+        self.pc
+    }
+
+    /// 開始する例外レベル
+    pub fn exception_level(&self) -> ExceptionLevel {
+        self.level
+    }
+
+    /// 開始時の CPSR
+    pub fn initial_cpsr(&self) -> u64 {
+        self.cpsr.unwrap_or(self.level.cpsr())
+    }
+
+    /// 指定した汎用レジスタ (番号, 値)。同じレジスタは後の指定が優先される
+    pub fn gprs(&self) -> &[(usize, u64)] {
+        &self.gprs
+    }
+
+    /// 開始時に使うスタックポインタ
+    pub fn initial_sp(&self) -> Option<u64> {
+        self.sp
+    }
+
+    /// 開始時のスタックポインタが SP_EL0 か (CPSR の SPSel が 0)
+    pub fn uses_sp_el0(&self) -> bool {
+        self.initial_cpsr() & 0x1 == 0
+    }
+
+    /// VBAR_EL1 の初期値
+    pub fn initial_vbar(&self) -> Option<u64> {
+        self.vbar
+    }
+
+    /// 例外ベクタのシムを配置するアドレス
+    pub fn vector_shim_addr(&self) -> Option<u64> {
+        self.vector_shim
+    }
+
+    /// SCTLR_EL1 の初期値
+    pub fn initial_sctlr(&self) -> Option<u64> {
+        self.sctlr
+    }
+
+    /// デバッグ例外をトラップするか
+    pub fn traps_debug(&self) -> bool {
+        self.trap_debug
+    }
+
+    /// ゲストの BRK の扱い
+    pub fn brk_handling(&self) -> BrkPolicy {
+        self.brk_policy
+    }
+
+    /// 処理した BRK を出力するか
+    pub fn logs_brk(&self) -> bool {
+        self.log_brk
+    }
+
+    /// 設定を検証する
+    ///
+    /// # Errors
+    /// X30 を超える汎用レジスタを指定した場合と、例外ベクタのシムの指定が
+    /// VBAR・デバッグ例外のトラップ・アラインメントと矛盾する場合、
+    /// デバッグ例外をトラップせずに BRK の扱いを指定した場合はエラーを返す
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(&(index, _)) = self.gprs.iter().find(|&&(index, _)| index >= GPR_COUNT) {
+            return Err(format!(
+                "X{} is not a general-purpose register (X0-X30); use sp() for the stack pointer",
+                index
+            )
+            .into());
+        }
+        if !self.trap_debug && self.brk_policy != BrkPolicy::Exit {
+            return Err(
+                "brk_policy() only applies to trapped BRKs; it needs trap_debug(true)".into(),
+            );
+        }
+        if let Some(addr) = self.vector_shim {
+            if self.vbar.is_some() {
+                return Err(
+                    "vector_shim() sets VBAR_EL1 itself; do not combine it with vbar()".into(),
+                );
+            }
+            if !self.trap_debug {
+                return Err(
+                    "vector_shim() needs trap_debug(true) to turn exceptions into BRK exits".into(),
+                );
+            }
+            if !addr.is_multiple_of(VECTOR_TABLE_SIZE) {
+                return Err(format!(
+                    "vector shim at 0x{:x} is not 0x{:x}-aligned",
+                    addr, VECTOR_TABLE_SIZE
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn 既定は_el1h_でデバッグ例外をトラップする() {
+        let options = RunOptions::default();
+        assert_eq!(options.exception_level(), ExceptionLevel::El1);
+        assert_eq!(options.initial_cpsr(), 0x3c5);
+        assert_eq!(options.initial_pc(), None);
+        assert!(options.traps_debug());
+        assert!(options.validate().is_ok());
+        assert!(!options.uses_sp_el0());
+
+        let options = RunOptions::new().level(ExceptionLevel::El0);
+        assert_eq!(options.initial_cpsr(), 0x3c0);
+        assert!(options.uses_sp_el0());
+        // EL1t は SP_EL0 を使う
+        assert!(RunOptions::new().cpsr(0x3c4).uses_sp_el0());
+    }
+
+    #[test]
+    fn x30_を超える汎用レジスタはエラーになる() {
+        let err = RunOptions::new().gpr(31, 0).validate().unwrap_err();
+        assert!(err.to_string().contains("X31"));
+        assert!(RunOptions::new().gpr(30, 0).validate().is_ok());
+    }
+
+    #[test]
+    fn ベクタのシムは_vbar_やトラップなしの指定と矛盾する() {
+        let options = RunOptions::new().vector_shim(0x4000_0800);
+        assert_eq!(options.vector_shim_addr(), Some(0x4000_0800));
+        assert!(options.validate().is_ok());
+
+        let err = options.clone().vbar(0x4000_0000).validate().unwrap_err();
+        assert!(err.to_string().contains("vbar()"));
+        let err = options.trap_debug(false).validate().unwrap_err();
+        assert!(err.to_string().contains("trap_debug"));
+        let err = RunOptions::new()
+            .vector_shim(0x4000_0400)
+            .validate()
+            .unwrap_err();
+        assert!(err.to_string().contains("aligned"));
+    }
+
+    #[test]
+    fn brk_の扱いはデバッグ例外のトラップが必要() {
+        assert_eq!(RunOptions::new().brk_handling(), BrkPolicy::Exit);
+        let options = RunOptions::new().brk_policy(BrkPolicy::Forward);
+        assert_eq!(options.brk_handling(), BrkPolicy::Forward);
+        assert!(options.validate().is_ok());
+
+        let err = options.trap_debug(false).validate().unwrap_err();
+        assert!(err.to_string().contains("trap_debug"));
+        assert!(RunOptions::new().trap_debug(false).validate().is_ok());
+    }
+
+    #[test]
+    fn cpsr_の指定は例外レベルより優先する() {
+        let options = RunOptions::new().level(ExceptionLevel::El0).cpsr(0x3c4);
+        assert_eq!(options.initial_cpsr(), 0x3c4);
+        assert!(options.validate().is_ok());
+    }
+}
@@ -0,0 +1,519 @@
+//! ホストの Hypervisor.framework の動作確認 (セルフテスト)
+//!
+//! run ループやデバイスは、どのシステムレジスタがトラップされるか、WFI で
+//! VM Exit するか、Data Abort の syndrome (ISV) が有効になるアクセスはどれか、
+//! といったホストの動作を前提にしている。これらは macOS のバージョンや CPU で
+//! 変わりうるため、短い命令列をゲストで実行して実際の動作を表にまとめる。
+//!
+//! 命令列は run ループを通さずに vCPU で直接実行し、VM Exit をそのまま記録する
+//! (トラップしたシステムレジスタの読み取りは 0、MMIO の読み取りは 0 を返して
+//! 次の命令に進む)。ゲストの中で例外が起きた場合は例外ベクタのシム
+//! ([`crate::boot::vectors`]) で止まり、ESR_EL1 の例外クラスを記録する。
+//!
+//! ```ignore
+//! let mut hv = Hypervisor::new(0x4000_0000, 0x10_0000)?;
+//! let report = hv.selftest()?;
+//! println!("{}", report);
+//! for violation in report.violated_assumptions() {
+//!     eprintln!("warning: {}", violation);
+//! }
+//! ```
+
+use crate::backend::VcpuBackend;
+use crate::boot::stub::encode;
+use crate::boot::vectors::{self, ShimVector};
+use crate::memory::GuestRam;
+use applevisor::{ExitReason, InterruptType, Reg, SysReg};
+use std::error::Error;
+use std::fmt;
+
+/// 命令列とベクタテーブルに使うゲスト RAM の大きさ (RAM の先頭から)
+pub const SCRATCH_SIZE: u64 = 0x1000;
+
+/// ベクタテーブルの配置 (RAM 先頭からのオフセット、2KB アライン)
+const VECTOR_OFFSET: u64 = 0x800;
+
+/// EL1h、DAIF すべてマスク
+const CPSR_EL1H_MASKED: u64 = 0x3c5;
+
+/// 1 つの命令列で許す VM Exit の数
+const MAX_EXITS: usize = 16;
+
+/// 命令列の実行結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// VM Exit なしでゲストの中で完了した
+    Passthrough,
+    /// VM Exit した (最初の VM Exit の ESR_EL2)
+    Trapped { syndrome: u64 },
+    /// ゲストの EL1 に例外が入った (ESR_EL1 の例外クラス)
+    GuestException { ec: u8 },
+}
+
+impl Outcome {
+    /// VM Exit したか
+    pub fn is_trapped(&self) -> bool {
+        matches!(self, Outcome::Trapped { .. })
+    }
+
+    /// Data Abort の syndrome が有効 (ISV=1) か
+    pub fn has_valid_syndrome(&self) -> bool {
+        matches!(self, Outcome::Trapped { syndrome } if syndrome & (1 << 24) != 0)
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Passthrough => write!(f, "passthrough"),
+            Outcome::Trapped { syndrome } => write!(
+                f,
+                "trap (EC=0x{:x}, ISS=0x{:x})",
+                (syndrome >> 26) & 0x3f,
+                syndrome & 0x1ff_ffff
+            ),
+            Outcome::GuestException { ec } => write!(f, "guest exception (EC=0x{:x})", ec),
+        }
+    }
+}
+
+/// システムレジスタへのアクセスの向き
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysregAccess {
+    /// MRS
+    Read,
+    /// MSR (XZR を書き込む)
+    Write,
+}
+
+/// システムレジスタのエンコーディング (op0, op1, CRn, CRm, op2)
+type Encoding = (u8, u8, u8, u8, u8);
+
+/// 確認するシステムレジスタ
+///
+/// 書き込みは 0 を書いても害のないレジスタだけにする。
+const SYSREGS: &[(&str, Encoding, &[SysregAccess])] = {
+    use SysregAccess::{Read, Write};
+    &[
+        ("CNTFRQ_EL0", (3, 3, 14, 0, 0), &[Read]),
+        ("CNTPCT_EL0", (3, 3, 14, 0, 1), &[Read]),
+        ("CNTVCT_EL0", (3, 3, 14, 0, 2), &[Read]),
+        ("CNTP_CTL_EL0", (3, 3, 14, 2, 1), &[Read, Write]),
+        ("CNTP_CVAL_EL0", (3, 3, 14, 2, 2), &[Read, Write]),
+        ("CNTV_CTL_EL0", (3, 3, 14, 3, 1), &[Read, Write]),
+        ("CNTV_CVAL_EL0", (3, 3, 14, 3, 2), &[Read, Write]),
+        ("MIDR_EL1", (3, 0, 0, 0, 0), &[Read]),
+        ("MPIDR_EL1", (3, 0, 0, 0, 5), &[Read]),
+        ("ID_AA64PFR0_EL1", (3, 0, 0, 4, 0), &[Read]),
+        ("ID_AA64DFR0_EL1", (3, 0, 0, 5, 0), &[Read]),
+        ("ID_AA64MMFR0_EL1", (3, 0, 0, 7, 0), &[Read]),
+        ("CTR_EL0", (3, 3, 0, 0, 1), &[Read]),
+        ("ICC_SRE_EL1", (3, 0, 12, 12, 5), &[Read]),
+        ("PMCR_EL0", (3, 3, 9, 12, 0), &[Read]),
+        ("MDSCR_EL1", (2, 0, 0, 2, 2), &[Read, Write]),
+        ("OSLAR_EL1", (2, 0, 1, 0, 4), &[Write]),
+        ("OSLSR_EL1", (2, 0, 1, 1, 4), &[Read]),
+    ]
+};
+
+/// 確認する MMIO アクセスの形式 (X1 = アドレス、X2 = 0)
+const DATA_ABORTS: &[(&str, u32)] = &[
+    ("LDR X0, [X1]", 0xF940_0020),
+    ("STR X0, [X1]", 0xF900_0020),
+    ("LDR W0, [X1, X2]", 0xB862_6820),
+    ("LDRSB X0, [X1]", 0x3980_0020),
+    ("LDAR X0, [X1]", 0xC8DF_FC20),
+    ("STLR X0, [X1]", 0xC89F_FC20),
+    ("LDR X0, [X1], #8", 0xF840_8420),
+    ("LDR X0, [X1, #8]!", 0xF840_8C20),
+    ("LDP X0, X2, [X1]", 0xA940_0820),
+    ("STP X0, X2, [X1]", 0xA900_0820),
+    ("LDXR X0, [X1]", 0xC85F_7C20),
+    ("STR Q0, [X1]", 0x3D80_0020),
+];
+
+/// システムレジスタ 1 つの結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SysregResult {
+    /// レジスタ名
+    pub name: &'static str,
+    /// アクセスの向き
+    pub access: SysregAccess,
+    /// 結果
+    pub outcome: Outcome,
+}
+
+/// CNTVCT_EL0 の確認結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CounterResult {
+    /// 2 回の読み取りで減らなかった
+    pub monotonic: bool,
+    /// ゲストの値がホストのカウンタから vtimer_offset を引いた値だった
+    pub offset_applied: bool,
+}
+
+/// セルフテストの結果 (ホストの能力表)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    /// システムレジスタごとの結果
+    pub sysregs: Vec<SysregResult>,
+    /// CNTVCT_EL0 の読み取り
+    pub counter: CounterResult,
+    /// IRQ がペンディングの状態での WFI
+    pub wfi: Outcome,
+    /// SEVL の直後の WFE
+    pub wfe: Outcome,
+    /// MMIO アクセスの形式ごとの結果
+    pub data_aborts: Vec<(&'static str, Outcome)>,
+}
+
+impl SelfTestReport {
+    /// システムレジスタの結果
+    pub fn sysreg(&self, name: &str, access: SysregAccess) -> Option<Outcome> {
+        self.sysregs
+            .iter()
+            .find(|r| r.name == name && r.access == access)
+            .map(|r| r.outcome)
+    }
+
+    /// MMIO アクセスの形式の結果
+    pub fn data_abort(&self, form: &str) -> Option<Outcome> {
+        self.data_aborts
+            .iter()
+            .find(|(name, _)| *name == form)
+            .map(|&(_, outcome)| outcome)
+    }
+
+    /// このハイパーバイザーが前提にしている動作と異なる点
+    ///
+    /// 空でなければ、run ループやデバイスが期待どおりに動かない可能性がある。
+    pub fn violated_assumptions(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+        let mut expect = |ok: bool, what: &str, outcome: Option<Outcome>| {
+            if !ok {
+                let seen = outcome.map_or("not probed".to_string(), |o| o.to_string());
+                violations.push(format!("{} (got: {})", what, seen));
+            }
+        };
+
+        let mmfr0 = self.sysreg("ID_AA64MMFR0_EL1", SysregAccess::Read);
+        expect(
+            mmfr0 == Some(Outcome::Passthrough),
+            "ID_AA64MMFR0_EL1 reads should not trap (set_address_config)",
+            mmfr0,
+        );
+        let cntvct = self.sysreg("CNTVCT_EL0", SysregAccess::Read);
+        expect(
+            cntvct == Some(Outcome::Passthrough),
+            "CNTVCT_EL0 reads should not trap",
+            cntvct,
+        );
+        expect(
+            self.counter.monotonic && self.counter.offset_applied,
+            "CNTVCT_EL0 should count up from vtimer_offset",
+            cntvct,
+        );
+        expect(
+            self.wfi.is_trapped(),
+            "WFI should exit to the host (idle handling)",
+            Some(self.wfi),
+        );
+        for form in ["LDR X0, [X1]", "STR X0, [X1]"] {
+            let outcome = self.data_abort(form);
+            expect(
+                outcome.is_some_and(|o| o.has_valid_syndrome()),
+                &format!("{} to MMIO should report ISV=1 (handle_data_abort)", form),
+                outcome,
+            );
+        }
+        violations
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "System registers:")?;
+        for r in &self.sysregs {
+            let access = match r.access {
+                SysregAccess::Read => "MRS",
+                SysregAccess::Write => "MSR",
+            };
+            writeln!(f, "  {:<18} {} {}", r.name, access, r.outcome)?;
+        }
+        writeln!(
+            f,
+            "CNTVCT_EL0: monotonic={} offset_applied={}",
+            self.counter.monotonic, self.counter.offset_applied
+        )?;
+        writeln!(f, "WFI (IRQ pending): {}", self.wfi)?;
+        writeln!(f, "WFE (after SEVL): {}", self.wfe)?;
+        writeln!(f, "MMIO data aborts:")?;
+        for (form, outcome) in &self.data_aborts {
+            let isv = match outcome {
+                Outcome::Trapped { .. } if outcome.has_valid_syndrome() => " ISV=1",
+                Outcome::Trapped { .. } => " ISV=0",
+                _ => "",
+            };
+            writeln!(f, "  {:<20} {}{}", form, outcome, isv)?;
+        }
+        Ok(())
+    }
+}
+
+/// 命令列を 1 回実行した結果
+struct Probe {
+    outcome: Outcome,
+    registers: [u64; 2],
+}
+
+/// `code` を RAM の先頭に置き、`BRK` で止まるまで vCPU を直接実行する
+fn probe(
+    vcpu: &dyn VcpuBackend,
+    mem: &GuestRam,
+    base: u64,
+    code: &[u32],
+) -> Result<Probe, Box<dyn Error>> {
+    let bytes: Vec<u8> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    mem.write(base, &bytes)?;
+    vcpu.set_reg(Reg::PC, base)?;
+    vcpu.set_reg(Reg::CPSR, CPSR_EL1H_MASKED)?;
+    vcpu.set_sys_reg(SysReg::VBAR_EL1, base + VECTOR_OFFSET)?;
+    vcpu.set_sys_reg(SysReg::ESR_EL1, 0)?;
+
+    let mut first_trap = None;
+    for _ in 0..MAX_EXITS {
+        vcpu.run()?;
+        let exit = vcpu.get_exit_info();
+        if !matches!(exit.reason, ExitReason::EXCEPTION) {
+            continue;
+        }
+        let syndrome = exit.exception.syndrome;
+        let iss = syndrome & 0x1ff_ffff;
+        let pc = vcpu.get_reg(Reg::PC)?;
+        match (syndrome >> 26) & 0x3f {
+            0x3c => {
+                let outcome = if ShimVector::from_syndrome(syndrome).is_some() {
+                    let esr = vcpu.get_sys_reg(SysReg::ESR_EL1)?;
+                    Outcome::GuestException {
+                        ec: ((esr >> 26) & 0x3f) as u8,
+                    }
+                } else {
+                    first_trap.map_or(Outcome::Passthrough, |syndrome| Outcome::Trapped {
+                        syndrome,
+                    })
+                };
+                return Ok(Probe {
+                    outcome,
+                    registers: [vcpu.get_reg(Reg::X0)?, vcpu.get_reg(Reg::X1)?],
+                });
+            }
+            // MRS (Direction = 1) は 0 を読んだことにする
+            0x18 => {
+                let rt = (iss >> 5) & 0x1f;
+                if let Some(reg) = crate::gpr(rt as usize).filter(|_| iss & 1 == 1) {
+                    vcpu.set_reg(reg, 0)?;
+                }
+                vcpu.set_reg(Reg::PC, pc + 4)?;
+            }
+            0x01 => vcpu.set_reg(Reg::PC, pc + 4)?,
+            // MMIO の読み取りは 0 を読んだことにする (ISV=0 なら何もしない)
+            0x24 => {
+                let srt = (iss >> 16) & 0x1f;
+                if let Some(reg) =
+                    crate::gpr(srt as usize).filter(|_| iss & (1 << 24) != 0 && iss & (1 << 6) == 0)
+                {
+                    vcpu.set_reg(reg, 0)?;
+                }
+                vcpu.set_reg(Reg::PC, pc + 4)?;
+            }
+            ec => {
+                return Err(format!(
+                    "selftest: unexpected exception EC=0x{:x} at PC 0x{:x}",
+                    ec, pc
+                )
+                .into())
+            }
+        }
+        first_trap.get_or_insert(syndrome);
+    }
+    Err(format!("selftest: no BRK after {} VM exits", MAX_EXITS).into())
+}
+
+/// すべての確認を実行する
+///
+/// RAM の先頭 [`SCRATCH_SIZE`] bytes と vCPU のレジスタを書き換える。
+/// 退避と復元は [`crate::Hypervisor::selftest`] が行う。
+///
+/// # Arguments
+/// * `base` - ゲスト RAM の先頭アドレス
+/// * `mmio_addr` - RAM もデバイスも割り当てていない (Data Abort になる) アドレス
+pub fn run(
+    vcpu: &dyn VcpuBackend,
+    mem: &GuestRam,
+    base: u64,
+    mmio_addr: u64,
+) -> Result<SelfTestReport, Box<dyn Error>> {
+    mem.write(base + VECTOR_OFFSET, &vectors::shim_table())?;
+
+    let mut sysregs = Vec::new();
+    for &(name, (op0, op1, crn, crm, op2), accesses) in SYSREGS {
+        for &access in accesses {
+            let inst = match access {
+                SysregAccess::Read => encode::mrs(op0, op1, crn, crm, op2, 0),
+                SysregAccess::Write => encode::msr(op0, op1, crn, crm, op2, 31),
+            };
+            let outcome = probe(vcpu, mem, base, &[inst, encode::BRK0])?.outcome;
+            sysregs.push(SysregResult {
+                name,
+                access,
+                outcome,
+            });
+        }
+    }
+
+    // CNTVCT_EL0 を 2 回読み、ホストのカウンタと比べる
+    let offset = vcpu.get_vtimer_offset()?;
+    let before = vcpu.hardware_counter();
+    let mrs_cntvct = |rt| encode::mrs(3, 3, 14, 0, 2, rt);
+    let counter = probe(
+        vcpu,
+        mem,
+        base,
+        &[mrs_cntvct(0), encode::ISB, mrs_cntvct(1), encode::BRK0],
+    )?;
+    let after = vcpu.hardware_counter();
+    let [first, second] = counter.registers;
+    let counter = CounterResult {
+        monotonic: second >= first,
+        offset_applied: (before.wrapping_sub(offset)..=after.wrapping_sub(offset)).contains(&first),
+    };
+
+    // IRQ をマスクしたままペンディングにしておくと、WFI はトラップされなくても止まらない
+    vcpu.set_pending_interrupt(InterruptType::IRQ, true)?;
+    let wfi = probe(vcpu, mem, base, &[0xD503_207F, encode::BRK0]);
+    vcpu.set_pending_interrupt(InterruptType::IRQ, false)?;
+    let wfi = wfi?.outcome;
+    // SEVL でイベントを立てておくと、WFE はトラップされなくても止まらない
+    let wfe = probe(vcpu, mem, base, &[0xD503_20BF, 0xD503_205F, encode::BRK0])?.outcome;
+
+    let mut data_aborts = Vec::new();
+    for &(form, inst) in DATA_ABORTS {
+        let code = [
+            encode::movz(1, mmio_addr as u16, 0),
+            encode::movk(1, (mmio_addr >> 16) as u16, 1),
+            encode::movk(1, (mmio_addr >> 32) as u16, 2),
+            encode::movz(2, 0, 0),
+            inst,
+            encode::BRK0,
+        ];
+        data_aborts.push((form, probe(vcpu, mem, base, &code)?.outcome));
+    }
+
+    Ok(SelfTestReport {
+        sysregs,
+        counter,
+        wfi,
+        wfe,
+        data_aborts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{MockExit, MockVcpu, MockVm};
+
+    const BASE: u64 = 0x4000_0000;
+
+    fn mapped_ram() -> GuestRam {
+        let mut ram = GuestRam::new(0x10_0000).unwrap();
+        ram.map(&MockVm, BASE).unwrap();
+        ram
+    }
+
+    #[test]
+    fn vm_exit_なしで_brk_に届けばパススルー() {
+        let (vcpu, ram) = (MockVcpu::new(), mapped_ram());
+        vcpu.push_exit(MockExit::brk().reg(Reg::X0, 7));
+        let result = probe(&vcpu, &ram, BASE, &[encode::BRK0]).unwrap();
+        assert_eq!(result.outcome, Outcome::Passthrough);
+        assert_eq!(result.registers[0], 7);
+        assert_eq!(vcpu.sys_reg(SysReg::VBAR_EL1), BASE + VECTOR_OFFSET);
+    }
+
+    #[test]
+    fn トラップしたシステムレジスタは_0_を読んで次の命令に進む() {
+        let (vcpu, ram) = (MockVcpu::new(), mapped_ram());
+        // MRS X3, CNTP_CTL_EL0
+        let syndrome = 0x18 << 26 | 1 << 25 | 3 << 20 | 3 << 14 | 14 << 10 | 3 << 5 | 2 << 1 | 1;
+        vcpu.push_exit(MockExit::exception(syndrome, 0).reg(Reg::X3, 0xdead));
+        vcpu.push_exit(MockExit::brk());
+        let result = probe(&vcpu, &ram, BASE, &[0, encode::BRK0]).unwrap();
+        assert_eq!(result.outcome, Outcome::Trapped { syndrome });
+        assert_eq!(vcpu.reg(Reg::X3), 0);
+        assert_eq!(vcpu.reg(Reg::PC), BASE + 4);
+
+        // MSR X3, CNTP_CTL_EL0 (Direction = 0) は X3 を書き換えない
+        let (vcpu, ram) = (MockVcpu::new(), mapped_ram());
+        let syndrome = syndrome & !1;
+        vcpu.push_exit(MockExit::exception(syndrome, 0).reg(Reg::X3, 0xdead));
+        vcpu.push_exit(MockExit::brk());
+        probe(&vcpu, &ram, BASE, &[0, encode::BRK0]).unwrap();
+        assert_eq!(vcpu.reg(Reg::X3), 0xdead);
+        assert_eq!(vcpu.reg(Reg::PC), BASE + 4);
+    }
+
+    #[test]
+    fn ベクタの_brk_はゲストの例外として記録する() {
+        let (vcpu, ram) = (MockVcpu::new(), mapped_ram());
+        vcpu.push_exit(
+            MockExit::exception(
+                0x3c << 26 | 1 << 25 | (vectors::SHIM_BRK_BASE as u64 + 4),
+                0,
+            )
+            .sys_reg(SysReg::ESR_EL1, 1 << 25),
+        );
+        let result = probe(&vcpu, &ram, BASE, &[0, encode::BRK0]).unwrap();
+        assert_eq!(result.outcome, Outcome::GuestException { ec: 0 });
+    }
+
+    #[test]
+    fn 前提と異なる動作を報告する() {
+        let isv = |valid: bool| Outcome::Trapped {
+            syndrome: 0x24 << 26 | (valid as u64) << 24,
+        };
+        let mut report = SelfTestReport {
+            sysregs: vec![
+                SysregResult {
+                    name: "ID_AA64MMFR0_EL1",
+                    access: SysregAccess::Read,
+                    outcome: Outcome::Passthrough,
+                },
+                SysregResult {
+                    name: "CNTVCT_EL0",
+                    access: SysregAccess::Read,
+                    outcome: Outcome::Passthrough,
+                },
+            ],
+            counter: CounterResult {
+                monotonic: true,
+                offset_applied: true,
+            },
+            wfi: Outcome::Trapped {
+                syndrome: 0x01 << 26,
+            },
+            wfe: Outcome::Passthrough,
+            data_aborts: vec![("LDR X0, [X1]", isv(true)), ("STR X0, [X1]", isv(true))],
+        };
+        assert!(report.violated_assumptions().is_empty());
+        assert!(report.to_string().contains("LDR X0, [X1]"));
+
+        report.wfi = Outcome::Passthrough;
+        report.data_aborts[1].1 = isv(false);
+        let violations = report.violated_assumptions();
+        assert_eq!(violations.len(), 2, "{:?}", violations);
+        assert!(violations[0].starts_with("WFI"));
+        assert!(violations[1].contains("STR X0, [X1]"));
+        assert!(report.to_string().contains("ISV=0"));
+    }
+}
@@ -0,0 +1,340 @@
+//! 実行統計
+//!
+//! VM Exit の回数、デバイスごとの MMIO 処理時間やタイマー割り込みのレイテンシを集計し、
+//! `Hypervisor::stats()` で公開する。
+//! 起動が遅い場合に、UART の出力・GIC の走査・ディスク I/O のどこで
+//! 時間を使っているかを切り分けるために使用する。
+
+use std::time::Duration;
+
+/// 処理時間の集計 (最小・平均・最大)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    /// 記録した回数
+    pub count: u64,
+    /// 合計時間
+    pub total: Duration,
+    /// 最小時間 (未記録なら 0)
+    pub min: Duration,
+    /// 最大時間
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    /// 処理時間を 1 回分記録する
+    pub fn record(&mut self, elapsed: Duration) {
+        if self.count == 0 || elapsed < self.min {
+            self.min = elapsed;
+        }
+        if elapsed > self.max {
+            self.max = elapsed;
+        }
+        self.count += 1;
+        self.total += elapsed;
+    }
+
+    /// 平均時間 (未記録なら 0)
+    pub fn avg(&self) -> Duration {
+        if self.count == 0 {
+            Duration::ZERO
+        } else {
+            self.total / self.count as u32
+        }
+    }
+}
+
+/// デバイスがホストとやり取りしたデータ量 (ディスクなど)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoCounters {
+    /// ホストから読み取ったバイト数
+    pub read_bytes: u64,
+    /// ホストへ書き込んだバイト数
+    pub written_bytes: u64,
+}
+
+/// virtio-blk のリクエストの統計
+///
+/// `reads` / `writes` / `flushes` / `errors` は、ゲストがキューに入れたリクエストと、
+/// ホストが `VirtioBlockDevice::read_sectors` などで直接行った読み書きの両方を数える。
+/// `rejected` と `queue_depth` はゲストのキューだけが対象。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockStats {
+    /// 読み取りリクエスト数
+    pub reads: u64,
+    /// 書き込みリクエスト数
+    pub writes: u64,
+    /// フラッシュリクエスト数
+    pub flushes: u64,
+    /// 失敗したリクエスト数 (ホストの I/O エラーと障害注入)
+    pub errors: u64,
+    /// 不正な記述子のため IOERR で拒否したリクエスト数
+    pub rejected: u64,
+    /// リクエスト 1 つ分のホスト側の処理時間
+    pub latency: LatencyStats,
+    /// ドライバーが追加し、デバイスがまだ取り出していないリクエスト数
+    ///
+    /// 参照した時点のゲスト RAM 上の Available Ring と Used Ring の差。
+    /// 0 のままゲストの I/O が止まっているならゲスト側、溜まっているなら
+    /// デバイス側 (通知が届いていないか処理が遅い) の問題。
+    pub queue_depth: u16,
+}
+
+/// MMIO デバイス 1 つ分の統計
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceStats {
+    /// デバイス名 (`MmioHandler::name`)
+    pub name: String,
+    /// MMIO ベースアドレス
+    pub base: u64,
+    /// MMIO 読み取りの処理時間
+    pub reads: LatencyStats,
+    /// MMIO 書き込みの処理時間
+    pub writes: LatencyStats,
+    /// virtio キュー処理 (QueueNotify 1 回分) の処理時間 (virtio デバイスのみ)
+    pub queue: Option<LatencyStats>,
+    /// ホスト側の I/O 量 (ディスクなどのバックエンドを持つデバイスのみ)
+    pub io: Option<IoCounters>,
+    /// virtio-blk のリクエストの統計 (virtio-blk のみ)
+    pub block: Option<BlockStats>,
+}
+
+/// VM Exit の回数
+///
+/// run ループが Exit ごとに数え、ログ出力の間引きにも使う。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExitStats {
+    /// VM Exit の総数
+    pub exit_count: u64,
+    /// WFI/WFE による Exit
+    pub wfi_count: u64,
+    /// データアボート (MMIO) による Exit
+    pub mmio_count: u64,
+    /// `VTIMER_ACTIVATED` の Exit
+    pub vtimer_activated_count: u64,
+    /// その他の例外による Exit
+    pub other_exception_count: u64,
+    /// タイマーなどの IRQ が新たに保留になった回数
+    pub timer_pending_count: u64,
+    /// ゲストのタイマー設定をソフトウェアタイマーに同期した回数
+    pub timer_sync_count: u64,
+    /// ソフトウェアタイマーの発火で IRQ を注入した回数
+    pub sw_timer_fire_count: u64,
+}
+
+impl ExitStats {
+    pub(crate) fn log_wfi(&mut self) {
+        self.wfi_count += 1;
+        if self.wfi_count <= 5 || self.wfi_count.is_multiple_of(10000) {
+            eprintln!("[WFI #{}] at exit #{}", self.wfi_count, self.exit_count);
+        }
+    }
+
+    pub(crate) fn log_timer_pending(&mut self) {
+        self.timer_pending_count += 1;
+        if self.timer_pending_count <= 10 {
+            eprintln!("[TIMER] IRQ pending #{}", self.timer_pending_count);
+        }
+    }
+
+    pub(crate) fn log_timer_sync(&mut self, guest_ctl: u64, guest_cval: u64, virt_counter: u64) {
+        self.timer_sync_count += 1;
+        if self.timer_sync_count <= 20 || self.timer_sync_count.is_multiple_of(5000) {
+            let enabled = (guest_ctl & 0x1) != 0;
+            let imask = (guest_ctl & 0x2) != 0;
+            eprintln!(
+                "[TIMER_SYNC #{}] guest_ctl=0x{:x} (enabled={}, imask={}), guest_cval=0x{:x}, sw_counter=0x{:x}",
+                self.timer_sync_count, guest_ctl, enabled, imask, guest_cval, virt_counter
+            );
+        }
+    }
+
+    pub(crate) fn log_sw_timer_fire(&mut self, hw_counter: u64, cval: u64) {
+        self.sw_timer_fire_count += 1;
+        if self.sw_timer_fire_count <= 20 || self.sw_timer_fire_count.is_multiple_of(1000) {
+            eprintln!(
+                "[SW_TIMER_FIRE #{}] counter=0x{:x} >= cval=0x{:x} -> injecting IRQ via GIC",
+                self.sw_timer_fire_count, hw_counter, cval
+            );
+        }
+    }
+
+    pub(crate) fn log_vtimer_activated(&mut self) {
+        self.vtimer_activated_count += 1;
+        if self.vtimer_activated_count <= 10 {
+            eprintln!(
+                "[VTIMER_ACTIVATED #{}] Timer fired!",
+                self.vtimer_activated_count
+            );
+        }
+    }
+
+    pub(crate) fn log_exit_summary(
+        &self,
+        post_run_ctl: u64,
+        post_run_cval: u64,
+        hw_counter: u64,
+        gic_pending: bool,
+    ) {
+        let timer_enabled = (post_run_ctl & 0x1) != 0;
+        let timer_imask = (post_run_ctl & 0x2) != 0;
+        let istatus = timer_enabled && hw_counter >= post_run_cval;
+        eprintln!(
+            "[TIMER STATE @{}] CTL=0x{:x} (enable={}, imask={}, istatus={}), CVAL=0x{:x}, counter=0x{:x}",
+            self.exit_count, post_run_ctl, timer_enabled, timer_imask, istatus, post_run_cval, hw_counter
+        );
+        eprintln!(
+            "[STATS @{}] WFI={}, MMIO={}, VTIMER_ACTIVATED={}, OTHER_EXC={}, GIC_pending={}",
+            self.exit_count,
+            self.wfi_count,
+            self.mmio_count,
+            self.vtimer_activated_count,
+            self.other_exception_count,
+            gic_pending
+        );
+    }
+}
+
+/// ゲストのアイドル状態
+///
+/// 割り込みのないまま WFI が続くほど、ホストの待ち方を長くする
+/// ([`crate::event_loop::backoff`])。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdleState {
+    /// ゲストが動いている (直前の VM Exit が WFI 以外、または割り込みがあった)
+    #[default]
+    Running,
+    /// アイドルになったばかり: スレッドを譲るだけですぐにゲストに戻る
+    Yielding,
+    /// アイドルが続いている: 短い時間だけ待つ
+    Napping,
+    /// 長くアイドル: 次のタイマーの期限か起こされるまで待つ
+    Blocked,
+}
+
+/// ゲストがアイドルの間のホストの待ちの統計
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IdleStats {
+    /// 現在の状態
+    pub state: IdleState,
+    /// 割り込みのないまま続いている WFI の数
+    pub consecutive_wfis: u64,
+    /// スレッドを譲った回数
+    pub yields: u64,
+    /// 短い時間だけ待った回数
+    pub naps: u64,
+    /// タイマーの期限か起こされるまで待った回数
+    pub blocks: u64,
+    /// 待っていた時間の合計
+    pub idle_time: Duration,
+}
+
+impl IdleStats {
+    /// 割り込みのない WFI を 1 回数え、次の状態にする
+    pub(crate) fn enter(&mut self, state: impl FnOnce(u64) -> IdleState) -> IdleState {
+        self.consecutive_wfis += 1;
+        self.state = state(self.consecutive_wfis);
+        self.state
+    }
+
+    /// 現在の状態での待ちを 1 回分記録する
+    pub(crate) fn record(&mut self, waited: Duration) {
+        match self.state {
+            IdleState::Running => {}
+            IdleState::Yielding => self.yields += 1,
+            IdleState::Napping => self.naps += 1,
+            IdleState::Blocked => self.blocks += 1,
+        }
+        self.idle_time += waited;
+    }
+
+    /// ゲストが動き出した
+    pub(crate) fn wake(&mut self) {
+        self.state = IdleState::Running;
+        self.consecutive_wfis = 0;
+    }
+}
+
+/// ハイパーバイザー全体の統計
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HypervisorStats {
+    /// 登録順のデバイス統計
+    pub devices: Vec<DeviceStats>,
+    /// VM Exit の回数
+    pub exits: ExitStats,
+    /// 仮想タイマーが CVAL に達してからゲストが割り込みを acknowledge するまでの時間
+    ///
+    /// タイマー割り込みは VM Exit ごとのポーリングで注入するため、ゲストが
+    /// 長く Exit しないとこの値が伸びる。
+    pub timer_irq_latency: LatencyStats,
+    /// ゲストがアイドルの間のホストの待ち
+    pub idle: IdleStats,
+}
+
+impl HypervisorStats {
+    /// 名前でデバイス統計を探す
+    pub fn device(&self, name: &str) -> Option<&DeviceStats> {
+        self.devices.iter().find(|d| d.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_の回数を数える() {
+        let mut exits = ExitStats::default();
+        exits.exit_count += 1;
+        exits.log_wfi();
+        exits.log_timer_pending();
+        exits.log_sw_timer_fire(0x200, 0x100);
+        exits.log_vtimer_activated();
+        assert_eq!(
+            exits,
+            ExitStats {
+                exit_count: 1,
+                wfi_count: 1,
+                timer_pending_count: 1,
+                sw_timer_fire_count: 1,
+                vtimer_activated_count: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn 連続した_wfi_を数えて待ちを記録する() {
+        let mut idle = IdleStats::default();
+        let backoff = |wfis| match wfis {
+            1 => IdleState::Yielding,
+            2 => IdleState::Napping,
+            _ => IdleState::Blocked,
+        };
+        for _ in 0..3 {
+            idle.enter(backoff);
+            idle.record(Duration::from_millis(1));
+        }
+        assert_eq!(idle.state, IdleState::Blocked);
+        assert_eq!((idle.yields, idle.naps, idle.blocks), (1, 1, 1));
+        assert_eq!(idle.idle_time, Duration::from_millis(3));
+
+        idle.wake();
+        assert_eq!(idle.state, IdleState::Running);
+        assert_eq!(idle.enter(backoff), IdleState::Yielding);
+    }
+
+    #[test]
+    fn 最小_平均_最大を集計する() {
+        let mut stats = LatencyStats::default();
+        assert_eq!(stats.avg(), Duration::ZERO);
+
+        stats.record(Duration::from_micros(30));
+        stats.record(Duration::from_micros(10));
+        stats.record(Duration::from_micros(20));
+
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.min, Duration::from_micros(10));
+        assert_eq!(stats.max, Duration::from_micros(30));
+        assert_eq!(stats.avg(), Duration::from_micros(20));
+    }
+}
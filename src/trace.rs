@@ -0,0 +1,333 @@
+//! Chrome trace 形式のトレース出力
+//!
+//! run ループの VM Exit・IRQ 注入・MMIO アクセス (virtio のキュー処理と
+//! ディスク I/O は QueueNotify の書き込み中に行われる) をイベントとして記録し、
+//! chrome://tracing や Perfetto (https://ui.perfetto.dev) で読み込める
+//! JSON として出力する。
+//!
+//! vCPU とデバイスはそれぞれ別のトラック (tid) として表示される。
+//!
+//! 長時間の実行でバッファが膨らまないよう、[`Tracer::set_rate_limit`] で
+//! カテゴリごとに記録する件数を制限できる。
+//!
+//! ```ignore
+//! let tracer = hv.enable_tracing();
+//! hv.boot_linux(...)?;
+//! tracer.write_chrome_json("boot.trace.json")?;
+//! ```
+
+use crate::rate_limit::RateLimiter;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// デバイストラックの tid の開始値 (vCPU トラックと重ならないようにする)
+const DEVICE_TID_BASE: u64 = 100;
+
+/// イベントを表示するトラック
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Track {
+    /// vCPU (番号)
+    Vcpu(u32),
+    /// MMIO デバイス (`MmioHandler::name`)
+    Device(String),
+}
+
+/// 記録されたイベント
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// イベント名
+    pub name: String,
+    /// カテゴリ ("exit", "irq", "mmio" など)
+    pub cat: &'static str,
+    /// 表示するトラック
+    pub track: Track,
+    /// トレース開始からの時刻
+    pub ts: Duration,
+    /// 所要時間 (瞬間イベントなら None)
+    pub dur: Option<Duration>,
+}
+
+struct TraceBuffer {
+    start: Instant,
+    events: Vec<TraceEvent>,
+    /// カテゴリごとの制限のひな形 (None なら制限しない)
+    rate_limit: Option<RateLimiter>,
+    limiters: HashMap<&'static str, RateLimiter>,
+}
+
+impl TraceBuffer {
+    /// 制限内ならイベントを追加する
+    ///
+    /// 捨てたイベントがあれば、先に「N events suppressed」を同じトラックに記録する。
+    fn push(&mut self, event: TraceEvent) {
+        if let Some(template) = &self.rate_limit {
+            let limiter = self
+                .limiters
+                .entry(event.cat)
+                .or_insert_with(|| template.clone());
+            match limiter.check() {
+                None => return,
+                Some(0) => {}
+                Some(suppressed) => self.events.push(TraceEvent {
+                    name: format!("{} events suppressed", suppressed),
+                    cat: event.cat,
+                    track: event.track.clone(),
+                    ts: event.ts,
+                    dur: None,
+                }),
+            }
+        }
+        self.events.push(event);
+    }
+}
+
+/// イベントの記録先
+///
+/// clone したハンドルは同じバッファに記録する。
+#[derive(Clone)]
+pub struct Tracer {
+    inner: Arc<Mutex<TraceBuffer>>,
+}
+
+impl Tracer {
+    /// 現在時刻を起点にトレースを開始する
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(TraceBuffer {
+                start: Instant::now(),
+                events: Vec::new(),
+                rate_limit: None,
+                limiters: HashMap::new(),
+            })),
+        }
+    }
+
+    /// `start` から現在までの区間をイベントとして記録する
+    pub fn complete(
+        &self,
+        track: Track,
+        cat: &'static str,
+        name: impl Into<String>,
+        start: Instant,
+    ) {
+        let end = Instant::now();
+        let mut buf = self.inner.lock().unwrap();
+        let ts = start.saturating_duration_since(buf.start);
+        buf.push(TraceEvent {
+            name: name.into(),
+            cat,
+            track,
+            ts,
+            dur: Some(end.saturating_duration_since(start)),
+        });
+    }
+
+    /// 瞬間イベントを記録する
+    pub fn instant(&self, track: Track, cat: &'static str, name: impl Into<String>) {
+        let mut buf = self.inner.lock().unwrap();
+        let ts = buf.start.elapsed();
+        buf.push(TraceEvent {
+            name: name.into(),
+            cat,
+            track,
+            ts,
+            dur: None,
+        });
+    }
+
+    /// カテゴリごとに記録する件数を制限する
+    ///
+    /// `limit` はカテゴリごとに clone して使う。None なら制限しない。
+    pub fn set_rate_limit(&self, limit: Option<RateLimiter>) {
+        let mut buf = self.inner.lock().unwrap();
+        buf.rate_limit = limit;
+        buf.limiters.clear();
+    }
+
+    /// 制限によって記録しなかったイベントの数
+    pub fn suppressed_events(&self) -> u64 {
+        let buf = self.inner.lock().unwrap();
+        buf.limiters
+            .values()
+            .map(RateLimiter::total_suppressed)
+            .sum()
+    }
+
+    /// 記録済みのイベント
+    pub fn events(&self) -> Vec<TraceEvent> {
+        self.inner.lock().unwrap().events.clone()
+    }
+
+    /// Chrome trace event format (JSON) に変換する
+    pub fn to_chrome_json(&self) -> String {
+        let buf = self.inner.lock().unwrap();
+        let mut devices: Vec<&str> = Vec::new();
+        let mut vcpus: Vec<u32> = Vec::new();
+        let mut entries = Vec::with_capacity(buf.events.len());
+
+        for event in &buf.events {
+            let tid = match &event.track {
+                Track::Vcpu(n) => {
+                    if !vcpus.contains(n) {
+                        vcpus.push(*n);
+                    }
+                    *n as u64
+                }
+                Track::Device(name) => {
+                    let index = devices.iter().position(|d| d == name).unwrap_or_else(|| {
+                        devices.push(name);
+                        devices.len() - 1
+                    });
+                    DEVICE_TID_BASE + index as u64
+                }
+            };
+            let mut entry = format!(
+                "{{\"name\":\"{}\",\"cat\":\"{}\",\"pid\":1,\"tid\":{},\"ts\":{}",
+                escape(&event.name),
+                event.cat,
+                tid,
+                micros(event.ts)
+            );
+            match event.dur {
+                Some(dur) => {
+                    let _ = write!(entry, ",\"ph\":\"X\",\"dur\":{}}}", micros(dur));
+                }
+                None => entry.push_str(",\"ph\":\"i\",\"s\":\"t\"}"),
+            }
+            entries.push(entry);
+        }
+
+        // トラック名のメタデータ
+        vcpus.sort_unstable();
+        let names = vcpus
+            .iter()
+            .map(|&n| (n as u64, format!("vCPU {}", n)))
+            .chain(
+                devices
+                    .iter()
+                    .enumerate()
+                    .map(|(i, d)| (DEVICE_TID_BASE + i as u64, d.to_string())),
+            );
+        for (tid, name) in names {
+            entries.push(format!(
+                "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":\"{}\"}}}}",
+                tid,
+                escape(&name)
+            ));
+        }
+
+        format!("{{\"traceEvents\":[\n{}\n]}}\n", entries.join(",\n"))
+    }
+
+    /// Chrome trace 形式でファイルに書き出す
+    pub fn write_chrome_json(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        std::fs::write(path, self.to_chrome_json())
+            .map_err(|e| format!("Failed to write trace to {}: {}", path, e).into())
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// マイクロ秒 (小数点以下 3 桁) で表す
+fn micros(d: Duration) -> String {
+    format!("{}.{:03}", d.as_micros(), d.as_nanos() % 1000)
+}
+
+/// JSON 文字列用にエスケープする
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::host_time::ManualClock;
+
+    #[test]
+    fn イベントをトラックごとに記録する() {
+        let tracer = Tracer::new();
+        let handle = tracer.clone();
+
+        let start = Instant::now();
+        tracer.complete(Track::Vcpu(0), "exit", "data abort", start);
+        handle.instant(Track::Vcpu(0), "irq", "inject IRQ 27");
+        handle.complete(
+            Track::Device("virtio-blk".to_string()),
+            "mmio",
+            "write +0x50",
+            start,
+        );
+
+        let events = tracer.events();
+        assert_eq!(events.len(), 3);
+        assert!(events[0].dur.is_some());
+        assert_eq!(events[1].dur, None);
+        assert_eq!(events[2].track, Track::Device("virtio-blk".to_string()));
+    }
+
+    #[test]
+    fn chrome_trace_形式で出力する() {
+        let tracer = Tracer::new();
+        tracer.complete(Track::Vcpu(0), "exit", "data abort", Instant::now());
+        tracer.instant(Track::Device("pl011".to_string()), "mmio", "say \"hi\"");
+
+        let json = tracer.to_chrome_json();
+        assert!(json.starts_with("{\"traceEvents\":["));
+        assert!(json.contains("\"ph\":\"X\""));
+        assert!(json.contains("\"ph\":\"i\""));
+        assert!(json.contains("\"tid\":100"));
+        assert!(json.contains("say \\\"hi\\\""));
+        assert!(json.contains("{\"name\":\"vCPU 0\"}"));
+        assert!(json.contains("{\"name\":\"pl011\"}"));
+        assert!(json.trim_end().ends_with("]}"));
+    }
+
+    #[test]
+    fn カテゴリごとに件数を制限し捨てた件数を記録する() {
+        let clock = ManualClock::new();
+        let tracer = Tracer::new();
+        tracer.set_rate_limit(Some(RateLimiter::with_clock(
+            2,
+            Duration::from_secs(1),
+            Arc::new(clock.clone()),
+        )));
+
+        for _ in 0..5 {
+            tracer.instant(Track::Vcpu(0), "exit", "hvc");
+        }
+        tracer.instant(Track::Vcpu(0), "irq", "inject IRQ 27");
+        assert_eq!(tracer.events().len(), 3);
+        assert_eq!(tracer.suppressed_events(), 3);
+
+        clock.advance(Duration::from_secs(1));
+        tracer.instant(Track::Vcpu(0), "exit", "hvc");
+        let names: Vec<String> = tracer.events().into_iter().map(|e| e.name).collect();
+        assert_eq!(
+            names,
+            ["hvc", "hvc", "inject IRQ 27", "3 events suppressed", "hvc"]
+        );
+    }
+
+    #[test]
+    fn 空のトレースも有効な_json_になる() {
+        assert_eq!(Tracer::new().to_chrome_json(), "{\"traceEvents\":[\n\n]}\n");
+    }
+}
@@ -0,0 +1,81 @@
+//! 他のスレッドから vCPU を止めるハンドル
+//!
+//! `hv_vcpu_run` はゲストが VM Exit を起こすまで戻らないため、ゲストが
+//! ループしているとコンソール入力や停止要求に反応できない。[`VcpuHandle::kick`] は
+//! `hv_vcpus_exit` で vCPU を強制的に `CANCELED` で抜けさせ、run ループに
+//! 割り込みや停止要求を確認させる。vCPU が WFI でアイドル待ちしている場合は
+//! [`Waker`] で待ちを解除する。
+//!
+//! ```ignore
+//! let handle = hv.vcpu_handle();
+//! std::thread::spawn(move || {
+//!     std::thread::sleep(Duration::from_secs(5));
+//!     handle.request_stop().unwrap();
+//! });
+//! let result = hv.run(None, None, None)?; // exit_reason == CANCELED
+//! ```
+
+use crate::event_loop::Waker;
+use applevisor::{Vcpu, VcpuInstance};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Default)]
+struct KickState {
+    /// run ループを抜ける要求
+    stop: AtomicBool,
+    /// これまでの kick 回数
+    kicks: AtomicU64,
+}
+
+/// vCPU を外から抜けさせるハンドル (`Send + Sync`、clone 可能)
+#[derive(Debug, Clone)]
+pub struct VcpuHandle {
+    /// 止める vCPU (モックの vCPU では None)
+    instance: Option<VcpuInstance>,
+    /// run ループのアイドル待ち
+    waker: Option<Waker>,
+    state: Arc<KickState>,
+}
+
+impl VcpuHandle {
+    pub(crate) fn new(instance: Option<VcpuInstance>, waker: Option<Waker>) -> Self {
+        Self {
+            instance,
+            waker,
+            state: Arc::default(),
+        }
+    }
+
+    /// ゲストの実行を中断させ、run ループに割り込みを確認させる
+    ///
+    /// vCPU がゲストを実行していない場合、次の `hv_vcpu_run` (またはアイドル待ち) が
+    /// すぐに戻る。
+    pub fn kick(&self) -> Result<(), Box<dyn Error>> {
+        self.state.kicks.fetch_add(1, Ordering::Relaxed);
+        if let Some(instance) = self.instance {
+            Vcpu::stop(&[instance])?;
+        }
+        if let Some(waker) = &self.waker {
+            waker.wake()?;
+        }
+        Ok(())
+    }
+
+    /// run ループを抜けるよう要求する (`run` は `ExitReason::CANCELED` で戻る)
+    pub fn request_stop(&self) -> Result<(), Box<dyn Error>> {
+        self.state.stop.store(true, Ordering::SeqCst);
+        self.kick()
+    }
+
+    /// これまでの kick 回数
+    pub fn kicks(&self) -> u64 {
+        self.state.kicks.load(Ordering::Relaxed)
+    }
+
+    /// 停止要求を取り出す (run ループ用)
+    pub(crate) fn take_stop_request(&self) -> bool {
+        self.state.stop.swap(false, Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,170 @@
+//! VM 全体の構成 (vCPU 数など)
+//!
+//! CPU の数は Device Tree の `cpus` ノード、各 vCPU の MPIDR_EL1、
+//! GICD_TYPER の CPUNumber に現れる。これらを個別に 1 CPU 前提で書かず、
+//! [`VmConfig`] の値から導出する。
+//!
+//! 複数 vCPU の実行 (セカンダリ CPU の起動) はまだ実装されていないため、
+//! 2 以上を指定できるのは `smp` feature を有効にした場合だけ。
+//!
+//! ```ignore
+//! let config = VmConfig::new().vcpus(1);
+//! let hv = Hypervisor::with_vm_config(0x4000_0000, 128 * 1024 * 1024, config)?;
+//! ```
+
+use crate::host_capabilities::HostCapabilities;
+use std::error::Error;
+
+/// GICv2 が扱える CPU の最大数 (GICD_TYPER.CPUNumber は 3 bit)
+pub const MAX_VCPUS: u32 = 8;
+
+/// MPIDR_EL1 の RES1 ビット
+const MPIDR_RES1: u64 = 1 << 31;
+
+/// vCPU 数を検証する
+///
+/// # Errors
+/// 0 個、[`MAX_VCPUS`] を超える場合、`smp` feature なしで 2 以上の場合はエラーを返す
+pub fn check_vcpu_count(vcpus: u32) -> Result<(), Box<dyn Error>> {
+    if vcpus == 0 {
+        return Err("at least one vCPU is required".into());
+    }
+    if vcpus > MAX_VCPUS {
+        return Err(format!(
+            "{} vCPUs requested but GICv2 supports at most {}",
+            vcpus, MAX_VCPUS
+        )
+        .into());
+    }
+    if vcpus > 1 && !cfg!(feature = "smp") {
+        return Err(format!(
+            "{} vCPUs requested but SMP support is not enabled (build with --features smp)",
+            vcpus
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// vCPU の MPIDR_EL1 (Aff0 に CPU 番号を入れる)
+pub fn mpidr(cpu: u32) -> u64 {
+    MPIDR_RES1 | cpu as u64
+}
+
+/// Device Tree の `cpu@N` ノードの `reg` (MPIDR のアフィニティ部分)
+pub fn mpidr_affinity(cpu: u32) -> u32 {
+    (mpidr(cpu) & 0x00ff_ffff) as u32
+}
+
+/// VM の構成
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmConfig {
+    vcpus: u32,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VmConfig {
+    /// 1 vCPU の構成
+    pub fn new() -> Self {
+        Self { vcpus: 1 }
+    }
+
+    /// vCPU 数を設定する (検証は [`VmConfig::validate`] で行う)
+    pub fn vcpus(mut self, vcpus: u32) -> Self {
+        self.vcpus = vcpus;
+        self
+    }
+
+    /// vCPU 数
+    pub fn vcpu_count(&self) -> u32 {
+        self.vcpus
+    }
+
+    /// 構成を検証する
+    ///
+    /// # Errors
+    /// vCPU 数が不正な場合はエラーを返す ([`check_vcpu_count`])
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        check_vcpu_count(self.vcpus)
+    }
+
+    /// 構成とゲスト RAM がホストの範囲に収まるか検証する
+    ///
+    /// ゲストの stage-1 粒度は vCPU から ID_AA64MMFR0_EL1 を読むまで分からないため
+    /// ここでは検証しない。VM の作成後に
+    /// [`Hypervisor::host_capabilities`](crate::Hypervisor::host_capabilities) の
+    /// [`HostCapabilities::check_granule`] か
+    /// [`Hypervisor::check_address_config`](crate::Hypervisor::check_address_config) で確認する。
+    ///
+    /// # Errors
+    /// [`VmConfig::validate`] のエラーに加え、vCPU 数がホストの上限を超える場合、
+    /// RAM が IPA 空間に収まらない場合はエラーを返す
+    pub fn validate_for_host(
+        &self,
+        host: &HostCapabilities,
+        ram_base: u64,
+        ram_size: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        self.validate()?;
+        host.check_vcpus(self.vcpus)?;
+        host.check_ram(ram_base, ram_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn 既定は_1_vcpu() {
+        let config = VmConfig::default();
+        assert_eq!(config.vcpu_count(), 1);
+        assert!(config.validate().is_ok());
+        assert_eq!(mpidr(0), 0x8000_0000);
+        assert_eq!(mpidr_affinity(3), 3);
+    }
+
+    #[test]
+    fn 不正な_vcpu_数はエラーになる() {
+        let err = VmConfig::new().vcpus(0).validate().unwrap_err();
+        assert!(err.to_string().contains("at least one vCPU"));
+        let err = VmConfig::new().vcpus(9).validate().unwrap_err();
+        assert!(err.to_string().contains("at most 8"));
+
+        let result = VmConfig::new().vcpus(2).validate();
+        if cfg!(feature = "smp") {
+            assert!(result.is_ok());
+        } else {
+            assert!(result.unwrap_err().to_string().contains("--features smp"));
+        }
+    }
+
+    #[test]
+    fn ホストの範囲を超える構成はエラーになる() {
+        let host = HostCapabilities {
+            ipa_bits: 36,
+            max_ipa_bits: 40,
+            stage2_page_size: 0x4000,
+            max_vcpus: 1,
+            mmfr0: None,
+        };
+        let config = VmConfig::new();
+        assert!(config
+            .validate_for_host(&host, 0x4000_0000, 128 * 1024 * 1024)
+            .is_ok());
+        let err = config
+            .validate_for_host(&host, 0xf_c000_0000, 0x8000_0000)
+            .unwrap_err();
+        assert!(err.to_string().contains("36-bit IPA space"));
+        let err = config
+            .vcpus(0)
+            .validate_for_host(&host, 0x4000_0000, 0x4000)
+            .unwrap_err();
+        assert!(err.to_string().contains("at least one vCPU"));
+    }
+}
@@ -0,0 +1,305 @@
+//! ゲスト RAM への書き込みの監視
+//!
+//! 指定した範囲を含むページを stage-2 で書き込み禁止にし、ゲストの書き込みを
+//! Data Abort として捕まえる。範囲に当たった書き込みはログに出すか、
+//! コールバックに渡す。ゲストのメモリ破壊がどこから来ているかを調べるための機能。
+//!
+//! - ISV=1 の書き込み (通常の STR) は値をハイパーバイザーが RAM に書き込み、
+//!   ページは書き込み禁止のまま次の書き込みも捕まえる。
+//! - ISV=0 の書き込み (STP・DC ZVA など) は値が分からないため、ページを
+//!   一時的に書き込み可能にして命令を再実行させ、次の VM Exit で書き込み禁止に
+//!   戻す。その間の同じページへの書き込みは捕まえられない。
+//! - ホスト側 ([`GuestRam::write`](crate::memory::GuestRam::write)) やデバイスの
+//!   DMA による書き込みは stage-2 を通らないので対象外。
+//!
+//! 範囲外でも同じページへの書き込みは Data Abort になる (記録はしない) ため、
+//! よく書き込まれるページを監視するとゲストが遅くなる。
+//!
+//! ```ignore
+//! hv.watch_writes("task_struct", 0x4123_4000..0x4123_4100, WatchAction::Log)?;
+//! hv.run(None, None, None)?;
+//! for hit in hv.watch_hits("task_struct") { println!("{}", hit); }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+/// 書き込みを捕まえたときの動作
+pub enum WatchAction {
+    /// `[WATCH]` 付きで標準エラー出力に出す
+    Log,
+    /// コールバックを呼ぶ (run ループのスレッドで呼ばれる)
+    Callback(Box<dyn FnMut(&WatchHit) + Send>),
+}
+
+/// 監視範囲への書き込み 1 回分
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchHit {
+    /// 監視範囲の名前
+    pub name: String,
+    /// 書き込み先のゲスト物理アドレス
+    pub addr: u64,
+    /// 書き込みサイズ (ISV=0 の命令では不明)
+    pub size: Option<usize>,
+    /// 書き込んだ値 (ISV=0 の命令では不明)
+    pub value: Option<u64>,
+    /// 書き込んだ命令の PC
+    pub pc: u64,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: write to 0x{:x}", self.name, self.addr)?;
+        match (self.size, self.value) {
+            (Some(size), Some(value)) => write!(f, " ({} bytes) = 0x{:x}", size, value)?,
+            _ => write!(f, " (size unknown)")?,
+        }
+        write!(f, " at pc 0x{:x}", self.pc)
+    }
+}
+
+struct Watch {
+    name: String,
+    range: Range<u64>,
+    action: WatchAction,
+    hits: Vec<WatchHit>,
+}
+
+/// 書き込み監視の範囲と記録
+pub struct WriteWatches {
+    page_size: u64,
+    watches: Vec<Watch>,
+    /// 書き込み禁止にしているページと、そのページに重なる範囲の数
+    pages: BTreeMap<u64, usize>,
+    /// 一時的に書き込み可能にしたページ (次の VM Exit で書き込み禁止に戻す)
+    rearm: BTreeSet<u64>,
+}
+
+impl WriteWatches {
+    /// 監視なしの状態を作成
+    pub fn new(page_size: u64) -> Self {
+        Self {
+            page_size,
+            watches: Vec::new(),
+            pages: BTreeMap::new(),
+            rearm: BTreeSet::new(),
+        }
+    }
+
+    /// 監視範囲があるか
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    /// アドレスを含むページの先頭
+    pub fn page_of(&self, addr: u64) -> u64 {
+        addr & !(self.page_size - 1)
+    }
+
+    /// 範囲を追加し、新しく書き込み禁止にするページを返す
+    ///
+    /// # Errors
+    /// 範囲が空の場合、同じ名前の範囲がすでにある場合はエラーを返す
+    pub fn add(
+        &mut self,
+        name: &str,
+        range: Range<u64>,
+        action: WatchAction,
+    ) -> Result<Vec<u64>, Box<dyn Error>> {
+        if range.is_empty() {
+            return Err(format!("watch range {} is empty", name).into());
+        }
+        if self.watches.iter().any(|w| w.name == name) {
+            return Err(format!("watch range {} already exists", name).into());
+        }
+        let mut pages = Vec::new();
+        for page in self.pages(&range) {
+            let count = self.pages.entry(page).or_insert(0);
+            if *count == 0 {
+                pages.push(page);
+            }
+            *count += 1;
+        }
+        self.watches.push(Watch {
+            name: name.to_string(),
+            range,
+            action,
+            hits: Vec::new(),
+        });
+        Ok(pages)
+    }
+
+    /// 範囲を削除し、書き込み可能に戻すページを返す
+    ///
+    /// # Errors
+    /// 範囲が登録されていない場合はエラーを返す
+    pub fn remove(&mut self, name: &str) -> Result<Vec<u64>, Box<dyn Error>> {
+        let index = self
+            .watches
+            .iter()
+            .position(|w| w.name == name)
+            .ok_or_else(|| format!("watch range {} does not exist", name))?;
+        let watch = self.watches.remove(index);
+        let mut pages = Vec::new();
+        for page in self.pages(&watch.range) {
+            if let Some(count) = self.pages.get_mut(&page) {
+                *count -= 1;
+                if *count == 0 {
+                    self.pages.remove(&page);
+                    self.rearm.remove(&page);
+                    pages.push(page);
+                }
+            }
+        }
+        Ok(pages)
+    }
+
+    /// 書き込み禁止にしているページか
+    pub fn is_watched_page(&self, addr: u64) -> bool {
+        self.pages.contains_key(&self.page_of(addr))
+    }
+
+    /// 書き込みを記録し、範囲ごとの動作を実行する
+    ///
+    /// # Returns
+    /// 書き込みが当たった範囲の数 (同じページの範囲外への書き込みなら 0)
+    pub fn record(&mut self, addr: u64, size: Option<usize>, value: Option<u64>, pc: u64) -> usize {
+        // サイズ不明の書き込みはページ内のどこまで書いたか分からないので、
+        // ページ内の範囲すべてに当たったものとして扱う
+        let (start, end) = match size {
+            Some(size) => (addr, addr + size as u64),
+            None => (self.page_of(addr), self.page_of(addr) + self.page_size),
+        };
+        let mut count = 0;
+        for watch in &mut self.watches {
+            if start >= watch.range.end || end <= watch.range.start {
+                continue;
+            }
+            let hit = WatchHit {
+                name: watch.name.clone(),
+                addr,
+                size,
+                value,
+                pc,
+            };
+            match &mut watch.action {
+                WatchAction::Log => eprintln!("[WATCH] {}", hit),
+                WatchAction::Callback(callback) => callback(&hit),
+            }
+            watch.hits.push(hit);
+            count += 1;
+        }
+        count
+    }
+
+    /// 範囲への書き込みの記録
+    pub fn hits(&self, name: &str) -> &[WatchHit] {
+        self.watches
+            .iter()
+            .find(|w| w.name == name)
+            .map_or(&[], |w| &w.hits)
+    }
+
+    /// 一時的に書き込み可能にしたページを登録する
+    pub fn defer_rearm(&mut self, page: u64) {
+        self.rearm.insert(self.page_of(page));
+    }
+
+    /// 書き込み禁止に戻すページを取り出す
+    pub fn take_rearm(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.rearm).into_iter().collect()
+    }
+
+    fn pages(&self, range: &Range<u64>) -> impl Iterator<Item = u64> {
+        let page_size = self.page_size;
+        let first = self.page_of(range.start);
+        let last = self.page_of(range.end - 1);
+        (first..=last).step_by(page_size as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    const PAGE: u64 = 0x4000;
+
+    #[test]
+    fn 範囲を含むページだけを書き込み禁止にする() {
+        let mut watches = WriteWatches::new(PAGE);
+        let pages = watches
+            .add("a", 0x4000_3ff0..0x4000_4010, WatchAction::Log)
+            .unwrap();
+        assert_eq!(pages, vec![0x4000_0000, 0x4000_4000]);
+        // 同じページの範囲は追加で保護しない
+        let pages = watches
+            .add("b", 0x4000_4100..0x4000_4200, WatchAction::Log)
+            .unwrap();
+        assert!(pages.is_empty());
+        assert!(watches.is_watched_page(0x4000_7fff));
+        assert!(!watches.is_watched_page(0x4000_8000));
+
+        assert!(watches.add("a", 0x5000..0x5004, WatchAction::Log).is_err());
+        assert!(watches.add("c", 0x5000..0x5000, WatchAction::Log).is_err());
+
+        // "b" と共有しているページは書き込み禁止のまま
+        assert_eq!(watches.remove("a").unwrap(), vec![0x4000_0000]);
+        assert_eq!(watches.remove("b").unwrap(), vec![0x4000_4000]);
+        assert!(watches.is_empty());
+        assert!(watches.remove("b").is_err());
+    }
+
+    #[test]
+    fn 範囲に当たった書き込みだけを記録する() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let mut watches = WriteWatches::new(PAGE);
+        watches
+            .add(
+                "flag",
+                0x4000_0100..0x4000_0108,
+                WatchAction::Callback(Box::new(move |hit| sink.lock().unwrap().push(hit.clone()))),
+            )
+            .unwrap();
+
+        // 同じページの範囲外
+        assert_eq!(watches.record(0x4000_00f8, Some(8), Some(1), 0x1000), 0);
+        // 範囲の先頭に重なる
+        assert_eq!(watches.record(0x4000_00fc, Some(8), Some(2), 0x1004), 1);
+        // サイズ不明はページ全体として扱う
+        assert_eq!(watches.record(0x4000_0000, None, None, 0x1008), 1);
+
+        let hits = watches.hits("flag");
+        assert_eq!(hits.len(), 2);
+        assert_eq!(seen.lock().unwrap().as_slice(), hits);
+        assert_eq!(
+            hits[0].to_string(),
+            "flag: write to 0x400000fc (8 bytes) = 0x2 at pc 0x1004"
+        );
+        assert_eq!(
+            hits[1].to_string(),
+            "flag: write to 0x40000000 (size unknown) at pc 0x1008"
+        );
+        assert!(watches.hits("other").is_empty());
+    }
+
+    #[test]
+    fn 一時的に書き込み可能にしたページは一度だけ戻す() {
+        let mut watches = WriteWatches::new(PAGE);
+        watches
+            .add("a", 0x4000_0000..0x4000_0010, WatchAction::Log)
+            .unwrap();
+        watches.defer_rearm(0x4000_0008);
+        watches.defer_rearm(0x4000_0000);
+        assert_eq!(watches.take_rearm(), vec![0x4000_0000]);
+        assert!(watches.take_rearm().is_empty());
+
+        // 削除した範囲のページは戻さない
+        watches.defer_rearm(0x4000_0000);
+        watches.remove("a").unwrap();
+        assert!(watches.take_rearm().is_empty());
+    }
+}
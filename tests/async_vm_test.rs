@@ -0,0 +1,29 @@
+//! AsyncVm のテスト (`async` feature)
+//!
+//! ローカルで実行: `cargo test --features async --test async_vm_test -- --ignored`
+
+#![cfg(feature = "async")]
+
+use applevisor::ExitReason;
+use hypervisor::async_vm::{block_on, AsyncVm};
+use hypervisor::Hypervisor;
+
+/// 専用スレッドでゲストを実行し、停止要求で run が完了することを確認
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn 専用スレッドのゲストを_await_できる() {
+    let vm = block_on(AsyncVm::spawn(|| {
+        let mut hv = Hypervisor::new(0x4000_0000, 0x100_0000)?;
+        hv.write_instructions(&[0x1400_0000])?; // B .
+        Ok(hv)
+    }))
+    .expect("Failed to create VM");
+
+    let run = vm.run(None, None, None);
+    vm.request_stop().unwrap();
+    let result = block_on(run).expect("Failed to run");
+    assert!(matches!(result.exit_reason, ExitReason::CANCELED));
+
+    let pc = block_on(vm.with(|hv| hv.get_reg(applevisor::Reg::PC).unwrap())).unwrap();
+    assert_eq!(pc, 0x4000_0000);
+}
@@ -0,0 +1,76 @@
+//! ブートスタブのテスト
+//!
+//! これらのテストは Hypervisor.framework の entitlements が必要です。
+//! ローカルで実行する場合は `cargo test --ignored` を使用してください。
+
+use hypervisor::boot::stub::BootStub;
+use hypervisor::Hypervisor;
+
+const GUEST_BASE: u64 = 0x4000_0000;
+
+/// スタブがレジスタを設定してペイロードへジャンプすることを確認
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn bare_metal_スタブが引数を設定してペイロードへジャンプする() {
+    let mut hv = Hypervisor::new(GUEST_BASE, 0x100_0000).expect("Failed to create hypervisor");
+
+    // ペイロード: BRK #0 のみ
+    let payload_addr = GUEST_BASE + 0x1000;
+    hv.write_instruction(0x1000, 0xD420_0000)
+        .expect("Failed to write payload");
+
+    let stub = BootStub::bare_metal(payload_addr, &[0x1234, 0x5678]);
+    let pc = hv
+        .install_boot_stub(GUEST_BASE, &stub)
+        .expect("Failed to install stub");
+
+    let result = hv.run(None, None, Some(pc)).expect("Failed to run");
+
+    assert_eq!(result.pc, payload_addr);
+    assert_eq!(result.registers[0], 0x1234);
+    assert_eq!(result.registers[1], 0x5678);
+}
+
+/// chain_load スタブがイメージをコピーしてから実行することを確認
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn chain_load_スタブがイメージをコピーして実行する() {
+    let mut hv = Hypervisor::new(GUEST_BASE, 0x100_0000).expect("Failed to create hypervisor");
+
+    // "フラッシュ" 領域にペイロードを配置: mov x0, #42; brk #0
+    let flash_offset = 0x10_0000;
+    hv.write_instruction(flash_offset, 0xD280_0540).unwrap();
+    hv.write_instruction(flash_offset + 4, 0xD420_0000).unwrap();
+
+    let dst = GUEST_BASE + 0x8_0000;
+    let stub = BootStub::chain_load(GUEST_BASE + flash_offset, dst, 8, &[]);
+    let pc = hv.install_boot_stub(GUEST_BASE, &stub).unwrap();
+
+    let result = hv.run(None, None, Some(pc)).expect("Failed to run");
+
+    assert_eq!(result.registers[0], 42);
+    assert_eq!(result.pc, dst + 4);
+}
+
+/// VBAR を設定しないペイロードの例外がシムで BRK の VM Exit になることを確認
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn ベクタのシムで想定外の例外から戻る() {
+    use hypervisor::boot::vectors::{VectorKind, VectorSource};
+    use hypervisor::run_options::RunOptions;
+
+    let mut hv = Hypervisor::new(GUEST_BASE, 0x10_0000).expect("Failed to create hypervisor");
+    // ペイロード: SVC #0 (ハンドラなし) → BRK #0 には届かない
+    hv.write_instructions(&[0xD400_0001, 0xD420_0000])
+        .expect("Failed to write payload");
+
+    let options = RunOptions::new().vector_shim(GUEST_BASE + 0x8000);
+    let result = hv.run_with(&options).expect("Failed to run");
+
+    let vector = result.shim_vector().expect("Expected a shim exit");
+    assert_eq!(vector.kind, VectorKind::Sync);
+    assert_eq!(vector.source, VectorSource::CurrentSpx);
+    let el1 = result.el1.expect("Expected the EL1 context");
+    assert_eq!(el1.exception_class(), 0x15, "Expected SVC in ESR_EL1");
+    assert_eq!(el1.elr, GUEST_BASE + 4);
+}
@@ -3,6 +3,8 @@
 //! UART PL011 への出力が正しく動作することを確認するテスト。
 //! 実際の Linux カーネルの earlycon ドライバと同様の動作をテストする。
 
+#![cfg(feature = "uart")]
+
 use hypervisor::devices::uart::Pl011Uart;
 use hypervisor::mmio::MmioHandler;
 use hypervisor::Hypervisor;
@@ -110,7 +112,8 @@ fn uart_に1文字出力できる() {
 
     // UART デバイスを登録
     let uart = Pl011Uart::new(UART_BASE);
-    hv.register_mmio_handler(Box::new(uart));
+    hv.register_mmio_handler(Box::new(uart))
+        .expect("Failed to register MMIO handler");
 
     // 'A' を UART に出力する命令
     // MOVZ encoding: sf=1, opc=10, 100101, hw, imm16, Rd
@@ -146,7 +149,8 @@ fn uart_flag_registerを読める() {
 
     // UART デバイスを登録
     let uart = Pl011Uart::new(UART_BASE);
-    hv.register_mmio_handler(Box::new(uart));
+    hv.register_mmio_handler(Box::new(uart))
+        .expect("Failed to register MMIO handler");
 
     // UART_FR を読み取る命令
     // MOVZ X1, #0x0900, LSL #16 = 0xD2A1_2001
@@ -189,7 +193,8 @@ fn uart_control_registerを読み書きできる() {
 
     // UART デバイスを登録
     let uart = Pl011Uart::new(UART_BASE);
-    hv.register_mmio_handler(Box::new(uart));
+    hv.register_mmio_handler(Box::new(uart))
+        .expect("Failed to register MMIO handler");
 
     // UART_CR に書き込み、読み取りする命令
     // X0 = CR_UARTEN | CR_TXE | CR_RXE = 0x301
@@ -0,0 +1,46 @@
+//! デバイス向けゲストメモリアクセスの統合テスト
+//!
+//! ローカルで実行: `cargo test --test guest_memory_test -- --ignored`
+
+use hypervisor::memory::GuestMemoryExt;
+use hypervisor::Hypervisor;
+
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn guest_memory_経由の書き込みをハイパーバイザーから読める() {
+    let mut hv = Hypervisor::new(0x4000_0000, 0x10_0000).expect("Failed to create hypervisor");
+    let mem = hv.guest_memory();
+
+    mem.write_obj(0xdead_beefu32, 0x4000_1000).unwrap();
+    assert_eq!(hv.read_byte(0x4000_1000).unwrap(), 0xef);
+
+    hv.write_byte(0x4000_2000, 0x5a).unwrap();
+    assert_eq!(mem.read_obj::<u8>(0x4000_2000).unwrap(), 0x5a);
+
+    // RAM の外は拒否される
+    assert!(mem.read_obj::<u32>(0x4010_0000).is_err());
+
+    // shutdown 後はマッピングが解除されアクセスできない
+    hv.shutdown().unwrap();
+    assert!(mem.read_obj::<u32>(0x4000_1000).is_err());
+}
+
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn ゲストが書いた範囲をまとめて確認できる() {
+    let mut hv = Hypervisor::new(0x4000_0000, 0x10_0000).expect("Failed to create hypervisor");
+    hv.write_instructions(&[
+        0xD2A8_0021, // MOVZ X1, #0x4001, LSL #16
+        0xD297_DDE2, // MOVZ X2, #0xbeef
+        0xF900_0022, // STR X2, [X1]
+        0xD420_0000, // BRK #0
+    ])
+    .expect("Failed to write instructions");
+    hv.run(None, None, None).expect("Failed to run");
+
+    let snapshot = hv.memory_snapshot(0x4001_0000..0x4001_0008).unwrap();
+    assert_eq!(snapshot, [0xef, 0xbe, 0, 0, 0, 0, 0, 0]);
+    hv.compare_region(0x4001_0000, &0xbeefu64.to_le_bytes())
+        .unwrap();
+    assert!(hv.compare_region(0x4001_0000, &[0; 8]).is_err());
+}
@@ -29,6 +29,7 @@ fn test_device_tree_with_kernel() {
         cmdline: "console=ttyAMA0 earlycon".to_string(),
         initrd_start: None,
         initrd_end: None,
+        ..Default::default()
     };
 
     let dtb = generate_device_tree(&config).unwrap();
@@ -60,6 +61,7 @@ fn test_kernel_image_and_device_tree_integration() {
         cmdline: "console=ttyAMA0".to_string(),
         initrd_start: None,
         initrd_end: None,
+        ..Default::default()
     };
     let dtb = generate_device_tree(&config).unwrap();
 
@@ -17,7 +17,7 @@ fn encode_brk(imm: u16) -> u32 {
 #[test]
 #[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
 fn interrupt_controller_が正しく初期化される() {
-    let hv = Hypervisor::new(0x10000, 4096).expect("Failed to create hypervisor");
+    let hv = Hypervisor::new(0x10000, 0x4000).expect("Failed to create hypervisor");
 
     // InterruptController が初期化されている
     let ic = hv.interrupt_controller();
@@ -28,7 +28,7 @@ fn interrupt_controller_が正しく初期化される() {
 #[test]
 #[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
 fn タイマーを設定すると割り込みがペンディングになる() {
-    let mut hv = Hypervisor::new(0x10000, 4096).expect("Failed to create hypervisor");
+    let mut hv = Hypervisor::new(0x10000, 0x4000).expect("Failed to create hypervisor");
 
     // GIC を有効化
     hv.interrupt_controller_mut().enable();
@@ -44,7 +44,7 @@ fn タイマーを設定すると割り込みがペンディングになる() {
         .unwrap();
 
     // タイマー IRQ をポーリング
-    hv.interrupt_controller_mut().poll_timer_irqs();
+    hv.irq_chip().poll_timer_irqs();
 
     // 割り込みがペンディングになっている
     assert!(hv.interrupt_controller().has_pending_irq());
@@ -53,7 +53,7 @@ fn タイマーを設定すると割り込みがペンディングになる() {
 #[test]
 #[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
 fn acknowledge_と_eoi_のフローが動作する() {
-    let mut hv = Hypervisor::new(0x10000, 4096).expect("Failed to create hypervisor");
+    let mut hv = Hypervisor::new(0x10000, 0x4000).expect("Failed to create hypervisor");
 
     // GIC を有効化
     hv.interrupt_controller_mut().enable();
@@ -68,7 +68,7 @@ fn acknowledge_と_eoi_のフローが動作する() {
         .write_sysreg(TimerReg::CNTV_CVAL_EL0, counter.saturating_sub(100))
         .unwrap();
 
-    hv.interrupt_controller_mut().poll_timer_irqs();
+    hv.irq_chip().poll_timer_irqs();
 
     // Acknowledge
     let irq = hv.interrupt_controller_mut().acknowledge();
@@ -85,7 +85,7 @@ fn acknowledge_と_eoi_のフローが動作する() {
 #[test]
 #[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
 fn タイマーなしでゲストを実行できる() {
-    let mut hv = Hypervisor::new(0x10000, 4096).expect("Failed to create hypervisor");
+    let mut hv = Hypervisor::new(0x10000, 0x4000).expect("Failed to create hypervisor");
 
     // 単純な BRK 命令
     let instructions = vec![encode_brk(0)];
@@ -104,7 +104,7 @@ fn タイマーなしでゲストを実行できる() {
 #[test]
 #[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
 fn gic_有効時でもゲストを実行できる() {
-    let mut hv = Hypervisor::new(0x10000, 4096).expect("Failed to create hypervisor");
+    let mut hv = Hypervisor::new(0x10000, 0x4000).expect("Failed to create hypervisor");
 
     // GIC を有効化
     hv.interrupt_controller_mut().enable();
@@ -127,7 +127,7 @@ fn gic_有効時でもゲストを実行できる() {
 #[test]
 #[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
 fn ペンディングirqがない場合はインジェクトしない() {
-    let mut hv = Hypervisor::new(0x10000, 4096).expect("Failed to create hypervisor");
+    let mut hv = Hypervisor::new(0x10000, 0x4000).expect("Failed to create hypervisor");
 
     // GIC を有効化するがタイマーは設定しない
     hv.interrupt_controller_mut().enable();
@@ -0,0 +1,98 @@
+//! kexec / kdump テスト
+//!
+//! 1 回目のカーネルが `kexec -p` で crash カーネルをロードしてからパニックし、
+//! crash カーネルが `/proc/vmcore` を取得できることを確認する。
+//! crash カーネルは前のカーネルの GIC とタイマーの状態を引き継いで起動するため、
+//! アクティブなまま残った割り込み (ICACTIVER / GICC_APRn) とタイマー割り込みの
+//! 取り下げが正しく動作しないと、crash カーネルの起動が途中で止まる。
+//!
+//! カーネルと initramfs は次のスクリプトで作成する:
+//! - `scripts/build-linux-kernel.sh` (`crashkernel=64M` 付き)
+//! - `scripts/build-initramfs.sh`
+//! - `scripts/build-kdump-initramfs.sh`
+//!
+//! ローカルで実行: `cargo test --test kdump_test -- --ignored`
+
+#![cfg(feature = "uart")]
+
+use hypervisor::boot::device_tree::{generate_device_tree, DeviceTreeConfig};
+use hypervisor::devices::console::Console;
+use hypervisor::devices::uart::Pl011Uart;
+use hypervisor::Hypervisor;
+use std::fs;
+use std::path::Path;
+
+const RAM_BASE: u64 = 0x4000_0000;
+const RAM_SIZE: usize = 256 * 1024 * 1024; // crashkernel=64M を確保できるサイズ
+const KERNEL_ENTRY: u64 = 0x4008_0000;
+const UART_BASE: u64 = 0x0900_0000;
+const DTB_ADDR: u64 = 0x4400_0000;
+const INITRAMFS_ADDR: u64 = 0x4500_0000;
+
+const KERNEL_IMAGE_PATH: &str = "output/Image";
+const KDUMP_INITRAMFS_PATH: &str = "output/kdump-initramfs.cpio.gz";
+
+/// パニック後に crash カーネルが起動して vmcore を取得できることを確認
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements, kernel image and kdump initramfs (run locally with --ignored)"]
+fn パニック後に_crash_カーネルが_vmcore_を取得できる() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let (kernel_path, initramfs_path) = (
+        root.join(KERNEL_IMAGE_PATH),
+        root.join(KDUMP_INITRAMFS_PATH),
+    );
+    if !kernel_path.exists() || !initramfs_path.exists() {
+        eprintln!(
+            "Kernel image or kdump initramfs not found ({:?}, {:?})",
+            kernel_path, initramfs_path
+        );
+        eprintln!("Build them first with: docker run ... scripts/build-kdump-initramfs.sh");
+        return;
+    }
+    let kernel = fs::read(&kernel_path).expect("Failed to read kernel image");
+    let initramfs = fs::read(&initramfs_path).expect("Failed to read kdump initramfs");
+
+    let mut hv = Hypervisor::new(RAM_BASE, RAM_SIZE).expect("Failed to create hypervisor");
+    let console = Console::new();
+    hv.register_mmio_handler(Box::new(Pl011Uart::with_console(UART_BASE, console.sink())))
+        .expect("Failed to register MMIO handler");
+
+    let initramfs_end = INITRAMFS_ADDR + initramfs.len() as u64;
+    let dtb = generate_device_tree(&DeviceTreeConfig {
+        memory_base: RAM_BASE,
+        memory_size: RAM_SIZE as u64,
+        initrd_start: Some(INITRAMFS_ADDR),
+        initrd_end: Some(initramfs_end),
+        dtb_addr: Some(DTB_ADDR),
+        ..Default::default()
+    })
+    .expect("Failed to generate device tree");
+    hv.load_blob("dtb", DTB_ADDR, &dtb).unwrap();
+    hv.load_blob("initrd", INITRAMFS_ADDR, &initramfs).unwrap();
+    hv.load_blob("kernel", KERNEL_ENTRY, &kernel).unwrap();
+
+    hv.set_reg(applevisor::Reg::X0, DTB_ADDR).unwrap();
+    let result = hv
+        .run(Some(0x3c5), Some(true), Some(KERNEL_ENTRY))
+        .expect("Failed to run kernel");
+    println!(
+        "Exit reason: {:?}, PC: 0x{:x}",
+        result.exit_reason, result.pc
+    );
+
+    let output = console.output();
+    println!("{}", output);
+    assert!(
+        output.contains("crashkernel reserved"),
+        "crashkernel= was not honoured"
+    );
+    assert!(output.contains("KDUMP: triggering panic"));
+    assert!(
+        output.contains("Starting crashdump kernel"),
+        "The first kernel did not jump to the crash kernel"
+    );
+    assert!(
+        output.contains("KDUMP: vmcore captured"),
+        "The crash kernel did not reach userspace"
+    );
+}
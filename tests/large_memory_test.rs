@@ -0,0 +1,60 @@
+//! 4GB を超えるゲスト RAM の統合テスト
+//!
+//! ホストの物理メモリが足りないマシンでは何もせずに終わる。
+//!
+//! ローカルで実行: `cargo test --test large_memory_test -- --ignored`
+
+use hypervisor::Hypervisor;
+
+const GUEST_ADDR: u64 = 0x4000_0000;
+const RAM_SIZE: usize = 8 * 1024 * 1024 * 1024;
+
+/// ホストの物理メモリ (bytes)
+fn host_memory() -> u64 {
+    let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if pages <= 0 || page_size <= 0 {
+        return 0;
+    }
+    pages as u64 * page_size as u64
+}
+
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn ram_の_4gb_より上にゲストが書き込める() {
+    // 確保は遅延されるが、ゲストが使い切っても耐えられるホストに限る
+    if host_memory() < RAM_SIZE as u64 {
+        eprintln!(
+            "skipping: host has {} bytes of memory, need {}",
+            host_memory(),
+            RAM_SIZE
+        );
+        return;
+    }
+
+    let mut hv = Hypervisor::new(GUEST_ADDR, RAM_SIZE).expect("Failed to create hypervisor");
+    let high = GUEST_ADDR + RAM_SIZE as u64 - 0x1_0000; // 0x2_3fff_0000
+    assert_eq!(high, 0x2_3fff_0000);
+
+    hv.write_instructions(&[
+        0xD2A7_FFE1, // MOVZ X1, #0x3fff, LSL #16
+        0xF2C0_0041, // MOVK X1, #0x2, LSL #32
+        0xD297_DDE2, // MOVZ X2, #0xbeef
+        0xF900_0022, // STR X2, [X1]
+        0xD420_0000, // BRK #0
+    ])
+    .expect("Failed to write instructions");
+    hv.run(None, None, None).expect("Failed to run");
+
+    hv.compare_region(high, &0xbeefu64.to_le_bytes()).unwrap();
+
+    // ホスト側からの読み書きも 4GB の境界をまたいで届く
+    hv.write_byte(0x1_0000_0000, 0x5a).unwrap();
+    assert_eq!(hv.read_byte(0x1_0000_0000).unwrap(), 0x5a);
+    assert!(hv.read_byte(GUEST_ADDR + RAM_SIZE as u64).is_err());
+
+    // 触れたページだけが常駐する
+    let usage = hv.memory_usage();
+    assert_eq!(usage.declared, RAM_SIZE);
+    assert!(usage.resident < RAM_SIZE / 2);
+}
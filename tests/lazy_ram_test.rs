@@ -0,0 +1,25 @@
+//! ゲスト RAM の遅延確保の統合テスト
+//!
+//! ローカルで実行: `cargo test --test lazy_ram_test -- --ignored`
+
+use hypervisor::Hypervisor;
+
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn 大きな_ram_を宣言しても触れた分だけ常駐する() {
+    let declared = 8 * 1024 * 1024 * 1024;
+    let mut hv = Hypervisor::new(0x4000_0000, declared).expect("Failed to create hypervisor");
+
+    let before = hv.memory_usage();
+    assert_eq!(before.declared, declared);
+    assert!(before.resident < 64 * 1024 * 1024);
+
+    // 16MB 分に触れる
+    for offset in (0..16 * 1024 * 1024u64).step_by(0x4000) {
+        hv.write_byte(0x4000_0000 + offset, 1).unwrap();
+    }
+
+    let after = hv.memory_usage();
+    assert!(after.resident >= 16 * 1024 * 1024);
+    assert!(after.resident < declared / 2);
+}
@@ -0,0 +1,44 @@
+//! Hypervisor のライフサイクル (shutdown / 再作成) の統合テスト
+//!
+//! Hypervisor.framework は 1 プロセス 1 VM のため、すべての確認を
+//! 1 つのテスト関数で順に行う。
+//! ローカルで実行: `cargo test --test lifecycle_test -- --ignored`
+
+use applevisor::Reg;
+use hypervisor::Hypervisor;
+
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn shutdown_後に同じプロセスで_vm_を再作成できる() {
+    Hypervisor::check_availability().expect("Hypervisor.framework should be available");
+
+    let mut hv = Hypervisor::new(0x4000_0000, 0x10_0000).expect("Failed to create hypervisor");
+    assert!(hv.is_active());
+    // VM が存在する間も確認できる
+    Hypervisor::check_availability().expect("Availability check should not fail while a VM exists");
+
+    // 二重作成はエラー
+    let err = Hypervisor::new(0x4000_0000, 0x10_0000)
+        .err()
+        .expect("Second Hypervisor should be rejected");
+    assert!(err.to_string().contains("one VM per process"));
+
+    hv.shutdown().expect("Failed to shut down hypervisor");
+    assert!(!hv.is_active());
+
+    // shutdown 後の操作はエラー
+    assert!(hv.get_reg(Reg::X0).is_err());
+    assert!(hv.run(None, None, None).is_err());
+    assert!(hv.shutdown().is_err());
+
+    // 再作成できる
+    let hv2 = Hypervisor::new(0x4000_0000, 0x10_0000).expect("Failed to recreate hypervisor");
+    hv2.set_reg(Reg::X0, 42).expect("Failed to set X0");
+    assert_eq!(hv2.get_reg(Reg::X0).unwrap(), 42);
+
+    drop(hv);
+    drop(hv2);
+
+    // drop でも解放される
+    let _hv3 = Hypervisor::new(0x4000_0000, 0x10_0000).expect("Failed to create after drop");
+}
@@ -3,6 +3,8 @@
 //! 実際の Linux カーネルをハイパーバイザーで起動し、
 //! earlycon 出力を確認する。
 
+#![cfg(feature = "uart")]
+
 use applevisor::Reg;
 use hypervisor::boot::device_tree::{generate_device_tree, DeviceTreeConfig};
 use hypervisor::boot::kernel::KernelImage;
@@ -96,7 +98,8 @@ fn linux_カーネルが起動してuart出力する() {
     // UART 出力を収集
     let uart_output = Arc::new(Mutex::new(Vec::new()));
     let uart = UartCollector::new(UART_BASE, Arc::clone(&uart_output));
-    hv.register_mmio_handler(Box::new(uart));
+    hv.register_mmio_handler(Box::new(uart))
+        .expect("Failed to register MMIO handler");
 
     // GIC は Hypervisor が自動的に登録する
 
@@ -167,20 +170,15 @@ fn linux_カーネルがinitramfsでシェルを起動する() {
     // UART 出力を収集
     let uart_output = Arc::new(Mutex::new(Vec::new()));
     let uart = UartCollector::new(UART_BASE, Arc::clone(&uart_output));
-    hv.register_mmio_handler(Box::new(uart));
+    hv.register_mmio_handler(Box::new(uart))
+        .expect("Failed to register MMIO handler");
 
     // GIC は Hypervisor が自動的に登録する
 
     // initramfs をメモリに配置
     let initramfs_end = INITRAMFS_ADDR + initramfs_data.len() as u64;
-    for (i, &byte) in initramfs_data.iter().enumerate() {
-        hv.write_byte(INITRAMFS_ADDR + i as u64, byte)
-            .expect("Failed to write initramfs");
-    }
-    println!(
-        "initramfs loaded at 0x{:x}-0x{:x}",
-        INITRAMFS_ADDR, initramfs_end
-    );
+    hv.load_blob("initrd", INITRAMFS_ADDR, &initramfs_data)
+        .expect("Failed to write initramfs");
 
     // Device Tree を生成（initramfs 情報付き）
     let dtb = generate_device_tree(&DeviceTreeConfig {
@@ -193,22 +191,18 @@ fn linux_カーネルがinitramfsでシェルを起動する() {
         cmdline: "console=ttyAMA0 earlycon=pl011,0x09000000 loglevel=8 rdinit=/init".to_string(),
         initrd_start: Some(INITRAMFS_ADDR),
         initrd_end: Some(initramfs_end),
+        ..Default::default()
     })
     .expect("Failed to generate device tree");
 
     // Device Tree をメモリに配置
-    for (i, &byte) in dtb.iter().enumerate() {
-        hv.write_byte(DTB_ADDR + i as u64, byte)
-            .expect("Failed to write DTB");
-    }
-    println!("DTB loaded at 0x{:x} ({} bytes)", DTB_ADDR, dtb.len());
+    hv.load_blob("dtb", DTB_ADDR, &dtb)
+        .expect("Failed to write DTB");
 
     // カーネルをメモリに配置
-    for (i, &byte) in kernel.data().iter().enumerate() {
-        hv.write_byte(KERNEL_ENTRY + i as u64, byte)
-            .expect("Failed to write kernel");
-    }
-    println!("Kernel loaded at 0x{:x}", KERNEL_ENTRY);
+    hv.load_blob("kernel", KERNEL_ENTRY, kernel.data())
+        .expect("Failed to write kernel");
+    print!("{}", hv.load_map());
 
     // ARM64 Linux ブート条件を設定
     hv.set_reg(Reg::X0, DTB_ADDR).expect("Failed to set X0");
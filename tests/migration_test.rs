@@ -0,0 +1,40 @@
+//! マイグレーションストリームのテスト
+//!
+//! これらのテストは Hypervisor.framework の entitlements が必要です。
+//! ローカルで実行する場合は `cargo test --ignored` を使用してください。
+
+#![cfg(feature = "snapshot")]
+
+use applevisor::Reg;
+use hypervisor::Hypervisor;
+
+const GUEST_ADDR: u64 = 0x4000_0000;
+const MEM_SIZE: usize = 0x10_0000;
+
+/// 停止した VM のレジスタと RAM を新しい VM に移せることを確認
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn 停止した_vm_を別の_vm_に移せる() {
+    let mut hv = Hypervisor::new(GUEST_ADDR, MEM_SIZE).expect("Failed to create hypervisor");
+    let instructions: [u32; 2] = [
+        0xD280_0540, // MOV X0, #42
+        0xD420_0000, // BRK #0
+    ];
+    hv.write_instructions(&instructions)
+        .expect("Failed to write instructions");
+    hv.write_data(0x8000, 0xdead_beef).unwrap();
+
+    let mut stream = Vec::new();
+    hv.precopy_ram(&mut stream).expect("Failed to pre-copy RAM");
+    let result = hv.run(None, None, None).expect("Failed to run");
+    assert_eq!(result.registers[0], 42);
+    hv.migrate_out(&mut stream).expect("Failed to migrate out");
+    hv.shutdown().unwrap();
+
+    let mut hv = Hypervisor::new(GUEST_ADDR, MEM_SIZE).expect("Failed to create hypervisor");
+    hv.migrate_in(&mut stream.as_slice())
+        .expect("Failed to migrate in");
+    assert_eq!(hv.get_reg(Reg::X0).unwrap(), 42);
+    assert_eq!(hv.get_reg(Reg::PC).unwrap(), result.pc);
+    assert_eq!(hv.read_data(0x8000).unwrap(), 0xdead_beef);
+}
@@ -3,6 +3,8 @@
 //! UART に "Hello from mini kernel!" と出力する簡単なカーネルを実行し、
 //! ハイパーバイザーの Linux 起動機能をテストする。
 
+#![cfg(feature = "uart")]
+
 use hypervisor::boot::device_tree::{generate_device_tree, DeviceTreeConfig};
 use hypervisor::boot::kernel::KernelImage;
 use hypervisor::devices::uart::Pl011Uart;
@@ -85,7 +87,8 @@ fn mini_kernel_がuartに出力して終了する() {
 
     // UART デバイスを登録
     let uart = Pl011Uart::new(UART_BASE);
-    hv.register_mmio_handler(Box::new(uart));
+    hv.register_mmio_handler(Box::new(uart))
+        .expect("Failed to register MMIO handler");
 
     // ミニカーネルを作成
     let kernel_data = create_mini_kernel();
@@ -120,6 +123,7 @@ fn device_tree_が正しく生成される() {
         cmdline: "console=ttyAMA0 earlycon".to_string(),
         initrd_start: None,
         initrd_end: None,
+        ..Default::default()
     };
 
     let dtb = generate_device_tree(&config).expect("Failed to generate DTB");
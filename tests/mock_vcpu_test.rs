@@ -0,0 +1,1042 @@
+//! モックの vCPU で run ループを動かすテスト
+//!
+//! `MockVcpu` が台本どおりに VM Exit を返すため、Hypervisor.framework の
+//! entitlements がない macOS (CI のランナーなど) でも例外の振り分け・MMIO・
+//! 割り込みの処理を確認できる。
+
+use applevisor::{ExitReason, Reg, SysReg};
+use hypervisor::addressing::{AddressConfig, PageGranule};
+use hypervisor::backend::{MockExit, MockVcpu, MockVm, VcpuBackend};
+use hypervisor::boot::fdt::to_dts;
+use hypervisor::boot::kernel::KernelImage;
+use hypervisor::boot::layout::{IrqMap, PMEM_ALIGN};
+use hypervisor::boot::vectors::{shim_table, VectorKind, VectorSource, SHIM_BRK_BASE};
+use hypervisor::devices::gic::{GIC_CPU_BASE, GIC_DIST_BASE};
+use hypervisor::devices::host_time::ManualClock;
+use hypervisor::devices::pl330::Pl330Stub;
+use hypervisor::devices::scmi::ScmiDevice;
+use hypervisor::devices::sdk::api::{Device, DtDescribe, DtNode};
+use hypervisor::devices::smmu::SmmuV3Stub;
+use hypervisor::event_loop::{NAP_WFIS, YIELD_WFIS};
+use hypervisor::exit_history::ExitHistory;
+use hypervisor::host_channel::{GuestEvent, HostChannel, HOST_CHANNEL_HVC_ID};
+use hypervisor::irq_storm::{IrqStorm, IrqStormGuard};
+use hypervisor::memory::GuestRam;
+use hypervisor::mmio::MmioHandler;
+use hypervisor::monitor::MonitorCommand;
+use hypervisor::qmp::QmpServer;
+use hypervisor::run_options::{BrkPolicy, ExceptionLevel, RunOptions};
+use hypervisor::selftest::Outcome;
+use hypervisor::stats::IdleState;
+use hypervisor::watch::WatchAction;
+use hypervisor::{Hypervisor, StopReason};
+use hypervisor_debug_exit::{DebugExit, DEBUG_EXIT_BASE};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const GUEST_ADDR: u64 = 0x4000_0000;
+const DEVICE_BASE: u64 = 0x0a00_0000;
+
+fn mock_hypervisor(vcpu: &MockVcpu) -> Hypervisor {
+    let ram = GuestRam::new(0x10_0000).expect("Failed to allocate guest RAM");
+    Hypervisor::with_backend(GUEST_ADDR, ram, &MockVm, Box::new(vcpu.clone()))
+        .expect("Failed to create hypervisor")
+}
+
+fn exception_class(syndrome: Option<u64>) -> u64 {
+    syndrome.map(|s| (s >> 26) & 0x3f).unwrap_or(0)
+}
+
+/// 書き込まれた値を記録し、読み取りにはオフセットを返すデバイス
+struct RecordingDevice {
+    writes: Arc<Mutex<Vec<(u64, u64, usize)>>>,
+}
+
+impl MmioHandler for RecordingDevice {
+    fn base(&self) -> u64 {
+        DEVICE_BASE
+    }
+
+    fn size(&self) -> u64 {
+        0x1000
+    }
+
+    fn read(&mut self, offset: u64, _size: usize) -> Result<u64, Box<dyn Error>> {
+        Ok(0x1000 + offset)
+    }
+
+    fn write(&mut self, offset: u64, value: u64, size: usize) -> Result<(), Box<dyn Error>> {
+        self.writes.lock().unwrap().push((offset, value, size));
+        Ok(())
+    }
+}
+
+#[test]
+fn mmio_の読み書きがデバイスに届く() {
+    let vcpu = MockVcpu::new();
+    vcpu.push_exit(MockExit::mmio_write(DEVICE_BASE + 0x10, 4, 0xabcd));
+    vcpu.push_exit(MockExit::mmio_read(DEVICE_BASE + 0x20, 8));
+    vcpu.push_exit(MockExit::brk());
+
+    let mut hv = mock_hypervisor(&vcpu);
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    hv.register_mmio_handler(Box::new(RecordingDevice {
+        writes: writes.clone(),
+    }))
+    .expect("Failed to register MMIO handler");
+
+    let result = hv.run(None, None, None).expect("Failed to run");
+
+    assert_eq!(exception_class(result.exception_syndrome), 0x3c);
+    assert_eq!(*writes.lock().unwrap(), vec![(0x10, 0xabcd, 4)]);
+    assert_eq!(result.registers[0], 0x1020);
+    // Data Abort ごとに PC が 4 進む
+    assert_eq!(result.pc, GUEST_ADDR + 8);
+    assert_eq!(vcpu.remaining(), 0);
+}
+
+#[test]
+fn 同じ_vm_exit_を繰り返すだけなら_livelock_で止める() {
+    let vcpu = MockVcpu::new();
+    // 監視中のページへの ISV=0 の書き込みは PC を進めずに再実行させる。
+    // 書き込み可能に戻らないまま同じ命令でフォルトし続ける
+    let watched = GUEST_ADDR + 0x8000;
+    for _ in 0..3 {
+        let store = MockExit::exception(0x24 << 26 | 1 << 25 | 1 << 6, watched);
+        vcpu.push_exit(store.reg(Reg::PC, GUEST_ADDR + 0x20));
+    }
+
+    let mut hv = mock_hypervisor(&vcpu);
+    hv.watch_writes("page", watched..watched + 8, WatchAction::Log)
+        .unwrap();
+    hv.set_livelock_threshold(3);
+    let result = hv.run(None, None, None).expect("Failed to run");
+
+    let StopReason::Livelock(livelock) = result.stop_reason else {
+        panic!("expected a livelock, got {:?}", result.stop_reason);
+    };
+    assert_eq!(livelock.pc, GUEST_ADDR + 0x20);
+    assert_eq!(livelock.fault_addr, Some(watched));
+    assert_eq!(livelock.repeats, 3);
+    assert_eq!(exception_class(result.exception_syndrome), 0x24);
+    assert_eq!(vcpu.remaining(), 0);
+}
+
+#[test]
+fn mmio_のポーリングは_livelock_とみなさない() {
+    let vcpu = MockVcpu::new();
+    // 同じ読み込みを繰り返すが、ハンドラが毎回 PC を進める
+    for _ in 0..5 {
+        let read = MockExit::mmio_read(DEVICE_BASE + 0x20, 4).reg(Reg::X0, 0x20);
+        vcpu.push_exit(read.reg(Reg::PC, GUEST_ADDR + 0x20));
+    }
+    vcpu.push_exit(MockExit::brk());
+
+    let mut hv = mock_hypervisor(&vcpu);
+    hv.register_mmio_handler(Box::new(RecordingDevice {
+        writes: Arc::new(Mutex::new(Vec::new())),
+    }))
+    .expect("Failed to register MMIO handler");
+    hv.set_livelock_threshold(3);
+    let result = hv.run(None, None, None).expect("Failed to run");
+
+    assert_eq!(result.stop_reason, StopReason::Exit);
+    assert_eq!(exception_class(result.exception_syndrome), 0x3c);
+    assert_eq!(vcpu.remaining(), 0);
+}
+
+/// レジスタを 1 つだけ持つ外部 crate 風のデバイス
+struct Scratch(u64);
+
+impl DtDescribe for Scratch {
+    fn dt_node(&self, base: u64, irq: Option<u32>) -> Option<DtNode> {
+        Some(DtNode::new("scratch", base, 0x1000).interrupt(irq))
+    }
+}
+
+impl Device for Scratch {
+    fn name(&self) -> &str {
+        "scratch"
+    }
+
+    fn size(&self) -> u64 {
+        0x1000
+    }
+
+    fn read(&mut self, _offset: u64, _size: usize) -> Result<u64, Box<dyn Error>> {
+        Ok(self.0)
+    }
+
+    fn write(&mut self, _offset: u64, value: u64, _size: usize) -> Result<(), Box<dyn Error>> {
+        self.0 = value;
+        Ok(())
+    }
+}
+
+#[test]
+fn 外部の_crate_のデバイスを追加する() {
+    let scratch = 0x0a10_0000;
+    let vcpu = MockVcpu::new();
+    vcpu.push_exit(MockExit::mmio_write(scratch, 8, 0x1234));
+    vcpu.push_exit(MockExit::mmio_read(scratch, 8));
+    vcpu.push_exit(MockExit::brk());
+
+    let mut hv = mock_hypervisor(&vcpu);
+    hv.add_device(scratch, Some(80), Scratch(0))
+        .expect("Failed to add device");
+    // 重なる配置と SPI でない割り込みは拒否する
+    assert!(hv.add_device(scratch + 0x800, None, Scratch(0)).is_err());
+    assert!(hv.add_device(GUEST_ADDR, None, Scratch(0)).is_err());
+    assert!(hv
+        .add_device(scratch + 0x1000, Some(27), Scratch(0))
+        .is_err());
+
+    let result = hv.run(None, None, None).expect("Failed to run");
+    assert_eq!(result.registers[0], 0x1234);
+}
+
+#[test]
+fn debug_exit_ポートへの書き込みで終了する() {
+    let vcpu = MockVcpu::new();
+    vcpu.push_exit(MockExit::mmio_write(DEBUG_EXIT_BASE, 4, 0x10));
+    vcpu.push_exit(MockExit::brk());
+
+    let mut hv = mock_hypervisor(&vcpu);
+    hv.add_device(DEBUG_EXIT_BASE, None, DebugExit::new())
+        .expect("Failed to add debug-exit");
+    let result = hv.run(None, None, None).expect("Failed to run");
+
+    let StopReason::DeviceExit(exit) = result.stop_reason else {
+        panic!("expected a device exit, got {:?}", result.stop_reason);
+    };
+    assert_eq!((exit.device.as_str(), exit.code), ("debug-exit", 0x21));
+    assert_eq!(result.exit_reason, ExitReason::CANCELED);
+    // 書き込みの後はゲストに戻らない
+    assert_eq!(vcpu.remaining(), 1);
+}
+
+#[test]
+fn メモリで裏付けた_mmio_領域はデバイスと重ならない() {
+    let vcpu = MockVcpu::new();
+    let mut hv = mock_hypervisor(&vcpu);
+    hv.register_mmio_handler(Box::new(RecordingDevice {
+        writes: Arc::new(Mutex::new(Vec::new())),
+    }))
+    .expect("Failed to register MMIO handler");
+
+    let framebuffer = hv
+        .add_memory_mmio("framebuffer", 0x1000_0000, 0x8000)
+        .expect("Failed to add framebuffer");
+    framebuffer.write(0x1000_0010, &[0xff; 4]).unwrap();
+    let shared = hv.memory_mmio("framebuffer").unwrap();
+    let mut pixel = [0; 4];
+    shared.read(0x1000_0010, &mut pixel).unwrap();
+    assert_eq!(pixel, [0xff; 4]);
+
+    // 登録済みのデバイス・RAM・同じ名前・ページに揃っていない範囲
+    let err = hv
+        .add_memory_mmio("ring", DEVICE_BASE, 0x4000)
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("overlaps MMIO device"));
+    assert!(hv.add_memory_mmio("ring", GUEST_ADDR, 0x4000).is_err());
+    assert!(hv.add_memory_mmio("ring", 0x1000_4000, 0x4000).is_err());
+    assert!(hv
+        .add_memory_mmio("framebuffer", 0x2000_0000, 0x4000)
+        .is_err());
+    assert!(hv.add_memory_mmio("ring", 0x2000_0000, 0x1000).is_err());
+    let err = hv
+        .add_memory_mmio("ring", u64::MAX - 0x3fff, 0x8000)
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("overflows the address space"));
+    assert!(hv.add_memory_mmio("ring", 0x2000_0000, 0x4000).is_ok());
+}
+
+#[test]
+fn メモリで裏付けた_mmio_領域に重なるデバイスは登録できない() {
+    let vcpu = MockVcpu::new();
+    let mut hv = mock_hypervisor(&vcpu);
+    hv.add_memory_mmio("framebuffer", DEVICE_BASE, 0x4000)
+        .expect("Failed to add framebuffer");
+    let device = || {
+        Box::new(RecordingDevice {
+            writes: Arc::new(Mutex::new(Vec::new())),
+        })
+    };
+    let err = hv.register_mmio_handler(device()).err().unwrap();
+    assert!(err
+        .to_string()
+        .starts_with("MMIO device 'mmio': Memory region 0xa000000-0xa001000 overlaps"));
+    assert!(hv.register_fast_mmio_handler(device(), &[0]).is_err());
+
+    // 固定の位置に置くデバイスも同じように確かめる
+    hv.add_memory_mmio("stubs", 0x2000_0000, 0x1_0000)
+        .expect("Failed to add region");
+    assert!(hv.attach_pl330(Pl330Stub::new(0x2000_1000)).is_err());
+    assert!(hv.attach_smmu(SmmuV3Stub::new(0x2000_0000)).is_err());
+    assert!(hv.attach_scmi(ScmiDevice::new(0x2000_4000)).is_err());
+    // RAM に重なる位置も同じ
+    assert!(hv
+        .register_mmio_handler(Box::new(Pl330Stub::new(GUEST_ADDR)))
+        .is_err());
+}
+
+#[test]
+fn 登録済みのデバイスに重なるメモリ領域は追加できない() {
+    let vcpu = MockVcpu::new();
+    let mut hv = mock_hypervisor(&vcpu);
+    hv.attach_pl330(Pl330Stub::new(0x2000_1000))
+        .expect("Failed to attach PL330");
+
+    let region = GuestRam::new(0x4000).unwrap();
+    let err = hv.add_memory_region(0x2000_0000, region).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Memory region at 0x20000000-0x20004000 overlaps MMIO device 'pl330' at 0x20001000"
+    );
+    hv.add_memory_region(0x2000_4000, GuestRam::new(0x4000).unwrap())
+        .expect("Failed to add memory region");
+}
+
+#[test]
+#[cfg(feature = "snapshot")]
+fn メモリで裏付けた_mmio_領域もマイグレーションで移す() {
+    let vcpu = MockVcpu::new();
+    let mut hv = mock_hypervisor(&vcpu);
+    let framebuffer = hv
+        .add_memory_mmio("framebuffer", 0x1000_0000, 0x8000)
+        .expect("Failed to add framebuffer");
+    framebuffer.write(0x1000_7ff0, &[0xab; 4]).unwrap();
+    let mut stream = Vec::new();
+    hv.migrate_out(&mut stream).expect("Failed to migrate out");
+
+    let vcpu = MockVcpu::new();
+    let mut hv = mock_hypervisor(&vcpu);
+    assert!(hv.migrate_in(&mut stream.as_slice()).is_err());
+    let framebuffer = hv
+        .add_memory_mmio("framebuffer", 0x1000_0000, 0x8000)
+        .expect("Failed to add framebuffer");
+    hv.migrate_in(&mut stream.as_slice())
+        .expect("Failed to migrate in");
+    let mut pixel = [0; 4];
+    framebuffer.read(0x1000_7ff0, &mut pixel).unwrap();
+    assert_eq!(pixel, [0xab; 4]);
+}
+
+#[test]
+fn 永続メモリはセクション境界に揃える() {
+    let vcpu = MockVcpu::new();
+    let mut hv = mock_hypervisor(&vcpu);
+    let path = std::env::temp_dir().join(format!("hv-mock-pmem-{}", std::process::id()));
+    let file = std::fs::File::create(&path).unwrap();
+    file.set_len(PMEM_ALIGN / 2).unwrap();
+    let err = hv.add_pmem(&path, 0x8000_0000, false).err().unwrap();
+    assert!(err.to_string().contains("not aligned to 0x8000000"));
+
+    file.set_len(PMEM_ALIGN).unwrap();
+    assert!(hv.add_pmem(&path, 0x8000_0000 + 0x20_0000, false).is_err());
+    hv.add_pmem(&path, 0x8000_0000, false)
+        .expect("Failed to add pmem");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+#[cfg(feature = "snapshot")]
+fn 永続メモリはマイグレーションで配置だけを送る() {
+    let path = std::env::temp_dir().join(format!("hv-mock-pmem-mig-{}", std::process::id()));
+    std::fs::File::create(&path)
+        .unwrap()
+        .set_len(PMEM_ALIGN)
+        .unwrap();
+    let vcpu = MockVcpu::new();
+    let mut hv = mock_hypervisor(&vcpu);
+    hv.add_pmem(&path, 0x8000_0000, false)
+        .expect("Failed to add pmem");
+    let mut stream = Vec::new();
+    hv.migrate_out(&mut stream).expect("Failed to migrate out");
+    // 内容はファイルが運ぶため、ストリームに 128MB 分のページは含まない
+    assert!(stream.len() < PMEM_ALIGN as usize / 2);
+
+    let vcpu = MockVcpu::new();
+    let mut hv = mock_hypervisor(&vcpu);
+    assert!(hv.migrate_in(&mut stream.as_slice()).is_err());
+    hv.add_pmem(&path, 0x8000_0000, false)
+        .expect("Failed to add pmem");
+    hv.migrate_in(&mut stream.as_slice())
+        .expect("Failed to migrate in");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn 割り込み番号を変えるとタイマーの注入先も変わる() {
+    let vcpu = MockVcpu::new();
+    let mut hv = mock_hypervisor(&vcpu);
+    let irqs = IrqMap {
+        virt_timer: 20,
+        phys_timer: 21,
+        ..IrqMap::default()
+    };
+    hv.set_irq_map(irqs).expect("Failed to set IRQ map");
+    let policy = hv.irq_chip().policy();
+    assert_eq!((policy.virt, policy.phys), (20, 21));
+}
+
+#[test]
+fn hvc_で_psci_version_を返す() {
+    let vcpu = MockVcpu::new();
+    vcpu.push_exit(MockExit::hvc(0x8400_0000));
+    vcpu.push_exit(MockExit::brk());
+
+    let mut hv = mock_hypervisor(&vcpu);
+    let result = hv.run(None, None, None).expect("Failed to run");
+
+    assert_eq!(exception_class(result.exception_syndrome), 0x3c);
+    assert_eq!(vcpu.reg(Reg::X0), 0x0001_0000);
+}
+
+#[test]
+fn system_reset_の後に指定したカーネルを取り出す() {
+    let vcpu = MockVcpu::new();
+    vcpu.push_exit(MockExit::hvc(0x8400_0009));
+
+    let mut hv = mock_hypervisor(&vcpu);
+    let kernel = std::env::temp_dir().join(format!("hv-mock-next-{}", std::process::id()));
+    std::fs::write(&kernel, [0x00, 0x00, 0x00, 0x14]).unwrap();
+    let command = MonitorCommand::parse(&format!("boot next {} -- quiet", kernel.display()));
+    hv.monitor_command(command.unwrap()).unwrap();
+    assert!(hv
+        .monitor_command(MonitorCommand::parse("boot next /nonexistent/Image").unwrap())
+        .is_err());
+
+    let result = hv.run(None, None, None).expect("Failed to run");
+    assert!(result.is_system_reset());
+    let next = hv.take_next_boot().unwrap();
+    assert_eq!(next.cmdline_or("console=ttyAMA0"), "quiet");
+    assert_eq!(next.load().unwrap().kernel.size(), 4);
+    assert_eq!(
+        hv.monitor_command(MonitorCommand::InfoBoot).unwrap(),
+        "next boot: unchanged"
+    );
+    std::fs::remove_file(&kernel).unwrap();
+}
+
+#[test]
+fn ゲストが指定したカーネルで同じ_vm_を起動し直す() {
+    let root = std::env::temp_dir().join(format!("hv-mock-reboot-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("Image-b"), [0x00, 0x00, 0x00, 0x14]).unwrap();
+    std::fs::write(root.join("initrd"), b"initrd").unwrap();
+    let request = b"Image-b initrd -- quiet";
+
+    let vcpu = MockVcpu::new();
+    vcpu.push_exit(
+        MockExit::hvc(HOST_CHANNEL_HVC_ID)
+            .reg(Reg::X1, 5)
+            .reg(Reg::X2, GUEST_ADDR + 0x1000)
+            .reg(Reg::X3, request.len() as u64),
+    );
+    vcpu.push_exit(MockExit::hvc(0x8400_0009));
+    vcpu.push_exit(MockExit::brk());
+
+    let mut hv = mock_hypervisor(&vcpu);
+    hv.load_blob("request", GUEST_ADDR + 0x1000, request)
+        .expect("Failed to load request");
+    hv.attach_host_channel(HostChannel::new().serve_files(&root));
+    let result = hv.run(None, None, None).expect("Failed to run");
+    assert!(result.is_system_reset());
+    assert!(matches!(hv.guest_events(), [GuestEvent::BootNext(_)]));
+    vcpu.set_sys_reg(SysReg::VBAR_EL1, GUEST_ADDR + 0x800)
+        .unwrap();
+
+    let kernel = KernelImage::from_bytes(vec![0x00, 0x00, 0x00, 0x14], None);
+    hv.reboot(&kernel, "console=ttyAMA0", Some(GUEST_ADDR + 0xc_0000))
+        .expect("Failed to reboot");
+
+    // ゲストが書き換えたレジスタは作成直後の値に戻る
+    assert_eq!(vcpu.sys_reg(SysReg::VBAR_EL1), 0);
+    let initrd = hv.load_map().get("initrd").unwrap();
+    assert_eq!(initrd.addr, GUEST_ADDR + 0xf_f000);
+    let dts = to_dts(hv.dump_device_tree().unwrap()).unwrap();
+    assert!(dts.contains("bootargs = \"quiet\";"));
+    assert!(dts.contains("linux,initrd-start"));
+    assert!(hv.next_boot().is_none());
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn アドレス幅の設定をゲストの_id_aa64mmfr0_el1_に反映する() {
+    // Apple M2 相当: PARange = 40 bit, TGran4 / TGran16 サポート, TGran64 非サポート
+    const APPLE_MMFR0: u64 = (0xf << 24) | (0x1 << 20) | 0x2;
+    let vcpu = MockVcpu::new();
+    vcpu.set_sys_reg(SysReg::ID_AA64MMFR0_EL1, APPLE_MMFR0)
+        .unwrap();
+    let mut hv = mock_hypervisor(&vcpu);
+
+    let config = AddressConfig {
+        granule: PageGranule::Size16K,
+        pa_bits: 36,
+    };
+    let mmfr0 = hv.set_address_config(&config).unwrap();
+    assert_eq!(mmfr0, (APPLE_MMFR0 & !0xf) | 0x1);
+    assert_eq!(vcpu.sys_reg(SysReg::ID_AA64MMFR0_EL1), mmfr0);
+
+    // ホストが扱えない設定は反映しない
+    let config = AddressConfig {
+        granule: PageGranule::Size64K,
+        pa_bits: 36,
+    };
+    assert!(hv.set_address_config(&config).is_err());
+    assert_eq!(vcpu.sys_reg(SysReg::ID_AA64MMFR0_EL1), mmfr0);
+}
+
+#[test]
+fn 仮想タイマーの発火で_irq_を注入する() {
+    let vcpu = MockVcpu::new();
+    // ゲストが GIC を有効にして仮想タイマーの PPI 27 を許可する
+    vcpu.push_exit(MockExit::mmio_write(GIC_DIST_BASE, 4, 1));
+    vcpu.push_exit(MockExit::mmio_write(GIC_DIST_BASE + 0x100, 4, 1 << 27));
+    vcpu.push_exit(MockExit::mmio_write(GIC_CPU_BASE, 4, 1));
+    vcpu.push_exit(MockExit::mmio_write(GIC_CPU_BASE + 0x4, 4, 0xff));
+    // CVAL を過ぎたタイマーを有効にした状態で VM Exit する
+    // (run ループは実行前に CNTV_CTL_EL0 を書き換えるため、VM Exit ごとに
+    // ゲストのタイマー設定を書き戻す)
+    vcpu.set_counter(200);
+    vcpu.push_exit(expired_timer(MockExit::vtimer()));
+    vcpu.push_exit(expired_timer(MockExit::brk()));
+
+    let mut hv = mock_hypervisor(&vcpu);
+    let result = hv.run(None, None, None).expect("Failed to run");
+    assert_eq!(result.exit_reason, ExitReason::EXCEPTION);
+    assert!(vcpu.irq_pending());
+
+    // GICC_IAR で受け付け、タイマーを止めると IRQ 線が下がる
+    vcpu.push_exit(expired_timer(MockExit::mmio_read(GIC_CPU_BASE + 0x0c, 4)));
+    vcpu.push_exit(MockExit::brk());
+    let result = hv.run(None, None, Some(result.pc)).expect("Failed to run");
+    assert_eq!(result.registers[0], 27);
+    assert!(!vcpu.irq_pending());
+}
+
+/// 割り込み線を下げない (ステータスをクリアし忘れた) デバイス
+struct StuckIrqDevice;
+
+impl MmioHandler for StuckIrqDevice {
+    fn base(&self) -> u64 {
+        DEVICE_BASE
+    }
+
+    fn size(&self) -> u64 {
+        0x1000
+    }
+
+    fn read(&mut self, _offset: u64, _size: usize) -> Result<u64, Box<dyn Error>> {
+        Ok(0)
+    }
+
+    fn write(&mut self, _offset: u64, _value: u64, _size: usize) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "stuck"
+    }
+
+    fn irq(&self) -> Option<u32> {
+        Some(40)
+    }
+
+    fn pending_irq(&self) -> Option<u32> {
+        Some(40)
+    }
+}
+
+#[test]
+fn 割り込み線が下がらないデバイスの割り込みストームを検出する() {
+    let vcpu = MockVcpu::new();
+    vcpu.push_exit(MockExit::mmio_write(GIC_DIST_BASE, 4, 1));
+    vcpu.push_exit(MockExit::mmio_write(GIC_DIST_BASE + 0x104, 4, 1 << 8));
+    vcpu.push_exit(MockExit::mmio_write(GIC_CPU_BASE, 4, 1));
+    vcpu.push_exit(MockExit::mmio_write(GIC_CPU_BASE + 0x4, 4, 0xff));
+    // ゲストは割り込みを受け付けて EOI するが、デバイスは割り込み線を下げない
+    for _ in 0..5 {
+        vcpu.push_exit(MockExit::mmio_read(GIC_CPU_BASE + 0x0c, 4));
+        vcpu.push_exit(MockExit::mmio_write(GIC_CPU_BASE + 0x10, 4, 40));
+    }
+    vcpu.push_exit(MockExit::brk());
+
+    let mut hv = mock_hypervisor(&vcpu);
+    hv.register_mmio_handler(Box::new(StuckIrqDevice))
+        .expect("Failed to register MMIO handler");
+    hv.set_irq_storm_guard(IrqStormGuard::with_clock(
+        3,
+        Duration::from_secs(3600),
+        Arc::new(ManualClock::new()),
+    ));
+    hv.run(None, None, None).expect("Failed to run");
+
+    assert_eq!(
+        hv.irq_storms(),
+        [IrqStorm {
+            irq: 40,
+            device: Some("stuck".to_string()),
+            repends: 3,
+        }]
+    );
+    // ストームの後は注入を絞るため、最後の受け付けはスプリアスになる
+    assert_eq!(vcpu.reg(Reg::X0), 1023);
+}
+
+#[test]
+fn 予期しない例外では_el1_のコンテキストを返す() {
+    let vcpu = MockVcpu::new();
+    // ゲストのベクタが未設定で、EL1 の Data Abort が Instruction Abort として EL2 に回る
+    vcpu.push_exit(
+        MockExit::exception(0x20 << 26, 0)
+            .sys_reg(SysReg::ESR_EL1, 0x25 << 26 | 0x4)
+            .sys_reg(SysReg::FAR_EL1, 0xdead_0000)
+            .sys_reg(SysReg::ELR_EL1, GUEST_ADDR + 0x40)
+            .sys_reg(SysReg::SPSR_EL1, 0x3c5)
+            .sys_reg(SysReg::SP_EL1, GUEST_ADDR + 0x8000),
+    );
+
+    let mut hv = mock_hypervisor(&vcpu);
+    let result = hv.run(None, None, None).expect("Failed to run");
+
+    assert_eq!(exception_class(result.exception_syndrome), 0x20);
+    let el1 = result.el1.expect("EL1 context is captured");
+    assert!(el1.has_guest_exception());
+    assert_eq!(el1.exception_class(), 0x25);
+    assert_eq!(el1.far, 0xdead_0000);
+    assert_eq!(el1.elr, GUEST_ADDR + 0x40);
+    assert_eq!(el1.spsr, 0x3c5);
+    assert_eq!(el1.sp_el1, GUEST_ADDR + 0x8000);
+
+    // BRK での終了は予期したものなので含めない
+    vcpu.push_exit(MockExit::brk());
+    let result = hv.run(None, None, Some(result.pc)).expect("Failed to run");
+    assert!(result.el1.is_none());
+}
+
+#[test]
+fn 直近の_vm_exit_を履歴に残す() {
+    let vcpu = MockVcpu::new();
+    for _ in 0..3 {
+        vcpu.push_exit(MockExit::mmio_read(0x0801_000c, 4).reg(Reg::PC, GUEST_ADDR + 0x10));
+    }
+    vcpu.push_exit(MockExit::exception(0x20 << 26, 0).reg(Reg::X2, 0x22));
+
+    let mut hv = mock_hypervisor(&vcpu);
+    hv.set_exit_history(ExitHistory::new(2).registers(&[2]));
+    hv.run(None, None, None).expect("Failed to run");
+
+    let history = hv.exit_history();
+    let records: Vec<_> = history.records().collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].class, "data abort");
+    assert_eq!(records[0].fault_addr, Some(0x0801_000c));
+    assert_eq!(records[1].class, "exception");
+    assert_eq!(records[1].registers, [(2, 0x22)]);
+    assert!(history.summary().starts_with("the last exit was exception"));
+}
+
+#[test]
+fn ゲストのテストコードからイベントを受け取る() {
+    let vcpu = MockVcpu::new();
+    let event = |kind: u64, code: u64| {
+        MockExit::hvc(HOST_CHANNEL_HVC_ID)
+            .reg(Reg::X1, kind)
+            .reg(Reg::X2, GUEST_ADDR + 0x1000)
+            .reg(Reg::X3, 3)
+            .reg(Reg::X4, code)
+    };
+    vcpu.push_exit(event(1, 0));
+    vcpu.push_exit(event(2, 5));
+    vcpu.push_exit(MockExit::brk());
+
+    let mut hv = mock_hypervisor(&vcpu);
+    hv.load_blob("name", GUEST_ADDR + 0x1000, b"net")
+        .expect("Failed to load name");
+    hv.attach_host_channel(HostChannel::new());
+    hv.run(None, None, None).expect("Failed to run");
+
+    assert_eq!(vcpu.reg(Reg::X0), 0);
+    assert_eq!(
+        hv.take_guest_events(),
+        [
+            GuestEvent::TestPassed("net".into()),
+            GuestEvent::TestFailed {
+                name: "net".into(),
+                code: 5
+            },
+        ]
+    );
+    assert!(hv.guest_events().is_empty());
+}
+
+#[test]
+#[cfg(feature = "uart")]
+fn boot_linux_はコンソールの指定を補う() {
+    let vcpu = MockVcpu::new();
+    vcpu.push_exit(MockExit::brk());
+    vcpu.push_exit(MockExit::brk());
+    vcpu.push_exit(MockExit::brk());
+    let mut hv = mock_hypervisor(&vcpu);
+    let kernel = KernelImage::from_bytes(vec![0x00, 0x00, 0x00, 0x14], None);
+    let dtb_addr = GUEST_ADDR + 0xc_0000;
+
+    // PL011 がなければ補わない
+    hv.boot_linux(&kernel, "rdinit=/init", Some(dtb_addr))
+        .expect("Failed to boot");
+    let dts = to_dts(hv.dump_device_tree().unwrap()).unwrap();
+    assert!(dts.contains("bootargs = \"rdinit=/init\";"));
+
+    hv.register_mmio_handler(Box::new(hypervisor::devices::uart::Pl011Uart::new(
+        0x0900_0000,
+    )))
+    .expect("Failed to register MMIO handler");
+    hv.boot_linux(&kernel, "rdinit=/init", Some(dtb_addr))
+        .expect("Failed to boot");
+    let dts = to_dts(hv.dump_device_tree().unwrap()).unwrap();
+    assert!(dts
+        .contains("bootargs = \"rdinit=/init console=ttyAMA0 earlycon=pl011,mmio32,0x9000000\";"));
+
+    hv.set_guest_console(hypervisor::boot::console::GuestConsole::Off);
+    hv.boot_linux(&kernel, "rdinit=/init", Some(dtb_addr))
+        .expect("Failed to boot");
+    let dts = to_dts(hv.dump_device_tree().unwrap()).unwrap();
+    assert!(dts.contains("bootargs = \"rdinit=/init\";"));
+}
+
+#[test]
+fn boot_uboot_は_ram_先頭の_dtb_を渡して起動する() {
+    let vcpu = MockVcpu::new();
+    vcpu.push_exit(MockExit::brk());
+    let mut hv = mock_hypervisor(&vcpu);
+    let uboot = KernelImage::from_bytes(vec![0x00, 0x00, 0x00, 0x14], Some(GUEST_ADDR + 0x8_0000));
+
+    let result = hv
+        .boot_uboot(&uboot, "console=ttyAMA0 root=/dev/vda rw")
+        .expect("Failed to boot");
+
+    assert_eq!(result.pc, GUEST_ADDR + 0x8_0000);
+    assert_eq!(vcpu.reg(Reg::X0), GUEST_ADDR);
+    let dts = to_dts(hv.dump_device_tree().unwrap()).unwrap();
+    assert!(dts.contains("clock-output-names = \"clk24mhz\";"));
+    assert!(dts.contains("stdout-path"));
+}
+
+#[test]
+fn boot_uboot_はタイマー周波数の異なるホストを拒否する() {
+    let vcpu = MockVcpu::new();
+    vcpu.set_counter_frequency(1_000_000_000);
+    let mut hv = mock_hypervisor(&vcpu);
+    let uboot = KernelImage::from_bytes(vec![0x00, 0x00, 0x00, 0x14], Some(GUEST_ADDR + 0x8_0000));
+
+    let err = hv.boot_uboot(&uboot, "console=ttyAMA0").err().unwrap();
+    assert!(err
+        .to_string()
+        .contains("CNTFRQ_EL0 is 1000000000 Hz but the timer emulation assumes 24000000 Hz"));
+    assert_eq!(vcpu.runs(), 0);
+}
+
+#[test]
+fn run_with_で初期レジスタと例外レベルを設定する() {
+    let vcpu = MockVcpu::new();
+    vcpu.push_exit(MockExit::brk());
+
+    let mut hv = mock_hypervisor(&vcpu);
+    let options = RunOptions::new()
+        .pc(GUEST_ADDR + 0x100)
+        .level(ExceptionLevel::El0)
+        .gpr(0, 0x1234)
+        .gpr(30, GUEST_ADDR + 0x200)
+        .sp(GUEST_ADDR + 0x8000)
+        .vbar(GUEST_ADDR + 0x800)
+        .sctlr(0x30d0_0800);
+    let result = hv.run_with(&options).expect("Failed to run");
+
+    assert_eq!(result.pc, GUEST_ADDR + 0x100);
+    assert_eq!(result.registers[0], 0x1234);
+    assert_eq!(result.registers[30], GUEST_ADDR + 0x200);
+    assert_eq!(vcpu.reg(Reg::CPSR), 0x3c0);
+    assert_eq!(vcpu.sys_reg(SysReg::SP_EL0), GUEST_ADDR + 0x8000);
+    assert_eq!(vcpu.sys_reg(SysReg::VBAR_EL1), GUEST_ADDR + 0x800);
+    assert_eq!(vcpu.sys_reg(SysReg::SCTLR_EL1), 0x30d0_0800);
+
+    // 不正な設定ではゲストを実行しない
+    let err = hv.run_with(&RunOptions::new().gpr(31, 0)).err().unwrap();
+    assert!(err.to_string().contains("X31"));
+    assert_eq!(vcpu.runs(), 1);
+}
+
+#[test]
+fn ベクタのシムでゲストの例外を_brk_の_vm_exit_にする() {
+    let vcpu = MockVcpu::new();
+    // 同じ EL・SP_ELx の IRQ ベクタ (5 番) の BRK
+    let shim_irq = 0x3c << 26 | 1 << 25 | (SHIM_BRK_BASE as u64 + 5);
+    vcpu.push_exit(
+        MockExit::exception(shim_irq, 0)
+            .sys_reg(SysReg::ELR_EL1, GUEST_ADDR + 0x10)
+            .sys_reg(SysReg::SPSR_EL1, 0x3c5),
+    );
+    let mut hv = mock_hypervisor(&vcpu);
+
+    let shim = GUEST_ADDR + 0x8000;
+    let result = hv
+        .run_with(&RunOptions::new().vector_shim(shim))
+        .expect("Failed to run");
+
+    let vector = result.shim_vector().expect("Expected a shim exit");
+    assert_eq!(vector.kind, VectorKind::Irq);
+    assert_eq!(vector.source, VectorSource::CurrentSpx);
+    assert_eq!(result.el1.unwrap().elr, GUEST_ADDR + 0x10);
+    assert_eq!(vcpu.sys_reg(SysReg::VBAR_EL1), shim);
+    assert_eq!(
+        hv.memory_snapshot(shim..shim + 0x800).unwrap(),
+        shim_table()
+    );
+    assert!(hv.load_map().get("vector-shim").is_some());
+
+    // シム以外の BRK はこれまでどおり
+    vcpu.push_exit(MockExit::brk());
+    let result = hv.run(None, None, None).expect("Failed to run");
+    assert_eq!(result.shim_vector(), None);
+    assert!(result.el1.is_none());
+}
+
+#[test]
+fn brk_を読み飛ばすかゲストの_el1_に渡す() {
+    let vcpu = MockVcpu::new();
+    // BRK #0x800 (KASAN の報告など)
+    let brk = 0x3c << 26 | 1 << 25 | 0x800;
+    vcpu.push_exit(MockExit::exception(brk, 0).reg(Reg::PC, GUEST_ADDR + 0x20));
+    // BRK はすべて読み飛ばすので SYSTEM_OFF で止める
+    vcpu.push_exit(MockExit::hvc(0x8400_0008).reg(Reg::PC, GUEST_ADDR + 0x40));
+    let mut hv = mock_hypervisor(&vcpu);
+
+    let options = RunOptions::new().brk_policy(BrkPolicy::Skip);
+    let result = hv.run_with(&options).expect("Failed to run");
+    assert_eq!(result.pc, GUEST_ADDR + 0x40);
+    assert_eq!(vcpu.runs(), 2);
+
+    // EL1h から受けた BRK は VBAR_EL1 + 0x200 へ
+    let vbar = GUEST_ADDR + 0x800;
+    vcpu.push_exit(MockExit::exception(brk, 0).reg(Reg::PC, GUEST_ADDR + 0x20));
+    vcpu.push_exit(MockExit::hvc(0x8400_0008));
+    let options = RunOptions::new().vbar(vbar).brk_policy(BrkPolicy::Forward);
+    let result = hv.run_with(&options).expect("Failed to run");
+    assert_eq!(result.pc, vbar + 0x200);
+    assert_eq!(vcpu.sys_reg(SysReg::ESR_EL1), brk);
+    assert_eq!(vcpu.sys_reg(SysReg::ELR_EL1), GUEST_ADDR + 0x20);
+    assert_eq!(vcpu.sys_reg(SysReg::SPSR_EL1), 0x3c5);
+    assert_eq!(vcpu.reg(Reg::CPSR), 0x3c5);
+
+    // 既定ではこれまでどおり最初の BRK で戻る
+    vcpu.push_exit(MockExit::exception(brk, 0));
+    let result = hv.run_with(&RunOptions::new()).expect("Failed to run");
+    assert_eq!(result.exception_syndrome, Some(brk));
+    assert_eq!(vcpu.remaining(), 0);
+}
+
+#[test]
+fn wfi_のアイドル待ちを_kick_で解除する() {
+    let vcpu = MockVcpu::new();
+    // 割り込みのない WFI が続くと、スレッドを譲る → 短く待つ → 期限まで待つ
+    for _ in 0..=NAP_WFIS {
+        vcpu.push_exit(MockExit::wfi());
+    }
+    vcpu.push_exit(MockExit::brk());
+    let mut hv = mock_hypervisor(&vcpu);
+
+    // タイマーが無効なので、最後の WFI は kick されなければ最長の待ち (1 秒) まで戻らない
+    let handle = hv.vcpu_handle();
+    let kicker = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        handle.kick().map_err(|e| e.to_string())
+    });
+    let start = Instant::now();
+    hv.run(None, None, None).expect("Failed to run");
+    kicker.join().unwrap().unwrap();
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert_eq!(vcpu.runs(), NAP_WFIS + 2);
+
+    let idle = hv.stats().idle;
+    assert_eq!(idle.yields, YIELD_WFIS);
+    assert_eq!(idle.naps, NAP_WFIS - YIELD_WFIS);
+    assert_eq!(idle.blocks, 1);
+    // brk でゲストが動き出した
+    assert_eq!(idle.state, IdleState::Running);
+    assert_eq!(idle.consecutive_wfis, 0);
+}
+
+#[test]
+fn モニタのコマンドを_run_ループで実行する() {
+    let vcpu = MockVcpu::new();
+    vcpu.push_exit(MockExit::mmio_write(GIC_DIST_BASE, 4, 1));
+    vcpu.push_exit(MockExit::brk());
+
+    let mut hv = mock_hypervisor(&vcpu);
+    for (i, &byte) in b"hello".iter().enumerate() {
+        hv.write_byte(GUEST_ADDR + i as u64, byte).unwrap();
+    }
+    let monitor = hv.monitor();
+    let kicks = hv.vcpu_handle();
+    let client = std::thread::spawn(move || {
+        monitor
+            .execute(MonitorCommand::InjectIrq(40))
+            .map_err(|e| e.to_string())
+    });
+    // 要求を送った後に kick するため、kick されていれば run ループが受け取れる
+    while kicks.kicks() == 0 {
+        std::thread::yield_now();
+    }
+    hv.run(None, None, None).expect("Failed to run");
+    assert_eq!(client.join().unwrap().unwrap(), "IRQ 40 is pending");
+
+    let gic = hv.monitor_command(MonitorCommand::InfoGic).unwrap();
+    assert!(gic.contains("distributor: on"));
+    assert!(gic.contains("pending: 40\n"));
+    let dump = hv
+        .monitor_command(MonitorCommand::DumpMem {
+            addr: GUEST_ADDR,
+            len: 5,
+        })
+        .unwrap();
+    assert!(dump.starts_with("0000000040000000: 6865 6c6c 6f"));
+    assert!(hv.monitor_command(MonitorCommand::InjectIrq(256)).is_err());
+    assert!(hv
+        .monitor_command(MonitorCommand::DumpMem {
+            addr: GUEST_ADDR,
+            len: 0x1001,
+        })
+        .is_err());
+}
+
+#[test]
+fn 応答を打ち切った操作は後から実行しない() {
+    let vcpu = MockVcpu::new();
+    vcpu.push_exit(MockExit::brk());
+
+    let mut hv = mock_hypervisor(&vcpu);
+    let control = hv.control_handle();
+    let ran = Arc::new(Mutex::new(false));
+    let flag = Arc::clone(&ran);
+    // run ループの外なので実行されずに打ち切られる
+    let err = control
+        .call(move |_| *flag.lock().unwrap() = true)
+        .unwrap_err();
+    assert!(err.to_string().contains("no reply within"));
+
+    hv.run(None, None, None).expect("Failed to run");
+    assert!(!*ran.lock().unwrap());
+}
+
+#[test]
+fn qmp_で一時停止中にデバイスを挿して再開する() {
+    let vcpu = MockVcpu::new();
+    vcpu.push_exit(MockExit::brk());
+    let mut hv = mock_hypervisor(&vcpu);
+    hv.add_virtio_slots(1).unwrap();
+
+    let disk = std::env::temp_dir().join(format!("qmp-disk-{}.img", std::process::id()));
+    std::fs::write(&disk, vec![0u8; 4096]).unwrap();
+    let input = format!(
+        "{{\"execute\": \"query-status\"}}\n\
+         {{\"execute\": \"qmp_capabilities\"}}\n\
+         {{\"execute\": \"stop\", \"id\": 1}}\n\
+         {{\"execute\": \"query-status\"}}\n\
+         {{\"execute\": \"device_add\", \"arguments\": \
+           {{\"driver\": \"virtio-blk-device\", \"id\": \"disk0\", \"file\": \"{}\"}}}}\n\
+         {{\"execute\": \"screendump\", \"arguments\": {{\"filename\": \"x.ppm\"}}}}\n\
+         {{\"execute\": \"cont\"}}\n",
+        disk.display()
+    );
+    let server = QmpServer::new(hv.control_handle());
+    let client = std::thread::spawn(move || {
+        let mut output = Vec::new();
+        server
+            .serve(input.as_bytes(), &mut output)
+            .map_err(|e| e.to_string())?;
+        Ok::<_, String>(String::from_utf8(output).unwrap())
+    });
+    let kicks = hv.vcpu_handle();
+    while kicks.kicks() == 0 {
+        std::thread::yield_now();
+    }
+    // stop を受け取ると run ループは再開 (cont) まで残りのコマンドを処理する
+    hv.run(None, None, None).expect("Failed to run");
+    let output = client.join().unwrap().unwrap();
+    std::fs::remove_file(&disk).unwrap();
+
+    let lines: Vec<&str> = output.lines().collect();
+    assert!(lines[0].starts_with(r#"{"QMP": {"version": {"package": "hypervisor "#));
+    assert!(lines[1].contains("Expecting capabilities negotiation"));
+    assert_eq!(lines[2], r#"{"return": {}}"#);
+    assert_eq!(lines[3], r#"{"return": {}, "id": 1}"#);
+    assert_eq!(
+        lines[4],
+        r#"{"return": {"running": false, "status": "paused"}}"#
+    );
+    #[cfg(feature = "virtio-blk")]
+    {
+        assert_eq!(lines[5], r#"{"return": {}}"#);
+        assert!(hv.virtio_slot(0).unwrap().is_bound());
+    }
+    assert!(lines[6].contains(r#""class": "GenericError""#));
+    assert_eq!(lines[7], r#"{"return": {}}"#);
+    assert!(!hv.is_paused());
+    assert_eq!(vcpu.runs(), 1);
+}
+
+#[test]
+fn セルフテストは書き換えた_ram_とレジスタを戻す() {
+    let vcpu = MockVcpu::new();
+    let mut hv = mock_hypervisor(&vcpu);
+    hv.write_instruction(0, 0xD420_0000).unwrap(); // BRK #0
+    vcpu.set_reg(Reg::X0, 0x1234).unwrap();
+    vcpu.set_sys_reg(SysReg::VBAR_EL1, GUEST_ADDR + 0x4000)
+        .unwrap();
+    // どの命令列も VM Exit なしで BRK まで進んだことにする
+    for _ in 0..64 {
+        vcpu.push_exit(MockExit::brk());
+    }
+
+    let report = hv.selftest().unwrap();
+    assert!(report
+        .sysregs
+        .iter()
+        .all(|r| r.outcome == Outcome::Passthrough));
+    // WFI と MMIO の読み書きがトラップされないのは前提と異なる
+    let violations = report.violated_assumptions();
+    assert!(violations.iter().any(|v| v.starts_with("WFI")));
+    assert!(violations.iter().any(|v| v.starts_with("LDR X0, [X1]")));
+
+    assert_eq!(hv.read_data(0).unwrap() as u32, 0xD420_0000);
+    assert_eq!(vcpu.reg(Reg::X0), 0x1234);
+    assert_eq!(vcpu.sys_reg(SysReg::VBAR_EL1), GUEST_ADDR + 0x4000);
+    assert!(!vcpu.irq_pending());
+}
+
+#[test]
+fn ホストの_ipa_空間を超える_ram_は_map_する前に拒否する() {
+    let vcpu = MockVcpu::new();
+    let hv = mock_hypervisor(&vcpu);
+    let host = hv.host_capabilities().unwrap();
+    assert_eq!(host.ipa_bits, 40);
+    assert!(host.guest_granules().is_some());
+    drop(hv);
+
+    let ram = GuestRam::new(0x10_0000).unwrap();
+    let err = Hypervisor::with_backend(1 << 40, ram, &MockVm, Box::new(MockVcpu::new()))
+        .err()
+        .expect("RAM beyond the IPA space must be rejected");
+    assert!(err.to_string().contains("40-bit IPA space"));
+}
+
+/// CVAL=100 で有効にした仮想タイマー
+fn expired_timer(exit: MockExit) -> MockExit {
+    exit.sys_reg(SysReg::CNTV_CTL_EL0, 1)
+        .sys_reg(SysReg::CNTV_CVAL_EL0, 100)
+}
@@ -0,0 +1,91 @@
+//! ワーカープール (1 プロセス 1 VM) のテスト
+//!
+//! ローカルで実行: `cargo test --test orchestration_test -- --ignored`
+//! Linux の起動テストは `scripts/build-linux-kernel.sh` でカーネルを作成しておくこと。
+
+#![cfg(feature = "uart")]
+
+use hypervisor::orchestration::protocol::{VmSpec, WorkerState};
+use hypervisor::orchestration::quota::CpuQuota;
+use hypervisor::orchestration::{VmPool, VmWorker};
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+const WORKER_BIN: &str = env!("CARGO_BIN_EXE_vm-worker");
+const KERNEL_IMAGE_PATH: &str = "output/Image";
+
+/// ワーカーのバイナリに entitlements を付ける (cargo の runner と同じ)
+fn sign_worker() {
+    let _ = Command::new("codesign")
+        .args(["-s", "-", "--entitlements", "entitlements.plist", "--force"])
+        .arg(WORKER_BIN)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .status();
+}
+
+/// VM を起動する前のワーカーが要求に応答することを確認
+#[test]
+fn 起動前のワーカーは状態とエラーを返す() {
+    let mut worker = VmWorker::spawn(Path::new(WORKER_BIN), "idle").unwrap();
+    assert_eq!(worker.status().unwrap(), WorkerState::Idle);
+
+    let err = worker.stop().unwrap_err();
+    assert!(
+        err.to_string().contains("No VM has been started"),
+        "{}",
+        err
+    );
+
+    let quota = CpuQuota::new(50, Duration::from_millis(200)).unwrap();
+    let err = worker.set_cpu_quota(Some(quota)).unwrap_err();
+    assert!(
+        err.to_string().contains("No VM has been started"),
+        "{}",
+        err
+    );
+
+    let err = worker
+        .start(&VmSpec {
+            memory_size: 128 * 1024 * 1024,
+            kernel: "/nonexistent/Image".into(),
+            cmdline: String::new(),
+        })
+        .unwrap_err();
+    assert!(err.to_string().contains("Failed to read kernel"), "{}", err);
+    assert_eq!(worker.exit_reason(), None);
+}
+
+/// 2 つのワーカーで同時に Linux を起動できることを確認
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements and a kernel image (run locally with --ignored)"]
+fn 複数のワーカーで同時に_linux_を起動できる() {
+    let kernel = Path::new(env!("CARGO_MANIFEST_DIR")).join(KERNEL_IMAGE_PATH);
+    if !kernel.exists() {
+        eprintln!("Kernel image not found at {:?}", kernel);
+        return;
+    }
+    sign_worker();
+
+    let spec = VmSpec {
+        memory_size: 128 * 1024 * 1024,
+        kernel,
+        cmdline: "console=ttyAMA0 earlycon=pl011,0x09000000".to_string(),
+    };
+    let mut pool = VmPool::new(WORKER_BIN);
+    pool.spawn("vm0", &spec).unwrap();
+    pool.spawn("vm1", &spec).unwrap();
+    assert!(pool.spawn("vm0", &spec).is_err());
+
+    for name in ["vm0", "vm1"] {
+        pool.get(name)
+            .unwrap()
+            .console()
+            .expect("Booting Linux", Duration::from_secs(30))
+            .unwrap();
+    }
+    for (name, status) in pool.statuses() {
+        assert_eq!(status, Ok(WorkerState::Running), "{}", name);
+    }
+    pool.stop_all().unwrap();
+}
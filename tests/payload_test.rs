@@ -0,0 +1,52 @@
+//! テスト用ペイロードのスモークテスト
+//!
+//! `hypervisor::payloads` のペイロードを実際に動かし、タイマー割り込み・MMIO・
+//! PSCI・ゲスト RAM の基本動作がデバイスの変更で壊れていないことを確認する。
+//!
+//! これらのテストは Hypervisor.framework の entitlements が必要です。
+//! ローカルで実行: `cargo test --test payload_test -- --ignored`
+
+use hypervisor::devices::timer::TIMER_FREQ;
+use hypervisor::payloads::{self, ScratchDevice};
+use hypervisor::Hypervisor;
+
+const RAM_BASE: u64 = 0x4000_0000;
+const RAM_SIZE: usize = 0x10_0000;
+/// ScratchDevice の配置 (virtio-mmio の先頭スロット、このテストでは未使用)
+const SCRATCH_BASE: u64 = 0x0a00_0000;
+
+fn new_hypervisor() -> Hypervisor {
+    Hypervisor::new(RAM_BASE, RAM_SIZE).expect("Failed to create hypervisor")
+}
+
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn タイマー割り込みを指定回数受け取る() {
+    let mut hv = new_hypervisor();
+    // 1ms ごとに 5 回
+    payloads::run_timer_irq_loop(&mut hv, RAM_BASE, 5, TIMER_FREQ / 1000).unwrap();
+}
+
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn mmio_のアクセス幅と符号拡張が正しい() {
+    let mut hv = new_hypervisor();
+    hv.register_mmio_handler(Box::new(ScratchDevice::new(SCRATCH_BASE)))
+        .expect("Failed to register MMIO handler");
+    payloads::run_mmio_torture(&mut hv, RAM_BASE, SCRATCH_BASE).unwrap();
+}
+
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn psci_の戻り値が正しい() {
+    let mut hv = new_hypervisor();
+    payloads::run_psci_exerciser(&mut hv, RAM_BASE).unwrap();
+}
+
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn ゲスト_ram_に書いたパターンを読み戻せる() {
+    let mut hv = new_hypervisor();
+    let start = RAM_BASE + 0x1_0000;
+    payloads::run_memory_pattern(&mut hv, RAM_BASE, start, 0x8000, 0x5a5a_a5a5_0f0f_f0f0).unwrap();
+}
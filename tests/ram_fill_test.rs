@@ -0,0 +1,26 @@
+//! ゲスト RAM の初期化パターンと scrub の統合テスト
+//!
+//! Hypervisor.framework は 1 プロセス 1 VM のため、1 つのテスト関数で順に確認する。
+//! ローカルで実行: `cargo test --test ram_fill_test -- --ignored`
+
+use hypervisor::{Hypervisor, RamFill};
+
+const RAM_BASE: u64 = 0x4000_0000;
+const RAM_SIZE: usize = 0x2_0000;
+
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn poison_で確保した_ram_を_scrub_でゼロに戻せる() {
+    let mut hv = Hypervisor::with_ram_fill(RAM_BASE, RAM_SIZE, RamFill::Poison)
+        .expect("Failed to create hypervisor");
+
+    // 先頭・末尾ともに 0xAA
+    assert_eq!(hv.read_byte(RAM_BASE).unwrap(), 0xAA);
+    assert_eq!(hv.read_byte(RAM_BASE + RAM_SIZE as u64 - 1).unwrap(), 0xAA);
+
+    hv.write_byte(RAM_BASE + 0x100, 0x55).unwrap();
+    hv.scrub_ram(RamFill::Zero).expect("Failed to scrub RAM");
+
+    assert_eq!(hv.read_byte(RAM_BASE + 0x100).unwrap(), 0x00);
+    assert_eq!(hv.read_byte(RAM_BASE + RAM_SIZE as u64 - 1).unwrap(), 0x00);
+}
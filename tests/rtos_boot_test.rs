@@ -0,0 +1,110 @@
+//! Linux 以外のゲスト (Zephyr) の起動スモークテスト
+//!
+//! Timer / GIC / UART モデルが Linux 固有の前提に依存していないことを確認する。
+//!
+//! Zephyr イメージのビルド方法:
+//! ```sh
+//! west build -b qemu_cortex_a53 samples/hello_world
+//! cp build/zephyr/zephyr.bin output/zephyr.bin
+//! ```
+//!
+//! `qemu_cortex_a53` は RAM 0x4000_0000、PL011 0x0900_0000 を前提にしており、
+//! `MachineLayout::QEMU_VIRT` と一致する。
+//! 注: Zephyr の qemu_cortex_a53 は GICv3 を使うため、割り込み駆動の処理は
+//! まだ動作しない。ポーリング UART による起動バナーまでを確認する。
+
+#![cfg(feature = "uart")]
+
+use hypervisor::boot::device_tree::{generate_device_tree, DeviceTreeConfig};
+use hypervisor::boot::kernel::KernelImage;
+use hypervisor::boot::layout::MachineLayout;
+use hypervisor::devices::uart::Pl011Uart;
+use hypervisor::mmio::MmioHandler;
+use hypervisor::Hypervisor;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// UART 出力を収集する構造体
+struct UartCollector {
+    inner: Pl011Uart,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MmioHandler for UartCollector {
+    fn base(&self) -> u64 {
+        self.inner.base()
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, Box<dyn Error>> {
+        self.inner.read(offset, size)
+    }
+
+    fn write(&mut self, offset: u64, value: u64, size: usize) -> Result<(), Box<dyn Error>> {
+        if offset == 0x00 {
+            self.output.lock().unwrap().push((value & 0xFF) as u8);
+        }
+        self.inner.write(offset, value, size)
+    }
+}
+
+const RAM_BASE: u64 = 0x4000_0000;
+const RAM_SIZE: usize = 128 * 1024 * 1024;
+const UART_BASE: u64 = 0x0900_0000;
+
+/// Zephyr イメージのパス
+const ZEPHYR_IMAGE_PATH: &str = "output/zephyr.bin";
+
+/// Zephyr hello_world が起動バナーを出力することを確認
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements and Zephyr image (run locally with --ignored)"]
+fn zephyr_が起動してバナーを出力する() {
+    let image_path = Path::new(env!("CARGO_MANIFEST_DIR")).join(ZEPHYR_IMAGE_PATH);
+    if !image_path.exists() {
+        eprintln!("Zephyr image not found at {:?}", image_path);
+        eprintln!("Build it with: west build -b qemu_cortex_a53 samples/hello_world");
+        return;
+    }
+
+    let data = fs::read(&image_path).expect("Failed to read Zephyr image");
+    // qemu_cortex_a53 のリンクアドレスは RAM 先頭
+    let image = KernelImage::from_bytes(data, Some(RAM_BASE));
+
+    let mut hv = Hypervisor::new(RAM_BASE, RAM_SIZE).expect("Failed to create hypervisor");
+
+    let output = Arc::new(Mutex::new(Vec::new()));
+    hv.register_mmio_handler(Box::new(UartCollector {
+        inner: Pl011Uart::new(UART_BASE),
+        output: Arc::clone(&output),
+    }))
+    .expect("Failed to register MMIO handler");
+
+    // Zephyr はボード定義を埋め込んでいるが、DTB を見るツールのためにボード名を合わせて渡す
+    let dtb = generate_device_tree(&DeviceTreeConfig::zephyr_qemu_cortex_a53())
+        .expect("Failed to generate device tree");
+    let dtb_addr = MachineLayout::default().dtb_addr();
+    hv.load_blob("dtb", dtb_addr, &dtb)
+        .expect("Failed to load device tree");
+
+    let result = hv
+        .boot_bare_metal(&image, &[dtb_addr])
+        .expect("Failed to boot Zephyr");
+    println!(
+        "Exit reason: {:?}, PC: 0x{:x}",
+        result.exit_reason, result.pc
+    );
+
+    let output = output.lock().unwrap();
+    let output_str = String::from_utf8_lossy(&output);
+    println!("=== UART Output ===\n{}", output_str);
+
+    assert!(
+        output_str.contains("Booting Zephyr OS"),
+        "Expected Zephyr boot banner"
+    );
+}
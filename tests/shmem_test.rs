@@ -0,0 +1,55 @@
+//! 共有メモリデバイスの統合テスト
+//!
+//! ゲストが共有メモリに書き込んでドアベルを鳴らし、ホストがファイル経由で
+//! 内容を確認する。
+//! ローカルで実行: `cargo test --test shmem_test -- --ignored`
+
+use hypervisor::devices::shmem::{regs, SharedMemoryDevice, SHMEM_DOORBELL_BASE};
+use hypervisor::memory::GuestRam;
+use hypervisor::Hypervisor;
+use std::fs;
+use std::time::Duration;
+
+const RAM_BASE: u64 = 0x4000_0000;
+const SHM_BASE: u64 = 0x5000_0000;
+const SHM_SIZE: usize = 0x4000;
+
+/// MOVZ Xd, #imm16, LSL #shift
+fn movz(rd: u32, imm: u16, shift: u32) -> u32 {
+    0xd280_0000 | ((shift / 16) << 21) | ((imm as u32) << 5) | rd
+}
+
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn ゲストが共有メモリに書いた値をホストが読める() {
+    let path = std::env::temp_dir().join("hv-shmem-test");
+    let _ = fs::remove_file(&path);
+
+    let mut hv = Hypervisor::new(RAM_BASE, 0x10_0000).expect("Failed to create hypervisor");
+    let shm = GuestRam::from_file(&path, SHM_SIZE).expect("Failed to map shared memory");
+    hv.add_memory_region(SHM_BASE, shm)
+        .expect("Failed to add shared memory");
+
+    let gic = hv.interrupt_controller().gic.clone();
+    let device = SharedMemoryDevice::new(SHMEM_DOORBELL_BASE, SHM_BASE, SHM_SIZE as u64, gic, 40);
+    let handle = device.host_handle(hv.waker());
+    hv.register_mmio_handler(Box::new(device))
+        .expect("Failed to register MMIO handler");
+
+    hv.write_instructions(&[
+        movz(0, 0x5000, 16),                               // X0 = SHM_BASE
+        movz(1, 0xbeef, 0),                                // X1 = 0xbeef
+        0xf900_0001,                                       // STR X1, [X0]
+        movz(2, 0x0b00, 16),                               // X2 = SHMEM_DOORBELL_BASE
+        movz(3, 1, 0),                                     // X3 = 1
+        0xb900_0043 | ((regs::DOORBELL as u32 / 4) << 10), // STR W3, [X2, #DOORBELL]
+        0xd420_0000,                                       // BRK #0
+    ])
+    .unwrap();
+
+    hv.run(None, None, None).expect("Failed to run guest");
+
+    assert_eq!(handle.wait_guest(Duration::from_millis(100)), Some(1));
+    let data = fs::read(&path).unwrap();
+    assert_eq!(u64::from_le_bytes(data[..8].try_into().unwrap()), 0xbeef);
+}
@@ -50,7 +50,7 @@ fn encode_brk(imm: u16) -> u32 {
 #[test]
 #[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
 fn mrs_cntfrq_el0_はタイマー周波数を読み取れる() {
-    let mut hv = Hypervisor::new(0x10000, 4096).expect("Failed to create hypervisor");
+    let mut hv = Hypervisor::new(0x10000, 0x4000).expect("Failed to create hypervisor");
 
     // MRS x0, CNTFRQ_EL0  ; Op0=3, Op1=3, CRn=14, CRm=0, Op2=0
     // BRK #0
@@ -79,7 +79,7 @@ fn mrs_cntfrq_el0_はタイマー周波数を読み取れる() {
 #[test]
 #[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
 fn mrs_cntpct_el0_は物理カウンタを読み取れる() {
-    let mut hv = Hypervisor::new(0x10000, 4096).expect("Failed to create hypervisor");
+    let mut hv = Hypervisor::new(0x10000, 0x4000).expect("Failed to create hypervisor");
 
     // MRS x0, CNTPCT_EL0  ; Op0=3, Op1=3, CRn=14, CRm=0, Op2=1
     // BRK #0
@@ -113,7 +113,7 @@ fn mrs_cntpct_el0_は物理カウンタを読み取れる() {
 #[test]
 #[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
 fn mrs_cntvct_el0_は仮想カウンタを読み取れる() {
-    let mut hv = Hypervisor::new(0x10000, 4096).expect("Failed to create hypervisor");
+    let mut hv = Hypervisor::new(0x10000, 0x4000).expect("Failed to create hypervisor");
 
     // MRS x0, CNTVCT_EL0  ; Op0=3, Op1=3, CRn=14, CRm=0, Op2=2
     // BRK #0
@@ -133,7 +133,7 @@ fn mrs_cntvct_el0_は仮想カウンタを読み取れる() {
 #[test]
 #[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
 fn msr_cntp_cval_el0_で物理タイマー比較値を書き込める() {
-    let mut hv = Hypervisor::new(0x10000, 4096).expect("Failed to create hypervisor");
+    let mut hv = Hypervisor::new(0x10000, 0x4000).expect("Failed to create hypervisor");
 
     // MOV x0, #0x1234
     // MSR CNTP_CVAL_EL0, x0  ; Op0=3, Op1=3, CRn=14, CRm=2, Op2=2
@@ -176,7 +176,7 @@ fn msr_cntp_cval_el0_で物理タイマー比較値を書き込める() {
 #[test]
 #[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
 fn msr_cntp_ctl_el0_で物理タイマーを有効化できる() {
-    let mut hv = Hypervisor::new(0x10000, 4096).expect("Failed to create hypervisor");
+    let mut hv = Hypervisor::new(0x10000, 0x4000).expect("Failed to create hypervisor");
 
     // MOV x0, #1           ; ENABLE ビット
     // MSR CNTP_CTL_EL0, x0 ; Op0=3, Op1=3, CRn=14, CRm=2, Op2=1
@@ -214,7 +214,7 @@ fn msr_cntp_ctl_el0_で物理タイマーを有効化できる() {
 #[test]
 #[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
 fn mrs_で複数のレジスタに読み込める() {
-    let mut hv = Hypervisor::new(0x10000, 4096).expect("Failed to create hypervisor");
+    let mut hv = Hypervisor::new(0x10000, 0x4000).expect("Failed to create hypervisor");
 
     // MRS x0, CNTFRQ_EL0
     // MRS x1, CNTPCT_EL0
@@ -254,7 +254,7 @@ fn mrs_で複数のレジスタに読み込める() {
 #[test]
 #[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
 fn 仮想タイマーレジスタにアクセスできる() {
-    let mut hv = Hypervisor::new(0x10000, 4096).expect("Failed to create hypervisor");
+    let mut hv = Hypervisor::new(0x10000, 0x4000).expect("Failed to create hypervisor");
 
     // MOV x0, #0x5678
     // MSR CNTV_CVAL_EL0, x0  ; Op0=3, Op1=3, CRn=14, CRm=3, Op2=2
@@ -274,3 +274,19 @@ fn 仮想タイマーレジスタにアクセスできる() {
     // x1 に書き込んだ値が読み返せるべき
     assert_eq!(result.registers[1], 0x5678);
 }
+
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn セルフテストでホストの動作が前提どおりか確認できる() {
+    let mut hv = Hypervisor::new(0x4000_0000, 0x10_0000).expect("Failed to create hypervisor");
+    hv.write_instructions(&[encode_brk(0)])
+        .expect("Failed to write instructions");
+
+    let report = hv.selftest().expect("Failed to run selftest");
+    println!("{}", report);
+    assert_eq!(report.violated_assumptions(), Vec::<String>::new());
+
+    // セルフテストが書き換えた RAM とレジスタは戻っている
+    let result = hv.run(None, None, None).expect("Failed to run");
+    assert_eq!(result.pc, 0x4000_0000);
+}
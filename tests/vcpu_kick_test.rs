@@ -0,0 +1,57 @@
+//! 他のスレッドからの vCPU kick のテスト
+//!
+//! これらのテストは Hypervisor.framework の entitlements が必要です。
+//! ローカルで実行する場合は `cargo test --ignored` を使用してください。
+
+use applevisor::ExitReason;
+use hypervisor::Hypervisor;
+use std::thread;
+use std::time::Duration;
+
+/// 無限ループ中のゲストを別スレッドから止められることを確認
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn ループ中のゲストを別スレッドから停止できる() {
+    let mut hv = Hypervisor::new(0x4000_0000, 0x100_0000).expect("Failed to create hypervisor");
+
+    // B . (自分自身への分岐) で VM Exit を起こさずに回り続ける
+    hv.write_instructions(&[0x1400_0000])
+        .expect("Failed to write instructions");
+
+    let handle = hv.vcpu_handle();
+    let stopper = {
+        let handle = handle.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            handle.request_stop().expect("Failed to kick vCPU");
+        })
+    };
+
+    let result = hv.run(None, None, None).expect("Failed to run");
+    stopper.join().unwrap();
+
+    assert!(matches!(result.exit_reason, ExitReason::CANCELED));
+    assert_eq!(result.pc, 0x4000_0000);
+    assert!(handle.kicks() >= 1);
+}
+
+/// 停止要求を処理した後は再び run できることを確認
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn 停止要求は一度だけ消費される() {
+    let mut hv = Hypervisor::new(0x4000_0000, 0x100_0000).expect("Failed to create hypervisor");
+    hv.write_instructions(&[0xD420_0000]) // BRK #0
+        .expect("Failed to write instructions");
+
+    // run の前に要求しておくと、ゲストを実行せずに戻る
+    hv.vcpu_handle().request_stop().unwrap();
+    let result = hv.run(None, None, None).expect("Failed to run");
+    assert!(matches!(result.exit_reason, ExitReason::CANCELED));
+
+    let result = hv.run(None, None, None).expect("Failed to run");
+    let ec = result
+        .exception_syndrome
+        .map(|s| (s >> 26) & 0x3f)
+        .unwrap_or(0);
+    assert_eq!(ec, 0x3c, "Expected BRK exception (EC=0x3c)");
+}
@@ -0,0 +1,58 @@
+//! ゲスト RAM への書き込み監視のテスト
+//!
+//! これらのテストは Hypervisor.framework の entitlements が必要です。
+//! ローカルで実行する場合は `cargo test --ignored` を使用してください。
+
+use hypervisor::watch::WatchAction;
+use hypervisor::Hypervisor;
+use std::sync::{Arc, Mutex};
+
+const RAM_BASE: u64 = 0x4000_0000;
+const WATCHED: u64 = 0x4010_0000;
+
+/// 範囲への書き込みだけが記録され、値は RAM に書き込まれることを確認
+#[test]
+#[ignore = "requires Hypervisor.framework entitlements (run locally with --ignored)"]
+fn 監視範囲への書き込みを捕まえる() {
+    let mut hv = Hypervisor::new(RAM_BASE, 0x100_0000).expect("Failed to create hypervisor");
+    hv.write_instructions(&[
+        0xD2A8_0201, // MOVZ X1, #0x4010, LSL #16
+        0xD282_4682, // MOVZ X2, #0x1234
+        0xF900_0022, // STR X2, [X1]          (範囲内, ISV=1)
+        0xF900_8022, // STR X2, [X1, #0x100]  (同じページの範囲外)
+        0xA900_0822, // STP X2, X2, [X1]      (範囲内, ISV=0)
+        0xD420_0000, // BRK #0
+    ])
+    .expect("Failed to write instructions");
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = seen.clone();
+    hv.watch_writes(
+        "flag",
+        WATCHED..WATCHED + 8,
+        WatchAction::Callback(Box::new(move |hit| sink.lock().unwrap().push(hit.pc))),
+    )
+    .expect("Failed to watch range");
+
+    let result = hv.run(None, None, None).expect("Failed to run");
+    let ec = result
+        .exception_syndrome
+        .map(|s| (s >> 26) & 0x3f)
+        .unwrap_or(0);
+    assert_eq!(ec, 0x3c, "Expected BRK exception (EC=0x3c)");
+
+    let hits = hv.watch_hits("flag");
+    assert_eq!(hits.len(), 2, "{:?}", hits);
+    assert_eq!(hits[0].pc, RAM_BASE + 8);
+    assert_eq!(hits[0].value, Some(0x1234));
+    assert_eq!(hits[1].pc, RAM_BASE + 16);
+    assert_eq!(*seen.lock().unwrap(), vec![RAM_BASE + 8, RAM_BASE + 16]);
+
+    // 捕まえた書き込みも RAM に反映されている
+    assert_eq!(hv.read_data(WATCHED - RAM_BASE).unwrap(), 0x1234);
+    assert_eq!(hv.read_data(WATCHED + 0x100 - RAM_BASE).unwrap(), 0x1234);
+    assert_eq!(hv.read_data(WATCHED + 8 - RAM_BASE).unwrap(), 0x1234);
+
+    hv.unwatch_writes("flag").expect("Failed to unwatch range");
+    assert!(hv.watch_writes("ram", 0..8, WatchAction::Log).is_err());
+}